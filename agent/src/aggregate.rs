@@ -0,0 +1,211 @@
+//! 补发离线缓冲时的样本聚合
+//!
+//! 长时间断网后补发离线缓冲（或以很短的上报间隔采集）时，重连瞬间会有大量样本涌向 Server。
+//! 开启聚合后，补发前把早于阈值的样本按时间桶合并为一条：CPU、负载、内存、磁盘用量、容器用量与
+//! 文件描述符数等瞬时值取桶内平均，累计计数器、系统信息等其余字段以及时间戳、序号取桶内最后一条样本。
+//! 补发量因此有上限，代价是桶内细节丢失；与缓冲超限时丢弃最旧的样本不同，这里保留的是摘要。
+//! 晚于阈值的样本原样发送；被合并掉的样本在 Server 端计入序号缺口
+
+use common::proto::{MetricsRequest, SystemMetrics};
+use std::time::Duration;
+
+/// 默认时间桶宽度（秒）
+pub const DEFAULT_AGGREGATE_BUCKET_SECS: u64 = 60;
+
+/// 离线缓冲补发时的聚合选项
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpoolAggregation {
+    /// 早于该时长的样本参与合并
+    pub after: Duration,
+    /// 时间桶宽度，同一桶内的样本合并为一条
+    pub bucket: Duration,
+}
+
+impl SpoolAggregation {
+    /// 按时间顺序合并 `now_ms` 之前超过阈值的样本，样本顺序保持不变
+    pub(crate) fn coalesce(
+        &self,
+        samples: Vec<MetricsRequest>,
+        now_ms: i64,
+    ) -> Vec<MetricsRequest> {
+        let cutoff = now_ms.saturating_sub(self.after.as_millis() as i64);
+        let bucket_ms = (self.bucket.as_millis() as i64).max(1);
+
+        let mut coalesced = Vec::new();
+        let mut group: Vec<MetricsRequest> = Vec::new();
+        for sample in samples {
+            let old = sample.timestamp < cutoff;
+            // 进入下一个桶或遇到较新的样本时，之前的桶合并为一条
+            if group.last().is_some_and(|last| {
+                !old || last.timestamp.div_euclid(bucket_ms)
+                    != sample.timestamp.div_euclid(bucket_ms)
+            }) {
+                coalesced.push(average(&group));
+                group.clear();
+            }
+            if old {
+                group.push(sample);
+            } else {
+                coalesced.push(sample);
+            }
+        }
+        if !group.is_empty() {
+            coalesced.push(average(&group));
+        }
+        coalesced
+    }
+}
+
+/// 以最后一条样本为基础，瞬时值改为桶内平均
+fn average(group: &[MetricsRequest]) -> MetricsRequest {
+    let mut merged = group.last().cloned().unwrap_or_default();
+    if group.len() < 2 {
+        return merged;
+    }
+    let systems: Vec<&SystemMetrics> = group.iter().filter_map(|s| s.system.as_ref()).collect();
+    let Some(system) = merged.system.as_mut() else {
+        return merged;
+    };
+
+    if let Some(cpu) = system.cpu.as_mut() {
+        let cpus: Vec<_> = systems.iter().filter_map(|s| s.cpu.as_ref()).collect();
+        cpu.usage_percent = mean(cpus.iter().map(|c| c.usage_percent));
+        cpu.load_avg_1 = mean(cpus.iter().map(|c| c.load_avg_1));
+        cpu.load_avg_5 = mean(cpus.iter().map(|c| c.load_avg_5));
+        cpu.load_avg_15 = mean(cpus.iter().map(|c| c.load_avg_15));
+        // 核心数在桶内变化时保留最后一条的每核数据
+        if cpus.iter().all(|c| c.per_core.len() == cpu.per_core.len()) {
+            for (i, core) in cpu.per_core.iter_mut().enumerate() {
+                *core = mean(cpus.iter().map(|c| c.per_core[i]));
+            }
+        }
+    }
+    if let Some(memory) = system.memory.as_mut() {
+        let memories: Vec<_> = systems.iter().filter_map(|s| s.memory.as_ref()).collect();
+        memory.used = mean_u64(memories.iter().map(|m| m.used));
+        memory.available = mean_u64(memories.iter().map(|m| m.available));
+        memory.usage_percent = mean(memories.iter().map(|m| m.usage_percent));
+        memory.swap_used = mean_u64(memories.iter().map(|m| m.swap_used));
+    }
+    for disk in &mut system.disks {
+        let disks: Vec<_> = systems
+            .iter()
+            .flat_map(|s| &s.disks)
+            .filter(|d| d.mount_point == disk.mount_point)
+            .collect();
+        disk.used = mean_u64(disks.iter().map(|d| d.used));
+        disk.available = mean_u64(disks.iter().map(|d| d.available));
+        disk.usage_percent = mean(disks.iter().map(|d| d.usage_percent));
+    }
+    if let Some(containers) = system.containers.as_mut() {
+        let all: Vec<_> = systems
+            .iter()
+            .filter_map(|s| s.containers.as_ref())
+            .collect();
+        containers.cpu_usage_percent = mean(all.iter().map(|c| c.cpu_usage_percent));
+        containers.memory_usage = mean_u64(all.iter().map(|c| c.memory_usage));
+    }
+    // 文件描述符上限取最后一条
+    if let Some(fds) = system.file_descriptors.as_mut() {
+        let all: Vec<_> = systems
+            .iter()
+            .filter_map(|s| s.file_descriptors.as_ref())
+            .collect();
+        fds.open = mean_u64(all.iter().map(|f| f.open));
+        fds.agent_open = mean_u64(all.iter().map(|f| f.agent_open));
+    }
+    merged
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> f64 {
+    let (sum, count) = values.fold((0.0, 0u32), |(sum, count), v| (sum + v, count + 1));
+    if count == 0 {
+        0.0
+    } else {
+        sum / count as f64
+    }
+}
+
+fn mean_u64(values: impl Iterator<Item = u64>) -> u64 {
+    mean(values.map(|v| v as f64)).round() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::{
+        CpuMetrics, DiskMetrics, FileDescriptorMetrics, MemoryMetrics, NetworkMetrics,
+    };
+
+    fn sample(timestamp: i64, sequence: u64, cpu: f64) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: "agent-1".to_string(),
+            timestamp,
+            sequence,
+            system: Some(SystemMetrics {
+                cpu: Some(CpuMetrics {
+                    usage_percent: cpu,
+                    per_core: vec![cpu, cpu / 2.0],
+                    ..Default::default()
+                }),
+                memory: Some(MemoryMetrics {
+                    total: 1000,
+                    used: sequence * 10,
+                    ..Default::default()
+                }),
+                disks: vec![DiskMetrics {
+                    mount_point: "/".to_string(),
+                    used: sequence * 100,
+                    ..Default::default()
+                }],
+                network: Some(NetworkMetrics {
+                    bytes_sent: sequence * 1000,
+                    ..Default::default()
+                }),
+                file_descriptors: Some(FileDescriptorMetrics {
+                    open: sequence * 20,
+                    max: 1000 + sequence,
+                    agent_open: sequence * 2,
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_old_samples_averaged_per_bucket() {
+        let aggregation = SpoolAggregation {
+            after: Duration::from_secs(60),
+            bucket: Duration::from_secs(10),
+        };
+        // 0..20s 的 4 条旧样本分属两个桶，100s 的样本未超过阈值
+        let samples = vec![
+            sample(0, 1, 10.0),
+            sample(5_000, 2, 30.0),
+            sample(10_000, 3, 50.0),
+            sample(15_000, 4, 70.0),
+            sample(100_000, 5, 90.0),
+        ];
+        let coalesced = aggregation.coalesce(samples, 120_000);
+
+        let summary: Vec<_> = coalesced
+            .iter()
+            .map(|s| (s.timestamp, s.sequence))
+            .collect();
+        assert_eq!(summary, [(5_000, 2), (15_000, 4), (100_000, 5)]);
+
+        let system = coalesced[0].system.as_ref().unwrap();
+        let cpu = system.cpu.as_ref().unwrap();
+        assert_eq!(cpu.usage_percent, 20.0);
+        assert_eq!(cpu.per_core, [20.0, 10.0]);
+        assert_eq!(system.memory.as_ref().unwrap().used, 15);
+        assert_eq!(system.disks[0].used, 150);
+        let fds = system.file_descriptors.unwrap();
+        assert_eq!((fds.open, fds.max, fds.agent_open), (30, 1002, 3));
+        // 累计计数器取桶内最后一条
+        assert_eq!(system.network.as_ref().unwrap().bytes_sent, 2000);
+        // 未超过阈值的样本原样保留
+        assert_eq!(coalesced[2], sample(100_000, 5, 90.0));
+    }
+}
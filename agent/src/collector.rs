@@ -1,17 +1,29 @@
 use common::proto::{
-    AgentMetrics, CpuMetrics, DiskMetrics, MemoryMetrics, NetworkMetrics, SystemInfo, SystemMetrics,
+    AgentMetrics, CollectorState, CollectorStatus, CpuMetrics, DiskMetrics, EntropyMetrics,
+    FileDescriptorMetrics, MemoryMetrics, NetworkMetrics, NumaNodeMemory, PressureMetrics,
+    PressureResource, PressureStall, ProcessMetrics, SystemInfo, SystemMetrics,
 };
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use std::time::Instant;
 use sysinfo::{
     Disks, MemoryRefreshKind, Networks, Pid, ProcessRefreshKind, ProcessesToUpdate, System,
     MINIMUM_CPU_UPDATE_INTERVAL,
 };
+use tracing::warn;
 
 // 全局统计
 static METRICS_SENT: AtomicU64 = AtomicU64::new(0);
 static ERRORS_COUNT: AtomicU64 = AtomicU64::new(0);
+static RECONNECT_COUNT: AtomicU64 = AtomicU64::new(0);
+static LAST_ERROR: Mutex<String> = Mutex::new(String::new());
+static COLLECTION_TIME_AVG: Mutex<Ewma> = Mutex::new(Ewma::new(COLLECTION_TIME_ALPHA));
+
+/// 采集耗时移动平均的平滑系数：新样本占 0.1，约反映最近 20 次采集
+const COLLECTION_TIME_ALPHA: f64 = 0.1;
 
 // 探针启动时间
 static AGENT_START_TIME: once_cell::sync::Lazy<Instant> = once_cell::sync::Lazy::new(Instant::now);
@@ -33,13 +45,57 @@ static DISKS: once_cell::sync::Lazy<Mutex<Disks>> =
 static NETWORKS: once_cell::sync::Lazy<Mutex<Networks>> =
     once_cell::sync::Lazy::new(|| Mutex::new(Networks::new_with_refreshed_list()));
 
+// 机器唯一标识，进程生命周期内不变，只读取一次
+static MACHINE_ID: once_cell::sync::Lazy<String> = once_cell::sync::Lazy::new(read_machine_id);
+
+// 本次启动标识，重启后才会变化，只读取一次
+static BOOT_ID: once_cell::sync::Lazy<String> = once_cell::sync::Lazy::new(read_boot_id);
+
 // 标记是否已经完成初始化等待
 static CPU_INITIALIZED: AtomicBool = AtomicBool::new(false);
 
 // TCP Ping 采集已按需临时停用。
 
-/// 采集系统指标
-pub fn collect_metrics() -> SystemMetrics {
+/// 采集选项
+#[derive(Debug, Clone)]
+pub struct CollectOptions {
+    /// 是否采集探针自身进程的 CPU/内存（关闭后跳过每次的进程刷新）
+    pub self_metrics: bool,
+    /// 上报 CPU 使用率最高的进程数，0 表示不采集（省去每次的全量进程刷新）
+    pub top_processes: usize,
+    /// 上报全部挂载点；默认同一文件系统的多个挂载（绑定挂载、overlay 等）只上报一次
+    pub all_mounts: bool,
+    /// 上报 systemd 失败单元（每次采集执行一次 systemctl，主机未运行 systemd 时跳过）
+    pub systemd: bool,
+    /// 上报 Docker 容器统计（经 Docker socket 查询，主机没有 Docker 时跳过）
+    pub docker: bool,
+    /// 上报各 NUMA 节点的内存（每次采集读取一遍 /sys/devices/system/node，非 NUMA 主机只有一项）
+    pub numa: bool,
+}
+
+impl Default for CollectOptions {
+    fn default() -> Self {
+        Self {
+            self_metrics: true,
+            top_processes: 0,
+            all_mounts: false,
+            systemd: false,
+            docker: false,
+            numa: false,
+        }
+    }
+}
+
+/// 按指定选项采集系统指标
+pub fn collect_metrics_with(options: &CollectOptions) -> SystemMetrics {
+    collect_metrics_using(options, collect_disk_metrics)
+}
+
+/// 采集系统指标，磁盘采集器可替换（便于测试失败场景）
+fn collect_metrics_using(
+    options: &CollectOptions,
+    disk_collector: fn() -> Vec<DiskMetrics>,
+) -> SystemMetrics {
     let start = Instant::now();
 
     // 第一次采集时，需要等待 MINIMUM_CPU_UPDATE_INTERVAL 以获取准确的 CPU 使用率
@@ -48,69 +104,273 @@ pub fn collect_metrics() -> SystemMetrics {
         CPU_INITIALIZED.store(true, Ordering::Relaxed);
     }
 
-    let (cpu, memory, system_info) = {
-        let mut sys = SYSTEM.lock().unwrap();
+    // 每个子系统独立采集，单个失败不影响其他子系统
+    let mut status = Vec::new();
 
-        // 刷新 CPU 使用率（需要两次刷新之间的差值）
-        sys.refresh_cpu_usage();
-        // 刷新其他系统信息
-        sys.refresh_memory_specifics(MemoryRefreshKind::everything());
+    let cpu = run_collector(
+        &mut status,
+        "cpu",
+        || {
+            let mut sys = lock_system();
+            // 刷新 CPU 使用率（需要两次刷新之间的差值）与各核心当前频率
+            sys.refresh_cpu_usage();
+            sys.refresh_cpu_frequency();
+            collect_cpu_metrics(&sys)
+        },
+        |cpu| (cpu.core_count == 0).then(|| "未检测到 CPU 核心".to_string()),
+    );
+    let memory = run_collector(
+        &mut status,
+        "memory",
+        || {
+            let mut sys = lock_system();
+            sys.refresh_memory_specifics(MemoryRefreshKind::everything());
+            collect_memory_metrics(&sys)
+        },
+        |memory| (memory.total == 0).then(|| "无法读取内存总量".to_string()),
+    );
+    let system_info = run_collector(
+        &mut status,
+        "system_info",
+        || collect_system_info(&lock_system()),
+        |_| None,
+    );
 
-        (
-            collect_cpu_metrics(&sys),
-            collect_memory_metrics(&sys),
-            collect_system_info(&sys),
+    // 磁盘/网络采集也计入本次采集耗时
+    let disks = run_collector(&mut status, "disks", disk_collector, |disks| {
+        disks.is_empty().then(|| "未枚举到任何磁盘".to_string())
+    })
+    .unwrap_or_default();
+    let disks = if options.all_mounts {
+        disks
+    } else {
+        dedup_disks(disks)
+    };
+    let network = run_collector(
+        &mut status,
+        "network",
+        collect_network_metrics,
+        |(_, interfaces)| (*interfaces == 0).then(|| "未发现网络接口".to_string()),
+    )
+    .map(|(network, _)| network);
+    let pressure = run_collector(&mut status, "pressure", collect_psi_metrics, |_| None).flatten();
+    let zombie_count =
+        run_collector(&mut status, "processes", collect_zombie_count, |_| None).unwrap_or_default();
+    // 未启用时不记录 top_processes 子系统状态
+    let top_processes = if options.top_processes > 0 {
+        let limit = options.top_processes;
+        run_collector(
+            &mut status,
+            "top_processes",
+            || collect_top_processes(limit),
+            |_| None,
         )
+        .unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    let self_metrics = options.self_metrics;
+    let file_descriptors = run_collector(
+        &mut status,
+        "file_descriptors",
+        || collect_fd_metrics(self_metrics),
+        |_| None,
+    )
+    .flatten();
+    let entropy =
+        run_collector(&mut status, "entropy", collect_entropy_metrics, |_| None).flatten();
+    // 未启用时不记录 numa 子系统状态
+    let numa_nodes = if options.numa {
+        run_collector(&mut status, "numa", collect_numa_metrics, |_| None).unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    // 未启用或主机未运行 systemd 时不记录 systemd 子系统状态
+    let systemd = if options.systemd && crate::systemd::available() {
+        run_collector(
+            &mut status,
+            "systemd",
+            crate::systemd::collect_systemd_metrics,
+            |result| result.as_ref().err().cloned(),
+        )
+        .and_then(Result::ok)
+    } else {
+        None
+    };
+    // 未启用或主机没有 Docker 时不记录 containers 子系统状态
+    let containers = if options.docker && crate::docker::available() {
+        run_collector(
+            &mut status,
+            "containers",
+            crate::docker::collect_container_metrics,
+            |result| result.as_ref().err().cloned(),
+        )
+        .and_then(Result::ok)
+    } else {
+        None
+    };
+    // 未启用 gpu feature 时不记录 gpu 子系统状态
+    let gpu = if cfg!(feature = "gpu") {
+        run_collector(&mut status, "gpu", crate::gpu::collect_gpu_metrics, |_| {
+            None
+        })
+        .unwrap_or_default()
+    } else {
+        Vec::new()
     };
-
-    // 磁盘/网络采集也计入本次采集耗时
-    let disks = collect_disk_metrics();
-    let network = collect_network_metrics();
     let collection_time_ms = start.elapsed().as_millis() as u64;
+
     // 最后刷新一次当前进程信息并写入探针自身指标
-    let agent_metrics = {
-        let mut sys = SYSTEM.lock().unwrap();
-        collect_agent_metrics(&mut sys, collection_time_ms)
-    };
+    let agent_metrics = run_collector(
+        &mut status,
+        "agent",
+        || {
+            if self_metrics {
+                collect_agent_metrics(Some(&mut lock_system()), collection_time_ms)
+            } else {
+                collect_agent_metrics(None, collection_time_ms)
+            }
+        },
+        |_| None,
+    );
 
     SystemMetrics {
-        cpu: Some(cpu),
-        memory: Some(memory),
+        cpu,
+        memory,
         disks,
-        network: Some(network),
-        system_info: Some(system_info),
-        agent_metrics: Some(agent_metrics),
+        network,
+        system_info,
+        agent_metrics,
         // TCP Ping 采集已按需临时停用，固定上报空数组。
         tcp_ping: vec![],
+        collector_status: status,
+        pressure,
+        gpu,
+        zombie_count,
+        file_descriptors,
+        top_processes,
+        entropy,
+        systemd,
+        numa_nodes,
+        containers,
     }
 }
 
-/// 采集探针自身指标
-fn collect_agent_metrics(sys: &mut System, collection_time_ms: u64) -> AgentMetrics {
-    let current_pid = Pid::from_u32(std::process::id());
+/// 获取全局 System 实例
+///
+/// 某个采集器 panic 时锁会被标记为 poisoned，这里继续使用内部数据，避免后续采集全部失败
+fn lock_system() -> MutexGuard<'static, System> {
+    SYSTEM.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
-    // 刷新当前进程信息
-    sys.refresh_processes_specifics(
-        ProcessesToUpdate::Some(&[current_pid]),
-        false,
-        ProcessRefreshKind::everything(),
-    );
+/// 运行单个子采集器并记录其状态
+///
+/// - 采集器 panic：记为 failed，返回 None
+/// - `degraded` 返回原因：记为 degraded，仍返回数据
+fn run_collector<T>(
+    status: &mut Vec<CollectorStatus>,
+    subsystem: &str,
+    collect: impl FnOnce() -> T,
+    degraded: impl FnOnce(&T) -> Option<String>,
+) -> Option<T> {
+    let (state, message, value) = match panic::catch_unwind(AssertUnwindSafe(collect)) {
+        Ok(value) => match degraded(&value) {
+            Some(reason) => (CollectorState::Degraded, reason, Some(value)),
+            None => (CollectorState::Ok, String::new(), Some(value)),
+        },
+        Err(payload) => {
+            let reason = panic_message(payload.as_ref());
+            warn!("{} 采集失败: {}", subsystem, reason);
+            (CollectorState::Failed, reason, None)
+        }
+    };
 
-    let process = sys.process(current_pid);
+    status.push(CollectorStatus {
+        subsystem: subsystem.to_string(),
+        state: state as i32,
+        message,
+    });
+    value
+}
 
-    let (cpu_usage, memory_usage) = if let Some(proc) = process {
-        (proc.cpu_usage() as f64, proc.memory())
+/// 提取 panic 信息
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
     } else {
-        (0.0, 0)
+        "unknown panic".to_string()
+    }
+}
+
+/// 探针自身进程只需要 CPU 与内存，避免每次刷新 environ/cmd/tasks 等重量级字段
+fn self_process_refresh_kind() -> ProcessRefreshKind {
+    ProcessRefreshKind::nothing().with_cpu().with_memory()
+}
+
+/// 采集探针自身指标
+///
+/// `sys` 为 None 时跳过进程刷新，CPU/内存字段上报为 0
+fn collect_agent_metrics(sys: Option<&mut System>, collection_time_ms: u64) -> AgentMetrics {
+    let (cpu_usage, memory_usage) = match sys {
+        Some(sys) => {
+            let current_pid = Pid::from_u32(std::process::id());
+
+            // 仅刷新当前进程的 CPU/内存
+            sys.refresh_processes_specifics(
+                ProcessesToUpdate::Some(&[current_pid]),
+                false,
+                self_process_refresh_kind(),
+            );
+
+            sys.process(current_pid)
+                .map(|proc| (proc.cpu_usage() as f64, proc.memory()))
+                .unwrap_or((0.0, 0))
+        }
+        None => (0.0, 0),
     };
 
+    let collection_time_avg_ms = COLLECTION_TIME_AVG
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .update(collection_time_ms as f64);
+
     AgentMetrics {
         cpu_usage,
         memory_usage,
         collection_time_ms,
+        collection_time_avg_ms,
         uptime_seconds: AGENT_START_TIME.elapsed().as_secs(),
         metrics_sent: METRICS_SENT.load(Ordering::Relaxed),
         errors_count: ERRORS_COUNT.load(Ordering::Relaxed),
+        last_error: LAST_ERROR
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone(),
+        reconnect_count: RECONNECT_COUNT.load(Ordering::Relaxed),
+    }
+}
+
+/// 指数加权移动平均，首个样本直接作为初值
+struct Ewma {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Ewma {
+    const fn new(alpha: f64) -> Self {
+        Self { alpha, value: None }
+    }
+
+    /// 加入一个样本，返回更新后的平均值
+    fn update(&mut self, sample: f64) -> f64 {
+        let value = match self.value {
+            Some(value) => value + self.alpha * (sample - value),
+            None => sample,
+        };
+        self.value = Some(value);
+        value
     }
 }
 
@@ -119,9 +379,20 @@ pub fn increment_metrics_sent() {
     METRICS_SENT.fetch_add(1, Ordering::Relaxed);
 }
 
-/// 增加错误计数
-pub fn increment_errors() {
+/// 增加错误计数，并记为最近一次错误
+pub fn record_error(message: String) {
     ERRORS_COUNT.fetch_add(1, Ordering::Relaxed);
+    *LAST_ERROR.lock().unwrap_or_else(PoisonError::into_inner) = message;
+}
+
+/// 增加重连计数
+pub fn increment_reconnects() {
+    RECONNECT_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+pub fn errors_count() -> u64 {
+    ERRORS_COUNT.load(Ordering::Relaxed)
 }
 
 fn collect_cpu_metrics(sys: &System) -> CpuMetrics {
@@ -133,6 +404,9 @@ fn collect_cpu_metrics(sys: &System) -> CpuMetrics {
         per_core.iter().sum::<f64>() / per_core.len() as f64
     };
 
+    // 读不到频率的核心 sysinfo 返回 0
+    let per_core_frequency = cpus.iter().map(|cpu| cpu.frequency() as f64).collect();
+
     let load_avg = System::load_average();
 
     CpuMetrics {
@@ -142,7 +416,88 @@ fn collect_cpu_metrics(sys: &System) -> CpuMetrics {
         load_avg_1: load_avg.one,
         load_avg_5: load_avg.five,
         load_avg_15: load_avg.fifteen,
+        per_core_frequency,
+        per_core_temperature: collect_core_temperatures(cpus.len()),
+    }
+}
+
+/// 各逻辑 CPU 的温度（℃），与 `per_core` 按下标对齐；没有 coretemp 传感器时为空
+#[cfg(target_os = "linux")]
+fn collect_core_temperatures(core_count: usize) -> Vec<f64> {
+    read_core_temperatures(
+        std::path::Path::new("/sys/class/hwmon"),
+        std::path::Path::new("/sys/devices/system/cpu"),
+        core_count,
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn collect_core_temperatures(_core_count: usize) -> Vec<f64> {
+    Vec::new()
+}
+
+/// 从 hwmon 的 coretemp 传感器读取各逻辑 CPU 的温度
+///
+/// coretemp 按物理核心（`Package id P` 下的 `Core C`）上报，逻辑 CPU 通过 `topology` 下的
+/// `physical_package_id` 与 `core_id` 对应到物理核心，超线程的兄弟 CPU 温度相同。
+/// 对应不到读数的 CPU 填 0；完全没有 coretemp 读数时返回空数组
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn read_core_temperatures(
+    hwmon: &std::path::Path,
+    cpu: &std::path::Path,
+    core_count: usize,
+) -> Vec<f64> {
+    let read = |path: std::path::PathBuf| {
+        std::fs::read_to_string(path)
+            .ok()
+            .map(|content| content.trim().to_string())
+    };
+
+    // (package, core) -> ℃
+    let mut readings = HashMap::new();
+    for entry in std::fs::read_dir(hwmon).into_iter().flatten().flatten() {
+        let dir = entry.path();
+        if read(dir.join("name")).as_deref() != Some("coretemp") {
+            continue;
+        }
+        let mut package = 0;
+        let mut cores = Vec::new();
+        for file in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let name = file.file_name().to_string_lossy().into_owned();
+            let Some(sensor) = name.strip_suffix("_label") else {
+                continue;
+            };
+            let Some(label) = read(file.path()) else {
+                continue;
+            };
+            if let Some(id) = label.strip_prefix("Package id ") {
+                package = id.parse().unwrap_or(0);
+            } else if let Some(id) = label.strip_prefix("Core ") {
+                let millidegrees = read(dir.join(format!("{}_input", sensor)))
+                    .and_then(|value| value.parse::<f64>().ok());
+                if let (Ok(core), Some(millidegrees)) = (id.parse::<u32>(), millidegrees) {
+                    cores.push((core, millidegrees / 1000.0));
+                }
+            }
+        }
+        for (core, celsius) in cores {
+            readings.insert((package, core), celsius);
+        }
     }
+    if readings.is_empty() {
+        return Vec::new();
+    }
+
+    (0..core_count)
+        .map(|index| {
+            let topology = cpu.join(format!("cpu{}", index)).join("topology");
+            let id = |name: &str| read(topology.join(name))?.parse::<u32>().ok();
+            id("physical_package_id")
+                .zip(id("core_id"))
+                .and_then(|key| readings.get(&key).copied())
+                .unwrap_or(0.0)
+        })
+        .collect()
 }
 
 fn collect_memory_metrics(sys: &System) -> MemoryMetrics {
@@ -166,7 +521,7 @@ fn collect_memory_metrics(sys: &System) -> MemoryMetrics {
 }
 
 fn collect_disk_metrics() -> Vec<DiskMetrics> {
-    let mut disks = DISKS.lock().unwrap();
+    let mut disks = DISKS.lock().unwrap_or_else(PoisonError::into_inner);
     disks.refresh(true);
 
     disks
@@ -195,8 +550,138 @@ fn collect_disk_metrics() -> Vec<DiskMetrics> {
         .collect()
 }
 
-fn collect_network_metrics() -> NetworkMetrics {
-    let mut networks = NETWORKS.lock().unwrap();
+/// 同一文件系统的多个挂载只保留一条，避免重复计算容量
+///
+/// 按文件系统身份识别同一文件系统，见 [`filesystem_identity`]
+fn dedup_disks(disks: Vec<DiskMetrics>) -> Vec<DiskMetrics> {
+    let overlays = overlay_upper_dirs();
+    dedup_disks_by(disks, |disk| filesystem_identity(disk, &overlays))
+}
+
+/// 文件系统身份
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FsIdentity {
+    /// 挂载点（overlay 取其 upperdir）所在文件系统的设备号 st_dev
+    Dev(u64),
+    /// 无法 stat 时退回块设备名（`/dev/` 开头）
+    Device(String),
+}
+
+/// 按 `identity` 合并同一文件系统的挂载
+///
+/// 保留的挂载优先选真实块设备（`/dev/` 开头），其次挂载点最短（与 df 相同），位置取该文件系统
+/// 首次出现处。身份未知的挂载（无法 stat 的 tmpfs 等伪文件系统）不参与合并。
+/// 不比较容量：同型号的两块空盘容量相同但仍是两块盘，同一文件系统的两次读数之间用量可能已变化
+fn dedup_disks_by(
+    disks: Vec<DiskMetrics>,
+    identity: impl Fn(&DiskMetrics) -> Option<FsIdentity>,
+) -> Vec<DiskMetrics> {
+    let is_primary_over = |candidate: &DiskMetrics, kept: &DiskMetrics| {
+        let rank = |disk: &DiskMetrics| (!disk.device.starts_with("/dev/"), disk.mount_point.len());
+        rank(candidate) < rank(kept)
+    };
+
+    let mut kept: Vec<(Option<FsIdentity>, DiskMetrics)> = Vec::with_capacity(disks.len());
+    for disk in disks {
+        let id = identity(&disk);
+        let duplicate = id
+            .as_ref()
+            .and_then(|id| kept.iter_mut().find(|(kept, _)| kept.as_ref() == Some(id)));
+        match duplicate {
+            Some((_, existing)) => {
+                if is_primary_over(&disk, existing) {
+                    *existing = disk;
+                }
+            }
+            None => kept.push((id, disk)),
+        }
+    }
+    kept.into_iter().map(|(_, disk)| disk).collect()
+}
+
+/// 挂载点所在文件系统的身份
+///
+/// overlay 的 st_dev 每个挂载各不相同，改取其 upperdir 所在（底层）文件系统，
+/// 使容器的 overlay 与底层设备合并
+#[cfg(unix)]
+fn filesystem_identity(
+    disk: &DiskMetrics,
+    overlays: &HashMap<String, std::path::PathBuf>,
+) -> Option<FsIdentity> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = overlays.get(&disk.mount_point).map_or_else(
+        || std::path::Path::new(&disk.mount_point),
+        |upper| upper.as_path(),
+    );
+    match std::fs::metadata(path) {
+        Ok(metadata) => Some(FsIdentity::Dev(metadata.dev())),
+        Err(_) => device_identity(disk),
+    }
+}
+
+#[cfg(not(unix))]
+fn filesystem_identity(
+    disk: &DiskMetrics,
+    _overlays: &HashMap<String, std::path::PathBuf>,
+) -> Option<FsIdentity> {
+    device_identity(disk)
+}
+
+/// 以块设备名作为身份，伪文件系统（tmpfs、overlay 等）没有可比较的设备名
+fn device_identity(disk: &DiskMetrics) -> Option<FsIdentity> {
+    disk.device
+        .starts_with("/dev/")
+        .then(|| FsIdentity::Device(disk.device.clone()))
+}
+
+/// 各 overlay 挂载点的 upperdir
+#[cfg(target_os = "linux")]
+fn overlay_upper_dirs() -> HashMap<String, std::path::PathBuf> {
+    std::fs::read_to_string("/proc/self/mounts")
+        .map(|mounts| parse_overlay_upper_dirs(&mounts))
+        .unwrap_or_default()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn overlay_upper_dirs() -> HashMap<String, std::path::PathBuf> {
+    HashMap::new()
+}
+
+/// 解析 /proc/self/mounts 中 overlay 挂载的 upperdir（字段中的空白以 `\040` 等八进制转义）
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_overlay_upper_dirs(mounts: &str) -> HashMap<String, std::path::PathBuf> {
+    let unescape = |field: &str| {
+        field
+            .replace("\\040", " ")
+            .replace("\\011", "\t")
+            .replace("\\012", "\n")
+            .replace("\\134", "\\")
+    };
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (_, mount_point, fs_type, options) = (
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+            );
+            if fs_type != "overlay" {
+                return None;
+            }
+            let upper = options
+                .split(',')
+                .find_map(|option| option.strip_prefix("upperdir="))?;
+            Some((unescape(mount_point), unescape(upper).into()))
+        })
+        .collect()
+}
+
+/// 采集网络指标，同时返回参与统计的接口数量
+fn collect_network_metrics() -> (NetworkMetrics, usize) {
+    let mut networks = NETWORKS.lock().unwrap_or_else(PoisonError::into_inner);
     networks.refresh(true);
 
     let mut bytes_sent = 0u64;
@@ -215,14 +700,15 @@ fn collect_network_metrics() -> NetworkMetrics {
         errors_out += network.total_errors_on_transmitted();
     }
 
-    NetworkMetrics {
+    let network = NetworkMetrics {
         bytes_sent,
         bytes_recv,
         packets_sent,
         packets_recv,
         errors_in,
         errors_out,
-    }
+    };
+    (network, networks.len())
 }
 
 fn collect_system_info(sys: &System) -> SystemInfo {
@@ -257,12 +743,6 @@ fn collect_system_info(sys: &System) -> SystemInfo {
         }
     };
 
-    // 获取主机名（优先使用环境变量 IRIS_HOSTNAME）
-    let hostname = std::env::var("IRIS_HOSTNAME")
-        .ok()
-        .or_else(System::host_name)
-        .unwrap_or_else(|| "Unknown".to_string());
-
     SystemInfo {
         os_name: System::name().unwrap_or_else(|| "Unknown".to_string()),
         os_version: System::os_version().unwrap_or_else(|| "Unknown".to_string()),
@@ -271,10 +751,354 @@ fn collect_system_info(sys: &System) -> SystemInfo {
         uptime: System::uptime(),
         cpu_model,
         cpu_frequency,
-        hostname,
+        // 主机名由 Agent 统一解析后填入，保证与上报身份一致
+        hostname: String::new(),
+        machine_id: MACHINE_ID.clone(),
+        boot_id: BOOT_ID.clone(),
     }
 }
 
+/// 读取机器唯一标识（systemd/dbus 的 machine-id），Server 用于识别 agent_id 冲突
+#[cfg(target_os = "linux")]
+fn read_machine_id() -> String {
+    ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .map(|id| id.trim().to_string())
+        .find(|id| !id.is_empty())
+        .unwrap_or_default()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_machine_id() -> String {
+    String::new()
+}
+
+/// 读取内核本次启动的 boot_id，Server 据此区分重启与 agent_id 冲突
+#[cfg(target_os = "linux")]
+fn read_boot_id() -> String {
+    read_boot_id_from(std::path::Path::new("/proc/sys/kernel/random/boot_id"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_boot_id() -> String {
+    String::new()
+}
+
+/// 读取 boot_id 文件，文件不存在或内容为空时返回空串
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn read_boot_id_from(path: &std::path::Path) -> String {
+    std::fs::read_to_string(path)
+        .map(|id| id.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// 采集 PSI 压力指标，内核未启用 PSI 时返回 None
+#[cfg(target_os = "linux")]
+fn collect_psi_metrics() -> Option<PressureMetrics> {
+    let read = |resource: &str| {
+        std::fs::read_to_string(format!("/proc/pressure/{}", resource))
+            .ok()
+            .and_then(|content| parse_psi(&content))
+    };
+
+    let pressure = PressureMetrics {
+        cpu: read("cpu"),
+        memory: read("memory"),
+        io: read("io"),
+    };
+    (pressure.cpu.is_some() || pressure.memory.is_some() || pressure.io.is_some())
+        .then_some(pressure)
+}
+
+/// 非 Linux 平台没有 PSI
+#[cfg(not(target_os = "linux"))]
+fn collect_psi_metrics() -> Option<PressureMetrics> {
+    None
+}
+
+/// 解析单个 PSI 文件，格式如：
+///
+/// ```text
+/// some avg10=0.12 avg60=0.05 avg300=0.01 total=123456
+/// full avg10=0.00 avg60=0.00 avg300=0.00 total=0
+/// ```
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_psi(content: &str) -> Option<PressureResource> {
+    let mut resource = PressureResource::default();
+
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let slot = match fields.next() {
+            Some("some") => &mut resource.some,
+            Some("full") => &mut resource.full,
+            _ => continue,
+        };
+
+        let mut stall = PressureStall::default();
+        for field in fields {
+            let Some((key, value)) = field.split_once('=') else {
+                continue;
+            };
+            match key {
+                "avg10" => stall.avg10 = value.parse().ok()?,
+                "avg60" => stall.avg60 = value.parse().ok()?,
+                "avg300" => stall.avg300 = value.parse().ok()?,
+                "total" => stall.total = value.parse().ok()?,
+                _ => {}
+            }
+        }
+        *slot = Some(stall);
+    }
+
+    (resource.some.is_some() || resource.full.is_some()).then_some(resource)
+}
+
+/// 统计僵尸进程数：单独扫描 /proc/<pid>/stat，不依赖 sysinfo 的进程刷新
+#[cfg(target_os = "linux")]
+fn collect_zombie_count() -> u32 {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return 0;
+    };
+    let stats = entries
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.bytes().all(|b| b.is_ascii_digit()))
+        })
+        // 进程可能在扫描期间退出，读不到的直接跳过
+        .filter_map(|entry| std::fs::read_to_string(entry.path().join("stat")).ok());
+    count_zombies(stats)
+}
+
+/// 非 Linux 平台不统计僵尸进程
+#[cfg(not(target_os = "linux"))]
+fn collect_zombie_count() -> u32 {
+    0
+}
+
+/// 统计状态为 `Z` 的进程数
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn count_zombies(stats: impl IntoIterator<Item = impl AsRef<str>>) -> u32 {
+    stats
+        .into_iter()
+        .filter(|stat| proc_state(stat.as_ref()) == Some('Z'))
+        .count() as u32
+}
+
+/// 从 /proc/<pid>/stat 取进程状态，格式如 `1234 (comm) Z 1 ...`
+///
+/// 进程名可能包含空格和括号，因此以最后一个 `)` 定位状态字段
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn proc_state(stat: &str) -> Option<char> {
+    stat.rsplit_once(')')?.1.trim_start().chars().next()
+}
+
+/// 按 CPU 使用率取前 `limit` 个进程
+///
+/// 全量刷新进程的 CPU/内存后排序，只为入选的进程额外读取 I/O 与线程数
+fn collect_top_processes(limit: usize) -> Vec<ProcessMetrics> {
+    let mut sys = lock_system();
+    sys.refresh_processes_specifics(
+        ProcessesToUpdate::All,
+        true,
+        ProcessRefreshKind::nothing().with_cpu().with_memory(),
+    );
+
+    let mut processes: Vec<_> = sys.processes().values().collect();
+    processes.sort_by(|a, b| b.cpu_usage().total_cmp(&a.cpu_usage()));
+    processes
+        .into_iter()
+        .take(limit)
+        .map(|process| {
+            let pid = process.pid().as_u32();
+            let (read_bytes, write_bytes) = read_process_io(pid);
+            ProcessMetrics {
+                pid,
+                name: process.name().to_string_lossy().into_owned(),
+                cpu_usage: process.cpu_usage() as f64,
+                memory: process.memory(),
+                read_bytes,
+                write_bytes,
+                thread_count: read_thread_count(pid),
+            }
+        })
+        .collect()
+}
+
+/// 读取进程累计的存储层读写字节数
+///
+/// 其他用户的进程通常无权读取 /proc/<pid>/io（EACCES），进程也可能已退出，均记为 0
+#[cfg(target_os = "linux")]
+fn read_process_io(pid: u32) -> (u64, u64) {
+    std::fs::read_to_string(format!("/proc/{}/io", pid))
+        .ok()
+        .and_then(|content| parse_proc_io(&content))
+        .unwrap_or_default()
+}
+
+/// 非 Linux 平台不采集进程 I/O
+#[cfg(not(target_os = "linux"))]
+fn read_process_io(_pid: u32) -> (u64, u64) {
+    (0, 0)
+}
+
+/// 统计 /proc/<pid>/task 下的线程数
+#[cfg(target_os = "linux")]
+fn read_thread_count(pid: u32) -> u32 {
+    std::fs::read_dir(format!("/proc/{}/task", pid))
+        .map(|entries| entries.count() as u32)
+        .unwrap_or_default()
+}
+
+/// 非 Linux 平台不统计线程数
+#[cfg(not(target_os = "linux"))]
+fn read_thread_count(_pid: u32) -> u32 {
+    0
+}
+
+/// 解析 /proc/<pid>/io，返回 (read_bytes, write_bytes)
+///
+/// 取实际到达存储层的 `read_bytes`/`write_bytes`，而不是包含页缓存命中的 `rchar`/`wchar`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_proc_io(content: &str) -> Option<(u64, u64)> {
+    let mut read_bytes = None;
+    let mut write_bytes = None;
+    for line in content.lines() {
+        match line.split_once(':') {
+            Some(("read_bytes", value)) => read_bytes = value.trim().parse().ok(),
+            Some(("write_bytes", value)) => write_bytes = value.trim().parse().ok(),
+            _ => {}
+        }
+    }
+    Some((read_bytes?, write_bytes?))
+}
+
+/// 采集文件描述符使用情况，`self_metrics` 为 false 时不统计探针进程自身
+#[cfg(target_os = "linux")]
+fn collect_fd_metrics(self_metrics: bool) -> Option<FileDescriptorMetrics> {
+    let mut fds = std::fs::read_to_string("/proc/sys/fs/file-nr")
+        .ok()
+        .and_then(|content| parse_file_nr(&content))?;
+    if self_metrics {
+        // 包含 read_dir 自身临时打开的目录描述符
+        fds.agent_open = std::fs::read_dir("/proc/self/fd")
+            .map(|entries| entries.count() as u64)
+            .unwrap_or_default();
+    }
+    Some(fds)
+}
+
+/// 非 Linux 平台不采集文件描述符
+#[cfg(not(target_os = "linux"))]
+fn collect_fd_metrics(_self_metrics: bool) -> Option<FileDescriptorMetrics> {
+    None
+}
+
+/// 解析 /proc/sys/fs/file-nr，格式为 `已分配 空闲 上限`，如 `1824\t0\t9223372036854775807`
+///
+/// 第三列即 fs.file-max；2.6 之后的内核空闲列恒为 0
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_file_nr(content: &str) -> Option<FileDescriptorMetrics> {
+    let mut fields = content.split_whitespace().map(str::parse::<u64>);
+    let allocated = fields.next()?.ok()?;
+    let free = fields.next()?.ok()?;
+    let max = fields.next()?.ok()?;
+    Some(FileDescriptorMetrics {
+        open: allocated.saturating_sub(free),
+        max,
+        agent_open: 0,
+    })
+}
+
+/// 采集内核熵池，读取失败时返回 None
+#[cfg(target_os = "linux")]
+fn collect_entropy_metrics() -> Option<EntropyMetrics> {
+    let read = |name: &str| {
+        std::fs::read_to_string(format!("/proc/sys/kernel/random/{}", name))
+            .ok()
+            .and_then(|content| parse_entropy_value(&content))
+    };
+    Some(EntropyMetrics {
+        available: read("entropy_avail")?,
+        pool_size: read("poolsize").unwrap_or_default(),
+    })
+}
+
+/// 非 Linux 平台不采集熵池
+#[cfg(not(target_os = "linux"))]
+fn collect_entropy_metrics() -> Option<EntropyMetrics> {
+    None
+}
+
+/// 解析 /proc/sys/kernel/random 下的单值文件，如 `256\n`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_entropy_value(content: &str) -> Option<u64> {
+    content.trim().parse().ok()
+}
+
+/// 采集各 NUMA 节点的内存，单节点主机只有一项（即整机合计）
+#[cfg(target_os = "linux")]
+fn collect_numa_metrics() -> Vec<NumaNodeMemory> {
+    read_numa_nodes(std::path::Path::new("/sys/devices/system/node"))
+}
+
+/// 非 Linux 平台不采集 NUMA 节点
+#[cfg(not(target_os = "linux"))]
+fn collect_numa_metrics() -> Vec<NumaNodeMemory> {
+    Vec::new()
+}
+
+/// 读取 `root` 下各 `node<N>/meminfo`，按节点编号排序；无法解析的节点跳过
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn read_numa_nodes(root: &std::path::Path) -> Vec<NumaNodeMemory> {
+    let mut nodes: Vec<_> = std::fs::read_dir(root)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let node = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("node")?
+                .parse::<u32>()
+                .ok()?;
+            let content = std::fs::read_to_string(entry.path().join("meminfo")).ok()?;
+            parse_node_meminfo(node, &content)
+        })
+        .collect();
+    nodes.sort_by_key(|node| node.node);
+    nodes
+}
+
+/// 解析节点 meminfo，行格式为 `Node 0 MemTotal:       32780000 kB`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_node_meminfo(node: u32, content: &str) -> Option<NumaNodeMemory> {
+    let mut total = None;
+    let mut free = None;
+    for line in content.lines() {
+        let mut fields = line.split_whitespace().skip(2);
+        let (Some(key), Some(kib)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let Ok(kib) = kib.parse::<u64>() else {
+            continue;
+        };
+        match key {
+            "MemTotal:" => total = Some(kib * 1024),
+            "MemFree:" => free = Some(kib * 1024),
+            _ => {}
+        }
+    }
+    Some(NumaNodeMemory {
+        node,
+        total: total?,
+        free: free.unwrap_or_default(),
+    })
+}
+
 /// 直接从 /proc/cpuinfo 读取 CPU 信息（Linux 备用方案）
 #[cfg(target_os = "linux")]
 fn read_cpu_info_from_proc() -> Option<(String, f64)> {
@@ -307,3 +1131,447 @@ fn read_cpu_info_from_proc() -> Option<(String, f64)> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collection_time_ewma_tracks_samples() {
+        let mut ewma = Ewma::new(COLLECTION_TIME_ALPHA);
+        assert_eq!(ewma.update(10.0), 10.0);
+
+        // 单次尖峰只小幅抬高平均值
+        let spiked = ewma.update(110.0);
+        assert!((spiked - 20.0).abs() < 1e-9);
+
+        // 持续变慢时平均值逐步逼近新的耗时
+        let mut avg = spiked;
+        for _ in 0..50 {
+            let next = ewma.update(50.0);
+            assert!(next <= 50.0 && (next - 50.0).abs() <= (avg - 50.0).abs());
+            avg = next;
+        }
+        assert!((avg - 50.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_narrowed_self_refresh_is_cheaper() {
+        let pid = Pid::from_u32(std::process::id());
+        let mut sys = System::new();
+
+        // 每轮刷新 10 次，取多轮中的最小耗时以降低调度抖动影响
+        let mut measure = |kind: ProcessRefreshKind| {
+            (0..5)
+                .map(|_| {
+                    let start = Instant::now();
+                    for _ in 0..10 {
+                        sys.refresh_processes_specifics(
+                            ProcessesToUpdate::Some(&[pid]),
+                            false,
+                            kind,
+                        );
+                    }
+                    start.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+
+        let everything = measure(ProcessRefreshKind::everything());
+        let narrowed = measure(self_process_refresh_kind());
+        assert!(
+            narrowed < everything,
+            "narrowed={:?} everything={:?}",
+            narrowed,
+            everything
+        );
+    }
+
+    #[test]
+    fn test_self_metrics_populated_with_narrowed_refresh() {
+        let metrics = collect_metrics_with(&CollectOptions::default());
+        let agent = metrics.agent_metrics.unwrap();
+        assert!(agent.memory_usage > 0);
+    }
+
+    #[test]
+    fn test_failing_disk_collector_reported_in_status() {
+        fn failing_disks() -> Vec<DiskMetrics> {
+            panic!("disk enumeration failed");
+        }
+
+        let metrics = collect_metrics_using(&CollectOptions::default(), failing_disks);
+
+        let disk_status = metrics
+            .collector_status
+            .iter()
+            .find(|s| s.subsystem == "disks")
+            .unwrap();
+        assert_eq!(disk_status.state, CollectorState::Failed as i32);
+        assert!(disk_status.message.contains("disk enumeration failed"));
+        assert!(metrics.disks.is_empty());
+
+        // 其他子系统不受影响
+        assert!(metrics.cpu.is_some());
+        assert!(metrics.memory.unwrap().total > 0);
+        assert!(metrics.network.is_some());
+        assert!(metrics.agent_metrics.is_some());
+        for subsystem in ["cpu", "memory", "system_info", "agent"] {
+            let status = metrics
+                .collector_status
+                .iter()
+                .find(|s| s.subsystem == subsystem)
+                .unwrap();
+            assert_eq!(status.state, CollectorState::Ok as i32, "{}", subsystem);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_mounts_collapsed() {
+        fn disk(mount_point: &str, device: &str, total: u64, used: u64) -> DiskMetrics {
+            DiskMetrics {
+                mount_point: mount_point.to_string(),
+                device: device.to_string(),
+                total,
+                used,
+                available: total - used,
+                ..Default::default()
+            }
+        }
+        fn mounts() -> Vec<DiskMetrics> {
+            vec![
+                // 容器主机：overlay 与底层设备、绑定挂载都指向同一文件系统；
+                // 两次 statvfs 之间有写入，各挂载读到的用量不完全相同
+                disk("/var/lib/docker/overlay2/abc/merged", "overlay", 500, 201),
+                disk("/", "/dev/sda1", 500, 200),
+                disk("/etc/hosts", "/dev/sda1", 500, 202),
+                disk("/data", "/dev/sdb1", 2000, 1500),
+                disk("/mnt/data", "/dev/sdb1", 2000, 1500),
+                // 同型号的两块空盘容量相同，但是两个文件系统
+                disk("/srv/a", "/dev/sdc1", 1000, 0),
+                disk("/srv/b", "/dev/sdd1", 1000, 0),
+                // 两个 tmpfs 各自独立
+                disk("/dev/shm", "tmpfs", 64, 0),
+                disk("/run/lock", "tmpfs", 64, 0),
+            ]
+        }
+        // 模拟 stat 结果：overlay 的 upperdir 在 sda1 上
+        let identity = |disk: &DiskMetrics| {
+            let dev = match disk.mount_point.as_str() {
+                "/" | "/etc/hosts" | "/var/lib/docker/overlay2/abc/merged" => 1,
+                "/data" | "/mnt/data" => 2,
+                "/srv/a" => 3,
+                "/srv/b" => 4,
+                "/dev/shm" => 5,
+                "/run/lock" => 6,
+                _ => return None,
+            };
+            Some(FsIdentity::Dev(dev))
+        };
+
+        let deduped = dedup_disks_by(mounts(), identity);
+        let summary: Vec<_> = deduped
+            .iter()
+            .map(|d| (d.mount_point.as_str(), d.device.as_str(), d.used))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("/", "/dev/sda1", 200),
+                ("/data", "/dev/sdb1", 1500),
+                ("/srv/a", "/dev/sdc1", 0),
+                ("/srv/b", "/dev/sdd1", 0),
+                ("/dev/shm", "tmpfs", 0),
+                ("/run/lock", "tmpfs", 0),
+            ]
+        );
+        let total: u64 = deduped.iter().map(|d| d.total).sum();
+        assert_eq!(total, 500 + 2000 + 1000 + 1000 + 64 + 64);
+
+        // 无法 stat 时按块设备名合并，伪文件系统不合并
+        let deduped = dedup_disks_by(mounts(), device_identity);
+        let devices: Vec<_> = deduped.iter().map(|d| d.device.as_str()).collect();
+        assert_eq!(
+            devices,
+            [
+                "overlay",
+                "/dev/sda1",
+                "/dev/sdb1",
+                "/dev/sdc1",
+                "/dev/sdd1",
+                "tmpfs",
+                "tmpfs"
+            ]
+        );
+
+        let upper = parse_overlay_upper_dirs(
+            "/dev/sda1 / ext4 rw,relatime 0 0\n\
+             overlay /var/lib/docker/overlay2/abc/merged overlay \
+             rw,lowerdir=/l1:/l2,upperdir=/var/lib/docker/overlay2/abc/diff,workdir=/w 0 0\n\
+             overlay /mnt/my\\040root overlay rw,upperdir=/up\\040per,workdir=/w 0 0\n",
+        );
+        assert_eq!(
+            upper["/var/lib/docker/overlay2/abc/merged"],
+            std::path::PathBuf::from("/var/lib/docker/overlay2/abc/diff")
+        );
+        assert_eq!(upper["/mnt/my root"], std::path::PathBuf::from("/up per"));
+        assert_eq!(upper.len(), 2);
+
+        // 开启 all_mounts 时保留全部挂载点
+        let options = CollectOptions {
+            all_mounts: true,
+            ..Default::default()
+        };
+        assert_eq!(collect_metrics_using(&options, mounts).disks.len(), 9);
+    }
+
+    #[test]
+    fn test_self_metrics_disabled_skips_process_refresh() {
+        let metrics = collect_metrics_with(&CollectOptions {
+            self_metrics: false,
+            ..Default::default()
+        });
+        let agent = metrics.agent_metrics.unwrap();
+        assert_eq!(agent.cpu_usage, 0.0);
+        assert_eq!(agent.memory_usage, 0);
+        // 其他指标不受影响
+        assert!(metrics.memory.unwrap().total > 0);
+        // 默认不采集进程列表与 NUMA 节点
+        assert!(metrics.top_processes.is_empty());
+        assert!(metrics.numa_nodes.is_empty());
+        assert!(!metrics
+            .collector_status
+            .iter()
+            .any(|status| status.subsystem == "numa"));
+    }
+
+    #[test]
+    fn test_numa_collected_when_enabled() {
+        let metrics = collect_metrics_with(&CollectOptions {
+            numa: true,
+            ..Default::default()
+        });
+        assert!(metrics
+            .collector_status
+            .iter()
+            .any(|status| status.subsystem == "numa"));
+    }
+
+    #[test]
+    fn test_top_processes_limited() {
+        let metrics = collect_metrics_with(&CollectOptions {
+            top_processes: 2,
+            ..Default::default()
+        });
+        assert!(!metrics.top_processes.is_empty());
+        assert!(metrics.top_processes.len() <= 2);
+        for process in &metrics.top_processes {
+            assert!(process.pid > 0);
+            #[cfg(target_os = "linux")]
+            assert!(process.thread_count >= 1);
+        }
+    }
+
+    #[test]
+    fn test_count_zombies() {
+        let table = [
+            "1 (systemd) S 0 1 1 0 -1 4194560 ...",
+            "812 (worker) Z 811 811 811 0 -1 4227084 ...",
+            // 进程名中含空格与括号
+            "813 (my (odd) proc) Z 811 811 811 0 -1 4227084 ...",
+            "900 (bash) R 899 900 900 34816 ...",
+            "901 (kthreadd) I 2 0 0 0 -1 ...",
+            "truncated",
+        ];
+        assert_eq!(count_zombies(table), 2);
+        assert_eq!(count_zombies(Vec::<String>::new()), 0);
+        assert_eq!(proc_state("813 (my (odd) proc) Z 811"), Some('Z'));
+    }
+
+    #[test]
+    fn test_parse_file_nr() {
+        let fds = parse_file_nr("1824\t0\t9223372036854775807\n").unwrap();
+        assert_eq!(fds.open, 1824);
+        assert_eq!(fds.max, 9223372036854775807);
+        assert_eq!(fds.agent_open, 0);
+
+        // 旧内核空闲列非 0 时扣除
+        assert_eq!(parse_file_nr("3072 512 65536").unwrap().open, 2560);
+
+        assert!(parse_file_nr("").is_none());
+        assert!(parse_file_nr("1824\t0").is_none());
+        assert!(parse_file_nr("abc 0 65536").is_none());
+    }
+
+    #[test]
+    fn test_parse_entropy_value() {
+        assert_eq!(parse_entropy_value("256\n"), Some(256));
+        assert_eq!(parse_entropy_value("3840"), Some(3840));
+        assert_eq!(parse_entropy_value(""), None);
+        assert_eq!(parse_entropy_value("-1\n"), None);
+    }
+
+    #[test]
+    fn test_parse_proc_io() {
+        let content = "rchar: 323934931\n\
+                       wchar: 323929600\n\
+                       syscr: 632687\n\
+                       syscw: 632675\n\
+                       read_bytes: 4096\n\
+                       write_bytes: 323932160\n\
+                       cancelled_write_bytes: 0\n";
+        assert_eq!(parse_proc_io(content), Some((4096, 323932160)));
+
+        assert_eq!(parse_proc_io(""), None);
+        assert_eq!(parse_proc_io("read_bytes: 1\n"), None);
+    }
+
+    #[test]
+    fn test_per_core_frequency_aligned_with_cores() {
+        let cpu = {
+            let mut sys = lock_system();
+            sys.refresh_cpu_usage();
+            sys.refresh_cpu_frequency();
+            collect_cpu_metrics(&sys)
+        };
+        assert_eq!(cpu.per_core_frequency.len(), cpu.core_count as usize);
+        assert_eq!(cpu.per_core.len(), cpu.core_count as usize);
+        assert!(
+            cpu.per_core_temperature.is_empty()
+                || cpu.per_core_temperature.len() == cpu.core_count as usize
+        );
+    }
+
+    #[test]
+    fn test_read_numa_nodes() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(read_numa_nodes(&root.path().join("missing")).is_empty());
+
+        for (node, total, free) in [(1, 33_554_432, 1_048_576), (0, 33_554_432, 20_971_520)] {
+            let dir = root.path().join(format!("node{}", node));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(
+                dir.join("meminfo"),
+                format!(
+                    "Node {0} MemTotal:       {1} kB\n\
+                     Node {0} MemFree:        {2} kB\n\
+                     Node {0} MemUsed:        {3} kB\n\
+                     Node {0} HugePages_Total:     0\n",
+                    node,
+                    total,
+                    free,
+                    total - free
+                ),
+            )
+            .unwrap();
+        }
+        // 与节点目录并列的其他文件被忽略
+        std::fs::write(root.path().join("possible"), "0-1\n").unwrap();
+        std::fs::create_dir_all(root.path().join("power")).unwrap();
+
+        let nodes = read_numa_nodes(root.path());
+        assert_eq!(
+            nodes,
+            vec![
+                NumaNodeMemory {
+                    node: 0,
+                    total: 33_554_432 * 1024,
+                    free: 20_971_520 * 1024,
+                },
+                NumaNodeMemory {
+                    node: 1,
+                    total: 33_554_432 * 1024,
+                    free: 1_048_576 * 1024,
+                },
+            ]
+        );
+
+        assert_eq!(parse_node_meminfo(0, "Node 0 MemFree: 1024 kB\n"), None);
+    }
+
+    #[test]
+    fn test_read_core_temperatures() {
+        let root = tempfile::tempdir().unwrap();
+        let hwmon = root.path().join("hwmon");
+        let cpu = root.path().join("cpu");
+        let write = |path: std::path::PathBuf, content: &str| {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+
+        std::fs::create_dir_all(&hwmon).unwrap();
+        assert!(read_core_temperatures(&hwmon, &cpu, 4).is_empty());
+
+        // 非 coretemp 的传感器被忽略
+        write(hwmon.join("hwmon0/name"), "acpitz\n");
+        write(hwmon.join("hwmon0/temp1_label"), "Core 0\n");
+        write(hwmon.join("hwmon0/temp1_input"), "90000\n");
+        write(hwmon.join("hwmon1/name"), "coretemp\n");
+        write(hwmon.join("hwmon1/temp1_label"), "Package id 0\n");
+        write(hwmon.join("hwmon1/temp1_input"), "50000\n");
+        write(hwmon.join("hwmon1/temp2_label"), "Core 0\n");
+        write(hwmon.join("hwmon1/temp2_input"), "45000\n");
+        write(hwmon.join("hwmon1/temp3_label"), "Core 1\n");
+        write(hwmon.join("hwmon1/temp3_input"), "47500\n");
+
+        // cpu2 是 cpu0 的超线程兄弟；cpu3 没有拓扑信息
+        for (index, core) in [(0, "0"), (1, "1"), (2, "0")] {
+            let topology = cpu.join(format!("cpu{}/topology", index));
+            write(topology.join("physical_package_id"), "0\n");
+            write(topology.join("core_id"), core);
+        }
+
+        assert_eq!(
+            read_core_temperatures(&hwmon, &cpu, 4),
+            vec![45.0, 47.5, 45.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn test_read_boot_id_from() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("boot_id");
+        std::fs::write(&path, "6f1c2a9e-3b4d-4e5f-8a7b-9c0d1e2f3a4b\n").unwrap();
+        assert_eq!(
+            read_boot_id_from(&path),
+            "6f1c2a9e-3b4d-4e5f-8a7b-9c0d1e2f3a4b"
+        );
+        assert_eq!(read_boot_id_from(&dir.path().join("missing")), "");
+    }
+
+    #[test]
+    fn test_parse_psi() {
+        let memory = parse_psi(
+            "some avg10=1.25 avg60=0.50 avg300=0.10 total=123456\n\
+             full avg10=0.75 avg60=0.20 avg300=0.05 total=6543\n",
+        )
+        .unwrap();
+        assert_eq!(
+            memory.some,
+            Some(PressureStall {
+                avg10: 1.25,
+                avg60: 0.50,
+                avg300: 0.10,
+                total: 123456,
+            })
+        );
+        assert_eq!(
+            memory.full,
+            Some(PressureStall {
+                avg10: 0.75,
+                avg60: 0.20,
+                avg300: 0.05,
+                total: 6543,
+            })
+        );
+
+        // 旧内核的 cpu 文件只有 some 行
+        let cpu = parse_psi("some avg10=3.00 avg60=2.00 avg300=1.00 total=42\n").unwrap();
+        assert_eq!(cpu.some.unwrap().avg60, 2.0);
+        assert!(cpu.full.is_none());
+
+        assert!(parse_psi("").is_none());
+        assert!(parse_psi("some avg10=abc avg60=0 avg300=0 total=0\n").is_none());
+    }
+}
@@ -0,0 +1,404 @@
+//! Agent 配置文件（TOML）
+//!
+//! 配置文件中的每一项都可由同名命令行参数覆盖，优先级为命令行 > 配置文件 > 内置默认值。
+//! 命令行参数同样解析成一个 [`AgentConfig`]，再用 [`AgentConfig::or`] 叠加到配置文件之上
+
+use crate::{
+    Agent, CollectOptions, HostnameMode, Proxy, ReportMode, Spool, SpoolAggregation, SpoolOptions,
+    TimestampSource, DEFAULT_AGGREGATE_BUCKET_SECS, DEFAULT_SPOOL_MAX_MB,
+};
+use anyhow::{bail, Context, Result};
+use serde::{de, Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// 默认 Server 地址
+pub const DEFAULT_SERVER: &str = "http://127.0.0.1:50051";
+/// 默认上报间隔（秒）
+pub const DEFAULT_INTERVAL_SECS: u64 = 1;
+
+/// Agent 配置，未设置的项为 None，由优先级更低的来源或默认值补全
+///
+/// 出现未知的键时解析失败
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentConfig {
+    /// Server 地址列表（http://host:port 或 unix:/path）
+    pub servers: Option<Vec<String>>,
+    /// 多个 Server 时的上报方式
+    #[serde(default, deserialize_with = "from_str_opt")]
+    pub mode: Option<ReportMode>,
+    /// 主机名解析方式
+    #[serde(default, deserialize_with = "from_str_opt")]
+    pub hostname_mode: Option<HostnameMode>,
+    /// 样本时间戳来源
+    #[serde(default, deserialize_with = "from_str_opt")]
+    pub timestamp_source: Option<TimestampSource>,
+    /// 上报间隔（秒）
+    pub interval: Option<u64>,
+    /// 出站代理（http:// 或 socks5://），未设置时读取 HTTPS_PROXY/ALL_PROXY
+    #[serde(default, deserialize_with = "from_str_opt")]
+    pub proxy: Option<Proxy>,
+    /// 附加到每条样本的部署标签
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// 采集子系统开关
+    #[serde(default)]
+    pub collectors: CollectorsConfig,
+    /// 离线缓冲
+    #[serde(default)]
+    pub spool: SpoolConfig,
+    /// 与 Server 之间的 TLS
+    #[serde(default)]
+    pub tls: TlsConfig,
+}
+
+/// 采集子系统开关
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CollectorsConfig {
+    /// 是否采集探针自身进程的 CPU/内存
+    pub self_metrics: Option<bool>,
+    /// 上报 CPU 使用率最高的进程数，0 表示不采集
+    pub top_processes: Option<usize>,
+    /// 上报全部挂载点，不合并同一文件系统的多个挂载
+    pub all_mounts: Option<bool>,
+    /// 上报 systemd 失败单元
+    pub systemd: Option<bool>,
+    /// 上报 Docker 容器统计
+    pub docker: Option<bool>,
+    /// 上报各 NUMA 节点的内存
+    pub numa: Option<bool>,
+}
+
+/// 离线缓冲，未设置目录时不启用
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpoolConfig {
+    /// 缓冲目录
+    pub dir: Option<PathBuf>,
+    /// 缓冲上限（MiB），超出后丢弃最旧的样本
+    pub max_mb: Option<u64>,
+    /// 是否以 gzip 压缩存储
+    pub compress: Option<bool>,
+    /// 补发时把早于该秒数的样本按时间桶聚合，未设置时逐条补发
+    pub aggregate_after_secs: Option<u64>,
+    /// 聚合的时间桶宽度（秒）
+    pub aggregate_bucket_secs: Option<u64>,
+}
+
+/// 与 Server 之间的 TLS，未设置任何一项时使用明文连接
+///
+/// 当前构建的 gRPC 客户端不含 TLS 实现：配置了任意一项时启动失败，而不是静默地明文连接。
+/// 需要加密时请在 Server 前放置 TLS 终结代理
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// 校验 Server 证书的 CA 证书（PEM）
+    pub ca_cert: Option<PathBuf>,
+    /// 客户端证书（PEM，双向 TLS），需与 `key` 同时设置
+    pub cert: Option<PathBuf>,
+    /// 客户端私钥（PEM，双向 TLS），需与 `cert` 同时设置
+    pub key: Option<PathBuf>,
+    /// 校验证书时使用的域名，默认取 Server 地址中的主机名
+    pub domain: Option<String>,
+}
+
+impl TlsConfig {
+    /// 是否设置了任意一项
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// 检查证书配置：客户端证书与私钥需成对出现，且各文件都可读取
+    fn validate(&self) -> Result<()> {
+        if self.cert.is_some() != self.key.is_some() {
+            bail!("tls.cert 与 tls.key 需同时设置");
+        }
+        for (name, path) in [
+            ("tls.ca_cert", &self.ca_cert),
+            ("tls.cert", &self.cert),
+            ("tls.key", &self.key),
+        ] {
+            if let Some(path) = path {
+                std::fs::metadata(path)
+                    .with_context(|| format!("读取 {} ({}) 失败", name, path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 按字符串读取并用 `FromStr` 解析，与命令行参数共用同一套取值
+fn from_str_opt<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| value.parse().map_err(de::Error::custom))
+        .transpose()
+}
+
+impl AgentConfig {
+    /// 读取并解析配置文件
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("读取配置文件 {} 失败", path.display()))?;
+        Self::from_toml(&content).with_context(|| format!("解析配置文件 {} 失败", path.display()))
+    }
+
+    /// 解析 TOML 文本
+    pub fn from_toml(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// 以 `self` 中已设置的项覆盖 `base`；labels 按键合并，同名键以 `self` 为准
+    pub fn or(self, base: AgentConfig) -> AgentConfig {
+        let mut labels = base.labels;
+        labels.extend(self.labels);
+        AgentConfig {
+            servers: self.servers.or(base.servers),
+            mode: self.mode.or(base.mode),
+            hostname_mode: self.hostname_mode.or(base.hostname_mode),
+            timestamp_source: self.timestamp_source.or(base.timestamp_source),
+            interval: self.interval.or(base.interval),
+            proxy: self.proxy.or(base.proxy),
+            labels,
+            collectors: CollectorsConfig {
+                self_metrics: self
+                    .collectors
+                    .self_metrics
+                    .or(base.collectors.self_metrics),
+                top_processes: self
+                    .collectors
+                    .top_processes
+                    .or(base.collectors.top_processes),
+                all_mounts: self.collectors.all_mounts.or(base.collectors.all_mounts),
+                systemd: self.collectors.systemd.or(base.collectors.systemd),
+                docker: self.collectors.docker.or(base.collectors.docker),
+                numa: self.collectors.numa.or(base.collectors.numa),
+            },
+            spool: SpoolConfig {
+                dir: self.spool.dir.or(base.spool.dir),
+                max_mb: self.spool.max_mb.or(base.spool.max_mb),
+                compress: self.spool.compress.or(base.spool.compress),
+                aggregate_after_secs: self
+                    .spool
+                    .aggregate_after_secs
+                    .or(base.spool.aggregate_after_secs),
+                aggregate_bucket_secs: self
+                    .spool
+                    .aggregate_bucket_secs
+                    .or(base.spool.aggregate_bucket_secs),
+            },
+            tls: TlsConfig {
+                ca_cert: self.tls.ca_cert.or(base.tls.ca_cert),
+                cert: self.tls.cert.or(base.tls.cert),
+                key: self.tls.key.or(base.tls.key),
+                domain: self.tls.domain.or(base.tls.domain),
+            },
+        }
+    }
+
+    /// Server 地址列表，未设置或为空时为默认地址
+    pub fn servers(&self) -> Vec<String> {
+        match &self.servers {
+            Some(servers) if !servers.is_empty() => servers.clone(),
+            _ => vec![DEFAULT_SERVER.to_string()],
+        }
+    }
+
+    /// 上报间隔（秒）
+    pub fn interval(&self) -> u64 {
+        self.interval.unwrap_or(DEFAULT_INTERVAL_SECS)
+    }
+
+    /// 出站代理：配置优先，否则取环境变量；`NO_PROXY` 中的主机直连
+    pub fn proxy(&self) -> Option<Proxy> {
+        self.proxy
+            .clone()
+            .or_else(Proxy::from_env)
+            .map(Proxy::with_no_proxy_from_env)
+    }
+
+    /// 离线缓冲选项，未设置目录时为 None
+    pub fn spool(&self) -> Option<SpoolOptions> {
+        Some(SpoolOptions {
+            dir: self.spool.dir.clone()?,
+            max_bytes: self.spool.max_mb.unwrap_or(DEFAULT_SPOOL_MAX_MB) << 20,
+            compress: self.spool.compress.unwrap_or(true),
+        })
+    }
+
+    /// 补发离线缓冲时的聚合选项，未设置 `aggregate_after_secs` 时为 None
+    pub fn spool_aggregation(&self) -> Option<SpoolAggregation> {
+        Some(SpoolAggregation {
+            after: Duration::from_secs(self.spool.aggregate_after_secs?),
+            bucket: Duration::from_secs(
+                self.spool
+                    .aggregate_bucket_secs
+                    .unwrap_or(DEFAULT_AGGREGATE_BUCKET_SECS),
+            ),
+        })
+    }
+
+    /// 补全内置默认值后构造 Agent
+    ///
+    /// 启用离线缓冲且目录无法创建，或配置了 TLS（当前构建不支持）时返回错误
+    pub fn build(self) -> Result<Agent> {
+        if self.tls.is_enabled() {
+            self.tls.validate()?;
+            bail!("配置了 [tls]，但当前构建不支持 TLS；需要加密时请在 Server 前放置 TLS 终结代理");
+        }
+        let spool = self.spool().map(Spool::open).transpose()?;
+        let spool_aggregation = self.spool_aggregation();
+        Ok(Agent::new(self.servers(), self.interval())
+            .with_proxy(self.proxy())
+            .with_report_mode(self.mode.unwrap_or_default())
+            .with_hostname_mode(self.hostname_mode.unwrap_or_default())
+            .with_timestamp_source(self.timestamp_source.unwrap_or_default())
+            .with_labels(self.labels)
+            .with_collect_options(CollectOptions {
+                self_metrics: self.collectors.self_metrics.unwrap_or(true),
+                top_processes: self.collectors.top_processes.unwrap_or_default(),
+                all_mounts: self.collectors.all_mounts.unwrap_or_default(),
+                systemd: self.collectors.systemd.unwrap_or_default(),
+                docker: self.collectors.docker.unwrap_or_default(),
+                numa: self.collectors.numa.unwrap_or_default(),
+            })
+            .with_spool_aggregation(spool_aggregation)
+            .with_spool(spool))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = include_str!("../iris-agent.example.toml");
+
+    #[test]
+    fn test_example_config_loads() {
+        let config = AgentConfig::from_toml(EXAMPLE).unwrap();
+        assert_eq!(
+            config.servers(),
+            vec!["http://10.0.0.1:50051", "http://10.0.0.2:50051"]
+        );
+        assert_eq!(config.mode, Some(ReportMode::Failover));
+        assert_eq!(config.hostname_mode, Some(HostnameMode::Short));
+        assert_eq!(config.interval(), 5);
+        assert_eq!(config.labels["env"], "prod");
+        assert_eq!(config.collectors.self_metrics, Some(true));
+        assert_eq!(config.collectors.top_processes, Some(5));
+        assert_eq!(config.collectors.all_mounts, Some(false));
+        assert_eq!(config.collectors.systemd, Some(false));
+        assert_eq!(config.collectors.docker, Some(false));
+        assert_eq!(config.collectors.numa, Some(false));
+        let spool = config.spool().unwrap();
+        assert_eq!(spool.dir, PathBuf::from("/var/lib/iris/spool"));
+        assert_eq!(spool.max_bytes, 256 << 20);
+        assert!(spool.compress);
+        let aggregation = config.spool_aggregation().unwrap();
+        assert_eq!(aggregation.after, Duration::from_secs(600));
+        assert_eq!(
+            aggregation.bucket,
+            Duration::from_secs(DEFAULT_AGGREGATE_BUCKET_SECS)
+        );
+        assert_eq!(AgentConfig::default().spool_aggregation(), None);
+        assert!(!config.tls.is_enabled());
+    }
+
+    #[test]
+    fn test_cli_overrides_file_overrides_defaults() {
+        let file = AgentConfig::from_toml(EXAMPLE).unwrap();
+        let cli = AgentConfig {
+            interval: Some(10),
+            mode: Some(ReportMode::Broadcast),
+            labels: BTreeMap::from([("env".to_string(), "staging".to_string())]),
+            ..Default::default()
+        };
+
+        let merged = cli.or(file);
+        // 命令行优先
+        assert_eq!(merged.interval(), 10);
+        assert_eq!(merged.mode, Some(ReportMode::Broadcast));
+        assert_eq!(merged.labels["env"], "staging");
+        // 命令行未指定时取配置文件
+        assert_eq!(merged.servers().len(), 2);
+        assert_eq!(merged.hostname_mode, Some(HostnameMode::Short));
+        assert_eq!(merged.labels["region"], "cn-east");
+
+        // 两者都未指定时取默认值
+        let defaults = AgentConfig::default().or(AgentConfig::default());
+        assert_eq!(defaults.servers(), vec![DEFAULT_SERVER]);
+        assert_eq!(defaults.interval(), DEFAULT_INTERVAL_SECS);
+        assert!(!defaults.tls.is_enabled());
+    }
+
+    #[test]
+    fn test_tls_section_validated() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let cert = temp_dir.path().join("client.pem");
+        std::fs::write(&cert, "cert").unwrap();
+
+        let file = AgentConfig::from_toml(&format!(
+            "[tls]\ncert = {:?}\ndomain = \"iris.example.com\"",
+            cert
+        ))
+        .unwrap();
+        let cli = AgentConfig {
+            tls: TlsConfig {
+                domain: Some("iris.internal".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let merged = cli.or(file);
+        assert_eq!(merged.tls.cert.as_ref(), Some(&cert));
+        assert_eq!(merged.tls.domain.as_deref(), Some("iris.internal"));
+
+        // 只有证书没有私钥
+        let err = merged.tls.validate().unwrap_err();
+        assert!(err.to_string().contains("tls.key"), "{:#}", err);
+        // 文件不存在
+        let missing = TlsConfig {
+            ca_cert: Some(temp_dir.path().join("missing.pem")),
+            ..Default::default()
+        };
+        assert!(missing.validate().is_err());
+        // 配置正确时也明确报告当前构建不支持，而不是明文连接
+        let valid = AgentConfig {
+            tls: TlsConfig {
+                cert: Some(cert.clone()),
+                key: Some(cert),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let err = valid.build().err().unwrap();
+        assert!(err.to_string().contains("不支持 TLS"), "{:#}", err);
+
+        let err = AgentConfig::from_toml("[tls]\npassword = \"x\"").unwrap_err();
+        assert!(format!("{:#}", err).contains("password"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_unknown_keys_rejected() {
+        let err = AgentConfig::from_toml("intervall = 5").unwrap_err();
+        assert!(format!("{:#}", err).contains("intervall"), "{:#}", err);
+
+        let err = AgentConfig::from_toml("[collectors]\ngpu = true").unwrap_err();
+        assert!(format!("{:#}", err).contains("gpu"), "{:#}", err);
+
+        let err = AgentConfig::from_toml("mode = \"roundrobin\"").unwrap_err();
+        assert!(format!("{:#}", err).contains("roundrobin"), "{:#}", err);
+
+        let err = AgentConfig::from_toml("proxy = \"ftp://proxy:21\"").unwrap_err();
+        assert!(format!("{:#}", err).contains("ftp"), "{:#}", err);
+    }
+}
@@ -0,0 +1,283 @@
+//! 连接错误诊断
+//!
+//! 把建立 gRPC 连接时的常见错误归类，给出针对性的排查提示；明显属于配置错误、
+//! 重试也不会成功的情况标记为致命，由调用方直接退出而不是无限重连
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+/// 连接错误类别
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectErrorKind {
+    /// 地址格式无效（缺少协议前缀、端口非法等）
+    InvalidAddress,
+    /// 使用了 `https://`，但当前构建不支持 TLS
+    TlsUnsupported,
+    /// 域名解析失败
+    Dns,
+    /// 目标端口拒绝连接
+    Refused,
+    /// 连接或握手超时
+    Timeout,
+    /// TLS 握手失败
+    TlsHandshake,
+    /// 对端不是明文 gRPC（HTTP/2）服务，常见于协议前缀与 Server 实际监听方式不符
+    Protocol,
+    /// Unix socket 不存在或无权访问
+    UnixSocket,
+    /// 无法连接代理，或代理拒绝建立隧道
+    Proxy,
+    /// 其他错误
+    Other,
+}
+
+impl ConnectErrorKind {
+    /// 是否为重试也不会成功的配置错误
+    pub fn is_fatal(self) -> bool {
+        matches!(self, Self::InvalidAddress | Self::TlsUnsupported)
+    }
+
+    /// 排查提示
+    pub fn hint(self) -> &'static str {
+        match self {
+            Self::InvalidAddress => "地址格式无效，应为 http://host:port 或 unix:/path/to/socket",
+            Self::TlsUnsupported => {
+                "当前构建不支持 TLS，请改用 http:// 地址，或在 Server 前放置 TLS 终结代理"
+            }
+            Self::Dns => "域名解析失败，请检查主机名拼写与本机 DNS 配置",
+            Self::Refused => "连接被拒绝，请确认 Server 已启动且 gRPC 端口（默认 50051）正确",
+            Self::Timeout => "连接超时，请检查网络连通性与防火墙规则",
+            Self::TlsHandshake => "TLS 握手失败，请检查证书与 Server 的 TLS 配置",
+            Self::Protocol => {
+                "对端不是明文 gRPC 服务：请确认端口是 gRPC 端口而非 HTTP API 端口，\
+                 以及 Server 是否要求 https://"
+            }
+            Self::UnixSocket => "Unix socket 不存在或无权访问，请检查路径与文件权限",
+            Self::Proxy => {
+                "经代理连接失败，请检查代理地址与认证信息，以及代理是否允许 CONNECT 到 Server 的 \
+                 gRPC 端口；不需要代理的地址可加入 NO_PROXY"
+            }
+            Self::Other => "未能识别的连接错误",
+        }
+    }
+}
+
+impl fmt::Display for ConnectErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidAddress => "invalid_address",
+            Self::TlsUnsupported => "tls_unsupported",
+            Self::Dns => "dns",
+            Self::Refused => "refused",
+            Self::Timeout => "timeout",
+            Self::TlsHandshake => "tls_handshake",
+            Self::Protocol => "protocol",
+            Self::UnixSocket => "unix_socket",
+            Self::Proxy => "proxy",
+            Self::Other => "other",
+        })
+    }
+}
+
+/// 建立连接失败，附带错误类别，可经 `anyhow::Error::downcast_ref` 取回
+#[derive(Debug)]
+pub struct ConnectError {
+    pub addr: String,
+    pub kind: ConnectErrorKind,
+    source: anyhow::Error,
+}
+
+impl ConnectError {
+    /// 对连接 `addr` 时的错误分类
+    pub fn new(addr: &str, source: anyhow::Error) -> Self {
+        Self {
+            addr: addr.to_string(),
+            kind: classify(addr, &source),
+            source,
+        }
+    }
+
+    /// 连接建立后首个 RPC 的失败：HTTP/2 握手在首个请求时才进行，协议不符等错误在此暴露。
+    /// 能归类时包装为 [`ConnectError`]，否则（如 Server 返回的业务错误）原样返回
+    pub fn from_status(addr: &str, status: tonic::Status) -> anyhow::Error {
+        let error = anyhow::Error::new(status);
+        match classify(addr, &error) {
+            ConnectErrorKind::Other => error,
+            kind => Self {
+                addr: addr.to_string(),
+                kind,
+                source: error,
+            }
+            .into(),
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "连接 {} 失败（{}）: {:#}。{}",
+            self.addr,
+            self.kind,
+            self.source,
+            self.kind.hint()
+        )
+    }
+}
+
+impl StdError for ConnectError {}
+
+/// 按地址与错误链归类连接错误
+pub fn classify(addr: &str, error: &anyhow::Error) -> ConnectErrorKind {
+    let lower = addr.to_ascii_lowercase();
+    if lower.starts_with("https://") {
+        return ConnectErrorKind::TlsUnsupported;
+    }
+    let is_unix = lower.starts_with(common::transport::UNIX_PREFIX);
+    if !is_unix && !lower.starts_with("http://") {
+        return ConnectErrorKind::InvalidAddress;
+    }
+
+    for cause in error.chain() {
+        if let Some(io_error) = cause.downcast_ref::<io::Error>() {
+            match io_error.kind() {
+                io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied if is_unix => {
+                    return ConnectErrorKind::UnixSocket;
+                }
+                io::ErrorKind::ConnectionRefused => return ConnectErrorKind::Refused,
+                io::ErrorKind::TimedOut => return ConnectErrorKind::Timeout,
+                _ => {}
+            }
+        }
+    }
+
+    let message = format!("{:#}", error).to_ascii_lowercase();
+    let contains = |needles: &[&str]| needles.iter().any(|needle| message.contains(needle));
+    if contains(&["proxy"]) {
+        ConnectErrorKind::Proxy
+    } else if contains(&[
+        "invalid uri",
+        "invalid port",
+        "invalid authority",
+        "invalid format",
+    ]) {
+        ConnectErrorKind::InvalidAddress
+    } else if contains(&[
+        "dns error",
+        "failed to lookup address",
+        "name or service not known",
+        "nodename nor servname",
+        "no such host",
+    ]) {
+        ConnectErrorKind::Dns
+    } else if contains(&["connection refused"]) {
+        ConnectErrorKind::Refused
+    } else if contains(&["timed out", "deadline has elapsed"]) {
+        ConnectErrorKind::Timeout
+    } else if contains(&["handshake", "certificate", "tls"]) {
+        ConnectErrorKind::TlsHandshake
+    } else if contains(&["http2", "h2 protocol", "frame", "invalid http version"]) {
+        ConnectErrorKind::Protocol
+    } else {
+        ConnectErrorKind::Other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_representative_errors() {
+        let io = |kind: io::ErrorKind| {
+            anyhow::Error::new(io::Error::new(kind, "io")).context("transport error")
+        };
+        let text = |message: &str| anyhow::anyhow!("transport error").context(message.to_string());
+        let http = "http://iris.example.com:50051";
+
+        for (addr, error, expected) in [
+            (
+                "https://iris.example.com:50051",
+                text("transport error"),
+                ConnectErrorKind::TlsUnsupported,
+            ),
+            (
+                "iris.example.com:50051",
+                text("transport error"),
+                ConnectErrorKind::InvalidAddress,
+            ),
+            (
+                http,
+                text("invalid uri character"),
+                ConnectErrorKind::InvalidAddress,
+            ),
+            (
+                http,
+                text("dns error: failed to lookup address information: Name or service not known"),
+                ConnectErrorKind::Dns,
+            ),
+            (
+                http,
+                io(io::ErrorKind::ConnectionRefused),
+                ConnectErrorKind::Refused,
+            ),
+            (http, io(io::ErrorKind::TimedOut), ConnectErrorKind::Timeout),
+            (
+                http,
+                text("invalid peer certificate: UnknownIssuer"),
+                ConnectErrorKind::TlsHandshake,
+            ),
+            (
+                http,
+                text("http2 error: connection error detected: frame with invalid size"),
+                ConnectErrorKind::Protocol,
+            ),
+            (
+                "unix:/run/iris/missing.sock",
+                io(io::ErrorKind::NotFound),
+                ConnectErrorKind::UnixSocket,
+            ),
+            (http, text("something else"), ConnectErrorKind::Other),
+        ] {
+            assert_eq!(classify(addr, &error), expected, "{}: {:#}", addr, error);
+        }
+
+        assert!(ConnectErrorKind::TlsUnsupported.is_fatal());
+        assert!(ConnectErrorKind::InvalidAddress.is_fatal());
+        assert!(!ConnectErrorKind::Refused.is_fatal());
+    }
+
+    #[tokio::test]
+    async fn test_classify_real_refused_connection() {
+        // 绑定后立即释放，得到一个当前无人监听的端口
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("http://127.0.0.1:{}", port);
+        let error = common::transport::connect(&addr).await.unwrap_err();
+        assert_eq!(
+            classify(&addr, &error),
+            ConnectErrorKind::Refused,
+            "{:#}",
+            error
+        );
+
+        let error = ConnectError::new("https://127.0.0.1:1", anyhow::anyhow!("x"));
+        assert!(error.to_string().contains("http://"));
+
+        // 误连到 HTTP API 端口时首个 RPC 才报出协议错误；Server 的业务错误不归类
+        let status = tonic::Status::unknown("h2 protocol error: http2 error");
+        let error = ConnectError::from_status(&addr, status);
+        assert_eq!(
+            error.downcast_ref::<ConnectError>().map(|e| e.kind),
+            Some(ConnectErrorKind::Protocol)
+        );
+        let status = tonic::Status::resource_exhausted("too many streams");
+        let error = ConnectError::from_status(&addr, status);
+        assert!(error.downcast_ref::<ConnectError>().is_none());
+    }
+}
@@ -0,0 +1,291 @@
+//! Docker 容器统计
+//!
+//! 需以 `--docker`（`collectors.docker`）开启：经 Docker socket（`/var/run/docker.sock`）
+//! 查询容器列表，统计运行中与其余状态的容器数，并逐个读取运行中容器的资源用量求和，
+//! 无需在每个容器里部署 Agent。每次采集对每个运行中的容器各请求一次，容器很多时应适当
+//! 调大上报间隔。主机没有 Docker socket 时只记录一次日志，之后不再尝试。
+//!
+//! CPU 使用率按相邻两次采集之间容器与主机 CPU 时间的增量计算，因此首次采集（以及新启动的
+//! 容器第一次被看到时）不计入
+
+use common::proto::ContainerMetrics;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use tracing::info;
+
+/// Docker socket 路径
+const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// 单次请求的读写超时
+#[cfg(unix)]
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Docker Engine API，测试中可替换
+trait DockerApi {
+    /// GET 请求，返回状态码 200 的响应体
+    fn get(&self, path: &str) -> Result<String, String>;
+}
+
+/// 通过 Unix socket 访问 Docker Engine API
+struct DockerSocket(&'static str);
+
+impl DockerApi for DockerSocket {
+    #[cfg(unix)]
+    fn get(&self, path: &str) -> Result<String, String> {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        let mut stream = UnixStream::connect(self.0)
+            .map_err(|e| format!("无法连接 Docker socket {}: {}", self.0, e))?;
+        stream
+            .set_read_timeout(Some(REQUEST_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(REQUEST_TIMEOUT)))
+            .map_err(|e| format!("设置 Docker socket 超时失败: {}", e))?;
+        // HTTP/1.0 请求：Docker 以 Connection: close 返回完整响应体，不使用分块编码
+        write!(stream, "GET {} HTTP/1.0\r\nHost: docker\r\n\r\n", path)
+            .map_err(|e| format!("请求 Docker API {} 失败: {}", path, e))?;
+        let mut response = Vec::new();
+        stream
+            .read_to_end(&mut response)
+            .map_err(|e| format!("读取 Docker API {} 响应失败: {}", path, e))?;
+        parse_response(&String::from_utf8_lossy(&response))
+            .map_err(|e| format!("Docker API {}: {}", path, e))
+    }
+
+    #[cfg(not(unix))]
+    fn get(&self, _path: &str) -> Result<String, String> {
+        Err("当前平台不支持 Docker socket".to_string())
+    }
+}
+
+/// 拆出 HTTP 响应体，状态码不是 200 时返回错误
+fn parse_response(response: &str) -> Result<String, String> {
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| "响应不完整".to_string())?;
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .ok_or_else(|| "无法解析响应状态行".to_string())?;
+    if status != "200" {
+        return Err(format!("状态码 {}: {}", status, body.trim()));
+    }
+    Ok(body.to_string())
+}
+
+/// `GET /containers/json` 中的一项（只取用到的字段）
+#[derive(Debug, Deserialize)]
+struct ContainerSummary {
+    #[serde(rename = "Id")]
+    id: String,
+    #[serde(rename = "State", default)]
+    state: String,
+}
+
+/// `GET /containers/{id}/stats` 的响应（只取用到的字段）
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ContainerStats {
+    cpu_stats: CpuStats,
+    memory_stats: MemoryStats,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CpuStats {
+    cpu_usage: CpuUsage,
+    /// 主机累计 CPU 时间（纳秒，全部核心合计）
+    system_cpu_usage: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CpuUsage {
+    /// 容器累计 CPU 时间（纳秒）
+    total_usage: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct MemoryStats {
+    usage: u64,
+    stats: HashMap<String, u64>,
+}
+
+impl MemoryStats {
+    /// 与 `docker stats` 相同：去掉可回收的非活跃页缓存（cgroup v2 为 `inactive_file`，
+    /// v1 为 `total_inactive_file`）
+    fn working_set(&self) -> u64 {
+        let inactive = self
+            .stats
+            .get("inactive_file")
+            .or_else(|| self.stats.get("total_inactive_file"))
+            .copied()
+            .unwrap_or_default();
+        self.usage.saturating_sub(inactive)
+    }
+}
+
+/// 各运行中容器上一次采集时的 (容器 CPU 时间, 主机 CPU 时间)
+type CpuSnapshots = HashMap<String, (u64, u64)>;
+
+static CPU_SNAPSHOTS: once_cell::sync::Lazy<Mutex<CpuSnapshots>> =
+    once_cell::sync::Lazy::new(Mutex::default);
+
+// 是否存在 Docker socket，只检测一次
+static DOCKER_AVAILABLE: once_cell::sync::Lazy<bool> = once_cell::sync::Lazy::new(|| {
+    let available = cfg!(unix) && std::path::Path::new(DOCKER_SOCKET).exists();
+    if !available {
+        info!("未找到 Docker socket {}，跳过容器采集", DOCKER_SOCKET);
+    }
+    available
+});
+
+/// 主机是否有 Docker socket
+pub fn available() -> bool {
+    *DOCKER_AVAILABLE
+}
+
+/// 采集 Docker 容器统计
+pub fn collect_container_metrics() -> Result<ContainerMetrics, String> {
+    let mut snapshots = CPU_SNAPSHOTS.lock().unwrap_or_else(PoisonError::into_inner);
+    collect_from(&DockerSocket(DOCKER_SOCKET), &mut snapshots)
+}
+
+/// 容器列表读取失败时返回错误；单个容器的用量读取失败（如刚好停止）时跳过该容器
+fn collect_from(
+    api: &impl DockerApi,
+    snapshots: &mut CpuSnapshots,
+) -> Result<ContainerMetrics, String> {
+    let containers: Vec<ContainerSummary> =
+        serde_json::from_str(&api.get("/containers/json?all=1")?)
+            .map_err(|e| format!("无法解析容器列表: {}", e))?;
+
+    let mut metrics = ContainerMetrics::default();
+    let mut current = CpuSnapshots::new();
+    let mut cpu_usage = 0.0;
+    for container in &containers {
+        if container.state != "running" {
+            metrics.stopped += 1;
+            continue;
+        }
+        metrics.running += 1;
+
+        let Ok(stats) = api
+            .get(&format!(
+                "/containers/{}/stats?stream=false&one-shot=true",
+                container.id
+            ))
+            .and_then(|body| {
+                serde_json::from_str::<ContainerStats>(&body).map_err(|e| e.to_string())
+            })
+        else {
+            continue;
+        };
+        metrics.memory_usage += stats.memory_stats.working_set();
+
+        let snapshot = (
+            stats.cpu_stats.cpu_usage.total_usage,
+            stats.cpu_stats.system_cpu_usage,
+        );
+        if let Some(&(container_prev, system_prev)) = snapshots.get(&container.id) {
+            let container_delta = snapshot.0.saturating_sub(container_prev);
+            let system_delta = snapshot.1.saturating_sub(system_prev);
+            if system_delta > 0 {
+                cpu_usage += container_delta as f64 / system_delta as f64 * 100.0;
+            }
+        }
+        current.insert(container.id.clone(), snapshot);
+    }
+    // 只保留仍在运行的容器，已停止或删除的容器随之清除
+    *snapshots = current;
+    metrics.cpu_usage_percent = cpu_usage.min(100.0);
+    Ok(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// 按路径返回预置响应，每轮采集（以读取容器列表为界）CPU 时间递增
+    struct StubApi {
+        containers: &'static str,
+        rounds: Cell<u64>,
+    }
+
+    impl DockerApi for StubApi {
+        fn get(&self, path: &str) -> Result<String, String> {
+            if path.starts_with("/containers/json") {
+                self.rounds.set(self.rounds.get() + 1);
+                return Ok(self.containers.to_string());
+            }
+            let round = self.rounds.get();
+            if path.starts_with("/containers/gone/") {
+                return Err("状态码 404: No such container".to_string());
+            }
+            // 每轮主机 CPU 时间增加 1e9，每个容器增加 1e8，即各占 10%
+            Ok(format!(
+                r#"{{"cpu_stats":{{"cpu_usage":{{"total_usage":{}}},"system_cpu_usage":{}}},
+                    "memory_stats":{{"usage":{},"stats":{{"inactive_file":{}}}}}}}"#,
+                round * 100_000_000,
+                round * 1_000_000_000,
+                300 << 20,
+                100 << 20
+            ))
+        }
+    }
+
+    #[test]
+    fn test_counts_and_aggregate_usage() {
+        let api = StubApi {
+            containers: r#"[
+                {"Id": "web", "State": "running", "Names": ["/web"]},
+                {"Id": "db", "State": "running"},
+                {"Id": "gone", "State": "running"},
+                {"Id": "job", "State": "exited"},
+                {"Id": "new", "State": "created"}
+            ]"#,
+            rounds: Cell::new(0),
+        };
+        let mut snapshots = CpuSnapshots::new();
+
+        let metrics = collect_from(&api, &mut snapshots).unwrap();
+        assert_eq!(metrics.running, 3);
+        assert_eq!(metrics.stopped, 2);
+        // 读取失败的容器只计数，不计用量
+        assert_eq!(metrics.memory_usage, 2 * (200 << 20));
+        // 首次采集没有增量
+        assert_eq!(metrics.cpu_usage_percent, 0.0);
+
+        let metrics = collect_from(&api, &mut snapshots).unwrap();
+        assert!(
+            (metrics.cpu_usage_percent - 20.0).abs() < 1e-9,
+            "{:?}",
+            metrics
+        );
+        assert_eq!(snapshots.len(), 2);
+    }
+
+    #[test]
+    fn test_unreachable_daemon_and_http_errors() {
+        struct Down;
+        impl DockerApi for Down {
+            fn get(&self, _path: &str) -> Result<String, String> {
+                Err("无法连接 Docker socket".to_string())
+            }
+        }
+        assert!(collect_from(&Down, &mut CpuSnapshots::new()).is_err());
+
+        assert_eq!(
+            parse_response("HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n[]").unwrap(),
+            "[]"
+        );
+        let err =
+            parse_response("HTTP/1.0 500 Internal Server Error\r\n\r\n{\"message\":\"boom\"}")
+                .unwrap_err();
+        assert!(err.contains("500"), "{}", err);
+    }
+}
@@ -0,0 +1,166 @@
+//! GPU 指标采集（NVML）
+//!
+//! 需要启用 `gpu` feature。NVML 库在运行时动态加载，
+//! 没有 NVIDIA GPU 或加载不到 libnvidia-ml 时上报空列表
+
+use common::proto::GpuMetrics;
+
+/// 单块 GPU 的原始读数，某项读取失败（驱动不支持等）时为 None
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+struct GpuReading {
+    /// NVML 设备序号（与 nvidia-smi 一致），个别设备读取失败时序号不连续
+    index: u32,
+    name: String,
+    uuid: String,
+    /// GPU 使用率（%）
+    utilization: Option<u32>,
+    /// (已用, 总量) 显存（字节）
+    memory: Option<(u64, u64)>,
+    /// 温度（℃）
+    temperature: Option<u32>,
+    /// 功耗（毫瓦）
+    power_mw: Option<u32>,
+}
+
+/// GPU 读数来源，测试中可替换 NVML
+#[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+trait GpuSource {
+    fn readings(&self) -> Vec<GpuReading>;
+}
+
+/// 采集 GPU 指标
+pub fn collect_gpu_metrics() -> Vec<GpuMetrics> {
+    #[cfg(feature = "gpu")]
+    {
+        match nvml::instance() {
+            Some(source) => collect_from(source),
+            None => Vec::new(),
+        }
+    }
+    #[cfg(not(feature = "gpu"))]
+    {
+        Vec::new()
+    }
+}
+
+#[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+fn collect_from(source: &impl GpuSource) -> Vec<GpuMetrics> {
+    source.readings().into_iter().map(to_proto).collect()
+}
+
+#[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+fn to_proto(reading: GpuReading) -> GpuMetrics {
+    let (memory_used, memory_total) = reading.memory.unwrap_or_default();
+    GpuMetrics {
+        index: reading.index,
+        name: reading.name,
+        uuid: reading.uuid,
+        utilization_percent: reading.utilization.unwrap_or_default() as f64,
+        memory_used,
+        memory_total,
+        temperature: reading.temperature.unwrap_or_default() as f64,
+        power_watts: reading.power_mw.unwrap_or_default() as f64 / 1000.0,
+    }
+}
+
+#[cfg(feature = "gpu")]
+mod nvml {
+    use super::{GpuReading, GpuSource};
+    use nvml_wrapper::enum_wrappers::device::TemperatureSensor;
+    use nvml_wrapper::Nvml;
+    use once_cell::sync::Lazy;
+    use tracing::info;
+
+    // NVML 只初始化一次；初始化失败（无驱动/无 GPU）后不再重试
+    static NVML: Lazy<Option<Nvml>> = Lazy::new(|| match Nvml::init() {
+        Ok(nvml) => Some(nvml),
+        Err(e) => {
+            info!("未能加载 NVML，跳过 GPU 采集: {}", e);
+            None
+        }
+    });
+
+    pub(super) fn instance() -> Option<&'static Nvml> {
+        NVML.as_ref()
+    }
+
+    impl GpuSource for Nvml {
+        fn readings(&self) -> Vec<GpuReading> {
+            let count = self.device_count().unwrap_or(0);
+            (0..count)
+                .filter_map(|index| Some((index, self.device_by_index(index).ok()?)))
+                .map(|(index, device)| GpuReading {
+                    index,
+                    name: device.name().unwrap_or_default(),
+                    uuid: device.uuid().unwrap_or_default(),
+                    utilization: device.utilization_rates().ok().map(|u| u.gpu),
+                    memory: device.memory_info().ok().map(|m| (m.used, m.total)),
+                    temperature: device.temperature(TemperatureSensor::Gpu).ok(),
+                    power_mw: device.power_usage().ok(),
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct StubSource(Vec<GpuReading>);
+
+    impl GpuSource for StubSource {
+        fn readings(&self) -> Vec<GpuReading> {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn test_two_gpus_mapped_to_proto() {
+        let source = StubSource(vec![
+            GpuReading {
+                index: 0,
+                name: "NVIDIA A100-SXM4-80GB".to_string(),
+                uuid: "GPU-0000".to_string(),
+                utilization: Some(87),
+                memory: Some((40 << 30, 80 << 30)),
+                temperature: Some(64),
+                power_mw: Some(312_500),
+            },
+            // 序号 1 的设备读取失败被跳过，序号保持与 NVML 一致
+            GpuReading {
+                index: 2,
+                name: "NVIDIA T4".to_string(),
+                uuid: "GPU-0001".to_string(),
+                utilization: Some(0),
+                memory: Some((0, 16 << 30)),
+                temperature: Some(35),
+                // 部分型号不支持功耗读数
+                power_mw: None,
+            },
+        ]);
+
+        let gpus = collect_from(&source);
+        assert_eq!(gpus.len(), 2);
+
+        assert_eq!(gpus[0].index, 0);
+        assert_eq!(gpus[0].name, "NVIDIA A100-SXM4-80GB");
+        assert_eq!(gpus[0].uuid, "GPU-0000");
+        assert_eq!(gpus[0].utilization_percent, 87.0);
+        assert_eq!(gpus[0].memory_used, 40 << 30);
+        assert_eq!(gpus[0].memory_total, 80 << 30);
+        assert_eq!(gpus[0].temperature, 64.0);
+        assert_eq!(gpus[0].power_watts, 312.5);
+
+        assert_eq!(gpus[1].index, 2);
+        assert_eq!(gpus[1].uuid, "GPU-0001");
+        assert_eq!(gpus[1].memory_total, 16 << 30);
+        assert_eq!(gpus[1].power_watts, 0.0);
+    }
+
+    #[test]
+    fn test_no_gpus_reports_empty() {
+        assert!(collect_from(&StubSource(vec![])).is_empty());
+    }
+}
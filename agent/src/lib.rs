@@ -1,87 +1,1455 @@
 use anyhow::Result;
 use common::proto::probe_service_client::ProbeServiceClient;
-use common::proto::MetricsRequest;
-use common::utils::{current_timestamp_ms, generate_agent_id};
+use common::proto::{HeartbeatRequest, MetricsRequest, MetricsResponse};
+use common::transport;
+use common::utils::{current_timestamp_ms, generate_agent_id, resolve_hostname, SampleClock};
+use futures::future::join_all;
+use pacer::{PaceChange, SendPacer};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
 use std::time::Duration;
-use tokio::sync::mpsc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::Instant;
 use tokio_stream::wrappers::ReceiverStream;
-use tracing::{error, info};
+use tokio_stream::StreamExt;
+use tonic::transport::Channel;
+use tracing::{debug, error, info, warn};
 
+mod aggregate;
 mod collector;
+mod config;
+mod diagnose;
+mod docker;
+mod gpu;
+mod pacer;
+mod proxy;
+mod replay;
+mod selftest;
+mod spool;
+mod systemd;
+mod validate;
+
+pub use aggregate::{SpoolAggregation, DEFAULT_AGGREGATE_BUCKET_SECS};
+pub use collector::CollectOptions;
+pub use common::utils::{HostnameMode, TimestampSource};
+pub use config::{AgentConfig, CollectorsConfig, SpoolConfig, TlsConfig};
+pub use diagnose::{ConnectError, ConnectErrorKind};
+pub use proxy::{Proxy, ProxyScheme};
+pub use replay::Replay;
+pub use selftest::{default_api_url, SelfTestReport, SelfTestStep, StepStatus};
+pub use spool::{Spool, SpoolOptions, DEFAULT_SPOOL_MAX_MB};
+
+/// 心跳间隔
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// 超过该时长没有一次成功心跳，视为连接已失效并重建
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+/// 连接出错后的重连等待时间
+const RECONNECT_DELAY: Duration = Duration::from_secs(3);
+/// 采集样本广播缓冲：连接暂时阻塞时最多积压的样本数，超出后丢弃最旧的
+const SAMPLE_BUFFER: usize = 16;
+/// 流式连接的发送通道容量，积压情况同时用作本地背压信号（见 [`pacer`]）
+const STREAM_BUFFER: usize = 100;
+/// 单次上报到同一 Server 的最大尝试次数
+const REPORT_MAX_ATTEMPTS: u32 = 3;
+/// 单次上报失败后的首次重试等待，之后每次翻倍
+const REPORT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// 多个 Server 时的上报方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReportMode {
+    /// 同一时刻只连接一个 Server，出错后切换到下一个
+    #[default]
+    Failover,
+    /// 同时向所有 Server 上报，各连接独立重连
+    Broadcast,
+}
+
+impl FromStr for ReportMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(Self::Failover),
+            "broadcast" => Ok(Self::Broadcast),
+            other => Err(format!(
+                "未知的上报模式: {}（可选 failover、broadcast）",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ReportMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Failover => f.write_str("failover"),
+            Self::Broadcast => f.write_str("broadcast"),
+        }
+    }
+}
 
 pub struct Agent {
     agent_id: String,
     hostname: String,
-    server_addr: String,
+    clock: SampleClock,
+    /// 下一条样本的序号，Server 据此估计丢失的样本数
+    sequence: AtomicU64,
+    servers: Vec<String>,
+    /// 附加到每条样本的部署标签
+    labels: HashMap<String, String>,
+    mode: ReportMode,
+    /// 出站代理，None 时直连
+    proxy: Option<Proxy>,
     interval: Duration,
+    collect_options: CollectOptions,
+    /// 离线缓冲，None 时连不上 Server 期间的样本直接丢弃
+    spool: Option<Mutex<Spool>>,
+    /// 补发离线缓冲时的聚合，None 时逐条补发
+    spool_aggregation: Option<SpoolAggregation>,
+    heartbeat_interval: Duration,
+    heartbeat_timeout: Duration,
+    reconnect_delay: Duration,
+    report_max_attempts: u32,
+    report_retry_delay: Duration,
 }
 
 impl Agent {
-    pub fn new(server_addr: String, interval_secs: u64) -> Self {
-        // 优先使用环境变量 IRIS_HOSTNAME，否则使用系统 hostname
-        let hostname = std::env::var("IRIS_HOSTNAME")
-            .ok()
-            .or_else(|| hostname::get().ok().and_then(|h| h.into_string().ok()))
-            .unwrap_or_else(|| "unknown".to_string());
+    /// 创建 Agent，`servers` 至少包含一个 Server 地址
+    pub fn new(servers: Vec<String>, interval_secs: u64) -> Self {
+        assert!(
+            !servers.is_empty(),
+            "at least one server address is required"
+        );
 
         Self {
             agent_id: generate_agent_id(),
-            hostname,
-            server_addr,
+            hostname: resolve_hostname(HostnameMode::default()),
+            clock: SampleClock::default(),
+            sequence: AtomicU64::new(1),
+            servers,
+            labels: HashMap::new(),
+            mode: ReportMode::default(),
+            proxy: None,
             interval: Duration::from_secs(interval_secs),
+            collect_options: CollectOptions::default(),
+            spool: None,
+            spool_aggregation: None,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            heartbeat_timeout: HEARTBEAT_TIMEOUT,
+            reconnect_delay: RECONNECT_DELAY,
+            report_max_attempts: REPORT_MAX_ATTEMPTS,
+            report_retry_delay: REPORT_RETRY_DELAY,
+        }
+    }
+
+    /// 设置采集选项
+    pub fn with_collect_options(mut self, options: CollectOptions) -> Self {
+        self.collect_options = options;
+        self
+    }
+
+    /// 设置主机名解析方式（环境变量 IRIS_HOSTNAME 仍优先）
+    pub fn with_hostname_mode(mut self, mode: HostnameMode) -> Self {
+        self.hostname = resolve_hostname(mode);
+        self
+    }
+
+    /// 设置样本时间戳来源
+    pub fn with_timestamp_source(mut self, source: TimestampSource) -> Self {
+        self.clock = SampleClock::new(source);
+        self
+    }
+
+    /// 设置附加到每条样本的部署标签
+    pub fn with_labels(mut self, labels: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.labels = labels.into_iter().collect();
+        self
+    }
+
+    /// 设置多个 Server 时的上报方式
+    pub fn with_report_mode(mut self, mode: ReportMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// 设置出站代理，None 时直连
+    pub fn with_proxy(mut self, proxy: Option<Proxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// 设置离线缓冲，None 时不缓冲
+    pub fn with_spool(mut self, spool: Option<Spool>) -> Self {
+        self.spool = spool.map(Mutex::new);
+        self
+    }
+
+    /// 设置补发离线缓冲时的聚合，None 时逐条补发
+    pub fn with_spool_aggregation(mut self, aggregation: Option<SpoolAggregation>) -> Self {
+        self.spool_aggregation = aggregation;
+        self
+    }
+
+    /// 采集一条完整的上报样本
+    pub fn collect_sample(&self) -> MetricsRequest {
+        let mut system = collector::collect_metrics_with(&self.collect_options);
+        validate::validate_metrics(&mut system);
+        // system_info 与上报身份使用同一个已解析的主机名
+        if let Some(info) = system.system_info.as_mut() {
+            info.hostname = self.hostname.clone();
         }
+
+        MetricsRequest {
+            agent_id: self.agent_id.clone(),
+            timestamp: self.clock.now_ms(),
+            system: Some(system),
+            hostname: self.hostname.clone(),
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+            labels: self.labels.clone(),
+        }
+    }
+
+    /// 采集一条样本并以格式化 JSON 写出（不连接 Server）
+    pub fn print_sample(&self, out: &mut impl Write) -> Result<()> {
+        let sample = self.collect_sample();
+        serde_json::to_writer_pretty(&mut *out, &sample)?;
+        writeln!(out)?;
+        Ok(())
+    }
+
+    /// 采集一条样本并通过单次 RPC 上报后返回
+    ///
+    /// failover 模式下依次尝试直到一个 Server 成功；broadcast 模式下发往所有 Server，
+    /// 任一失败即返回错误。每个 Server 失败后按退避重试，最多尝试 `REPORT_MAX_ATTEMPTS` 次
+    pub async fn report_once(&self) -> Result<()> {
+        let sample = self.collect_sample();
+        let mut last_err = None;
+        for addr in &self.servers {
+            match self.report_with_retry(addr, &sample).await {
+                Ok(message) => {
+                    info!("单次上报到 {} 完成: {}", addr, message);
+                    if self.mode == ReportMode::Failover {
+                        return Ok(());
+                    }
+                }
+                Err(e) => {
+                    warn!("单次上报到 {} 失败: {}", addr, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        match last_err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// 对每个 Server 执行端到端自检（见 [`SelfTestReport`]）：单次上报一条样本后经 HTTP API 查回
+    ///
+    /// `api` 为 HTTP API 地址，None 时按 [`default_api_url`] 由各 Server 地址推断
+    pub async fn self_test(&self, api: Option<&str>) -> Vec<SelfTestReport> {
+        let mut reports = Vec::new();
+        for addr in &self.servers {
+            let api = api.map(str::to_string).or_else(|| default_api_url(addr));
+            reports.push(selftest::run(self, addr, api).await);
+        }
+        reports
+    }
+
+    /// 上报到单个 Server，连接失败或 Server 返回 `success: false` 时退避重试
+    ///
+    /// 等待时间从 `report_retry_delay` 开始翻倍，Server 给出 `backoff_ms` 时至少等待该值
+    async fn report_with_retry(&self, addr: &str, sample: &MetricsRequest) -> Result<String> {
+        let mut delay = self.report_retry_delay;
+        let mut attempt = 1;
+        loop {
+            let (err, backoff) = match self.report_to(addr, sample.clone()).await {
+                Ok(response) if response.success => return Ok(response.message),
+                Ok(response) => (
+                    anyhow::anyhow!("Server 未能接收: {}", response.message),
+                    Duration::from_millis(response.backoff_ms),
+                ),
+                Err(e) if is_fatal(&e) => return Err(e),
+                Err(e) => (e, Duration::ZERO),
+            };
+            collector::record_error(format!("上报到 {} 失败: {:#}", addr, err));
+
+            if attempt >= self.report_max_attempts {
+                return Err(err.context(format!("尝试 {} 次后放弃", attempt)));
+            }
+            let wait = delay.max(backoff);
+            warn!(
+                "上报到 {} 失败（第 {}/{} 次）: {}，{:?} 后重试",
+                addr, attempt, self.report_max_attempts, err, wait
+            );
+            tokio::time::sleep(wait).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    async fn report_to(&self, addr: &str, sample: MetricsRequest) -> Result<MetricsResponse> {
+        let mut client = connect(addr, self.proxy.as_ref()).await?;
+        let response = client
+            .report_metrics(sample)
+            .await
+            .map_err(|status| ConnectError::from_status(addr, status))?;
+        Ok(response.into_inner())
     }
 
+    /// 持续采集并上报
+    ///
+    /// 连接出错时自动重连；遇到地址格式错误等重试也不会成功的配置错误时返回错误
     pub async fn run(&self) -> Result<()> {
-        info!("Agent {} 启动，连接到 {}", self.agent_id, self.server_addr);
+        info!(
+            "Agent {} 启动，Server: {}（{} 模式）",
+            self.agent_id,
+            self.servers.join(", "),
+            self.mode
+        );
 
+        // 样本只采集一次，由各连接各自订阅
+        let (samples, _) = broadcast::channel(SAMPLE_BUFFER);
+        let senders = samples.downgrade();
+        self.deliver(&senders, self.sample_loop(samples)).await
+    }
+
+    /// 回放录制的样本（见 [`Replay`]），经与 [`Agent::run`] 相同的连接发送
+    ///
+    /// 不循环时，全部样本发出后返回
+    pub async fn replay(&self, replay: Replay) -> Result<()> {
+        info!(
+            "Agent 回放 {} 条样本，Server: {}（{} 模式）",
+            replay.len(),
+            self.servers.join(", "),
+            self.mode
+        );
+
+        let (samples, _) = broadcast::channel(SAMPLE_BUFFER);
+        let senders = samples.downgrade();
+        self.deliver(&senders, replay.feed(samples)).await
+    }
+
+    /// 运行样本来源与各连接：来源持有唯一的发送端，结束后各连接发完积压的样本再返回
+    async fn deliver(
+        &self,
+        samples: &broadcast::WeakSender<MetricsRequest>,
+        source: impl Future<Output = ()>,
+    ) -> Result<()> {
+        let senders = async {
+            match self.mode {
+                ReportMode::Failover => self.run_failover(samples).await,
+                ReportMode::Broadcast => self.run_broadcast(samples).await,
+            }
+        };
+        tokio::pin!(senders);
+
+        let finished = tokio::select! {
+            _ = source => None,
+            result = &mut senders => Some(result),
+        };
+        match finished {
+            Some(result) => result,
+            None => senders.await,
+        }
+    }
+
+    /// 按间隔采集样本并广播给所有连接
+    async fn sample_loop(&self, samples: broadcast::Sender<MetricsRequest>) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            // 没有已建立的连接时写入离线缓冲，未启用缓冲时直接丢弃
+            if let Err(broadcast::error::SendError(sample)) = samples.send(self.collect_sample()) {
+                if let Some(spool) = &self.spool {
+                    let mut spool = spool.lock().unwrap_or_else(PoisonError::into_inner);
+                    if let Err(e) = spool.push(&sample) {
+                        warn!("写入离线缓冲失败: {:#}", e);
+                        collector::record_error(format!("写入离线缓冲失败: {:#}", e));
+                    }
+                }
+            }
+        }
+    }
+
+    /// 连接建立后先补发离线缓冲中的样本，逐段读取，发出后删除
+    async fn send_spooled(&self, addr: &str, tx: &mpsc::Sender<MetricsRequest>) -> Result<()> {
+        let Some(spool) = &self.spool else {
+            return Ok(());
+        };
+
+        let mut read = 0;
+        let mut sent = 0;
         loop {
-            match self.run_stream().await {
+            let segment = spool
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .read_oldest();
+            let (id, samples) = match segment {
+                Ok(Some(segment)) => segment,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("读取离线缓冲失败: {:#}", e);
+                    collector::record_error(format!("读取离线缓冲失败: {:#}", e));
+                    continue;
+                }
+            };
+            read += samples.len();
+            let mut pending: VecDeque<MetricsRequest> = match &self.spool_aggregation {
+                Some(aggregation) => aggregation.coalesce(samples, self.clock.now_ms()),
+                None => samples,
+            }
+            .into();
+
+            // 先占到发送位置再取出样本，失败时未发出的样本仍在 pending 中
+            let result = loop {
+                if pending.is_empty() {
+                    break Ok(());
+                }
+                // 流停滞时不无限等待，交给重连处理
+                match tokio::time::timeout(self.heartbeat_timeout, tx.reserve()).await {
+                    Ok(Ok(permit)) => {
+                        permit.send(pending.pop_front().expect("pending 非空"));
+                        sent += 1;
+                        collector::increment_metrics_sent();
+                    }
+                    Ok(Err(_)) => break Err(anyhow::anyhow!("补发离线缓冲样本失败，流已关闭")),
+                    Err(_) => {
+                        break Err(anyhow::anyhow!(
+                            "补发离线缓冲样本超过 {:?} 未完成，连接可能已失效",
+                            self.heartbeat_timeout
+                        ))
+                    }
+                }
+            };
+
+            // 全部发出后删除该段，否则把未发出的样本写回，下次连接继续补发
+            let unsent: Vec<MetricsRequest> = pending.into();
+            if let Err(e) = spool
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .finish_segment(id, &unsent)
+            {
+                warn!("更新离线缓冲失败: {:#}", e);
+                collector::record_error(format!("更新离线缓冲失败: {:#}", e));
+            }
+            result?;
+        }
+        if sent < read {
+            info!(
+                "已向 {} 补发 {} 条离线缓冲样本（由 {} 条聚合）",
+                addr, sent, read
+            );
+        } else if sent > 0 {
+            info!("已向 {} 补发 {} 条离线缓冲样本", addr, sent);
+        }
+        Ok(())
+    }
+
+    /// 各 Server 的连接独立运行：某个地址遇到致命的连接配置错误只停止该连接，
+    /// 全部连接都因此停止时才返回错误
+    async fn run_broadcast(&self, samples: &broadcast::WeakSender<MetricsRequest>) -> Result<()> {
+        let results = join_all(
+            self.servers
+                .iter()
+                .map(|addr| self.run_endpoint(addr, samples)),
+        )
+        .await;
+        let all_failed = results.iter().all(Result::is_err);
+        match results.into_iter().find_map(Result::err) {
+            Some(e) if all_failed => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// 依次连接各 Server，当前连接出错后切换到下一个
+    ///
+    /// 遇到致命的连接配置错误的地址不再尝试，所有地址都如此时返回错误
+    async fn run_failover(&self, samples: &broadcast::WeakSender<MetricsRequest>) -> Result<()> {
+        let distinct: HashSet<&str> = self.servers.iter().map(String::as_str).collect();
+        let mut fatal = HashSet::new();
+        for addr in self.servers.iter().cycle() {
+            if fatal.contains(addr.as_str()) {
+                continue;
+            }
+            let Some(receiver) = subscribe(samples) else {
+                return Ok(());
+            };
+            let result = self.run_stream(addr, receiver).await;
+            if result.is_ok() && samples.strong_count() == 0 {
+                info!("样本来源已结束，到 {} 的连接已发送全部样本", addr);
+                return Ok(());
+            }
+            collector::increment_reconnects();
+            if let Err(e) = result {
+                collector::record_error(format!("到 {} 的流式连接错误: {:#}", addr, e));
+                if is_fatal(&e) {
+                    fatal.insert(addr.as_str());
+                    if fatal.len() == distinct.len() {
+                        error!("{}，所有 Server 均不可用，停止重连", e);
+                        return Err(e);
+                    }
+                    error!("{}，不再尝试该 Server", e);
+                    continue;
+                }
+                error!(
+                    "到 {} 的流式连接错误: {}，{:?} 后尝试下一个 Server",
+                    addr, e, self.reconnect_delay
+                );
+                tokio::time::sleep(self.reconnect_delay).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// 持续向单个 Server 上报，出错后独立重连，不影响其他连接；遇到致命的连接配置错误时只停止该连接
+    async fn run_endpoint(
+        &self,
+        addr: &str,
+        samples: &broadcast::WeakSender<MetricsRequest>,
+    ) -> Result<()> {
+        loop {
+            let Some(receiver) = subscribe(samples) else {
+                return Ok(());
+            };
+            let result = self.run_stream(addr, receiver).await;
+            if result.is_ok() && samples.strong_count() == 0 {
+                info!("样本来源已结束，到 {} 的连接已发送全部样本", addr);
+                return Ok(());
+            }
+            collector::increment_reconnects();
+            match result {
                 Ok(_) => {
-                    info!("流式连接正常结束");
+                    info!("到 {} 的流式连接正常结束", addr);
                 }
                 Err(e) => {
-                    collector::increment_errors();
-                    error!("流式连接错误: {}，3秒后重连", e);
-                    tokio::time::sleep(Duration::from_secs(3)).await;
+                    collector::record_error(format!("到 {} 的流式连接错误: {:#}", addr, e));
+                    if is_fatal(&e) {
+                        error!("{}，停止重连", e);
+                        return Err(e);
+                    }
+                    error!(
+                        "到 {} 的流式连接错误: {}，{:?} 后重连",
+                        addr, e, self.reconnect_delay
+                    );
+                    tokio::time::sleep(self.reconnect_delay).await;
                 }
             }
         }
     }
 
-    async fn run_stream(&self) -> Result<()> {
-        let mut client = ProbeServiceClient::connect(self.server_addr.clone()).await?;
-        info!("成功连接到 Server，建立流式通道");
+    async fn run_stream(
+        &self,
+        addr: &str,
+        mut samples: broadcast::Receiver<MetricsRequest>,
+    ) -> Result<()> {
+        let mut client = connect(addr, self.proxy.as_ref()).await?;
+        info!("成功连接到 Server {}，建立流式通道", addr);
 
-        let (tx, rx) = mpsc::channel(100);
-        let stream = ReceiverStream::new(rx);
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        // 流被读到末尾时通知，样本来源结束后据此确认积压的样本都已交给连接
+        let (drained_tx, drained_rx) = oneshot::channel();
+        let stream = ReceiverStream::new(rx).chain(
+            futures::stream::once(async move {
+                let _ = drained_tx.send(());
+            })
+            .filter_map(|_| None),
+        );
 
-        // 发起流式请求
-        let response = client.stream_metrics(stream).await?;
+        // 发起流式请求；agent_id 不是合法的 metadata 值（如含非 ASCII 字符）时不携带
+        let mut request = tonic::Request::new(stream);
+        if let Ok(agent_id) = self.agent_id.parse() {
+            request
+                .metadata_mut()
+                .insert(transport::AGENT_ID_METADATA, agent_id);
+        }
+        let response = client
+            .stream_metrics(request)
+            .await
+            .map_err(|status| ConnectError::from_status(addr, status))?;
         info!("流式连接已建立: {}", response.into_inner().message);
+        self.send_spooled(addr, &tx).await?;
 
-        let mut interval = tokio::time::interval(self.interval);
+        let mut heartbeat = tokio::time::interval(self.heartbeat_interval);
+        let mut last_heartbeat_ok = Instant::now();
+        // Server 写入积压时经心跳下发的最小发送间隔，间隔内的样本直接丢弃
+        let mut min_gap = Duration::ZERO;
+        // 发送通道积压时本地放慢的发送间隔，与 min_gap 取较大者
+        let mut pacer = SendPacer::new(self.interval);
+        let mut last_sent: Option<Instant> = None;
 
         loop {
-            interval.tick().await;
+            tokio::select! {
+                sample = samples.recv() => {
+                    let request = match sample {
+                        Ok(request) => request,
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("到 {} 的发送跟不上采集，丢弃 {} 条样本", addr, skipped);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => {
+                            drop(tx);
+                            if tokio::time::timeout(self.heartbeat_interval, drained_rx).await.is_err() {
+                                warn!("到 {} 的流未能及时发完剩余样本", addr);
+                            }
+                            return Ok(());
+                        }
+                    };
 
-            // 采集系统指标
-            let metrics = collector::collect_metrics();
+                    if last_sent.is_some_and(|sent| sent.elapsed() < min_gap.max(pacer.gap())) {
+                        debug!("到 {} 的上报已放慢，跳过本次样本", addr);
+                        continue;
+                    }
+                    last_sent = Some(Instant::now());
 
-            // 通过流发送
-            let request = MetricsRequest {
-                agent_id: self.agent_id.clone(),
-                timestamp: current_timestamp_ms(),
-                system: Some(metrics),
-                hostname: self.hostname.clone(),
-            };
+                    match pacer.observe(STREAM_BUFFER - tx.capacity(), STREAM_BUFFER) {
+                        Some(PaceChange::Slowed(gap)) => {
+                            warn!("到 {} 的发送通道积压，上报间隔放慢到至少 {:?}，丢弃本次样本", addr, gap);
+                            continue;
+                        }
+                        Some(PaceChange::Recovered) => {
+                            info!("到 {} 的发送通道积压已消化，恢复正常上报", addr);
+                        }
+                        None => {}
+                    }
+
+                    if tx.send(request).await.is_err() {
+                        return Err(anyhow::anyhow!("发送指标失败，流已关闭"));
+                    }
+
+                    collector::increment_metrics_sent();
+                    info!("指标已发送到 {}", addr);
+                }
+                _ = heartbeat.tick() => {
+                    // 流上的发送在半开连接下也可能“成功”，用心跳确认链路确实可达
+                    let request = HeartbeatRequest {
+                        agent_id: self.agent_id.clone(),
+                        timestamp: current_timestamp_ms(),
+                    };
+                    match tokio::time::timeout(self.heartbeat_interval, client.heartbeat(request)).await {
+                        Ok(Ok(response)) => {
+                            last_heartbeat_ok = Instant::now();
+                            let backoff = Duration::from_millis(response.into_inner().backoff_ms);
+                            if backoff != min_gap {
+                                if backoff.is_zero() {
+                                    info!("Server {} 写入积压已解除，恢复正常上报", addr);
+                                } else {
+                                    warn!("Server {} 写入积压，上报间隔放慢到至少 {:?}", addr, backoff);
+                                }
+                                min_gap = backoff;
+                            }
+                        }
+                        Ok(Err(e)) => warn!("心跳失败: {}", e),
+                        Err(_) => warn!("心跳超时"),
+                    }
+
+                    if last_heartbeat_ok.elapsed() > self.heartbeat_timeout {
+                        return Err(anyhow::anyhow!(
+                            "超过 {:?} 没有成功心跳，连接可能已失效",
+                            self.heartbeat_timeout
+                        ));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 建立到 Server 的客户端，失败时附带错误类别与排查提示（见 [`ConnectError`]）
+///
+/// 设置了代理且该地址不在 `NO_PROXY` 中时经代理隧道连接
+async fn connect(addr: &str, proxy: Option<&Proxy>) -> Result<ProbeServiceClient<Channel>> {
+    let channel = match proxy.filter(|proxy| proxy.applies_to(addr)) {
+        Some(proxy) => {
+            debug!("经代理 {} 连接 {}", proxy, addr);
+            proxy.connect(addr).await
+        }
+        None => transport::connect(addr).await,
+    }
+    .map_err(|e| ConnectError::new(addr, e))?;
+    Ok(ProbeServiceClient::new(channel))
+}
+
+/// 样本来源仍在时订阅，来源已结束时返回 `None`
+fn subscribe(
+    samples: &broadcast::WeakSender<MetricsRequest>,
+) -> Option<broadcast::Receiver<MetricsRequest>> {
+    samples.upgrade().map(|sender| sender.subscribe())
+}
+
+/// 是否为重试也不会成功的连接配置错误
+fn is_fatal(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<ConnectError>()
+        .is_some_and(|e| e.kind.is_fatal())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::probe_service_server::{ProbeService, ProbeServiceServer};
+    use common::proto::{HeartbeatResponse, MetricsResponse, StreamResponse};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use tokio_stream::StreamExt;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+    use tonic::{Request, Response, Status};
+
+    /// 模拟半开连接的 Server：流照常接收，但心跳永远不返回
+    #[derive(Default, Clone)]
+    struct SilentServer {
+        streams: Arc<AtomicUsize>,
+    }
 
-            if tx.send(request).await.is_err() {
-                return Err(anyhow::anyhow!("发送指标失败，流已关闭"));
+    #[tonic::async_trait]
+    impl ProbeService for SilentServer {
+        async fn report_metrics(
+            &self,
+            _request: Request<MetricsRequest>,
+        ) -> Result<Response<MetricsResponse>, Status> {
+            Err(Status::unimplemented("unused"))
+        }
+
+        async fn stream_metrics(
+            &self,
+            request: Request<tonic::Streaming<MetricsRequest>>,
+        ) -> Result<Response<StreamResponse>, Status> {
+            self.streams.fetch_add(1, Ordering::SeqCst);
+            let mut stream = request.into_inner();
+            tokio::spawn(async move { while let Some(Ok(_)) = stream.next().await {} });
+            Ok(Response::new(StreamResponse {
+                success: true,
+                message: String::new(),
+            }))
+        }
+
+        async fn heartbeat(
+            &self,
+            _request: Request<HeartbeatRequest>,
+        ) -> Result<Response<HeartbeatResponse>, Status> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_when_heartbeat_stalls() {
+        let server = SilentServer::default();
+        let streams = server.streams.clone();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(ProbeServiceServer::new(server))
+                .serve_with_incoming(incoming),
+        );
+
+        let mut agent = Agent::new(vec![format!("http://{}", addr)], 1);
+        agent.heartbeat_interval = Duration::from_millis(100);
+        agent.heartbeat_timeout = Duration::from_millis(300);
+        agent.reconnect_delay = Duration::from_millis(50);
+        let handle = tokio::spawn(async move { agent.run().await });
+
+        let reconnected = tokio::time::timeout(Duration::from_secs(10), async {
+            while streams.load(Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        handle.abort();
+
+        assert!(reconnected.is_ok(), "心跳停滞后 Agent 应重建流式连接");
+    }
+
+    /// 记录收到的样本时间戳，心跳正常返回并带上 `backoff_ms`
+    #[derive(Default, Clone)]
+    struct RecordingServer {
+        timestamps: Arc<std::sync::Mutex<Vec<i64>>>,
+        backoff_ms: u64,
+    }
+
+    #[tonic::async_trait]
+    impl ProbeService for RecordingServer {
+        async fn report_metrics(
+            &self,
+            _request: Request<MetricsRequest>,
+        ) -> Result<Response<MetricsResponse>, Status> {
+            Err(Status::unimplemented("unused"))
+        }
+
+        async fn stream_metrics(
+            &self,
+            request: Request<tonic::Streaming<MetricsRequest>>,
+        ) -> Result<Response<StreamResponse>, Status> {
+            let mut stream = request.into_inner();
+            let timestamps = self.timestamps.clone();
+            tokio::spawn(async move {
+                while let Some(Ok(metrics)) = stream.next().await {
+                    timestamps.lock().unwrap().push(metrics.timestamp);
+                }
+            });
+            Ok(Response::new(StreamResponse {
+                success: true,
+                message: String::new(),
+            }))
+        }
+
+        async fn heartbeat(
+            &self,
+            _request: Request<HeartbeatRequest>,
+        ) -> Result<Response<HeartbeatResponse>, Status> {
+            Ok(Response::new(HeartbeatResponse {
+                alive: true,
+                server_time: current_timestamp_ms(),
+                backoff_ms: self.backoff_ms,
+            }))
+        }
+    }
+
+    /// 放行前不读取流上的样本（模拟写入停滞的 Server），放行后记录收到样本的时间戳
+    #[derive(Clone)]
+    struct StalledServer {
+        timestamps: Arc<std::sync::Mutex<Vec<i64>>>,
+        released: Arc<tokio::sync::Notify>,
+    }
+
+    #[tonic::async_trait]
+    impl ProbeService for StalledServer {
+        async fn report_metrics(
+            &self,
+            _request: Request<MetricsRequest>,
+        ) -> Result<Response<MetricsResponse>, Status> {
+            Err(Status::unimplemented("unused"))
+        }
+
+        async fn stream_metrics(
+            &self,
+            request: Request<tonic::Streaming<MetricsRequest>>,
+        ) -> Result<Response<StreamResponse>, Status> {
+            let mut stream = request.into_inner();
+            let timestamps = self.timestamps.clone();
+            let released = self.released.clone();
+            tokio::spawn(async move {
+                released.notified().await;
+                while let Some(Ok(metrics)) = stream.next().await {
+                    timestamps.lock().unwrap().push(metrics.timestamp);
+                }
+            });
+            Ok(Response::new(StreamResponse {
+                success: true,
+                message: String::new(),
+            }))
+        }
+
+        async fn heartbeat(
+            &self,
+            _request: Request<HeartbeatRequest>,
+        ) -> Result<Response<HeartbeatResponse>, Status> {
+            Ok(Response::new(HeartbeatResponse {
+                alive: true,
+                server_time: current_timestamp_ms(),
+                backoff_ms: 0,
+            }))
+        }
+    }
+
+    /// 单次上报总是返回 `success: false` 的 Server
+    #[derive(Default, Clone)]
+    struct RejectingServer {
+        attempts: Arc<AtomicUsize>,
+        backoff_ms: u64,
+    }
+
+    #[tonic::async_trait]
+    impl ProbeService for RejectingServer {
+        async fn report_metrics(
+            &self,
+            _request: Request<MetricsRequest>,
+        ) -> Result<Response<MetricsResponse>, Status> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            Ok(Response::new(MetricsResponse {
+                success: false,
+                message: "persistence unavailable".to_string(),
+                backoff_ms: self.backoff_ms,
+            }))
+        }
+
+        async fn stream_metrics(
+            &self,
+            _request: Request<tonic::Streaming<MetricsRequest>>,
+        ) -> Result<Response<StreamResponse>, Status> {
+            Err(Status::unimplemented("unused"))
+        }
+
+        async fn heartbeat(
+            &self,
+            _request: Request<HeartbeatRequest>,
+        ) -> Result<Response<HeartbeatResponse>, Status> {
+            Err(Status::unimplemented("unused"))
+        }
+    }
+
+    /// 前 `failures` 次流式请求直接拒绝，之后记录收到样本中的探针自身指标
+    #[derive(Default, Clone)]
+    struct FlakyServer {
+        streams: Arc<AtomicUsize>,
+        failures: usize,
+        agent_metrics: Arc<std::sync::Mutex<Vec<common::proto::AgentMetrics>>>,
+    }
+
+    #[tonic::async_trait]
+    impl ProbeService for FlakyServer {
+        async fn report_metrics(
+            &self,
+            _request: Request<MetricsRequest>,
+        ) -> Result<Response<MetricsResponse>, Status> {
+            Err(Status::unimplemented("unused"))
+        }
+
+        async fn stream_metrics(
+            &self,
+            request: Request<tonic::Streaming<MetricsRequest>>,
+        ) -> Result<Response<StreamResponse>, Status> {
+            if self.streams.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(Status::unavailable("flaky"));
             }
+            let mut stream = request.into_inner();
+            let agent_metrics = self.agent_metrics.clone();
+            tokio::spawn(async move {
+                while let Some(Ok(metrics)) = stream.next().await {
+                    if let Some(agent) = metrics.system.and_then(|system| system.agent_metrics) {
+                        agent_metrics.lock().unwrap().push(agent);
+                    }
+                }
+            });
+            Ok(Response::new(StreamResponse {
+                success: true,
+                message: String::new(),
+            }))
+        }
 
-            collector::increment_metrics_sent();
-            info!("指标已发送");
+        async fn heartbeat(
+            &self,
+            _request: Request<HeartbeatRequest>,
+        ) -> Result<Response<HeartbeatResponse>, Status> {
+            Ok(Response::new(HeartbeatResponse {
+                alive: true,
+                server_time: current_timestamp_ms(),
+                backoff_ms: 0,
+            }))
         }
     }
+
+    async fn spawn_server(service: impl ProbeService) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(ProbeServiceServer::new(service))
+                .serve_with_incoming(incoming),
+        );
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_fans_out_same_samples() {
+        let first = RecordingServer::default();
+        let second = RecordingServer::default();
+        let (first_seen, second_seen) = (first.timestamps.clone(), second.timestamps.clone());
+
+        // 不可达或配置错误（致命）的 Server 都不应拖住其他连接
+        let dead = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let servers = vec![
+            spawn_server(first).await,
+            dead,
+            "https://127.0.0.1:1".to_string(),
+            spawn_server(second).await,
+        ];
+
+        let mut agent = Agent::new(servers, 1).with_report_mode(ReportMode::Broadcast);
+        agent.interval = Duration::from_millis(50);
+        agent.reconnect_delay = Duration::from_millis(50);
+        let handle = tokio::spawn(async move { agent.run().await });
+
+        // 同一次采集的样本（时间戳相同）应同时到达两个 Server
+        let shared = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                {
+                    let first = first_seen.lock().unwrap();
+                    let second = second_seen.lock().unwrap();
+                    let common = first.iter().filter(|ts| second.contains(ts)).count();
+                    if common >= 3 {
+                        break;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        handle.abort();
+
+        assert!(
+            shared.is_ok(),
+            "broadcast 模式下两个 Server 应收到相同的样本"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failover_skips_misconfigured_server() {
+        let server = RecordingServer::default();
+        let seen = server.timestamps.clone();
+        let servers = vec!["ftp://127.0.0.1:1".to_string(), spawn_server(server).await];
+
+        let mut agent = Agent::new(servers, 1);
+        agent.interval = Duration::from_millis(50);
+        agent.reconnect_delay = Duration::from_millis(50);
+        let handle = tokio::spawn(async move { agent.run().await });
+
+        let landed = tokio::time::timeout(Duration::from_secs(10), async {
+            while seen.lock().unwrap().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        assert!(!handle.is_finished(), "致命错误只应停止该地址的连接");
+        handle.abort();
+        assert!(landed.is_ok(), "应切换到可用的 Server 继续上报");
+
+        // 所有地址都配置错误时返回错误
+        let agent = Agent::new(vec!["ftp://127.0.0.1:1".to_string()], 1);
+        let result = tokio::time::timeout(Duration::from_secs(10), agent.run()).await;
+        assert!(result.expect("全部地址致命时应停止").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_reported_in_agent_metrics() {
+        let server = FlakyServer {
+            failures: 2,
+            ..Default::default()
+        };
+        let seen = server.agent_metrics.clone();
+
+        let mut agent = Agent::new(vec![spawn_server(server).await], 1);
+        agent.interval = Duration::from_millis(50);
+        agent.reconnect_delay = Duration::from_millis(50);
+        agent.collect_options.self_metrics = false;
+        let handle = tokio::spawn(async move { agent.run().await });
+
+        // 两次被拒后重连成功，之后的样本应带上重连次数与最近错误
+        let reported = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(agent) = seen.lock().unwrap().last() {
+                    if agent.reconnect_count >= 2 {
+                        return agent.clone();
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        handle.abort();
+
+        let agent = reported.expect("重连后的样本应报告重连次数");
+        assert!(!agent.last_error.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_report_once_retries_then_gives_up() {
+        let server = RejectingServer {
+            backoff_ms: 100,
+            ..Default::default()
+        };
+        let attempts = server.attempts.clone();
+        let mut agent = Agent::new(vec![spawn_server(server).await], 1);
+        agent.report_retry_delay = Duration::from_millis(1);
+
+        let errors_before = collector::errors_count();
+        let start = Instant::now();
+        let result = agent.report_once().await;
+
+        assert!(result.is_err(), "Server 持续返回失败时应报错");
+        assert_eq!(
+            attempts.load(Ordering::SeqCst),
+            REPORT_MAX_ATTEMPTS as usize
+        );
+        assert!(collector::errors_count() >= errors_before + REPORT_MAX_ATTEMPTS as u64);
+        // 重试等待取 Server 给出的 backoff_ms，而不是更短的本地退避
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_slows_down_on_backpressure() {
+        let server = RecordingServer {
+            backoff_ms: 400,
+            ..Default::default()
+        };
+        let seen = server.timestamps.clone();
+
+        let mut agent = Agent::new(vec![spawn_server(server).await], 1);
+        agent.interval = Duration::from_millis(20);
+        agent.heartbeat_interval = Duration::from_millis(50);
+        let handle = tokio::spawn(async move { agent.run().await });
+        // 暂停的时钟在空闲时自动推进，10 秒的上报不占用实际时间
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        handle.abort();
+
+        // 不限速时约 500 条；按 400ms 间隔约 25 条
+        let received = seen.lock().unwrap().len();
+        assert!(
+            (1..=30).contains(&received),
+            "积压期间应放慢上报，实际收到 {} 条",
+            received
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_backs_off_when_channel_fills() {
+        const STALLED: i64 = 150;
+        const TOTAL: i64 = 300;
+        let server = StalledServer {
+            timestamps: Arc::default(),
+            released: Arc::new(tokio::sync::Notify::new()),
+        };
+        let (seen, released) = (server.timestamps.clone(), server.released.clone());
+        let addr = spawn_server(server).await;
+
+        let mut agent = Agent::new(vec![addr.clone()], 1);
+        agent.interval = Duration::from_millis(10);
+        let (samples, rx) = broadcast::channel(TOTAL as usize);
+        let handle = tokio::spawn(async move { agent.run_stream(&addr, rx).await });
+
+        // 样本足够大，连接自身的缓冲很快用尽，之后的积压都留在发送通道中
+        let hostname = "x".repeat(64 * 1024);
+        for timestamp in 0..TOTAL {
+            if timestamp == STALLED {
+                released.notify_one();
+            }
+            samples
+                .send(MetricsRequest {
+                    agent_id: "agent-1".to_string(),
+                    timestamp,
+                    hostname: hostname.clone(),
+                    ..Default::default()
+                })
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(samples);
+        handle.await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let seen = seen.lock().unwrap();
+        // 停滞期间积压到高水位后放慢并丢弃样本，而不是全部排队等待发送
+        let stalled = seen.iter().filter(|&&ts| ts < STALLED).count() as i64;
+        assert!(
+            stalled < STALLED,
+            "停滞期间应丢弃部分样本，实际收到 {} 条",
+            stalled
+        );
+        // 积压消化后在同一连接上恢复按间隔发送
+        let recovered = seen.iter().filter(|&&ts| ts >= STALLED + 50).count() as i64;
+        assert_eq!(
+            recovered,
+            TOTAL - STALLED - 50,
+            "{:?}",
+            &seen[seen.len() - 10..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spool_backlog_sent_as_aggregated_samples() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spool = Spool::open(SpoolOptions {
+            dir: dir.path().to_path_buf(),
+            max_bytes: DEFAULT_SPOOL_MAX_MB << 20,
+            compress: false,
+        })
+        .unwrap();
+        // 两小时前断网期间积压的 600 条样本，每秒一条
+        let start = current_timestamp_ms() - 2 * 3600 * 1000;
+        let start = start - start % 60_000;
+        for i in 0..600 {
+            spool
+                .push(&MetricsRequest {
+                    agent_id: "agent-1".to_string(),
+                    timestamp: start + i * 1000,
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        let server = RecordingServer::default();
+        let seen = server.timestamps.clone();
+        let agent = Agent::new(vec![spawn_server(server).await], 1)
+            .with_spool(Some(spool))
+            .with_spool_aggregation(Some(SpoolAggregation {
+                after: Duration::from_secs(60),
+                bucket: Duration::from_secs(60),
+            }));
+        let handle = tokio::spawn(async move { agent.run().await });
+
+        let backlog = || -> Vec<i64> {
+            let seen = seen.lock().unwrap();
+            seen.iter()
+                .copied()
+                .filter(|&t| t < start + 600_000)
+                .collect()
+        };
+        let landed = tokio::time::timeout(Duration::from_secs(5), async {
+            while backlog().len() < 10 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        handle.abort();
+
+        // 每分钟合并为一条，以桶内最后一条样本的时间戳发出
+        assert!(landed.is_ok(), "缓冲中的样本应补发到 Server");
+        let expected: Vec<i64> = (0..10).map(|m| start + m * 60_000 + 59_000).collect();
+        assert_eq!(backlog(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_replay_file_lands_samples() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.ndjson");
+        let lines: Vec<String> = [1_000i64, 3_000, 2_000]
+            .iter()
+            .map(|&timestamp| {
+                serde_json::to_string(&MetricsRequest {
+                    agent_id: "recorded".to_string(),
+                    timestamp,
+                    hostname: "recorded-host".to_string(),
+                    ..Default::default()
+                })
+                .unwrap()
+            })
+            .collect();
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let server = RecordingServer::default();
+        let seen = server.timestamps.clone();
+        let agent = Agent::new(vec![spawn_server(server).await], 1);
+
+        // 1000 倍速下 2 秒的录制约 2ms 播完，全部发出后 replay 返回
+        let replay = Replay::load(&path).unwrap().with_speed(1000.0);
+        tokio::time::timeout(Duration::from_secs(10), agent.replay(replay))
+            .await
+            .expect("回放不循环时应在发完后返回")
+            .unwrap();
+
+        let landed = tokio::time::timeout(Duration::from_secs(5), async {
+            while seen.lock().unwrap().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await;
+        assert!(landed.is_ok(), "回放的样本应全部到达 Server");
+        assert_eq!(*seen.lock().unwrap(), vec![1_000, 2_000, 3_000]);
+    }
+
+    #[test]
+    fn test_report_mode_from_str() {
+        assert_eq!("failover".parse(), Ok(ReportMode::Failover));
+        assert_eq!("broadcast".parse(), Ok(ReportMode::Broadcast));
+        assert!("roundrobin".parse::<ReportMode>().is_err());
+    }
+
+    #[test]
+    fn test_print_sample_outputs_json() {
+        let agent = Agent::new(vec!["http://127.0.0.1:1".to_string()], 1);
+        let mut out = Vec::new();
+        agent.print_sample(&mut out).unwrap();
+
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        for field in ["agent_id", "timestamp", "hostname", "system"] {
+            assert!(value.get(field).is_some(), "missing field {}", field);
+        }
+        let system = &value["system"];
+        for field in [
+            "cpu",
+            "memory",
+            "disks",
+            "network",
+            "system_info",
+            "agent_metrics",
+        ] {
+            assert!(system.get(field).is_some(), "missing system.{}", field);
+        }
+        assert!(system["cpu"]["core_count"].as_i64().unwrap() > 0);
+    }
+
+    /// 要求 Basic 认证的 HTTP CONNECT 代理，返回代理地址与成功建立的隧道数
+    async fn spawn_http_proxy(credentials: &'static str) -> (String, Arc<AtomicUsize>) {
+        use base64::Engine;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tunnels = Arc::new(AtomicUsize::new(0));
+        let expected = format!(
+            "proxy-authorization: basic {}",
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        )
+        .to_ascii_lowercase();
+        let counter = tunnels.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut client, _) = listener.accept().await.unwrap();
+                let (expected, counter) = (expected.clone(), counter.clone());
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        head.push(client.read_u8().await?);
+                    }
+                    let head = String::from_utf8_lossy(&head).to_ascii_lowercase();
+                    let target = head
+                        .split_whitespace()
+                        .nth(1)
+                        .unwrap_or_default()
+                        .to_string();
+                    if !head.lines().any(|line| line == expected) {
+                        client
+                            .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                            .await?;
+                        return Ok::<_, std::io::Error>(());
+                    }
+                    let mut upstream = tokio::net::TcpStream::connect(target).await?;
+                    client
+                        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .await?;
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+                    Ok(())
+                });
+            }
+        });
+        (format!("http://{}", addr), tunnels)
+    }
+
+    /// 要求用户名/密码认证的 SOCKS5 代理，返回代理地址与成功建立的隧道数
+    async fn spawn_socks5_proxy(
+        user: &'static str,
+        pass: &'static str,
+    ) -> (String, Arc<AtomicUsize>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tunnels = Arc::new(AtomicUsize::new(0));
+        let counter = tunnels.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut client, _) = listener.accept().await.unwrap();
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let mut greeting = [0u8; 2];
+                    client.read_exact(&mut greeting).await?;
+                    let mut methods = vec![0u8; greeting[1] as usize];
+                    client.read_exact(&mut methods).await?;
+                    if !methods.contains(&0x02) {
+                        client.write_all(&[0x05, 0xff]).await?;
+                        return Ok::<_, std::io::Error>(());
+                    }
+                    client.write_all(&[0x05, 0x02]).await?;
+
+                    let field = |len: usize| vec![0u8; len];
+                    client.read_u8().await?;
+                    let mut username = field(client.read_u8().await? as usize);
+                    client.read_exact(&mut username).await?;
+                    let mut password = field(client.read_u8().await? as usize);
+                    client.read_exact(&mut password).await?;
+                    if username != user.as_bytes() || password != pass.as_bytes() {
+                        client.write_all(&[0x01, 0x01]).await?;
+                        return Ok(());
+                    }
+                    client.write_all(&[0x01, 0x00]).await?;
+
+                    let mut request = [0u8; 4];
+                    client.read_exact(&mut request).await?;
+                    let host = match request[3] {
+                        0x01 => {
+                            let mut ip = [0u8; 4];
+                            client.read_exact(&mut ip).await?;
+                            std::net::Ipv4Addr::from(ip).to_string()
+                        }
+                        _ => {
+                            let mut name = field(client.read_u8().await? as usize);
+                            client.read_exact(&mut name).await?;
+                            String::from_utf8_lossy(&name).into_owned()
+                        }
+                    };
+                    let port = client.read_u16().await?;
+                    let mut upstream = tokio::net::TcpStream::connect((host, port)).await?;
+                    client
+                        .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                        .await?;
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+                    Ok(())
+                });
+            }
+        });
+        (format!("socks5://{}:{}@{}", user, pass, addr), tunnels)
+    }
+
+    #[tokio::test]
+    async fn test_reports_through_http_and_socks5_proxies() {
+        let (http_proxy, http_tunnels) = spawn_http_proxy("agent:s3cret").await;
+        let (socks_proxy, socks_tunnels) = spawn_socks5_proxy("agent", "s3cret").await;
+        let http_proxy = http_proxy.replace("http://", "http://agent:s3cret@");
+
+        for (proxy, tunnels) in [(http_proxy, http_tunnels), (socks_proxy, socks_tunnels)] {
+            let server = RecordingServer::default();
+            let seen = server.timestamps.clone();
+            let mut agent = Agent::new(vec![spawn_server(server).await], 1)
+                .with_proxy(Some(proxy.parse().unwrap()));
+            agent.interval = Duration::from_millis(50);
+            let handle = tokio::spawn(async move { agent.run().await });
+
+            let delivered = tokio::time::timeout(Duration::from_secs(10), async {
+                while seen.lock().unwrap().len() < 2 {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            })
+            .await;
+            handle.abort();
+
+            assert!(delivered.is_ok(), "经代理 {} 应能上报", proxy);
+            assert!(tunnels.load(Ordering::SeqCst) >= 1, "{}", proxy);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_auth_failure_classified() {
+        let (proxy, tunnels) = spawn_http_proxy("agent:s3cret").await;
+        let proxy = proxy.replace("http://", "http://agent:wrong@");
+        let server = spawn_server(RecordingServer::default()).await;
+
+        let error = connect(&server, Some(&proxy.parse().unwrap()))
+            .await
+            .unwrap_err();
+        let error = error.downcast_ref::<ConnectError>().unwrap();
+        assert_eq!(error.kind, ConnectErrorKind::Proxy, "{}", error);
+        assert!(error.to_string().contains("407"), "{}", error);
+        assert!(!error.to_string().contains("wrong"), "{}", error);
+        assert_eq!(tunnels.load(Ordering::SeqCst), 0);
+
+        // NO_PROXY 中的地址直连
+        let proxy: Proxy = proxy.parse().unwrap();
+        let proxy = proxy.with_no_proxy("127.0.0.1");
+        assert!(connect(&server, Some(&proxy)).await.is_ok());
+    }
 }
@@ -0,0 +1,88 @@
+//! 发送通道背压
+//!
+//! 流式连接中样本先进入有界的发送通道，再由 gRPC 流逐条发出。Server 或网络暂时变慢时通道
+//! 逐渐填满，继续按原间隔发送只会让发送阻塞、积压越来越旧的样本，最终以连接失效告终。
+//! 这里以通道积压作为信号：积压达到高水位时把本地发送间隔加倍（最多为上报间隔的
+//! `MAX_SLOWDOWN` 倍），间隔内的样本直接丢弃；积压回落到低水位以下时恢复原间隔，无需重连
+
+use std::time::Duration;
+
+/// 积压达到容量的该比例（分子/4）时放慢
+const HIGH_WATERMARK_QUARTERS: usize = 3;
+
+/// 积压回落到容量的该比例（分子/4）以下时恢复
+const LOW_WATERMARK_QUARTERS: usize = 1;
+
+/// 本地发送间隔最多放慢到上报间隔的倍数
+const MAX_SLOWDOWN: u32 = 8;
+
+/// 发送间隔的变化
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PaceChange {
+    /// 间隔放慢到给定值，本次样本应丢弃
+    Slowed(Duration),
+    /// 积压已消化，恢复按上报间隔发送
+    Recovered,
+}
+
+/// 按发送通道积压调整本地发送间隔
+#[derive(Debug)]
+pub(crate) struct SendPacer {
+    interval: Duration,
+    /// 当前的本地最小发送间隔，为零时不限制
+    gap: Duration,
+}
+
+impl SendPacer {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            interval: interval.max(Duration::from_millis(1)),
+            gap: Duration::ZERO,
+        }
+    }
+
+    /// 当前的本地最小发送间隔
+    pub(crate) fn gap(&self) -> Duration {
+        self.gap
+    }
+
+    /// 按通道积压（已占用条数 / 容量）调整发送间隔，间隔变化时返回变化
+    pub(crate) fn observe(&mut self, backlog: usize, capacity: usize) -> Option<PaceChange> {
+        if backlog * 4 >= capacity * HIGH_WATERMARK_QUARTERS {
+            let slowed = (self.gap.max(self.interval) * 2).min(self.interval * MAX_SLOWDOWN);
+            if slowed == self.gap {
+                return None;
+            }
+            self.gap = slowed;
+            Some(PaceChange::Slowed(slowed))
+        } else if backlog * 4 <= capacity * LOW_WATERMARK_QUARTERS && !self.gap.is_zero() {
+            self.gap = Duration::ZERO;
+            Some(PaceChange::Recovered)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_doubles_up_to_cap_and_resets() {
+        let interval = Duration::from_secs(1);
+        let mut pacer = SendPacer::new(interval);
+        assert_eq!(pacer.observe(50, 100), None);
+
+        let gaps: Vec<_> = std::iter::from_fn(|| pacer.observe(90, 100)).collect();
+        assert_eq!(
+            gaps,
+            [2, 4, 8].map(|secs| PaceChange::Slowed(Duration::from_secs(secs)))
+        );
+        // 积压介于高低水位之间时保持
+        assert_eq!(pacer.observe(50, 100), None);
+        assert_eq!(pacer.gap(), interval * 8);
+        assert_eq!(pacer.observe(10, 100), Some(PaceChange::Recovered));
+        assert_eq!(pacer.gap(), Duration::ZERO);
+    }
+}
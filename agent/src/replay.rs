@@ -0,0 +1,147 @@
+//! 回放录制的样本
+//!
+//! 从 NDJSON 文件（每行一个 `MetricsRequest`，如 Server 的 `history.ndjson` 导出）读取样本，
+//! 按原始时间间隔（可加速）经正常的流式通道发往 Server，无需真实主机即可压测 Server、
+//! 开发界面或复现故障现场。样本保留录制时的 agent_id 与主机名
+
+use anyhow::{Context, Result};
+use common::proto::MetricsRequest;
+use common::utils::current_timestamp_ms;
+use std::io::BufRead;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::info;
+
+/// 发送端积压达到该条数时暂停回放，等待连接发出，避免加速回放时样本被挤掉
+const REPLAY_BACKLOG: usize = 8;
+
+/// 等待连接建立时的轮询间隔
+const REPLAY_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// 回放来源
+#[derive(Debug, Clone)]
+pub struct Replay {
+    samples: Vec<MetricsRequest>,
+    speed: f64,
+    looping: bool,
+    rewrite_timestamps: bool,
+}
+
+impl Replay {
+    /// 从 NDJSON 文件读取样本
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("无法打开回放文件 {}", path.display()))?;
+        Self::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("无法读取回放文件 {}", path.display()))
+    }
+
+    /// 逐行解析 NDJSON，空行跳过；样本按时间戳排序
+    pub fn from_reader(reader: impl BufRead) -> Result<Self> {
+        let mut samples = Vec::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let sample: MetricsRequest = serde_json::from_str(&line)
+                .with_context(|| format!("第 {} 行不是合法的样本", index + 1))?;
+            samples.push(sample);
+        }
+        if samples.is_empty() {
+            anyhow::bail!("回放文件中没有样本");
+        }
+        samples.sort_by_key(|sample| sample.timestamp);
+        Ok(Self {
+            samples,
+            speed: 1.0,
+            looping: false,
+            rewrite_timestamps: false,
+        })
+    }
+
+    /// 回放速度倍数，如 10 表示以 10 倍速发送（默认 1）
+    pub fn with_speed(mut self, speed: f64) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// 播放完后从头循环
+    pub fn with_loop(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    /// 发送时把时间戳改写为当前时间；不改写时循环回放会重复发送相同的时间戳
+    pub fn with_rewrite_timestamps(mut self, rewrite: bool) -> Self {
+        self.rewrite_timestamps = rewrite;
+        self
+    }
+
+    /// 样本条数
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// 按原始间隔把样本送入发送通道，播放完（不循环时）丢弃发送端，连接随之结束
+    pub(crate) async fn feed(self, samples: broadcast::Sender<MetricsRequest>) {
+        let speed = if self.speed.is_finite() && self.speed > 0.0 {
+            self.speed
+        } else {
+            1.0
+        };
+        let mut round = 1u64;
+        loop {
+            let mut previous: Option<i64> = None;
+            for sample in &self.samples {
+                if let Some(previous) = previous {
+                    let gap = (sample.timestamp - previous).max(0) as f64 / speed;
+                    tokio::time::sleep(Duration::from_secs_f64(gap / 1000.0)).await;
+                }
+                previous = Some(sample.timestamp);
+
+                // 没有连接或连接发送跟不上时等待，回放不丢样本
+                while samples.receiver_count() == 0 || samples.len() >= REPLAY_BACKLOG {
+                    tokio::time::sleep(REPLAY_POLL_INTERVAL).await;
+                }
+                let mut sample = sample.clone();
+                if self.rewrite_timestamps {
+                    sample.timestamp = current_timestamp_ms();
+                }
+                let _ = samples.send(sample);
+            }
+            info!("第 {} 轮回放完成，共 {} 条样本", round, self.samples.len());
+            if !self.looping {
+                return;
+            }
+            round += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ndjson() {
+        let input = concat!(
+            r#"{"agent_id":"a","timestamp":2000,"system":null,"hostname":"h","sequence":2,"labels":{}}"#,
+            "\n\n",
+            r#"{"agent_id":"a","timestamp":1000,"system":null,"hostname":"h","sequence":1,"labels":{}}"#,
+            "\n",
+        );
+        let replay = Replay::from_reader(input.as_bytes()).unwrap();
+        assert_eq!(replay.len(), 2);
+        assert_eq!(replay.samples[0].timestamp, 1000);
+
+        let error = Replay::from_reader("{}\nnot json\n".as_bytes()).unwrap_err();
+        assert!(format!("{:#}", error).contains("第 1 行"));
+        assert!(Replay::from_reader("\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,382 @@
+//! 端到端自检
+//!
+//! `iris-agent --self-test` 用与正常上报相同的连接与代理设置，向每个 Server 单次上报一条真实
+//! 采集的样本，再经 HTTP API 查回，逐步报告域名解析、TLS、连接、准入、接收与查询是否成功，
+//! 失败时给出排查提示，回答"为什么界面上看不到数据"。某一步失败后其余步骤不再执行
+
+use crate::{connect, Agent, ConnectError, ConnectErrorKind};
+use common::proto::MetricsRequest;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// 自检步骤，按执行顺序
+const STEPS: [&str; 6] = ["dns", "tls", "connect", "auth", "ingest", "query"];
+
+/// 建立连接与单次上报各自的超时
+const RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 经 HTTP API 查回样本的最长等待时间
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 步骤结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepStatus {
+    Passed,
+    Failed,
+    /// 不适用，或前一步失败后未执行
+    Skipped,
+}
+
+/// 单个步骤的结果与说明
+#[derive(Debug, Clone)]
+pub struct SelfTestStep {
+    pub name: &'static str,
+    pub status: StepStatus,
+    pub detail: String,
+}
+
+/// 对单个 Server 的自检结果
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub server: String,
+    /// 查询所用的 HTTP API 地址，无法推断时为 None
+    pub api: Option<String>,
+    pub steps: Vec<SelfTestStep>,
+}
+
+impl SelfTestReport {
+    /// 是否没有失败的步骤
+    pub fn passed(&self) -> bool {
+        self.steps
+            .iter()
+            .all(|step| step.status != StepStatus::Failed)
+    }
+
+    fn record(&mut self, status: StepStatus, detail: impl Into<String>) {
+        self.steps.push(SelfTestStep {
+            name: STEPS[self.steps.len()],
+            status,
+            detail: detail.into(),
+        });
+    }
+
+    fn pass(&mut self, detail: impl Into<String>) {
+        self.record(StepStatus::Passed, detail);
+    }
+
+    fn skip(&mut self, detail: impl Into<String>) {
+        self.record(StepStatus::Skipped, detail);
+    }
+
+    /// 记录失败，其余步骤标记为未执行
+    fn fail(mut self, detail: impl Into<String>) -> Self {
+        self.record(StepStatus::Failed, detail);
+        while self.steps.len() < STEPS.len() {
+            self.skip("前一步失败，未执行");
+        }
+        self
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.api {
+            Some(api) => writeln!(f, "自检 {}（HTTP API {}）", self.server, api)?,
+            None => writeln!(f, "自检 {}", self.server)?,
+        }
+        for step in &self.steps {
+            let status = match step.status {
+                StepStatus::Passed => "通过",
+                StepStatus::Failed => "失败",
+                StepStatus::Skipped => "跳过",
+            };
+            writeln!(f, "  [{}] {:<7} {}", status, step.name, step.detail)?;
+        }
+        write!(f, "结果: {}", if self.passed() { "通过" } else { "未通过" })
+    }
+}
+
+/// 由 gRPC 地址推断 HTTP API 地址：与 Server 默认一致，为同一主机的 gRPC 端口 + 1。
+/// Unix socket 地址无法推断
+pub fn default_api_url(addr: &str) -> Option<String> {
+    let authority = addr.strip_prefix("http://")?.split('/').next()?;
+    let (host, port) = authority.rsplit_once(':')?;
+    let port: u16 = port.parse().ok()?;
+    Some(format!("http://{}:{}", host, port.checked_add(1)?))
+}
+
+/// 对单个 Server 执行自检
+pub(crate) async fn run(agent: &Agent, addr: &str, api: Option<String>) -> SelfTestReport {
+    let mut report = SelfTestReport {
+        server: addr.to_string(),
+        api,
+        steps: Vec::new(),
+    };
+    let lower = addr.to_ascii_lowercase();
+    let is_unix = lower.starts_with(common::transport::UNIX_PREFIX);
+    let proxy = agent.proxy.as_ref().filter(|proxy| proxy.applies_to(addr));
+
+    // 域名解析
+    if is_unix {
+        report.skip("Unix socket 地址，无需解析");
+    } else if let Some(proxy) = proxy {
+        report.skip(format!("经代理 {} 连接，由代理解析", proxy));
+    } else {
+        let Some(authority) = authority(addr) else {
+            return report.fail(format!(
+                "无法解析地址 {}。{}",
+                addr,
+                ConnectErrorKind::InvalidAddress.hint()
+            ));
+        };
+        match tokio::time::timeout(RPC_TIMEOUT, tokio::net::lookup_host(authority.clone())).await {
+            Ok(Ok(resolved)) => {
+                let resolved: Vec<String> = resolved.map(|a| a.to_string()).collect();
+                report.pass(format!("{} 解析为 {}", authority, resolved.join(", ")));
+            }
+            Ok(Err(e)) => {
+                return report.fail(format!(
+                    "{} 解析失败: {}。{}",
+                    authority,
+                    e,
+                    ConnectErrorKind::Dns.hint()
+                ))
+            }
+            Err(_) => {
+                return report.fail(format!(
+                    "{} 解析超过 {:?} 未完成。{}",
+                    authority,
+                    RPC_TIMEOUT,
+                    ConnectErrorKind::Dns.hint()
+                ))
+            }
+        }
+    }
+
+    // TLS：当前构建只支持明文 gRPC
+    if lower.starts_with("https://") {
+        return report.fail(ConnectErrorKind::TlsUnsupported.hint());
+    }
+    report.skip("明文连接，未使用 TLS");
+
+    // 建立连接
+    let mut client =
+        match tokio::time::timeout(RPC_TIMEOUT, connect(addr, agent.proxy.as_ref())).await {
+            Ok(Ok(client)) => client,
+            Ok(Err(e)) => return report.fail(format!("{:#}", e)),
+            Err(_) => {
+                return report.fail(format!(
+                    "连接超过 {:?} 未完成。{}",
+                    RPC_TIMEOUT,
+                    ConnectErrorKind::Timeout.hint()
+                ))
+            }
+        };
+
+    // 单次上报：HTTP/2 握手在首个请求时进行，协议不符等连接错误在此暴露
+    let sample = agent.collect_sample();
+    let response =
+        match tokio::time::timeout(RPC_TIMEOUT, client.report_metrics(sample.clone())).await {
+            Ok(Ok(response)) => response.into_inner(),
+            Ok(Err(status)) if status.code() == tonic::Code::PermissionDenied => {
+                report.pass("已建立 gRPC 连接");
+                return report.fail(format!(
+                    "Server 拒绝了 agent_id {}: {}。Server 开启了准入名单，请用 --allow-agent 或 \
+                 POST /api/admin/agents 登记该 ID",
+                    sample.agent_id,
+                    status.message()
+                ));
+            }
+            Ok(Err(status)) => {
+                let error = ConnectError::from_status(addr, status);
+                if error.downcast_ref::<ConnectError>().is_some() {
+                    return report.fail(format!("{:#}", error));
+                }
+                report.pass("已建立 gRPC 连接");
+                report.pass("Server 接受该 agent_id");
+                return report.fail(format!("上报失败: {:#}", error));
+            }
+            Err(_) => {
+                return report.fail(format!(
+                    "单次上报超过 {:?} 未返回，连接可能被防火墙或代理阻断。{}",
+                    RPC_TIMEOUT,
+                    ConnectErrorKind::Timeout.hint()
+                ))
+            }
+        };
+    report.pass("已建立 gRPC 连接");
+    report.pass(format!("Server 接受 agent_id {}", sample.agent_id));
+    if !response.success {
+        return report.fail(format!(
+            "Server 未能接收样本: {}。请查看 Server 日志（如持久化队列不可用）",
+            response.message
+        ));
+    }
+    report.pass(format!(
+        "Server 已接收样本（timestamp={}）",
+        sample.timestamp
+    ));
+
+    // 经 HTTP API 查回
+    let Some(api) = report.api.clone() else {
+        return report.fail("无法由 Unix socket 地址推断 HTTP API 地址，请以 --api 指定");
+    };
+    match query_back(&api, &sample).await {
+        Ok(()) => report.pass("HTTP API 返回了刚上报的样本"),
+        Err(detail) => return report.fail(detail),
+    }
+    report
+}
+
+/// 地址中的 host:port，未写端口时为 80
+fn authority(addr: &str) -> Option<String> {
+    let rest = addr
+        .strip_prefix("http://")
+        .or_else(|| addr.strip_prefix("https://"))?;
+    let authority = rest.split('/').next().filter(|a| !a.is_empty())?;
+    if authority.ends_with(']') || !authority.contains(':') {
+        Some(format!("{}:80", authority))
+    } else {
+        Some(authority.to_string())
+    }
+}
+
+/// 轮询最新指标接口，直到返回刚上报的样本或超时
+async fn query_back(api: &str, sample: &MetricsRequest) -> Result<(), String> {
+    let path = format!(
+        "/api/agents/{}/metrics",
+        utf8_percent_encode(&sample.agent_id, NON_ALPHANUMERIC)
+    );
+    let deadline = Instant::now() + QUERY_TIMEOUT;
+    loop {
+        let (status, body) = http_get(api, &path).await.map_err(|e| {
+            format!(
+                "请求 {}{} 失败: {}。请确认 HTTP API 地址（--api）与 Server 的 --http-addr 一致",
+                api, path, e
+            )
+        })?;
+        let latest = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|value| value["data"]["timestamp"].as_i64());
+        if status == 200 && latest >= Some(sample.timestamp) {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(match status {
+                200 => format!(
+                    "HTTP API 返回的最新样本（timestamp={}）早于刚上报的样本，\
+                     --api 可能指向了另一台 Server",
+                    latest.unwrap_or_default()
+                ),
+                404 => {
+                    "Server 已确认接收，但 HTTP API 查不到该 Agent，--api 可能指向了另一台 Server"
+                        .to_string()
+                }
+                status => format!("HTTP API 返回状态码 {}: {}", status, body.trim()),
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// 以 HTTP/1.0 发送 GET 请求，返回状态码与响应体；只支持 http://
+async fn http_get(api: &str, path: &str) -> Result<(u16, String), String> {
+    let rest = api
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("HTTP API 地址应为 http://host:port: {}", api))?;
+    let (authority, prefix) = match rest.split_once('/') {
+        Some((authority, prefix)) => (authority, format!("/{}", prefix.trim_end_matches('/'))),
+        None => (rest, String::new()),
+    };
+    let request = async {
+        let mut stream = tokio::net::TcpStream::connect(authority).await?;
+        stream
+            .write_all(
+                format!(
+                    "GET {}{} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n\r\n",
+                    prefix, path, authority
+                )
+                .as_bytes(),
+            )
+            .await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, std::io::Error>(String::from_utf8_lossy(&response).into_owned())
+    };
+    let response = tokio::time::timeout(RPC_TIMEOUT, request)
+        .await
+        .map_err(|_| format!("超过 {:?} 未响应", RPC_TIMEOUT))?
+        .map_err(|e| e.to_string())?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| "响应不完整".to_string())?;
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| "无法解析响应状态行".to_string())?;
+    Ok((status, body.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_api_url() {
+        assert_eq!(
+            default_api_url("http://10.0.0.1:50051").as_deref(),
+            Some("http://10.0.0.1:50052")
+        );
+        assert_eq!(
+            default_api_url("http://[::1]:50051").as_deref(),
+            Some("http://[::1]:50052")
+        );
+        assert_eq!(default_api_url("unix:/run/iris.sock"), None);
+        assert_eq!(
+            authority("http://iris.example.com").as_deref(),
+            Some("iris.example.com:80")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_self_test_passes_against_in_process_server() {
+        let handle = server::ServerBuilder::new("127.0.0.1:0")
+            .http_addr("127.0.0.1:0".parse().unwrap())
+            .memory_only()
+            .start()
+            .await
+            .unwrap();
+
+        // start 返回时已完成绑定，连接在服务开始接受前排队等待
+        let agent = Agent::new(vec![format!("http://{}", handle.grpc_addr())], 1);
+        let api = format!("http://{}", handle.http_addr());
+        let reports = agent.self_test(Some(&api)).await;
+        assert_eq!(reports.len(), 1);
+        let report = &reports[0];
+        assert!(report.passed(), "{}", report);
+        let steps: Vec<_> = report.steps.iter().map(|s| (s.name, s.status)).collect();
+        assert_eq!(
+            steps,
+            [
+                ("dns", StepStatus::Passed),
+                ("tls", StepStatus::Skipped),
+                ("connect", StepStatus::Passed),
+                ("auth", StepStatus::Passed),
+                ("ingest", StepStatus::Passed),
+                ("query", StepStatus::Passed),
+            ]
+        );
+        handle.shutdown().await.unwrap();
+
+        // Server 停止后在连接一步失败，其余步骤不再执行
+        let report = agent.self_test(Some(&api)).await.remove(0);
+        assert!(!report.passed());
+        assert_eq!(report.steps[2].status, StepStatus::Failed);
+        assert!(report.steps[2].detail.contains("refused"), "{}", report);
+        assert_eq!(report.steps[5].status, StepStatus::Skipped);
+    }
+}
@@ -0,0 +1,484 @@
+//! 离线样本磁盘缓冲
+//!
+//! 所有 Server 都连不上时，采集到的样本写入本地目录，连接恢复后先补发缓冲中的样本再继续
+//! 实时上报。缓冲按段文件存放（`<序号>.spool`，压缩时为 `<序号>.spool.gz`），总大小超过
+//! 上限时丢弃最旧的段。每条记录为 4 字节小端长度加 protobuf 编码的 `MetricsRequest`，
+//! 压缩时整个段是一个 gzip 流，每写入一条记录同步刷新一次，记录之间共享压缩字典。
+//!
+//! 补发时先读取最旧的段，段内样本全部交给连接后才删除该段；中途断线时把未发出的样本写回
+//! 该段，下次连接从这里继续。进程崩溃或断电可能在段尾留下不完整的记录（或缺少 gzip 结尾），
+//! 读取时跳过这些记录，不影响之前的样本
+
+use anyhow::{Context, Result};
+use common::proto::MetricsRequest;
+use common::utils::{format_bytes, ByteUnits};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prost::Message;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// 默认缓冲上限（MiB）
+pub const DEFAULT_SPOOL_MAX_MB: u64 = 64;
+
+/// 单个段文件的最小大小，上限很小时避免频繁切换段
+const MIN_SEGMENT_BYTES: u64 = 64 * 1024;
+
+/// 段文件数：单段大小为上限的 1/16，丢弃最旧的段时一次最多丢掉约 6% 的缓冲
+const SEGMENTS_PER_SPOOL: u64 = 16;
+
+/// 离线缓冲选项
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpoolOptions {
+    /// 缓冲目录，不存在时创建
+    pub dir: PathBuf,
+    /// 缓冲总大小上限（字节）
+    pub max_bytes: u64,
+    /// 是否以 gzip 压缩存储
+    pub compress: bool,
+}
+
+/// 一个段文件
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    id: u64,
+    compressed: bool,
+    bytes: u64,
+}
+
+impl Segment {
+    fn path(&self, dir: &Path) -> PathBuf {
+        let ext = if self.compressed { "spool.gz" } else { "spool" };
+        dir.join(format!("{:020}.{}", self.id, ext))
+    }
+}
+
+/// 当前段的写入句柄
+#[derive(Debug)]
+enum SegmentWriter {
+    Plain(File),
+    Gzip(GzEncoder<File>),
+}
+
+impl SegmentWriter {
+    fn new(file: File, compress: bool) -> Self {
+        if compress {
+            Self::Gzip(GzEncoder::new(file, Compression::default()))
+        } else {
+            Self::Plain(file)
+        }
+    }
+
+    /// 写入一条记录并刷新到文件，返回文件当前大小
+    fn append(&mut self, record: &[u8]) -> io::Result<u64> {
+        let file = match self {
+            Self::Plain(file) => {
+                file.write_all(record)?;
+                &*file
+            }
+            Self::Gzip(encoder) => {
+                encoder.write_all(record)?;
+                // 同步刷新：已写入的记录在崩溃后仍可解压
+                encoder.flush()?;
+                encoder.get_ref()
+            }
+        };
+        Ok(file.metadata()?.len())
+    }
+
+    /// 结束写入；压缩段写入 gzip 结尾，返回文件最终大小
+    fn finish(self) -> io::Result<u64> {
+        let file = match self {
+            Self::Plain(file) => file,
+            Self::Gzip(encoder) => encoder.finish()?,
+        };
+        Ok(file.metadata()?.len())
+    }
+}
+
+/// 离线样本磁盘缓冲
+#[derive(Debug)]
+pub struct Spool {
+    options: SpoolOptions,
+    /// 从旧到新排列的段，最后一个为当前写入的段
+    segments: VecDeque<Segment>,
+    /// 当前段的写入句柄，补发读取当前段时关闭，下次写入时新建段
+    writer: Option<SegmentWriter>,
+}
+
+impl Spool {
+    /// 打开缓冲目录，已有的段（包括上次运行留下的）按序号排列，之后补发
+    pub fn open(options: SpoolOptions) -> Result<Self> {
+        std::fs::create_dir_all(&options.dir)
+            .with_context(|| format!("无法创建离线缓冲目录 {}", options.dir.display()))?;
+
+        let mut segments = Vec::new();
+        for entry in std::fs::read_dir(&options.dir)
+            .with_context(|| format!("无法读取离线缓冲目录 {}", options.dir.display()))?
+        {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            let (id, compressed) = if let Some(id) = name.strip_suffix(".spool.gz") {
+                (id, true)
+            } else if let Some(id) = name.strip_suffix(".spool") {
+                (id, false)
+            } else {
+                continue;
+            };
+            let Ok(id) = id.parse() else {
+                continue;
+            };
+            segments.push(Segment {
+                id,
+                compressed,
+                bytes: entry.metadata()?.len(),
+            });
+        }
+        segments.sort_by_key(|segment| segment.id);
+
+        let spool = Self {
+            options,
+            segments: segments.into(),
+            writer: None,
+        };
+        if !spool.is_empty() {
+            info!(
+                "离线缓冲 {} 中有 {} 个待补发的段（{}）",
+                spool.options.dir.display(),
+                spool.segments.len(),
+                format_bytes(spool.bytes(), ByteUnits::Iec)
+            );
+        }
+        Ok(spool)
+    }
+
+    /// 缓冲中是否没有样本
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// 缓冲占用的磁盘空间（字节）
+    pub fn bytes(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.bytes).sum()
+    }
+
+    /// 追加一条样本，超出上限时丢弃最旧的段
+    pub fn push(&mut self, sample: &MetricsRequest) -> Result<()> {
+        let record = encode_record(sample);
+        let segment_limit = (self.options.max_bytes / SEGMENTS_PER_SPOOL).max(MIN_SEGMENT_BYTES);
+
+        let rotate = match self.segments.back() {
+            Some(current) => {
+                self.writer.is_none()
+                    || current.compressed != self.options.compress
+                    || current.bytes >= segment_limit
+            }
+            None => true,
+        };
+        if rotate {
+            let segment = Segment {
+                id: self.segments.back().map_or(1, |segment| segment.id + 1),
+                compressed: self.options.compress,
+                bytes: 0,
+            };
+            let path = segment.path(&self.options.dir);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("无法创建离线缓冲段 {}", path.display()))?;
+            self.close_writer();
+            self.segments.push_back(segment);
+            self.writer = Some(SegmentWriter::new(file, self.options.compress));
+        }
+
+        let (Some(writer), Some(current)) = (self.writer.as_mut(), self.segments.back_mut()) else {
+            unreachable!("切换段后必有当前段");
+        };
+        current.bytes = writer.append(&record)?;
+
+        while self.bytes() > self.options.max_bytes && self.segments.len() > 1 {
+            if let Some(oldest) = self.segments.pop_front() {
+                warn!(
+                    "离线缓冲超过上限 {}，丢弃最旧的段（{}）",
+                    format_bytes(self.options.max_bytes, ByteUnits::Iec),
+                    format_bytes(oldest.bytes, ByteUnits::Iec)
+                );
+                remove_segment(&self.options.dir, &oldest);
+            }
+        }
+        Ok(())
+    }
+
+    /// 读取最旧一段中的样本，返回 (段序号, 样本)，段文件保留到 [`Self::finish_segment`]；
+    /// 缓冲为空时返回 `None`
+    ///
+    /// 段尾不完整或无法解码的记录被跳过；整段无法读取时删除该段并返回错误
+    pub fn read_oldest(&mut self) -> Result<Option<(u64, Vec<MetricsRequest>)>> {
+        let Some(segment) = self.segments.front().copied() else {
+            return Ok(None);
+        };
+        if self.segments.len() == 1 {
+            // 正在写入的段交给补发，结束写入，下次写入时新建段
+            self.close_writer();
+        }
+
+        let path = segment.path(&self.options.dir);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                self.segments.pop_front();
+                remove_segment(&self.options.dir, &segment);
+                return Err(e).with_context(|| format!("无法读取离线缓冲段 {}", path.display()));
+            }
+        };
+        let (samples, skipped) = decode_segment(&data, segment.compressed);
+        if skipped > 0 {
+            warn!(
+                "离线缓冲段 {} 中有 {} 条记录不完整或已损坏，已跳过",
+                path.display(),
+                skipped
+            );
+        }
+        Ok(Some((segment.id, samples)))
+    }
+
+    /// 结束一段的补发：`unsent` 为空时删除该段，否则用未发出的样本改写该段，下次补发从这里继续
+    ///
+    /// 该段已因超出上限被丢弃时什么都不做
+    pub fn finish_segment(&mut self, id: u64, unsent: &[MetricsRequest]) -> Result<()> {
+        let Some(index) = self.segments.iter().position(|segment| segment.id == id) else {
+            return Ok(());
+        };
+        if unsent.is_empty() {
+            if let Some(segment) = self.segments.remove(index) {
+                remove_segment(&self.options.dir, &segment);
+            }
+            return Ok(());
+        }
+
+        let segment = &mut self.segments[index];
+        let path = segment.path(&self.options.dir);
+        let tmp = path.with_extension("tmp");
+        let file =
+            File::create(&tmp).with_context(|| format!("无法写入离线缓冲段 {}", tmp.display()))?;
+        let mut writer = SegmentWriter::new(file, segment.compressed);
+        for sample in unsent {
+            writer.append(&encode_record(sample))?;
+        }
+        segment.bytes = writer.finish()?;
+        std::fs::rename(&tmp, &path)
+            .with_context(|| format!("无法改写离线缓冲段 {}", path.display()))?;
+        Ok(())
+    }
+
+    /// 结束当前段的写入
+    fn close_writer(&mut self) {
+        let Some(writer) = self.writer.take() else {
+            return;
+        };
+        match writer.finish() {
+            Ok(bytes) => {
+                if let Some(current) = self.segments.back_mut() {
+                    current.bytes = bytes;
+                }
+            }
+            Err(e) => warn!("结束离线缓冲段写入失败: {}", e),
+        }
+    }
+}
+
+/// 编码一条记录：4 字节小端长度 + protobuf 负载
+fn encode_record(sample: &MetricsRequest) -> Vec<u8> {
+    let payload = sample.encode_to_vec();
+    let mut record = Vec::with_capacity(4 + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&payload);
+    record
+}
+
+/// 解码整段，返回完好的样本与跳过的记录数
+///
+/// 压缩段缺少 gzip 结尾或末尾损坏时，使用已解压出的部分
+fn decode_segment(data: &[u8], compressed: bool) -> (Vec<MetricsRequest>, usize) {
+    if !compressed || data.is_empty() {
+        return decode_records(data);
+    }
+    let mut plain = Vec::new();
+    let truncated = GzDecoder::new(data).read_to_end(&mut plain).is_err();
+    let (samples, skipped) = decode_records(&plain);
+    // 解压中断时最后一条记录通常已被截断并计入 skipped，这里不重复计数
+    (samples, skipped.max(usize::from(truncated)))
+}
+
+/// 逐条解码记录，返回完好的样本与跳过的记录数
+///
+/// 长度前缀或负载不完整时视为段尾被截断，停止读取；负载无法解码时跳过该条继续
+fn decode_records(mut data: &[u8]) -> (Vec<MetricsRequest>, usize) {
+    let mut samples = Vec::new();
+    let mut skipped = 0;
+    while !data.is_empty() {
+        let Some((len, rest)) = data.split_first_chunk::<4>() else {
+            skipped += 1;
+            break;
+        };
+        let len = u32::from_le_bytes(*len) as usize;
+        if rest.len() < len {
+            skipped += 1;
+            break;
+        }
+        let (payload, rest) = rest.split_at(len);
+        data = rest;
+
+        match MetricsRequest::decode(payload) {
+            Ok(sample) => samples.push(sample),
+            Err(_) => skipped += 1,
+        }
+    }
+    (samples, skipped)
+}
+
+fn remove_segment(dir: &Path, segment: &Segment) {
+    let path = segment.path(dir);
+    if let Err(e) = std::fs::remove_file(&path) {
+        warn!("删除离线缓冲段 {} 失败: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: i64) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: "agent-1".to_string(),
+            hostname: "host-1".to_string(),
+            timestamp,
+            ..Default::default()
+        }
+    }
+
+    fn options(dir: &Path, compress: bool) -> SpoolOptions {
+        SpoolOptions {
+            dir: dir.to_path_buf(),
+            max_bytes: DEFAULT_SPOOL_MAX_MB << 20,
+            compress,
+        }
+    }
+
+    /// 依次取出并删除全部段中的样本
+    fn drain(spool: &mut Spool) -> Vec<MetricsRequest> {
+        let mut samples = Vec::new();
+        while let Some((id, segment)) = spool.read_oldest().unwrap() {
+            samples.extend(segment);
+            spool.finish_segment(id, &[]).unwrap();
+        }
+        samples
+    }
+
+    #[test]
+    fn test_truncated_tail_is_skipped() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join(format!("{:020}.spool.gz", 1));
+        let mut spool = Spool::open(options(temp_dir.path(), true)).unwrap();
+        spool.push(&sample(1)).unwrap();
+        spool.push(&sample(2)).unwrap();
+        let complete = spool.bytes() as usize;
+        spool.push(&sample(3)).unwrap();
+        // 模拟写入最后一条记录时断电：段没有 gzip 结尾，最后一条只落盘了一半
+        let crashed = std::fs::read(&path).unwrap();
+        drop(spool);
+        let cut = complete + (crashed.len() - complete) / 2;
+        std::fs::write(&path, &crashed[..cut]).unwrap();
+
+        // 重新打开后补发完好的记录
+        let mut spool = Spool::open(options(temp_dir.path(), true)).unwrap();
+        let samples = drain(&mut spool);
+        let timestamps: Vec<_> = samples.iter().map(|sample| sample.timestamp).collect();
+        assert_eq!(timestamps, [1, 2]);
+        assert_eq!(samples[0], sample(1));
+        assert!(spool.read_oldest().unwrap().is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_compressed_segment_is_one_gzip_stream() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join(format!("{:020}.spool.gz", 1));
+        let mut spool = Spool::open(options(temp_dir.path(), true)).unwrap();
+        for timestamp in 1..=3 {
+            spool.push(&sample(timestamp)).unwrap();
+        }
+        drop(spool);
+
+        // 单个 gzip 成员即包含全部记录
+        let mut plain = Vec::new();
+        GzDecoder::new(File::open(&path).unwrap())
+            .read_to_end(&mut plain)
+            .unwrap();
+        let (samples, skipped) = decode_records(&plain);
+        assert_eq!(samples, [sample(1), sample(2), sample(3)]);
+        assert_eq!(skipped, 0);
+    }
+
+    #[test]
+    fn test_unsent_tail_written_back() {
+        for compress in [false, true] {
+            let temp_dir = tempfile::tempdir().unwrap();
+            let mut spool = Spool::open(options(temp_dir.path(), compress)).unwrap();
+            for timestamp in 1..=5 {
+                spool.push(&sample(timestamp)).unwrap();
+            }
+
+            // 补发读取期间写入的样本进入新段
+            let (id, samples) = spool.read_oldest().unwrap().unwrap();
+            assert_eq!(samples.len(), 5);
+            spool.push(&sample(6)).unwrap();
+
+            // 发出前三条后断线，剩下的写回原段，进程重启后从这里继续
+            spool.finish_segment(id, &samples[3..]).unwrap();
+            drop(spool);
+            let mut spool = Spool::open(options(temp_dir.path(), compress)).unwrap();
+            let timestamps: Vec<_> = drain(&mut spool)
+                .iter()
+                .map(|sample| sample.timestamp)
+                .collect();
+            assert_eq!(timestamps, [4, 5, 6], "compress={}", compress);
+            assert!(spool.is_empty());
+            assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_oldest_segments_dropped_over_limit() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut spool = Spool::open(SpoolOptions {
+            max_bytes: 2 * MIN_SEGMENT_BYTES,
+            ..options(temp_dir.path(), false)
+        })
+        .unwrap();
+        let mut big = sample(0);
+        big.labels.insert("padding".to_string(), "x".repeat(4096));
+        for timestamp in 0..100 {
+            big.timestamp = timestamp;
+            spool.push(&big).unwrap();
+        }
+        assert!(spool.bytes() <= 2 * MIN_SEGMENT_BYTES);
+
+        // 剩下的是最新的样本，顺序不变
+        let timestamps: Vec<_> = drain(&mut spool)
+            .iter()
+            .map(|sample| sample.timestamp)
+            .collect();
+        assert_eq!(timestamps.last(), Some(&99));
+        assert!(timestamps.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        assert!(spool.is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+//! systemd 失败单元采集
+//!
+//! 需以 `--systemd`（`collectors.systemd`）开启：每次采集都要启动一次 `systemctl`，
+//! 会增加几十毫秒的耗时。主机未运行 systemd（容器、非 Linux、其他 init）时只记录一次日志，
+//! 之后不再尝试
+
+use common::proto::SystemdMetrics;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// 等待 systemctl 返回的上限，超时后结束进程并记为降级
+const SYSTEMCTL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 失败单元列表来源，测试中可替换 systemctl
+trait UnitSource {
+    /// `systemctl list-units --failed --plain --no-legend` 的输出
+    fn failed_units(&self) -> Result<String, String>;
+}
+
+/// 通过 systemctl 命令读取
+struct Systemctl;
+
+impl UnitSource for Systemctl {
+    fn failed_units(&self) -> Result<String, String> {
+        let mut child = Command::new("systemctl")
+            .args([
+                "list-units",
+                "--failed",
+                "--all",
+                "--plain",
+                "--no-legend",
+                "--no-pager",
+                "--full",
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("无法执行 systemctl: {}", e))?;
+
+        let started = Instant::now();
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if started.elapsed() >= SYSTEMCTL_TIMEOUT => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(format!("systemctl 超过 {:?} 未返回", SYSTEMCTL_TIMEOUT));
+                }
+                Ok(None) => std::thread::sleep(Duration::from_millis(5)),
+                Err(e) => return Err(format!("等待 systemctl 失败: {}", e)),
+            }
+        };
+
+        let mut output = String::new();
+        if let Some(mut stdout) = child.stdout.take() {
+            stdout
+                .read_to_string(&mut output)
+                .map_err(|e| format!("读取 systemctl 输出失败: {}", e))?;
+        }
+        if !status.success() {
+            return Err(format!("systemctl 退出码 {}", status));
+        }
+        Ok(output)
+    }
+}
+
+// 是否运行在 systemd 下，只检测一次
+static SYSTEMD_BOOTED: once_cell::sync::Lazy<bool> = once_cell::sync::Lazy::new(|| {
+    // 与 sd_booted() 相同的判断方式
+    let booted = cfg!(target_os = "linux") && std::path::Path::new("/run/systemd/system").is_dir();
+    if !booted {
+        info!("主机未运行 systemd，跳过 systemd 采集");
+    }
+    booted
+});
+
+/// 主机是否运行 systemd
+pub fn available() -> bool {
+    *SYSTEMD_BOOTED
+}
+
+/// 采集 systemd 失败单元
+pub fn collect_systemd_metrics() -> Result<SystemdMetrics, String> {
+    collect_from(&Systemctl)
+}
+
+fn collect_from(source: &impl UnitSource) -> Result<SystemdMetrics, String> {
+    let output = source.failed_units()?;
+    let mut failed_units = parse_unit_list(&output);
+    failed_units.sort();
+    failed_units.dedup();
+    Ok(SystemdMetrics {
+        failed_count: failed_units.len() as u32,
+        failed_units,
+    })
+}
+
+/// 取每行的第一列作为单元名
+///
+/// 较旧的 systemd 即使指定 `--plain` 也会在失败单元前加 `●`，这里一并去掉
+fn parse_unit_list(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            line.trim_start()
+                .trim_start_matches(['●', '*'])
+                .split_whitespace()
+                .next()
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct StubSource(Result<&'static str, &'static str>);
+
+    impl UnitSource for StubSource {
+        fn failed_units(&self) -> Result<String, String> {
+            self.0.map(str::to_string).map_err(str::to_string)
+        }
+    }
+
+    #[test]
+    fn test_failed_units_reported() {
+        let source = StubSource(Ok(concat!(
+            "nginx.service          loaded failed failed A high performance web server\n",
+            "● backup.timer         loaded failed failed Nightly backup\n",
+            "systemd-resolved.service loaded failed failed Network Name Resolution\n",
+            "\n",
+        )));
+        let metrics = collect_from(&source).unwrap();
+        assert_eq!(metrics.failed_count, 3);
+        assert_eq!(
+            metrics.failed_units,
+            vec!["backup.timer", "nginx.service", "systemd-resolved.service"]
+        );
+
+        // 没有失败单元时输出为空
+        let metrics = collect_from(&StubSource(Ok(""))).unwrap();
+        assert_eq!(metrics.failed_count, 0);
+        assert!(metrics.failed_units.is_empty());
+
+        let err = collect_from(&StubSource(Err("systemctl 退出码 1"))).unwrap_err();
+        assert!(err.contains("systemctl"), "{}", err);
+    }
+}
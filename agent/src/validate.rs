@@ -0,0 +1,207 @@
+//! 上报前的指标校验
+//!
+//! 个别传感器或内核接口偶尔给出不可能的值（已用内存超过总量、使用率超过 100、负载为负），
+//! 这里在发送前把它们修正到合法范围，并记入探针错误计数，Server 与图表不会看到这些异常值
+
+use crate::collector;
+use common::proto::SystemMetrics;
+use tracing::warn;
+
+/// 修正样本中的异常值，有修正时记一次错误
+pub fn validate_metrics(system: &mut SystemMetrics) {
+    let anomalies = clamp_metrics(system);
+    if anomalies.is_empty() {
+        return;
+    }
+    let message = format!("采集到异常指标，已修正: {}", anomalies.join("；"));
+    warn!("{}", message);
+    collector::record_error(message);
+}
+
+/// 把各字段修正到合法范围，返回每处修正的说明
+fn clamp_metrics(system: &mut SystemMetrics) -> Vec<String> {
+    let mut anomalies = Vec::new();
+
+    if let Some(cpu) = system.cpu.as_mut() {
+        clamp_percent(&mut cpu.usage_percent, "cpu.usage_percent", &mut anomalies);
+        for (i, usage) in cpu.per_core.iter_mut().enumerate() {
+            clamp_percent(usage, &format!("cpu.per_core[{}]", i), &mut anomalies);
+        }
+        for (value, name) in [
+            (&mut cpu.load_avg_1, "cpu.load_avg_1"),
+            (&mut cpu.load_avg_5, "cpu.load_avg_5"),
+            (&mut cpu.load_avg_15, "cpu.load_avg_15"),
+        ] {
+            clamp_non_negative(value, name, &mut anomalies);
+        }
+    }
+
+    if let Some(memory) = system.memory.as_mut() {
+        clamp_used(
+            &mut memory.used,
+            memory.total,
+            "memory.used",
+            &mut anomalies,
+        );
+        clamp_used(
+            &mut memory.available,
+            memory.total,
+            "memory.available",
+            &mut anomalies,
+        );
+        clamp_used(
+            &mut memory.swap_used,
+            memory.swap_total,
+            "memory.swap_used",
+            &mut anomalies,
+        );
+        clamp_percent(
+            &mut memory.usage_percent,
+            "memory.usage_percent",
+            &mut anomalies,
+        );
+    }
+
+    for node in &mut system.numa_nodes {
+        clamp_used(
+            &mut node.free,
+            node.total,
+            &format!("numa_nodes[{}].free", node.node),
+            &mut anomalies,
+        );
+    }
+
+    for disk in &mut system.disks {
+        let prefix = format!("disks[{}]", disk.mount_point);
+        clamp_used(
+            &mut disk.used,
+            disk.total,
+            &format!("{}.used", prefix),
+            &mut anomalies,
+        );
+        clamp_used(
+            &mut disk.available,
+            disk.total,
+            &format!("{}.available", prefix),
+            &mut anomalies,
+        );
+        clamp_percent(
+            &mut disk.usage_percent,
+            &format!("{}.usage_percent", prefix),
+            &mut anomalies,
+        );
+    }
+
+    for gpu in &mut system.gpu {
+        let prefix = format!("gpu[{}]", gpu.index);
+        clamp_used(
+            &mut gpu.memory_used,
+            gpu.memory_total,
+            &format!("{}.memory_used", prefix),
+            &mut anomalies,
+        );
+        clamp_percent(
+            &mut gpu.utilization_percent,
+            &format!("{}.utilization_percent", prefix),
+            &mut anomalies,
+        );
+    }
+
+    anomalies
+}
+
+/// 使用率限制在 0–100，NaN 记为 0
+fn clamp_percent(value: &mut f64, name: &str, anomalies: &mut Vec<String>) {
+    let clamped = if value.is_nan() {
+        0.0
+    } else {
+        value.clamp(0.0, 100.0)
+    };
+    // NaN 与任何值都不相等，同样会被记录
+    if clamped != *value {
+        anomalies.push(format!("{}={} 修正为 {}", name, value, clamped));
+        *value = clamped;
+    }
+}
+
+/// 负载等非负值，负数与 NaN 记为 0
+fn clamp_non_negative(value: &mut f64, name: &str, anomalies: &mut Vec<String>) {
+    if value.is_nan() || *value < 0.0 {
+        anomalies.push(format!("{}={} 修正为 0", name, value));
+        *value = 0.0;
+    }
+}
+
+/// 已用量不超过总量
+fn clamp_used(used: &mut u64, total: u64, name: &str, anomalies: &mut Vec<String>) {
+    if *used > total {
+        anomalies.push(format!("{}={} 超过总量 {}", name, used, total));
+        *used = total;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::{CpuMetrics, DiskMetrics, MemoryMetrics};
+
+    #[test]
+    fn test_impossible_memory_is_clamped() {
+        let mut system = SystemMetrics {
+            memory: Some(MemoryMetrics {
+                total: 8 * 1024,
+                used: 12 * 1024,
+                available: 1024,
+                usage_percent: 150.0,
+                swap_total: 0,
+                swap_used: 512,
+            }),
+            ..Default::default()
+        };
+
+        let before = collector::errors_count();
+        validate_metrics(&mut system);
+
+        let memory = system.memory.unwrap();
+        assert_eq!(memory.used, 8 * 1024);
+        assert_eq!(memory.available, 1024);
+        assert_eq!(memory.usage_percent, 100.0);
+        assert_eq!(memory.swap_used, 0);
+        // 其他测试可能同时记错误，只断言至少增加一次
+        assert!(collector::errors_count() > before);
+    }
+
+    #[test]
+    fn test_clamp_cpu_and_disks() {
+        let mut system = SystemMetrics {
+            cpu: Some(CpuMetrics {
+                usage_percent: f64::NAN,
+                core_count: 2,
+                per_core: vec![-3.0, 42.0],
+                load_avg_1: -0.5,
+                load_avg_5: 1.5,
+                ..Default::default()
+            }),
+            disks: vec![DiskMetrics {
+                mount_point: "/".to_string(),
+                total: 100,
+                used: 40,
+                available: 60,
+                usage_percent: 40.0,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let anomalies = clamp_metrics(&mut system);
+        assert_eq!(anomalies.len(), 3);
+        let cpu = system.cpu.unwrap();
+        assert_eq!(cpu.usage_percent, 0.0);
+        assert_eq!(cpu.per_core, vec![0.0, 42.0]);
+        assert_eq!((cpu.load_avg_1, cpu.load_avg_5), (0.0, 1.5));
+        assert_eq!(system.disks[0].used, 40);
+
+        // 合法样本不产生修正
+        assert!(clamp_metrics(&mut SystemMetrics::default()).is_empty());
+    }
+}
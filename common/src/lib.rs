@@ -5,9 +5,17 @@ pub mod proto {
 
 pub use proto::*;
 
+pub mod schema;
+
 // 共享工具函数
 pub mod utils {
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use std::fmt;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    /// 覆盖主机名的环境变量，设置后在任何解析方式下都优先生效
+    pub const HOSTNAME_ENV: &str = "IRIS_HOSTNAME";
 
     /// 获取当前时间戳（毫秒）
     pub fn current_timestamp_ms() -> i64 {
@@ -17,6 +25,93 @@ pub mod utils {
             .as_millis() as i64
     }
 
+    /// 样本时间戳来源
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum TimestampSource {
+        /// 系统时钟，NTP 向后调整时时间戳会回退
+        #[default]
+        Wall,
+        /// 启动时锚定一次系统时钟，之后按单调时钟递增，进程内严格递增
+        Monotonic,
+    }
+
+    impl FromStr for TimestampSource {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "wall" => Ok(Self::Wall),
+                "monotonic" => Ok(Self::Monotonic),
+                other => Err(format!(
+                    "未知的时间戳来源: {}（可选 wall、monotonic）",
+                    other
+                )),
+            }
+        }
+    }
+
+    impl fmt::Display for TimestampSource {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Wall => f.write_str("wall"),
+                Self::Monotonic => f.write_str("monotonic"),
+            }
+        }
+    }
+
+    /// 样本时钟，按 `TimestampSource` 生成毫秒时间戳
+    #[derive(Debug)]
+    pub struct SampleClock {
+        source: TimestampSource,
+        /// 创建时的系统时间（毫秒）
+        anchor_ms: i64,
+        /// 创建时的单调时钟
+        anchor: Instant,
+        /// 上一次返回的时间戳（仅 monotonic 使用）
+        last_ms: AtomicI64,
+    }
+
+    impl SampleClock {
+        pub fn new(source: TimestampSource) -> Self {
+            Self {
+                source,
+                anchor_ms: current_timestamp_ms(),
+                anchor: Instant::now(),
+                last_ms: AtomicI64::new(i64::MIN),
+            }
+        }
+
+        pub fn source(&self) -> TimestampSource {
+            self.source
+        }
+
+        /// 当前样本时间戳（毫秒）
+        pub fn now_ms(&self) -> i64 {
+            match self.source {
+                TimestampSource::Wall => current_timestamp_ms(),
+                TimestampSource::Monotonic => self.monotonic_ms(self.anchor.elapsed()),
+            }
+        }
+
+        /// 锚点加单调时钟经过的时间；同一毫秒内多次调用时顺延 1ms，保证严格递增
+        fn monotonic_ms(&self, elapsed: Duration) -> i64 {
+            let candidate = self.anchor_ms + elapsed.as_millis() as i64;
+            let previous = self
+                .last_ms
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| {
+                    Some(candidate.max(last.saturating_add(1)))
+                })
+                .unwrap_or_else(|last| last);
+            candidate.max(previous.saturating_add(1))
+        }
+    }
+
+    impl Default for SampleClock {
+        fn default() -> Self {
+            Self::new(TimestampSource::default())
+        }
+    }
+
     /// 生成 Agent ID（基于主机名）
     pub fn generate_agent_id() -> String {
         let hostname = hostname::get()
@@ -26,4 +121,453 @@ pub mod utils {
 
         format!("agent-{}", hostname)
     }
+
+    /// 主机名解析方式
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum HostnameMode {
+        /// 系统返回的主机名原样使用
+        #[default]
+        System,
+        /// 短主机名（去掉第一个 `.` 之后的域名部分）
+        Short,
+        /// 完全限定域名：系统主机名不含域名时通过解析器取规范名，
+        /// 再退回内核 domainname，均失败时使用系统主机名
+        Fqdn,
+    }
+
+    impl FromStr for HostnameMode {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "system" => Ok(Self::System),
+                "short" => Ok(Self::Short),
+                "fqdn" => Ok(Self::Fqdn),
+                other => Err(format!(
+                    "未知的主机名解析方式: {}（可选 system、short、fqdn）",
+                    other
+                )),
+            }
+        }
+    }
+
+    impl fmt::Display for HostnameMode {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::System => f.write_str("system"),
+                Self::Short => f.write_str("short"),
+                Self::Fqdn => f.write_str("fqdn"),
+            }
+        }
+    }
+
+    /// 解析上报使用的主机名
+    ///
+    /// 环境变量 `IRIS_HOSTNAME` 非空时直接使用，否则按 `mode` 从系统主机名推导
+    pub fn resolve_hostname(mode: HostnameMode) -> String {
+        resolve_hostname_with(
+            mode,
+            std::env::var(HOSTNAME_ENV).ok(),
+            hostname::get().ok().and_then(|h| h.into_string().ok()),
+            lookup_fqdn,
+        )
+    }
+
+    fn resolve_hostname_with(
+        mode: HostnameMode,
+        env_override: Option<String>,
+        system: Option<String>,
+        lookup_fqdn: impl FnOnce(&str) -> Option<String>,
+    ) -> String {
+        if let Some(name) = env_override.filter(|name| !name.is_empty()) {
+            return name;
+        }
+        let Some(system) = system.filter(|name| !name.is_empty()) else {
+            return "unknown".to_string();
+        };
+
+        match mode {
+            HostnameMode::System => system,
+            HostnameMode::Short => system
+                .split('.')
+                .next()
+                .unwrap_or(system.as_str())
+                .to_string(),
+            HostnameMode::Fqdn if system.contains('.') => system,
+            HostnameMode::Fqdn => lookup_fqdn(&system).unwrap_or(system),
+        }
+    }
+
+    /// 为不含域名的主机名查找完全限定域名
+    fn lookup_fqdn(host: &str) -> Option<String> {
+        canonical_name(host)
+            .or_else(|| kernel_domain().map(|domain| format!("{}.{}", host, domain)))
+    }
+
+    /// 通过系统解析器（/etc/hosts、DNS）获取主机的规范名
+    #[cfg(unix)]
+    fn canonical_name(host: &str) -> Option<String> {
+        use std::ffi::{CStr, CString};
+
+        let node = CString::new(host).ok()?;
+        // SAFETY: hints 全零后只设置 flags/family；res 仅在 getaddrinfo 成功时读取并释放一次
+        unsafe {
+            let mut hints: libc::addrinfo = std::mem::zeroed();
+            hints.ai_flags = libc::AI_CANONNAME;
+            hints.ai_family = libc::AF_UNSPEC;
+            let mut res: *mut libc::addrinfo = std::ptr::null_mut();
+            if libc::getaddrinfo(node.as_ptr(), std::ptr::null(), &hints, &mut res) != 0
+                || res.is_null()
+            {
+                return None;
+            }
+            let name = (*res).ai_canonname;
+            let canonical =
+                (!name.is_null()).then(|| CStr::from_ptr(name).to_string_lossy().into_owned());
+            libc::freeaddrinfo(res);
+            canonical.filter(|name| name.contains('.'))
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn canonical_name(_host: &str) -> Option<String> {
+        None
+    }
+
+    /// 读取内核 domainname（未设置时为 `(none)`）
+    #[cfg(target_os = "linux")]
+    fn kernel_domain() -> Option<String> {
+        let domain = std::fs::read_to_string("/proc/sys/kernel/domainname").ok()?;
+        let domain = domain.trim();
+        (!domain.is_empty() && domain != "(none)").then(|| domain.to_string())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn kernel_domain() -> Option<String> {
+        None
+    }
+
+    /// 字节数的单位制
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum ByteUnits {
+        /// 二进制前缀，按 1024 进位（KiB、MiB、GiB……）
+        #[default]
+        Iec,
+        /// 十进制前缀，按 1000 进位（kB、MB、GB……）
+        Si,
+    }
+
+    impl ByteUnits {
+        fn base(self) -> f64 {
+            match self {
+                Self::Iec => 1024.0,
+                Self::Si => 1000.0,
+            }
+        }
+
+        fn suffixes(self) -> &'static [&'static str] {
+            match self {
+                Self::Iec => &["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"],
+                Self::Si => &["B", "kB", "MB", "GB", "TB", "PB", "EB"],
+            }
+        }
+    }
+
+    impl FromStr for ByteUnits {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "iec" => Ok(Self::Iec),
+                "si" => Ok(Self::Si),
+                other => Err(format!("未知的字节单位制: {}（可选 iec、si）", other)),
+            }
+        }
+    }
+
+    impl fmt::Display for ByteUnits {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Iec => f.write_str("iec"),
+                Self::Si => f.write_str("si"),
+            }
+        }
+    }
+
+    /// 字节数格式化为可读文本，如 `1023 B`、`1.5 GiB`（SI 为 `1.5 GB`），保留一位小数
+    pub fn format_bytes(bytes: u64, units: ByteUnits) -> String {
+        let base = units.base();
+        let suffixes = units.suffixes();
+        if (bytes as f64) < base {
+            return format!("{} {}", bytes, suffixes[0]);
+        }
+
+        let mut value = bytes as f64;
+        let mut index = 0;
+        // 四舍五入后达到进位值时（如 1023.99 KiB）进到下一级单位
+        while index + 1 < suffixes.len() && (value * 10.0).round() / 10.0 >= base {
+            value /= base;
+            index += 1;
+        }
+        format!("{:.1} {}", value, suffixes[index])
+    }
+
+    /// 百分比格式化为 `42.5%`，保留一位小数；NaN 与无穷显示为 `N/A`
+    pub fn format_percent(value: f64) -> String {
+        if value.is_finite() {
+            format!("{:.1}%", value)
+        } else {
+            "N/A".to_string()
+        }
+    }
+
+    /// 毫秒时长格式化为可读文本：1 秒内为 `850ms`，1 分钟内为 `12.5s`，
+    /// 更长时取最大的两级单位，如 `5m 3s`、`2h 5m`、`3d 4h`
+    pub fn format_duration_ms(ms: i64) -> String {
+        if ms < 0 {
+            return format!(
+                "-{}",
+                format_duration_ms(ms.checked_neg().unwrap_or(i64::MAX))
+            );
+        }
+        if ms < 1_000 {
+            return format!("{}ms", ms);
+        }
+        if ms < 60_000 {
+            return format!("{:.1}s", ms as f64 / 1000.0);
+        }
+
+        let secs = ms / 1000;
+        let (days, hours, minutes, seconds) =
+            (secs / 86_400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+        if days > 0 {
+            format!("{}d {}h", days, hours)
+        } else if hours > 0 {
+            format!("{}h {}m", hours, minutes)
+        } else {
+            format!("{}m {}s", minutes, seconds)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn resolve(mode: HostnameMode, env: Option<&str>, system: &str) -> String {
+            resolve_hostname_with(
+                mode,
+                env.map(str::to_string),
+                Some(system.to_string()),
+                |host| Some(format!("{}.example.com", host)),
+            )
+        }
+
+        #[test]
+        fn test_hostname_modes() {
+            assert_eq!(resolve(HostnameMode::System, None, "web01"), "web01");
+            assert_eq!(
+                resolve(HostnameMode::System, None, "web01.example.com"),
+                "web01.example.com"
+            );
+
+            assert_eq!(
+                resolve(HostnameMode::Short, None, "web01.example.com"),
+                "web01"
+            );
+            assert_eq!(resolve(HostnameMode::Short, None, "web01"), "web01");
+
+            assert_eq!(
+                resolve(HostnameMode::Fqdn, None, "web01"),
+                "web01.example.com"
+            );
+            // 已是 FQDN 时不再查找
+            assert_eq!(
+                resolve(HostnameMode::Fqdn, None, "db.internal.net"),
+                "db.internal.net"
+            );
+            // 查找失败时退回系统主机名
+            assert_eq!(
+                resolve_hostname_with(HostnameMode::Fqdn, None, Some("web01".to_string()), |_| {
+                    None
+                }),
+                "web01"
+            );
+
+            assert_eq!(
+                resolve_hostname_with(HostnameMode::System, None, None, |_| None),
+                "unknown"
+            );
+        }
+
+        #[test]
+        fn test_env_override_takes_precedence() {
+            for mode in [
+                HostnameMode::System,
+                HostnameMode::Short,
+                HostnameMode::Fqdn,
+            ] {
+                assert_eq!(
+                    resolve(mode, Some("custom.host"), "web01.example.com"),
+                    "custom.host"
+                );
+                // 空值视为未设置
+                assert_ne!(resolve(mode, Some(""), "web01.example.com"), "");
+            }
+        }
+
+        #[test]
+        fn test_monotonic_clock_survives_backward_step() {
+            let clock = SampleClock {
+                source: TimestampSource::Monotonic,
+                anchor_ms: 1_000_000,
+                anchor: Instant::now(),
+                last_ms: AtomicI64::new(i64::MIN),
+            };
+
+            // 锚定之后系统时钟被回拨（如回到 999_800）不影响结果：时间戳只取决于锚点与单调时钟
+            let first = clock.monotonic_ms(Duration::from_millis(500));
+            assert_eq!(first, 1_000_500);
+            let second = clock.monotonic_ms(Duration::from_millis(1500));
+            assert_eq!(second, 1_001_500);
+
+            // 同一毫秒内连续取值仍严格递增
+            let third = clock.monotonic_ms(Duration::from_millis(1500));
+            let fourth = clock.monotonic_ms(Duration::from_millis(1500));
+            assert!(second < third && third < fourth);
+
+            let live = SampleClock::new(TimestampSource::Monotonic);
+            let samples: Vec<i64> = (0..100).map(|_| live.now_ms()).collect();
+            assert!(samples.windows(2).all(|pair| pair[0] < pair[1]));
+            assert_eq!(live.source(), TimestampSource::Monotonic);
+        }
+
+        #[test]
+        fn test_timestamp_source_from_str() {
+            for source in [TimestampSource::Wall, TimestampSource::Monotonic] {
+                assert_eq!(source.to_string().parse(), Ok(source));
+            }
+            assert!("tai".parse::<TimestampSource>().is_err());
+        }
+
+        #[test]
+        fn test_hostname_mode_from_str() {
+            for mode in [
+                HostnameMode::System,
+                HostnameMode::Short,
+                HostnameMode::Fqdn,
+            ] {
+                assert_eq!(mode.to_string().parse(), Ok(mode));
+            }
+            assert!("long".parse::<HostnameMode>().is_err());
+        }
+
+        #[test]
+        fn test_format_bytes_boundaries() {
+            assert_eq!(format_bytes(0, ByteUnits::Iec), "0 B");
+            assert_eq!(format_bytes(1023, ByteUnits::Iec), "1023 B");
+            assert_eq!(format_bytes(1024, ByteUnits::Iec), "1.0 KiB");
+            assert_eq!(format_bytes(1536, ByteUnits::Iec), "1.5 KiB");
+            // 四舍五入到 1024.0 KiB 时进位
+            assert_eq!(format_bytes((1 << 20) - 1, ByteUnits::Iec), "1.0 MiB");
+            assert_eq!(format_bytes(5 << 40, ByteUnits::Iec), "5.0 TiB");
+            assert_eq!(format_bytes(u64::MAX, ByteUnits::Iec), "16.0 EiB");
+
+            assert_eq!(format_bytes(999, ByteUnits::Si), "999 B");
+            assert_eq!(format_bytes(1000, ByteUnits::Si), "1.0 kB");
+            assert_eq!(format_bytes(1024, ByteUnits::Si), "1.0 kB");
+            assert_eq!(format_bytes(2_500_000_000_000, ByteUnits::Si), "2.5 TB");
+
+            assert_eq!("si".parse::<ByteUnits>(), Ok(ByteUnits::Si));
+            assert!("metric".parse::<ByteUnits>().is_err());
+        }
+
+        #[test]
+        fn test_format_percent_and_duration() {
+            assert_eq!(format_percent(0.0), "0.0%");
+            assert_eq!(format_percent(99.95), "100.0%");
+            assert_eq!(format_percent(f64::NAN), "N/A");
+
+            assert_eq!(format_duration_ms(0), "0ms");
+            assert_eq!(format_duration_ms(999), "999ms");
+            assert_eq!(format_duration_ms(1_000), "1.0s");
+            assert_eq!(format_duration_ms(12_500), "12.5s");
+            assert_eq!(format_duration_ms(303_000), "5m 3s");
+            assert_eq!(format_duration_ms(7_500_000), "2h 5m");
+            assert_eq!(format_duration_ms(273_600_000), "3d 4h");
+            assert_eq!(format_duration_ms(-1_500), "-1.5s");
+            assert_eq!(format_duration_ms(i64::MIN), "-106751991167d 7h");
+        }
+    }
+}
+
+// 连接传输层
+pub mod transport {
+    use hyper_util::rt::TokioIo;
+    use std::future::Future;
+    use std::path::Path;
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tonic::transport::{Channel, Endpoint, Uri};
+
+    /// Unix socket 地址前缀，例如 `unix:/run/iris/iris.sock`
+    pub const UNIX_PREFIX: &str = "unix:";
+
+    /// 流式上报时携带 agent_id 的 gRPC metadata 键，Server 开启准入名单时在建立流之前据此校验
+    pub const AGENT_ID_METADATA: &str = "x-agent-id";
+
+    /// 若地址为 `unix:<path>` 形式，返回 socket 路径
+    pub fn unix_socket_path(addr: &str) -> Option<&Path> {
+        addr.strip_prefix(UNIX_PREFIX)
+            .map(|path| Path::new(path.strip_prefix("//").unwrap_or(path)))
+    }
+
+    /// 建立到 Server 的 gRPC 通道
+    ///
+    /// 支持 `http://host:port` 与 `unix:<path>` 两种地址
+    pub async fn connect(addr: &str) -> anyhow::Result<Channel> {
+        match unix_socket_path(addr) {
+            Some(path) => connect_unix(path).await,
+            None => Ok(Endpoint::from_shared(addr.to_string())?.connect().await?),
+        }
+    }
+
+    /// 通过自定义的连接函数建立到 `addr`（http://host:port）的 gRPC 通道
+    ///
+    /// `dial` 按目标 URI 返回已建立的字节流，例如经代理建立的隧道
+    pub async fn connect_via<F, Fut, S>(addr: &str, dial: F) -> anyhow::Result<Channel>
+    where
+        F: Fn(Uri) -> Fut + Send + 'static,
+        Fut: Future<Output = std::io::Result<S>> + Send + 'static,
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let channel = Endpoint::from_shared(addr.to_string())?
+            .connect_with_connector(tower::service_fn(move |uri: Uri| {
+                let stream = dial(uri);
+                async move { Ok::<_, std::io::Error>(TokioIo::new(stream.await?)) }
+            }))
+            .await?;
+        Ok(channel)
+    }
+
+    #[cfg(unix)]
+    async fn connect_unix(path: &Path) -> anyhow::Result<Channel> {
+        use tokio::net::UnixStream;
+
+        let path = path.to_path_buf();
+        // URI 仅用于满足 Endpoint 要求，实际连接由 connector 建立
+        let channel =
+            Endpoint::from_static("http://localhost")
+                .connect_with_connector(tower::service_fn(move |_: Uri| {
+                    let path = path.clone();
+                    async move {
+                        Ok::<_, std::io::Error>(TokioIo::new(UnixStream::connect(path).await?))
+                    }
+                }))
+                .await?;
+        Ok(channel)
+    }
+
+    #[cfg(not(unix))]
+    async fn connect_unix(path: &Path) -> anyhow::Result<Channel> {
+        anyhow::bail!("当前平台不支持 Unix socket: {}", path.display())
+    }
 }
@@ -0,0 +1,244 @@
+//! 指标字段的单位与量纲描述
+//!
+//! 直接解析编译期嵌入的 `probe.proto`：字段列表来自消息定义，单位来自字段注释
+//! （如 `（字节）`、`使用率`、`（毫秒）`），因此修改 proto 后描述自动同步
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// 嵌入的 proto 定义
+const PROTO_SOURCE: &str = include_str!("../../proto/probe.proto");
+
+/// 描述的根消息：HTTP API 返回的样本结构
+const ROOT_MESSAGE: &str = "MetricsRequest";
+
+/// 单个叶子字段的描述
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldSchema {
+    /// 字段在 JSON 样本中的路径，数组元素以 `[]` 表示，如 `system.disks[].total`
+    pub path: String,
+    /// 所属 proto 消息
+    pub message: &'static str,
+    /// proto 类型（string / uint64 / double / 枚举名等）
+    pub proto_type: &'static str,
+    /// 字段本身是否为数组（所在消息为数组元素时由路径中的 `[]` 体现）
+    pub repeated: bool,
+    /// 单位：bytes / percent / milliseconds / microseconds / seconds / megahertz / celsius / watts / count / none
+    pub unit: &'static str,
+    /// 取值满量程：percent 为 100（取值 0–100），其余单位为 1（数值即为该单位）
+    pub scale: f64,
+    /// proto 中的字段注释
+    pub description: &'static str,
+}
+
+/// proto 中的一个字段定义
+#[derive(Debug)]
+struct ProtoField {
+    name: &'static str,
+    proto_type: &'static str,
+    repeated: bool,
+    comment: &'static str,
+}
+
+/// `MetricsRequest` 下所有叶子字段的描述（按 proto 定义顺序）
+pub fn metric_fields() -> &'static [FieldSchema] {
+    static FIELDS: OnceLock<Vec<FieldSchema>> = OnceLock::new();
+    FIELDS.get_or_init(|| {
+        let messages = parse_messages(PROTO_SOURCE);
+        let mut fields = Vec::new();
+        collect_fields(&messages, ROOT_MESSAGE, "", &mut fields);
+        fields
+    })
+}
+
+/// 解析 proto 中的 message 定义（不支持嵌套消息，本项目的 proto 没有）
+fn parse_messages(source: &'static str) -> HashMap<&'static str, Vec<ProtoField>> {
+    let mut messages = HashMap::new();
+    let mut current: Option<(&'static str, Vec<ProtoField>)> = None;
+
+    for line in source.lines() {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("message ") {
+            let name = rest.trim_end_matches('{').trim();
+            current = Some((name, Vec::new()));
+            continue;
+        }
+        if line.starts_with('}') {
+            if let Some((name, fields)) = current.take() {
+                messages.insert(name, fields);
+            }
+            continue;
+        }
+        let Some((_, fields)) = current.as_mut() else {
+            continue;
+        };
+        if let Some(field) = parse_field(line) {
+            fields.push(field);
+        }
+    }
+
+    messages
+}
+
+/// 解析形如 `repeated uint64 total = 3; // 注释` 或 `map<string, string> labels = 6;` 的字段行
+fn parse_field(line: &'static str) -> Option<ProtoField> {
+    let (decl, comment) = match line.split_once("//") {
+        Some((decl, comment)) => (decl, comment.trim()),
+        None => (line, ""),
+    };
+    let decl = decl.trim().strip_suffix(';')?;
+    let (lhs, _number) = decl.split_once('=')?;
+
+    // map<K, V> 的类型里带空格，整体作为类型
+    let lhs = lhs.trim();
+    if lhs.starts_with("map<") {
+        let (proto_type, name) = lhs.split_at(lhs.find('>')? + 1);
+        return Some(ProtoField {
+            name: name.trim(),
+            proto_type,
+            repeated: false,
+            comment,
+        });
+    }
+
+    let mut tokens = lhs.split_whitespace();
+    let mut proto_type = tokens.next()?;
+    let repeated = proto_type == "repeated";
+    if repeated {
+        proto_type = tokens.next()?;
+    }
+    let name = tokens.next()?;
+
+    Some(ProtoField {
+        name,
+        proto_type,
+        repeated,
+        comment,
+    })
+}
+
+/// 从根消息出发展开消息类型字段，收集叶子字段
+fn collect_fields(
+    messages: &HashMap<&'static str, Vec<ProtoField>>,
+    message: &'static str,
+    prefix: &str,
+    out: &mut Vec<FieldSchema>,
+) {
+    let Some((message, fields)) = messages.get_key_value(message) else {
+        return;
+    };
+
+    for field in fields {
+        let path = format!(
+            "{}{}{}",
+            prefix,
+            field.name,
+            if field.repeated { "[]" } else { "" }
+        );
+        if messages.contains_key(field.proto_type) {
+            collect_fields(messages, field.proto_type, &format!("{}.", path), out);
+            continue;
+        }
+
+        let unit = infer_unit(field);
+        out.push(FieldSchema {
+            path,
+            message,
+            proto_type: field.proto_type,
+            repeated: field.repeated,
+            unit,
+            scale: if unit == "percent" { 100.0 } else { 1.0 },
+            description: field.comment,
+        });
+    }
+}
+
+/// 按字段注释推断单位，规则按优先级排列
+fn infer_unit(field: &ProtoField) -> &'static str {
+    let comment = field.comment;
+    let numeric = matches!(
+        field.proto_type,
+        "double" | "float" | "int32" | "int64" | "uint32" | "uint64"
+    );
+    if !numeric {
+        return "none";
+    }
+
+    if comment.contains('%') || comment.contains("使用率") {
+        "percent"
+    } else if comment.contains("字节") {
+        "bytes"
+    } else if comment.contains("毫秒") {
+        "milliseconds"
+    } else if comment.contains("微秒") {
+        "microseconds"
+    } else if comment.contains("（秒）") {
+        "seconds"
+    } else if comment.contains("MHz") {
+        "megahertz"
+    } else if comment.contains('℃') {
+        "celsius"
+    } else if comment.contains("（瓦）") {
+        "watts"
+    } else if comment.ends_with('数') {
+        "count"
+    } else {
+        "none"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(path: &str) -> &'static FieldSchema {
+        metric_fields()
+            .iter()
+            .find(|f| f.path == path)
+            .unwrap_or_else(|| panic!("missing field {}", path))
+    }
+
+    #[test]
+    fn test_known_field_units() {
+        assert_eq!(field("timestamp").unit, "milliseconds");
+        assert_eq!(field("system.memory.total").unit, "bytes");
+        assert_eq!(field("system.memory.swap_used").unit, "bytes");
+        assert_eq!(field("system.disks[].read_bytes").unit, "bytes");
+        assert_eq!(field("system.network.packets_recv").unit, "count");
+        assert_eq!(field("system.system_info.uptime").unit, "seconds");
+        assert_eq!(field("system.pressure.io.full.total").unit, "microseconds");
+        assert_eq!(
+            field("system.agent_metrics.collection_time_ms").unit,
+            "milliseconds"
+        );
+
+        let usage = field("system.cpu.usage_percent");
+        assert_eq!(usage.unit, "percent");
+        assert_eq!(usage.scale, 100.0);
+        assert_eq!(usage.message, "CpuMetrics");
+        assert_eq!(field("system.cpu.per_core[]").unit, "percent");
+        assert!(field("system.cpu.per_core[]").repeated);
+        assert_eq!(field("system.pressure.cpu.some.avg10").unit, "percent");
+
+        assert_eq!(field("system.cpu.load_avg_1").unit, "none");
+        assert_eq!(field("hostname").unit, "none");
+        assert_eq!(field("labels").proto_type, "map<string, string>");
+    }
+
+    #[test]
+    fn test_every_message_field_is_expanded() {
+        // 叶子字段的类型都不应是消息
+        let messages = parse_messages(PROTO_SOURCE);
+        for f in metric_fields() {
+            assert!(
+                !messages.contains_key(f.proto_type),
+                "{} not expanded",
+                f.path
+            );
+        }
+        assert!(metric_fields()
+            .iter()
+            .any(|f| f.path.starts_with("system.gpu[].")));
+    }
+}
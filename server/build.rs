@@ -0,0 +1,52 @@
+//! 生成构建信息（`/api/version`）：git 提交、构建时间与 rustc 版本
+//!
+//! 不在 git 仓库中或找不到 git / rustc 时对应字段为 `unknown`。设置了 `SOURCE_DATE_EPOCH`
+//! 时以它作为构建时间，便于复现构建
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let git_hash = command_output("git", &["rev-parse", "--short=12", "HEAD"]);
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+
+    let build_timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", build_timestamp);
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    println!(
+        "cargo:rustc-env=RUSTC_VERSION={}",
+        command_output(&rustc, &["--version"])
+    );
+
+    // 提交变化时重新生成；不是 git 仓库时不声明，避免每次构建都重新运行
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    for path in ["../.git/HEAD", "../.git/refs", "../.git/packed-refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+/// 运行命令并返回去掉首尾空白的标准输出，失败时返回 `unknown`
+fn command_output(program: &str, args: &[&str]) -> String {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|stdout| stdout.trim().to_string())
+        .filter(|stdout| !stdout.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
@@ -0,0 +1,59 @@
+//! agent_id 校验
+//!
+//! agent_id 直接用作 redb 键的前缀（与时间戳以 `\0` 分隔）和 API 路径段。样本入库前去掉首尾空白，
+//! 并拒绝空 ID、含控制字符（包括 `\0`）或超长的 ID，避免键解析错乱或凭空多出一个 Agent
+
+/// agent_id 的最大长度（字节）
+pub const MAX_AGENT_ID_LEN: usize = 256;
+
+/// 去掉 agent_id 首尾空白并校验，不合法时返回原因
+pub fn normalize_agent_id(agent_id: &mut String) -> Result<(), String> {
+    let trimmed = agent_id.trim();
+    if trimmed.len() != agent_id.len() {
+        *agent_id = trimmed.to_string();
+    }
+
+    if agent_id.is_empty() {
+        return Err("agent_id 不能为空".to_string());
+    }
+    if agent_id.len() > MAX_AGENT_ID_LEN {
+        return Err(format!(
+            "agent_id 长度 {} 字节，超过上限 {}",
+            agent_id.len(),
+            MAX_AGENT_ID_LEN
+        ));
+    }
+    if agent_id.chars().any(char::is_control) {
+        return Err(format!("agent_id {:?} 含控制字符", agent_id));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_agent_id() {
+        let mut id = "  agent-web-01\n".to_string();
+        normalize_agent_id(&mut id).unwrap();
+        assert_eq!(id, "agent-web-01");
+
+        let mut id = "agent-主机".to_string();
+        normalize_agent_id(&mut id).unwrap();
+        assert_eq!(id, "agent-主机");
+
+        for invalid in [
+            "agent\0evil".to_string(),
+            "agent\u{7}".to_string(),
+            "".to_string(),
+            " \t ".to_string(),
+            "a".repeat(MAX_AGENT_ID_LEN + 1),
+        ] {
+            let mut id = invalid.clone();
+            assert!(normalize_agent_id(&mut id).is_err(), "{:?}", invalid);
+        }
+        let mut id = "a".repeat(MAX_AGENT_ID_LEN);
+        normalize_agent_id(&mut id).unwrap();
+    }
+}
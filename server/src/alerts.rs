@@ -0,0 +1,586 @@
+//! 磁盘剩余空间告警
+//!
+//! 规则按挂载点逐个评估：剩余空间可以用绝对字节数（如 `10GiB`）或占总容量的百分比（如 `5%`）
+//! 表示。大容量卷上百分比阈值容易误报（20TB 的 5% 仍有 1TB 可用），绝对值规则不论卷多大
+//! 都只在剩余空间真正不足时触发。规则可限定挂载点（`/data=50GiB`），否则作用于所有挂载点。
+//!
+//! 阈值也可以是写满预测（如 `full:4h`）：对该挂载点最近的已用量做线性拟合，按当前增长速率
+//! 预计在给定时长内写满时触发。用量持平、下降或波动过大（拟合优度不足）时不触发，
+//! 与 `GET /api/agents/:id/disks/forecast` 使用同一套拟合逻辑。
+//!
+//! 每个 (Agent, 规则, 挂载点) 在越过阈值时产生一条 `AlertFired` 事件，回到阈值以上或该挂载点
+//! 不再上报时产生 `AlertResolved`，均写入 Agent 事件时间线。处于维护模式的 Agent 不触发新告警，
+//! 维护到期后仍低于阈值的挂载点照常触发。告警状态只保存在内存中，Server 优雅关闭时为仍在
+//! 触发的告警补记 `AlertResolved`，使时间线中的告警成对出现，重启后从下一条样本重新评估
+
+use crate::analytics::{estimate_seconds_to_full, fit_linear_trend, MIN_FORECAST_SAMPLES};
+use crate::maintenance::MaintenanceWindows;
+use crate::storage::{AgentEvent, AgentEventKind};
+use common::proto::{DiskMetrics, MetricsRequest};
+use common::utils::{current_timestamp_ms, format_bytes, ByteUnits};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use tracing::{debug, info, warn};
+
+/// 剩余空间阈值
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiskFreeThreshold {
+    /// 可用字节数
+    Bytes(u64),
+    /// 可用空间占总容量的百分比
+    Percent(f64),
+    /// 按当前增长趋势预计在该秒数内写满
+    FullWithin(u64),
+}
+
+impl DiskFreeThreshold {
+    /// 该挂载点的剩余空间是否低于阈值；总容量为 0 的挂载点（如伪文件系统）不参与百分比规则。
+    /// `seconds_to_full` 为该挂载点的写满预测，没有可信预测时为 None
+    fn is_breached(self, disk: &DiskMetrics, seconds_to_full: Option<f64>) -> bool {
+        match self {
+            Self::Bytes(bytes) => disk.available < bytes,
+            Self::Percent(percent) => {
+                disk.total > 0 && (disk.available as f64 / disk.total as f64) * 100.0 < percent
+            }
+            Self::FullWithin(secs) => seconds_to_full.is_some_and(|eta| eta < secs as f64),
+        }
+    }
+}
+
+/// 解析 `90s`、`30m`、`4h`、`2d` 形式的时长（秒）
+fn parse_duration_secs(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .filter(|&n| n > 0)
+        .and_then(|n| n.checked_mul(multiplier))
+}
+
+/// 以能整除的最大单位显示时长
+fn format_duration_secs(secs: u64) -> String {
+    [(86400, "d"), (3600, "h"), (60, "m")]
+        .into_iter()
+        .find(|(unit, _)| secs.is_multiple_of(*unit))
+        .map(|(unit, suffix)| format!("{}{}", secs / unit, suffix))
+        .unwrap_or_else(|| format!("{}s", secs))
+}
+
+impl FromStr for DiskFreeThreshold {
+    type Err = String;
+
+    /// 解析 `5%`、`10GiB`、`500MB`、字节数或写满预测 `full:4h`。KB/MB/GB/TB 按 1000 进位，
+    /// K/M/G/T 与 KiB/MiB/GiB/TiB 按 1024 进位，单位不区分大小写
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(within) = s.strip_prefix("full:") {
+            return parse_duration_secs(within)
+                .map(Self::FullWithin)
+                .ok_or_else(|| format!("无效的写满预测阈值 {}，应为 full:<时长>，如 full:4h", s));
+        }
+        if let Some(percent) = s.strip_suffix('%') {
+            return percent
+                .trim()
+                .parse()
+                .ok()
+                .filter(|percent: &f64| (0.0..=100.0).contains(percent))
+                .map(Self::Percent)
+                .ok_or_else(|| format!("无效的百分比阈值 {}，应在 0 到 100 之间", s));
+        }
+
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "kb" => 1_000,
+            "mb" => 1_000_000,
+            "gb" => 1_000_000_000,
+            "tb" => 1_000_000_000_000,
+            "k" | "kib" => 1 << 10,
+            "m" | "mib" => 1 << 20,
+            "g" | "gib" => 1 << 30,
+            "t" | "tib" => 1 << 40,
+            _ => {
+                return Err(format!(
+                    "无效的容量单位: {}（可选 B、KB、KiB、MB、MiB、GB、GiB、TB、TiB）",
+                    unit
+                ))
+            }
+        };
+        let number: f64 = number
+            .parse()
+            .ok()
+            .filter(|number: &f64| number.is_finite() && *number >= 0.0)
+            .ok_or_else(|| format!("无效的容量阈值: {}", s))?;
+        Ok(Self::Bytes((number * multiplier as f64).round() as u64))
+    }
+}
+
+impl fmt::Display for DiskFreeThreshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bytes(bytes) => write!(f, "{}", format_bytes(*bytes, ByteUnits::Iec)),
+            Self::Percent(percent) => write!(f, "{}%", percent),
+            Self::FullWithin(secs) => write!(f, "full:{}", format_duration_secs(*secs)),
+        }
+    }
+}
+
+/// 磁盘剩余空间告警规则
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskFreeRule {
+    /// 只评估该挂载点，None 时评估所有挂载点
+    pub mount_point: Option<String>,
+    pub threshold: DiskFreeThreshold,
+}
+
+impl FromStr for DiskFreeRule {
+    type Err = String;
+
+    /// 解析 `[<挂载点>=]<阈值>`，如 `10GiB`、`5%`、`/data=50GiB`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (mount_point, threshold) = match s.rsplit_once('=') {
+            Some((mount_point, threshold)) => {
+                let mount_point = mount_point.trim();
+                if mount_point.is_empty() {
+                    return Err(format!("无效的告警规则 {}，挂载点不能为空", s));
+                }
+                (Some(mount_point.to_string()), threshold)
+            }
+            None => (None, s),
+        };
+        Ok(Self {
+            mount_point,
+            threshold: threshold.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for DiskFreeRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.mount_point {
+            Some(mount_point) => write!(f, "{}={}", mount_point, self.threshold),
+            None => write!(f, "{}", self.threshold),
+        }
+    }
+}
+
+/// 挂载点 → 最近的 (时间戳毫秒, 已用字节)
+type MountUsage = HashMap<String, VecDeque<(i64, f64)>>;
+
+/// 写满预测规则参与拟合的最近样本数（与 `disks/forecast` 接口的默认值一致）
+const FORECAST_WINDOW: usize = 360;
+
+/// 按规则评估样本，跟踪各 Agent 正在触发的告警
+#[derive(Debug, Default)]
+pub struct AlertEngine {
+    rules: Vec<DiskFreeRule>,
+    /// agent_id → 正在触发的 (规则序号, 挂载点)
+    firing: Mutex<HashMap<String, HashSet<(usize, String)>>>,
+    /// agent_id → 各挂载点的已用量，仅在配置了写满预测规则时记录
+    usage: Mutex<HashMap<String, MountUsage>>,
+    maintenance: Arc<MaintenanceWindows>,
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<DiskFreeRule>, maintenance: Arc<MaintenanceWindows>) -> Self {
+        Self {
+            rules,
+            firing: Mutex::default(),
+            usage: Mutex::default(),
+            maintenance,
+        }
+    }
+
+    /// 记录该样本各挂载点的已用量，返回 挂载点 → 预计写满秒数；未配置写满预测规则时为空。
+    /// 时间戳不晚于已记录样本的（补发、乱序）样本不计入趋势
+    fn forecast(&self, metrics: &MetricsRequest, disks: &[DiskMetrics]) -> HashMap<String, f64> {
+        let mut forecasts = HashMap::new();
+        if !self
+            .rules
+            .iter()
+            .any(|rule| matches!(rule.threshold, DiskFreeThreshold::FullWithin(_)))
+        {
+            return forecasts;
+        }
+
+        let mut usage = self.usage.lock().unwrap_or_else(PoisonError::into_inner);
+        let mounts = usage.entry(metrics.agent_id.clone()).or_default();
+        for disk in disks {
+            let points = mounts.entry(disk.mount_point.clone()).or_default();
+            if points
+                .back()
+                .is_some_and(|&(ts, _)| ts >= metrics.timestamp)
+            {
+                continue;
+            }
+            points.push_back((metrics.timestamp, disk.used as f64));
+            if points.len() > FORECAST_WINDOW {
+                points.pop_front();
+            }
+            if points.len() < MIN_FORECAST_SAMPLES {
+                continue;
+            }
+            let seconds_to_full = fit_linear_trend(points.make_contiguous())
+                .and_then(|trend| estimate_seconds_to_full(&trend, disk.used, disk.total));
+            if let Some(seconds_to_full) = seconds_to_full {
+                forecasts.insert(disk.mount_point.clone(), seconds_to_full);
+            }
+        }
+        forecasts
+    }
+
+    /// 以当前 Server 时间评估一条样本，返回告警状态变化对应的时间线事件
+    ///
+    /// 不带磁盘数据的样本（如关闭了磁盘采集）不参与评估，已触发的告警保持不变
+    pub fn observe(&self, metrics: &MetricsRequest) -> Vec<AgentEvent> {
+        self.observe_at(metrics, current_timestamp_ms())
+    }
+
+    fn observe_at(&self, metrics: &MetricsRequest, now: i64) -> Vec<AgentEvent> {
+        if self.rules.is_empty() {
+            return Vec::new();
+        }
+        let Some(system) = metrics.system.as_ref().filter(|s| !s.disks.is_empty()) else {
+            return Vec::new();
+        };
+        let disks: HashMap<&str, &DiskMetrics> = system
+            .disks
+            .iter()
+            .map(|disk| (disk.mount_point.as_str(), disk))
+            .collect();
+        let forecasts = self.forecast(metrics, &system.disks);
+
+        let mut breached = HashSet::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            let candidates: Vec<&DiskMetrics> = match &rule.mount_point {
+                Some(mount_point) => disks
+                    .get(mount_point.as_str())
+                    .copied()
+                    .into_iter()
+                    .collect(),
+                None => disks.values().copied().collect(),
+            };
+            for disk in candidates {
+                let seconds_to_full = forecasts.get(&disk.mount_point).copied();
+                if rule.threshold.is_breached(disk, seconds_to_full) {
+                    breached.insert((index, disk.mount_point.clone()));
+                }
+            }
+        }
+
+        let mut firing = self.firing.lock().unwrap_or_else(PoisonError::into_inner);
+        let active = firing.entry(metrics.agent_id.clone()).or_default();
+        let mut events = Vec::new();
+
+        // 维护期间只保留已触发的告警，新越过阈值的挂载点到期后再触发
+        if let Some(until) = self.maintenance.active_until(&metrics.agent_id, now) {
+            let suppressed = breached.difference(active).count();
+            if suppressed > 0 {
+                debug!(
+                    "Agent {} 处于维护模式（至 {}），暂不触发 {} 个告警",
+                    metrics.agent_id, until, suppressed
+                );
+            }
+            breached.retain(|key| active.contains(key));
+        }
+
+        for key in breached.difference(active) {
+            let (index, mount_point) = key;
+            let available = disks[mount_point.as_str()].available;
+            let details = match forecasts.get(mount_point) {
+                Some(seconds_to_full)
+                    if matches!(
+                        self.rules[*index].threshold,
+                        DiskFreeThreshold::FullWithin(_)
+                    ) =>
+                {
+                    format!(
+                        "挂载点 {} 可用 {}，预计 {:.1} 小时后写满（阈值 {}）",
+                        mount_point,
+                        format_bytes(available, ByteUnits::Iec),
+                        seconds_to_full / 3600.0,
+                        self.rules[*index]
+                    )
+                }
+                _ => format!(
+                    "挂载点 {} 可用 {}，低于阈值 {}",
+                    mount_point,
+                    format_bytes(available, ByteUnits::Iec),
+                    self.rules[*index]
+                ),
+            };
+            warn!("Agent {} 告警: {}", metrics.agent_id, details);
+            events.push(AgentEvent {
+                kind: AgentEventKind::AlertFired,
+                timestamp: metrics.timestamp,
+                details,
+            });
+        }
+        for key in active.difference(&breached) {
+            let (index, mount_point) = key;
+            let details = match disks.get(mount_point.as_str()) {
+                Some(disk) => format!(
+                    "挂载点 {} 可用 {}，已恢复（阈值 {}）",
+                    mount_point,
+                    format_bytes(disk.available, ByteUnits::Iec),
+                    self.rules[*index]
+                ),
+                None => format!(
+                    "挂载点 {} 不再上报，告警解除（阈值 {}）",
+                    mount_point, self.rules[*index]
+                ),
+            };
+            info!("Agent {} 告警恢复: {}", metrics.agent_id, details);
+            events.push(AgentEvent {
+                kind: AgentEventKind::AlertResolved,
+                timestamp: metrics.timestamp,
+                details,
+            });
+        }
+        *active = breached;
+        events
+    }
+
+    /// 清空全部正在触发的告警，返回 (agent_id, `AlertResolved` 事件)；用于优雅关闭，
+    /// 重启后仍低于阈值的挂载点会重新触发
+    pub fn resolve_all(&self, now: i64) -> Vec<(String, AgentEvent)> {
+        let firing =
+            std::mem::take(&mut *self.firing.lock().unwrap_or_else(PoisonError::into_inner));
+        let mut events = Vec::new();
+        for (agent_id, active) in firing {
+            let mut active: Vec<_> = active.into_iter().collect();
+            active.sort();
+            for (index, mount_point) in active {
+                events.push((
+                    agent_id.clone(),
+                    AgentEvent {
+                        kind: AgentEventKind::AlertResolved,
+                        timestamp: now,
+                        details: format!(
+                            "Server 关闭，挂载点 {} 的告警不再跟踪（阈值 {}）",
+                            mount_point, self.rules[index]
+                        ),
+                    },
+                ));
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::SystemMetrics;
+
+    const GIB: u64 = 1 << 30;
+
+    fn sample(disks: &[(&str, u64, u64)], timestamp: i64) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: "agent-1".to_string(),
+            timestamp,
+            system: Some(SystemMetrics {
+                disks: disks
+                    .iter()
+                    .map(|(mount_point, total, available)| DiskMetrics {
+                        mount_point: mount_point.to_string(),
+                        total: *total,
+                        available: *available,
+                        used: total - available,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        assert_eq!(
+            "10GiB".parse::<DiskFreeRule>().unwrap(),
+            DiskFreeRule {
+                mount_point: None,
+                threshold: DiskFreeThreshold::Bytes(10 * GIB),
+            }
+        );
+        assert_eq!(
+            "/data = 5%".parse::<DiskFreeRule>().unwrap(),
+            DiskFreeRule {
+                mount_point: Some("/data".to_string()),
+                threshold: DiskFreeThreshold::Percent(5.0),
+            }
+        );
+        assert_eq!(
+            "1.5gb".parse::<DiskFreeThreshold>(),
+            Ok(DiskFreeThreshold::Bytes(1_500_000_000))
+        );
+        assert_eq!(
+            "4096".parse::<DiskFreeThreshold>(),
+            Ok(DiskFreeThreshold::Bytes(4096))
+        );
+        assert_eq!(
+            "/data=full:4h".parse::<DiskFreeRule>().unwrap(),
+            DiskFreeRule {
+                mount_point: Some("/data".to_string()),
+                threshold: DiskFreeThreshold::FullWithin(4 * 3600),
+            }
+        );
+        assert_eq!(
+            "full:90m".parse::<DiskFreeRule>().unwrap().to_string(),
+            "full:90m"
+        );
+        for invalid in [
+            "", "10XB", "150%", "=10GiB", "-1G", "full:", "full:4x", "full:0h",
+        ] {
+            assert!(invalid.parse::<DiskFreeRule>().is_err(), "{}", invalid);
+        }
+        assert_eq!(
+            "/data=10GiB".parse::<DiskFreeRule>().unwrap().to_string(),
+            "/data=10.0 GiB"
+        );
+    }
+
+    #[test]
+    fn test_absolute_rule_ignores_large_mount_percentage() {
+        let engine = AlertEngine::new(vec!["10GiB".parse().unwrap()], Arc::default());
+
+        // 20 TiB 的卷使用率 97%，仍有约 614 GiB 可用，不触发；50 GiB 的卷只剩 4 GiB，触发
+        let big_total = 20 * 1024 * GIB;
+        let big_free = big_total * 3 / 100;
+        let events = engine.observe(&sample(
+            &[("/big", big_total, big_free), ("/small", 50 * GIB, 4 * GIB)],
+            1_000,
+        ));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, AgentEventKind::AlertFired);
+        assert!(
+            events[0].details.contains("/small"),
+            "{}",
+            events[0].details
+        );
+
+        // 持续低于阈值不重复触发；不带磁盘数据的样本不影响状态
+        assert!(engine
+            .observe(&sample(
+                &[("/big", big_total, big_free), ("/small", 50 * GIB, 3 * GIB)],
+                2_000
+            ))
+            .is_empty());
+        assert!(engine.observe(&sample(&[], 3_000)).is_empty());
+
+        let events = engine.observe(&sample(
+            &[
+                ("/big", big_total, big_free),
+                ("/small", 50 * GIB, 20 * GIB),
+            ],
+            4_000,
+        ));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, AgentEventKind::AlertResolved);
+        assert_eq!(events[0].timestamp, 4_000);
+    }
+
+    #[test]
+    fn test_percent_rule_limited_to_mount() {
+        let engine = AlertEngine::new(vec!["/data=5%".parse().unwrap()], Arc::default());
+        // 只评估 /data；/ 剩余 1% 不触发
+        let events = engine.observe(&sample(&[("/", 100, 1), ("/data", 100, 4)], 1_000));
+        assert_eq!(events.len(), 1);
+        assert!(events[0].details.contains("/data"));
+
+        // 挂载点不再上报时解除
+        let events = engine.observe(&sample(&[("/", 100, 1)], 2_000));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, AgentEventKind::AlertResolved);
+    }
+
+    #[test]
+    fn test_maintenance_suppresses_firing_until_expiry() {
+        let maintenance = Arc::new(MaintenanceWindows::default());
+        let engine = AlertEngine::new(vec!["10GiB".parse().unwrap()], maintenance.clone());
+        let low = |timestamp| sample(&[("/data", 50 * GIB, 4 * GIB)], timestamp);
+
+        maintenance.start("agent-1", 10_000);
+        assert!(engine.observe_at(&low(1_000), 1_000).is_empty());
+        assert!(engine.observe_at(&low(9_000), 9_000).is_empty());
+
+        // 到期后自动解除，仍低于阈值的挂载点照常触发
+        let events = engine.observe_at(&low(10_000), 10_000);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, AgentEventKind::AlertFired);
+        assert_eq!(maintenance.active_until("agent-1", 10_000), None);
+
+        // 维护期间已触发的告警仍可恢复
+        maintenance.start("agent-1", 20_000);
+        let events = engine.observe_at(&sample(&[("/data", 50 * GIB, 20 * GIB)], 11_000), 11_000);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, AgentEventKind::AlertResolved);
+    }
+
+    #[test]
+    fn test_forecast_rule_fires_when_disk_fills_within_window() {
+        let engine = AlertEngine::new(vec!["/data=full:4h".parse().unwrap()], Arc::default());
+        // 每 10 分钟写入 1 GiB：剩余 30 GiB 时预计约 5 小时写满，不触发；剩余 20 GiB 时约 3.3 小时，触发
+        let filling = |step: i64| {
+            sample(
+                &[("/data", 100 * GIB, (60 - step as u64) * GIB)],
+                step * 600_000,
+            )
+        };
+        for step in 0..30 {
+            assert!(engine.observe(&filling(step)).is_empty(), "step {}", step);
+        }
+        // 乱序的旧样本不影响趋势
+        assert!(engine.observe(&filling(3)).is_empty());
+        let mut fired = Vec::new();
+        for step in 30..40 {
+            fired.extend(engine.observe(&filling(step)));
+        }
+        assert_eq!(fired.len(), 1, "{:?}", fired);
+        assert_eq!(fired[0].kind, AgentEventKind::AlertFired);
+        assert!(fired[0].details.contains("写满"), "{}", fired[0].details);
+
+        // 用量持平后不再预测写满，告警恢复
+        let flat = |step: i64| sample(&[("/data", 100 * GIB, 20 * GIB)], step * 600_000);
+        let resolved: Vec<_> = (40..400)
+            .flat_map(|step| engine.observe(&flat(step)))
+            .collect();
+        assert_eq!(resolved.len(), 1, "{:?}", resolved);
+        assert_eq!(resolved[0].kind, AgentEventKind::AlertResolved);
+    }
+
+    #[test]
+    fn test_resolve_all_clears_firing_alerts() {
+        let engine = AlertEngine::new(vec!["10GiB".parse().unwrap()], Arc::default());
+        let events = engine.observe_at(&sample(&[("/data", 50 * GIB, 5 * GIB)], 1_000), 1_000);
+        assert_eq!(events.len(), 1);
+
+        let resolved = engine.resolve_all(2_000);
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].0, "agent-1");
+        assert_eq!(resolved[0].1.kind, AgentEventKind::AlertResolved);
+        assert_eq!(resolved[0].1.timestamp, 2_000);
+        assert!(engine.resolve_all(3_000).is_empty());
+
+        // 状态清空后仍低于阈值的挂载点重新触发
+        let events = engine.observe_at(&sample(&[("/data", 50 * GIB, 5 * GIB)], 4_000), 4_000);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, AgentEventKind::AlertFired);
+    }
+}
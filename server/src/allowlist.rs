@@ -0,0 +1,49 @@
+//! Agent 准入名单
+//!
+//! 默认接受任何 agent_id 的样本。开启准入名单后只接受名单中的 agent_id：名单由启动参数
+//! 给出，运行时可经 `POST /api/admin/agents`（须配置管理令牌）追加（只保存在内存中，Server 重启后以启动参数
+//! 为准）。未登记的 Agent 上报时返回 `PERMISSION_DENIED`
+
+use std::collections::HashSet;
+use std::sync::{PoisonError, RwLock};
+
+/// Agent 准入名单，`agents` 为 None 时不限制
+#[derive(Debug, Default)]
+pub struct AgentAllowlist {
+    agents: Option<RwLock<HashSet<String>>>,
+}
+
+impl AgentAllowlist {
+    /// `agents` 为 None 时不限制，否则只接受其中的 agent_id
+    pub fn new(agents: Option<HashSet<String>>) -> Self {
+        Self {
+            agents: agents.map(RwLock::new),
+        }
+    }
+
+    /// 是否开启了准入名单
+    pub fn is_enabled(&self) -> bool {
+        self.agents.is_some()
+    }
+
+    /// 是否接受该 agent_id 的样本
+    pub fn is_allowed(&self, agent_id: &str) -> bool {
+        self.agents.as_ref().is_none_or(|agents| {
+            agents
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .contains(agent_id)
+        })
+    }
+
+    /// 登记一个 agent_id，返回是否为新登记；未开启准入名单时返回 None
+    pub fn register(&self, agent_id: String) -> Option<bool> {
+        let agents = self.agents.as_ref()?;
+        Some(
+            agents
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(agent_id),
+        )
+    }
+}
@@ -0,0 +1,915 @@
+//! 指标分析工具
+//!
+//! 基于历史样本的轻量计算（趋势拟合、写满预测等），供 HTTP API 复用
+
+use common::proto::{CpuMetrics, MetricsRequest, SystemMetrics};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::timezone::TimeZone;
+
+/// 预测所需的最少样本数
+pub const MIN_FORECAST_SAMPLES: usize = 5;
+/// 拟合优度下限，低于该值视为数据噪声过大，不给出预测
+pub const MIN_FORECAST_R_SQUARED: f64 = 0.8;
+
+/// 线性趋势拟合结果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinearTrend {
+    /// 斜率（每秒变化量）
+    pub slope_per_sec: f64,
+    /// 拟合优度 R²（0-1）
+    pub r_squared: f64,
+}
+
+/// 对 (时间戳毫秒, 数值) 序列做最小二乘线性拟合
+///
+/// 样本不足或时间跨度为 0 时返回 None
+pub fn fit_linear_trend(points: &[(i64, f64)]) -> Option<LinearTrend> {
+    if points.len() < 2 {
+        return None;
+    }
+
+    // 以首个时间戳为原点，避免毫秒时间戳平方后丢失精度
+    let origin = points[0].0;
+    let n = points.len() as f64;
+    let xs: Vec<f64> = points
+        .iter()
+        .map(|(ts, _)| (ts - origin) as f64 / 1000.0)
+        .collect();
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, v)| v).sum::<f64>() / n;
+
+    let mut sxx = 0.0;
+    let mut sxy = 0.0;
+    let mut syy = 0.0;
+    for (x, (_, y)) in xs.iter().zip(points) {
+        let dx = x - mean_x;
+        let dy = y - mean_y;
+        sxx += dx * dx;
+        sxy += dx * dy;
+        syy += dy * dy;
+    }
+
+    if sxx == 0.0 {
+        return None;
+    }
+
+    let slope_per_sec = sxy / sxx;
+    // 数值完全不变时拟合是“完美”的，但斜率为 0
+    let r_squared = if syy == 0.0 {
+        1.0
+    } else {
+        (sxy * sxy) / (sxx * syy)
+    };
+
+    Some(LinearTrend {
+        slope_per_sec,
+        r_squared,
+    })
+}
+
+/// 单个挂载点的写满预测
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskForecast {
+    pub mount_point: String,
+    pub device: String,
+    /// 总容量（字节）
+    pub total: u64,
+    /// 最近一次已用量（字节）
+    pub used: u64,
+    /// 参与拟合的样本数
+    pub samples: usize,
+    /// 增长速率（字节/秒），样本不足时为 None
+    pub growth_bytes_per_sec: Option<f64>,
+    /// 拟合优度 R²
+    pub r_squared: Option<f64>,
+    /// 预计多少秒后写满；用量持平、下降或噪声过大时为 None
+    pub seconds_to_full: Option<f64>,
+    /// 预计写满时间（毫秒时间戳）
+    pub full_at: Option<i64>,
+}
+
+/// 根据增长趋势估算写满剩余时间（秒）
+///
+/// 仅在趋势为增长且拟合优度达标时给出结果
+pub fn estimate_seconds_to_full(trend: &LinearTrend, used: u64, total: u64) -> Option<f64> {
+    if trend.slope_per_sec <= 0.0 || trend.r_squared < MIN_FORECAST_R_SQUARED {
+        return None;
+    }
+    Some(total.saturating_sub(used) as f64 / trend.slope_per_sec)
+}
+
+/// 单个挂载点的已用量序列
+#[derive(Default)]
+struct MountSeries {
+    device: String,
+    total: u64,
+    /// (时间戳毫秒, 已用字节)
+    points: Vec<(i64, f64)>,
+}
+
+/// 按挂载点对历史样本做写满预测（history 需按时间升序）
+pub fn forecast_disks(history: &[MetricsRequest]) -> Vec<DiskForecast> {
+    let mut series: BTreeMap<String, MountSeries> = BTreeMap::new();
+    for sample in history {
+        let Some(system) = &sample.system else {
+            continue;
+        };
+        for disk in &system.disks {
+            let entry = series.entry(disk.mount_point.clone()).or_default();
+            entry.device = disk.device.clone();
+            entry.total = disk.total;
+            entry.points.push((sample.timestamp, disk.used as f64));
+        }
+    }
+
+    series
+        .into_iter()
+        .map(
+            |(
+                mount_point,
+                MountSeries {
+                    device,
+                    total,
+                    points,
+                },
+            )| {
+                let (last_ts, used) = points
+                    .last()
+                    .map(|(ts, used)| (*ts, *used as u64))
+                    .unwrap_or_default();
+                let trend = if points.len() >= MIN_FORECAST_SAMPLES {
+                    fit_linear_trend(&points)
+                } else {
+                    None
+                };
+                let seconds_to_full = trend.and_then(|t| estimate_seconds_to_full(&t, used, total));
+
+                DiskForecast {
+                    mount_point,
+                    device,
+                    total,
+                    used,
+                    samples: points.len(),
+                    growth_bytes_per_sec: trend.map(|t| t.slope_per_sec),
+                    r_squared: trend.map(|t| t.r_squared),
+                    seconds_to_full,
+                    full_at: seconds_to_full.map(|secs| last_ts + (secs * 1000.0).round() as i64),
+                }
+            },
+        )
+        .collect()
+}
+
+/// 可按名称选取的单值指标，供只需要一条数值序列的接口使用
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricField {
+    /// CPU 使用率（%）
+    Cpu,
+    /// 内存使用率（%）
+    Memory,
+    /// Swap 已使用（字节）
+    Swap,
+    /// 1 分钟负载
+    Load1,
+    /// 5 分钟负载
+    Load5,
+    /// 15 分钟负载
+    Load15,
+    /// 各挂载点中最高的磁盘使用率（%）
+    Disk,
+    /// 系统在用的文件描述符数
+    Fds,
+    /// 内核可用熵（位）
+    Entropy,
+}
+
+impl MetricField {
+    /// 全部可选指标
+    pub const ALL: [Self; 9] = [
+        Self::Cpu,
+        Self::Memory,
+        Self::Swap,
+        Self::Load1,
+        Self::Load5,
+        Self::Load15,
+        Self::Disk,
+        Self::Fds,
+        Self::Entropy,
+    ];
+
+    /// 查询参数中使用的名称
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Cpu => "cpu",
+            Self::Memory => "memory",
+            Self::Swap => "swap",
+            Self::Load1 => "load1",
+            Self::Load5 => "load5",
+            Self::Load15 => "load15",
+            Self::Disk => "disk",
+            Self::Fds => "fds",
+            Self::Entropy => "entropy",
+        }
+    }
+
+    /// 按名称查找指标
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|field| field.name() == name)
+    }
+
+    /// 从样本中取该指标的值，样本缺少对应字段时为 None
+    pub fn value(self, metrics: &MetricsRequest) -> Option<f64> {
+        let system = metrics.system.as_ref()?;
+        match self {
+            Self::Cpu => system.cpu.as_ref().map(|cpu| cpu.usage_percent),
+            Self::Memory => system.memory.as_ref().map(|memory| memory.usage_percent),
+            Self::Swap => system.memory.as_ref().map(|memory| memory.swap_used as f64),
+            Self::Load1 => system.cpu.as_ref().map(|cpu| cpu.load_avg_1),
+            Self::Load5 => system.cpu.as_ref().map(|cpu| cpu.load_avg_5),
+            Self::Load15 => system.cpu.as_ref().map(|cpu| cpu.load_avg_15),
+            Self::Disk => system
+                .disks
+                .iter()
+                .map(|disk| disk.usage_percent)
+                .max_by(f64::total_cmp),
+            Self::Fds => system.file_descriptors.as_ref().map(|fds| fds.open as f64),
+            Self::Entropy => system
+                .entropy
+                .as_ref()
+                .map(|entropy| entropy.available as f64),
+        }
+    }
+}
+
+/// 时间桶内的聚合方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    /// 平均值
+    #[default]
+    Avg,
+    /// 最小值
+    Min,
+    /// 最大值
+    Max,
+    /// 求和
+    Sum,
+    /// 样本数
+    Count,
+    /// 最后一个值
+    Last,
+}
+
+impl Aggregation {
+    /// 聚合按时间升序的取值，空序列为 None
+    pub fn apply(self, values: &[f64]) -> Option<f64> {
+        let last = *values.last()?;
+        Some(match self {
+            Self::Avg => values.iter().sum::<f64>() / values.len() as f64,
+            Self::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            Self::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            Self::Sum => values.iter().sum(),
+            Self::Count => values.len() as f64,
+            Self::Last => last,
+        })
+    }
+
+    /// 合并多个 Agent 在同一时间桶内各自的聚合值
+    ///
+    /// avg/min/max 对各 Agent 的值再取同样的聚合；sum/count/last 对各 Agent 的值求和
+    /// （如 last 得到的是各 Agent 最新值之和）
+    pub fn combine(self, values: &[f64]) -> Option<f64> {
+        match self {
+            Self::Avg | Self::Min | Self::Max => self.apply(values),
+            Self::Sum | Self::Count | Self::Last => Self::Sum.apply(values),
+        }
+    }
+}
+
+/// 单个时间桶的聚合状态，大小固定，与桶内点数无关
+#[derive(Debug, Clone, Copy)]
+struct BucketState {
+    count: usize,
+    sum: f64,
+    min: f64,
+    max: f64,
+    /// 桶内时间戳最大的点，时间戳相同时取后到达的
+    last: (i64, f64),
+}
+
+impl BucketState {
+    fn new(ts: i64, value: f64) -> Self {
+        Self {
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+            last: (ts, value),
+        }
+    }
+
+    fn push(&mut self, ts: i64, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        if ts >= self.last.0 {
+            self.last = (ts, value);
+        }
+    }
+
+    fn value(&self, aggregation: Aggregation) -> f64 {
+        match aggregation {
+            Aggregation::Avg => self.sum / self.count as f64,
+            Aggregation::Min => self.min,
+            Aggregation::Max => self.max,
+            Aggregation::Sum => self.sum,
+            Aggregation::Count => self.count as f64,
+            Aggregation::Last => self.last.1,
+        }
+    }
+}
+
+/// 逐点把 (时间戳毫秒, 取值) 折叠进从 `start` 起、宽 `bucket_ms` 的时间桶
+///
+/// 每个桶只保存固定大小的聚合状态，内存占用取决于桶数而不是点数，
+/// 可直接消费存储层的流式游标聚合任意长的时间范围
+#[derive(Debug)]
+pub struct BucketFolder {
+    start: i64,
+    bucket_ms: i64,
+    aggregation: Aggregation,
+    time_zone: Option<TimeZone>,
+    buckets: BTreeMap<i64, BucketState>,
+}
+
+impl BucketFolder {
+    pub fn new(start: i64, bucket_ms: i64, aggregation: Aggregation) -> Self {
+        Self {
+            start,
+            bucket_ms,
+            aggregation,
+            time_zone: None,
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    /// 桶边界按时区的本地时钟对齐（见 [`TimeZone::bucket_start`]），而不是从 `start` 起算
+    pub fn with_time_zone(mut self, time_zone: Option<TimeZone>) -> Self {
+        self.time_zone = time_zone;
+        self
+    }
+
+    /// 折叠一个点；早于 `start` 的点忽略
+    pub fn push(&mut self, ts: i64, value: f64) {
+        if ts < self.start || self.bucket_ms <= 0 {
+            return;
+        }
+        let bucket = match &self.time_zone {
+            Some(time_zone) => time_zone.bucket_start(ts, self.bucket_ms),
+            None => self.start + (ts - self.start) / self.bucket_ms * self.bucket_ms,
+        };
+        self.buckets
+            .entry(bucket)
+            .and_modify(|state| state.push(ts, value))
+            .or_insert_with(|| BucketState::new(ts, value));
+    }
+
+    /// 当前持有的桶数
+    #[cfg(test)]
+    pub fn bucket_count(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// 返回 (桶起点, 聚合值)，按桶起点升序，空桶不输出
+    pub fn finish(self) -> Vec<(i64, f64)> {
+        self.buckets
+            .into_iter()
+            .map(|(bucket, state)| (bucket, state.value(self.aggregation)))
+            .collect()
+    }
+}
+
+/// 把多个 Agent 已分桶的序列按桶起点合并为一条，桶值按 [`Aggregation::combine`] 合并
+pub fn combine_series(series: &[Vec<(i64, f64)>], aggregation: Aggregation) -> Vec<(i64, f64)> {
+    let mut buckets: BTreeMap<i64, Vec<f64>> = BTreeMap::new();
+    for &(ts, value) in series.iter().flatten() {
+        buckets.entry(ts).or_default().push(value);
+    }
+
+    buckets
+        .into_iter()
+        .filter_map(|(bucket, values)| Some((bucket, aggregation.combine(&values)?)))
+        .collect()
+}
+
+/// 按时间升序的序列中相邻点时间差的中位数，少于两个点时为 None
+pub fn median_interval(points: &[(i64, f64)]) -> Option<i64> {
+    let mut gaps: Vec<i64> = points
+        .windows(2)
+        .map(|pair| pair[1].0 - pair[0].0)
+        .filter(|&gap| gap > 0)
+        .collect();
+    if gaps.is_empty() {
+        return None;
+    }
+    gaps.sort_unstable();
+    Some(gaps[gaps.len() / 2])
+}
+
+/// 在 `timestamps` 各时刻对按时间升序的 `points` 做线性插值
+///
+/// 时刻早于首个点、晚于最后一个点，或两侧的点相距超过 `max_gap`（断线空档）时为 None，
+/// 不做外推。`timestamps` 需升序
+pub fn interpolate_at(points: &[(i64, f64)], timestamps: &[i64], max_gap: i64) -> Vec<Option<f64>> {
+    let mut next = 0;
+    timestamps
+        .iter()
+        .map(|&ts| {
+            while next < points.len() && points[next].0 < ts {
+                next += 1;
+            }
+            let &(after_ts, after) = points.get(next)?;
+            if after_ts == ts {
+                return Some(after);
+            }
+            let &(before_ts, before) = points.get(next.checked_sub(1)?)?;
+            if after_ts - before_ts > max_gap {
+                return None;
+            }
+            let ratio = (ts - before_ts) as f64 / (after_ts - before_ts) as f64;
+            Some(before + (after - before) * ratio)
+        })
+        .collect()
+}
+
+/// 按时间把历史样本重采样为约 `points` 个等宽时间桶，每桶输出一条代表样本
+///
+/// 代表样本以桶内最后一条为模板（保留系统信息与累计计数器），CPU/内存/磁盘/GPU
+/// 的瞬时量取桶内平均值；空桶不输出，样本数不超过 `points` 时原样返回。
+/// history 需按时间升序
+pub fn resample_history(history: Vec<MetricsRequest>, points: usize) -> Vec<MetricsRequest> {
+    if points == 0 || history.len() <= points {
+        return history;
+    }
+    let (Some(first), Some(last)) = (history.first(), history.last()) else {
+        return history;
+    };
+    let mut resampler = Resampler::new(first.timestamp, last.timestamp, points);
+    for sample in history {
+        resampler.push(sample);
+    }
+    resampler.finish()
+}
+
+/// 把按时间升序到达的样本逐条折叠进 `[start, end]` 上约 `points` 个等宽时间桶
+///
+/// 只缓存当前桶内的样本，时间范围很大时内存占用也只与单个桶的样本数有关；
+/// 代表样本的合并规则同 [`resample_history`]，范围外的样本计入首/末桶
+pub struct Resampler {
+    start: i64,
+    span: u128,
+    points: usize,
+    current: Option<usize>,
+    bucket: Vec<MetricsRequest>,
+    output: Vec<MetricsRequest>,
+}
+
+impl Resampler {
+    pub fn new(start: i64, end: i64, points: usize) -> Self {
+        Self {
+            start,
+            span: (end.saturating_sub(start)).max(0) as u128 + 1,
+            points: points.max(1),
+            current: None,
+            bucket: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, sample: MetricsRequest) {
+        let offset = sample.timestamp.saturating_sub(self.start).max(0) as u128;
+        let index = ((offset * self.points as u128 / self.span) as usize).min(self.points - 1);
+        if self.current != Some(index) {
+            self.flush();
+            self.current = Some(index);
+        }
+        self.bucket.push(sample);
+    }
+
+    /// 输出各非空桶的代表样本（按时间升序）
+    pub fn finish(mut self) -> Vec<MetricsRequest> {
+        self.flush();
+        self.output
+    }
+
+    fn flush(&mut self) {
+        if let Some(representative) = average_bucket(std::mem::take(&mut self.bucket)) {
+            self.output.push(representative);
+        }
+    }
+}
+
+/// 合并单个桶内的样本：以最后一条为模板，瞬时量取平均
+///
+/// 轻量 Agent 可能只上报部分分区：模板缺少的分区（含空的列表分区，模板整个缺少 `system`
+/// 时同样）取桶内最近一条上报了该分区的样本，瞬时量只在上报了该分区（或该挂载点、GPU）的样本之间平均，
+/// 未上报的样本不按 0 计入
+fn average_bucket(samples: Vec<MetricsRequest>) -> Option<MetricsRequest> {
+    let mut representative = samples.last()?.clone();
+    if samples.len() == 1 {
+        return Some(representative);
+    }
+    let systems: Vec<_> = samples.iter().filter_map(|s| s.system.as_ref()).collect();
+    if systems.is_empty() {
+        return Some(representative);
+    }
+    let system = representative.system.get_or_insert_with(Default::default);
+
+    fill_missing(&mut system.cpu, &systems, |s| s.cpu.as_ref());
+    fill_missing(&mut system.memory, &systems, |s| s.memory.as_ref());
+    fill_missing(&mut system.network, &systems, |s| s.network.as_ref());
+    fill_missing(&mut system.system_info, &systems, |s| {
+        s.system_info.as_ref()
+    });
+    fill_missing(&mut system.agent_metrics, &systems, |s| {
+        s.agent_metrics.as_ref()
+    });
+    fill_missing(&mut system.pressure, &systems, |s| s.pressure.as_ref());
+    fill_missing(&mut system.file_descriptors, &systems, |s| {
+        s.file_descriptors.as_ref()
+    });
+    fill_missing(&mut system.entropy, &systems, |s| s.entropy.as_ref());
+    fill_missing(&mut system.systemd, &systems, |s| s.systemd.as_ref());
+    fill_missing(&mut system.containers, &systems, |s| s.containers.as_ref());
+    // 列表分区为空数组时同样视为未上报
+    fill_empty(&mut system.disks, &systems, |s| &s.disks);
+    fill_empty(&mut system.gpu, &systems, |s| &s.gpu);
+    fill_empty(&mut system.tcp_ping, &systems, |s| &s.tcp_ping);
+    fill_empty(&mut system.collector_status, &systems, |s| {
+        &s.collector_status
+    });
+    fill_empty(&mut system.top_processes, &systems, |s| &s.top_processes);
+    fill_empty(&mut system.numa_nodes, &systems, |s| &s.numa_nodes);
+    let mean = |values: &mut dyn Iterator<Item = f64>| {
+        let (sum, count) = values.fold((0.0, 0u32), |(sum, count), v| (sum + v, count + 1));
+        sum / f64::from(count.max(1))
+    };
+    let mean_u64 =
+        |values: &mut dyn Iterator<Item = u64>| mean(&mut values.map(|v| v as f64)).round() as u64;
+
+    if let Some(cpu) = system.cpu.as_mut() {
+        let peers: Vec<_> = systems.iter().filter_map(|s| s.cpu.as_ref()).collect();
+        cpu.usage_percent = mean(&mut peers.iter().map(|c| c.usage_percent));
+        cpu.load_avg_1 = mean(&mut peers.iter().map(|c| c.load_avg_1));
+        cpu.load_avg_5 = mean(&mut peers.iter().map(|c| c.load_avg_5));
+        cpu.load_avg_15 = mean(&mut peers.iter().map(|c| c.load_avg_15));
+        // 各核心数组长度一致时逐核心取平均
+        let mean_per_core = |own: &mut Vec<f64>, field: fn(&CpuMetrics) -> &Vec<f64>| {
+            if peers.iter().all(|c| field(c).len() == own.len()) {
+                for (i, core) in own.iter_mut().enumerate() {
+                    *core = mean(&mut peers.iter().map(|c| field(c)[i]));
+                }
+            }
+        };
+        mean_per_core(&mut cpu.per_core, |c| &c.per_core);
+        mean_per_core(&mut cpu.per_core_frequency, |c| &c.per_core_frequency);
+        mean_per_core(&mut cpu.per_core_temperature, |c| &c.per_core_temperature);
+    }
+
+    if let Some(memory) = system.memory.as_mut() {
+        let peers: Vec<_> = systems.iter().filter_map(|s| s.memory.as_ref()).collect();
+        memory.used = mean_u64(&mut peers.iter().map(|m| m.used));
+        memory.available = mean_u64(&mut peers.iter().map(|m| m.available));
+        memory.usage_percent = mean(&mut peers.iter().map(|m| m.usage_percent));
+        memory.swap_used = mean_u64(&mut peers.iter().map(|m| m.swap_used));
+    }
+
+    for disk in &mut system.disks {
+        let peers: Vec<_> = systems
+            .iter()
+            .filter_map(|s| s.disks.iter().find(|d| d.mount_point == disk.mount_point))
+            .collect();
+        disk.used = mean_u64(&mut peers.iter().map(|d| d.used));
+        disk.available = mean_u64(&mut peers.iter().map(|d| d.available));
+        disk.usage_percent = mean(&mut peers.iter().map(|d| d.usage_percent));
+    }
+
+    for gpu in &mut system.gpu {
+        let peers: Vec<_> = systems
+            .iter()
+            .filter_map(|s| s.gpu.iter().find(|g| g.uuid == gpu.uuid))
+            .collect();
+        gpu.utilization_percent = mean(&mut peers.iter().map(|g| g.utilization_percent));
+        gpu.memory_used = mean_u64(&mut peers.iter().map(|g| g.memory_used));
+        gpu.temperature = mean(&mut peers.iter().map(|g| g.temperature));
+        gpu.power_watts = mean(&mut peers.iter().map(|g| g.power_watts));
+    }
+
+    Some(representative)
+}
+
+/// 模板缺少该分区时取桶内最近一条上报了它的样本
+fn fill_missing<T: Clone>(
+    own: &mut Option<T>,
+    systems: &[&SystemMetrics],
+    section: fn(&SystemMetrics) -> Option<&T>,
+) {
+    if own.is_none() {
+        *own = systems.iter().rev().find_map(|s| section(s)).cloned();
+    }
+}
+
+/// 模板的列表分区为空时取桶内最近一条非空的
+fn fill_empty<T: Clone>(
+    own: &mut Vec<T>,
+    systems: &[&SystemMetrics],
+    section: fn(&SystemMetrics) -> &Vec<T>,
+) {
+    if own.is_empty() {
+        if let Some(list) = systems
+            .iter()
+            .rev()
+            .map(|s| section(s))
+            .find(|l| !l.is_empty())
+        {
+            own.clone_from(list);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::{
+        CollectorStatus, DiskMetrics, EntropyMetrics, MemoryMetrics, NetworkMetrics,
+        NumaNodeMemory, PressureMetrics, SystemInfo, TcpPingMetrics,
+    };
+
+    #[test]
+    fn test_interpolate_at_shared_boundaries() {
+        let points = [(1000, 10.0), (3000, 30.0), (4000, 20.0), (20_000, 0.0)];
+        assert_eq!(median_interval(&points), Some(2000));
+        assert_eq!(median_interval(&points[..1]), None);
+
+        let values = interpolate_at(&points, &[0, 1000, 2000, 3500, 4000, 10_000, 30_000], 5000);
+        // 范围外与断线空档内不插值
+        assert_eq!(
+            values,
+            vec![
+                None,
+                Some(10.0),
+                Some(20.0),
+                Some(25.0),
+                Some(20.0),
+                None,
+                None
+            ]
+        );
+    }
+
+    const GB: u64 = 1_000_000_000;
+
+    fn disk_sample(timestamp: i64, used: u64, total: u64) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: "agent-1".to_string(),
+            timestamp,
+            hostname: "test-host".to_string(),
+            sequence: 0,
+            labels: Default::default(),
+            system: Some(SystemMetrics {
+                disks: vec![DiskMetrics {
+                    mount_point: "/data".to_string(),
+                    device: "/dev/sdb1".to_string(),
+                    total,
+                    used,
+                    available: total - used,
+                    usage_percent: used as f64 / total as f64 * 100.0,
+                    read_bytes: 0,
+                    write_bytes: 0,
+                }],
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn test_steadily_filling_disk_forecast() {
+        // 每分钟增长 1GB，60 分钟后已用 60GB，总量 100GB → 约 40 分钟后写满
+        let history: Vec<_> = (0..=60)
+            .map(|i| disk_sample(i * 60_000, (i as u64) * GB, 100 * GB))
+            .collect();
+
+        let forecasts = forecast_disks(&history);
+        assert_eq!(forecasts.len(), 1);
+
+        let f = &forecasts[0];
+        assert_eq!(f.mount_point, "/data");
+        assert_eq!(f.samples, 61);
+        let secs = f.seconds_to_full.unwrap();
+        assert!((secs - 40.0 * 60.0).abs() < 1.0, "unexpected eta: {}", secs);
+        assert_eq!(f.full_at, Some(60 * 60_000 + 40 * 60_000));
+        assert!(f.r_squared.unwrap() > 0.99);
+    }
+
+    #[test]
+    fn test_flat_or_shrinking_usage_has_no_forecast() {
+        let flat: Vec<_> = (0..10)
+            .map(|i| disk_sample(i * 60_000, 50 * GB, 100 * GB))
+            .collect();
+        assert!(forecast_disks(&flat)[0].seconds_to_full.is_none());
+
+        let shrinking: Vec<_> = (0..10)
+            .map(|i| disk_sample(i * 60_000, (50 - i as u64) * GB, 100 * GB))
+            .collect();
+        assert!(forecast_disks(&shrinking)[0].seconds_to_full.is_none());
+    }
+
+    #[test]
+    fn test_noisy_usage_requires_confidence() {
+        // 上下剧烈抖动，整体只有轻微增长
+        let noisy: Vec<_> = (0..20)
+            .map(|i| {
+                let used = if i % 2 == 0 { 40 * GB } else { 60 * GB } + i as u64;
+                disk_sample(i * 60_000, used, 100 * GB)
+            })
+            .collect();
+        let f = &forecast_disks(&noisy)[0];
+        assert!(f.r_squared.unwrap() < MIN_FORECAST_R_SQUARED);
+        assert!(f.seconds_to_full.is_none());
+    }
+
+    #[test]
+    fn test_too_few_samples() {
+        let history: Vec<_> = (0..3)
+            .map(|i| disk_sample(i * 60_000, (i as u64) * GB, 100 * GB))
+            .collect();
+        let f = &forecast_disks(&history)[0];
+        assert!(f.growth_bytes_per_sec.is_none());
+        assert!(f.seconds_to_full.is_none());
+    }
+
+    #[test]
+    fn test_resample_history_to_points() {
+        let history: Vec<_> = (0..5000)
+            .map(|i| disk_sample(i * 1000, (i as u64) * GB, 10_000 * GB))
+            .collect();
+
+        let resampled = resample_history(history, 50);
+        assert_eq!(resampled.len(), 50);
+        // 每桶 100 条样本，以最后一条为模板，已用量取平均
+        assert_eq!(resampled[0].timestamp, 99_000);
+        let disk = &resampled[0].system.as_ref().unwrap().disks[0];
+        assert_eq!(disk.used, 49 * GB + GB / 2);
+        assert!(resampled
+            .windows(2)
+            .all(|w| w[0].timestamp < w[1].timestamp));
+    }
+
+    #[test]
+    fn test_resample_history_fewer_samples_than_points() {
+        let history: Vec<_> = (0..10)
+            .map(|i| disk_sample(i * 1000, (i as u64) * GB, 100 * GB))
+            .collect();
+        let resampled = resample_history(history.clone(), 50);
+        assert_eq!(resampled, history);
+    }
+
+    #[test]
+    fn test_resample_fills_every_section_from_latest_reporter() {
+        let full = SystemMetrics {
+            memory: Some(MemoryMetrics {
+                used: 2 * GB,
+                ..Default::default()
+            }),
+            network: Some(NetworkMetrics {
+                bytes_sent: 100,
+                ..Default::default()
+            }),
+            system_info: Some(SystemInfo {
+                kernel_version: "6.1".to_string(),
+                ..Default::default()
+            }),
+            pressure: Some(PressureMetrics::default()),
+            entropy: Some(EntropyMetrics {
+                available: 256,
+                ..Default::default()
+            }),
+            tcp_ping: vec![TcpPingMetrics::default()],
+            collector_status: vec![CollectorStatus::default()],
+            numa_nodes: vec![NumaNodeMemory::default()],
+            ..Default::default()
+        };
+        let mut newer = full.clone();
+        newer.network.as_mut().unwrap().bytes_sent = 200;
+        // 桶内最后一条只上报了内存，另有一条整个缺少 system
+        let light = SystemMetrics {
+            memory: Some(MemoryMetrics {
+                used: 4 * GB,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let sample = |timestamp, system| MetricsRequest {
+            agent_id: "agent-1".to_string(),
+            timestamp,
+            system,
+            ..Default::default()
+        };
+        let bucket = vec![
+            sample(1000, Some(full)),
+            sample(2000, Some(newer.clone())),
+            sample(3000, None),
+            sample(4000, Some(light)),
+        ];
+
+        let merged = average_bucket(bucket).unwrap();
+        assert_eq!(merged.timestamp, 4000);
+        let system = merged.system.unwrap();
+        assert_eq!(system.network, newer.network);
+        assert_eq!(system.system_info, newer.system_info);
+        assert_eq!(system.pressure, newer.pressure);
+        assert_eq!(system.entropy, newer.entropy);
+        assert_eq!(system.tcp_ping, newer.tcp_ping);
+        assert_eq!(system.collector_status, newer.collector_status);
+        assert_eq!(system.numa_nodes, newer.numa_nodes);
+        // 没有任何样本上报的分区仍为缺省
+        assert!(system.cpu.is_none() && system.containers.is_none());
+        // 瞬时量只在上报了该分区的三条样本之间平均
+        assert_eq!(system.memory.unwrap().used, (8 * GB) / 3 + 1);
+
+        // 模板整个缺少 system 时同样取之前的样本
+        let merged = average_bucket(vec![sample(1000, Some(newer.clone())), sample(2000, None)]);
+        assert_eq!(merged.unwrap().system.unwrap().network, newer.network);
+        let merged = average_bucket(vec![sample(1000, None), sample(2000, None)]);
+        assert!(merged.unwrap().system.is_none());
+    }
+
+    fn bucket_values(
+        points: &[(i64, f64)],
+        start: i64,
+        bucket_ms: i64,
+        aggregation: Aggregation,
+    ) -> Vec<(i64, f64)> {
+        let mut folder = BucketFolder::new(start, bucket_ms, aggregation);
+        for &(ts, value) in points {
+            folder.push(ts, value);
+        }
+        folder.finish()
+    }
+
+    #[test]
+    fn test_bucket_folder_week_of_1hz_data_holds_one_state_per_bucket() {
+        const WEEK_SECS: i64 = 7 * 24 * 3600;
+        const HOUR_MS: i64 = 3_600_000;
+
+        let mut folder = BucketFolder::new(0, HOUR_MS, Aggregation::Avg);
+        let mut peak_buckets = 0;
+        for i in 0..WEEK_SECS {
+            folder.push(i * 1000, (i % 100) as f64);
+            peak_buckets = peak_buckets.max(folder.bucket_count());
+        }
+
+        // 60 万个点只对应 168 个固定大小的桶状态
+        assert_eq!(peak_buckets, 168);
+        let buckets = folder.finish();
+        assert_eq!(buckets.len(), 168);
+        assert_eq!(buckets[0], (0, 49.5));
+        assert_eq!(buckets[167].0, 167 * HOUR_MS);
+    }
+
+    #[test]
+    fn test_bucket_values_aggregations() {
+        let points: Vec<(i64, f64)> = (0..10).map(|i| (i * 1000, i as f64)).collect();
+
+        assert_eq!(
+            bucket_values(&points, 0, 5000, Aggregation::Avg),
+            vec![(0, 2.0), (5000, 7.0)]
+        );
+        assert_eq!(
+            bucket_values(&points, 2000, 5000, Aggregation::Max),
+            vec![(2000, 6.0), (7000, 9.0)]
+        );
+        assert_eq!(
+            bucket_values(&points, 0, 4000, Aggregation::Count),
+            vec![(0, 4.0), (4000, 4.0), (8000, 2.0)]
+        );
+        assert_eq!(
+            bucket_values(&points, 0, 10_000, Aggregation::Last),
+            vec![(0, 9.0)]
+        );
+
+        // 乱序到达时 last 仍取时间戳最大的点
+        assert_eq!(
+            bucket_values(&[(2000, 2.0), (1000, 1.0)], 0, 10_000, Aggregation::Last),
+            vec![(0, 2.0)]
+        );
+
+        assert_eq!(Aggregation::Min.apply(&[]), None);
+        assert_eq!(Aggregation::Avg.combine(&[1.0, 3.0]), Some(2.0));
+        assert_eq!(Aggregation::Last.combine(&[1.0, 3.0]), Some(4.0));
+    }
+}
@@ -1,30 +1,183 @@
 use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
+    body::{Body, Bytes},
+    extract::{
+        rejection::JsonRejection,
+        ws::{
+            rejection::WebSocketUpgradeRejection, Message as WsMessage, WebSocket, WebSocketUpgrade,
+        },
+        Path, Query, State,
+    },
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
     response::{
         sse::{Event, Sse},
-        IntoResponse,
+        IntoResponse, Response,
     },
-    routing::get,
+    routing::{get, post},
     Json, Router,
 };
-use futures::stream::{self, Stream};
+use futures::stream::{Stream, StreamExt};
+use prost::Message;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
 use std::convert::Infallible;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
 use tower_http::cors::{Any, CorsLayer};
-use tracing::info;
+use tracing::{debug, info, warn};
 
+use crate::allowlist::AgentAllowlist;
+use crate::analytics::{self, Aggregation, BucketFolder, MetricField};
 use crate::assets::{serve_asset, serve_index, serve_spa};
-use crate::storage::Storage;
-use common::proto::MetricsRequest;
+use crate::cadence::CadenceTracker;
+use crate::disconnect::{DisconnectTracker, StreamDisconnect};
+use crate::duplicates::DuplicateDetector;
+use crate::events::MetricsEvent;
+use crate::exposition::{self, CSV_HEADER};
+use crate::grafana;
+use crate::health::{self, HealthScore, HealthWeights};
+use crate::lag::{IngestLag, LagTracker};
+use crate::maintenance::MaintenanceWindows;
+use crate::sequence::SequenceTracker;
+use crate::stats::{IngestSnapshot, IngestStats};
+use crate::storage::{
+    rollup::HOURLY_FIELDS, AgentEvent, CompactReport, HistoryConsistency, HostnameChange, Storage,
+    HISTORY_FLUSH_TIMEOUT,
+};
+use crate::timezone::TimeZone;
+use crate::trace;
+use common::proto::{MetricsRequest, SystemInfo};
+use common::schema::{self, FieldSchema};
+use common::utils::{current_timestamp_ms, format_bytes, format_duration_ms, ByteUnits};
+
+/// Protobuf 响应的媒体类型
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// 仅实时模式下所读取的历史已有样本被缓存淘汰时，读取历史的响应都带 `X-History-Truncated: true`
+///
+/// Protobuf、导出等没有 JSON 外壳的响应也能据此判断数据不完整
+pub const HISTORY_TRUNCATED_HEADER: &str = "x-history-truncated";
+
+/// 历史查询 limit 上限默认值
+pub const DEFAULT_MAX_HISTORY_LIMIT: usize = 1000;
+
+/// 每个 SSE 订阅者独立缓冲的事件数默认值
+pub const DEFAULT_SSE_CLIENT_BUFFER: usize = 256;
+
+/// 单个 HTTP 请求的处理时限默认值
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 最新样本距今超过该时长（毫秒）的 Agent 视为离线，与前端判断一致
+const AGENT_OFFLINE_AFTER_MS: i64 = 10_000;
+
+/// 上报间隔较长的 Agent 连续错过这么多次上报才视为离线
+const AGENT_OFFLINE_AFTER_INTERVALS: i64 = 3;
+
+/// Agent 列表中每个 Agent 附带的 sparkline 取值数上限，避免列表响应过大
+pub const MAX_LIST_SPARKLINE_POINTS: usize = 120;
+
+/// 指标查询未指定 `from` 时的默认时间范围（毫秒）
+const DEFAULT_QUERY_RANGE_MS: i64 = 3_600_000;
+
+/// HTTP API 配置
+#[derive(Debug, Clone)]
+pub struct ApiConfig {
+    /// 单次历史查询允许的最大条数，超出部分在查询存储前截断
+    pub max_history_limit: usize,
+    /// 每个 SSE / WebSocket 订阅者的事件缓冲：订阅者消费过慢填满后只对其发送 resync 快照
+    pub sse_client_buffer: usize,
+    /// 健康评分中各维度的权重
+    pub health_weights: HealthWeights,
+    /// 是否提供 SSE / WebSocket 实时推送，关闭时两者返回 404
+    pub broadcast_enabled: bool,
+    /// 历史查询未指定 `consistency` 时使用的一致性模式
+    pub history_consistency: HistoryConsistency,
+    /// 单个请求的处理时限，超出时取消处理并返回 504；为零时不限制。SSE / WebSocket 不受限制
+    pub request_timeout: Duration,
+    /// 管理接口（`POST /api/admin/agents`）的访问令牌，请求须带 `Authorization: Bearer <令牌>`；
+    /// None 时该接口关闭，返回 403
+    pub admin_token: Option<String>,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            max_history_limit: DEFAULT_MAX_HISTORY_LIMIT,
+            sse_client_buffer: DEFAULT_SSE_CLIENT_BUFFER,
+            health_weights: HealthWeights::default(),
+            broadcast_enabled: true,
+            history_consistency: HistoryConsistency::default(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            admin_token: None,
+        }
+    }
+}
+
+impl ApiConfig {
+    /// 校验管理接口的 `Authorization: Bearer` 令牌：未配置令牌时返回 403，令牌缺失或不符时返回 401
+    fn authorize_admin(&self, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+        let Some(expected) = self.admin_token.as_deref() else {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "未配置管理令牌（--admin-token），管理接口已关闭".to_string(),
+            ));
+        };
+        let provided = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim);
+        // 逐字节比较全部内容，耗时不随首个不同字节的位置变化
+        let matches = provided.is_some_and(|provided| {
+            provided.len() == expected.len()
+                && provided
+                    .bytes()
+                    .zip(expected.bytes())
+                    .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        });
+        if matches {
+            Ok(())
+        } else {
+            Err((StatusCode::UNAUTHORIZED, "缺少或错误的管理令牌".to_string()))
+        }
+    }
+
+    /// 将请求的 limit 限制在上限内，发生截断时返回提示信息
+    fn clamp_limit(&self, requested: usize) -> (usize, Option<String>) {
+        if requested > self.max_history_limit {
+            (
+                self.max_history_limit,
+                Some(format!(
+                    "limit {} 超过上限，已调整为 {}",
+                    requested, self.max_history_limit
+                )),
+            )
+        } else {
+            (requested, None)
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct ApiState {
     pub storage: std::sync::Arc<Storage>,
-    pub broadcast: broadcast::Sender<MetricsRequest>,
+    pub broadcast: broadcast::Sender<MetricsEvent>,
+    pub stats: Arc<IngestStats>,
+    pub duplicates: Arc<DuplicateDetector>,
+    pub sequences: Arc<SequenceTracker>,
+    pub cadences: Arc<CadenceTracker>,
+    pub lags: Arc<LagTracker>,
+    pub allowlist: Arc<AgentAllowlist>,
+    pub maintenance: Arc<MaintenanceWindows>,
+    pub disconnects: Arc<DisconnectTracker>,
+    pub config: ApiConfig,
+    /// Server 关闭通知，收到后结束 SSE / WebSocket 订阅，让 HTTP 服务能在预算内排空
+    pub shutdown: watch::Receiver<bool>,
 }
 
 /// Agent 信息响应
@@ -33,6 +186,234 @@ pub struct AgentInfo {
     pub agent_id: String,
     pub last_seen: i64,
     pub hostname: String,
+    /// 近期有多台不同机器使用该 agent_id 上报
+    pub duplicate_agent_id: bool,
+    /// 按样本序号跳跃估计的、Server 启动以来丢失的样本数
+    pub dropped_estimate: u64,
+    /// Agent 最近一次错误信息（未出错或旧版 Agent 为 null）
+    pub last_error: Option<String>,
+    /// Agent 重新连接 Server 的次数（最新样本不带探针自身指标时为 null）
+    pub reconnect_count: Option<u64>,
+    /// 按最近相邻样本间隔的中位数估计的上报间隔（Server 启动后样本太少时为 null）
+    pub expected_interval_ms: Option<i64>,
+    /// Server 启动以来该 Agent 最近一次流式连接断开的方式与原因（没有断开过时为 null）
+    pub last_disconnect: Option<StreamDisconnect>,
+    /// 样本到达 Server 的时间减去样本时间戳：正值表示回填或网络缓慢，负值表示时钟超前
+    /// （Server 启动以来没有收到样本时为 null）
+    pub ingest_lag: Option<IngestLag>,
+    /// 维护模式的截止时间（毫秒），期间不触发新告警（不在维护中时为 null）
+    pub maintenance_until: Option<i64>,
+    /// 最近若干条样本的 CPU 使用率（按时间升序），仅在 `include=sparkline` 时返回
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sparkline: Option<Vec<f64>>,
+}
+
+/// Agent 静态信息响应：最新样本中的系统信息与标签，不含变化频繁的指标
+#[derive(Serialize)]
+pub struct AgentStaticInfo {
+    pub agent_id: String,
+    pub last_seen: i64,
+    pub hostname: String,
+    /// 最新样本不带系统信息时为 null
+    pub system_info: Option<SystemInfo>,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Agent 最新样本中有数据的指标分区，供界面只渲染相关面板
+///
+/// 采集器可单独开关、各平台支持的指标也不同，因此以最新样本为准逐项判断
+#[derive(Debug, Default, Serialize)]
+pub struct AvailableFields {
+    pub agent_id: String,
+    /// 参与判断的样本时间戳
+    pub timestamp: i64,
+    pub cpu: bool,
+    pub memory: bool,
+    /// 上报的挂载点（按样本中的顺序），没有磁盘数据时为空
+    pub disks: Vec<String>,
+    /// 网络计数器（Agent 上报的是各网卡合计，不区分接口）
+    pub network: bool,
+    pub pressure: bool,
+    pub file_descriptors: bool,
+    pub entropy: bool,
+    /// 各 NUMA 节点内存（非 Linux 不上报）
+    pub numa: bool,
+    pub systemd: bool,
+    /// Docker 容器统计（需 Agent 开启 `--docker`）
+    pub containers: bool,
+    /// CPU 使用率最高的进程（需 Agent 开启 `--top-processes`）
+    pub processes: bool,
+    /// 上报的 GPU 设备序号，没有 GPU 数据时为空
+    pub gpu: Vec<u32>,
+    /// 温度读数（CPU 各核心温度与 GPU 温度）
+    pub thermal: bool,
+    pub tcp_ping: bool,
+    pub system_info: bool,
+    pub agent_metrics: bool,
+}
+
+impl AvailableFields {
+    fn of(metrics: &MetricsRequest) -> Self {
+        let mut fields = Self {
+            agent_id: metrics.agent_id.clone(),
+            timestamp: metrics.timestamp,
+            ..Default::default()
+        };
+        let Some(system) = &metrics.system else {
+            return fields;
+        };
+        fields.cpu = system.cpu.is_some();
+        fields.memory = system.memory.is_some();
+        fields.disks = system
+            .disks
+            .iter()
+            .map(|disk| disk.mount_point.clone())
+            .collect();
+        fields.network = system.network.is_some();
+        fields.pressure = system.pressure.is_some();
+        fields.file_descriptors = system.file_descriptors.is_some();
+        fields.entropy = system.entropy.is_some();
+        fields.numa = !system.numa_nodes.is_empty();
+        fields.systemd = system.systemd.is_some();
+        fields.containers = system.containers.is_some();
+        fields.processes = !system.top_processes.is_empty();
+        fields.gpu = system.gpu.iter().map(|gpu| gpu.index).collect();
+        let core_temperatures = system
+            .cpu
+            .as_ref()
+            .is_some_and(|cpu| cpu.per_core_temperature.iter().any(|t| *t > 0.0));
+        fields.thermal = core_temperatures || system.gpu.iter().any(|gpu| gpu.temperature > 0.0);
+        fields.tcp_ping = !system.tcp_ping.is_empty();
+        fields.system_info = system.system_info.is_some();
+        fields.agent_metrics = system.agent_metrics.is_some();
+        fields
+    }
+}
+
+/// Agent 健康评分响应
+#[derive(Serialize)]
+pub struct AgentHealth {
+    pub agent_id: String,
+    /// 参与评分的最新样本时间戳
+    pub timestamp: i64,
+    #[serde(flatten)]
+    pub health: HealthScore,
+}
+
+/// Agent 列表查询参数
+#[derive(Deserialize)]
+pub struct AgentListQuery {
+    /// 按最新样本中的字段排序，缺省时按 agent_id 排列
+    pub sort: Option<AgentSort>,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// 仅返回指定状态的 Agent
+    pub status: Option<AgentStatus>,
+    /// 额外返回的内容，逗号分隔；目前仅支持 `sparkline`
+    pub include: Option<String>,
+    /// `include=sparkline` 时每个 Agent 返回的 CPU 取值数，上限 `MAX_LIST_SPARKLINE_POINTS`
+    #[serde(default = "default_list_sparkline_points")]
+    pub sparkline_points: usize,
+}
+
+fn default_list_sparkline_points() -> usize {
+    30
+}
+
+impl AgentListQuery {
+    /// 是否要求附带 sparkline，`include` 含未知项时返回错误
+    fn include_sparkline(&self) -> Result<bool, String> {
+        let mut sparkline = false;
+        for item in self
+            .include
+            .iter()
+            .flat_map(|include| include.split(','))
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            match item {
+                "sparkline" => sparkline = true,
+                other => return Err(format!("未知的 include 项: {}（可选 sparkline）", other)),
+            }
+        }
+        Ok(sparkline)
+    }
+}
+
+/// Agent 列表排序字段
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentSort {
+    /// CPU 使用率
+    Cpu,
+    /// 内存使用率
+    Memory,
+    /// 最后上报时间
+    LastSeen,
+}
+
+impl AgentSort {
+    /// 从最新样本取排序键，样本缺少该字段时为 None
+    fn key(self, latest: &MetricsRequest) -> Option<f64> {
+        let system = latest.system.as_ref();
+        match self {
+            Self::Cpu => system?.cpu.as_ref().map(|cpu| cpu.usage_percent),
+            Self::Memory => system?.memory.as_ref().map(|memory| memory.usage_percent),
+            Self::LastSeen => Some(latest.timestamp as f64),
+        }
+    }
+}
+
+/// 排序方向，默认降序
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Agent 在线状态
+///
+/// 最新样本距今超过 `AGENT_OFFLINE_AFTER_MS` 与 `AGENT_OFFLINE_AFTER_INTERVALS` 个预期上报间隔
+/// 中较大者时视为离线，上报间隔较长的 Agent 不会在两次上报之间被误判
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentStatus {
+    Online,
+    Offline,
+}
+
+/// 距最近一次上报超过多少毫秒视为离线，见 [`AgentStatus`]
+pub(crate) fn offline_after_ms(expected_interval_ms: Option<i64>) -> i64 {
+    expected_interval_ms.map_or(AGENT_OFFLINE_AFTER_MS, |interval| {
+        interval
+            .saturating_mul(AGENT_OFFLINE_AFTER_INTERVALS)
+            .max(AGENT_OFFLINE_AFTER_MS)
+    })
+}
+
+impl AgentStatus {
+    fn of(last_seen: i64, now: i64, expected_interval_ms: Option<i64>) -> Self {
+        if now - last_seen > offline_after_ms(expected_interval_ms) {
+            Self::Offline
+        } else {
+            Self::Online
+        }
+    }
+}
+
+/// 按排序键排序，缺少排序键的条目无论升降序都排在最后；键相同时保持原有顺序
+fn sort_missing_last<T>(items: &mut [(T, Option<f64>)], order: SortOrder) {
+    items.sort_by(|(_, a), (_, b)| match (a, b) {
+        (Some(a), Some(b)) => match order {
+            SortOrder::Asc => a.total_cmp(b),
+            SortOrder::Desc => b.total_cmp(a),
+        },
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
 }
 
 /// 指标历史查询参数
@@ -40,14 +421,282 @@ pub struct AgentInfo {
 pub struct HistoryQuery {
     #[serde(default = "default_limit")]
     pub limit: usize,
+    /// 将时间范围内的样本按时间重采样为约这么多个点（每点取桶内平均），缺省时返回原始样本。
+    /// 指定时按 `start`/`end` 读取完整范围，不受 `limit` 截断
+    pub points: Option<usize>,
+    /// 重采样范围起点（毫秒，含），缺省为最早的样本
+    pub start: Option<i64>,
+    /// 重采样范围终点（毫秒，含），缺省为最新的样本
+    pub end: Option<i64>,
+    /// 一致性模式，缺省时使用 Server 配置（`--history-consistency`）
+    pub consistency: Option<HistoryConsistency>,
 }
 
 fn default_limit() -> usize {
     100
 }
 
-fn max_history_limit() -> usize {
-    1000
+/// SSE 订阅参数
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    /// 仅推送指定 Agent 的指标；缺省时推送全部
+    pub agent: Option<String>,
+}
+
+/// Sparkline 查询参数
+#[derive(Deserialize)]
+pub struct SparklineQuery {
+    /// 取值的指标
+    pub field: MetricField,
+    /// 返回的点数
+    #[serde(default = "default_sparkline_points")]
+    pub points: usize,
+    /// 参与重采样的最近样本数
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+fn default_sparkline_points() -> usize {
+    60
+}
+
+/// Sparkline 响应：单个指标的精简数值序列
+#[derive(Debug, Serialize)]
+pub struct Sparkline {
+    /// 按时间升序的取值，缺少该字段的样本不计入
+    pub values: Vec<f64>,
+    /// 最后一个值对应的样本时间戳，没有取值时为 null
+    pub last_ts: Option<i64>,
+}
+
+/// 磁盘写满预测查询参数
+#[derive(Deserialize)]
+pub struct ForecastQuery {
+    /// 参与趋势拟合的最近样本数
+    #[serde(default = "default_forecast_limit")]
+    pub limit: usize,
+}
+
+fn default_forecast_limit() -> usize {
+    360
+}
+
+/// Agent 登记请求体（`POST /api/admin/agents`）
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegisterAgentRequest {
+    pub agent_id: String,
+}
+
+/// 维护模式请求体（`POST /api/agents/:id/maintenance`）
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceRequest {
+    /// 维护时长（秒），从收到请求时起算；为 0 时立即结束维护
+    pub duration_secs: u64,
+}
+
+/// 可用指标分区查询参数
+#[derive(Deserialize)]
+pub struct AvailableFieldsQuery {
+    /// 判断的样本时间戳（毫秒），缺省时取最新样本
+    pub timestamp: Option<i64>,
+}
+
+/// 事件时间线查询参数，时间范围为闭区间（毫秒），缺省时不限
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+}
+
+/// 指标查询请求体（`POST /api/query`），出现未知字段时拒绝
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricQuery {
+    /// 查询的 Agent，缺省或为空时查询全部
+    #[serde(default)]
+    pub agents: Vec<String>,
+    /// 取值的指标
+    pub field: MetricField,
+    /// 起始时间（毫秒，含），缺省为 `to` 前一小时
+    pub from: Option<i64>,
+    /// 结束时间（毫秒，含），缺省为当前时间
+    pub to: Option<i64>,
+    /// 桶内聚合方式，缺省为 avg；只指定聚合不指定 `bucket_ms` 时整个时间范围为一个桶
+    pub aggregation: Option<Aggregation>,
+    /// 时间桶宽度（毫秒），与 `aggregation` 都缺省时返回原始取值
+    pub bucket_ms: Option<i64>,
+    /// 把所有 Agent 合并为一条序列，需要分桶或聚合
+    #[serde(default)]
+    pub combine: bool,
+    /// 数据来源的分辨率，缺省为原始样本
+    #[serde(default)]
+    pub resolution: Resolution,
+    /// 桶边界对齐的时区（IANA 时区名或 `+08:00` 形式的固定偏移），需要 `bucket_ms`；
+    /// 缺省时桶从 `from` 起算
+    pub tz: Option<String>,
+}
+
+/// 指标查询的数据分辨率
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    /// 原始样本，受保留策略清理
+    #[default]
+    Raw,
+    /// 长期小时汇总（小时均值），不受保留策略清理，仅支持 cpu/memory/disk
+    Hour,
+}
+
+/// 校验后的查询计划
+#[derive(Debug, PartialEq)]
+pub(crate) struct QueryPlan {
+    pub from: i64,
+    pub to: i64,
+    /// (桶宽毫秒, 聚合方式)，None 表示返回原始取值
+    pub buckets: Option<(i64, Aggregation)>,
+    /// 桶边界按该时区的本地时钟对齐
+    pub time_zone: Option<TimeZone>,
+}
+
+impl MetricQuery {
+    /// 补全默认值并校验参数组合，桶数不能超过 `max_points`；`time_zone` 为已解析的 `tz`
+    fn plan(
+        &self,
+        now: i64,
+        max_points: usize,
+        time_zone: Option<TimeZone>,
+    ) -> Result<QueryPlan, String> {
+        let to = self.to.unwrap_or(now);
+        let from = self
+            .from
+            .unwrap_or_else(|| to.saturating_sub(DEFAULT_QUERY_RANGE_MS));
+        if from > to {
+            return Err(format!("from ({}) 晚于 to ({})", from, to));
+        }
+        if self.bucket_ms.is_some_and(|bucket_ms| bucket_ms <= 0) {
+            return Err("bucket_ms 必须为正数".to_string());
+        }
+        if self.tz.is_some() && self.bucket_ms.is_none() {
+            return Err("tz 需要同时指定 bucket_ms".to_string());
+        }
+        if self.resolution == Resolution::Hour && !HOURLY_FIELDS.contains(&self.field) {
+            return Err(format!(
+                "resolution=hour 仅支持 cpu、memory、disk，不支持 {:?}",
+                self.field
+            ));
+        }
+
+        let buckets = match (self.bucket_ms, self.aggregation) {
+            (None, None) if self.combine => {
+                return Err("combine 需要同时指定 bucket_ms 或 aggregation".to_string());
+            }
+            (None, None) => None,
+            (bucket_ms, aggregation) => {
+                let span = to.saturating_sub(from).saturating_add(1);
+                let bucket_ms = bucket_ms.unwrap_or(span);
+                let count = (span - 1) / bucket_ms + 1;
+                if count > max_points as i64 {
+                    return Err(format!(
+                        "时间范围内有 {} 个桶，超过上限 {}，请增大 bucket_ms",
+                        count, max_points
+                    ));
+                }
+                Some((bucket_ms, aggregation.unwrap_or_default()))
+            }
+        };
+
+        Ok(QueryPlan {
+            from,
+            to,
+            buckets,
+            time_zone,
+        })
+    }
+}
+
+/// 指标查询结果
+#[derive(Debug, Serialize)]
+pub struct QueryResult {
+    pub series: Vec<QuerySeries>,
+}
+
+/// 查询结果中的一条时间序列
+#[derive(Debug, Serialize)]
+pub struct QuerySeries {
+    /// 所属 Agent，合并查询时为 null
+    pub agent_id: Option<String>,
+    /// 按时间升序的点；分桶时 `ts` 为桶起点
+    pub points: Vec<QueryPoint>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryPoint {
+    pub ts: i64,
+    pub value: f64,
+}
+
+/// 多 Agent 对比请求体（`POST /api/compare`），出现未知字段时拒绝
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompareQuery {
+    /// 参与对比的 Agent，不能为空
+    pub agents: Vec<String>,
+    /// 取值的指标
+    pub field: MetricField,
+    /// 起始时间（毫秒，含），缺省为 `to` 前一小时
+    pub from: Option<i64>,
+    /// 结束时间（毫秒，含），缺省为当前时间
+    pub to: Option<i64>,
+    /// 期望的时间轴间隔（毫秒）；小于最慢 Agent 的上报间隔时按后者对齐
+    pub bucket_ms: Option<i64>,
+}
+
+/// 多 Agent 对比结果：所有序列共用同一条时间轴
+#[derive(Debug, Serialize)]
+pub struct CompareResult {
+    /// 实际采用的时间轴间隔（毫秒）
+    pub bucket_ms: i64,
+    /// 共享的时间轴，从 `from` 起每 `bucket_ms` 一个点
+    pub timestamps: Vec<i64>,
+    /// 按请求顺序，每个 Agent 一条
+    pub series: Vec<CompareSeries>,
+}
+
+/// 对齐到共享时间轴的单个 Agent 序列
+#[derive(Debug, Serialize)]
+pub struct CompareSeries {
+    pub agent_id: String,
+    /// 该 Agent 在时间范围内的上报间隔（相邻样本时间差的中位数，毫秒），样本不足时为 null
+    pub interval_ms: Option<i64>,
+    /// 与 `timestamps` 一一对应的插值，范围外或断线空档内为 null
+    pub values: Vec<Option<f64>>,
+}
+
+/// Server 构建信息（由 build.rs 在编译时生成）
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    /// crate 版本
+    pub version: &'static str,
+    /// 构建所在的 git 提交（短哈希），不在 git 仓库中构建时为 `unknown`
+    pub git_hash: &'static str,
+    /// 构建时间（Unix 秒）
+    pub build_timestamp: u64,
+    /// 编译器版本，如 `rustc 1.83.0 (90b35a623 2024-11-26)`
+    pub rustc_version: &'static str,
+}
+
+impl BuildInfo {
+    /// 当前二进制的构建信息
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: env!("GIT_HASH"),
+            build_timestamp: env!("BUILD_TIMESTAMP").parse().unwrap_or_default(),
+            rustc_version: env!("RUSTC_VERSION"),
+        }
+    }
 }
 
 /// API 响应包装
@@ -56,6 +705,9 @@ pub struct ApiResponse<T> {
     pub success: bool,
     pub data: Option<T>,
     pub message: Option<String>,
+    /// 数据因仅实时模式的缓存上限而不完整，只在为 true 时出现
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
 }
 
 impl<T: Serialize> ApiResponse<T> {
@@ -64,26 +716,34 @@ impl<T: Serialize> ApiResponse<T> {
             success: true,
             data: Some(data),
             message: None,
+            truncated: false,
         }
     }
 
-    #[allow(dead_code)]
+    /// 成功响应并附带提示信息
+    pub fn with_message(mut self, message: Option<String>) -> Self {
+        self.message = message;
+        self
+    }
+
+    /// 标记数据是否被截断
+    pub fn with_truncated(mut self, truncated: bool) -> Self {
+        self.truncated = truncated;
+        self
+    }
+
     pub fn error(message: String) -> ApiResponse<()> {
         ApiResponse {
             success: false,
             data: None,
             message: Some(message),
+            truncated: false,
         }
     }
 }
 
 /// 创建 HTTP API 路由
-pub fn create_router(
-    storage: std::sync::Arc<Storage>,
-    broadcast: broadcast::Sender<MetricsRequest>,
-) -> Router {
-    let state = ApiState { storage, broadcast };
-
+pub fn create_router(state: ApiState) -> Router {
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
@@ -91,112 +751,3557 @@ pub fn create_router(
 
     info!("Web UI: 使用嵌入静态资源");
 
-    Router::new()
-        .route("/api", get(root))
+    // 探活/就绪检查供编排系统使用，不经过业务层中间件
+    let probes = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz));
+
+    // 实时推送是长连接，不受请求处理时限约束
+    let streams = Router::new()
         .route("/api/stream", get(sse_handler))
+        .route("/api/ws", get(ws_handler));
+
+    let timeout = state.config.request_timeout;
+    let api = Router::new()
+        .route("/api", get(root))
+        .route("/api/version", get(get_version))
+        .route("/api/schema", get(get_schema))
         .route("/api/agents", get(list_agents))
+        .route("/api/metrics/recent", get(get_recent_metrics))
         .route("/api/agents/:id/metrics", get(get_agent_metrics))
         .route("/api/agents/:id/metrics/history", get(get_agent_history))
+        .route(
+            "/api/agents/:id/metrics/history.ndjson",
+            get(export_agent_history),
+        )
+        .route(
+            "/api/agents/:id/metrics/history.csv",
+            get(export_agent_history_csv),
+        )
+        .route(
+            "/api/agents/:id/metrics/history.influx",
+            get(export_agent_history_influx),
+        )
+        .route("/api/agents/:id/sparkline", get(get_sparkline))
+        .route("/api/agents/:id/disks/forecast", get(get_disk_forecast))
+        .route("/api/agents/:id/hostnames", get(get_hostname_history))
+        .route("/api/agents/:id/events", get(get_agent_events))
+        .route("/api/agents/:id/maintenance", post(set_maintenance))
+        .route("/api/agents/:id/info", get(get_agent_info))
+        .route("/api/agents/:id/health", get(get_agent_health))
+        .route(
+            "/api/agents/:id/available-fields",
+            get(get_available_fields),
+        )
+        .route("/api/query", post(query_metrics))
+        .route("/api/compare", post(compare_agents))
+        .route("/api/admin/ingest-stats", get(get_ingest_stats))
+        .route("/api/admin/compact", post(compact_database))
+        .route("/api/admin/agents", post(register_agent))
+        .route("/metrics", get(prometheus_metrics))
+        .route("/grafana", get(grafana::test_connection))
+        .route("/grafana/search", post(grafana::search))
+        .route("/grafana/query", post(grafana::query))
         .route("/assets/*path", get(serve_asset))
         .route("/", get(serve_index))
         .route("/*path", get(serve_spa))
+        .layer(middleware::from_fn(move |request, next| {
+            request_timeout(timeout, request, next)
+        }))
+        .merge(streams)
         .layer(cors)
-        .with_state(Arc::new(state))
+        .layer(middleware::from_fn(trace::http_request_id));
+
+    probes.merge(api).with_state(Arc::new(state))
+}
+
+/// 限制请求处理时长：超时后丢弃处理中的 future 并返回 504。持久化层的阻塞扫描随之收到取消
+/// 标记，在读完当前一批记录后停止并释放读事务
+///
+/// 只约束生成响应头之前的处理，已开始发送的流式响应体（如 NDJSON 导出）不受影响
+async fn request_timeout(
+    timeout: Duration,
+    request: axum::extract::Request,
+    next: middleware::Next,
+) -> Response {
+    if timeout.is_zero() {
+        return next.run(request).await;
+    }
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!("API: {} {} 处理超过 {:?}，已取消", method, uri, timeout);
+            (
+                StatusCode::GATEWAY_TIMEOUT,
+                Json(ApiResponse::<()>::error(format!(
+                    "请求处理超过 {:?}，已取消",
+                    timeout
+                ))),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// 历史类接口先确认 Agent 存在：agent_id 写错时返回 404，已知 Agent 在范围内没有数据时返回空列表
+async fn ensure_agent_known(storage: &Storage, agent_id: &str) -> Result<(), StatusCode> {
+    if storage.get_agent_latest(agent_id).await.is_some() {
+        return Ok(());
+    }
+    info!("API: Agent {} 不存在", agent_id);
+    Err(StatusCode::NOT_FOUND)
+}
+
+/// 获取指定 Agent 的主机名变更历史
+async fn get_hostname_history(
+    State(state): State<Arc<ApiState>>,
+    Path(agent_id): Path<String>,
+) -> Result<Json<ApiResponse<Vec<HostnameChange>>>, StatusCode> {
+    ensure_agent_known(&state.storage, &agent_id).await?;
+    let history = state.storage.get_hostname_history(&agent_id).await;
+
+    info!("API: 返回 {} 的 {} 条主机名记录", agent_id, history.len());
+    Ok(Json(ApiResponse::ok(history)))
+}
+
+/// 获取指定 Agent 的事件时间线（重启、上下线、主机名变更、告警），按时间升序
+///
+/// start 晚于 end 时返回 400；范围内没有事件时返回空列表
+async fn get_agent_events(
+    State(state): State<Arc<ApiState>>,
+    Path(agent_id): Path<String>,
+    Query(query): Query<EventsQuery>,
+) -> Result<Json<ApiResponse<Vec<AgentEvent>>>, (StatusCode, Json<ApiResponse<()>>)> {
+    let start = query.start.unwrap_or(0);
+    let end = query.end.unwrap_or(i64::MAX);
+    if start > end {
+        info!(
+            "API: 拒绝无效事件查询: start ({}) 晚于 end ({})",
+            start, end
+        );
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse::<()>::error(format!(
+                "start ({}) 晚于 end ({})",
+                start, end
+            ))),
+        ));
+    }
+
+    ensure_agent_known(&state.storage, &agent_id)
+        .await
+        .map_err(|status| {
+            (
+                status,
+                Json(ApiResponse::<()>::error(format!(
+                    "Agent {} 不存在",
+                    agent_id
+                ))),
+            )
+        })?;
+    let events = state.storage.get_events(&agent_id, start, end).await;
+    info!("API: 返回 {} 的 {} 条事件", agent_id, events.len());
+    Ok(Json(ApiResponse::ok(events)))
+}
+
+/// 获取指定 Agent 的静态信息（系统信息、标签与最后上报时间）
+async fn get_agent_info(
+    State(state): State<Arc<ApiState>>,
+    Path(agent_id): Path<String>,
+) -> Result<Json<ApiResponse<AgentStaticInfo>>, StatusCode> {
+    let Some(latest) = state.storage.get_agent_latest(&agent_id).await else {
+        info!("API: Agent {} 不存在", agent_id);
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    info!("API: 返回 {} 的静态信息", agent_id);
+    Ok(Json(ApiResponse::ok(AgentStaticInfo {
+        agent_id,
+        last_seen: latest.timestamp,
+        hostname: latest.hostname,
+        system_info: latest.system.and_then(|system| system.system_info),
+        labels: latest.labels.into_iter().collect(),
+    })))
+}
+
+/// 按最新样本计算 Agent 的健康评分，附带各维度的贡献
+async fn get_agent_health(
+    State(state): State<Arc<ApiState>>,
+    Path(agent_id): Path<String>,
+) -> Result<Json<ApiResponse<AgentHealth>>, StatusCode> {
+    let Some(latest) = state.storage.get_agent_latest(&agent_id).await else {
+        info!("API: Agent {} 不存在", agent_id);
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let health = health::health_score(&latest, &state.config.health_weights);
+    info!("API: 返回 {} 的健康评分 {:?}", agent_id, health.score);
+    Ok(Json(ApiResponse::ok(AgentHealth {
+        agent_id,
+        timestamp: latest.timestamp,
+        health,
+    })))
+}
+
+/// 按最新样本列出 Agent 有数据的指标分区
+async fn get_available_fields(
+    State(state): State<Arc<ApiState>>,
+    Path(agent_id): Path<String>,
+    Query(query): Query<AvailableFieldsQuery>,
+) -> Result<Json<ApiResponse<AvailableFields>>, StatusCode> {
+    let sample = match query.timestamp {
+        Some(timestamp) => {
+            ensure_agent_known(&state.storage, &agent_id).await?;
+            state
+                .storage
+                .get_agent_range(&agent_id, timestamp, timestamp)
+                .await
+                .pop()
+        }
+        None => state.storage.get_agent_latest(&agent_id).await,
+    };
+    let Some(sample) = sample else {
+        info!("API: Agent {} 没有对应的样本", agent_id);
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    info!("API: 返回 {} 的可用指标分区", agent_id);
+    Ok(Json(ApiResponse::ok(AvailableFields::of(&sample))))
+}
+
+/// 存活检查：进程能响应即返回 200
+async fn healthz() -> impl IntoResponse {
+    (StatusCode::OK, "ok")
+}
+
+/// 就绪检查：存储已初始化、持久化可写且未处于关闭流程时返回 200，否则 503
+async fn readyz(State(state): State<Arc<ApiState>>) -> impl IntoResponse {
+    if state.storage.is_ready().await {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+/// Server 自身的样本接收统计
+async fn get_ingest_stats(State(state): State<Arc<ApiState>>) -> Json<ApiResponse<IngestSnapshot>> {
+    let mut snapshot = state.stats.snapshot();
+    snapshot.cache_evictions = state.storage.cache_evictions().await;
+    Json(ApiResponse::ok(snapshot))
+}
+
+/// 压缩数据库文件，返回压缩前后的文件大小
+///
+/// 仅内存模式返回 404；等待独占数据库超时或压缩失败返回 503
+async fn compact_database(
+    State(state): State<Arc<ApiState>>,
+) -> Result<Json<ApiResponse<CompactReport>>, StatusCode> {
+    match state.storage.compact().await {
+        Ok(Some(report)) => {
+            info!(
+                "API: 数据库压缩完成，{} -> {}，耗时 {}",
+                format_bytes(report.before_bytes, ByteUnits::Iec),
+                format_bytes(report.after_bytes, ByteUnits::Iec),
+                format_duration_ms(report.elapsed_ms as i64)
+            );
+            Ok(Json(ApiResponse::ok(report)))
+        }
+        Ok(None) => {
+            info!("API: 仅内存模式，没有可压缩的数据库");
+            Err(StatusCode::NOT_FOUND)
+        }
+        Err(e) => {
+            warn!("API: 数据库压缩失败: {}", e);
+            Err(StatusCode::SERVICE_UNAVAILABLE)
+        }
+    }
+}
+
+/// 把 agent_id 登记到准入名单，返回登记后的 agent_id
+///
+/// 须带管理令牌：未配置令牌时返回 403，令牌缺失或不符时返回 401（见 `ApiConfig::admin_token`）。
+/// 未开启准入名单时返回 404；请求体无法解析或 agent_id 不合法时返回 400
+async fn register_agent(
+    State(state): State<Arc<ApiState>>,
+    headers: HeaderMap,
+    body: Result<Json<RegisterAgentRequest>, JsonRejection>,
+) -> Result<Json<ApiResponse<String>>, (StatusCode, Json<ApiResponse<()>>)> {
+    if let Err((status, message)) = state.config.authorize_admin(&headers) {
+        warn!("API: 拒绝 Agent 登记: {}", message);
+        return Err((status, Json(ApiResponse::<()>::error(message))));
+    }
+    if !state.allowlist.is_enabled() {
+        info!("API: 未开启准入名单，无需登记 Agent");
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(ApiResponse::<()>::error(
+                "未开启准入名单（--allowlist），无需登记".to_string(),
+            )),
+        ));
+    }
+    let bad_request = |message: String| {
+        info!("API: 拒绝 Agent 登记: {}", message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse::<()>::error(message)),
+        )
+    };
+    let Json(RegisterAgentRequest { mut agent_id }) =
+        body.map_err(|rejection| bad_request(rejection.body_text()))?;
+    crate::agent_id::normalize_agent_id(&mut agent_id).map_err(bad_request)?;
+
+    let message = if state.allowlist.register(agent_id.clone()) == Some(true) {
+        info!("API: Agent {} 已登记", agent_id);
+        None
+    } else {
+        Some(format!("Agent {} 已在准入名单中", agent_id))
+    };
+    Ok(Json(ApiResponse::ok(agent_id).with_message(message)))
+}
+
+/// 设置或结束 Agent 的维护模式，返回维护截止时间（结束维护时为 null）
+async fn set_maintenance(
+    State(state): State<Arc<ApiState>>,
+    Path(agent_id): Path<String>,
+    body: Result<Json<MaintenanceRequest>, JsonRejection>,
+) -> Result<Json<ApiResponse<Option<i64>>>, (StatusCode, Json<ApiResponse<()>>)> {
+    let Json(MaintenanceRequest { duration_secs }) = body.map_err(|rejection| {
+        info!("API: 拒绝维护模式设置: {}", rejection.body_text());
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse::<()>::error(rejection.body_text())),
+        )
+    })?;
+    if state.storage.get_agent_latest(&agent_id).await.is_none() {
+        info!("API: Agent {} 不存在，无法设置维护模式", agent_id);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(ApiResponse::<()>::error(format!(
+                "Agent {} 不存在",
+                agent_id
+            ))),
+        ));
+    }
+
+    let now = current_timestamp_ms();
+    if duration_secs == 0 {
+        let message = if state.maintenance.end(&agent_id, now) {
+            info!("API: Agent {} 结束维护模式", agent_id);
+            None
+        } else {
+            Some(format!("Agent {} 不在维护模式中", agent_id))
+        };
+        return Ok(Json(ApiResponse::ok(None).with_message(message)));
+    }
+
+    let duration_ms = i64::try_from(duration_secs)
+        .unwrap_or(i64::MAX)
+        .saturating_mul(1000);
+    let until = now.saturating_add(duration_ms);
+    state.maintenance.start(&agent_id, until);
+    info!(
+        "API: Agent {} 进入维护模式，持续 {}",
+        agent_id,
+        format_duration_ms(duration_ms)
+    );
+    Ok(Json(ApiResponse::ok(Some(until))))
+}
+
+/// 指标字段的单位与量纲描述（由 proto 定义生成）
+async fn get_schema() -> Json<ApiResponse<&'static [FieldSchema]>> {
+    Json(ApiResponse::ok(schema::metric_fields()))
+}
+
+/// Server 版本与构建信息
+async fn get_version() -> Json<ApiResponse<BuildInfo>> {
+    Json(ApiResponse::ok(BuildInfo::current()))
 }
 
 /// 根路径
 async fn root() -> impl IntoResponse {
     Json(serde_json::json!({
         "name": "Iris API",
-        "version": "0.1.0",
+        "version": env!("CARGO_PKG_VERSION"),
         "endpoints": [
-            "GET /api/stream (SSE)",
+            "GET /api/stream?agent=<id> (SSE)",
+            "GET /api/ws?agent=<id> (WebSocket)",
+            "GET /api/version",
+            "GET /api/schema",
             "GET /api/agents",
+            "GET /api/metrics/recent?limit=100",
             "GET /api/agents/:id/metrics",
-            "GET /api/agents/:id/metrics/history?limit=100"
+            "GET /api/agents/:id/metrics/history?limit=100&points=500&start=&end=",
+            "GET /api/agents/:id/metrics/history.ndjson",
+            "GET /api/agents/:id/metrics/history.csv",
+            "GET /api/agents/:id/metrics/history.influx",
+            "GET /api/agents/:id/sparkline?field=cpu&points=60",
+            "GET /api/agents/:id/disks/forecast?limit=360",
+            "GET /api/agents/:id/hostnames",
+            "GET /api/agents/:id/events?start=&end=",
+            "POST /api/agents/:id/maintenance",
+            "GET /api/agents/:id/info",
+            "GET /api/agents/:id/health",
+            "GET /api/agents/:id/available-fields?timestamp=",
+            "POST /api/query",
+            "POST /api/compare",
+            "GET /api/admin/ingest-stats",
+            "POST /api/admin/compact",
+            "POST /api/admin/agents",
+            "GET /grafana",
+            "POST /grafana/search",
+            "POST /grafana/query",
+            "GET /metrics"
         ]
     }))
 }
 
+/// 推送给单个流式订阅者（SSE / WebSocket）的消息
+enum StreamMessage {
+    /// 单条指标（广播前已序列化的 JSON）
+    Metrics(Arc<str>),
+    /// 缓冲溢出后的重新同步：各 Agent 最新样本组成的 JSON 数组
+    Resync(String),
+}
+
+/// 实时推送已关闭（`--no-broadcast`）时返回 404 与说明
+fn ensure_broadcast_enabled(state: &ApiState) -> Result<(), (StatusCode, Json<ApiResponse<()>>)> {
+    if state.config.broadcast_enabled {
+        return Ok(());
+    }
+    Err((
+        StatusCode::NOT_FOUND,
+        Json(ApiResponse::<()>::error(
+            "实时推送已关闭（--no-broadcast），请改用历史查询接口".to_string(),
+        )),
+    ))
+}
+
 /// SSE 流式推送
+///
+/// 每个订阅者有独立的有界缓冲，由单独的任务从广播转发；慢订阅者只影响自己
 async fn sse_handler(
     State(state): State<Arc<ApiState>>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    let rx = state.broadcast.subscribe();
-
-    let stream = stream::unfold(rx, |mut rx| async move {
-        match rx.recv().await {
-            Ok(metrics) => {
-                // 将 Protobuf 转为 JSON
-                if let Ok(json) = serde_json::to_string(&metrics) {
-                    Some((Ok(Event::default().data(json)), rx))
-                } else {
-                    Some((Ok(Event::default().comment("序列化失败")), rx))
-                }
-            }
-            Err(_) => None,
-        }
+    Query(query): Query<StreamQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, Json<ApiResponse<()>>)>
+{
+    ensure_broadcast_enabled(&state)?;
+    let (tx, rx) = mpsc::channel(state.config.sse_client_buffer.max(1));
+    tokio::spawn(forward_stream(
+        state.broadcast.subscribe(),
+        tx,
+        query.agent,
+        state.storage.clone(),
+        state.shutdown.clone(),
+    ));
+
+    let stream = ReceiverStream::new(rx).map(|message| {
+        Ok(match message {
+            // JSON 已在广播前序列化，这里直接复用
+            StreamMessage::Metrics(json) => Event::default().data(&*json),
+            StreamMessage::Resync(snapshot) => Event::default().event("resync").data(snapshot),
+        })
     });
 
-    Sse::new(stream).keep_alive(
+    Ok(Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
             .interval(Duration::from_secs(15))
             .text("keep-alive"),
-    )
+    ))
 }
 
-/// 获取所有 Agent 列表
-async fn list_agents(
+/// WebSocket 流式推送，推送内容与 SSE 相同，供会破坏 SSE 的代理环境使用
+///
+/// 文本帧为单条指标 JSON；缓冲溢出后的 resync 快照以 JSON 数组文本帧发送。
+/// 客户端的 Ping 由底层自动回复 Pong
+async fn ws_handler(
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
     State(state): State<Arc<ApiState>>,
-) -> Result<Json<ApiResponse<Vec<AgentInfo>>>, StatusCode> {
-    let agent_ids = state.storage.get_all_agents().await;
+    Query(query): Query<StreamQuery>,
+) -> Response {
+    // 推送已关闭时统一返回 404，不因请求缺少升级头而报 400
+    if let Err(disabled) = ensure_broadcast_enabled(&state) {
+        return disabled.into_response();
+    }
+    let ws = match ws {
+        Ok(ws) => ws,
+        Err(rejection) => return rejection.into_response(),
+    };
+    ws.on_upgrade(move |socket| ws_session(socket, state, query.agent))
+}
 
-    let mut agents = Vec::new();
-    for agent_id in agent_ids {
-        if let Some(latest) = state.storage.get_agent_latest(&agent_id).await {
-            agents.push(AgentInfo {
-                agent_id: latest.agent_id.clone(),
-                last_seen: latest.timestamp,
-                hostname: latest.hostname.clone(),
-            });
+/// 单个 WebSocket 连接：转发广播事件直到任一方关闭，退出时取消订阅
+async fn ws_session(mut socket: WebSocket, state: Arc<ApiState>, filter: Option<String>) {
+    let (tx, mut rx) = mpsc::channel(state.config.sse_client_buffer.max(1));
+    let forward = tokio::spawn(forward_stream(
+        state.broadcast.subscribe(),
+        tx,
+        filter,
+        state.storage.clone(),
+        state.shutdown.clone(),
+    ));
+
+    loop {
+        tokio::select! {
+            message = rx.recv() => {
+                let Some(message) = message else {
+                    break;
+                };
+                let text = match message {
+                    StreamMessage::Metrics(json) => json.to_string(),
+                    StreamMessage::Resync(snapshot) => snapshot,
+                };
+                if socket.send(WsMessage::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => match incoming {
+                // Ping/Pong 与客户端发来的其他数据帧不需要处理
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
         }
     }
 
-    info!("API: 返回 {} 个 Agent", agents.len());
-    Ok(Json(ApiResponse::ok(agents)))
+    // 转发任务持有广播订阅，连接关闭后立即结束，不必等到下一条事件
+    forward.abort();
 }
 
-/// 获取指定 Agent 的最新指标
-async fn get_agent_metrics(
-    State(state): State<Arc<ApiState>>,
-    Path(agent_id): Path<String>,
-) -> Result<Json<ApiResponse<MetricsRequest>>, StatusCode> {
-    match state.storage.get_agent_latest(&agent_id).await {
-        Some(metrics) => {
-            info!("API: 返回 {} 的最新指标", agent_id);
-            Ok(Json(ApiResponse::ok(metrics)))
-        }
-        None => {
-            info!("API: Agent {} 不存在", agent_id);
-            Err(StatusCode::NOT_FOUND)
-        }
+/// 把广播事件转发到单个流式订阅者（SSE / WebSocket）的缓冲
+///
+/// 订阅者缓冲已满（或转发落后于广播）时丢弃后续事件，等缓冲腾出空间后发送一条
+/// `resync` 快照，之后恢复逐条推送；订阅者断开、广播关闭或 Server 关闭后退出
+async fn forward_stream(
+    events: broadcast::Receiver<MetricsEvent>,
+    tx: mpsc::Sender<StreamMessage>,
+    filter: Option<String>,
+    storage: Arc<Storage>,
+    shutdown: watch::Receiver<bool>,
+) {
+    tokio::select! {
+        _ = forward_events(events, tx, filter, storage) => {}
+        _ = crate::shutdown_requested(shutdown) => {}
     }
 }
 
-/// 获取指定 Agent 的历史指标
+async fn forward_events(
+    mut events: broadcast::Receiver<MetricsEvent>,
+    tx: mpsc::Sender<StreamMessage>,
+    filter: Option<String>,
+    storage: Arc<Storage>,
+) {
+    let mut overflowed = false;
+    loop {
+        if overflowed {
+            tokio::select! {
+                permit = tx.reserve() => {
+                    let Ok(permit) = permit else {
+                        return;
+                    };
+                    permit.send(StreamMessage::Resync(
+                        stream_snapshot(&storage, filter.as_deref()).await,
+                    ));
+                    overflowed = false;
+                }
+                // 溢出期间的事件由快照覆盖，直接丢弃
+                result = events.recv() => {
+                    if let Err(RecvError::Closed) = result {
+                        return;
+                    }
+                }
+            }
+            continue;
+        }
+
+        match events.recv().await {
+            Ok(event) => {
+                if filter.as_deref().is_some_and(|id| id != &*event.agent_id) {
+                    continue;
+                }
+                match tx.try_send(StreamMessage::Metrics(event.json)) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        info!("SSE 订阅者消费过慢，缓冲已满，稍后发送 resync 快照");
+                        overflowed = true;
+                    }
+                    Err(TrySendError::Closed(_)) => return,
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                info!("SSE 转发落后广播 {} 条，稍后发送 resync 快照", skipped);
+                overflowed = true;
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// 各 Agent 最新样本组成的 JSON 数组（按订阅过滤）
+async fn stream_snapshot(storage: &Storage, filter: Option<&str>) -> String {
+    let mut latest = Vec::new();
+    for agent_id in storage.get_all_agents().await {
+        if filter.is_some_and(|id| id != agent_id) {
+            continue;
+        }
+        if let Some(metrics) = storage.get_agent_latest(&agent_id).await {
+            latest.push(metrics);
+        }
+    }
+    serde_json::to_string(&latest).unwrap_or_else(|_| "[]".to_string())
+}
+
+/// 获取所有 Agent 列表，可按最新样本排序并按在线状态过滤
+async fn list_agents(
+    State(state): State<Arc<ApiState>>,
+    Query(query): Query<AgentListQuery>,
+) -> Result<Json<ApiResponse<Vec<AgentInfo>>>, StatusCode> {
+    let include_sparkline = query.include_sparkline().map_err(|message| {
+        info!("API: 拒绝 Agent 列表查询: {}", message);
+        StatusCode::BAD_REQUEST
+    })?;
+    let sparkline_points = query.sparkline_points.min(MAX_LIST_SPARKLINE_POINTS);
+    let agent_ids = state.storage.get_all_agents().await;
+    let now = current_timestamp_ms();
+
+    let mut agents = Vec::new();
+    for agent_id in agent_ids {
+        if let Some(latest) = state.storage.get_agent_latest(&agent_id).await {
+            let expected_interval_ms = state.cadences.expected_interval_ms(&agent_id);
+            if query.status.is_some_and(|status| {
+                AgentStatus::of(latest.timestamp, now, expected_interval_ms) != status
+            }) {
+                continue;
+            }
+            let agent_metrics = latest
+                .system
+                .as_ref()
+                .and_then(|system| system.agent_metrics.as_ref());
+            let info = AgentInfo {
+                agent_id: latest.agent_id.clone(),
+                last_seen: latest.timestamp,
+                hostname: latest.hostname.clone(),
+                duplicate_agent_id: state.duplicates.is_duplicate(&agent_id),
+                dropped_estimate: state.sequences.dropped_estimate(&agent_id),
+                last_error: agent_metrics
+                    .map(|metrics| metrics.last_error.clone())
+                    .filter(|error| !error.is_empty()),
+                reconnect_count: agent_metrics.map(|metrics| metrics.reconnect_count),
+                expected_interval_ms,
+                last_disconnect: state.disconnects.last(&agent_id),
+                ingest_lag: state.lags.lag(&agent_id),
+                maintenance_until: state.maintenance.active_until(&agent_id, now),
+                sparkline: None,
+            };
+            let key = query.sort.and_then(|sort| sort.key(&latest));
+            agents.push((info, key));
+        }
+    }
+
+    if query.sort.is_some() {
+        sort_missing_last(&mut agents, query.order);
+    }
+    let mut agents: Vec<AgentInfo> = agents.into_iter().map(|(info, _)| info).collect();
+    // 只取内存缓存，不为列表页逐个读盘
+    if include_sparkline {
+        for info in &mut agents {
+            let values = state
+                .storage
+                .map_cached_recent(&info.agent_id, sparkline_points, |metrics| {
+                    MetricField::Cpu.value(metrics)
+                })
+                .await;
+            info.sparkline = Some(values);
+        }
+    }
+
+    info!("API: 返回 {} 个 Agent", agents.len());
+    Ok(Json(ApiResponse::ok(agents)))
+}
+
+/// 获取所有 Agent 中最新的若干条指标
+async fn get_recent_metrics(
+    State(state): State<Arc<ApiState>>,
+    Query(query): Query<HistoryQuery>,
+) -> Json<ApiResponse<Vec<MetricsRequest>>> {
+    let (limit, clamped) = state.config.clamp_limit(query.limit);
+    let recent = state.storage.get_recent_across_agents(limit).await;
+
+    info!("API: 返回全局最新的 {} 条指标", recent.len());
+    Json(ApiResponse::ok(recent).with_message(clamped))
+}
+
+/// 客户端是否要求 Protobuf 响应（`Accept: application/x-protobuf`）
+fn wants_protobuf(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media| media.split(';').next().unwrap_or("").trim() == PROTOBUF_CONTENT_TYPE)
+}
+
+/// Protobuf 响应体
+fn protobuf_response(body: Vec<u8>) -> Response {
+    ([(header::CONTENT_TYPE, PROTOBUF_CONTENT_TYPE)], body).into_response()
+}
+
+/// 历史不完整时为响应加上 [`HISTORY_TRUNCATED_HEADER`]
+pub(crate) fn mark_truncated(mut response: Response, truncated: bool) -> Response {
+    if truncated {
+        response
+            .headers_mut()
+            .insert(HISTORY_TRUNCATED_HEADER, HeaderValue::from_static("true"));
+    }
+    response
+}
+
+/// 给定的 Agent 中是否有任何一个的历史已被缓存淘汰
+pub(crate) async fn any_history_evicted<'a>(
+    storage: &Storage,
+    agents: impl IntoIterator<Item = &'a String>,
+) -> bool {
+    for agent_id in agents {
+        if storage.has_evicted_history(agent_id).await {
+            return true;
+        }
+    }
+    false
+}
+
+/// 获取指定 Agent 的最新指标
+///
+/// `Accept: application/x-protobuf` 时返回编码后的单个 `MetricsRequest`
+async fn get_agent_metrics(
+    State(state): State<Arc<ApiState>>,
+    Path(agent_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    match state.storage.get_agent_latest(&agent_id).await {
+        Some(metrics) => {
+            info!("API: 返回 {} 的最新指标", agent_id);
+            if wants_protobuf(&headers) {
+                return Ok(protobuf_response(metrics.encode_to_vec()));
+            }
+            Ok(Json(ApiResponse::ok(metrics)).into_response())
+        }
+        None => {
+            info!("API: Agent {} 不存在", agent_id);
+            Err(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+/// 获取指定 Agent 的历史指标
+///
+/// `Accept: application/x-protobuf` 时返回依次拼接的长度前缀（varint）编码 `MetricsRequest`
 async fn get_agent_history(
     State(state): State<Arc<ApiState>>,
     Path(agent_id): Path<String>,
     Query(query): Query<HistoryQuery>,
-) -> Result<Json<ApiResponse<Vec<MetricsRequest>>>, StatusCode> {
-    let limit = query.limit.min(max_history_limit());
-    let history = state.storage.get_agent_history(&agent_id, limit).await;
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    ensure_agent_known(&state.storage, &agent_id).await?;
+    let consistency = query
+        .consistency
+        .unwrap_or(state.config.history_consistency);
+    if let Some(points) = query.points {
+        return resample_agent_history(&state, &agent_id, &query, points, consistency, &headers)
+            .await;
+    }
+    let (limit, clamped) = state.config.clamp_limit(query.limit);
+    let history = state
+        .storage
+        .get_agent_history_with(&agent_id, limit, consistency)
+        .await
+        .map_err(|e| {
+            warn!(
+                "API: 按 {} 读取 {} 的历史失败: {:#}",
+                consistency, agent_id, e
+            );
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    let truncated = state
+        .storage
+        .is_history_truncated(&agent_id, limit, history.len())
+        .await;
+    history_response(&agent_id, history, clamped, truncated, &headers)
+}
+
+/// 把 Agent 在 `[start, end]` 内的全部样本重采样为约 `points` 个点
+///
+/// 原始样本从存储游标逐条读取、逐桶合并，不先按 `limit` 截断，宽时间范围也覆盖完整；
+/// 内存中只保留当前桶的样本。原始样本数不超过 `points` 时原样返回
+async fn resample_agent_history(
+    state: &ApiState,
+    agent_id: &str,
+    query: &HistoryQuery,
+    points: usize,
+    consistency: HistoryConsistency,
+    headers: &HeaderMap,
+) -> Result<Response, StatusCode> {
+    let (points, clamped) = state.config.clamp_limit(points);
+    if consistency == HistoryConsistency::PersistAuthoritative
+        && !state.storage.flush_pending(HISTORY_FLUSH_TIMEOUT).await
+    {
+        warn!(
+            "API: 按 {} 读取 {} 的历史失败: 样本未能及时落盘",
+            consistency, agent_id
+        );
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    let start = query.start.unwrap_or(i64::MIN);
+    let end = match query.end {
+        Some(end) => end,
+        None => state
+            .storage
+            .get_agent_latest(agent_id)
+            .await
+            .map_or(i64::MAX, |latest| latest.timestamp),
+    };
+    if start > end {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mut samples = state.storage.stream_agent_range(agent_id, start, end);
+    // 先缓冲至多 points 条：样本不多于 points 时原样返回
+    let mut head = Vec::new();
+    while head.len() <= points {
+        match samples.recv().await {
+            Some(metrics) => head.push(metrics),
+            None => break,
+        }
+    }
+    let history = if head.len() <= points {
+        head
+    } else {
+        let first = query.start.unwrap_or(head[0].timestamp);
+        let mut resampler = analytics::Resampler::new(first, end, points);
+        for metrics in head {
+            resampler.push(metrics);
+        }
+        while let Some(metrics) = samples.recv().await {
+            resampler.push(metrics);
+        }
+        resampler.finish()
+    };
+    let truncated = state.storage.has_evicted_history(agent_id).await;
+    history_response(agent_id, history, clamped, truncated, headers)
+}
 
+/// 按 Accept 头以 JSON 或长度前缀 Protobuf 返回历史样本
+fn history_response(
+    agent_id: &str,
+    history: Vec<MetricsRequest>,
+    clamped: Option<String>,
+    truncated: bool,
+    headers: &HeaderMap,
+) -> Result<Response, StatusCode> {
     if history.is_empty() {
-        info!("API: Agent {} 没有历史数据，返回空列表", agent_id);
+        info!("API: Agent {} 在范围内没有历史数据，返回空列表", agent_id);
     } else {
         info!("API: 返回 {} 的 {} 条历史记录", agent_id, history.len());
     }
 
-    Ok(Json(ApiResponse::ok(history)))
+    if wants_protobuf(headers) {
+        let mut body = Vec::new();
+        for metrics in &history {
+            metrics
+                .encode_length_delimited(&mut body)
+                .expect("encoding into Vec cannot fail");
+        }
+        return Ok(mark_truncated(protobuf_response(body), truncated));
+    }
+
+    let response = Json(
+        ApiResponse::ok(history)
+            .with_message(clamped)
+            .with_truncated(truncated),
+    )
+    .into_response();
+    Ok(mark_truncated(response, truncated))
+}
+
+/// 获取指定 Agent 单个指标的精简序列：最近 `limit` 条样本重采样为 `points` 个点后取值
+async fn get_sparkline(
+    State(state): State<Arc<ApiState>>,
+    Path(agent_id): Path<String>,
+    Query(query): Query<SparklineQuery>,
+) -> Result<Response, StatusCode> {
+    ensure_agent_known(&state.storage, &agent_id).await?;
+    let (limit, clamped) = state.config.clamp_limit(query.limit);
+    let history = state.storage.get_agent_history(&agent_id, limit).await;
+    let truncated = state
+        .storage
+        .is_history_truncated(&agent_id, limit, history.len())
+        .await;
+    let resampled = analytics::resample_history(history, query.points);
+
+    let (last_ts, values): (Vec<i64>, Vec<f64>) = resampled
+        .iter()
+        .filter_map(|metrics| Some((metrics.timestamp, query.field.value(metrics)?)))
+        .unzip();
+    let sparkline = Sparkline {
+        values,
+        last_ts: last_ts.last().copied(),
+    };
+
+    info!(
+        "API: 返回 {} 的 {:?} sparkline，{} 个点",
+        agent_id,
+        query.field,
+        sparkline.values.len()
+    );
+    let response = Json(
+        ApiResponse::ok(sparkline)
+            .with_message(clamped)
+            .with_truncated(truncated),
+    )
+    .into_response();
+    Ok(mark_truncated(response, truncated))
+}
+
+/// 按查询计划取单个 Agent 某个指标的序列
+///
+/// 原始样本逐条从存储游标折叠：分桶时每桶只保留聚合状态，不分桶时只保留最近
+/// `max_points` 个点（发生截断时第二项为 true），内存占用与时间范围内的样本数无关
+pub(crate) async fn fold_agent_points(
+    state: &ApiState,
+    agent_id: &str,
+    field: MetricField,
+    resolution: Resolution,
+    plan: &QueryPlan,
+    max_points: usize,
+) -> (Vec<(i64, f64)>, bool) {
+    let mut truncated = false;
+    let mut folder = plan.buckets.map(|(bucket_ms, aggregation)| {
+        BucketFolder::new(plan.from, bucket_ms, aggregation).with_time_zone(plan.time_zone.clone())
+    });
+    let mut latest = VecDeque::new();
+    let mut push = |ts: i64, value: f64| match &mut folder {
+        Some(folder) => folder.push(ts, value),
+        None => {
+            if latest.len() == max_points {
+                latest.pop_front();
+                truncated = true;
+            }
+            latest.push_back((ts, value));
+        }
+    };
+
+    match resolution {
+        Resolution::Raw => {
+            let mut samples = state
+                .storage
+                .stream_agent_range(agent_id, plan.from, plan.to);
+            while let Some(metrics) = samples.recv().await {
+                if let Some(value) = field.value(&metrics) {
+                    push(metrics.timestamp, value);
+                }
+            }
+        }
+        Resolution::Hour => {
+            let rollups = state
+                .storage
+                .get_hourly_range(agent_id, plan.from, plan.to)
+                .await;
+            for (hour, rollup) in rollups {
+                if let Some(value) = rollup.value(field) {
+                    push(hour, value);
+                }
+            }
+        }
+    }
+
+    let points = match folder {
+        Some(folder) => folder.finish(),
+        None => latest.into(),
+    };
+    (points, truncated)
+}
+
+/// 按 JSON 查询体取一个或多个 Agent 某个指标在时间范围内的序列，可分桶聚合或跨 Agent 合并
+///
+/// 请求体无法解析（未知字段、指标或聚合方式）或参数组合无效时返回 400 与错误说明
+async fn query_metrics(
+    State(state): State<Arc<ApiState>>,
+    body: Result<Json<MetricQuery>, JsonRejection>,
+) -> Result<Response, (StatusCode, Json<ApiResponse<()>>)> {
+    let bad_request = |message: String| {
+        info!("API: 拒绝无效查询: {}", message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse::<()>::error(message)),
+        )
+    };
+    let Json(query) = body.map_err(|rejection| bad_request(rejection.body_text()))?;
+    let max_points = state.config.max_history_limit;
+    let time_zone = match &query.tz {
+        Some(tz) => Some(TimeZone::resolve(tz).await.map_err(bad_request)?),
+        None => None,
+    };
+    let plan = query
+        .plan(current_timestamp_ms(), max_points, time_zone)
+        .map_err(bad_request)?;
+
+    let agents = if query.agents.is_empty() {
+        state.storage.get_all_agents().await
+    } else {
+        query.agents
+    };
+    let evicted = any_history_evicted(&state.storage, &agents).await;
+
+    let mut truncated = false;
+    let mut per_agent = Vec::with_capacity(agents.len());
+    for agent_id in agents {
+        let (points, agent_truncated) = fold_agent_points(
+            &state,
+            &agent_id,
+            query.field,
+            query.resolution,
+            &plan,
+            max_points,
+        )
+        .await;
+        truncated |= agent_truncated;
+        per_agent.push((agent_id, points));
+    }
+
+    let to_points = |points: Vec<(i64, f64)>| {
+        points
+            .into_iter()
+            .map(|(ts, value)| QueryPoint { ts, value })
+            .collect()
+    };
+    let series = match plan.buckets {
+        Some((_, aggregation)) if query.combine => {
+            let points: Vec<_> = per_agent.into_iter().map(|(_, points)| points).collect();
+            vec![QuerySeries {
+                agent_id: None,
+                points: to_points(analytics::combine_series(&points, aggregation)),
+            }]
+        }
+        _ => per_agent
+            .into_iter()
+            .map(|(agent_id, points)| QuerySeries {
+                agent_id: Some(agent_id),
+                points: to_points(points),
+            })
+            .collect(),
+    };
+
+    info!(
+        "API: 查询 {:?} [{}, {}]，返回 {} 条序列",
+        query.field,
+        plan.from,
+        plan.to,
+        series.len()
+    );
+    let message = truncated.then(|| {
+        format!(
+            "原始取值超过 {} 个点，每个 Agent 仅保留最近的部分",
+            max_points
+        )
+    });
+    let response = Json(
+        ApiResponse::ok(QueryResult { series })
+            .with_message(message)
+            .with_truncated(evicted),
+    )
+    .into_response();
+    Ok(mark_truncated(response, evicted))
+}
+
+/// 把多个 Agent 的同一指标插值到共享时间轴上，便于逐点对比找出异常的 Agent
+///
+/// 时间轴间隔取请求的 `bucket_ms`、各 Agent 中最慢的上报间隔与范围内点数上限所需间隔三者的最大值，
+/// 上报频率不同的 Agent 因此都对齐到最粗的节奏；各点取该时刻两侧样本的线性插值，不做桶内聚合
+async fn compare_agents(
+    State(state): State<Arc<ApiState>>,
+    body: Result<Json<CompareQuery>, JsonRejection>,
+) -> Result<Response, (StatusCode, Json<ApiResponse<()>>)> {
+    let bad_request = |message: String| {
+        info!("API: 拒绝无效对比: {}", message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse::<()>::error(message)),
+        )
+    };
+    let Json(query) = body.map_err(|rejection| bad_request(rejection.body_text()))?;
+    if query.agents.is_empty() {
+        return Err(bad_request("agents 不能为空".to_string()));
+    }
+    if query.bucket_ms.is_some_and(|bucket_ms| bucket_ms <= 0) {
+        return Err(bad_request("bucket_ms 必须为正数".to_string()));
+    }
+    let to = query.to.unwrap_or_else(current_timestamp_ms);
+    let from = query
+        .from
+        .unwrap_or_else(|| to.saturating_sub(DEFAULT_QUERY_RANGE_MS));
+    if from > to {
+        return Err(bad_request(format!("from ({}) 晚于 to ({})", from, to)));
+    }
+
+    for agent_id in &query.agents {
+        ensure_agent_known(&state.storage, agent_id)
+            .await
+            .map_err(|status| {
+                (
+                    status,
+                    Json(ApiResponse::<()>::error(format!(
+                        "Agent {} 不存在",
+                        agent_id
+                    ))),
+                )
+            })?;
+    }
+
+    // 原始取值先折叠进不超过 max_points 个等宽的桶，范围内的全部样本都参与，
+    // 不会因点数过多只保留最近的部分
+    let max_points = state.config.max_history_limit.max(1);
+    let span = to.saturating_sub(from).saturating_add(1);
+    let min_bucket_ms = (span - 1) / max_points as i64 + 1;
+    let evicted = any_history_evicted(&state.storage, &query.agents).await;
+    let mut per_agent = Vec::with_capacity(query.agents.len());
+    for agent_id in query.agents {
+        let points =
+            fold_compare_points(&state, &agent_id, query.field, from, to, min_bucket_ms).await;
+        let interval_ms = analytics::median_interval(&points);
+        per_agent.push((agent_id, interval_ms, points));
+    }
+
+    let bucket_ms = per_agent
+        .iter()
+        .filter_map(|(_, interval_ms, _)| *interval_ms)
+        .chain(query.bucket_ms)
+        .chain([min_bucket_ms])
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    // 按桶数生成时间轴：bucket_ms 不小于 min_bucket_ms，点数不超过 max_points，末点不超过 to
+    let count = (span - 1) / bucket_ms + 1;
+    let timestamps: Vec<i64> = (0..count).map(|i| from + i * bucket_ms).collect();
+    // 两侧样本相距超过两个间隔视为断线，不跨空档插值
+    let max_gap = bucket_ms.saturating_mul(2);
+    let series: Vec<CompareSeries> = per_agent
+        .into_iter()
+        .map(|(agent_id, interval_ms, points)| CompareSeries {
+            values: analytics::interpolate_at(&points, &timestamps, max_gap),
+            agent_id,
+            interval_ms,
+        })
+        .collect();
+
+    info!(
+        "API: 对比 {} 个 Agent 的 {:?} [{}, {}]，间隔 {} ms",
+        series.len(),
+        query.field,
+        from,
+        to,
+        bucket_ms
+    );
+    let response = Json(
+        ApiResponse::ok(CompareResult {
+            bucket_ms,
+            timestamps,
+            series,
+        })
+        .with_truncated(evicted),
+    )
+    .into_response();
+    Ok(mark_truncated(response, evicted))
+}
+
+/// 把 [from, to] 内的原始取值按 `bucket_ms` 分桶，每个桶输出 (样本平均时间戳, 平均值)
+///
+/// 保留样本的实际时间，桶内只有一个样本时与原始点完全相同，插值不会因桶边界产生偏移
+async fn fold_compare_points(
+    state: &ApiState,
+    agent_id: &str,
+    field: MetricField,
+    from: i64,
+    to: i64,
+    bucket_ms: i64,
+) -> Vec<(i64, f64)> {
+    // 桶序号 → (相对桶起点的时间偏移之和, 取值之和, 样本数)
+    let mut buckets: BTreeMap<i64, (i128, f64, u32)> = BTreeMap::new();
+    let mut samples = state.storage.stream_agent_range(agent_id, from, to);
+    while let Some(metrics) = samples.recv().await {
+        let Some(value) = field.value(&metrics) else {
+            continue;
+        };
+        let offset = metrics.timestamp.saturating_sub(from);
+        let bucket = buckets.entry(offset / bucket_ms).or_default();
+        bucket.0 += i128::from(offset % bucket_ms);
+        bucket.1 += value;
+        bucket.2 += 1;
+    }
+    buckets
+        .into_iter()
+        .map(|(index, (offsets, sum, count))| {
+            let offset = (offsets / i128::from(count)) as i64;
+            (from + index * bucket_ms + offset, sum / f64::from(count))
+        })
+        .collect()
+}
+
+/// 以 NDJSON 流式导出指定 Agent 的全部历史指标（每行一个 JSON 对象）
+async fn export_agent_history(
+    State(state): State<Arc<ApiState>>,
+    Path(agent_id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    ensure_agent_known(&state.storage, &agent_id).await?;
+    info!("API: 开始导出 {} 的历史指标", agent_id);
+    let truncated = state.storage.has_evicted_history(&agent_id).await;
+    let lines = ReceiverStream::new(state.storage.stream_agent_history(&agent_id)).map(|metrics| {
+        serde_json::to_vec(&metrics).map(|mut line| {
+            line.push(b'\n');
+            Bytes::from(line)
+        })
+    });
+
+    let response = (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    );
+    Ok(mark_truncated(response.into_response(), truncated))
+}
+
+/// 以 CSV 流式导出指定 Agent 的全部历史指标（首行为表头）
+async fn export_agent_history_csv(
+    State(state): State<Arc<ApiState>>,
+    Path(agent_id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    ensure_agent_known(&state.storage, &agent_id).await?;
+    info!("API: 开始导出 {} 的历史指标（CSV）", agent_id);
+    let truncated = state.storage.has_evicted_history(&agent_id).await;
+    let header = futures::stream::once(async { Ok(Bytes::from_static(CSV_HEADER.as_bytes())) });
+    let rows = ReceiverStream::new(state.storage.stream_agent_history(&agent_id))
+        .map(|metrics| Ok::<_, Infallible>(Bytes::from(exposition::csv_row(&metrics))));
+
+    let response = (
+        [(header::CONTENT_TYPE, "text/csv; charset=utf-8")],
+        Body::from_stream(header.chain(rows)),
+    );
+    Ok(mark_truncated(response.into_response(), truncated))
+}
+
+/// 以 InfluxDB 行协议流式导出指定 Agent 的全部历史指标
+async fn export_agent_history_influx(
+    State(state): State<Arc<ApiState>>,
+    Path(agent_id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    ensure_agent_known(&state.storage, &agent_id).await?;
+    info!("API: 开始导出 {} 的历史指标（InfluxDB 行协议）", agent_id);
+    let truncated = state.storage.has_evicted_history(&agent_id).await;
+    let lines = ReceiverStream::new(state.storage.stream_agent_history(&agent_id))
+        .map(|metrics| Ok::<_, Infallible>(Bytes::from(exposition::influx_lines(&metrics))));
+
+    let response = (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        Body::from_stream(lines),
+    );
+    Ok(mark_truncated(response.into_response(), truncated))
+}
+
+/// 以 Prometheus 文本格式输出各 Agent 最新样本的主要指标，供 Prometheus 直接抓取
+async fn prometheus_metrics(State(state): State<Arc<ApiState>>) -> impl IntoResponse {
+    let mut latest = Vec::new();
+    for agent_id in state.storage.get_all_agents().await {
+        if let Some(metrics) = state.storage.get_agent_latest(&agent_id).await {
+            latest.push(metrics);
+        }
+    }
+    debug!("API: Prometheus 抓取 {} 个 Agent 的最新样本", latest.len());
+
+    (
+        [(header::CONTENT_TYPE, exposition::PROMETHEUS_CONTENT_TYPE)],
+        exposition::prometheus(&latest),
+    )
+}
+
+/// 获取指定 Agent 各挂载点的写满预测
+async fn get_disk_forecast(
+    State(state): State<Arc<ApiState>>,
+    Path(agent_id): Path<String>,
+    Query(query): Query<ForecastQuery>,
+) -> Result<Response, StatusCode> {
+    ensure_agent_known(&state.storage, &agent_id).await?;
+    let (limit, clamped) = state.config.clamp_limit(query.limit);
+    let history = state.storage.get_agent_history(&agent_id, limit).await;
+    let truncated = state
+        .storage
+        .is_history_truncated(&agent_id, limit, history.len())
+        .await;
+
+    let forecasts = analytics::forecast_disks(&history);
+    info!(
+        "API: 返回 {} 的 {} 个挂载点写满预测（{} 条样本）",
+        agent_id,
+        forecasts.len(),
+        history.len()
+    );
+    let response = Json(
+        ApiResponse::ok(forecasts)
+            .with_message(clamped)
+            .with_truncated(truncated),
+    )
+    .into_response();
+    Ok(mark_truncated(response, truncated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{AgentEventKind, StorageConfig};
+    use axum::body::Body;
+    use axum::http::Request;
+    use futures::StreamExt;
+    use tower::ServiceExt;
+
+    fn api_state(
+        storage: Arc<Storage>,
+        broadcast: broadcast::Sender<MetricsEvent>,
+        config: ApiConfig,
+    ) -> ApiState {
+        ApiState {
+            storage,
+            broadcast,
+            stats: Arc::default(),
+            duplicates: Arc::default(),
+            sequences: Arc::default(),
+            cadences: Arc::default(),
+            lags: Arc::default(),
+            allowlist: Arc::default(),
+            maintenance: Arc::default(),
+            disconnects: Arc::default(),
+            config,
+            shutdown: watch::channel(false).1,
+        }
+    }
+
+    fn router(storage: Arc<Storage>) -> Router {
+        let (tx, _) = broadcast::channel(16);
+        create_router(api_state(storage, tx, ApiConfig::default()))
+    }
+
+    async fn status_of(router: Router, uri: &str) -> StatusCode {
+        router
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_healthz_and_readyz_memory_only() {
+        let storage = Arc::new(Storage::new());
+        assert_eq!(
+            status_of(router(storage.clone()), "/healthz").await,
+            StatusCode::OK
+        );
+        assert_eq!(status_of(router(storage), "/readyz").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_readyz_ok_with_persistence() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(Storage::with_config(StorageConfig {
+            db_path: Some(
+                temp_dir
+                    .path()
+                    .join("ready.redb")
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            ..Default::default()
+        }));
+        assert_eq!(
+            status_of(router(storage.clone()), "/readyz").await,
+            StatusCode::OK
+        );
+
+        // 关闭流程中不再就绪，但进程仍存活
+        storage.shutdown().await.unwrap();
+        assert_eq!(
+            status_of(router(storage.clone()), "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(status_of(router(storage), "/healthz").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_readyz_unavailable_when_persistence_failed() {
+        // 以目录作为数据库路径，redb 无法打开
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(Storage::with_config(StorageConfig {
+            db_path: Some(temp_dir.path().to_string_lossy().to_string()),
+            ..Default::default()
+        }));
+        assert_eq!(
+            status_of(router(storage.clone()), "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(status_of(router(storage), "/healthz").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_request_id_header() {
+        let app = router(Arc::new(Storage::new()));
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/api")
+                    .header(trace::REQUEST_ID_HEADER, "req-1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[trace::REQUEST_ID_HEADER], "req-1");
+
+        // 未提供时生成
+        let response = app
+            .oneshot(Request::get("/api").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(response.headers().contains_key(trace::REQUEST_ID_HEADER));
+    }
+
+    #[tokio::test]
+    async fn test_sse_agent_filter() {
+        let (tx, _) = broadcast::channel(16);
+        let app = create_router(api_state(
+            Arc::new(Storage::new()),
+            tx.clone(),
+            ApiConfig::default(),
+        ));
+
+        let response = app
+            .oneshot(
+                Request::get("/api/stream?agent=agent-1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for agent_id in ["agent-2", "agent-1"] {
+            crate::events::publish(
+                &tx,
+                &MetricsRequest {
+                    agent_id: agent_id.to_string(),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let mut body = response.into_body().into_data_stream();
+        let frame = body.next().await.unwrap().unwrap();
+        let text = String::from_utf8(frame.to_vec()).unwrap();
+        assert!(text.starts_with("data: "), "unexpected frame: {}", text);
+        assert!(text.contains("\"agent_id\":\"agent-1\""));
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout_cancels_slow_handler() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        struct DropFlag(Arc<AtomicBool>);
+        impl Drop for DropFlag {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let flag = cancelled.clone();
+        let app = Router::new()
+            .route(
+                "/slow",
+                get(move || async move {
+                    let _guard = DropFlag(flag);
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    "done"
+                }),
+            )
+            .layer(middleware::from_fn(|request, next| {
+                request_timeout(Duration::from_millis(50), request, next)
+            }));
+
+        let response = app
+            .oneshot(Request::get("/slow").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(cancelled.load(Ordering::SeqCst));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["success"], false);
+
+        // SSE 不受处理时限约束：超过时限后仍能收到推送
+        let (tx, _) = broadcast::channel(16);
+        let app = create_router(api_state(
+            Arc::new(Storage::new()),
+            tx.clone(),
+            ApiConfig {
+                request_timeout: Duration::from_millis(20),
+                ..Default::default()
+            },
+        ));
+        let response = app
+            .oneshot(Request::get("/api/stream").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        crate::events::publish(
+            &tx,
+            &MetricsRequest {
+                agent_id: "agent-1".to_string(),
+                ..Default::default()
+            },
+        );
+        let mut body = response.into_body().into_data_stream();
+        let frame = body.next().await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&frame).contains("agent-1"));
+    }
+
+    #[tokio::test]
+    async fn test_ws_agent_filter_and_ping() {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::Message as ClientMessage;
+
+        let (tx, _) = broadcast::channel(16);
+        let app = create_router(api_state(
+            Arc::new(Storage::new()),
+            tx.clone(),
+            ApiConfig::default(),
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async(format!("ws://{}/api/ws?agent=agent-1", addr))
+                .await
+                .unwrap();
+
+        // 客户端 Ping 得到 Pong，同时说明连接已订阅广播
+        socket
+            .send(ClientMessage::Ping(b"hi".to_vec()))
+            .await
+            .unwrap();
+        let pong = socket.next().await.unwrap().unwrap();
+        assert_eq!(pong, ClientMessage::Pong(b"hi".to_vec()));
+        assert_eq!(tx.receiver_count(), 1);
+
+        for agent_id in ["agent-2", "agent-1"] {
+            crate::events::publish(
+                &tx,
+                &MetricsRequest {
+                    agent_id: agent_id.to_string(),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let frame = socket.next().await.unwrap().unwrap();
+        let text = frame.into_text().unwrap();
+        assert!(text.contains("\"agent_id\":\"agent-1\""), "{}", text);
+
+        // 客户端关闭后订阅随之释放
+        socket.close(None).await.unwrap();
+        for _ in 0..50 {
+            if tx.receiver_count() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(tx.receiver_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sse_slow_client_gets_resync() {
+        let storage = Arc::new(Storage::new());
+        let (tx, _) = broadcast::channel(16);
+        let app = create_router(api_state(
+            storage.clone(),
+            tx.clone(),
+            ApiConfig {
+                sse_client_buffer: 1,
+                ..Default::default()
+            },
+        ));
+
+        let mut bodies = Vec::new();
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(Request::get("/api/stream").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            bodies.push(response.into_body().into_data_stream());
+        }
+        let mut slow = bodies.pop().unwrap();
+        let mut fast = bodies.pop().unwrap();
+
+        // 快订阅者逐条消费，慢订阅者始终不读
+        for ts in 1..=5 {
+            let metrics = MetricsRequest {
+                agent_id: "agent-1".to_string(),
+                timestamp: ts,
+                ..Default::default()
+            };
+            storage.save_metrics(&metrics).await;
+            crate::events::publish(&tx, &metrics);
+
+            let frame = fast.next().await.unwrap().unwrap();
+            let text = String::from_utf8(frame.to_vec()).unwrap();
+            assert!(text.starts_with("data: "), "unexpected frame: {}", text);
+            assert!(text.contains(&format!("\"timestamp\":{}", ts)));
+        }
+
+        // 慢订阅者先收到缓冲中的第一条，随后是携带最新样本的 resync
+        let frame = slow.next().await.unwrap().unwrap();
+        let text = String::from_utf8(frame.to_vec()).unwrap();
+        assert!(
+            text.contains("\"timestamp\":1"),
+            "unexpected frame: {}",
+            text
+        );
+
+        let frame = slow.next().await.unwrap().unwrap();
+        let text = String::from_utf8(frame.to_vec()).unwrap();
+        assert!(
+            text.starts_with("event: resync"),
+            "unexpected frame: {}",
+            text
+        );
+        assert!(text.contains("\"timestamp\":5"));
+    }
+
+    #[tokio::test]
+    async fn test_list_agents_sort_and_filter() {
+        use common::proto::{CpuMetrics, SystemMetrics};
+
+        let storage = Arc::new(Storage::new());
+        let now = current_timestamp_ms();
+        // (agent_id, CPU 使用率, 距今毫秒数)；agent-d 的样本不带 CPU 指标
+        for (agent_id, cpu, age) in [
+            ("agent-a", Some(20.0), 0),
+            ("agent-b", Some(90.0), 60_000),
+            ("agent-c", Some(55.0), 0),
+            ("agent-d", None, 0),
+            ("agent-e", Some(5.0), 120_000),
+        ] {
+            storage
+                .save_metrics(&MetricsRequest {
+                    agent_id: agent_id.to_string(),
+                    timestamp: now - age,
+                    system: Some(SystemMetrics {
+                        cpu: cpu.map(|usage_percent| CpuMetrics {
+                            usage_percent,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .await;
+        }
+
+        async fn agent_ids(app: Router, uri: &str) -> Vec<String> {
+            let response = app
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            value["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|agent| agent["agent_id"].as_str().unwrap().to_string())
+                .collect()
+        }
+
+        let app = router(storage);
+        // 缺少排序字段的 Agent 无论升降序都排在最后
+        assert_eq!(
+            agent_ids(app.clone(), "/api/agents?sort=cpu&order=desc").await,
+            ["agent-b", "agent-c", "agent-a", "agent-e", "agent-d"]
+        );
+        assert_eq!(
+            agent_ids(app.clone(), "/api/agents?sort=cpu&order=asc").await,
+            ["agent-e", "agent-a", "agent-c", "agent-b", "agent-d"]
+        );
+        assert_eq!(
+            agent_ids(app.clone(), "/api/agents?sort=cpu&status=offline").await,
+            ["agent-b", "agent-e"]
+        );
+        assert_eq!(
+            agent_ids(app.clone(), "/api/agents?sort=last_seen&order=asc").await,
+            ["agent-e", "agent-b", "agent-a", "agent-c", "agent-d"]
+        );
+        assert_eq!(
+            status_of(app, "/api/agents?sort=disk").await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_agents_include_sparkline() {
+        use common::proto::{CpuMetrics, SystemMetrics};
+
+        let storage = Arc::new(Storage::new());
+        let now = current_timestamp_ms();
+        for (agent_id, base) in [("agent-a", 10.0), ("agent-b", 50.0)] {
+            for i in 0..5 {
+                storage
+                    .save_metrics(&MetricsRequest {
+                        agent_id: agent_id.to_string(),
+                        timestamp: now - (5 - i) * 1000,
+                        system: Some(SystemMetrics {
+                            cpu: Some(CpuMetrics {
+                                usage_percent: base + i as f64,
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    })
+                    .await;
+            }
+        }
+
+        async fn agents(app: Router, uri: &str) -> Vec<serde_json::Value> {
+            let response = app
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            value["data"].as_array().unwrap().clone()
+        }
+
+        let app = router(storage);
+        // 默认不返回 sparkline
+        for agent in agents(app.clone(), "/api/agents").await {
+            assert!(agent.get("sparkline").is_none(), "{}", agent);
+        }
+
+        let listed = agents(
+            app.clone(),
+            "/api/agents?include=sparkline&sparkline_points=3",
+        )
+        .await;
+        let sparklines: Vec<_> = listed
+            .iter()
+            .map(|agent| (agent["agent_id"].clone(), agent["sparkline"].clone()))
+            .collect();
+        assert_eq!(
+            sparklines,
+            [
+                ("agent-a".into(), serde_json::json!([12.0, 13.0, 14.0])),
+                ("agent-b".into(), serde_json::json!([52.0, 53.0, 54.0])),
+            ]
+        );
+
+        // 点数超过上限时截断，而不是拒绝
+        let listed = agents(
+            app.clone(),
+            "/api/agents?include=sparkline&sparkline_points=100000",
+        )
+        .await;
+        assert_eq!(listed[0]["sparkline"].as_array().unwrap().len(), 5);
+        assert_eq!(
+            status_of(app, "/api/agents?include=disks").await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_threshold_scales_with_cadence() {
+        let storage = Arc::new(Storage::new());
+        let (tx, _) = broadcast::channel(16);
+        let state = api_state(storage.clone(), tx, ApiConfig::default());
+        let now = current_timestamp_ms();
+        // agent-slow 每分钟上报一次，agent-fast 只有一条样本；两者最新样本都在 1 分钟前
+        for i in (1..=5).rev() {
+            let sample = MetricsRequest {
+                agent_id: "agent-slow".to_string(),
+                timestamp: now - i * 60_000,
+                ..Default::default()
+            };
+            state.cadences.observe(&sample);
+            storage.save_metrics(&sample).await;
+        }
+        storage
+            .save_metrics(&MetricsRequest {
+                agent_id: "agent-fast".to_string(),
+                timestamp: now - 60_000,
+                ..Default::default()
+            })
+            .await;
+
+        let app = create_router(state);
+        let response = app
+            .clone()
+            .oneshot(Request::get("/api/agents").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let intervals: Vec<_> = value["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|agent| agent["expected_interval_ms"].clone())
+            .collect();
+        assert_eq!(intervals, [serde_json::Value::Null, 60_000.into()]);
+
+        // 1 分钟未上报对每分钟上报的 Agent 仍在 3 个间隔之内
+        let response = app
+            .oneshot(
+                Request::get("/api/agents?status=offline")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["data"].as_array().unwrap().len(), 1);
+        assert_eq!(value["data"][0]["agent_id"], "agent-fast");
+        assert_eq!(
+            AgentStatus::of(now - 200_000, now, Some(60_000)),
+            AgentStatus::Offline
+        );
+    }
+
+    #[tokio::test]
+    async fn test_agent_list_reports_ingest_lag() {
+        let storage = Arc::new(Storage::new());
+        let (tx, _) = broadcast::channel(16);
+        let state = api_state(storage.clone(), tx, ApiConfig::default());
+        // 样本在 5 秒前采集，现在才到达
+        let sample = MetricsRequest {
+            agent_id: "agent-late".to_string(),
+            timestamp: current_timestamp_ms() - 5_000,
+            ..Default::default()
+        };
+        state.lags.observe(&sample);
+        storage.save_metrics(&sample).await;
+
+        let response = create_router(state)
+            .oneshot(Request::get("/api/agents").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let lag = &value["data"][0]["ingest_lag"];
+        let latest = lag["latest_ms"].as_i64().unwrap();
+        assert!((5_000..6_000).contains(&latest), "{}", lag);
+        assert_eq!(lag["avg_ms"].as_f64().unwrap(), latest as f64);
+    }
+
+    #[tokio::test]
+    async fn test_agent_info() {
+        use common::proto::SystemMetrics;
+
+        let storage = Arc::new(Storage::new());
+        storage
+            .save_metrics(&MetricsRequest {
+                agent_id: "agent-1".to_string(),
+                timestamp: 5000,
+                hostname: "web-01".to_string(),
+                labels: [("env".to_string(), "prod".to_string())].into(),
+                system: Some(SystemMetrics {
+                    system_info: Some(SystemInfo {
+                        os_name: "Ubuntu".to_string(),
+                        os_version: "24.04".to_string(),
+                        kernel_version: "6.8.0-45-generic".to_string(),
+                        arch: "x86_64".to_string(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .await;
+
+        let app = router(storage);
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/api/agents/agent-1/info")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let data = &value["data"];
+        assert_eq!(data["last_seen"], 5000);
+        assert_eq!(data["hostname"], "web-01");
+        assert_eq!(data["labels"], serde_json::json!({"env": "prod"}));
+        assert_eq!(data["system_info"]["os_name"], "Ubuntu");
+        assert_eq!(data["system_info"]["kernel_version"], "6.8.0-45-generic");
+        assert_eq!(data["system_info"]["arch"], "x86_64");
+        // 只返回静态信息，不含指标
+        assert!(data.get("system").is_none());
+
+        assert_eq!(
+            status_of(app, "/api/agents/missing/info").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_agent_health() {
+        use common::proto::{CpuMetrics, MemoryMetrics, SystemMetrics};
+
+        let storage = Arc::new(Storage::new());
+        storage
+            .save_metrics(&MetricsRequest {
+                agent_id: "agent-1".to_string(),
+                timestamp: 5000,
+                system: Some(SystemMetrics {
+                    cpu: Some(CpuMetrics {
+                        usage_percent: 20.0,
+                        core_count: 4,
+                        load_avg_1: 2.0,
+                        ..Default::default()
+                    }),
+                    memory: Some(MemoryMetrics {
+                        usage_percent: 50.0,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .await;
+
+        let (tx, _) = broadcast::channel(16);
+        let app = create_router(api_state(
+            storage,
+            tx,
+            ApiConfig {
+                health_weights: "cpu=0.4,memory=0.3,disk=0.2,load=0.1".parse().unwrap(),
+                ..Default::default()
+            },
+        ));
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/api/agents/agent-1/health")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let data = &value["data"];
+        assert_eq!(data["timestamp"], 5000);
+        // 没有磁盘，其余维度按 0.8 重新归一化: (0.4×80 + 0.3×50 + 0.1×50) / 0.8 = 65
+        assert!((data["score"].as_f64().unwrap() - 65.0).abs() < 1e-9);
+        assert_eq!(data["missing"], serde_json::json!(["disk"]));
+        assert_eq!(data["dimensions"][0]["dimension"], "cpu");
+        assert_eq!(data["dimensions"][0]["headroom"], 80.0);
+        assert!((data["dimensions"][0]["weight"].as_f64().unwrap() - 0.5).abs() < 1e-9);
+
+        assert_eq!(
+            status_of(app, "/api/agents/missing/health").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_available_fields() {
+        use common::proto::{CpuMetrics, GpuMetrics, MemoryMetrics, SystemMetrics};
+
+        let storage = Arc::new(Storage::new());
+        // 没有磁盘与进程数据的 Agent
+        storage
+            .save_metrics(&MetricsRequest {
+                agent_id: "agent-1".to_string(),
+                timestamp: 5000,
+                system: Some(SystemMetrics {
+                    cpu: Some(CpuMetrics::default()),
+                    memory: Some(MemoryMetrics::default()),
+                    gpu: vec![GpuMetrics {
+                        index: 0,
+                        temperature: 61.0,
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .await;
+
+        let app = router(storage);
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/api/agents/agent-1/available-fields")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let data = &value["data"];
+        assert_eq!(data["timestamp"], 5000);
+        assert_eq!(data["cpu"], true);
+        assert_eq!(data["memory"], true);
+        assert_eq!(data["gpu"], serde_json::json!([0]));
+        assert_eq!(data["thermal"], true);
+        assert_eq!(data["disks"], serde_json::json!([]));
+        assert_eq!(data["processes"], false);
+        assert_eq!(data["network"], false);
+
+        assert_eq!(
+            status_of(app, "/api/agents/missing/available-fields").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_partial_sample_sections_read_back_as_absent() {
+        use common::proto::{CpuMetrics, DiskMetrics, MemoryMetrics, SystemMetrics};
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("test.db")
+            .to_string_lossy()
+            .to_string();
+        let cpu_only = |timestamp: i64, cpu: f64| MetricsRequest {
+            agent_id: "agent-1".to_string(),
+            timestamp,
+            system: Some(SystemMetrics {
+                cpu: Some(CpuMetrics {
+                    usage_percent: cpu,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        {
+            let storage = Storage::with_config(StorageConfig {
+                db_path: Some(db_path.clone()),
+                ..Default::default()
+            });
+            // 完整样本与只含 CPU 的样本交替
+            for i in 0..4 {
+                let timestamp = 1_000 + i * 1_000;
+                let mut sample = cpu_only(timestamp, 10.0 * (i + 1) as f64);
+                if i % 2 == 0 {
+                    let system = sample.system.as_mut().unwrap();
+                    system.memory = Some(MemoryMetrics {
+                        usage_percent: 40.0 + i as f64 * 10.0,
+                        ..Default::default()
+                    });
+                    system.disks = vec![DiskMetrics {
+                        mount_point: "/".to_string(),
+                        usage_percent: 70.0,
+                        ..Default::default()
+                    }];
+                }
+                storage.save_metrics(&sample).await;
+            }
+            storage.shutdown().await.unwrap();
+        }
+
+        // 重新打开后从持久化层读回，未上报的分区仍为缺省而不是 0
+        let storage = Arc::new(Storage::with_config(StorageConfig {
+            db_path: Some(db_path),
+            ..Default::default()
+        }));
+        let latest = storage.get_agent_latest("agent-1").await.unwrap();
+        assert_eq!(latest, cpu_only(4_000, 40.0));
+
+        let app = router(storage.clone());
+        let get = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+
+        let history = get("/api/agents/agent-1/metrics/history").await;
+        let system = &history["data"][1]["system"];
+        assert_eq!(system["cpu"]["usage_percent"], 20.0);
+        assert!(system["memory"].is_null(), "{}", system);
+        assert_eq!(system["disks"], serde_json::json!([]));
+
+        // 重采样只在上报了该分区的样本之间平均
+        let resampled = get("/api/agents/agent-1/metrics/history?points=1").await;
+        let system = &resampled["data"][0]["system"];
+        assert_eq!(system["cpu"]["usage_percent"], 25.0);
+        assert_eq!(system["memory"]["usage_percent"], 50.0);
+        assert_eq!(system["disks"][0]["usage_percent"], 70.0);
+
+        let fields = get("/api/agents/agent-1/available-fields").await;
+        assert_eq!(fields["data"]["cpu"], true);
+        assert_eq!(fields["data"]["memory"], false);
+        assert_eq!(fields["data"]["disks"], serde_json::json!([]));
+        let fields = get("/api/agents/agent-1/available-fields?timestamp=3000").await;
+        assert_eq!(fields["data"]["timestamp"], 3000);
+        assert_eq!(fields["data"]["memory"], true);
+        assert_eq!(fields["data"]["disks"], serde_json::json!(["/"]));
+        assert_eq!(
+            status_of(app, "/api/agents/agent-1/available-fields?timestamp=3500").await,
+            StatusCode::NOT_FOUND
+        );
+        storage.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sparkline() {
+        use common::proto::{CpuMetrics, SystemMetrics};
+
+        let storage = Arc::new(Storage::new());
+        for ts in 0..100 {
+            storage
+                .save_metrics(&MetricsRequest {
+                    agent_id: "agent-1".to_string(),
+                    timestamp: ts * 1000,
+                    system: Some(SystemMetrics {
+                        cpu: Some(CpuMetrics {
+                            usage_percent: ts as f64,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .await;
+        }
+
+        let app = router(storage);
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/api/agents/agent-1/sparkline?field=cpu&points=10")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let values = value["data"]["values"].as_array().unwrap();
+        assert_eq!(values.len(), 10);
+        // 最后一个桶为 90..=99 的平均值
+        assert_eq!(values.last().unwrap().as_f64(), Some(94.5));
+        assert_eq!(value["data"]["last_ts"], 99_000);
+
+        assert_eq!(
+            status_of(app.clone(), "/api/agents/agent-1/sparkline?field=bogus").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status_of(app, "/api/agents/agent-1/sparkline").await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_history_limit_clamped() {
+        let storage = Arc::new(Storage::new());
+        for ts in 0..20 {
+            storage
+                .save_metrics(&MetricsRequest {
+                    agent_id: "agent-1".to_string(),
+                    timestamp: ts,
+                    ..Default::default()
+                })
+                .await;
+        }
+
+        let (tx, _) = broadcast::channel(16);
+        let app = create_router(api_state(
+            storage,
+            tx,
+            ApiConfig {
+                max_history_limit: 5,
+                ..Default::default()
+            },
+        ));
+        let response = app
+            .oneshot(
+                Request::get("/api/agents/agent-1/metrics/history?limit=10000000")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let data = value["data"].as_array().unwrap();
+        assert_eq!(data.len(), 5);
+        // 返回最新的 5 条
+        assert_eq!(data[0]["timestamp"], 15);
+        assert!(value["message"].as_str().unwrap().contains("10000000"));
+    }
+
+    #[tokio::test]
+    async fn test_history_points_cover_range_beyond_limit() {
+        let storage = Arc::new(Storage::new());
+        for ts in 0..100 {
+            storage
+                .save_metrics(&MetricsRequest {
+                    agent_id: "agent-1".to_string(),
+                    timestamp: ts * 1000,
+                    ..Default::default()
+                })
+                .await;
+        }
+
+        let (tx, _) = broadcast::channel(16);
+        let app = create_router(api_state(
+            storage,
+            tx,
+            ApiConfig {
+                max_history_limit: 5,
+                ..Default::default()
+            },
+        ));
+        let get = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+
+        // 样本数远超 limit 上限：重采样仍覆盖全部 100 条的时间范围
+        let value = get("/api/agents/agent-1/metrics/history?points=4").await;
+        let data = value["data"].as_array().unwrap();
+        assert_eq!(data.len(), 4);
+        assert_eq!(data[0]["timestamp"], 24_000);
+        assert_eq!(data[3]["timestamp"], 99_000);
+
+        let value = get("/api/agents/agent-1/metrics/history?points=2&start=50000&end=59000").await;
+        let data = value["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0]["timestamp"], 54_000);
+        assert_eq!(data[1]["timestamp"], 59_000);
+    }
+
+    #[tokio::test]
+    async fn test_live_only_history_truncated_at_cache_size() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(Storage::with_config(StorageConfig {
+            // 仅实时模式忽略数据库路径
+            db_path: Some(temp_dir.path().join("test.db").to_string_lossy().into()),
+            cache_size_per_agent: 10,
+            live_only: true,
+            ..Default::default()
+        }));
+        for ts in 0..25 {
+            storage
+                .save_metrics(&MetricsRequest {
+                    agent_id: "agent-1".to_string(),
+                    timestamp: ts,
+                    ..Default::default()
+                })
+                .await;
+        }
+        assert!(!temp_dir.path().join("test.db").exists());
+
+        let history_of = |uri: &'static str| {
+            let app = router(storage.clone());
+            async move {
+                let response = app
+                    .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+
+        // 超出缓存的部分已丢弃：返回缓存上限条数并标记截断
+        let value = history_of("/api/agents/agent-1/metrics/history?limit=100").await;
+        let data = value["data"].as_array().unwrap();
+        assert_eq!(data.len(), 10);
+        assert_eq!(data[0]["timestamp"], 15);
+        assert_eq!(value["truncated"], true);
+
+        // 缓存足够时不标记
+        let value = history_of("/api/agents/agent-1/metrics/history?limit=5").await;
+        assert_eq!(value["data"].as_array().unwrap().len(), 5);
+        assert!(value.get("truncated").is_none());
+
+        // 没有 JSON 外壳的 Protobuf 与导出，以及范围查询，都以响应头标记
+        let header_of = |request: Request<Body>| {
+            let app = router(storage.clone());
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                response
+                    .headers()
+                    .get(HISTORY_TRUNCATED_HEADER)
+                    .map(|value| value.to_str().unwrap().to_string())
+            }
+        };
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let post = |uri: &str, body: &str| {
+            Request::post(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let truncated = [
+            Request::get("/api/agents/agent-1/metrics/history?limit=100")
+                .header(header::ACCEPT, PROTOBUF_CONTENT_TYPE)
+                .body(Body::empty())
+                .unwrap(),
+            get("/api/agents/agent-1/metrics/history?points=3&start=0&end=24"),
+            get("/api/agents/agent-1/metrics/history.ndjson"),
+            get("/api/agents/agent-1/metrics/history.csv"),
+            get("/api/agents/agent-1/metrics/history.influx"),
+            post(
+                "/api/query",
+                r#"{"field":"cpu","agents":["agent-1"],"from":0,"to":100}"#,
+            ),
+            post(
+                "/api/compare",
+                r#"{"field":"cpu","agents":["agent-1"],"from":0,"to":100}"#,
+            ),
+        ];
+        for request in truncated {
+            let uri = request.uri().to_string();
+            assert_eq!(header_of(request).await.as_deref(), Some("true"), "{}", uri);
+        }
+        assert_eq!(
+            header_of(get("/api/agents/agent-1/metrics/history?limit=5")).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prometheus_and_influx_exports_carry_labels() {
+        let storage = Arc::new(Storage::new());
+        let system = Some(common::proto::SystemMetrics {
+            cpu: Some(common::proto::CpuMetrics {
+                usage_percent: 42.0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        storage
+            .save_metrics(&MetricsRequest {
+                agent_id: "agent-1".to_string(),
+                hostname: "host-1".to_string(),
+                timestamp: 1_000,
+                labels: [("env".to_string(), "prod".to_string())].into(),
+                system: system.clone(),
+                ..Default::default()
+            })
+            .await;
+        storage
+            .save_metrics(&MetricsRequest {
+                agent_id: "agent-2".to_string(),
+                hostname: "host-2".to_string(),
+                timestamp: 1_000,
+                system,
+                ..Default::default()
+            })
+            .await;
+
+        let body_of = |uri: &'static str| {
+            let router = router(storage.clone());
+            async move {
+                let response = router
+                    .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        let text = body_of("/metrics").await;
+        assert!(text.contains(
+            "iris_cpu_usage_percent{agent_id=\"agent-1\",hostname=\"host-1\",env=\"prod\"} 42\n"
+        ));
+        assert!(
+            text.contains("iris_cpu_usage_percent{agent_id=\"agent-2\",hostname=\"host-2\"} 42\n")
+        );
+
+        let lines = body_of("/api/agents/agent-1/metrics/history.influx").await;
+        assert!(lines.starts_with("iris_system,agent_id=agent-1,env=prod,hostname=host-1 "));
+
+        let csv = body_of("/api/agents/agent-1/metrics/history.csv").await;
+        assert_eq!(
+            csv.lines().nth(1).unwrap(),
+            "1000,agent-1,host-1,env=prod,42,0,,,,"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_history_ndjson_export() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(Storage::with_config(StorageConfig {
+            db_path: Some(
+                temp_dir
+                    .path()
+                    .join("test.db")
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            ),
+            batch_size: 10,
+            batch_timeout: Duration::from_secs(60),
+            ..Default::default()
+        }));
+        // 20 条落盘，最后 5 条只在缓存中
+        for ts in 0..25 {
+            storage
+                .save_metrics(&MetricsRequest {
+                    agent_id: "agent-1".to_string(),
+                    timestamp: ts,
+                    ..Default::default()
+                })
+                .await;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let response = router(storage)
+            .oneshot(
+                Request::get("/api/agents/agent-1/metrics/history.ndjson")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-ndjson"
+        );
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let timestamps: Vec<i64> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["timestamp"]
+                    .as_i64()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(timestamps, (0..25).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_version_endpoint() {
+        let app = router(Arc::new(Storage::new()));
+        let response = app
+            .clone()
+            .oneshot(Request::get("/api/version").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let data = &value["data"];
+        assert!(!env!("CARGO_PKG_VERSION").is_empty());
+        assert_eq!(data["version"], env!("CARGO_PKG_VERSION"));
+        assert!(!data["git_hash"].as_str().unwrap().is_empty());
+        assert!(data["build_timestamp"].as_u64().unwrap() > 0);
+        assert!(data["rustc_version"].as_str().unwrap().starts_with("rustc"));
+
+        // 根路径返回同一版本
+        let response = app
+            .oneshot(Request::get("/api").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[tokio::test]
+    async fn test_schema_endpoint() {
+        let response = router(Arc::new(Storage::new()))
+            .oneshot(Request::get("/api/schema").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let fields = value["data"].as_array().unwrap();
+        let unit_of = |path: &str| {
+            fields
+                .iter()
+                .find(|f| f["path"] == path)
+                .map(|f| f["unit"].as_str().unwrap().to_string())
+        };
+        assert_eq!(unit_of("system.memory.total").as_deref(), Some("bytes"));
+        assert_eq!(
+            unit_of("system.cpu.usage_percent").as_deref(),
+            Some("percent")
+        );
+        assert_eq!(unit_of("timestamp").as_deref(), Some("milliseconds"));
+    }
+
+    #[tokio::test]
+    async fn test_protobuf_responses() {
+        let storage = Arc::new(Storage::new());
+        for ts in 1..=3 {
+            storage
+                .save_metrics(&MetricsRequest {
+                    agent_id: "agent-1".to_string(),
+                    timestamp: ts,
+                    hostname: "test-host".to_string(),
+                    ..Default::default()
+                })
+                .await;
+        }
+
+        let get = |uri: &str, accept: &str| {
+            Request::get(uri)
+                .header(header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = router(storage.clone())
+            .oneshot(get("/api/agents/agent-1/metrics", "application/x-protobuf"))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            PROTOBUF_CONTENT_TYPE
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let latest = MetricsRequest::decode(body).unwrap();
+        assert_eq!(latest.timestamp, 3);
+        assert_eq!(latest.hostname, "test-host");
+
+        let response = router(storage.clone())
+            .oneshot(get(
+                "/api/agents/agent-1/metrics/history",
+                "application/json;q=0.5, application/x-protobuf",
+            ))
+            .await
+            .unwrap();
+        let mut body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let mut timestamps = Vec::new();
+        while !body.is_empty() {
+            timestamps.push(
+                MetricsRequest::decode_length_delimited(&mut body)
+                    .unwrap()
+                    .timestamp,
+            );
+        }
+        assert_eq!(timestamps, vec![1, 2, 3]);
+
+        // 默认仍返回 JSON
+        let response = router(storage)
+            .oneshot(get("/api/agents/agent-1/metrics", "*/*"))
+            .await
+            .unwrap();
+        assert!(response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("application/json"));
+    }
+
+    async fn post_query(
+        router: Router,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        post_json(router, "/api/query", body).await
+    }
+
+    async fn post_json(
+        router: Router,
+        uri: &str,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let response = router
+            .oneshot(
+                Request::post(uri)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_query_shapes() {
+        use common::proto::{CpuMetrics, SystemMetrics};
+
+        let storage = Arc::new(Storage::new());
+        for (agent_id, offset) in [("agent-1", 0.0), ("agent-2", 100.0)] {
+            for ts in 0..10 {
+                storage
+                    .save_metrics(&MetricsRequest {
+                        agent_id: agent_id.to_string(),
+                        timestamp: ts * 1000,
+                        system: Some(SystemMetrics {
+                            cpu: Some(CpuMetrics {
+                                usage_percent: offset + ts as f64,
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    })
+                    .await;
+            }
+        }
+        let app = router(storage);
+
+        // 原始取值，按时间范围截取
+        let (status, value) = post_query(
+            app.clone(),
+            serde_json::json!({"agents": ["agent-1"], "field": "cpu", "from": 2000, "to": 4000}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let series = value["data"]["series"].as_array().unwrap();
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0]["agent_id"], "agent-1");
+        assert_eq!(
+            series[0]["points"],
+            serde_json::json!([
+                {"ts": 2000, "value": 2.0},
+                {"ts": 3000, "value": 3.0},
+                {"ts": 4000, "value": 4.0}
+            ])
+        );
+
+        // 每个 Agent 分桶取最大值
+        let (status, value) = post_query(
+            app.clone(),
+            serde_json::json!({
+                "field": "cpu", "from": 0, "to": 9999,
+                "aggregation": "max", "bucket_ms": 5000
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let series = value["data"]["series"].as_array().unwrap();
+        assert_eq!(series.len(), 2);
+        assert_eq!(series[1]["agent_id"], "agent-2");
+        assert_eq!(
+            series[1]["points"],
+            serde_json::json!([{"ts": 0, "value": 104.0}, {"ts": 5000, "value": 109.0}])
+        );
+
+        // 跨 Agent 合并：各 Agent 桶内平均后再取平均
+        let (status, value) = post_query(
+            app.clone(),
+            serde_json::json!({
+                "field": "cpu", "from": 0, "to": 9999,
+                "bucket_ms": 5000, "combine": true
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let series = value["data"]["series"].as_array().unwrap();
+        assert_eq!(series.len(), 1);
+        assert!(series[0]["agent_id"].is_null());
+        assert_eq!(
+            series[0]["points"],
+            serde_json::json!([{"ts": 0, "value": 52.0}, {"ts": 5000, "value": 57.0}])
+        );
+
+        // 只指定聚合时整个范围为一个桶
+        let (_, value) = post_query(
+            app.clone(),
+            serde_json::json!({
+                "field": "cpu", "from": 0, "to": 9999,
+                "aggregation": "count", "combine": true
+            }),
+        )
+        .await;
+        assert_eq!(
+            value["data"]["series"][0]["points"],
+            serde_json::json!([{"ts": 0, "value": 20.0}])
+        );
+
+        for (body, expected) in [
+            (serde_json::json!({"field": "bogus"}), "bogus"),
+            (
+                serde_json::json!({"field": "cpu", "aggregation": "median"}),
+                "median",
+            ),
+            (
+                serde_json::json!({"field": "cpu", "agent": "agent-1"}),
+                "agent",
+            ),
+            (
+                serde_json::json!({"field": "cpu", "combine": true}),
+                "combine",
+            ),
+            (
+                serde_json::json!({"field": "cpu", "from": 10, "to": 5}),
+                "from",
+            ),
+            (
+                serde_json::json!({"field": "cpu", "from": 0, "to": 9999, "bucket_ms": 1}),
+                "bucket_ms",
+            ),
+            (
+                serde_json::json!({"field": "load1", "resolution": "hour"}),
+                "resolution",
+            ),
+            (serde_json::json!({"field": "cpu", "tz": "+08:00"}), "tz"),
+            (
+                serde_json::json!({"field": "cpu", "bucket_ms": 1000, "tz": "Mars/Olympus"}),
+                "Mars/Olympus",
+            ),
+        ] {
+            let (status, value) = post_query(app.clone(), body.clone()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+            assert_eq!(value["success"], false);
+            let message = value["message"].as_str().unwrap();
+            assert!(message.contains(expected), "{}: {}", body, message);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compare_aligns_to_coarsest_cadence() {
+        use common::proto::{CpuMetrics, SystemMetrics};
+
+        // 三个 Agent 分别每 1、2、5 秒上报一次，agent-3 的取值整体偏高
+        let storage = Arc::new(Storage::new());
+        for (agent_id, interval, offset) in [
+            ("agent-1", 1000, 0.0),
+            ("agent-2", 2000, 0.0),
+            ("agent-3", 5000, 50.0),
+        ] {
+            for ts in (0..=20_000).step_by(interval) {
+                storage
+                    .save_metrics(&MetricsRequest {
+                        agent_id: agent_id.to_string(),
+                        timestamp: ts as i64,
+                        system: Some(SystemMetrics {
+                            cpu: Some(CpuMetrics {
+                                usage_percent: offset + ts as f64 / 1000.0,
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    })
+                    .await;
+            }
+        }
+        let app = router(storage);
+
+        let (status, value) = post_json(
+            app.clone(),
+            "/api/compare",
+            serde_json::json!({
+                "agents": ["agent-1", "agent-2", "agent-3"],
+                "field": "cpu", "from": 0, "to": 20_000, "bucket_ms": 1000
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let data = &value["data"];
+        // 请求的 1 秒间隔小于 agent-3 的上报间隔，按 5 秒对齐
+        assert_eq!(data["bucket_ms"], 5000);
+        assert_eq!(
+            data["timestamps"],
+            serde_json::json!([0, 5000, 10_000, 15_000, 20_000])
+        );
+        let series = data["series"].as_array().unwrap();
+        assert_eq!(series.len(), 3);
+        for (series, interval) in series.iter().zip([1000, 2000, 5000]) {
+            assert_eq!(series["values"].as_array().unwrap().len(), 5);
+            assert_eq!(series["interval_ms"], interval);
+        }
+        assert_eq!(series[1]["agent_id"], "agent-2");
+        assert_eq!(
+            series[1]["values"],
+            serde_json::json!([0.0, 5.0, 10.0, 15.0, 20.0])
+        );
+        assert_eq!(series[2]["values"][2], 60.0);
+
+        // 时间轴超出样本范围的点为 null，范围内没有样本的 Agent 全部为 null
+        let (_, value) = post_json(
+            app.clone(),
+            "/api/compare",
+            serde_json::json!({
+                "agents": ["agent-1", "agent-2"],
+                "field": "cpu", "from": 10_000, "to": 30_000, "bucket_ms": 10_000
+            }),
+        )
+        .await;
+        let series = &value["data"]["series"];
+        assert_eq!(series[0]["values"], serde_json::json!([10.0, 20.0, null]));
+        let (_, value) = post_json(
+            app.clone(),
+            "/api/compare",
+            serde_json::json!({
+                "agents": ["agent-1"],
+                "field": "cpu", "from": 30_000, "to": 40_000
+            }),
+        )
+        .await;
+        let series = &value["data"]["series"];
+        assert!(series[0]["values"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|value| value.is_null()));
+        assert!(series[0]["interval_ms"].is_null());
+
+        // 不存在的 Agent 返回 404
+        let (status, value) = post_json(
+            app.clone(),
+            "/api/compare",
+            serde_json::json!({"agents": ["agent-1", "missing"], "field": "cpu"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(value["success"], false);
+
+        for body in [
+            serde_json::json!({"agents": [], "field": "cpu"}),
+            serde_json::json!({"agents": ["agent-1"], "field": "cpu", "bucket_ms": 0}),
+            serde_json::json!({"agents": ["agent-1"], "field": "cpu", "combine": true}),
+        ] {
+            let (status, _) = post_json(app.clone(), "/api/compare", body.clone()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compare_folds_all_points_beyond_limit() {
+        use common::proto::{CpuMetrics, SystemMetrics};
+
+        let storage = Arc::new(Storage::new());
+        for ts in (0..=20_000).step_by(1000) {
+            storage
+                .save_metrics(&MetricsRequest {
+                    agent_id: "agent-1".to_string(),
+                    timestamp: ts,
+                    system: Some(SystemMetrics {
+                        cpu: Some(CpuMetrics {
+                            usage_percent: ts as f64 / 1000.0,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .await;
+        }
+        let (tx, _) = broadcast::channel(16);
+        let app = create_router(api_state(
+            storage,
+            tx,
+            ApiConfig {
+                max_history_limit: 5,
+                ..Default::default()
+            },
+        ));
+
+        let (status, value) = post_json(
+            app,
+            "/api/compare",
+            serde_json::json!({"agents": ["agent-1"], "field": "cpu", "from": 0, "to": 20_000}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let data = &value["data"];
+        // 21 个取值折叠为 5 个桶，时间轴不超过 5 个点
+        assert_eq!(data["bucket_ms"], 4001);
+        assert_eq!(data["timestamps"].as_array().unwrap().len(), 5);
+        // 早于最近 5 个取值的时刻同样有值
+        let values = data["series"][0]["values"].as_array().unwrap();
+        let early = values[2].as_f64().unwrap();
+        assert!((early - 8.002).abs() < 1e-9, "{}", early);
+        assert!(value["message"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_compare_extreme_range_stays_bounded() {
+        use common::proto::{CpuMetrics, SystemMetrics};
+
+        let storage = Arc::new(Storage::new());
+        storage
+            .save_metrics(&MetricsRequest {
+                agent_id: "agent-1".to_string(),
+                timestamp: 1000,
+                system: Some(SystemMetrics {
+                    cpu: Some(CpuMetrics {
+                        usage_percent: 10.0,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .await;
+        let (tx, _) = broadcast::channel(16);
+        let app = create_router(api_state(
+            storage,
+            tx,
+            ApiConfig {
+                max_history_limit: 5,
+                ..Default::default()
+            },
+        ));
+
+        // 时间范围长度饱和到 i64::MAX 时，桶宽与时间轴都不能溢出，点数仍受上限约束
+        for (from, to) in [
+            (0, i64::MAX),
+            (i64::MIN, i64::MAX),
+            (i64::MAX - 10, i64::MAX),
+        ] {
+            let (status, value) = post_json(
+                app.clone(),
+                "/api/compare",
+                serde_json::json!({"agents": ["agent-1"], "field": "cpu", "from": from, "to": to}),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK, "[{}, {}]", from, to);
+            let timestamps = value["data"]["timestamps"].as_array().unwrap();
+            assert!(
+                !timestamps.is_empty() && timestamps.len() <= 5,
+                "{:?}",
+                timestamps
+            );
+            assert_eq!(timestamps[0], from);
+            assert!(timestamps.iter().all(|ts| ts.as_i64().unwrap() <= to));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_daily_buckets_in_time_zone() {
+        use common::proto::{CpuMetrics, SystemMetrics};
+        const HOUR_MS: i64 = 3_600_000;
+        const DAY_MS: i64 = 24 * HOUR_MS;
+
+        // 本地（UTC+8）零点为 UTC 前一日 16:00；每 6 小时一个样本，共两天
+        let local_midnight = 20_000 * DAY_MS - 8 * HOUR_MS;
+        let storage = Arc::new(Storage::new());
+        for i in 0..8 {
+            storage
+                .save_metrics(&MetricsRequest {
+                    agent_id: "agent-1".to_string(),
+                    timestamp: local_midnight + i * 6 * HOUR_MS,
+                    system: Some(SystemMetrics {
+                        cpu: Some(CpuMetrics {
+                            usage_percent: if i < 4 { 10.0 } else { 30.0 },
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .await;
+        }
+        let app = router(storage);
+
+        // 从 UTC 零点起查询：按本地零点分桶，第一个桶起点早于 from
+        let (status, value) = post_query(
+            app.clone(),
+            serde_json::json!({
+                "field": "cpu", "from": 20_000 * DAY_MS, "to": local_midnight + 2 * DAY_MS - 1,
+                "bucket_ms": DAY_MS, "tz": "+08:00"
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            value["data"]["series"][0]["points"],
+            serde_json::json!([
+                {"ts": local_midnight, "value": 10.0},
+                {"ts": local_midnight + DAY_MS, "value": 30.0}
+            ])
+        );
+
+        // 缺省按 UTC 时间线从 from 起算，桶跨越本地零点
+        let (_, value) = post_query(
+            app,
+            serde_json::json!({
+                "field": "cpu", "from": 20_000 * DAY_MS - DAY_MS, "to": 20_002 * DAY_MS,
+                "bucket_ms": DAY_MS
+            }),
+        )
+        .await;
+        assert_eq!(
+            value["data"]["series"][0]["points"],
+            serde_json::json!([
+                {"ts": 19_999 * DAY_MS, "value": 10.0},
+                {"ts": 20_000 * DAY_MS, "value": 20.0},
+                {"ts": 20_001 * DAY_MS, "value": 30.0}
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_grafana_search_and_query() {
+        use common::proto::{CpuMetrics, SystemMetrics};
+
+        let storage = Arc::new(Storage::new());
+        for (agent_id, offset) in [("web-01", 0.0), ("web-02", 100.0)] {
+            for ts in 0..10 {
+                storage
+                    .save_metrics(&MetricsRequest {
+                        agent_id: agent_id.to_string(),
+                        timestamp: ts * 1000,
+                        system: Some(SystemMetrics {
+                            cpu: Some(CpuMetrics {
+                                usage_percent: offset + ts as f64,
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    })
+                    .await;
+            }
+        }
+        let app = router(storage);
+
+        assert_eq!(status_of(app.clone(), "/grafana").await, StatusCode::OK);
+
+        let (status, value) = post_json(
+            app.clone(),
+            "/grafana/search",
+            serde_json::json!({"target": "web-02:c"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(value, serde_json::json!(["web-02:cpu"]));
+        let (_, value) = post_json(app.clone(), "/grafana/search", serde_json::json!({})).await;
+        let targets = value.as_array().unwrap();
+        assert!(targets.contains(&serde_json::json!("memory")));
+        assert!(targets.contains(&serde_json::json!("web-01:load1")));
+
+        // Grafana 发送 ISO 时间，数据点为 [取值, 毫秒时间戳]
+        let (status, value) = post_json(
+            app.clone(),
+            "/grafana/query",
+            serde_json::json!({
+                "range": {"from": "1970-01-01T00:00:00.000Z", "to": "1970-01-01T00:00:09.999Z"},
+                "intervalMs": 5000,
+                "maxDataPoints": 100,
+                "targets": [
+                    {"target": "web-01:cpu", "refId": "A"},
+                    {"target": "web-02:cpu", "refId": "B", "hide": true}
+                ]
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            value,
+            serde_json::json!([
+                {"target": "web-01:cpu", "datapoints": [[2.0, 0], [7.0, 5000]]}
+            ])
+        );
+
+        // 只写指标名时返回全部 Agent；maxDataPoints 限制桶数
+        let (_, value) = post_json(
+            app.clone(),
+            "/grafana/query",
+            serde_json::json!({
+                "range": {"from": 0, "to": 9999},
+                "intervalMs": 1000,
+                "maxDataPoints": 1,
+                "targets": [{"target": "cpu"}]
+            }),
+        )
+        .await;
+        assert_eq!(
+            value,
+            serde_json::json!([
+                {"target": "web-01:cpu", "datapoints": [[4.5, 0]]},
+                {"target": "web-02:cpu", "datapoints": [[104.5, 0]]}
+            ])
+        );
+
+        let (status, value) = post_json(
+            app,
+            "/grafana/query",
+            serde_json::json!({
+                "range": {"from": 0, "to": 9999},
+                "targets": [{"target": "web-01:bogus"}]
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(value["message"].as_str().unwrap().contains("bogus"));
+    }
+
+    #[tokio::test]
+    async fn test_query_hourly_series_survives_retention() {
+        use crate::storage::rollup::HOUR_MS;
+        use common::proto::{CpuMetrics, SystemMetrics};
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("test.db")
+            .to_str()
+            .unwrap()
+            .to_string();
+        // 三天前起连续两小时的原始样本，早于一天的保留期
+        let base = (current_timestamp_ms() - 3 * 86_400_000) / HOUR_MS * HOUR_MS;
+
+        {
+            let storage = Storage::with_config(StorageConfig {
+                db_path: Some(db_path.clone()),
+                ..Default::default()
+            });
+            for (offset, cpu) in [(0, 10.0), (60_000, 30.0), (HOUR_MS, 50.0)] {
+                storage
+                    .save_metrics(&MetricsRequest {
+                        agent_id: "agent-1".to_string(),
+                        timestamp: base + offset,
+                        system: Some(SystemMetrics {
+                            cpu: Some(CpuMetrics {
+                                usage_percent: cpu,
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    })
+                    .await;
+            }
+            storage.shutdown().await.unwrap();
+        }
+
+        // 重新打开并启用清理：启动后立即执行一轮，先生成小时汇总再删除过期原始数据
+        let storage = Arc::new(Storage::with_config(StorageConfig {
+            db_path: Some(db_path),
+            enable_cleanup: true,
+            retention_days: 1,
+            ..Default::default()
+        }));
+        let range = |storage: Arc<Storage>| async move {
+            storage.get_agent_range("agent-1", 0, i64::MAX).await.len()
+        };
+        for _ in 0..100 {
+            if range(storage.clone()).await == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(range(storage.clone()).await, 0);
+
+        let (status, value) = post_query(
+            router(storage.clone()),
+            serde_json::json!({
+                "agents": ["agent-1"], "field": "cpu", "resolution": "hour",
+                "from": base, "to": base + 2 * HOUR_MS
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            value["data"]["series"][0]["points"],
+            serde_json::json!([
+                {"ts": base, "value": 20.0},
+                {"ts": base + HOUR_MS, "value": 50.0}
+            ])
+        );
+        storage.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_agent_events_timeline() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("events.redb")
+            .to_string_lossy()
+            .to_string();
+        let detector = crate::reboot::RebootDetector::default();
+        {
+            let storage = Storage::with_config(StorageConfig {
+                db_path: Some(db_path.clone()),
+                ..Default::default()
+            });
+            for (timestamp, boot_id) in [(1_000, "boot-a"), (2_000, "boot-a"), (3_000, "boot-b")] {
+                let sample = MetricsRequest {
+                    agent_id: "agent-1".to_string(),
+                    timestamp,
+                    system: Some(common::proto::SystemMetrics {
+                        system_info: Some(SystemInfo {
+                            boot_id: boot_id.to_string(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                };
+                if let Some(event) = detector.observe(&sample) {
+                    storage.record_event("agent-1", event).await;
+                }
+                storage.save_metrics(&sample).await;
+            }
+            storage
+                .record_event(
+                    "agent-1",
+                    AgentEvent {
+                        kind: AgentEventKind::AlertFired,
+                        timestamp: 2_500,
+                        details: "cpu > 90%".to_string(),
+                    },
+                )
+                .await;
+            storage.shutdown().await.unwrap();
+        }
+
+        // 重新打开后事件仍在，按时间升序返回
+        let storage = Arc::new(Storage::with_config(StorageConfig {
+            db_path: Some(db_path),
+            ..Default::default()
+        }));
+        let app = router(storage.clone());
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/api/agents/agent-1/events")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let events: Vec<_> = value["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| (event["type"].clone(), event["timestamp"].clone()))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("alert_fired".into(), 2_500.into()),
+                ("reboot".into(), 3_000.into())
+            ]
+        );
+
+        // 时间范围过滤与无效范围
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/api/agents/agent-1/events?start=2600&end=4000")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["data"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            status_of(app, "/api/agents/agent-1/events?start=5&end=1").await,
+            StatusCode::BAD_REQUEST
+        );
+        storage.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_history_endpoints_distinguish_unknown_agent_from_empty_range() {
+        let storage = Arc::new(Storage::new());
+        storage
+            .save_metrics(&MetricsRequest {
+                agent_id: "agent-1".to_string(),
+                timestamp: 1_000,
+                ..Default::default()
+            })
+            .await;
+        let app = router(storage.clone());
+
+        for path in [
+            "metrics/history",
+            "metrics/history.ndjson",
+            "metrics/history.csv",
+            "metrics/history.influx",
+            "sparkline?field=cpu",
+            "disks/forecast",
+            "hostnames",
+            "events",
+        ] {
+            assert_eq!(
+                status_of(app.clone(), &format!("/api/agents/missing/{}", path)).await,
+                StatusCode::NOT_FOUND,
+                "{}",
+                path
+            );
+        }
+
+        // 已知 Agent 在范围内没有数据时返回 200 与空数组
+        for path in ["events?start=5000&end=6000", "disks/forecast"] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::get(format!("/api/agents/agent-1/{}", path))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(value["data"], serde_json::json!([]), "{}", path);
+        }
+    }
+
+    async fn register_with_token(
+        router: Router,
+        token: Option<&str>,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let mut request =
+            Request::post("/api/admin/agents").header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let response = router
+            .oneshot(request.body(Body::from(body.to_string())).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn admin_router(storage: Arc<Storage>, allowlist: Option<Arc<AgentAllowlist>>) -> Router {
+        let (tx, _) = broadcast::channel(16);
+        let mut state = api_state(
+            storage,
+            tx,
+            ApiConfig {
+                admin_token: Some("secret".to_string()),
+                ..Default::default()
+            },
+        );
+        if let Some(allowlist) = allowlist {
+            state.allowlist = allowlist;
+        }
+        create_router(state)
+    }
+
+    #[tokio::test]
+    async fn test_register_agent_requires_admin_token() {
+        let storage = Arc::new(Storage::new());
+        let allowlist = Arc::new(AgentAllowlist::new(Some(Default::default())));
+        let body = serde_json::json!({"agent_id": "agent-new"});
+
+        // 未配置令牌时接口关闭，即使请求带了令牌
+        let (tx, _) = broadcast::channel(16);
+        let mut state = api_state(storage.clone(), tx, ApiConfig::default());
+        state.allowlist = allowlist.clone();
+        let (status, value) =
+            register_with_token(create_router(state), Some("secret"), body.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(value["success"], false);
+
+        let app = admin_router(storage, Some(allowlist.clone()));
+        for token in [None, Some("wrong"), Some("secre"), Some("secret2")] {
+            let (status, _) = register_with_token(app.clone(), token, body.clone()).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{:?}", token);
+        }
+        assert!(!allowlist.is_allowed("agent-new"));
+
+        let (status, _) = register_with_token(app, Some("secret"), body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(allowlist.is_allowed("agent-new"));
+    }
+
+    #[tokio::test]
+    async fn test_register_agent_requires_allowlist() {
+        let storage = Arc::new(Storage::new());
+        let body = serde_json::json!({"agent_id": " agent-new "});
+        let (status, _) = register_with_token(
+            admin_router(storage.clone(), None),
+            Some("secret"),
+            body.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let allowlist = Arc::new(AgentAllowlist::new(Some(Default::default())));
+        let app = admin_router(storage, Some(allowlist.clone()));
+
+        let (status, value) = register_with_token(app.clone(), Some("secret"), body.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(value["data"], "agent-new");
+        assert!(allowlist.is_allowed("agent-new"));
+        // 重复登记仍成功，message 给出提示
+        let (status, value) = register_with_token(app.clone(), Some("secret"), body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(value["message"].is_string());
+
+        let (status, _) = register_with_token(
+            app,
+            Some("secret"),
+            serde_json::json!({"agent_id": "bad\0id"}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_mode_shown_in_agent_list() {
+        let storage = Arc::new(Storage::new());
+        storage
+            .save_metrics(&MetricsRequest {
+                agent_id: "agent-1".to_string(),
+                timestamp: current_timestamp_ms(),
+                ..Default::default()
+            })
+            .await;
+        let app = router(storage);
+        let maintenance_of = |app: Router| async move {
+            let response = app
+                .oneshot(Request::get("/api/agents").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            value["data"][0]["maintenance_until"].clone()
+        };
+        assert!(maintenance_of(app.clone()).await.is_null());
+
+        let (status, value) = post_json(
+            app.clone(),
+            "/api/agents/agent-1/maintenance",
+            serde_json::json!({"duration_secs": 3600}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let until = value["data"].as_i64().unwrap();
+        assert!(until > current_timestamp_ms() + 3_500_000);
+        assert_eq!(maintenance_of(app.clone()).await, until);
+
+        // 时长为 0 时立即结束
+        let (status, value) = post_json(
+            app.clone(),
+            "/api/agents/agent-1/maintenance",
+            serde_json::json!({"duration_secs": 0}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(value["data"].is_null());
+        assert!(maintenance_of(app.clone()).await.is_null());
+
+        let (status, _) = post_json(
+            app.clone(),
+            "/api/agents/agent-unknown/maintenance",
+            serde_json::json!({"duration_secs": 60}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = post_json(
+            app,
+            "/api/agents/agent-1/maintenance",
+            serde_json::json!({"minutes": 60}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
 }
@@ -0,0 +1,304 @@
+//! 以库的形式嵌入 Server
+//!
+//! [`ProbeServer::run`] 会自行安装信号处理并阻塞到进程退出，适合独立部署。需要在自己的
+//! 程序中与其他服务共存时，用 [`ServerBuilder`] 指定存储、监听地址与是否处理信号，
+//! 在调用方的 tokio 运行时上驱动服务，并通过 [`ServerHandle::shutdown`] 主动关闭
+
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::{listen, shutdown_signal, ProbeServer, ServerConfig, ShutdownHooks};
+
+/// 存储方式
+#[derive(Debug, Clone, Default)]
+enum StorageChoice {
+    /// 与 `iris-server` 相同：/var/lib/iris 存在时持久化，否则仅内存
+    #[default]
+    Auto,
+    /// 持久化到指定数据库文件
+    Path(String),
+    /// 仅内存，不持久化
+    Memory,
+}
+
+/// Server 构建器
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// let handle = server::ServerBuilder::new("127.0.0.1:50051")
+///     .memory_only()
+///     .start()
+///     .await?;
+/// // ... 运行其他服务 ...
+/// handle.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    addr: String,
+    config: ServerConfig,
+    storage: StorageChoice,
+    handle_signals: bool,
+    hooks: ShutdownHooks,
+}
+
+impl ServerBuilder {
+    /// `addr` 为 gRPC 监听地址：`ip:port`（支持 IPv6 `[::]:50051`）或 `unix:<path>`
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            config: ServerConfig::default(),
+            storage: StorageChoice::Auto,
+            handle_signals: false,
+            hooks: ShutdownHooks::default(),
+        }
+    }
+
+    /// 使用指定 Server 配置
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// HTTP API 监听地址，未设置时为 gRPC 端口 + 1（gRPC 使用 Unix socket 时必填）
+    pub fn http_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.http_addr = Some(addr);
+        self
+    }
+
+    /// 持久化到指定数据库文件
+    pub fn db_path(mut self, path: impl Into<String>) -> Self {
+        self.storage = StorageChoice::Path(path.into());
+        self
+    }
+
+    /// 仅内存模式，不持久化
+    pub fn memory_only(mut self) -> Self {
+        self.storage = StorageChoice::Memory;
+        self
+    }
+
+    /// 是否在收到 Ctrl+C 或 SIGTERM 时关闭（默认否，由嵌入方自行决定何时关闭）
+    pub fn handle_signals(mut self, enabled: bool) -> Self {
+        self.handle_signals = enabled;
+        self
+    }
+
+    /// 注册关闭钩子：优雅关闭时在关闭 Storage 之前按注册顺序调用，用于落盘内存中的状态
+    ///
+    /// 每个钩子最多运行 [`crate::DEFAULT_HOOK_TIMEOUT`]，需要其他超时时改用
+    /// [`ShutdownHooks::register_with_timeout`]。钩子返回错误、panic 或超时只记录日志，
+    /// 不影响其余钩子与 Storage 关闭
+    pub fn shutdown_hook<F, Fut>(self, name: impl Into<String>, hook: F) -> Self
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.hooks.register(name, hook);
+        self
+    }
+
+    /// 使用已有的关闭钩子注册表，Server 启动后仍可通过它继续注册
+    ///
+    /// 替换构建器当前的注册表，需在 [`Self::shutdown_hook`] 之前调用
+    pub fn shutdown_hooks(mut self, hooks: ShutdownHooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// 绑定监听地址并创建 ProbeServer
+    async fn bind(
+        self,
+    ) -> Result<(
+        ProbeServer,
+        listen::GrpcListener,
+        tokio::net::TcpListener,
+        bool,
+    )> {
+        let grpc_listener = listen::GrpcListener::bind(&self.addr)?;
+        let http_addr = grpc_listener.http_addr(self.config.http_addr)?;
+        let mut server = match self.storage {
+            StorageChoice::Auto => ProbeServer::from_config(self.config)?,
+            StorageChoice::Path(path) => ProbeServer::persistent(&path, self.config)?,
+            StorageChoice::Memory => ProbeServer::in_memory(self.config)?,
+        };
+        server.hooks = self.hooks;
+        let http_listener = tokio::net::TcpListener::bind(http_addr)
+            .await
+            .with_context(|| format!("无法绑定 HTTP 地址 {}", http_addr))?;
+        Ok((server, grpc_listener, http_listener, self.handle_signals))
+    }
+
+    /// 运行 Server，直到 `shutdown` 完成（或启用信号处理时收到信号）后优雅关闭
+    ///
+    /// 返回的 future 由调用方驱动；超出 `shutdown_timeout` 时返回错误
+    pub async fn serve_with_shutdown(self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        let (server, grpc_listener, http_listener, handle_signals) = self.bind().await?;
+        let shutdown = async move {
+            if handle_signals {
+                tokio::select! {
+                    _ = shutdown => {}
+                    _ = shutdown_signal() => {}
+                }
+            } else {
+                shutdown.await;
+            }
+        };
+        server.serve(grpc_listener, http_listener, shutdown).await
+    }
+
+    /// 在当前 tokio 运行时上启动 Server，监听地址绑定完成后返回句柄
+    pub async fn start(self) -> Result<ServerHandle> {
+        let (server, grpc_listener, http_listener, handle_signals) = self.bind().await?;
+        let grpc_addr = grpc_listener.local_addr()?;
+        let http_addr = http_listener.local_addr()?;
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let shutdown = async move {
+            let stop = async {
+                let _ = stop_rx.await;
+            };
+            if handle_signals {
+                tokio::select! {
+                    _ = stop => {}
+                    _ = shutdown_signal() => {}
+                }
+            } else {
+                stop.await;
+            }
+        };
+        let task = tokio::spawn(server.serve(grpc_listener, http_listener, shutdown));
+        Ok(ServerHandle {
+            grpc_addr,
+            http_addr,
+            stop: Some(stop_tx),
+            task,
+        })
+    }
+}
+
+/// 已启动的 Server
+///
+/// 丢弃句柄时会触发关闭，但不等待其完成
+#[derive(Debug)]
+pub struct ServerHandle {
+    grpc_addr: String,
+    http_addr: SocketAddr,
+    stop: Option<oneshot::Sender<()>>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl ServerHandle {
+    /// gRPC 实际监听的地址（`ip:port` 或 `unix:<path>`），端口为 0 时为系统分配的端口
+    pub fn grpc_addr(&self) -> &str {
+        &self.grpc_addr
+    }
+
+    /// HTTP API 实际监听的地址
+    pub fn http_addr(&self) -> SocketAddr {
+        self.http_addr
+    }
+
+    /// 优雅关闭并等待完成，超出 `shutdown_timeout` 时返回错误
+    pub async fn shutdown(mut self) -> Result<()> {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        self.wait().await
+    }
+
+    /// 等待 Server 退出（收到信号或服务异常退出），不主动触发关闭
+    pub async fn wait(&mut self) -> Result<()> {
+        (&mut self.task)
+            .await
+            .map_err(|e| anyhow::anyhow!("Server 任务异常退出: {}", e))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::probe_service_client::ProbeServiceClient;
+    use common::proto::HeartbeatRequest;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_builder_starts_and_shuts_down_programmatically() {
+        let handle = ServerBuilder::new("127.0.0.1:0")
+            .http_addr("127.0.0.1:0".parse().unwrap())
+            .memory_only()
+            .start()
+            .await
+            .unwrap();
+        let http_addr = handle.http_addr();
+        assert_ne!(http_addr.port(), 0);
+        let grpc_addr: SocketAddr = handle.grpc_addr().parse().unwrap();
+        assert_ne!(grpc_addr.port(), 0);
+
+        let mut stream = tokio::net::TcpStream::connect(http_addr).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: iris\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 200"));
+
+        let mut client = loop {
+            match ProbeServiceClient::connect(format!("http://{}", handle.grpc_addr())).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        };
+        let response = client
+            .heartbeat(HeartbeatRequest {
+                agent_id: "agent-1".to_string(),
+                timestamp: 0,
+            })
+            .await
+            .unwrap();
+        assert!(response.into_inner().alive);
+
+        tokio::time::timeout(Duration::from_secs(10), handle.shutdown())
+            .await
+            .expect("关闭应在预算内结束")
+            .unwrap();
+        assert!(tokio::net::TcpStream::connect(http_addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_hooks_run_on_shutdown() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let flushed = Arc::new(AtomicBool::new(false));
+        let hooks = ShutdownHooks::default();
+        let handle = ServerBuilder::new("127.0.0.1:0")
+            .http_addr("127.0.0.1:0".parse().unwrap())
+            .memory_only()
+            .shutdown_hooks(hooks.clone())
+            .shutdown_hook("failing", || async { Err(anyhow::anyhow!("落盘失败")) })
+            .start()
+            .await
+            .unwrap();
+        // 启动后仍可通过注册表注册
+        let flag = flushed.clone();
+        hooks.register("flush", move || async move {
+            flag.store(true, Ordering::SeqCst);
+            Ok(())
+        });
+        assert!(!flushed.load(Ordering::SeqCst));
+
+        // 钩子失败不影响关闭结果
+        tokio::time::timeout(Duration::from_secs(10), handle.shutdown())
+            .await
+            .expect("关闭应在预算内结束")
+            .unwrap();
+        assert!(flushed.load(Ordering::SeqCst));
+        assert!(hooks.is_empty());
+    }
+}
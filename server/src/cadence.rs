@@ -0,0 +1,121 @@
+//! 上报间隔估计
+//!
+//! 不同 Agent 的上报间隔不同（`--interval` 可从 1 秒到数分钟），Server 按 agent_id 记录
+//! 最近若干个相邻样本的时间戳差，取中位数作为该 Agent 的预期上报间隔。中位数不受偶发的
+//! 断线空档或重试突发影响，上报不规律的 Agent 也能得到稳定的估计
+
+use common::proto::MetricsRequest;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, PoisonError};
+
+/// 每个 Agent 保留的最近间隔数
+const CADENCE_WINDOW: usize = 32;
+
+/// 给出估计所需的最少间隔数
+const MIN_CADENCE_SAMPLES: usize = 3;
+
+/// 单个 Agent 的间隔状态
+#[derive(Debug, Default)]
+struct CadenceState {
+    /// 最近一次收到的样本时间戳
+    last_ts: i64,
+    /// 最近的相邻样本时间戳差（毫秒），最旧的在前
+    deltas: VecDeque<i64>,
+}
+
+/// 按 agent_id 估计上报间隔
+#[derive(Debug, Default)]
+pub struct CadenceTracker {
+    agents: Mutex<HashMap<String, CadenceState>>,
+}
+
+impl CadenceTracker {
+    /// 记录一条样本的时间戳
+    ///
+    /// 时间戳不晚于上一条的样本（重试、乱序回放）不产生间隔
+    pub fn observe(&self, metrics: &MetricsRequest) {
+        let mut agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(state) = agents.get_mut(&metrics.agent_id) else {
+            agents.insert(
+                metrics.agent_id.clone(),
+                CadenceState {
+                    last_ts: metrics.timestamp,
+                    deltas: VecDeque::new(),
+                },
+            );
+            return;
+        };
+
+        if metrics.timestamp <= state.last_ts {
+            return;
+        }
+        if state.deltas.len() == CADENCE_WINDOW {
+            state.deltas.pop_front();
+        }
+        state.deltas.push_back(metrics.timestamp - state.last_ts);
+        state.last_ts = metrics.timestamp;
+    }
+
+    /// 该 agent_id 的预期上报间隔（毫秒），收到的样本太少时为 None
+    pub fn expected_interval_ms(&self, agent_id: &str) -> Option<i64> {
+        let agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        let deltas = &agents.get(agent_id)?.deltas;
+        if deltas.len() < MIN_CADENCE_SAMPLES {
+            return None;
+        }
+        let mut sorted: Vec<i64> = deltas.iter().copied().collect();
+        sorted.sort_unstable();
+        let mid = sorted.len() / 2;
+        Some(if sorted.len().is_multiple_of(2) {
+            (sorted[mid - 1] + sorted[mid]) / 2
+        } else {
+            sorted[mid]
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(agent_id: &str, timestamp: i64) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: agent_id.to_string(),
+            timestamp,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_regular_1s_samples_estimate_1000ms() {
+        let tracker = CadenceTracker::default();
+        tracker.observe(&sample("agent-1", 10_000));
+        tracker.observe(&sample("agent-1", 11_000));
+        // 间隔太少时不给出估计
+        assert_eq!(tracker.expected_interval_ms("agent-1"), None);
+
+        for i in 2..60 {
+            // 带少量采集抖动
+            tracker.observe(&sample("agent-1", 10_000 + i * 1000 + (i % 3) * 5));
+        }
+        let estimate = tracker.expected_interval_ms("agent-1").unwrap();
+        assert!((990..=1010).contains(&estimate), "{}", estimate);
+        assert_eq!(tracker.expected_interval_ms("agent-2"), None);
+    }
+
+    #[test]
+    fn test_irregular_reporter_uses_median() {
+        let tracker = CadenceTracker::default();
+        let mut ts = 0;
+        for delta in [10_000, 10_000, 600_000, 10_000, 50, 10_000, 10_000] {
+            ts += delta;
+            tracker.observe(&sample("agent-1", ts));
+        }
+        // 重复与乱序的样本不产生间隔
+        tracker.observe(&sample("agent-1", ts));
+        tracker.observe(&sample("agent-1", ts - 5000));
+
+        // 一次 10 分钟的断线与一次重试突发不影响估计
+        assert_eq!(tracker.expected_interval_ms("agent-1"), Some(10_000));
+    }
+}
@@ -0,0 +1,105 @@
+//! 流式连接断开原因
+//!
+//! 流式连接结束时原因只写在日志里，排查时需要翻日志才能区分是 Agent 正常关闭、连接被重置
+//! 还是样本解码失败。这里按 agent_id 在内存中保留最近一次断开的原因与时间，随 Agent 列表
+//! 一起返回，与离线检测互为补充。Server 重启后清空
+
+use common::utils::current_timestamp_ms;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+
+/// 流式连接结束的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectKind {
+    /// Agent 正常结束了流
+    Closed,
+    /// 超过空闲超时未收到样本，Server 主动关闭
+    IdleTimeout,
+    /// Server 正在关闭
+    Shutdown,
+    /// 流出错（连接重置、样本解码失败等）
+    Error,
+}
+
+impl DisconnectKind {
+    /// 用于事件时间线的简短说明
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Closed => "Agent 关闭了流式连接",
+            Self::IdleTimeout => "空闲超时",
+            Self::Shutdown => "Server 关闭",
+            Self::Error => "流式连接出错",
+        }
+    }
+}
+
+/// 最近一次流式连接断开的记录
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StreamDisconnect {
+    pub kind: DisconnectKind,
+    /// 出错时的错误信息，其他方式为 null
+    pub reason: Option<String>,
+    /// 断开时的 Server 时间（毫秒）
+    pub timestamp: i64,
+}
+
+/// 按 agent_id 记录最近一次流式连接断开
+#[derive(Debug, Default)]
+pub struct DisconnectTracker {
+    agents: Mutex<HashMap<String, StreamDisconnect>>,
+}
+
+impl DisconnectTracker {
+    /// 记录一次断开，覆盖之前的记录
+    ///
+    /// 未收到任何合法样本的流不知道属于哪个 Agent，不记录
+    pub fn record(&self, agent_id: &str, kind: DisconnectKind, reason: Option<String>) {
+        if agent_id.is_empty() {
+            return;
+        }
+        let mut agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        agents.insert(
+            agent_id.to_string(),
+            StreamDisconnect {
+                kind,
+                reason,
+                timestamp: current_timestamp_ms(),
+            },
+        );
+    }
+
+    /// 该 agent_id 最近一次流式连接断开，Server 启动以来没有断开过时为 None
+    pub fn last(&self, agent_id: &str) -> Option<StreamDisconnect> {
+        let agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        agents.get(agent_id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latest_disconnect_kept() {
+        let tracker = DisconnectTracker::default();
+        tracker.record("", DisconnectKind::Closed, None);
+        assert_eq!(tracker.last(""), None);
+
+        tracker.record(
+            "agent-1",
+            DisconnectKind::Error,
+            Some("connection reset".to_string()),
+        );
+        let last = tracker.last("agent-1").unwrap();
+        assert_eq!(last.kind, DisconnectKind::Error);
+        assert_eq!(last.reason.as_deref(), Some("connection reset"));
+
+        tracker.record("agent-1", DisconnectKind::Closed, None);
+        let last = tracker.last("agent-1").unwrap();
+        assert_eq!(last.kind, DisconnectKind::Closed);
+        assert_eq!(last.reason, None);
+        assert_eq!(tracker.last("agent-2"), None);
+    }
+}
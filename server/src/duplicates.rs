@@ -0,0 +1,139 @@
+//! agent_id 冲突检测
+//!
+//! agent_id 由主机名生成，两台同名主机会把指标写到同一个 agent_id 下。
+//! 这里按 agent_id 记录近期样本中出现过的机器标识（`SystemInfo.machine_id`），
+//! 窗口内出现多个不同标识即视为冲突
+
+use common::proto::MetricsRequest;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// 判定冲突的时间窗口：窗口内出现过的机器标识才参与比较
+pub const DUPLICATE_WINDOW: Duration = Duration::from_secs(300);
+
+/// agent_id 冲突检测器
+#[derive(Debug)]
+pub struct DuplicateDetector {
+    window: Duration,
+    /// agent_id -> (machine_id -> 最近一次出现时间)
+    seen: Mutex<HashMap<String, HashMap<String, Instant>>>,
+}
+
+impl Default for DuplicateDetector {
+    fn default() -> Self {
+        Self::new(DUPLICATE_WINDOW)
+    }
+}
+
+impl DuplicateDetector {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 记录一条样本的机器标识，返回该 agent_id 当前是否存在冲突
+    ///
+    /// 样本不带机器标识（旧版 Agent 或无法读取）时不参与检测
+    pub fn observe(&self, metrics: &MetricsRequest) -> bool {
+        let machine_id = metrics
+            .system
+            .as_ref()
+            .and_then(|s| s.system_info.as_ref())
+            .map(|info| info.machine_id.as_str())
+            .unwrap_or_default();
+        self.observe_at(&metrics.agent_id, machine_id, Instant::now())
+    }
+
+    fn observe_at(&self, agent_id: &str, machine_id: &str, now: Instant) -> bool {
+        if machine_id.is_empty() {
+            return self.is_duplicate_at(agent_id, now);
+        }
+
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        let machines = seen.entry(agent_id.to_string()).or_default();
+        machines.retain(|_, last| now.duration_since(*last) < self.window);
+        let is_new = machines.insert(machine_id.to_string(), now).is_none();
+
+        let duplicate = machines.len() > 1;
+        // 只在新标识加入时告警，避免冲突期间每条样本都刷日志
+        if duplicate && is_new {
+            warn!(
+                "duplicate_agent_id: Agent {} 在 {:?} 内收到来自 {} 台不同机器的样本（machine_id: {}），请为这些主机设置不同的 IRIS_HOSTNAME",
+                agent_id,
+                self.window,
+                machines.len(),
+                machines.keys().cloned().collect::<Vec<_>>().join(", ")
+            );
+        }
+        duplicate
+    }
+
+    /// 该 agent_id 当前是否存在冲突
+    pub fn is_duplicate(&self, agent_id: &str) -> bool {
+        self.is_duplicate_at(agent_id, Instant::now())
+    }
+
+    fn is_duplicate_at(&self, agent_id: &str, now: Instant) -> bool {
+        let seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        seen.get(agent_id).is_some_and(|machines| {
+            machines
+                .values()
+                .filter(|last| now.duration_since(**last) < self.window)
+                .count()
+                > 1
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::{SystemInfo, SystemMetrics};
+
+    fn sample(agent_id: &str, machine_id: &str) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: agent_id.to_string(),
+            system: Some(SystemMetrics {
+                system_info: Some(SystemInfo {
+                    machine_id: machine_id.to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_two_fingerprints_flag_duplicate() {
+        let detector = DuplicateDetector::default();
+        assert!(!detector.observe(&sample("agent-web", "machine-a")));
+        assert!(!detector.observe(&sample("agent-web", "machine-a")));
+        assert!(!detector.is_duplicate("agent-web"));
+
+        assert!(detector.observe(&sample("agent-web", "machine-b")));
+        assert!(detector.is_duplicate("agent-web"));
+
+        // 其他 agent 与不带标识的样本不受影响
+        assert!(!detector.observe(&sample("agent-db", "machine-c")));
+        assert!(!detector.is_duplicate("agent-db"));
+        assert!(detector.observe(&sample("agent-web", "")));
+    }
+
+    #[test]
+    fn test_duplicate_clears_after_window() {
+        let detector = DuplicateDetector::new(Duration::from_secs(60));
+        let start = Instant::now();
+        detector.observe_at("agent-web", "machine-a", start);
+        assert!(detector.observe_at("agent-web", "machine-b", start + Duration::from_secs(10)));
+
+        // machine-a 超出窗口后只剩一个标识
+        let later = start + Duration::from_secs(65);
+        assert!(!detector.observe_at("agent-web", "machine-b", later));
+        assert!(!detector.is_duplicate_at("agent-web", later));
+    }
+}
@@ -0,0 +1,107 @@
+//! 实时推送事件
+//!
+//! 每条指标只序列化一次 JSON，所有 SSE 订阅者共享同一份 `Arc<str>`
+
+use common::proto::MetricsRequest;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+/// 推送给前端的指标事件
+#[derive(Debug, Clone)]
+pub struct MetricsEvent {
+    /// 来源 Agent，用于按 Agent 过滤订阅
+    pub agent_id: Arc<str>,
+    /// 预先序列化好的 MetricsRequest JSON
+    pub json: Arc<str>,
+}
+
+impl MetricsEvent {
+    /// 序列化指标生成事件
+    pub fn from_metrics(metrics: &MetricsRequest) -> serde_json::Result<Self> {
+        Ok(Self {
+            agent_id: Arc::from(metrics.agent_id.as_str()),
+            json: Arc::from(serde_json::to_string(metrics)?),
+        })
+    }
+}
+
+/// 一次广播的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublishOutcome {
+    /// 已送达的订阅者数
+    Delivered(usize),
+    /// 没有订阅者（或发送时已全部断开），未发送
+    NoSubscribers,
+    /// 指标序列化失败，未发送
+    Failed,
+}
+
+/// 广播指标给所有订阅者，返回广播结果供接收统计记录
+///
+/// 没有订阅者时跳过序列化
+pub fn publish(tx: &broadcast::Sender<MetricsEvent>, metrics: &MetricsRequest) -> PublishOutcome {
+    if tx.receiver_count() == 0 {
+        return PublishOutcome::NoSubscribers;
+    }
+
+    match MetricsEvent::from_metrics(metrics) {
+        // 检查后订阅者可能恰好全部断开，此时发送失败
+        Ok(event) => tx
+            .send(event)
+            .map_or(PublishOutcome::NoSubscribers, PublishOutcome::Delivered),
+        Err(e) => {
+            warn!("Agent {} 指标序列化失败: {}", metrics.agent_id, e);
+            PublishOutcome::Failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(agent_id: &str) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: agent_id.to_string(),
+            timestamp: 1000,
+            hostname: "test-host".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_share_single_serialization() {
+        let (tx, mut rx1) = broadcast::channel(16);
+        let mut rx2 = tx.subscribe();
+        let mut rx3 = tx.subscribe();
+
+        assert_eq!(
+            publish(&tx, &sample("agent-1")),
+            PublishOutcome::Delivered(3)
+        );
+
+        let e1: MetricsEvent = rx1.recv().await.unwrap();
+        let e2 = rx2.recv().await.unwrap();
+        let e3 = rx3.recv().await.unwrap();
+
+        // 所有订阅者拿到的是同一块内存，而不是各自序列化的副本
+        assert!(Arc::ptr_eq(&e1.json, &e2.json));
+        assert!(Arc::ptr_eq(&e1.json, &e3.json));
+        assert_eq!(&*e1.agent_id, "agent-1");
+
+        let decoded: MetricsRequest = serde_json::from_str(&e1.json).unwrap();
+        assert_eq!(decoded, sample("agent-1"));
+    }
+
+    #[test]
+    fn test_publish_without_subscribers_is_noop() {
+        let (tx, rx) = broadcast::channel::<MetricsEvent>(16);
+        drop(rx);
+        assert_eq!(
+            publish(&tx, &sample("agent-1")),
+            PublishOutcome::NoSubscribers
+        );
+        assert_eq!(tx.len(), 0);
+    }
+}
@@ -0,0 +1,501 @@
+//! 指标导出格式
+//!
+//! 把样本格式化为 Prometheus 文本格式（`GET /metrics`，各 Agent 的最新样本）、InfluxDB 行协议
+//! 与 CSV（历史导出），供外部系统直接采集或导入。Agent 上报的 `labels` 随每条指标输出，
+//! 下游可按 env/region/role 等维度分组：与 `agent_id`、`hostname`、`mount_point` 重名或取值
+//! 为空的标签被忽略，没有标签的 Agent 不产生额外的标签
+
+use common::proto::{DiskMetrics, MetricsRequest, SystemMetrics};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Prometheus 文本格式的 Content-Type
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// CSV 表头，`labels` 列为按键排序、以分号连接的 `key=value`，键与值中的 `\`、`;`、`=` 以反斜杠转义
+pub const CSV_HEADER: &str = "timestamp,agent_id,hostname,labels,cpu_usage_percent,load_avg_1,\
+memory_used,memory_total,network_bytes_sent,network_bytes_recv\n";
+
+/// 由 Iris 填写、不能被 Agent 标签覆盖的标签名
+const RESERVED_LABELS: [&str; 3] = ["agent_id", "hostname", "mount_point"];
+
+/// 主机级指标
+struct HostMetric {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&SystemMetrics) -> Option<f64>,
+}
+
+const HOST_METRICS: [HostMetric; 8] = [
+    HostMetric {
+        name: "iris_cpu_usage_percent",
+        kind: "gauge",
+        help: "CPU 使用率（%）",
+        value: |system| system.cpu.as_ref().map(|cpu| cpu.usage_percent),
+    },
+    HostMetric {
+        name: "iris_load1",
+        kind: "gauge",
+        help: "1 分钟负载",
+        value: |system| system.cpu.as_ref().map(|cpu| cpu.load_avg_1),
+    },
+    HostMetric {
+        name: "iris_load5",
+        kind: "gauge",
+        help: "5 分钟负载",
+        value: |system| system.cpu.as_ref().map(|cpu| cpu.load_avg_5),
+    },
+    HostMetric {
+        name: "iris_load15",
+        kind: "gauge",
+        help: "15 分钟负载",
+        value: |system| system.cpu.as_ref().map(|cpu| cpu.load_avg_15),
+    },
+    HostMetric {
+        name: "iris_memory_used_bytes",
+        kind: "gauge",
+        help: "已使用内存（字节）",
+        value: |system| system.memory.as_ref().map(|memory| memory.used as f64),
+    },
+    HostMetric {
+        name: "iris_memory_total_bytes",
+        kind: "gauge",
+        help: "内存总量（字节）",
+        value: |system| system.memory.as_ref().map(|memory| memory.total as f64),
+    },
+    HostMetric {
+        name: "iris_network_sent_bytes_total",
+        kind: "counter",
+        help: "各网卡合计发送字节数",
+        value: |system| system.network.as_ref().map(|net| net.bytes_sent as f64),
+    },
+    HostMetric {
+        name: "iris_network_received_bytes_total",
+        kind: "counter",
+        help: "各网卡合计接收字节数",
+        value: |system| system.network.as_ref().map(|net| net.bytes_recv as f64),
+    },
+];
+
+/// 按挂载点区分的磁盘指标
+struct DiskMetric {
+    name: &'static str,
+    help: &'static str,
+    value: fn(&DiskMetrics) -> f64,
+}
+
+const DISK_METRICS: [DiskMetric; 3] = [
+    DiskMetric {
+        name: "iris_disk_used_bytes",
+        help: "磁盘已使用（字节）",
+        value: |disk| disk.used as f64,
+    },
+    DiskMetric {
+        name: "iris_disk_total_bytes",
+        help: "磁盘总容量（字节）",
+        value: |disk| disk.total as f64,
+    },
+    DiskMetric {
+        name: "iris_disk_usage_percent",
+        help: "磁盘使用率（%）",
+        value: |disk| disk.usage_percent,
+    },
+];
+
+/// Agent 上报的标签（按键排序），跳过保留名与空值
+fn agent_labels(sample: &MetricsRequest) -> BTreeMap<&str, &str> {
+    sample
+        .labels
+        .iter()
+        .filter(|(key, value)| !value.is_empty() && !RESERVED_LABELS.contains(&key.as_str()))
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect()
+}
+
+/// 以 Prometheus 文本格式输出样本中的主要指标，通常每个 Agent 取最新的一条
+///
+/// 不带对应子系统数据的样本不输出该指标，没有任何样本有值的指标不输出 HELP/TYPE
+pub fn prometheus(samples: &[MetricsRequest]) -> String {
+    let labels: Vec<_> = samples.iter().map(prometheus_labels).collect();
+    let mut out = String::new();
+
+    for metric in &HOST_METRICS {
+        let mut values = samples
+            .iter()
+            .zip(&labels)
+            .filter_map(|(sample, labels)| Some((labels, (metric.value)(sample.system.as_ref()?)?)))
+            .peekable();
+        if values.peek().is_none() {
+            continue;
+        }
+        let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(out, "# TYPE {} {}", metric.name, metric.kind);
+        for (labels, value) in values {
+            let _ = writeln!(
+                out,
+                "{}{{{}}} {}",
+                metric.name,
+                labels,
+                prometheus_value(value)
+            );
+        }
+    }
+
+    for metric in &DISK_METRICS {
+        let mut values = samples
+            .iter()
+            .zip(&labels)
+            .flat_map(|(sample, labels)| {
+                let disks = sample
+                    .system
+                    .as_ref()
+                    .map_or(&[][..], |system| &system.disks);
+                disks.iter().map(move |disk| (labels, disk))
+            })
+            .peekable();
+        if values.peek().is_none() {
+            continue;
+        }
+        let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(out, "# TYPE {} gauge", metric.name);
+        for (labels, disk) in values {
+            let _ = writeln!(
+                out,
+                "{}{{{},mount_point=\"{}\"}} {}",
+                metric.name,
+                labels,
+                escape_prometheus_value(&disk.mount_point),
+                prometheus_value((metric.value)(disk))
+            );
+        }
+    }
+    out
+}
+
+/// 单个样本的 Prometheus 标签（不含花括号）：`agent_id`、`hostname` 与 Agent 标签
+///
+/// 标签名中的非法字符替换为下划线，替换后重名或以 `__`（Prometheus 保留）开头的标签被忽略
+fn prometheus_labels(sample: &MetricsRequest) -> String {
+    let mut out = format!(
+        "agent_id=\"{}\",hostname=\"{}\"",
+        escape_prometheus_value(&sample.agent_id),
+        escape_prometheus_value(&sample.hostname)
+    );
+    let mut seen: BTreeSet<String> = RESERVED_LABELS.iter().map(|s| s.to_string()).collect();
+    for (key, value) in agent_labels(sample) {
+        let name = prometheus_label_name(key);
+        if name.starts_with("__") || !seen.insert(name.clone()) {
+            continue;
+        }
+        let _ = write!(out, ",{}=\"{}\"", name, escape_prometheus_value(value));
+    }
+    out
+}
+
+/// 标签名只能由字母、数字与下划线组成且不以数字开头
+fn prometheus_label_name(key: &str) -> String {
+    let mut name: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/// 标签值中的反斜杠、双引号与换行需要转义
+fn escape_prometheus_value(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+fn prometheus_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// 以 InfluxDB 行协议输出一条样本：主机指标一行（`iris_system`），每个挂载点一行（`iris_disk`）
+///
+/// 时间戳为纳秒；不带系统指标的样本没有可写的字段，返回空字符串
+pub fn influx_lines(sample: &MetricsRequest) -> String {
+    let Some(system) = sample.system.as_ref() else {
+        return String::new();
+    };
+    let mut tags = agent_labels(sample);
+    tags.insert("agent_id", &sample.agent_id);
+    tags.insert("hostname", &sample.hostname);
+    let timestamp = sample.timestamp.saturating_mul(1_000_000);
+
+    let mut fields = Vec::new();
+    if let Some(cpu) = &system.cpu {
+        fields.push(format!(
+            "cpu_usage_percent={}",
+            influx_float(cpu.usage_percent)
+        ));
+        fields.push(format!("load1={}", influx_float(cpu.load_avg_1)));
+        fields.push(format!("load5={}", influx_float(cpu.load_avg_5)));
+        fields.push(format!("load15={}", influx_float(cpu.load_avg_15)));
+    }
+    if let Some(memory) = &system.memory {
+        fields.push(format!("memory_used={}", influx_integer(memory.used)));
+        fields.push(format!("memory_total={}", influx_integer(memory.total)));
+    }
+    if let Some(network) = &system.network {
+        fields.push(format!(
+            "network_bytes_sent={}",
+            influx_integer(network.bytes_sent)
+        ));
+        fields.push(format!(
+            "network_bytes_recv={}",
+            influx_integer(network.bytes_recv)
+        ));
+    }
+
+    let mut out = String::new();
+    if !fields.is_empty() {
+        let _ = writeln!(
+            out,
+            "iris_system{} {} {}",
+            influx_tags(&tags),
+            fields.join(","),
+            timestamp
+        );
+    }
+    for disk in &system.disks {
+        let mut tags = tags.clone();
+        tags.insert("mount_point", &disk.mount_point);
+        let _ = writeln!(
+            out,
+            "iris_disk{} used={},total={},usage_percent={} {}",
+            influx_tags(&tags),
+            influx_integer(disk.used),
+            influx_integer(disk.total),
+            influx_float(disk.usage_percent),
+            timestamp
+        );
+    }
+    out
+}
+
+/// 按键排序的标签集（含前导逗号），空值的标签不被行协议接受，直接省略
+fn influx_tags(tags: &BTreeMap<&str, &str>) -> String {
+    let mut out = String::new();
+    for (key, value) in tags.iter().filter(|(_, value)| !value.is_empty()) {
+        let _ = write!(
+            out,
+            ",{}={}",
+            escape_influx_tag(key),
+            escape_influx_tag(value)
+        );
+    }
+    out
+}
+
+/// 标签键与值中的逗号、等号与空格需要转义；行协议不支持换行，以转义的空格代替
+fn escape_influx_tag(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            ',' | '=' | ' ' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' | '\r' => out.push_str("\\ "),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// 行协议的浮点字段不接受 NaN 与无穷，以 0 代替
+fn influx_float(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "0".to_string()
+    }
+}
+
+fn influx_integer(value: u64) -> String {
+    format!("{}i", i64::try_from(value).unwrap_or(i64::MAX))
+}
+
+/// 以 CSV 输出一条样本（一行，字段顺序见 [`CSV_HEADER`]），缺少的子系统留空
+pub fn csv_row(sample: &MetricsRequest) -> String {
+    let labels = agent_labels(sample)
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", escape_csv_label(key), escape_csv_label(value)))
+        .collect::<Vec<_>>()
+        .join(";");
+    let system = sample.system.as_ref();
+    let cpu = system.and_then(|system| system.cpu.as_ref());
+    let memory = system.and_then(|system| system.memory.as_ref());
+    let network = system.and_then(|system| system.network.as_ref());
+
+    let cells = [
+        sample.timestamp.to_string(),
+        escape_csv(&sample.agent_id),
+        escape_csv(&sample.hostname),
+        escape_csv(&labels),
+        cpu.map(|cpu| cpu.usage_percent.to_string())
+            .unwrap_or_default(),
+        cpu.map(|cpu| cpu.load_avg_1.to_string())
+            .unwrap_or_default(),
+        memory
+            .map(|memory| memory.used.to_string())
+            .unwrap_or_default(),
+        memory
+            .map(|memory| memory.total.to_string())
+            .unwrap_or_default(),
+        network
+            .map(|network| network.bytes_sent.to_string())
+            .unwrap_or_default(),
+        network
+            .map(|network| network.bytes_recv.to_string())
+            .unwrap_or_default(),
+    ];
+    let mut row = cells.join(",");
+    row.push('\n');
+    row
+}
+
+/// `labels` 列中标签键与值里的反斜杠、分号与等号加反斜杠转义，使 `key=value;…` 可无歧义地拆分
+fn escape_csv_label(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | ';' | '=') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// 含逗号、双引号或换行的字段加双引号，内部的双引号写两遍（RFC 4180）
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::{CpuMetrics, MemoryMetrics};
+
+    fn sample(agent_id: &str, labels: &[(&str, &str)]) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: agent_id.to_string(),
+            hostname: "host 1".to_string(),
+            timestamp: 1_000,
+            labels: labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            system: Some(SystemMetrics {
+                cpu: Some(CpuMetrics {
+                    usage_percent: 12.5,
+                    ..Default::default()
+                }),
+                memory: Some(MemoryMetrics {
+                    used: 1024,
+                    total: 4096,
+                    ..Default::default()
+                }),
+                disks: vec![DiskMetrics {
+                    mount_point: "/data".to_string(),
+                    used: 10,
+                    total: 100,
+                    usage_percent: 10.0,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_prometheus_propagates_labels() {
+        let text = prometheus(&[
+            sample(
+                "web-1",
+                &[
+                    ("env", "prod"),
+                    ("team-name", "a \"b\""),
+                    ("hostname", "spoofed"),
+                    ("empty", ""),
+                ],
+            ),
+            sample("web-2", &[]),
+        ]);
+
+        assert!(text.contains("# TYPE iris_cpu_usage_percent gauge\n"));
+        assert!(text.contains(
+            "iris_cpu_usage_percent{agent_id=\"web-1\",hostname=\"host 1\",env=\"prod\",\
+             team_name=\"a \\\"b\\\"\"} 12.5\n"
+        ));
+        // 没有标签的 Agent 只有内置标签
+        assert!(
+            text.contains("iris_cpu_usage_percent{agent_id=\"web-2\",hostname=\"host 1\"} 12.5\n")
+        );
+        assert!(text.contains(
+            "iris_disk_used_bytes{agent_id=\"web-2\",hostname=\"host 1\",mount_point=\"/data\"} 10\n"
+        ));
+        assert!(!text.contains("spoofed"));
+        assert!(!text.contains("empty"));
+        // 没有网络数据时不输出网络指标
+        assert!(!text.contains("iris_network"));
+    }
+
+    #[test]
+    fn test_influx_propagates_labels() {
+        let lines = influx_lines(&sample("web-1", &[("region", "eu west"), ("env", "prod")]));
+        let lines: Vec<_> = lines.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "iris_system,agent_id=web-1,env=prod,hostname=host\\ 1,region=eu\\ west \
+                 cpu_usage_percent=12.5,load1=0,load5=0,load15=0,memory_used=1024i,\
+                 memory_total=4096i 1000000000",
+                "iris_disk,agent_id=web-1,env=prod,hostname=host\\ 1,mount_point=/data,\
+                 region=eu\\ west used=10i,total=100i,usage_percent=10 1000000000",
+            ]
+        );
+
+        let plain = influx_lines(&sample("web-2", &[]));
+        assert!(plain.starts_with("iris_system,agent_id=web-2,hostname=host\\ 1 "));
+        assert!(influx_lines(&MetricsRequest::default()).is_empty());
+    }
+
+    #[test]
+    fn test_csv_labels_column() {
+        let row = csv_row(&sample("web-1", &[("role", "db"), ("env", "prod,eu")]));
+        assert!(row.starts_with("1000,web-1,host 1,\"env=prod,eu;role=db\",12.5,0,1024,4096,,\n"));
+        let plain = csv_row(&sample("web-2", &[]));
+        assert!(plain.starts_with("1000,web-2,host 1,,12.5,"));
+        // 键与值中的分隔符转义后仍可按 `;` 与 `=` 拆回原标签
+        let row = csv_row(&sample(
+            "web-3",
+            &[("a;b", "x=y"), ("path", "C:\\tmp;1"), ("z", "k=v;k2=v2")],
+        ));
+        assert!(row.starts_with(
+            "1000,web-3,host 1,a\\;b=x\\=y;path=C:\\\\tmp\\;1;z=k\\=v\\;k2\\=v2,12.5,"
+        ));
+        assert_eq!(
+            plain.trim_end().split(',').count(),
+            CSV_HEADER.trim_end().split(',').count()
+        );
+    }
+}
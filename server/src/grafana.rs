@@ -0,0 +1,262 @@
+//! Grafana SimpleJSON 数据源
+//!
+//! 实现 SimpleJSON 插件约定的 `GET /grafana`（连接测试）、`POST /grafana/search`（列出可选目标）
+//! 与 `POST /grafana/query`（按时间范围取序列），Grafana 无需经过 Prometheus 即可直接读取 Iris。
+//! 目标写作 `<agent_id>:<指标>`（如 `web-01:cpu`），只写指标名时返回全部 Agent；
+//! 序列按 Grafana 给出的 `intervalMs` 与 `maxDataPoints` 分桶取均值，时间戳为毫秒
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::analytics::{Aggregation, MetricField};
+use crate::api::{
+    any_history_evicted, fold_agent_points, mark_truncated, ApiResponse, ApiState, QueryPlan,
+    Resolution,
+};
+use crate::timezone::days_from_civil;
+
+type GrafanaError = (StatusCode, Json<ApiResponse<()>>);
+
+/// `/grafana/search` 请求体
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchRequest {
+    /// 过滤条件：只返回包含该子串的目标，缺省或为空时返回全部
+    #[serde(default)]
+    pub target: String,
+}
+
+/// `/grafana/query` 请求体（只列出用到的字段）
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRequest {
+    pub range: QueryRange,
+    /// Grafana 建议的点间隔（毫秒）
+    pub interval_ms: Option<i64>,
+    /// 面板能显示的最大点数
+    pub max_data_points: Option<usize>,
+    #[serde(default)]
+    pub targets: Vec<QueryTarget>,
+}
+
+/// 查询时间范围
+#[derive(Debug, Deserialize)]
+pub struct QueryRange {
+    pub from: GrafanaTime,
+    pub to: GrafanaTime,
+}
+
+/// Grafana 发送 ISO 8601 字符串，也接受毫秒时间戳
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum GrafanaTime {
+    Millis(i64),
+    Iso(String),
+}
+
+impl GrafanaTime {
+    fn millis(&self) -> Result<i64, String> {
+        match self {
+            Self::Millis(ms) => Ok(*ms),
+            Self::Iso(text) => {
+                parse_rfc3339_ms(text).ok_or_else(|| format!("无法解析时间: {}", text))
+            }
+        }
+    }
+}
+
+/// 单个查询目标
+#[derive(Debug, Deserialize)]
+pub struct QueryTarget {
+    #[serde(default)]
+    pub target: String,
+    /// 面板中被隐藏的目标不查询
+    #[serde(default)]
+    pub hide: bool,
+}
+
+/// 返回给 Grafana 的一条时间序列
+#[derive(Debug, Serialize, PartialEq)]
+pub struct TimeSeries {
+    pub target: String,
+    /// `[取值, 毫秒时间戳]`，按时间升序
+    pub datapoints: Vec<(f64, i64)>,
+}
+
+/// 连接测试：SimpleJSON 插件保存数据源时请求，返回 200 即可
+pub async fn test_connection() -> StatusCode {
+    StatusCode::OK
+}
+
+/// 列出可查询的目标：每个指标名，以及每个 Agent 的 `<agent_id>:<指标>`
+pub async fn search(State(state): State<Arc<ApiState>>, body: Bytes) -> Json<Vec<String>> {
+    // 插件在未输入内容时可能发送空请求体
+    let request: SearchRequest = serde_json::from_slice(&body).unwrap_or_default();
+    let agents = state.storage.get_all_agents().await;
+
+    let fields = MetricField::ALL.map(MetricField::name);
+    let targets: Vec<String> = fields
+        .iter()
+        .map(|field| field.to_string())
+        .chain(agents.iter().flat_map(|agent_id| {
+            fields
+                .iter()
+                .map(move |field| format!("{}:{}", agent_id, field))
+        }))
+        .filter(|target| target.contains(request.target.as_str()))
+        .collect();
+
+    info!("Grafana: search 返回 {} 个目标", targets.len());
+    Json(targets)
+}
+
+/// 按时间范围取各目标的序列
+pub async fn query(
+    State(state): State<Arc<ApiState>>,
+    body: Result<Json<QueryRequest>, axum::extract::rejection::JsonRejection>,
+) -> Result<Response, GrafanaError> {
+    let bad_request = |message: String| {
+        info!("Grafana: 拒绝无效查询: {}", message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse::<()>::error(message)),
+        )
+    };
+    let Json(request) = body.map_err(|rejection| bad_request(rejection.body_text()))?;
+    let from = request.range.from.millis().map_err(bad_request)?;
+    let to = request.range.to.millis().map_err(bad_request)?;
+    if from > to {
+        return Err(bad_request(format!("from ({}) 晚于 to ({})", from, to)));
+    }
+
+    // 桶宽取 Grafana 建议的间隔，但桶数不超过面板点数与 Server 的历史查询上限
+    let max_points = state.config.max_history_limit.max(1);
+    let points = request
+        .max_data_points
+        .unwrap_or(max_points)
+        .clamp(1, max_points) as i64;
+    let span = to - from + 1;
+    let bucket_ms = request
+        .interval_ms
+        .unwrap_or(1)
+        .max(1)
+        .max((span + points - 1) / points);
+    let plan = QueryPlan {
+        from,
+        to,
+        buckets: Some((bucket_ms, Aggregation::Avg)),
+        time_zone: None,
+    };
+
+    let all_agents = state.storage.get_all_agents().await;
+    let mut series = Vec::new();
+    let mut queried = Vec::new();
+    for target in request
+        .targets
+        .iter()
+        .filter(|target| !target.hide && !target.target.is_empty())
+    {
+        let (agents, field) = match target.target.rsplit_once(':') {
+            Some((agent_id, field)) => (vec![agent_id.to_string()], field),
+            None => (all_agents.clone(), target.target.as_str()),
+        };
+        let field = MetricField::from_name(field)
+            .ok_or_else(|| bad_request(format!("未知的指标: {}", target.target)))?;
+
+        for agent_id in agents {
+            queried.push(agent_id.clone());
+            let (points, _) =
+                fold_agent_points(&state, &agent_id, field, Resolution::Raw, &plan, max_points)
+                    .await;
+            series.push(TimeSeries {
+                target: format!("{}:{}", agent_id, field.name()),
+                datapoints: points.into_iter().map(|(ts, value)| (value, ts)).collect(),
+            });
+        }
+    }
+
+    info!(
+        "Grafana: 查询 [{}, {}]，桶宽 {} ms，返回 {} 条序列",
+        from,
+        to,
+        bucket_ms,
+        series.len()
+    );
+    let truncated = any_history_evicted(&state.storage, &queried).await;
+    Ok(mark_truncated(Json(series).into_response(), truncated))
+}
+
+/// 解析 RFC 3339 时间（如 `2026-02-15T06:33:44.866Z` 或带 `+08:00` 偏移），返回毫秒时间戳
+fn parse_rfc3339_ms(text: &str) -> Option<i64> {
+    let (date, time) = text.split_once(['T', 't', ' '])?;
+
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: i64 = date_parts.next()?.parse().ok()?;
+    let day: i64 = date_parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // 时区：Z 或 ±HH:MM
+    let (clock, offset_ms) = if let Some(clock) = time.strip_suffix(['Z', 'z']) {
+        (clock, 0)
+    } else {
+        let sign_at = time.rfind(['+', '-'])?;
+        let (clock, offset) = time.split_at(sign_at);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..].split_once(':')?;
+        let offset_min = hours.parse::<i64>().ok()? * 60 + minutes.parse::<i64>().ok()?;
+        (clock, sign * offset_min * 60_000)
+    };
+
+    let (clock, fraction) = clock.split_once('.').unwrap_or((clock, ""));
+    let mut clock_parts = clock.splitn(3, ':');
+    let hour: i64 = clock_parts.next()?.parse().ok()?;
+    let minute: i64 = clock_parts.next()?.parse().ok()?;
+    let second: i64 = clock_parts.next()?.parse().ok()?;
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // 只保留到毫秒
+    let millis = format!("{:0<3}", &fraction[..fraction.len().min(3)])
+        .parse::<i64>()
+        .ok()?;
+
+    let days = days_from_civil(year, month, day);
+    Some(((days * 24 + hour) * 60 + minute) * 60_000 + second * 1000 + millis - offset_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rfc3339_ms() {
+        assert_eq!(parse_rfc3339_ms("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(
+            parse_rfc3339_ms("2026-02-15T06:33:44.866Z"),
+            Some(1_771_137_224_866)
+        );
+        assert_eq!(
+            parse_rfc3339_ms("2026-02-15T14:33:44.866+08:00"),
+            Some(1_771_137_224_866)
+        );
+        assert_eq!(
+            parse_rfc3339_ms("2000-02-29T00:00:00.5Z"),
+            Some(951_782_400_500)
+        );
+        assert_eq!(parse_rfc3339_ms("2026-13-01T00:00:00Z"), None);
+        assert_eq!(parse_rfc3339_ms("now-6h"), None);
+    }
+}
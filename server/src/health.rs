@@ -0,0 +1,274 @@
+//! Agent 健康评分
+//!
+//! 由最新样本中 CPU、内存、磁盘、负载四个维度的余量加权得到 0-100 的综合分数，
+//! 便于在机群视图中一眼找出资源紧张的主机。样本缺少某个维度时该维度不参与计算，
+//! 其余维度的权重按比例放大，分数仍落在 0-100
+
+use common::proto::MetricsRequest;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// 评分维度
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthDimension {
+    /// 100 - CPU 使用率
+    Cpu,
+    /// 100 - 内存使用率
+    Memory,
+    /// 100 - 各挂载点中最高的磁盘使用率
+    Disk,
+    /// 按核心数归一化的 1 分钟负载余量，每核负载达到 1 时为 0
+    Load,
+}
+
+impl fmt::Display for HealthDimension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Cpu => "cpu",
+            Self::Memory => "memory",
+            Self::Disk => "disk",
+            Self::Load => "load",
+        })
+    }
+}
+
+impl HealthDimension {
+    const ALL: [Self; 4] = [Self::Cpu, Self::Memory, Self::Disk, Self::Load];
+
+    /// 该维度的余量（0-100），样本缺少对应指标时为 None
+    fn headroom(self, metrics: &MetricsRequest) -> Option<f64> {
+        let system = metrics.system.as_ref()?;
+        let used = match self {
+            Self::Cpu => system.cpu.as_ref()?.usage_percent,
+            Self::Memory => system.memory.as_ref()?.usage_percent,
+            Self::Disk => system
+                .disks
+                .iter()
+                .map(|disk| disk.usage_percent)
+                .max_by(f64::total_cmp)?,
+            Self::Load => {
+                let cpu = system.cpu.as_ref().filter(|cpu| cpu.core_count > 0)?;
+                cpu.load_avg_1 / cpu.core_count as f64 * 100.0
+            }
+        };
+        Some((100.0 - used).clamp(0.0, 100.0))
+    }
+}
+
+/// 各维度的权重，只有相对大小有意义
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HealthWeights {
+    pub cpu: f64,
+    pub memory: f64,
+    pub disk: f64,
+    pub load: f64,
+}
+
+impl Default for HealthWeights {
+    fn default() -> Self {
+        Self {
+            cpu: 0.3,
+            memory: 0.3,
+            disk: 0.2,
+            load: 0.2,
+        }
+    }
+}
+
+impl HealthWeights {
+    fn get(&self, dimension: HealthDimension) -> f64 {
+        match dimension {
+            HealthDimension::Cpu => self.cpu,
+            HealthDimension::Memory => self.memory,
+            HealthDimension::Disk => self.disk,
+            HealthDimension::Load => self.load,
+        }
+    }
+}
+
+impl FromStr for HealthWeights {
+    type Err = String;
+
+    /// 解析 `cpu=0.4,memory=0.3,disk=0.2,load=0.1`，未列出的维度保留默认权重，权重为 0 时不参与评分
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut weights = Self::default();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (name, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("无效的权重 {}，应为 维度=权重", entry))?;
+            let value: f64 = value
+                .trim()
+                .parse()
+                .ok()
+                .filter(|value: &f64| value.is_finite() && *value >= 0.0)
+                .ok_or_else(|| format!("无效的权重 {}，应为非负数", entry))?;
+            let slot = match name.trim() {
+                "cpu" => &mut weights.cpu,
+                "memory" => &mut weights.memory,
+                "disk" => &mut weights.disk,
+                "load" => &mut weights.load,
+                other => {
+                    return Err(format!(
+                        "未知的评分维度: {}（可选 cpu、memory、disk、load）",
+                        other
+                    ))
+                }
+            };
+            *slot = value;
+        }
+        if HealthDimension::ALL.iter().all(|d| weights.get(*d) == 0.0) {
+            return Err("权重不能全部为 0".to_string());
+        }
+        Ok(weights)
+    }
+}
+
+impl fmt::Display for HealthWeights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cpu={},memory={},disk={},load={}",
+            self.cpu, self.memory, self.disk, self.load
+        )
+    }
+}
+
+/// 单个维度对分数的贡献
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthContribution {
+    pub dimension: HealthDimension,
+    /// 该维度的余量（0-100）
+    pub headroom: f64,
+    /// 重新归一化后的权重，参与评分的维度合计为 1
+    pub weight: f64,
+    /// headroom × weight，各维度之和即为总分
+    pub contribution: f64,
+}
+
+/// 健康评分结果
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthScore {
+    /// 0-100，越高越健康；没有任何可用维度时为 None
+    pub score: Option<f64>,
+    pub dimensions: Vec<HealthContribution>,
+    /// 样本缺少、未参与评分的维度（权重为 0 的维度不列出）
+    pub missing: Vec<HealthDimension>,
+}
+
+/// 按权重计算样本的健康评分
+pub fn health_score(metrics: &MetricsRequest, weights: &HealthWeights) -> HealthScore {
+    let mut present = Vec::new();
+    let mut missing = Vec::new();
+    for dimension in HealthDimension::ALL {
+        let weight = weights.get(dimension);
+        if weight == 0.0 {
+            continue;
+        }
+        match dimension.headroom(metrics) {
+            Some(headroom) => present.push((dimension, headroom, weight)),
+            None => missing.push(dimension),
+        }
+    }
+
+    let total_weight: f64 = present.iter().map(|(_, _, weight)| weight).sum();
+    let dimensions: Vec<HealthContribution> = present
+        .into_iter()
+        .map(|(dimension, headroom, weight)| {
+            let weight = weight / total_weight;
+            HealthContribution {
+                dimension,
+                headroom,
+                weight,
+                contribution: headroom * weight,
+            }
+        })
+        .collect();
+    let score = (!dimensions.is_empty()).then(|| dimensions.iter().map(|d| d.contribution).sum());
+
+    HealthScore {
+        score,
+        dimensions,
+        missing,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::{CpuMetrics, DiskMetrics, MemoryMetrics, SystemMetrics};
+
+    fn sample(disks: &[f64]) -> MetricsRequest {
+        MetricsRequest {
+            system: Some(SystemMetrics {
+                cpu: Some(CpuMetrics {
+                    usage_percent: 20.0,
+                    core_count: 4,
+                    load_avg_1: 2.0,
+                    ..Default::default()
+                }),
+                memory: Some(MemoryMetrics {
+                    usage_percent: 50.0,
+                    ..Default::default()
+                }),
+                disks: disks
+                    .iter()
+                    .map(|usage| DiskMetrics {
+                        usage_percent: *usage,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_health_score_with_known_weights() {
+        let weights: HealthWeights = "cpu=0.4,memory=0.3,disk=0.2,load=0.1".parse().unwrap();
+
+        // 余量: cpu 80, memory 50, disk 10（取最满的挂载点）, load 50（每核负载 0.5）
+        let health = health_score(&sample(&[30.0, 90.0]), &weights);
+        assert!((health.score.unwrap() - 54.0).abs() < 1e-9);
+        let headrooms: Vec<_> = health
+            .dimensions
+            .iter()
+            .map(|d| (d.dimension, d.headroom))
+            .collect();
+        assert_eq!(
+            headrooms,
+            vec![
+                (HealthDimension::Cpu, 80.0),
+                (HealthDimension::Memory, 50.0),
+                (HealthDimension::Disk, 10.0),
+                (HealthDimension::Load, 50.0),
+            ]
+        );
+        assert!(health.missing.is_empty());
+
+        // 没有磁盘时其余维度重新归一化: (0.4×80 + 0.3×50 + 0.1×50) / 0.8
+        let health = health_score(&sample(&[]), &weights);
+        assert!((health.score.unwrap() - 65.0).abs() < 1e-9);
+        assert_eq!(health.missing, vec![HealthDimension::Disk]);
+        let total: f64 = health.dimensions.iter().map(|d| d.weight).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+
+        // 样本不含系统指标时没有分数
+        let health = health_score(&MetricsRequest::default(), &weights);
+        assert!(health.score.is_none());
+        assert_eq!(health.missing.len(), 4);
+
+        assert!("cpu=-1".parse::<HealthWeights>().is_err());
+        assert!("gpu=1".parse::<HealthWeights>().is_err());
+        assert!("cpu=0,memory=0,disk=0,load=0"
+            .parse::<HealthWeights>()
+            .is_err());
+        assert_eq!("load=0".parse::<HealthWeights>().unwrap().load, 0.0);
+    }
+}
@@ -0,0 +1,89 @@
+//! 流式连接期间的 Agent 心跳
+//!
+//! Agent 在流式连接期间定期调用 `heartbeat`（与流共用同一个 gRPC 连接）。Server 尚未估计出
+//! 上报间隔时，上报间隔长于空闲超时下限的 Agent 在两条样本之间可能被误判为空闲；这段时间内
+//! 心跳按时到达可顺延流的空闲超时，但距上一条样本有硬上限，心跳正常而样本停滞的流仍会关闭。
+//! 只记录有活跃流的 agent_id，任意 agent_id 的心跳不会让状态无限增长
+
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use tokio::time::Instant;
+
+/// 单个 agent_id 的心跳状态
+#[derive(Debug)]
+struct StreamHeartbeat {
+    /// 该 agent_id 的活跃流数（重连时新旧两条流可能短暂并存）
+    streams: usize,
+    /// 最近一次收到心跳的时刻
+    last_beat: Option<Instant>,
+}
+
+/// 按 agent_id 记录活跃流最近一次收到的心跳
+#[derive(Debug, Default)]
+pub struct HeartbeatTracker {
+    agents: Mutex<HashMap<String, StreamHeartbeat>>,
+}
+
+impl HeartbeatTracker {
+    /// 流收到该 agent_id 的第一条样本，开始记录其心跳
+    pub fn attach(&self, agent_id: &str) {
+        let mut agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        agents
+            .entry(agent_id.to_string())
+            .or_insert(StreamHeartbeat {
+                streams: 0,
+                last_beat: None,
+            })
+            .streams += 1;
+    }
+
+    /// 流关闭，该 agent_id 没有其他活跃流时移除记录
+    pub fn detach(&self, agent_id: &str) {
+        let mut agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(state) = agents.get_mut(agent_id) {
+            state.streams -= 1;
+            if state.streams == 0 {
+                agents.remove(agent_id);
+            }
+        }
+    }
+
+    /// 记录一次心跳，没有活跃流的 agent_id 忽略
+    pub fn beat(&self, agent_id: &str, now: Instant) {
+        let mut agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(state) = agents.get_mut(agent_id) {
+            state.last_beat = Some(now);
+        }
+    }
+
+    /// 该 agent_id 最近一次心跳的时刻
+    pub fn last_beat(&self, agent_id: &str) -> Option<Instant> {
+        let agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        agents.get(agent_id).and_then(|state| state.last_beat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_beats_only_recorded_while_streaming() {
+        let tracker = HeartbeatTracker::default();
+        let now = Instant::now();
+        tracker.beat("agent-1", now);
+        assert_eq!(tracker.last_beat("agent-1"), None);
+
+        tracker.attach("agent-1");
+        tracker.attach("agent-1");
+        tracker.beat("agent-1", now);
+        assert_eq!(tracker.last_beat("agent-1"), Some(now));
+
+        // 还有一条流时保留记录
+        tracker.detach("agent-1");
+        assert_eq!(tracker.last_beat("agent-1"), Some(now));
+        tracker.detach("agent-1");
+        assert_eq!(tracker.last_beat("agent-1"), None);
+        assert!(tracker.agents.lock().unwrap().is_empty());
+    }
+}
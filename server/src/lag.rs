@@ -0,0 +1,104 @@
+//! 接收延迟
+//!
+//! 按 agent_id 记录样本到达 Server 的时间与样本时间戳之差（毫秒），用于区分“Agent 宕机”
+//! 与“Agent 落后或在回填”：持续为较大的正值表示回填或网络缓慢，负值表示 Agent 时钟超前。
+//! 同时给出最近一次的延迟与指数移动平均，Server 重启后清空
+
+use common::proto::MetricsRequest;
+use common::utils::current_timestamp_ms;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+
+/// 移动平均的平滑系数，越大越偏向最近的样本
+const LAG_EWMA_ALPHA: f64 = 0.2;
+
+/// 单个 Agent 的接收延迟
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct IngestLag {
+    /// 最近一条样本的延迟（毫秒）
+    pub latest_ms: i64,
+    /// 延迟的指数移动平均（毫秒）
+    pub avg_ms: f64,
+}
+
+/// 按 agent_id 跟踪接收延迟
+#[derive(Debug, Default)]
+pub struct LagTracker {
+    agents: Mutex<HashMap<String, IngestLag>>,
+}
+
+impl LagTracker {
+    /// 以当前 Server 时间记录一条样本的接收延迟
+    pub fn observe(&self, metrics: &MetricsRequest) {
+        self.observe_at(metrics, current_timestamp_ms());
+    }
+
+    fn observe_at(&self, metrics: &MetricsRequest, now: i64) {
+        // 时间戳由 Agent 上报，极端值不能让减法溢出
+        let lag = now.saturating_sub(metrics.timestamp);
+        let mut agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        agents
+            .entry(metrics.agent_id.clone())
+            .and_modify(|state| {
+                state.latest_ms = lag;
+                state.avg_ms += LAG_EWMA_ALPHA * (lag as f64 - state.avg_ms);
+            })
+            .or_insert(IngestLag {
+                latest_ms: lag,
+                avg_ms: lag as f64,
+            });
+    }
+
+    /// 该 agent_id 的接收延迟，Server 启动以来没有收到样本时为 None
+    pub fn lag(&self, agent_id: &str) -> Option<IngestLag> {
+        let agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        agents.get(agent_id).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: i64) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: "agent-1".to_string(),
+            timestamp,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_latest_and_moving_average() {
+        let tracker = LagTracker::default();
+        assert!(tracker.lag("agent-1").is_none());
+
+        tracker.observe_at(&sample(9_000), 10_000);
+        assert_eq!(
+            tracker.lag("agent-1"),
+            Some(IngestLag {
+                latest_ms: 1_000,
+                avg_ms: 1_000.0
+            })
+        );
+
+        // 时钟超前的样本延迟为负，平均值逐步靠拢
+        tracker.observe_at(&sample(12_000), 11_000);
+        let lag = tracker.lag("agent-1").unwrap();
+        assert_eq!(lag.latest_ms, -1_000);
+        assert!((lag.avg_ms - 600.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_extreme_timestamps_saturate() {
+        let tracker = LagTracker::default();
+        tracker.observe_at(&sample(i64::MIN), 10_000);
+        assert_eq!(tracker.lag("agent-1").unwrap().latest_ms, i64::MAX);
+
+        tracker.observe_at(&sample(i64::MAX), -10_000);
+        let lag = tracker.lag("agent-1").unwrap();
+        assert_eq!(lag.latest_ms, i64::MIN);
+        assert!(lag.avg_ms.is_finite());
+    }
+}
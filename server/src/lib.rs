@@ -1,259 +1,943 @@
 use anyhow::Result;
-use common::proto::probe_service_server::{ProbeService, ProbeServiceServer};
+use common::proto::probe_service_server::ProbeService;
 use common::proto::{
     HeartbeatRequest, HeartbeatResponse, MetricsRequest, MetricsResponse, StreamResponse,
 };
+use common::transport;
 use common::utils::current_timestamp_ms;
+use disconnect::DisconnectKind;
+use std::collections::HashSet;
+use std::future::Future;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use storage::{AgentEvent, AgentEventKind};
 use tokio::signal;
-use tokio::sync::broadcast;
 use tokio::sync::watch;
+use tokio::sync::{broadcast, Semaphore};
 use tokio_stream::StreamExt;
-use tonic::{transport::Server, Request, Response, Status};
-use tracing::info;
+use tonic::{Request, Response, Status};
+use tracing::{debug, info, info_span, warn, Instrument};
 
+mod agent_id;
+mod alerts;
+mod allowlist;
+mod analytics;
 mod api;
 mod assets;
+mod builder;
+mod cadence;
+mod disconnect;
+mod duplicates;
+mod events;
+mod exposition;
+mod grafana;
+mod health;
+mod heartbeat;
+mod lag;
+mod listen;
+mod liveness;
+mod maintenance;
+mod reboot;
+mod sanitize;
+mod sequence;
+mod shutdown;
+mod stats;
 mod storage;
+mod timezone;
+mod trace;
+
+pub use alerts::{DiskFreeRule, DiskFreeThreshold};
+pub use api::{DEFAULT_MAX_HISTORY_LIMIT, DEFAULT_REQUEST_TIMEOUT, DEFAULT_SSE_CLIENT_BUFFER};
+pub use builder::{ServerBuilder, ServerHandle};
+pub use health::HealthWeights;
+pub use sanitize::NonFinitePolicy;
+pub use shutdown::{ShutdownHooks, DEFAULT_HOOK_TIMEOUT};
+pub use storage::{
+    FieldRetention, HistoryConsistency, DEFAULT_CACHE_SIZE_PER_AGENT, DEFAULT_COALESCE_MAX_DELTA,
+    DEFAULT_DB_CACHE_BYTES,
+};
+
+/// 同时活跃的流式连接数上限默认值
+pub const DEFAULT_MAX_CONCURRENT_STREAMS: usize = 10_000;
+
+/// 流式连接空闲超时默认值
+pub const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 流式连接的空闲超时至少为预期上报间隔的倍数，上报间隔较长的 Agent 不会被误判为空闲
+const STREAM_IDLE_INTERVALS: u32 = 3;
+
+/// 尚未估计出上报间隔时，心跳最多把空闲超时顺延到距上一条样本多少个空闲超时下限
+const STREAM_HEARTBEAT_GRACE: u32 = 5;
+
+/// 优雅关闭的总预算默认值
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 写入队列积压时建议 Agent 采用的最小上报间隔
+pub const BACKPRESSURE_BACKOFF: Duration = Duration::from_secs(5);
+
+/// 巡检单次上报 Agent 是否离线的周期
+const LIVENESS_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Server 配置
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// 流式连接空闲超时的下限：超过该时长未收到任何样本则主动关闭流，触发 Agent 重连。
+    /// 已估计出上报间隔的 Agent 至少等待 3 个间隔；尚未估计出间隔时，心跳可把超时顺延到
+    /// 距上一条样本 5 倍下限
+    pub stream_idle_timeout: Duration,
+    /// 单次历史查询允许的最大条数
+    pub max_history_limit: usize,
+    /// 每个 SSE 订阅者的事件缓冲条数
+    pub sse_client_buffer: usize,
+    /// 每个 Agent 在内存中缓存的样本数，超出后淘汰最旧的样本
+    pub cache_size_per_agent: usize,
+    /// HTTP API 监听地址，None 时为 gRPC 端口 + 1（gRPC 使用 Unix socket 时必填）
+    pub http_addr: Option<std::net::SocketAddr>,
+    /// 不参与自动清理的 agent_id（按完整 ID 精确匹配）
+    pub cleanup_exempt_agents: HashSet<String>,
+    /// 是否拒绝打开对其他用户可访问的已有数据库文件（默认只记录告警）
+    pub strict_permissions: bool,
+    /// 是否要求持久化：数据目录不存在时拒绝以仅内存模式启动
+    pub require_persistence: bool,
+    /// 数据库分片数（按 agent_id 拆分到多个 redb 文件并行写入），默认 1 即单文件
+    pub db_shards: usize,
+    /// redb 缓存大小（字节），None 时使用 redb 默认值（见 `StorageConfig::db_cache_bytes`）
+    pub db_cache_bytes: Option<usize>,
+    /// 同时活跃的流式连接数上限，超出时以 `RESOURCE_EXHAUSTED` 拒绝新连接
+    pub max_concurrent_streams: usize,
+    /// 落盘前的写入合并窗口，为零时不合并（见 `StorageConfig::coalesce_window`）
+    pub coalesce_window: Duration,
+    /// 写入合并时视为“几乎相同”的最大差值（百分点）
+    pub coalesce_max_delta: f64,
+    /// 样本最大时效：时间戳早于 Server 接收时刻超过该时长的样本（如 Agent 重连后回放的积压）
+    /// 只作为历史保存，不成为最新样本，也不推送到实时流。为零时不检查
+    pub max_sample_age: Duration,
+    /// 样本含 NaN/Inf 时的处理方式：替换为 0（默认）或丢弃整条样本
+    pub non_finite: NonFinitePolicy,
+    /// 健康评分（`/api/agents/:id/health`）中各维度的权重
+    pub health_weights: HealthWeights,
+    /// 优雅关闭的总预算：排空连接与 Storage 落盘须在此时长内完成，超时后放弃等待并退出
+    pub shutdown_timeout: Duration,
+    /// 是否向 SSE / WebSocket 订阅者实时推送样本。只做接收与存储、没有看板的节点可关闭，
+    /// 关闭后不再广播与序列化样本，推送接口返回 404，查询接口不受影响
+    pub broadcast_enabled: bool,
+    /// 历史查询（`/api/agents/:id/metrics/history`）未指定 `consistency` 时使用的一致性模式
+    pub history_consistency: HistoryConsistency,
+    /// 按字段分级保留：清理时去掉超出保留期的样本中的低重要度字段（见 `StorageConfig::field_retention`）
+    pub field_retention: FieldRetention,
+    /// 仅实时模式：不论数据目录是否存在都不持久化，只保留每个 Agent 最近 `cache_size_per_agent`
+    /// 条样本，超出缓存的历史查询标记为已截断（见 `StorageConfig::live_only`）
+    pub live_only: bool,
+    /// Agent 准入名单：None 时接受任何 agent_id（默认），否则只接受其中的与经
+    /// `POST /api/admin/agents` 登记的 agent_id，其余上报以 `PERMISSION_DENIED` 拒绝
+    pub allowed_agents: Option<HashSet<String>>,
+    /// 管理接口（`POST /api/admin/agents`）的访问令牌，请求须带 `Authorization: Bearer <令牌>`；
+    /// None 时该接口关闭（默认）
+    pub admin_token: Option<String>,
+    /// 磁盘剩余空间告警规则，按挂载点评估，状态变化写入 Agent 事件时间线（默认不告警）
+    pub disk_free_alerts: Vec<DiskFreeRule>,
+    /// 单个 HTTP 请求的处理时限，超出时取消处理并返回 504，为零时不限制（SSE / WebSocket 不受限制）
+    pub request_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            stream_idle_timeout: STREAM_IDLE_TIMEOUT,
+            max_history_limit: api::DEFAULT_MAX_HISTORY_LIMIT,
+            sse_client_buffer: api::DEFAULT_SSE_CLIENT_BUFFER,
+            cache_size_per_agent: storage::DEFAULT_CACHE_SIZE_PER_AGENT,
+            http_addr: None,
+            cleanup_exempt_agents: HashSet::new(),
+            strict_permissions: false,
+            require_persistence: false,
+            db_shards: 1,
+            db_cache_bytes: None,
+            max_concurrent_streams: DEFAULT_MAX_CONCURRENT_STREAMS,
+            coalesce_window: Duration::ZERO,
+            coalesce_max_delta: storage::DEFAULT_COALESCE_MAX_DELTA,
+            max_sample_age: Duration::ZERO,
+            non_finite: NonFinitePolicy::default(),
+            health_weights: HealthWeights::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            broadcast_enabled: true,
+            history_consistency: HistoryConsistency::default(),
+            field_retention: FieldRetention::default(),
+            live_only: false,
+            allowed_agents: None,
+            admin_token: None,
+            disk_free_alerts: Vec::new(),
+            request_timeout: api::DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+}
 
 pub struct ProbeServer {
     storage: std::sync::Arc<storage::Storage>,
-    broadcast: broadcast::Sender<MetricsRequest>,
+    broadcast: broadcast::Sender<events::MetricsEvent>,
+    stats: std::sync::Arc<stats::IngestStats>,
+    duplicates: std::sync::Arc<duplicates::DuplicateDetector>,
+    sequences: std::sync::Arc<sequence::SequenceTracker>,
+    cadences: std::sync::Arc<cadence::CadenceTracker>,
+    disconnects: std::sync::Arc<disconnect::DisconnectTracker>,
+    lags: std::sync::Arc<lag::LagTracker>,
+    reboots: std::sync::Arc<reboot::RebootDetector>,
+    liveness: std::sync::Arc<liveness::LivenessTracker>,
+    heartbeats: std::sync::Arc<heartbeat::HeartbeatTracker>,
+    allowlist: std::sync::Arc<allowlist::AgentAllowlist>,
+    alerts: std::sync::Arc<alerts::AlertEngine>,
+    maintenance: std::sync::Arc<maintenance::MaintenanceWindows>,
+    /// 流式连接许可，每条活跃的流占用一个
+    streams: Arc<Semaphore>,
+    /// 关闭通知，收到后结束所有流式连接
+    shutdown: watch::Receiver<bool>,
+    /// 关闭 Storage 之前运行的关闭钩子
+    hooks: ShutdownHooks,
+    config: ServerConfig,
 }
 
 impl ProbeServer {
     pub fn new() -> Result<Self> {
+        Self::from_config(ServerConfig::default())
+    }
+
+    /// 使用指定 Server 配置创建 ProbeServer，按数据目录是否存在选择持久化或仅内存模式
+    pub fn from_config(config: ServerConfig) -> Result<Self> {
         // 检查生产环境数据目录是否存在
-        let persist_enabled = std::path::Path::new("/var/lib/iris").exists();
+        let persist_enabled = Path::new("/var/lib/iris").exists();
 
-        if persist_enabled {
+        if config.live_only {
+            if config.require_persistence {
+                return Err(anyhow::anyhow!(
+                    "仅实时模式不持久化，不能与要求持久化同时使用"
+                ));
+            }
+            info!(
+                "仅实时模式：每个 Agent 只在内存中保留最近 {} 条样本",
+                config.cache_size_per_agent
+            );
+            Self::in_memory(config)
+        } else if persist_enabled {
             info!("生产环境模式：数据将持久化到 /var/lib/iris/metrics.redb");
-            Self::with_db_path("/var/lib/iris/metrics.redb")
+            Self::persistent("/var/lib/iris/metrics.redb", config)
+        } else if config.require_persistence {
+            Err(anyhow::anyhow!(
+                "数据目录 /var/lib/iris 不存在，已要求持久化，拒绝以仅内存模式启动"
+            ))
         } else {
             info!("开发环境模式：数据仅保存在内存中（不持久化）");
-            Self::memory_only()
+            Self::in_memory(config)
         }
     }
 
     /// 使用指定数据库路径创建 ProbeServer（持久化模式）
     pub fn with_db_path(db_path: &str) -> Result<Self> {
-        // 确保 data 目录存在
-        if let Some(parent) = Path::new(db_path).parent() {
-            std::fs::create_dir_all(parent)?;
-        }
+        Self::persistent(db_path, ServerConfig::default())
+    }
 
+    /// 创建仅内存模式的 ProbeServer（不持久化）
+    pub fn memory_only() -> Result<Self> {
+        Self::in_memory(ServerConfig::default())
+    }
+
+    fn persistent(db_path: &str, config: ServerConfig) -> Result<Self> {
         let (tx, _) = broadcast::channel(1000);
 
         // 使用配置创建 Storage（持久化）
-        let config = storage::StorageConfig {
+        let storage_config = storage::StorageConfig {
             db_path: Some(db_path.to_string()),
+            cache_size_per_agent: config.cache_size_per_agent,
+            cleanup_exempt_agents: config.cleanup_exempt_agents.clone(),
+            strict_permissions: config.strict_permissions,
+            // 持久化初始化失败时拒绝以仅内存模式启动
+            require_persistence: true,
+            db_shards: config.db_shards,
+            db_cache_bytes: config.db_cache_bytes,
+            coalesce_window: config.coalesce_window,
+            coalesce_max_delta: config.coalesce_max_delta,
+            field_retention: config.field_retention.clone(),
             ..Default::default()
         };
-        let storage = std::sync::Arc::new(storage::Storage::with_config(config));
-        if !storage.is_persist_enabled() {
-            return Err(anyhow::anyhow!(
-                "Storage 持久化初始化失败，拒绝以仅内存模式启动（db_path={})",
-                db_path
-            ));
-        }
+        let storage = std::sync::Arc::new(storage::Storage::try_with_config(storage_config)?);
+        let maintenance = Arc::<maintenance::MaintenanceWindows>::default();
 
         info!("Storage initialized with db_path: {}", db_path);
 
         Ok(Self {
             storage,
             broadcast: tx,
+            stats: Default::default(),
+            duplicates: Default::default(),
+            sequences: Default::default(),
+            cadences: Default::default(),
+            disconnects: Default::default(),
+            lags: Default::default(),
+            reboots: Default::default(),
+            liveness: Default::default(),
+            heartbeats: Default::default(),
+            allowlist: Arc::new(allowlist::AgentAllowlist::new(
+                config.allowed_agents.clone(),
+            )),
+            alerts: Arc::new(alerts::AlertEngine::new(
+                config.disk_free_alerts.clone(),
+                maintenance.clone(),
+            )),
+            maintenance,
+            streams: Arc::new(Semaphore::new(config.max_concurrent_streams)),
+            shutdown: watch::channel(false).1,
+            hooks: ShutdownHooks::default(),
+            config,
         })
     }
 
-    /// 创建仅内存模式的 ProbeServer（不持久化）
-    pub fn memory_only() -> Result<Self> {
+    fn in_memory(config: ServerConfig) -> Result<Self> {
         let (tx, _) = broadcast::channel(1000);
 
         // 使用配置创建 Storage（仅内存）
-        let config = storage::StorageConfig {
+        let storage_config = storage::StorageConfig {
             db_path: None,
+            cache_size_per_agent: config.cache_size_per_agent,
+            live_only: config.live_only,
             ..Default::default()
         };
-        let storage = std::sync::Arc::new(storage::Storage::with_config(config));
+        let storage = std::sync::Arc::new(storage::Storage::with_config(storage_config));
+        let maintenance = Arc::<maintenance::MaintenanceWindows>::default();
 
         info!("Storage initialized in memory-only mode");
 
         Ok(Self {
             storage,
             broadcast: tx,
+            stats: Default::default(),
+            duplicates: Default::default(),
+            sequences: Default::default(),
+            cadences: Default::default(),
+            disconnects: Default::default(),
+            lags: Default::default(),
+            reboots: Default::default(),
+            liveness: Default::default(),
+            heartbeats: Default::default(),
+            allowlist: Arc::new(allowlist::AgentAllowlist::new(
+                config.allowed_agents.clone(),
+            )),
+            alerts: Arc::new(alerts::AlertEngine::new(
+                config.disk_free_alerts.clone(),
+                maintenance.clone(),
+            )),
+            maintenance,
+            streams: Arc::new(Semaphore::new(config.max_concurrent_streams)),
+            shutdown: watch::channel(false).1,
+            hooks: ShutdownHooks::default(),
+            config,
         })
     }
 
-    pub async fn run(addr: String) -> Result<()> {
-        let grpc_addr: std::net::SocketAddr = addr.parse()?;
-        let server = ProbeServer::new()?;
-        let storage_for_shutdown = server.storage.clone();
-        let storage = server.storage.clone();
-        let broadcast = server.broadcast.clone();
-        let server_for_grpc = server;
+    /// 替换 Server 配置
+    pub fn with_server_config(mut self, config: ServerConfig) -> Self {
+        self.streams = Arc::new(Semaphore::new(config.max_concurrent_streams));
+        self.allowlist = Arc::new(allowlist::AgentAllowlist::new(
+            config.allowed_agents.clone(),
+        ));
+        self.alerts = Arc::new(alerts::AlertEngine::new(
+            config.disk_free_alerts.clone(),
+            self.maintenance.clone(),
+        ));
+        self.config = config;
+        self
+    }
 
-        // 启动 HTTP API 服务器（端口 +1）
-        let http_port = grpc_addr.port() + 1;
-        let http_addr = format!("{}:{}", grpc_addr.ip(), http_port);
-        let http_listener = tokio::net::TcpListener::bind(&http_addr).await?;
+    pub async fn run(addr: String) -> Result<()> {
+        Self::run_with_config(addr, ServerConfig::default()).await
+    }
 
-        let (shutdown_tx, _) = watch::channel(false);
+    /// 使用自定义 Server 配置启动
+    ///
+    /// `addr` 可以是 `ip:port`（支持 IPv6 `[::]:50051`）或 `unix:<path>`。收到 Ctrl+C 或
+    /// SIGTERM 后优雅关闭，超出 `shutdown_timeout` 时返回错误，调用方应立即退出进程
+    ///
+    /// 需要与其他服务共存或由程序主动关闭时改用 [`ServerBuilder`]
+    pub async fn run_with_config(addr: String, config: ServerConfig) -> Result<()> {
+        ServerBuilder::new(addr)
+            .config(config)
+            .handle_signals(true)
+            .serve_with_shutdown(std::future::pending())
+            .await
+    }
 
-        let mut http_shutdown_rx = shutdown_tx.subscribe();
-        let mut grpc_shutdown_rx = shutdown_tx.subscribe();
+    /// 在已绑定的监听器上运行 gRPC 与 HTTP 服务，直到 `shutdown` 完成或任一服务退出
+    ///
+    /// 关闭时先通知 gRPC 流式连接与 SSE / WebSocket 订阅结束、停止接受新连接，再运行关闭
+    /// 钩子、关闭 Storage 落盘剩余数据。整个过程共用 `shutdown_timeout` 预算，钩子最多用掉排空
+    /// 连接后剩余预算的一半；超时后中止未完成的连接并返回错误，不再等待卡住的写入
+    async fn serve(
+        mut self,
+        grpc_listener: listen::GrpcListener,
+        http_listener: tokio::net::TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        let http_addr = http_listener.local_addr()?;
+        let budget = self.config.shutdown_timeout;
+        let storage_for_shutdown = self.storage.clone();
+        let hooks = self.hooks.clone();
+        // 为仍在触发的告警补记恢复事件，须在 Storage 关闭之前写入时间线
+        let alerts = self.alerts.clone();
+        let alert_storage = self.storage.clone();
+        hooks.register("alerts", move || async move {
+            for (agent_id, event) in alerts.resolve_all(current_timestamp_ms()) {
+                alert_storage.record_event(&agent_id, event).await;
+            }
+            Ok(())
+        });
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        self.shutdown = shutdown_rx.clone();
+        tokio::spawn(sweep_liveness(
+            self.liveness.clone(),
+            self.cadences.clone(),
+            self.storage.clone(),
+            shutdown_rx.clone(),
+        ));
 
+        let app = api::create_router(api::ApiState {
+            storage: self.storage.clone(),
+            broadcast: self.broadcast.clone(),
+            stats: self.stats.clone(),
+            duplicates: self.duplicates.clone(),
+            sequences: self.sequences.clone(),
+            cadences: self.cadences.clone(),
+            disconnects: self.disconnects.clone(),
+            lags: self.lags.clone(),
+            allowlist: self.allowlist.clone(),
+            maintenance: self.maintenance.clone(),
+            config: api::ApiConfig {
+                max_history_limit: self.config.max_history_limit,
+                sse_client_buffer: self.config.sse_client_buffer,
+                health_weights: self.config.health_weights,
+                broadcast_enabled: self.config.broadcast_enabled,
+                history_consistency: self.config.history_consistency,
+                request_timeout: self.config.request_timeout,
+                admin_token: self.config.admin_token.clone(),
+            },
+            shutdown: shutdown_rx.clone(),
+        });
+        let http_shutdown = shutdown_requested(shutdown_rx.clone());
         let mut http_handle = tokio::spawn(async move {
-            let app = api::create_router(storage, broadcast);
             info!("HTTP API 启动在 http://{}", http_addr);
             axum::serve(http_listener, app)
-                .with_graceful_shutdown(async move {
-                    while !*http_shutdown_rx.borrow() {
-                        if http_shutdown_rx.changed().await.is_err() {
-                            break;
-                        }
-                    }
-                })
-                .await
-                .map_err(anyhow::Error::from)
-        });
-
-        let mut grpc_handle = tokio::spawn(async move {
-            info!("gRPC Server 启动在 {}", grpc_addr);
-            let shutdown_signal = async move {
-                tokio::select! {
-                    r = signal::ctrl_c() => {
-                        if let Err(e) = r {
-                            tracing::error!("无法监听 Ctrl+C 信号: {}", e);
-                        } else {
-                            info!("收到关闭信号，正在优雅关闭...");
-                        }
-                    }
-                    _ = async {
-                        while !*grpc_shutdown_rx.borrow() {
-                            if grpc_shutdown_rx.changed().await.is_err() {
-                                break;
-                            }
-                        }
-                    } => {}
-                }
-            };
-
-            Server::builder()
-                .add_service(ProbeServiceServer::new(server_for_grpc))
-                .serve_with_shutdown(grpc_addr, shutdown_signal)
+                .with_graceful_shutdown(http_shutdown)
                 .await
                 .map_err(anyhow::Error::from)
         });
+        let mut grpc_handle =
+            tokio::spawn(grpc_listener.serve(self, shutdown_requested(shutdown_rx)));
 
+        // 任一服务先退出时记下结果，关闭其余部分后返回错误
+        let mut grpc_result = None;
+        let mut http_result = None;
         tokio::select! {
-            grpc_result = &mut grpc_handle => {
-                let _ = shutdown_tx.send(true);
-                grpc_result.map_err(|e| anyhow::anyhow!("gRPC 任务异常退出: {}", e))??;
+            _ = shutdown => {}
+            result = &mut grpc_handle => grpc_result = Some(result),
+            result = &mut http_handle => http_result = Some(result),
+        }
+        let exited_early = grpc_result.is_some() || http_result.is_some();
+        let _ = shutdown_tx.send(true);
+        let deadline = tokio::time::Instant::now() + budget;
+        info!("正在优雅关闭，预算 {:?}...", budget);
 
-                match tokio::time::timeout(std::time::Duration::from_secs(5), &mut http_handle).await {
-                    Ok(join_result) => {
-                        join_result.map_err(|e| anyhow::anyhow!("HTTP 任务异常退出: {}", e))??;
-                    }
-                    Err(_) => {
-                        info!("HTTP 任务未在超时内退出，正在中止...");
-                        http_handle.abort();
-                    }
-                }
+        let drained = tokio::time::timeout_at(deadline, async {
+            if grpc_result.is_none() {
+                grpc_result = Some((&mut grpc_handle).await);
             }
-            http_result = &mut http_handle => {
-                let _ = shutdown_tx.send(true);
-                let http_err = match http_result {
-                    Ok(Ok(())) => {
-                        anyhow::anyhow!("HTTP 服务器意外退出")
-                    }
-                    Ok(Err(e)) => {
-                        anyhow::anyhow!("HTTP 服务器启动/运行失败: {}", e)
-                    }
-                    Err(e) => {
-                        anyhow::anyhow!("HTTP 任务异常退出: {}", e)
-                    }
-                };
+            if http_result.is_none() {
+                http_result = Some((&mut http_handle).await);
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!("连接未在关闭预算内排空，强制断开");
+            grpc_handle.abort();
+            http_handle.abort();
+        }
 
-                match tokio::time::timeout(std::time::Duration::from_secs(5), &mut grpc_handle).await {
-                    Ok(join_result) => {
-                        join_result.map_err(|e| anyhow::anyhow!("gRPC 任务异常退出: {}", e))??;
-                    }
-                    Err(_) => {
-                        info!("gRPC 任务未在超时内退出，正在中止...");
-                        grpc_handle.abort();
-                    }
-                }
+        let service_error = match (grpc_result, http_result) {
+            (Some(Err(e)), _) => Some(anyhow::anyhow!("gRPC 任务异常退出: {}", e)),
+            (Some(Ok(Err(e))), _) => Some(e.context("gRPC 服务器运行失败")),
+            (_, Some(Err(e))) => Some(anyhow::anyhow!("HTTP 任务异常退出: {}", e)),
+            (_, Some(Ok(Err(e)))) => Some(anyhow::anyhow!("HTTP 服务器启动/运行失败: {}", e)),
+            _ if exited_early => Some(anyhow::anyhow!("服务器意外退出")),
+            _ => None,
+        };
 
-                return Err(http_err);
+        // 子系统的落盘回调可能写入 Storage，先于 Storage 关闭运行；钩子最多用掉剩余预算的
+        // 一半，另一半留给 Storage 落盘
+        if !hooks.is_empty() {
+            info!("正在运行 {} 个关闭钩子...", hooks.len());
+            let now = tokio::time::Instant::now();
+            let hooks_deadline = now + deadline.saturating_duration_since(now) / 2;
+            let failed = hooks.run(hooks_deadline).await;
+            if failed > 0 {
+                warn!("{} 个关闭钩子未成功完成", failed);
             }
         }
 
         // 关闭 Storage，确保数据全部写入
         info!("正在关闭 Storage...");
-        storage_for_shutdown.shutdown().await?;
+        match tokio::time::timeout_at(deadline, storage_for_shutdown.shutdown()).await {
+            Ok(report) => {
+                let report = report?;
+                if report.dropped > 0 {
+                    warn!(
+                        "Storage 关闭时落盘 {} 条，丢弃 {} 条未能写入的指标",
+                        report.flushed, report.dropped
+                    );
+                } else {
+                    info!("Storage 关闭时落盘 {} 条，无数据丢失", report.flushed);
+                }
+            }
+            Err(_) => {
+                warn!(
+                    "Storage 未能在关闭预算 {:?} 内完成落盘，放弃等待并强制退出",
+                    budget
+                );
+                return Err(anyhow::anyhow!(
+                    "关闭超时：Storage 未能在 {:?} 内完成落盘",
+                    budget
+                ));
+            }
+        }
 
+        if let Some(e) = service_error {
+            return Err(e);
+        }
         info!("服务器已优雅关闭");
         Ok(())
     }
 }
 
+/// 等待 Ctrl+C 或 SIGTERM（systemd 停止服务时发送）
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            tracing::error!("无法监听 Ctrl+C 信号: {}", e);
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("无法监听 SIGTERM 信号: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    info!("收到关闭信号，正在优雅关闭...");
+}
+
+/// 等待关闭通知；发送端已丢弃（未经 `serve` 启动，如测试中直接挂载服务）时永不完成
+pub(crate) async fn shutdown_requested(mut shutdown: watch::Receiver<bool>) {
+    if shutdown.wait_for(|stop| *stop).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// 取 gRPC metadata 中的 `x-request-id` 作为 trace_id，缺失时生成
+fn grpc_trace_id<T>(request: &Request<T>) -> String {
+    trace::trace_id_or_new(
+        request
+            .metadata()
+            .get(trace::REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok()),
+    )
+}
+
+/// 样本时间戳是否早于 Server 接收时刻超过 `max_age`（为零时不检查）
+///
+/// 以 Server 接收时刻为基准：Agent 时钟偏差超过 `max_age` 时其全部样本都会被视为过期
+fn is_stale(metrics: &MetricsRequest, max_age: Duration) -> bool {
+    !max_age.is_zero()
+        && current_timestamp_ms().saturating_sub(metrics.timestamp)
+            > max_age.as_millis().min(i64::MAX as u128) as i64
+}
+
+/// 按策略处理样本中的 NaN/Inf 并记录告警，需要丢弃该样本时返回 false
+fn sanitize_sample(metrics: &mut MetricsRequest, policy: NonFinitePolicy) -> bool {
+    let fields = sanitize::sanitize_non_finite(metrics);
+    if fields.is_empty() {
+        return true;
+    }
+    match policy {
+        NonFinitePolicy::Zero => {
+            warn!(
+                "{} 的样本含非有限数值，已替换为 0: {}",
+                metrics.agent_id,
+                fields.join(", ")
+            );
+            true
+        }
+        NonFinitePolicy::Drop => {
+            warn!(
+                "{} 的样本含非有限数值，已丢弃: {}",
+                metrics.agent_id,
+                fields.join(", ")
+            );
+            false
+        }
+    }
+}
+
+/// 流式连接的空闲超时：不短于配置的下限，已估计出上报间隔时至少为 `STREAM_IDLE_INTERVALS` 个间隔
+fn stream_idle_timeout(min_timeout: Duration, expected_interval_ms: Option<i64>) -> Duration {
+    expected_interval_ms.map_or(min_timeout, |interval| {
+        Duration::from_millis(u64::try_from(interval).unwrap_or(0))
+            .saturating_mul(STREAM_IDLE_INTERVALS)
+            .max(min_timeout)
+    })
+}
+
+/// 定期巡检单次上报的 Agent，把超过离线阈值未上报的记入事件时间线，直到 Server 关闭
+async fn sweep_liveness(
+    liveness: Arc<liveness::LivenessTracker>,
+    cadences: Arc<cadence::CadenceTracker>,
+    storage: Arc<storage::Storage>,
+    shutdown: watch::Receiver<bool>,
+) {
+    let mut ticker = tokio::time::interval(LIVENESS_SWEEP_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let stop = shutdown_requested(shutdown);
+    tokio::pin!(stop);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = &mut stop => return,
+        }
+        let offline = liveness.sweep(current_timestamp_ms(), |agent_id| {
+            api::offline_after_ms(cadences.expected_interval_ms(agent_id))
+        });
+        for (agent_id, event) in offline {
+            storage.record_event(&agent_id, event).await;
+        }
+    }
+}
+
+impl ProbeServer {
+    /// 写入队列积压时建议 Agent 放慢上报的最小间隔（毫秒），未积压时为 0
+    async fn backoff_ms(&self) -> u64 {
+        if self.storage.is_backlogged().await {
+            BACKPRESSURE_BACKOFF.as_millis() as u64
+        } else {
+            0
+        }
+    }
+}
+
 #[tonic::async_trait]
 impl ProbeService for ProbeServer {
     async fn report_metrics(
         &self,
         request: Request<MetricsRequest>,
     ) -> Result<Response<MetricsResponse>, Status> {
-        let req = request.into_inner();
-        info!("收到来自 {} 的指标数据", req.agent_id);
+        let trace_id = grpc_trace_id(&request);
+        let mut req = request.into_inner();
+        if let Err(reason) = agent_id::normalize_agent_id(&mut req.agent_id) {
+            warn!("拒绝样本: {}", reason);
+            return Err(Status::invalid_argument(reason));
+        }
+        if !self.allowlist.is_allowed(&req.agent_id) {
+            warn!("拒绝未登记的 Agent {} 的样本", req.agent_id);
+            return Err(Status::permission_denied(format!(
+                "agent_id {} 未登记",
+                req.agent_id
+            )));
+        }
+        let span = info_span!("report_metrics", trace_id = %trace_id, agent_id = %req.agent_id);
+
+        async move {
+            info!("收到来自 {} 的指标数据", req.agent_id);
+            self.stats.record(&req.agent_id);
+            if let Some(event) = self.liveness.observe(&req.agent_id, current_timestamp_ms()) {
+                self.storage.record_event(&req.agent_id, event).await;
+            }
+            self.duplicates.observe(&req);
+            self.sequences.observe(&req);
+            self.cadences.observe(&req);
+            self.lags.observe(&req);
 
-        // 广播给前端
-        let _ = self.broadcast.send(req.clone());
+            if !sanitize_sample(&mut req, self.config.non_finite) {
+                return Ok(Response::new(MetricsResponse {
+                    success: false,
+                    message: "样本含非有限数值，已丢弃".to_string(),
+                    backoff_ms: self.backoff_ms().await,
+                }));
+            }
 
-        // 存储指标数据（异步持久化，不阻塞响应）
-        self.storage.save_metrics(&req).await;
+            // 过期的回填样本只作为历史保存，不推送、不更新最新样本
+            let saved = if is_stale(&req, self.config.max_sample_age) {
+                info!(
+                    "{} 的样本时间戳 {} 已过期，仅作为历史保存",
+                    req.agent_id, req.timestamp
+                );
+                self.storage.save_backfill(&req).await
+            } else {
+                if let Some(event) = self.reboots.observe(&req) {
+                    self.storage.record_event(&req.agent_id, event).await;
+                }
+                // 存储指标数据（异步持久化，不阻塞响应）；入队失败时 Agent 会重试同一条样本，
+                // 因此不缓存、不广播、不评估告警，避免重试产生重复的缓存条目与推送
+                let saved = self.storage.save_metrics(&req).await;
+                if saved {
+                    if self.config.broadcast_enabled {
+                        let outcome = events::publish(&self.broadcast, &req);
+                        self.stats.record_broadcast(outcome);
+                    }
+                    for event in self.alerts.observe(&req) {
+                        self.storage.record_event(&req.agent_id, event).await;
+                    }
+                }
+                saved
+            };
 
-        let response = MetricsResponse {
-            success: true,
-            message: "指标接收成功".to_string(),
-        };
+            let response = MetricsResponse {
+                success: saved,
+                message: if saved {
+                    "指标接收成功".to_string()
+                } else {
+                    "持久化队列不可用，样本未保存".to_string()
+                },
+                backoff_ms: self.backoff_ms().await,
+            };
 
-        Ok(Response::new(response))
+            Ok(Response::new(response))
+        }
+        .instrument(span)
+        .await
     }
 
     async fn stream_metrics(
         &self,
         request: Request<tonic::Streaming<MetricsRequest>>,
     ) -> Result<Response<StreamResponse>, Status> {
+        // 开启准入名单时，建立流之前先按 metadata 中的 agent_id 校验，之后每条样本仍逐条校验
+        if self.allowlist.is_enabled() {
+            let agent_id = request
+                .metadata()
+                .get(transport::AGENT_ID_METADATA)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .unwrap_or_default();
+            if !self.allowlist.is_allowed(agent_id) {
+                warn!("拒绝未登记的 Agent {:?} 的流式连接", agent_id);
+                return Err(Status::permission_denied(format!(
+                    "agent_id {:?} 未登记（流式连接须在 {} metadata 中携带 agent_id）",
+                    agent_id,
+                    transport::AGENT_ID_METADATA
+                )));
+            }
+        }
+
+        // 达到上限时直接拒绝，避免重连风暴下流式任务无限堆积
+        let Ok(permit) = self.streams.clone().try_acquire_owned() else {
+            warn!(
+                "活跃流式连接数已达上限 {}，拒绝新的流式连接",
+                self.config.max_concurrent_streams
+            );
+            return Err(Status::resource_exhausted(format!(
+                "活跃流式连接数已达上限 {}，请稍后重试",
+                self.config.max_concurrent_streams
+            )));
+        };
+        let active = self.stats.stream_opened();
+
+        // 同一条流上的全部样本共用一个 trace_id
+        let span = info_span!("stream_metrics", trace_id = %grpc_trace_id(&request));
         let mut stream = request.into_inner();
-        let broadcast = self.broadcast.clone();
+        // 关闭实时推送时不广播
+        let broadcast = self
+            .config
+            .broadcast_enabled
+            .then(|| self.broadcast.clone());
         let storage = self.storage.clone();
+        let stats = self.stats.clone();
+        let duplicates = self.duplicates.clone();
+        let sequences = self.sequences.clone();
+        let cadences = self.cadences.clone();
+        let disconnects = self.disconnects.clone();
+        let lags = self.lags.clone();
+        let reboots = self.reboots.clone();
+        let liveness = self.liveness.clone();
+        let heartbeats = self.heartbeats.clone();
+        let allowlist = self.allowlist.clone();
+        let alerts = self.alerts.clone();
+        let min_idle_timeout = self.config.stream_idle_timeout;
+        let max_sample_age = self.config.max_sample_age;
+        let non_finite = self.config.non_finite;
+        let shutdown = self.shutdown.clone();
 
-        tokio::spawn(async move {
-            let mut agent_id = String::new();
-
-            while let Some(result) = stream.next().await {
-                match result {
-                    Ok(metrics) => {
-                        if agent_id.is_empty() {
-                            agent_id = metrics.agent_id.clone();
-                            info!("Agent {} 建立流式连接", agent_id);
+        tokio::spawn(
+            async move {
+                let mut agent_id = String::new();
+                // 非法 agent_id 的样本逐条丢弃，每条流只告警一次
+                let mut rejected_id = false;
+                // 最近一次收到样本的时刻，与其后最近一次顺延超时的心跳时刻，空闲超时从后者起算
+                let mut last_sample = tokio::time::Instant::now();
+                let mut last_activity = last_sample;
+
+                let (kind, reason) = loop {
+                    // 半开连接下 Agent 端发送可能一直“成功”，这里超时后主动关闭流，让 Agent 重连
+                    let expected_interval_ms = cadences.expected_interval_ms(&agent_id);
+                    let idle_timeout = stream_idle_timeout(min_idle_timeout, expected_interval_ms);
+                    let heartbeat_limit =
+                        last_sample + min_idle_timeout.saturating_mul(STREAM_HEARTBEAT_GRACE);
+                    let deadline = if last_activity > last_sample {
+                        (last_activity + idle_timeout).min(heartbeat_limit)
+                    } else {
+                        last_activity + idle_timeout
+                    };
+                    let next = tokio::select! {
+                        next = tokio::time::timeout_at(deadline, stream.next()) => next,
+                        _ = shutdown_requested(shutdown.clone()) => {
+                            info!("Server 正在关闭，结束 Agent {} 的流式连接", agent_id);
+                            break (DisconnectKind::Shutdown, None);
+                        }
+                    };
+                    let result = match next {
+                        Ok(Some(result)) => result,
+                        Ok(None) => break (DisconnectKind::Closed, None),
+                        Err(_) => {
+                            // 尚未估计出上报间隔时，心跳按时到达说明连接可用，从最近一次心跳起重新计时，
+                            // 但不超过距上一条样本的硬上限：心跳正常而样本停滞的流仍会关闭
+                            if let Some(beat) = heartbeats
+                                .last_beat(&agent_id)
+                                .filter(|beat| *beat > last_activity && *beat < heartbeat_limit)
+                                .filter(|_| expected_interval_ms.is_none())
+                            {
+                                last_activity = beat;
+                                continue;
+                            }
+                            warn!(
+                                "Agent {} 超过 {:?} 未上报数据，主动关闭流式连接",
+                                agent_id, idle_timeout
+                            );
+                            break (DisconnectKind::IdleTimeout, None);
                         }
+                    };
+                    last_sample = tokio::time::Instant::now();
+                    last_activity = last_sample;
 
-                        // 1. 立即广播给前端（实时）
-                        let _ = broadcast.send(metrics.clone());
+                    match result {
+                        Ok(mut metrics) => {
+                            if let Err(reason) =
+                                crate::agent_id::normalize_agent_id(&mut metrics.agent_id)
+                            {
+                                if !rejected_id {
+                                    warn!("流式连接中的样本已丢弃: {}", reason);
+                                    rejected_id = true;
+                                }
+                                continue;
+                            }
+                            if !allowlist.is_allowed(&metrics.agent_id) {
+                                warn!(
+                                    "流式连接中出现未登记的 Agent {}，关闭连接",
+                                    metrics.agent_id
+                                );
+                                break (
+                                    DisconnectKind::Error,
+                                    Some(format!("agent_id {} 未登记", metrics.agent_id)),
+                                );
+                            }
+                            if agent_id.is_empty() {
+                                agent_id = metrics.agent_id.clone();
+                                info!("Agent {} 建立流式连接", agent_id);
+                                liveness.forget(&agent_id);
+                                heartbeats.attach(&agent_id);
+                                storage
+                                    .record_event(
+                                        &agent_id,
+                                        AgentEvent {
+                                            kind: AgentEventKind::Online,
+                                            timestamp: current_timestamp_ms(),
+                                            details: "建立流式连接".to_string(),
+                                        },
+                                    )
+                                    .await;
+                            }
+                            stats.record(&metrics.agent_id);
+                            duplicates.observe(&metrics);
+                            sequences.observe(&metrics);
+                            cadences.observe(&metrics);
+                            lags.observe(&metrics);
 
-                        // 2. 存储所有指标（异步持久化，不阻塞接收）
-                        storage.save_metrics(&metrics).await;
-                    }
-                    Err(e) => {
-                        info!("Agent {} 流式连接错误: {}", agent_id, e);
-                        break;
+                            if !sanitize_sample(&mut metrics, non_finite) {
+                                continue;
+                            }
+
+                            // 过期的回填样本只作为历史保存
+                            if is_stale(&metrics, max_sample_age) {
+                                debug!(
+                                    "{} 的样本时间戳 {} 已过期，仅作为历史保存",
+                                    metrics.agent_id, metrics.timestamp
+                                );
+                                storage.save_backfill(&metrics).await;
+                                continue;
+                            }
+                            if let Some(event) = reboots.observe(&metrics) {
+                                storage.record_event(&metrics.agent_id, event).await;
+                            }
+                            // 存储指标（异步持久化，不阻塞接收），保存成功后再广播与评估告警
+                            if !storage.save_metrics(&metrics).await {
+                                continue;
+                            }
+                            if let Some(broadcast) = &broadcast {
+                                stats.record_broadcast(events::publish(broadcast, &metrics));
+                            }
+                            for event in alerts.observe(&metrics) {
+                                storage.record_event(&metrics.agent_id, event).await;
+                            }
+                        }
+                        Err(e) => {
+                            info!("Agent {} 流式连接错误: {}", agent_id, e);
+                            let reason = if e.message().is_empty() {
+                                e.code().to_string()
+                            } else {
+                                e.message().to_string()
+                            };
+                            break (DisconnectKind::Error, Some(reason));
+                        }
                     }
+                };
+
+                info!("Agent {} 断开流式连接", agent_id);
+                if !agent_id.is_empty() {
+                    heartbeats.detach(&agent_id);
+                    let details = match &reason {
+                        Some(reason) => format!("{}: {}", kind.describe(), reason),
+                        None => kind.describe().to_string(),
+                    };
+                    storage
+                        .record_event(
+                            &agent_id,
+                            AgentEvent {
+                                kind: AgentEventKind::Offline,
+                                timestamp: current_timestamp_ms(),
+                                details,
+                            },
+                        )
+                        .await;
                 }
+                disconnects.record(&agent_id, kind, reason);
+                drop((active, permit));
             }
-
-            info!("Agent {} 断开流式连接", agent_id);
-        });
+            .instrument(span),
+        );
 
         Ok(Response::new(StreamResponse {
             success: true,
@@ -265,14 +949,978 @@ impl ProbeService for ProbeServer {
         &self,
         request: Request<HeartbeatRequest>,
     ) -> Result<Response<HeartbeatResponse>, Status> {
-        let req = request.into_inner();
+        let mut req = request.into_inner();
         info!("收到来自 {} 的心跳", req.agent_id);
+        if crate::agent_id::normalize_agent_id(&mut req.agent_id).is_ok() {
+            self.heartbeats
+                .beat(&req.agent_id, tokio::time::Instant::now());
+        }
 
         let response = HeartbeatResponse {
             alive: true,
             server_time: current_timestamp_ms(),
+            backoff_ms: self.backoff_ms().await,
         };
 
         Ok(Response::new(response))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::probe_service_client::ProbeServiceClient;
+    use common::proto::probe_service_server::ProbeServiceServer;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+
+    /// 在随机端口启动 gRPC 服务，返回连接地址
+    async fn spawn_grpc(server: ProbeServer) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        tokio::spawn(async move {
+            Server::builder()
+                .add_service(ProbeServiceServer::new(server))
+                .serve_with_incoming(incoming)
+                .await
+                .unwrap();
+        });
+        format!("http://{}", addr)
+    }
+
+    fn sample(agent_id: &str, timestamp: i64) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: agent_id.to_string(),
+            timestamp,
+            hostname: "test-host".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_silent_stream_closed_after_idle_timeout() {
+        let server = ProbeServer::memory_only()
+            .unwrap()
+            .with_server_config(ServerConfig {
+                stream_idle_timeout: Duration::from_millis(200),
+                ..Default::default()
+            });
+        let storage = server.storage.clone();
+        let addr = spawn_grpc(server).await;
+
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+        let (tx, rx) = mpsc::channel(1);
+        client
+            .stream_metrics(ReceiverStream::new(rx))
+            .await
+            .unwrap();
+        tx.send(sample("agent-silent", 1000)).await.unwrap();
+
+        // 之后保持静默：服务端超时关闭流后，Agent 侧的发送通道应被关闭
+        let closed = tokio::time::timeout(Duration::from_secs(5), tx.closed()).await;
+        assert!(closed.is_ok(), "静默流应在空闲超时后被服务端关闭");
+        assert!(tx.send(sample("agent-silent", 2000)).await.is_err());
+
+        let latest = storage.get_agent_latest("agent-silent").await.unwrap();
+        assert_eq!(latest.timestamp, 1000);
+    }
+
+    #[tokio::test]
+    async fn test_slow_stream_kept_open_beyond_idle_timeout() {
+        let server = ProbeServer::memory_only()
+            .unwrap()
+            .with_server_config(ServerConfig {
+                stream_idle_timeout: Duration::from_millis(200),
+                ..Default::default()
+            });
+        let storage = server.storage.clone();
+        let addr = spawn_grpc(server).await;
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+
+        // 上报间隔 1 秒（按样本时间戳估计）：空闲超时放宽到 3 个间隔，长于配置的 200ms 下限
+        let (tx, rx) = mpsc::channel(1);
+        client
+            .stream_metrics(ReceiverStream::new(rx))
+            .await
+            .unwrap();
+        for ts in [1000, 2000, 3000, 4000] {
+            tx.send(sample("agent-slow", ts)).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(!tx.is_closed(), "已知上报间隔较长时不应按下限关闭流");
+        tx.send(sample("agent-slow", 5000)).await.unwrap();
+
+        // 上报间隔尚未估计出来时，心跳按时到达同样使流保持打开
+        let (beat_tx, beat_rx) = mpsc::channel(1);
+        client
+            .stream_metrics(ReceiverStream::new(beat_rx))
+            .await
+            .unwrap();
+        beat_tx.send(sample("agent-beat", 1000)).await.unwrap();
+        for _ in 0..12 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client
+                .heartbeat(HeartbeatRequest {
+                    agent_id: "agent-beat".to_string(),
+                    timestamp: 0,
+                })
+                .await
+                .unwrap();
+        }
+        assert!(!beat_tx.is_closed(), "心跳按时到达时不应关闭流");
+        beat_tx.send(sample("agent-beat", 2000)).await.unwrap();
+
+        // 心跳持续到达但样本停滞：距上一条样本超过 5 倍下限（1 秒）后仍关闭流
+        let mut beat_client = client.clone();
+        let beating = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let _ = beat_client
+                    .heartbeat(HeartbeatRequest {
+                        agent_id: "agent-beat".to_string(),
+                        timestamp: 0,
+                    })
+                    .await;
+            }
+        });
+        let stalled_at = std::time::Instant::now();
+        let closed = tokio::time::timeout(Duration::from_secs(5), beat_tx.closed()).await;
+        beating.abort();
+        assert!(closed.is_ok(), "样本停滞的流即使心跳正常也应被关闭");
+        assert!(stalled_at.elapsed() >= Duration::from_millis(600));
+        let latest = storage.get_agent_latest("agent-beat").await.unwrap();
+        assert_eq!(latest.timestamp, 2000);
+        let latest = storage.get_agent_latest("agent-slow").await.unwrap();
+        assert_eq!(latest.timestamp, 5000);
+    }
+
+    #[tokio::test]
+    async fn test_stream_disconnect_reason_surfaces_in_agent_list() {
+        use axum::body::Body;
+        use axum::http::Request as HttpRequest;
+        use tonic::codec::ProstCodec;
+        use tower::ServiceExt;
+
+        /// 与 `MetricsRequest` 兼容的编码，`bad_hostname` 与 hostname 同号但类型不符，
+        /// 设置后 Server 解码失败
+        #[derive(Clone, PartialEq, prost::Message)]
+        struct RawSample {
+            #[prost(string, tag = "1")]
+            agent_id: String,
+            #[prost(int64, tag = "2")]
+            timestamp: i64,
+            #[prost(uint64, optional, tag = "4")]
+            bad_hostname: Option<u64>,
+        }
+
+        let server = ProbeServer::memory_only().unwrap();
+        let app = api::create_router(api::ApiState {
+            storage: server.storage.clone(),
+            broadcast: server.broadcast.clone(),
+            stats: server.stats.clone(),
+            duplicates: server.duplicates.clone(),
+            sequences: server.sequences.clone(),
+            cadences: server.cadences.clone(),
+            disconnects: server.disconnects.clone(),
+            lags: server.lags.clone(),
+            allowlist: server.allowlist.clone(),
+            maintenance: server.maintenance.clone(),
+            config: Default::default(),
+            shutdown: server.shutdown.clone(),
+        });
+        let disconnects = server.disconnects.clone();
+        let addr = spawn_grpc(server).await;
+        let now = current_timestamp_ms();
+
+        // agent-bad 先发一条合法样本，再发一条无法解码的样本
+        let channel = tonic::transport::Endpoint::from_shared(addr.clone())
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut grpc = tonic::client::Grpc::new(channel);
+        grpc.ready().await.unwrap();
+        let (tx, rx) = mpsc::channel(4);
+        for bad_hostname in [None, Some(1)] {
+            tx.send(RawSample {
+                agent_id: "agent-bad".to_string(),
+                timestamp: now,
+                bad_hostname,
+            })
+            .await
+            .unwrap();
+        }
+        grpc.client_streaming::<_, _, StreamResponse, _>(
+            Request::new(ReceiverStream::new(rx)),
+            "/probe.ProbeService/StreamMetrics".parse().unwrap(),
+            ProstCodec::default(),
+        )
+        .await
+        .unwrap();
+
+        // agent-ok 正常结束流
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+        let (tx_ok, rx) = mpsc::channel(4);
+        tx_ok.send(sample("agent-ok", now)).await.unwrap();
+        drop(tx_ok);
+        client
+            .stream_metrics(ReceiverStream::new(rx))
+            .await
+            .unwrap();
+
+        // 流在后台任务中处理，等待两条流都结束
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while disconnects.last("agent-bad").is_none() || disconnects.last("agent-ok").is_none()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("both streams should end");
+        drop(tx);
+
+        let response = app
+            .oneshot(HttpRequest::get("/api/agents").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let agents = value["data"].as_array().unwrap();
+        let disconnect_of = |agent_id: &str| {
+            agents
+                .iter()
+                .find(|agent| agent["agent_id"] == agent_id)
+                .unwrap()["last_disconnect"]
+                .clone()
+        };
+
+        let bad = disconnect_of("agent-bad");
+        assert_eq!(bad["kind"], "error");
+        let reason = bad["reason"].as_str().unwrap();
+        assert!(reason.contains("decode"), "{}", reason);
+        assert!(bad["timestamp"].as_i64().unwrap() >= now);
+
+        let ok = disconnect_of("agent-ok");
+        assert_eq!(ok["kind"], "closed");
+        assert!(ok["reason"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_disabled_still_persists() {
+        use axum::body::Body;
+        use axum::http::{Request as HttpRequest, StatusCode};
+        use tower::ServiceExt;
+
+        let server = ProbeServer::memory_only()
+            .unwrap()
+            .with_server_config(ServerConfig {
+                broadcast_enabled: false,
+                ..Default::default()
+            });
+        let storage = server.storage.clone();
+        let mut events = server.broadcast.subscribe();
+        let app = api::create_router(api::ApiState {
+            storage: server.storage.clone(),
+            broadcast: server.broadcast.clone(),
+            stats: server.stats.clone(),
+            duplicates: server.duplicates.clone(),
+            sequences: server.sequences.clone(),
+            cadences: server.cadences.clone(),
+            disconnects: server.disconnects.clone(),
+            lags: server.lags.clone(),
+            allowlist: server.allowlist.clone(),
+            maintenance: server.maintenance.clone(),
+            config: api::ApiConfig {
+                broadcast_enabled: false,
+                ..Default::default()
+            },
+            shutdown: server.shutdown.clone(),
+        });
+        let addr = spawn_grpc(server).await;
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+
+        let now = current_timestamp_ms();
+        let response = client
+            .report_metrics(sample("agent-1", now))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.success);
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(sample("agent-1", now + 1000)).await.unwrap();
+        drop(tx);
+        client
+            .stream_metrics(ReceiverStream::new(rx))
+            .await
+            .unwrap();
+
+        // 即使有订阅者也不广播，样本照常保存（流式样本在后台任务中处理）
+        let history: Vec<_> = loop {
+            let history = storage.get_agent_history("agent-1", 10).await;
+            if history.len() == 2 {
+                break history.iter().map(|m| m.timestamp).collect();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(history, vec![now, now + 1000]);
+        assert!(events.try_recv().is_err());
+
+        let status = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(HttpRequest::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (status, String::from_utf8_lossy(&body).into_owned())
+            }
+        };
+        let (code, body) = status("/api/stream").await;
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        assert!(body.contains("实时推送已关闭"), "{}", body);
+        assert_eq!(status("/api/ws").await.0, StatusCode::NOT_FOUND);
+        // 查询接口不受影响
+        assert_eq!(
+            status("/api/agents/agent-1/metrics").await.0,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_agent_id_rejected_at_ingest() {
+        let server = ProbeServer::memory_only().unwrap();
+        let storage = server.storage.clone();
+        let addr = spawn_grpc(server).await;
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+
+        let now = current_timestamp_ms();
+        for invalid in ["agent\0evil", ""] {
+            let status = client
+                .report_metrics(sample(invalid, now))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument, "{:?}", invalid);
+        }
+        let response = client
+            .report_metrics(sample(" agent-1 ", now))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.success);
+
+        // 流式连接中的非法样本被丢弃，同一条流上的合法样本照常保存
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(sample("agent\0evil", now + 1000)).await.unwrap();
+        tx.send(sample("agent-1", now + 2000)).await.unwrap();
+        drop(tx);
+        client
+            .stream_metrics(ReceiverStream::new(rx))
+            .await
+            .unwrap();
+        let history: Vec<_> = loop {
+            let history = storage.get_agent_history("agent-1", 10).await;
+            if history.len() == 2 {
+                break history.iter().map(|m| m.timestamp).collect();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(history, vec![now, now + 2000]);
+        assert_eq!(storage.get_all_agents().await, vec!["agent-1".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_rejects_unknown_agents() {
+        let server = ProbeServer::memory_only()
+            .unwrap()
+            .with_server_config(ServerConfig {
+                allowed_agents: Some(["agent-ok".to_string()].into()),
+                ..Default::default()
+            });
+        let storage = server.storage.clone();
+        let allowlist = server.allowlist.clone();
+        let addr = spawn_grpc(server).await;
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+
+        let now = current_timestamp_ms();
+        assert!(
+            client
+                .report_metrics(sample("agent-ok", now))
+                .await
+                .unwrap()
+                .into_inner()
+                .success
+        );
+        let status = client
+            .report_metrics(sample("agent-unknown", now))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        // 流式连接按 metadata 中的 agent_id 在建立前校验，未携带时同样拒绝
+        let stream = |agent_id: Option<&str>| {
+            let (_tx, rx) = mpsc::channel::<MetricsRequest>(1);
+            let mut request = Request::new(ReceiverStream::new(rx));
+            if let Some(agent_id) = agent_id {
+                request
+                    .metadata_mut()
+                    .insert(transport::AGENT_ID_METADATA, agent_id.parse().unwrap());
+            }
+            request
+        };
+        for agent_id in [Some("agent-unknown"), None] {
+            let status = client.stream_metrics(stream(agent_id)).await.unwrap_err();
+            assert_eq!(
+                status.code(),
+                tonic::Code::PermissionDenied,
+                "{:?}",
+                agent_id
+            );
+        }
+        client
+            .stream_metrics(stream(Some("agent-ok")))
+            .await
+            .unwrap();
+
+        // 登记后接受
+        assert_eq!(allowlist.register("agent-unknown".to_string()), Some(true));
+        client
+            .report_metrics(sample("agent-unknown", now))
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_all_agents().await,
+            vec!["agent-ok".to_string(), "agent-unknown".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disk_free_alert_recorded_in_timeline() {
+        let server = ProbeServer::memory_only()
+            .unwrap()
+            .with_server_config(ServerConfig {
+                disk_free_alerts: vec!["10GiB".parse().unwrap()],
+                ..Default::default()
+            });
+        let storage = server.storage.clone();
+        let addr = spawn_grpc(server).await;
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+
+        let now = current_timestamp_ms();
+        let with_free = |timestamp: i64, available: u64| {
+            let mut metrics = sample("agent-1", timestamp);
+            metrics.system = Some(common::proto::SystemMetrics {
+                disks: vec![common::proto::DiskMetrics {
+                    mount_point: "/data".to_string(),
+                    total: 100 << 30,
+                    available,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            });
+            metrics
+        };
+        for (timestamp, available) in [
+            (now, 2 << 30),
+            (now + 1000, 3 << 30),
+            (now + 2000, 50 << 30),
+        ] {
+            client
+                .report_metrics(with_free(timestamp, available))
+                .await
+                .unwrap();
+        }
+
+        let kinds: Vec<_> = storage
+            .get_events("agent-1", 0, i64::MAX)
+            .await
+            .into_iter()
+            .map(|event| event.kind)
+            .filter(|kind| *kind != AgentEventKind::Online)
+            .collect();
+        assert_eq!(
+            kinds,
+            [AgentEventKind::AlertFired, AgentEventKind::AlertResolved]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_resolves_firing_alerts() {
+        let server = ProbeServer::memory_only()
+            .unwrap()
+            .with_server_config(ServerConfig {
+                disk_free_alerts: vec!["10GiB".parse().unwrap()],
+                ..Default::default()
+            });
+        let storage = server.storage.clone();
+        let grpc_listener = listen::GrpcListener::bind("127.0.0.1:0").unwrap();
+        let grpc_addr = grpc_listener.local_addr().unwrap();
+        let http_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(server.serve(grpc_listener, http_listener, async {
+            let _ = stop_rx.await;
+        }));
+
+        let mut client = loop {
+            match ProbeServiceClient::connect(format!("http://{}", grpc_addr)).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        };
+        let mut metrics = sample("agent-1", current_timestamp_ms());
+        metrics.system = Some(common::proto::SystemMetrics {
+            disks: vec![common::proto::DiskMetrics {
+                mount_point: "/data".to_string(),
+                total: 100 << 30,
+                available: 2 << 30,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        client.report_metrics(metrics).await.unwrap();
+        drop(client);
+
+        stop_tx.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+
+        // 关闭钩子为仍在触发的告警补记恢复事件
+        let kinds: Vec<_> = storage
+            .get_events("agent-1", 0, i64::MAX)
+            .await
+            .into_iter()
+            .map(|event| event.kind)
+            .filter(|kind| *kind != AgentEventKind::Online)
+            .collect();
+        assert_eq!(
+            kinds,
+            [AgentEventKind::AlertFired, AgentEventKind::AlertResolved]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stale_sample_kept_as_history_only() {
+        let server = ProbeServer::memory_only()
+            .unwrap()
+            .with_server_config(ServerConfig {
+                max_sample_age: Duration::from_secs(60),
+                ..Default::default()
+            });
+        let storage = server.storage.clone();
+        let mut events = server.broadcast.subscribe();
+        let addr = spawn_grpc(server).await;
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+
+        let now = current_timestamp_ms();
+        let stale = now - 2 * 3_600_000;
+        for timestamp in [now, stale] {
+            let response = client
+                .report_metrics(sample("agent-backfill", timestamp))
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(response.success);
+        }
+
+        // 回放的旧样本不成为最新样本，也不推送到实时流
+        let latest = storage.get_agent_latest("agent-backfill").await.unwrap();
+        assert_eq!(latest.timestamp, now);
+        assert_eq!(&*events.try_recv().unwrap().agent_id, "agent-backfill");
+        assert!(events.try_recv().is_err());
+
+        // 但仍按时间顺序保留在历史中
+        let history: Vec<_> = storage
+            .get_agent_history("agent-backfill", 10)
+            .await
+            .iter()
+            .map(|m| m.timestamp)
+            .collect();
+        assert_eq!(history, vec![stale, now]);
+
+        // 过期样本不参与重启检测，之后的实时样本只作为基准
+        for (timestamp, boot_id) in [(stale, "boot-a"), (now, "boot-b")] {
+            let mut metrics = sample("agent-reboot", timestamp);
+            metrics.system = Some(common::proto::SystemMetrics {
+                system_info: Some(common::proto::SystemInfo {
+                    boot_id: boot_id.to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+            client.report_metrics(metrics).await.unwrap();
+        }
+        let kinds: Vec<_> = storage
+            .get_events("agent-reboot", 0, i64::MAX)
+            .await
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(kinds, [AgentEventKind::Online]);
+    }
+
+    #[tokio::test]
+    async fn test_non_finite_sample_sanitized_before_storage_and_sse() {
+        let server = ProbeServer::memory_only().unwrap();
+        let storage = server.storage.clone();
+        let mut events = server.broadcast.subscribe();
+        let addr = spawn_grpc(server).await;
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+
+        let mut metrics = sample("agent-nan", 1000);
+        metrics.system = Some(common::proto::SystemMetrics {
+            cpu: Some(common::proto::CpuMetrics {
+                usage_percent: f64::NAN,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let response = client
+            .report_metrics(metrics.clone())
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.success);
+
+        let latest = storage.get_agent_latest("agent-nan").await.unwrap();
+        let cpu = latest.system.unwrap().cpu.unwrap();
+        assert_eq!(cpu.usage_percent, 0.0);
+        let event = events.try_recv().unwrap();
+        assert!(
+            event.json.contains(r#""usage_percent":0.0"#),
+            "{}",
+            event.json
+        );
+        assert!(
+            !event.json.contains(r#""usage_percent":null"#),
+            "{}",
+            event.json
+        );
+
+        // 配置为丢弃时整条样本不保存、不推送
+        let server = ProbeServer::memory_only()
+            .unwrap()
+            .with_server_config(ServerConfig {
+                non_finite: NonFinitePolicy::Drop,
+                ..Default::default()
+            });
+        let storage = server.storage.clone();
+        let mut events = server.broadcast.subscribe();
+        let addr = spawn_grpc(server).await;
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+        let response = client.report_metrics(metrics).await.unwrap().into_inner();
+        assert!(!response.success);
+        assert!(storage.get_agent_latest("agent-nan").await.is_none());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ingest_stats_count_all_samples() {
+        let server = ProbeServer::memory_only().unwrap();
+        let stats = server.stats.clone();
+        let addr = spawn_grpc(server).await;
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+
+        // 流式 20 条 + 单次上报 5 条
+        let (tx, rx) = mpsc::channel(32);
+        client
+            .stream_metrics(ReceiverStream::new(rx))
+            .await
+            .unwrap();
+        for ts in 0..20 {
+            tx.send(sample("agent-stream", ts)).await.unwrap();
+        }
+        drop(tx);
+        for ts in 0..5 {
+            client
+                .report_metrics(sample("agent-unary", ts))
+                .await
+                .unwrap();
+        }
+
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            while stats.total() < 25 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await;
+        assert!(
+            received.is_ok(),
+            "expected 25 samples, got {}",
+            stats.total()
+        );
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total, 25);
+        assert_eq!(snapshot.per_agent["agent-stream"], 20);
+        assert_eq!(snapshot.per_agent["agent-unary"], 5);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_delivered_counts_subscribers() {
+        let server = ProbeServer::memory_only().unwrap();
+        let stats = server.stats.clone();
+        let broadcast = server.broadcast.clone();
+        let addr = spawn_grpc(server).await;
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+
+        client.report_metrics(sample("agent-1", 1)).await.unwrap();
+        let snapshot = stats.snapshot().broadcast;
+        assert_eq!(snapshot.no_subscribers, 1);
+        assert_eq!(snapshot.delivered, 0);
+
+        let subscribers: Vec<_> = (0..3).map(|_| broadcast.subscribe()).collect();
+        client.report_metrics(sample("agent-1", 2)).await.unwrap();
+        let snapshot = stats.snapshot().broadcast;
+        assert_eq!(snapshot.published, 1);
+        assert_eq!(snapshot.delivered, 3);
+        assert_eq!(snapshot.last_subscribers, 3);
+
+        // 订阅者全部断开后不再送达
+        drop(subscribers);
+        client.report_metrics(sample("agent-1", 3)).await.unwrap();
+        let snapshot = stats.snapshot().broadcast;
+        assert_eq!(snapshot.delivered, 3);
+        assert_eq!(snapshot.no_subscribers, 2);
+        assert_eq!(snapshot.last_subscribers, 0);
+    }
+
+    #[tokio::test]
+    async fn test_streams_over_cap_rejected() {
+        let server = ProbeServer::memory_only()
+            .unwrap()
+            .with_server_config(ServerConfig {
+                max_concurrent_streams: 2,
+                ..Default::default()
+            });
+        let stats = server.stats.clone();
+        let addr = spawn_grpc(server).await;
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+
+        let mut senders = Vec::new();
+        for _ in 0..2 {
+            let (tx, rx) = mpsc::channel(1);
+            client
+                .stream_metrics(ReceiverStream::new(rx))
+                .await
+                .unwrap();
+            senders.push(tx);
+        }
+        assert_eq!(stats.active_streams(), 2);
+
+        let (_tx, rx) = mpsc::channel(1);
+        let status = client
+            .stream_metrics(ReceiverStream::new(rx))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(stats.active_streams(), 2);
+
+        // 一条流结束后释放名额
+        drop(senders.pop());
+        let released = tokio::time::timeout(Duration::from_secs(5), async {
+            while stats.active_streams() > 1 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await;
+        assert!(released.is_ok(), "流结束后应释放连接名额");
+        let (_tx, rx) = mpsc::channel(1);
+        client
+            .stream_metrics(ReceiverStream::new(rx))
+            .await
+            .unwrap();
+        assert_eq!(stats.active_streams(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_report_fails_when_persistence_unavailable() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("iris.db");
+        let server = ProbeServer::with_db_path(db_path.to_str().unwrap()).unwrap();
+        let storage = server.storage.clone();
+        let mut events = server.broadcast.subscribe();
+        let addr = spawn_grpc(server).await;
+        let mut client = ProbeServiceClient::connect(addr).await.unwrap();
+
+        let response = client
+            .report_metrics(sample("agent-1", 1000))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.success);
+        assert_eq!(response.backoff_ms, 0);
+        assert!(events.try_recv().is_ok());
+
+        // 写入队列关闭后应告知 Agent 未接收；Agent 重试同一条样本也不会重复缓存或推送
+        storage.shutdown().await.unwrap();
+        for _ in 0..3 {
+            let response = client
+                .report_metrics(sample("agent-1", 2000))
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(!response.success);
+        }
+        assert_eq!(
+            storage.get_agent_latest("agent-1").await.unwrap().timestamp,
+            1000
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    /// 把日志输出收集到内存，供断言 span 字段
+    #[derive(Clone, Default)]
+    struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trace_id_follows_report_through_flush() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        // 单线程运行时，批量写入任务也在当前线程上执行
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("iris.db");
+        let server = ProbeServer::with_db_path(db_path.to_str().unwrap()).unwrap();
+
+        let mut request = Request::new(sample("agent-traced", 1000));
+        request
+            .metadata_mut()
+            .insert(trace::REQUEST_ID_HEADER, "trace-abc123".parse().unwrap());
+        assert!(
+            server
+                .report_metrics(request)
+                .await
+                .unwrap()
+                .into_inner()
+                .success
+        );
+        server.storage.shutdown().await.unwrap();
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        for message in [
+            "收到来自 agent-traced 的指标数据",
+            "Metrics saved to cache",
+            "Metrics flushed to persistence",
+        ] {
+            let line = output
+                .lines()
+                .find(|line| line.contains(message))
+                .unwrap_or_else(|| panic!("missing log {:?} in:\n{}", message, output));
+            assert!(line.contains("trace_id=trace-abc123"), "{}", line);
+        }
+    }
+
+    /// 以指定落盘延迟启动完整服务，在一条 Agent 流式连接与一个 SSE 订阅保持打开时关闭，
+    /// 返回关闭结果与耗时
+    async fn shutdown_with_open_connections(
+        flush_delay: Duration,
+        budget: Duration,
+    ) -> (Result<()>, Duration) {
+        use crate::storage::memory::MemoryBackend;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut server = ProbeServer::memory_only()
+            .unwrap()
+            .with_server_config(ServerConfig {
+                shutdown_timeout: budget,
+                ..Default::default()
+            });
+        let backend = MemoryBackend::new().with_flush_delay(flush_delay);
+        server.storage = Arc::new(storage::Storage::with_backend(
+            storage::StorageConfig {
+                batch_size: 1,
+                ..Default::default()
+            },
+            Arc::new(backend),
+        ));
+
+        let grpc_listener = listen::GrpcListener::bind("127.0.0.1:0").unwrap();
+        let grpc_addr = grpc_listener.local_addr().unwrap();
+        let http_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_addr = http_listener.local_addr().unwrap();
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(server.serve(grpc_listener, http_listener, async {
+            let _ = stop_rx.await;
+        }));
+
+        let mut client = loop {
+            match ProbeServiceClient::connect(format!("http://{}", grpc_addr)).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        };
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(sample("agent-1", current_timestamp_ms()))
+            .await
+            .unwrap();
+        client
+            .stream_metrics(ReceiverStream::new(rx))
+            .await
+            .unwrap();
+
+        let mut sse = tokio::net::TcpStream::connect(http_addr).await.unwrap();
+        sse.write_all(b"GET /api/stream HTTP/1.1\r\nHost: iris\r\n\r\n")
+            .await
+            .unwrap();
+        let mut head = [0u8; 64];
+        let n = sse.read(&mut head).await.unwrap();
+        assert!(String::from_utf8_lossy(&head[..n]).starts_with("HTTP/1.1 200"));
+
+        let started = std::time::Instant::now();
+        stop_tx.send(()).unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(10), serving)
+            .await
+            .expect("关闭应在预算内结束")
+            .unwrap();
+        let elapsed = started.elapsed();
+
+        // SSE 订阅在关闭时被结束
+        let mut rest = Vec::new();
+        let closed = tokio::time::timeout(Duration::from_secs(1), sse.read_to_end(&mut rest)).await;
+        assert!(closed.is_ok(), "SSE 连接未被关闭");
+        drop(tx);
+        (result, elapsed)
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_bounded_by_budget_with_slow_writer() {
+        let budget = Duration::from_secs(1);
+
+        // 打开的流式连接与 SSE 订阅不会拖住关闭，数据正常落盘
+        let (result, elapsed) = shutdown_with_open_connections(Duration::ZERO, budget).await;
+        result.unwrap();
+        assert!(elapsed < budget, "{:?}", elapsed);
+
+        // 落盘卡住一分钟时按预算放弃等待并报告
+        let (result, elapsed) =
+            shutdown_with_open_connections(Duration::from_secs(60), budget).await;
+        let error = result.unwrap_err();
+        assert!(error.to_string().contains("关闭超时"), "{:#}", error);
+        assert!(elapsed >= budget, "{:?}", elapsed);
+        assert!(elapsed < budget + Duration::from_secs(2), "{:?}", elapsed);
+    }
+}
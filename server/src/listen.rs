@@ -0,0 +1,218 @@
+//! 监听地址解析
+//!
+//! gRPC 支持 `ip:port`（含 IPv6 `[::1]:50051`）与 `unix:<path>`；
+//! HTTP API 默认监听 gRPC 端口 + 1，Unix socket 模式下需显式指定
+
+use anyhow::{Context, Result};
+use common::proto::probe_service_server::ProbeServiceServer;
+use common::transport::unix_socket_path;
+use std::future::Future;
+use std::net::SocketAddr;
+use tonic::transport::Server;
+use tracing::info;
+
+use crate::ProbeServer;
+
+/// 已解析/绑定的 gRPC 监听器
+pub enum GrpcListener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, std::path::PathBuf),
+}
+
+impl GrpcListener {
+    /// 解析并立即绑定地址；Unix socket 先清理残留的 socket 文件
+    ///
+    /// TCP 端口为 0 时由系统分配，实际端口见 [`Self::local_addr`]
+    pub fn bind(addr: &str) -> Result<Self> {
+        match unix_socket_path(addr) {
+            #[cfg(unix)]
+            Some(path) => {
+                remove_stale_socket(path)?;
+                let listener = tokio::net::UnixListener::bind(path)
+                    .with_context(|| format!("无法绑定 Unix socket {}", path.display()))?;
+                Ok(Self::Unix(listener, path.to_path_buf()))
+            }
+            #[cfg(not(unix))]
+            Some(path) => anyhow::bail!("当前平台不支持 Unix socket: {}", path.display()),
+            None => {
+                let addr: SocketAddr = addr
+                    .parse()
+                    .with_context(|| format!("无效的 gRPC 监听地址: {}", addr))?;
+                let listener = std::net::TcpListener::bind(addr)
+                    .with_context(|| format!("无法绑定 gRPC 地址 {}", addr))?;
+                listener.set_nonblocking(true)?;
+                Ok(Self::Tcp(listener))
+            }
+        }
+    }
+
+    /// 实际监听的地址：`ip:port` 或 `unix:<path>`
+    pub fn local_addr(&self) -> Result<String> {
+        match self {
+            Self::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            #[cfg(unix)]
+            Self::Unix(_, path) => Ok(format!("unix:{}", path.display())),
+        }
+    }
+
+    /// 推导 HTTP API 地址：优先使用显式配置，否则为 gRPC 端口 + 1
+    pub fn http_addr(&self, configured: Option<SocketAddr>) -> Result<SocketAddr> {
+        if let Some(addr) = configured {
+            return Ok(addr);
+        }
+        match self {
+            Self::Tcp(listener) => derive_http_addr(listener.local_addr()?),
+            #[cfg(unix)]
+            Self::Unix(..) => {
+                anyhow::bail!("gRPC 使用 Unix socket 时需通过 --http-addr 指定 HTTP 地址")
+            }
+        }
+    }
+
+    /// 在该监听器上运行 gRPC 服务，直到 shutdown 完成
+    pub async fn serve(
+        self,
+        server: ProbeServer,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        let router = Server::builder().add_service(ProbeServiceServer::new(server));
+        match self {
+            Self::Tcp(listener) => {
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                info!("gRPC Server 启动在 {}", listener.local_addr()?);
+                let incoming =
+                    tonic::transport::server::TcpIncoming::from_listener(listener, true, None)
+                        .map_err(|e| anyhow::anyhow!("无法监听 gRPC 地址: {}", e))?;
+                router
+                    .serve_with_incoming_shutdown(incoming, shutdown)
+                    .await?;
+            }
+            #[cfg(unix)]
+            Self::Unix(listener, path) => {
+                info!("gRPC Server 启动在 unix:{}", path.display());
+                let incoming = tokio_stream::wrappers::UnixListenerStream::new(listener);
+                let result = router
+                    .serve_with_incoming_shutdown(incoming, shutdown)
+                    .await;
+                let _ = std::fs::remove_file(&path);
+                result?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 清理上次运行残留的 socket 文件
+///
+/// 只删除无人监听的 socket；路径上是普通文件、目录等其他类型，或仍有进程在监听时返回错误，
+/// 避免写错 `--listen` 时误删文件
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("无法读取 {} 的文件信息", path.display())),
+    };
+    if !metadata.file_type().is_socket() {
+        anyhow::bail!("{} 已存在且不是 Unix socket，拒绝覆盖", path.display());
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        anyhow::bail!("Unix socket {} 正在被其他进程监听", path.display());
+    }
+    std::fs::remove_file(path)
+        .with_context(|| format!("无法清理残留的 socket 文件 {}", path.display()))
+}
+
+/// 由 gRPC 地址推导 HTTP 地址（同 IP，端口 + 1）
+///
+/// 使用 `SocketAddr` 而非字符串拼接，IPv6 会正确格式化为 `[::1]:50052`
+pub fn derive_http_addr(grpc_addr: SocketAddr) -> Result<SocketAddr> {
+    let port = grpc_addr
+        .port()
+        .checked_add(1)
+        .context("gRPC 端口为 65535，无法推导 HTTP 端口")?;
+    Ok(SocketAddr::new(grpc_addr.ip(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::probe_service_client::ProbeServiceClient;
+    use common::proto::HeartbeatRequest;
+
+    #[test]
+    fn test_http_addr_ipv6() {
+        let http = derive_http_addr("[::1]:50051".parse().unwrap()).unwrap();
+        assert_eq!(http.to_string(), "[::1]:50052");
+
+        let http = derive_http_addr("0.0.0.0:50051".parse().unwrap()).unwrap();
+        assert_eq!(http.to_string(), "0.0.0.0:50052");
+
+        assert!(derive_http_addr("127.0.0.1:65535".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_bind_reports_assigned_port() {
+        let listener = GrpcListener::bind("127.0.0.1:0").unwrap();
+        let grpc: SocketAddr = listener.local_addr().unwrap().parse().unwrap();
+        assert_ne!(grpc.port(), 0);
+        assert_eq!(listener.http_addr(None).unwrap().port(), grpc.port() + 1);
+        assert!(GrpcListener::bind("localhost").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_accepts_grpc() {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = format!("unix:{}", dir.path().join("iris.sock").display());
+
+        let listener = GrpcListener::bind(&addr).unwrap();
+        assert!(listener.http_addr(None).is_err());
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = ProbeServer::memory_only().unwrap();
+        let handle = tokio::spawn(listener.serve(server, async {
+            let _ = rx.await;
+        }));
+
+        let channel = common::transport::connect(&addr).await.unwrap();
+        let response = ProbeServiceClient::new(channel)
+            .heartbeat(HeartbeatRequest {
+                agent_id: "agent-1".to_string(),
+                timestamp: 0,
+            })
+            .await
+            .unwrap();
+        assert!(response.into_inner().alive);
+
+        tx.send(()).unwrap();
+        handle.await.unwrap().unwrap();
+        assert!(!dir.path().join("iris.sock").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_bind_only_replaces_stale_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // 普通文件不会被删除
+        let file = dir.path().join("metrics.db");
+        std::fs::write(&file, b"data").unwrap();
+        assert!(GrpcListener::bind(&format!("unix:{}", file.display())).is_err());
+        assert_eq!(std::fs::read(&file).unwrap(), b"data");
+
+        // 仍在监听的 socket 不会被抢占
+        let socket = dir.path().join("iris.sock");
+        let addr = format!("unix:{}", socket.display());
+        let listener = GrpcListener::bind(&addr).unwrap();
+        assert!(GrpcListener::bind(&addr).is_err());
+
+        // 监听者退出后残留的 socket 文件会被清理并重新绑定
+        drop(listener);
+        assert!(socket.exists());
+        assert!(GrpcListener::bind(&addr).is_ok());
+    }
+}
@@ -0,0 +1,125 @@
+//! 单次上报 Agent 的上下线检测
+//!
+//! 流式连接的建立与断开直接对应上下线事件；单次上报（`report_metrics`）没有连接可跟踪，
+//! 这里按 agent_id 记录最近一次收到样本的 Server 时间：首次或离线后再次上报时记录 `Online`，
+//! 定期巡检时超过离线阈值（与 Agent 列表的在线状态判断相同）未上报的记录 `Offline`。
+//! 改用流式连接的 Agent 由流自身记录上下线，不再在此跟踪。状态只保存在内存中，Server 重启后
+//! 每个 Agent 的第一条样本记录一次 `Online`
+
+use crate::storage::{AgentEvent, AgentEventKind};
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use tracing::info;
+
+/// 单个 Agent 的在线状态
+#[derive(Debug)]
+struct Liveness {
+    /// 最近一次收到样本的 Server 时间（毫秒）
+    last_seen: i64,
+    online: bool,
+}
+
+/// 按 agent_id 跟踪单次上报 Agent 的上下线
+#[derive(Debug, Default)]
+pub struct LivenessTracker {
+    agents: Mutex<HashMap<String, Liveness>>,
+}
+
+impl LivenessTracker {
+    /// 记录一次单次上报，Agent 首次出现或此前已离线时返回 `Online` 事件
+    pub fn observe(&self, agent_id: &str, now: i64) -> Option<AgentEvent> {
+        let mut agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        let was_online = match agents.get_mut(agent_id) {
+            Some(state) => {
+                state.last_seen = state.last_seen.max(now);
+                std::mem::replace(&mut state.online, true)
+            }
+            None => {
+                agents.insert(
+                    agent_id.to_string(),
+                    Liveness {
+                        last_seen: now,
+                        online: true,
+                    },
+                );
+                false
+            }
+        };
+        if was_online {
+            return None;
+        }
+        info!("Agent {} 开始单次上报", agent_id);
+        Some(AgentEvent {
+            kind: AgentEventKind::Online,
+            timestamp: now,
+            details: "收到单次上报".to_string(),
+        })
+    }
+
+    /// Agent 改用流式连接，上下线改由流记录
+    pub fn forget(&self, agent_id: &str) {
+        self.agents
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(agent_id);
+    }
+
+    /// 巡检全部在线的 Agent，返回超过 `offline_after(agent_id)` 毫秒未上报者的 `Offline` 事件
+    pub fn sweep(
+        &self,
+        now: i64,
+        offline_after: impl Fn(&str) -> i64,
+    ) -> Vec<(String, AgentEvent)> {
+        let mut agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut events = Vec::new();
+        for (agent_id, state) in agents.iter_mut() {
+            let threshold = offline_after(agent_id);
+            if !state.online || now.saturating_sub(state.last_seen) <= threshold {
+                continue;
+            }
+            state.online = false;
+            info!("Agent {} 超过 {} ms 未上报，视为离线", agent_id, threshold);
+            events.push((
+                agent_id.clone(),
+                AgentEvent {
+                    kind: AgentEventKind::Offline,
+                    timestamp: now,
+                    details: format!("超过 {}s 未上报", threshold / 1000),
+                },
+            ));
+        }
+        events.sort_by(|a, b| a.0.cmp(&b.0));
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_online_offline_transitions() {
+        let tracker = LivenessTracker::default();
+        let online = tracker.observe("agent-1", 1_000).unwrap();
+        assert_eq!(online.kind, AgentEventKind::Online);
+        assert!(tracker.observe("agent-1", 2_000).is_none());
+
+        // 未超过阈值时保持在线
+        assert!(tracker.sweep(10_000, |_| 10_000).is_empty());
+        let offline = tracker.sweep(12_001, |_| 10_000);
+        assert_eq!(offline.len(), 1);
+        assert_eq!(offline[0].0, "agent-1");
+        assert_eq!(offline[0].1.kind, AgentEventKind::Offline);
+        assert_eq!(offline[0].1.timestamp, 12_001);
+        // 已离线的不重复记录
+        assert!(tracker.sweep(60_000, |_| 10_000).is_empty());
+
+        // 恢复上报后重新上线
+        let online = tracker.observe("agent-1", 61_000).unwrap();
+        assert_eq!(online.kind, AgentEventKind::Online);
+
+        // 改用流式连接后不再巡检
+        tracker.forget("agent-1");
+        assert!(tracker.sweep(1_000_000, |_| 10_000).is_empty());
+    }
+}
@@ -0,0 +1,43 @@
+//! Agent 维护模式
+//!
+//! 计划内维护（如重启主机）期间不希望收到告警：经 `POST /api/agents/:id/maintenance` 为 Agent
+//! 设置维护截止时间，到期前告警引擎不再为该 Agent 触发新告警（已触发的告警仍可正常恢复）。
+//! 到期后自动解除，无需再次调用。维护状态只保存在内存中，Server 重启后清空
+
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+
+/// 按 agent_id 记录维护截止时间（毫秒）
+#[derive(Debug, Default)]
+pub struct MaintenanceWindows {
+    until: Mutex<HashMap<String, i64>>,
+}
+
+impl MaintenanceWindows {
+    /// 设置维护截止时间，覆盖之前的设置
+    pub fn start(&self, agent_id: &str, until_ms: i64) {
+        let mut until = self.until.lock().unwrap_or_else(PoisonError::into_inner);
+        until.insert(agent_id.to_string(), until_ms);
+    }
+
+    /// 提前结束维护，返回之前是否处于维护中
+    pub fn end(&self, agent_id: &str, now: i64) -> bool {
+        let mut until = self.until.lock().unwrap_or_else(PoisonError::into_inner);
+        until
+            .remove(agent_id)
+            .is_some_and(|until_ms| until_ms > now)
+    }
+
+    /// 该 Agent 在 `now` 时的维护截止时间，不在维护中时为 None；已到期的记录随之清除
+    pub fn active_until(&self, agent_id: &str, now: i64) -> Option<i64> {
+        let mut until = self.until.lock().unwrap_or_else(PoisonError::into_inner);
+        match until.get(agent_id) {
+            Some(&until_ms) if until_ms > now => Some(until_ms),
+            Some(_) => {
+                until.remove(agent_id);
+                None
+            }
+            None => None,
+        }
+    }
+}
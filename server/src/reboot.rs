@@ -0,0 +1,122 @@
+//! 主机重启检测
+//!
+//! 按 agent_id 记录最近一次的 `SystemInfo.boot_id` 与运行时长。boot_id 变化即视为重启；
+//! 非 Linux 主机没有 boot_id，退而看运行时长是否回落。只有带 `system_info` 的实时样本参与
+//! 判断：时间戳不晚于已记录样本的（补发、乱序）样本直接忽略，否则重启前缓冲的旧样本会被
+//! 误判为又一次重启。Server 重启后第一条样本只作为基准，不产生事件
+
+use crate::storage::{AgentEvent, AgentEventKind};
+use common::proto::MetricsRequest;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use tracing::info;
+
+/// 单个 Agent 最近一次的启动信息
+#[derive(Debug, Default, Clone)]
+struct BootState {
+    boot_id: String,
+    /// 运行时长（秒）
+    uptime: u64,
+    /// 样本时间戳（毫秒）
+    timestamp: i64,
+}
+
+/// 按 agent_id 检测主机重启
+#[derive(Debug, Default)]
+pub struct RebootDetector {
+    agents: Mutex<HashMap<String, BootState>>,
+}
+
+impl RebootDetector {
+    /// 记录一条样本的启动信息，检测到重启时返回对应的时间线事件
+    ///
+    /// 时间戳不晚于该 Agent 已记录样本的样本不参与判断，也不覆盖记录
+    pub fn observe(&self, metrics: &MetricsRequest) -> Option<AgentEvent> {
+        let info = metrics.system.as_ref()?.system_info.as_ref()?;
+        let current = BootState {
+            boot_id: info.boot_id.clone(),
+            uptime: info.uptime,
+            timestamp: metrics.timestamp,
+        };
+
+        let mut agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        if agents
+            .get(&metrics.agent_id)
+            .is_some_and(|previous| previous.timestamp >= current.timestamp)
+        {
+            return None;
+        }
+        let previous = agents.insert(metrics.agent_id.clone(), current.clone())?;
+
+        let details = if !current.boot_id.is_empty() && !previous.boot_id.is_empty() {
+            if current.boot_id == previous.boot_id {
+                return None;
+            }
+            format!("boot_id {} → {}", previous.boot_id, current.boot_id)
+        } else if current.uptime < previous.uptime {
+            format!("运行时长 {}s → {}s", previous.uptime, current.uptime)
+        } else {
+            return None;
+        };
+
+        info!("Agent {} 所在主机已重启: {}", metrics.agent_id, details);
+        Some(AgentEvent {
+            kind: AgentEventKind::Reboot,
+            timestamp: metrics.timestamp,
+            details,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::{SystemInfo, SystemMetrics};
+
+    fn sample(boot_id: &str, uptime: u64, timestamp: i64) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: "agent-1".to_string(),
+            timestamp,
+            system: Some(SystemMetrics {
+                system_info: Some(SystemInfo {
+                    boot_id: boot_id.to_string(),
+                    uptime,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_boot_id_change_and_uptime_fallback() {
+        let detector = RebootDetector::default();
+        assert!(detector.observe(&sample("a", 100, 1_000)).is_none());
+        assert!(detector.observe(&sample("a", 110, 2_000)).is_none());
+
+        let event = detector.observe(&sample("b", 5, 3_000)).unwrap();
+        assert_eq!(event.kind, AgentEventKind::Reboot);
+        assert_eq!(event.timestamp, 3_000);
+
+        // 没有 boot_id 时看运行时长是否回落
+        assert!(detector.observe(&sample("", 50, 4_000)).is_none());
+        assert!(detector.observe(&sample("", 3, 5_000)).is_some());
+
+        // 不带 system_info 的样本不影响判断
+        assert!(detector.observe(&MetricsRequest::default()).is_none());
+    }
+
+    #[test]
+    fn test_out_of_order_samples_ignored() {
+        let detector = RebootDetector::default();
+        assert!(detector.observe(&sample("a", 100, 1_000)).is_none());
+        let event = detector.observe(&sample("b", 5, 2_000)).unwrap();
+        assert_eq!(event.timestamp, 2_000);
+
+        // 重启前缓冲、之后补发的旧样本不算又一次重启，也不改变基准
+        assert!(detector.observe(&sample("a", 150, 1_500)).is_none());
+        assert!(detector.observe(&sample("a", 160, 2_000)).is_none());
+        assert!(detector.observe(&sample("b", 6, 3_000)).is_none());
+    }
+}
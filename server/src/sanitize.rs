@@ -0,0 +1,322 @@
+//! 非有限浮点数清理
+//!
+//! 采集端的除零等边界情况可能产生 NaN/Inf。它们能正常编码落盘，但序列化为 JSON 后变成
+//! `null`，会破坏前端图表与下游消费者。样本在入库与推送前按 [`NonFinitePolicy`] 处理
+
+use common::proto::MetricsRequest;
+use std::fmt;
+use std::str::FromStr;
+
+/// 样本含非有限浮点数时的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NonFinitePolicy {
+    /// 把非有限值替换为 0，样本照常保存
+    #[default]
+    Zero,
+    /// 丢弃整条样本
+    Drop,
+}
+
+impl FromStr for NonFinitePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(Self::Zero),
+            "drop" => Ok(Self::Drop),
+            other => Err(format!(
+                "未知的非有限值处理方式: {}（可选 zero、drop）",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for NonFinitePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Zero => f.write_str("zero"),
+            Self::Drop => f.write_str("drop"),
+        }
+    }
+}
+
+/// 把样本中的 NaN/Inf 替换为 0，返回被替换的字段路径（如 `system.cpu.per_core[2]`）
+pub fn sanitize_non_finite(metrics: &mut MetricsRequest) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut fix = |path: &dyn Fn() -> String, value: &mut f64| {
+        if !value.is_finite() {
+            fields.push(path());
+            *value = 0.0;
+        }
+    };
+
+    let Some(system) = &mut metrics.system else {
+        return fields;
+    };
+    if let Some(cpu) = &mut system.cpu {
+        fix(
+            &|| "system.cpu.usage_percent".into(),
+            &mut cpu.usage_percent,
+        );
+        for (i, value) in cpu.per_core.iter_mut().enumerate() {
+            fix(&|| format!("system.cpu.per_core[{}]", i), value);
+        }
+        for (i, value) in cpu.per_core_frequency.iter_mut().enumerate() {
+            fix(&|| format!("system.cpu.per_core_frequency[{}]", i), value);
+        }
+        for (i, value) in cpu.per_core_temperature.iter_mut().enumerate() {
+            fix(&|| format!("system.cpu.per_core_temperature[{}]", i), value);
+        }
+        fix(&|| "system.cpu.load_avg_1".into(), &mut cpu.load_avg_1);
+        fix(&|| "system.cpu.load_avg_5".into(), &mut cpu.load_avg_5);
+        fix(&|| "system.cpu.load_avg_15".into(), &mut cpu.load_avg_15);
+    }
+    if let Some(memory) = &mut system.memory {
+        fix(
+            &|| "system.memory.usage_percent".into(),
+            &mut memory.usage_percent,
+        );
+    }
+    for (i, disk) in system.disks.iter_mut().enumerate() {
+        fix(
+            &|| format!("system.disks[{}].usage_percent", i),
+            &mut disk.usage_percent,
+        );
+    }
+    if let Some(info) = &mut system.system_info {
+        fix(
+            &|| "system.system_info.cpu_frequency".into(),
+            &mut info.cpu_frequency,
+        );
+    }
+    if let Some(agent) = &mut system.agent_metrics {
+        fix(
+            &|| "system.agent_metrics.cpu_usage".into(),
+            &mut agent.cpu_usage,
+        );
+        fix(
+            &|| "system.agent_metrics.collection_time_avg_ms".into(),
+            &mut agent.collection_time_avg_ms,
+        );
+    }
+    if let Some(pressure) = &mut system.pressure {
+        let resources = [
+            ("cpu", &mut pressure.cpu),
+            ("memory", &mut pressure.memory),
+            ("io", &mut pressure.io),
+        ];
+        for (name, resource) in resources {
+            let Some(resource) = resource else { continue };
+            for (kind, stall) in [("some", &mut resource.some), ("full", &mut resource.full)] {
+                let Some(stall) = stall else { continue };
+                fix(
+                    &|| format!("system.pressure.{}.{}.avg10", name, kind),
+                    &mut stall.avg10,
+                );
+                fix(
+                    &|| format!("system.pressure.{}.{}.avg60", name, kind),
+                    &mut stall.avg60,
+                );
+                fix(
+                    &|| format!("system.pressure.{}.{}.avg300", name, kind),
+                    &mut stall.avg300,
+                );
+            }
+        }
+    }
+    for (i, gpu) in system.gpu.iter_mut().enumerate() {
+        fix(
+            &|| format!("system.gpu[{}].utilization_percent", i),
+            &mut gpu.utilization_percent,
+        );
+        fix(
+            &|| format!("system.gpu[{}].temperature", i),
+            &mut gpu.temperature,
+        );
+        fix(
+            &|| format!("system.gpu[{}].power_watts", i),
+            &mut gpu.power_watts,
+        );
+    }
+    for (i, process) in system.top_processes.iter_mut().enumerate() {
+        fix(
+            &|| format!("system.top_processes[{}].cpu_usage", i),
+            &mut process.cpu_usage,
+        );
+    }
+    if let Some(containers) = &mut system.containers {
+        fix(
+            &|| "system.containers.cpu_usage_percent".into(),
+            &mut containers.cpu_usage_percent,
+        );
+    }
+
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::{CpuMetrics, DiskMetrics, SystemMetrics};
+
+    #[test]
+    fn test_sanitize_replaces_non_finite_fields() {
+        let mut metrics = MetricsRequest {
+            agent_id: "agent-1".to_string(),
+            system: Some(SystemMetrics {
+                cpu: Some(CpuMetrics {
+                    usage_percent: f64::NAN,
+                    per_core: vec![10.0, f64::INFINITY],
+                    load_avg_1: 0.5,
+                    ..Default::default()
+                }),
+                disks: vec![DiskMetrics {
+                    usage_percent: f64::NEG_INFINITY,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let fields = sanitize_non_finite(&mut metrics);
+        assert_eq!(
+            fields,
+            vec![
+                "system.cpu.usage_percent",
+                "system.cpu.per_core[1]",
+                "system.disks[0].usage_percent",
+            ]
+        );
+        let system = metrics.system.as_ref().unwrap();
+        let cpu = system.cpu.as_ref().unwrap();
+        assert_eq!(cpu.usage_percent, 0.0);
+        assert_eq!(cpu.per_core, vec![10.0, 0.0]);
+        assert_eq!(cpu.load_avg_1, 0.5);
+        assert_eq!(system.disks[0].usage_percent, 0.0);
+
+        // 全部有限时不做修改
+        assert!(sanitize_non_finite(&mut metrics).is_empty());
+        assert_eq!("drop".parse(), Ok(NonFinitePolicy::Drop));
+        assert!("nan".parse::<NonFinitePolicy>().is_err());
+    }
+
+    /// proto 中消息名 → 测试样本里该消息所在的字段路径
+    const MESSAGE_PATHS: &[(&str, &str)] = &[
+        ("CpuMetrics", "system.cpu"),
+        ("MemoryMetrics", "system.memory"),
+        ("DiskMetrics", "system.disks[0]"),
+        ("SystemInfo", "system.system_info"),
+        ("AgentMetrics", "system.agent_metrics"),
+        ("PressureStall", "system.pressure.io.full"),
+        ("GpuMetrics", "system.gpu[0]"),
+        ("ProcessMetrics", "system.top_processes[0]"),
+        ("ContainerMetrics", "system.containers"),
+    ];
+
+    /// 按 proto 定义列出每个 double 字段在测试样本中的路径，repeated 字段取第一个元素
+    fn proto_double_paths() -> Vec<String> {
+        let proto = include_str!("../../proto/probe.proto");
+        let mut message = "";
+        let mut paths = Vec::new();
+        for line in proto.lines().map(str::trim) {
+            if let Some(name) = line.strip_prefix("message ") {
+                message = name.trim_end_matches('{').trim();
+                continue;
+            }
+            let (repeated, rest) = match line.strip_prefix("repeated ") {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let Some(field) = rest.strip_prefix("double ") else {
+                continue;
+            };
+            let field = field.split_whitespace().next().unwrap();
+            let prefix = MESSAGE_PATHS
+                .iter()
+                .find(|(name, _)| *name == message)
+                .map(|(_, prefix)| prefix)
+                .unwrap_or_else(|| panic!("{} 中的 double 字段 {} 未纳入清理", message, field));
+            let suffix = if repeated { "[0]" } else { "" };
+            paths.push(format!("{}.{}{}", prefix, field, suffix));
+        }
+        paths
+    }
+
+    #[test]
+    fn test_sanitize_covers_every_double_field() {
+        use common::proto::{
+            AgentMetrics, ContainerMetrics, GpuMetrics, MemoryMetrics, PressureMetrics,
+            PressureResource, PressureStall, ProcessMetrics, SystemInfo,
+        };
+
+        let nan = f64::NAN;
+        let mut metrics = MetricsRequest {
+            system: Some(SystemMetrics {
+                cpu: Some(CpuMetrics {
+                    usage_percent: nan,
+                    per_core: vec![nan],
+                    load_avg_1: nan,
+                    load_avg_5: nan,
+                    load_avg_15: nan,
+                    per_core_frequency: vec![nan],
+                    per_core_temperature: vec![nan],
+                    ..Default::default()
+                }),
+                memory: Some(MemoryMetrics {
+                    usage_percent: nan,
+                    ..Default::default()
+                }),
+                disks: vec![DiskMetrics {
+                    usage_percent: nan,
+                    ..Default::default()
+                }],
+                system_info: Some(SystemInfo {
+                    cpu_frequency: nan,
+                    ..Default::default()
+                }),
+                agent_metrics: Some(AgentMetrics {
+                    cpu_usage: nan,
+                    collection_time_avg_ms: nan,
+                    ..Default::default()
+                }),
+                pressure: Some(PressureMetrics {
+                    io: Some(PressureResource {
+                        full: Some(PressureStall {
+                            avg10: nan,
+                            avg60: nan,
+                            avg300: nan,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                gpu: vec![GpuMetrics {
+                    utilization_percent: nan,
+                    temperature: nan,
+                    power_watts: nan,
+                    ..Default::default()
+                }],
+                top_processes: vec![ProcessMetrics {
+                    cpu_usage: nan,
+                    ..Default::default()
+                }],
+                containers: Some(ContainerMetrics {
+                    cpu_usage_percent: nan,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let mut fields = sanitize_non_finite(&mut metrics);
+        let mut expected = proto_double_paths();
+        fields.sort();
+        expected.sort();
+        assert_eq!(fields, expected);
+        assert!(sanitize_non_finite(&mut metrics).is_empty());
+    }
+}
@@ -0,0 +1,116 @@
+//! 样本丢失估计
+//!
+//! Agent 为每条样本填写递增的 `MetricsRequest.sequence`（从 1 开始）。这里按 agent_id
+//! 记录最近一次的序号，序号跳跃的部分计为丢失；序号回退视为 Agent 重启后重新计数，
+//! 不计为丢失
+
+use common::proto::MetricsRequest;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use tracing::{info, warn};
+
+/// 单个 Agent 的序号状态
+#[derive(Debug, Default, Clone, Copy)]
+struct SequenceState {
+    /// 最近一次收到的序号
+    last: u64,
+    /// 启动以来估计丢失的样本数
+    dropped: u64,
+}
+
+/// 按 agent_id 跟踪样本序号
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    agents: Mutex<HashMap<String, SequenceState>>,
+}
+
+impl SequenceTracker {
+    /// 记录一条样本的序号
+    ///
+    /// 序号为 0（旧版 Agent 未填写）的样本不参与统计；与上一条相同的序号视为重试，忽略
+    pub fn observe(&self, metrics: &MetricsRequest) {
+        if metrics.sequence == 0 {
+            return;
+        }
+
+        let mut agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(state) = agents.get_mut(&metrics.agent_id) else {
+            agents.insert(
+                metrics.agent_id.clone(),
+                SequenceState {
+                    last: metrics.sequence,
+                    dropped: 0,
+                },
+            );
+            return;
+        };
+
+        let sequence = metrics.sequence;
+        if sequence > state.last {
+            let gap = sequence - state.last - 1;
+            if gap > 0 {
+                warn!(
+                    "Agent {} 样本序号从 {} 跳到 {}，估计丢失 {} 条",
+                    metrics.agent_id, state.last, sequence, gap
+                );
+            }
+            state.dropped += gap;
+        } else if sequence < state.last {
+            // 重启后从 1 开始计数，新一轮中未收到的前几条仍计为丢失
+            info!(
+                "Agent {} 样本序号从 {} 回退到 {}，视为 Agent 重启",
+                metrics.agent_id, state.last, sequence
+            );
+            state.dropped += sequence - 1;
+        }
+        state.last = sequence;
+    }
+
+    /// 该 agent_id 启动以来估计丢失的样本数
+    pub fn dropped_estimate(&self, agent_id: &str) -> u64 {
+        let agents = self.agents.lock().unwrap_or_else(PoisonError::into_inner);
+        agents.get(agent_id).map_or(0, |state| state.dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(agent_id: &str, sequence: u64) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: agent_id.to_string(),
+            sequence,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_gap_counted_as_dropped() {
+        let tracker = SequenceTracker::default();
+        for sequence in [1, 2, 3, 7, 8, 8, 10] {
+            tracker.observe(&sample("agent-1", sequence));
+        }
+        // 缺 4、5、6、9；重复的 8 不计
+        assert_eq!(tracker.dropped_estimate("agent-1"), 4);
+        assert_eq!(tracker.dropped_estimate("agent-2"), 0);
+    }
+
+    #[test]
+    fn test_restart_not_counted_as_drop() {
+        let tracker = SequenceTracker::default();
+        for sequence in [100, 101, 1, 2, 3] {
+            tracker.observe(&sample("agent-1", sequence));
+        }
+        assert_eq!(tracker.dropped_estimate("agent-1"), 0);
+
+        // 重启后首条样本丢失
+        tracker.observe(&sample("agent-1", 2));
+        assert_eq!(tracker.dropped_estimate("agent-1"), 1);
+
+        // 旧版 Agent 不带序号
+        tracker.observe(&sample("agent-1", 0));
+        tracker.observe(&sample("agent-1", 3));
+        assert_eq!(tracker.dropped_estimate("agent-1"), 1);
+    }
+}
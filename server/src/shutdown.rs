@@ -0,0 +1,163 @@
+//! 关闭钩子
+//!
+//! 在内存中累积状态的子系统（告警、分析窗口、自身监控等）注册落盘回调，Server 优雅关闭时
+//! 在连接排空之后、关闭 Storage 之前按注册顺序逐个调用。每个钩子有各自的超时（默认
+//! [`DEFAULT_HOOK_TIMEOUT`]），全部钩子最多用掉剩余关闭预算的一半，另一半留给 Storage 落盘：
+//! 单个钩子返回错误、panic 或超时只记录日志，不影响其余钩子与 Storage 落盘
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// 未指定超时的钩子最多运行的时长
+pub const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Hook = Box<dyn FnOnce() -> BoxFuture<'static, Result<()>> + Send>;
+
+struct Registered {
+    name: String,
+    timeout: Duration,
+    hook: Hook,
+}
+
+/// 关闭钩子注册表，克隆后共享同一组钩子
+#[derive(Clone, Default)]
+pub struct ShutdownHooks {
+    hooks: Arc<Mutex<Vec<Registered>>>,
+}
+
+impl ShutdownHooks {
+    /// 注册一个关闭钩子，`name` 用于日志，超时为 [`DEFAULT_HOOK_TIMEOUT`]
+    pub fn register<F, Fut>(&self, name: impl Into<String>, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.register_with_timeout(name, DEFAULT_HOOK_TIMEOUT, hook);
+    }
+
+    /// 注册一个关闭钩子并指定其超时；实际可用时长不超过关闭时分给钩子的剩余预算
+    pub fn register_with_timeout<F, Fut>(&self, name: impl Into<String>, timeout: Duration, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.lock().push(Registered {
+            name: name.into(),
+            timeout,
+            hook: Box::new(move || Box::pin(hook())),
+        });
+    }
+
+    /// 已注册且尚未运行的钩子数
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 按注册顺序运行全部钩子，每个钩子只运行一次，运行到各自的超时或 `deadline`（先到者）
+    /// 为止；返回失败（含超时）的钩子数
+    pub(crate) async fn run(&self, deadline: Instant) -> usize {
+        let hooks = std::mem::take(&mut *self.lock());
+        let mut failed = 0;
+        for Registered {
+            name,
+            timeout,
+            hook,
+        } in hooks
+        {
+            let hook_deadline = (Instant::now() + timeout).min(deadline);
+            // 在独立任务中运行，panic 不会中断后续钩子
+            let mut task = tokio::spawn(hook());
+            match tokio::time::timeout_at(hook_deadline, &mut task).await {
+                Ok(Ok(Ok(()))) => info!("关闭钩子 {} 已完成", name),
+                Ok(Ok(Err(e))) => {
+                    failed += 1;
+                    warn!("关闭钩子 {} 失败: {:#}", name, e);
+                }
+                Ok(Err(e)) => {
+                    failed += 1;
+                    warn!("关闭钩子 {} 异常退出: {}", name, e);
+                }
+                Err(_) => {
+                    failed += 1;
+                    task.abort();
+                    warn!("关闭钩子 {} 未能在 {:?} 内完成，已放弃", name, timeout);
+                }
+            }
+        }
+        failed
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Registered>> {
+        self.hooks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl fmt::Debug for ShutdownHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.lock().iter().map(|hook| hook.name.clone()).collect();
+        f.debug_struct("ShutdownHooks")
+            .field("hooks", &names)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_failing_hooks_do_not_block_others() {
+        let hooks = ShutdownHooks::default();
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        hooks.register("error", || async { Err(anyhow::anyhow!("写入失败")) });
+        hooks.register("panic", || async { panic!("钩子 panic") });
+        let counter = ran.clone();
+        hooks.register("flush", move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        hooks.register("stuck", std::future::pending);
+        assert_eq!(hooks.len(), 4);
+
+        // 前面的钩子失败不影响后面的钩子，卡住的钩子在预算耗尽后放弃
+        let deadline = Instant::now() + Duration::from_millis(500);
+        assert_eq!(hooks.run(deadline).await, 3);
+        assert_eq!(ran.load(Ordering::SeqCst), 1);
+
+        // 钩子只运行一次
+        assert!(hooks.is_empty());
+        assert_eq!(hooks.run(Instant::now()).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stuck_hook_only_uses_its_own_timeout() {
+        let hooks = ShutdownHooks::default();
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        hooks.register_with_timeout("stuck", Duration::from_millis(50), std::future::pending);
+        let counter = ran.clone();
+        hooks.register("flush", move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+
+        // 卡住的钩子只占用自己的超时，后面的钩子仍有预算可用
+        let started = Instant::now();
+        let deadline = started + Duration::from_secs(10);
+        assert_eq!(hooks.run(deadline).await, 1);
+        assert_eq!(ran.load(Ordering::SeqCst), 1);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
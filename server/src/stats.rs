@@ -0,0 +1,286 @@
+//! Server 自身的接收统计
+//!
+//! 统计自启动以来收到的样本总数、各 Agent 样本数、最近窗口内的平均接收速率、
+//! 当前活跃的流式连接数，以及实时推送的广播结果
+
+use crate::events::PublishOutcome;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+
+/// 接收速率的统计窗口（秒）
+pub const RATE_WINDOW_SECS: u64 = 60;
+
+/// 单独统计样本数的 Agent 上限，超出后淘汰最久未上报的 Agent
+pub const MAX_TRACKED_AGENTS: usize = 10_000;
+
+/// 接收统计
+#[derive(Debug)]
+pub struct IngestStats {
+    started: Instant,
+    /// 累计接收样本数
+    total: AtomicU64,
+    /// agent_id -> (累计样本数, 最近一次上报时启动后的秒数)
+    per_agent: Mutex<HashMap<String, (u64, u64)>>,
+    /// 因超出 `MAX_TRACKED_AGENTS` 被淘汰的 Agent 数
+    evicted_agents: AtomicU64,
+    /// 按秒分桶的样本数：(启动后的秒数, 样本数)，只保留窗口内的桶
+    buckets: Mutex<VecDeque<(u64, u64)>>,
+    /// 当前活跃的流式连接数
+    active_streams: AtomicU64,
+    /// 实时推送的广播结果
+    broadcast_published: AtomicU64,
+    broadcast_delivered: AtomicU64,
+    broadcast_no_subscribers: AtomicU64,
+    broadcast_failed: AtomicU64,
+    last_subscribers: AtomicU64,
+}
+
+/// 活跃流式连接的计数守卫，释放时计数减一
+#[derive(Debug)]
+pub struct ActiveStream(Arc<IngestStats>);
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.0.active_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 接收统计快照
+#[derive(Debug, Clone, Serialize)]
+pub struct IngestSnapshot {
+    /// 启动以来累计接收样本数
+    pub total: u64,
+    /// 最近窗口内的平均接收速率（样本/秒）
+    pub rate_per_sec: f64,
+    /// 速率统计窗口（秒，启动不足一个窗口时为已运行时长）
+    pub window_secs: u64,
+    /// Server 运行时长（秒）
+    pub uptime_secs: u64,
+    /// 各 Agent 启动以来的样本数（最多 `MAX_TRACKED_AGENTS` 个）
+    pub per_agent: BTreeMap<String, u64>,
+    /// 因超出上限被移出 `per_agent` 的 Agent 数
+    pub evicted_agents: u64,
+    /// 当前活跃的流式连接数
+    pub active_streams: u64,
+    /// 各 Agent 内存缓存因超出单 Agent 上限被淘汰的样本数（由 API 层从 Storage 填充）
+    pub cache_evictions: BTreeMap<String, u64>,
+    /// 实时推送的广播结果
+    pub broadcast: BroadcastSnapshot,
+}
+
+/// 实时推送（SSE / WebSocket）的广播统计，关闭实时推送时全部为 0
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BroadcastSnapshot {
+    /// 启动以来成功广播的样本数
+    pub published: u64,
+    /// 启动以来送达订阅者的累计次数（每条样本按送达的订阅者数累加）
+    pub delivered: u64,
+    /// 因没有订阅者而未广播的样本数
+    pub no_subscribers: u64,
+    /// 因序列化失败而未广播的样本数
+    pub failed: u64,
+    /// 最近一次广播送达的订阅者数，最近一次没有订阅者时为 0
+    pub last_subscribers: u64,
+}
+
+impl Default for IngestStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IngestStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            total: AtomicU64::new(0),
+            per_agent: Mutex::new(HashMap::new()),
+            evicted_agents: AtomicU64::new(0),
+            buckets: Mutex::new(VecDeque::new()),
+            active_streams: AtomicU64::new(0),
+            broadcast_published: AtomicU64::new(0),
+            broadcast_delivered: AtomicU64::new(0),
+            broadcast_no_subscribers: AtomicU64::new(0),
+            broadcast_failed: AtomicU64::new(0),
+            last_subscribers: AtomicU64::new(0),
+        }
+    }
+
+    /// 记录一条新建立的流式连接，返回的守卫在连接结束时释放
+    pub fn stream_opened(self: &Arc<Self>) -> ActiveStream {
+        self.active_streams.fetch_add(1, Ordering::Relaxed);
+        ActiveStream(self.clone())
+    }
+
+    /// 当前活跃的流式连接数
+    pub fn active_streams(&self) -> u64 {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+
+    /// 记录收到的一条样本
+    pub fn record(&self, agent_id: &str) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        let now = self.started.elapsed().as_secs();
+
+        {
+            let mut per_agent = self
+                .per_agent
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            match per_agent.get_mut(agent_id) {
+                Some((count, last_seen)) => {
+                    *count += 1;
+                    *last_seen = now;
+                }
+                None => {
+                    if per_agent.len() >= MAX_TRACKED_AGENTS {
+                        self.evict_least_recent(&mut per_agent);
+                    }
+                    per_agent.insert(agent_id.to_string(), (1, now));
+                }
+            }
+        }
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        match buckets.back_mut() {
+            Some((sec, count)) if *sec == now => *count += 1,
+            _ => buckets.push_back((now, 1)),
+        }
+        Self::evict(&mut buckets, now);
+    }
+
+    /// 记录一次实时推送的广播结果
+    pub fn record_broadcast(&self, outcome: PublishOutcome) {
+        match outcome {
+            PublishOutcome::Delivered(subscribers) => {
+                self.broadcast_published.fetch_add(1, Ordering::Relaxed);
+                self.broadcast_delivered
+                    .fetch_add(subscribers as u64, Ordering::Relaxed);
+                self.last_subscribers
+                    .store(subscribers as u64, Ordering::Relaxed);
+            }
+            PublishOutcome::NoSubscribers => {
+                self.broadcast_no_subscribers
+                    .fetch_add(1, Ordering::Relaxed);
+                self.last_subscribers.store(0, Ordering::Relaxed);
+            }
+            PublishOutcome::Failed => {
+                self.broadcast_failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// 累计接收样本数
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// 生成统计快照
+    pub fn snapshot(&self) -> IngestSnapshot {
+        let uptime_secs = self.started.elapsed().as_secs();
+        // 当前秒尚未结束，窗口按已完整经过的秒数加 1 计算
+        let window_secs = (uptime_secs + 1).min(RATE_WINDOW_SECS);
+
+        let in_window: u64 = {
+            let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+            Self::evict(&mut buckets, uptime_secs);
+            buckets.iter().map(|(_, count)| count).sum()
+        };
+
+        let per_agent = self
+            .per_agent
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(id, (count, _))| (id.clone(), *count))
+            .collect();
+
+        IngestSnapshot {
+            total: self.total(),
+            rate_per_sec: in_window as f64 / window_secs as f64,
+            window_secs,
+            uptime_secs,
+            per_agent,
+            evicted_agents: self.evicted_agents.load(Ordering::Relaxed),
+            active_streams: self.active_streams(),
+            cache_evictions: BTreeMap::new(),
+            broadcast: BroadcastSnapshot {
+                published: self.broadcast_published.load(Ordering::Relaxed),
+                delivered: self.broadcast_delivered.load(Ordering::Relaxed),
+                no_subscribers: self.broadcast_no_subscribers.load(Ordering::Relaxed),
+                failed: self.broadcast_failed.load(Ordering::Relaxed),
+                last_subscribers: self.last_subscribers.load(Ordering::Relaxed),
+            },
+        }
+    }
+
+    /// 移除最久未上报的 Agent（已下线或改名的 Agent 不再占用统计）
+    fn evict_least_recent(&self, per_agent: &mut HashMap<String, (u64, u64)>) {
+        let oldest = per_agent
+            .iter()
+            .min_by_key(|(_, (_, last_seen))| *last_seen)
+            .map(|(id, _)| id.clone());
+        if let Some(oldest) = oldest {
+            per_agent.remove(&oldest);
+            self.evicted_agents.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// 丢弃窗口之外的桶
+    fn evict(buckets: &mut VecDeque<(u64, u64)>, now: u64) {
+        while buckets
+            .front()
+            .is_some_and(|(sec, _)| sec + RATE_WINDOW_SECS <= now)
+        {
+            buckets.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_counts() {
+        let stats = IngestStats::new();
+        for _ in 0..3 {
+            stats.record("agent-1");
+        }
+        stats.record("agent-2");
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total, 4);
+        assert_eq!(snapshot.per_agent["agent-1"], 3);
+        assert_eq!(snapshot.per_agent["agent-2"], 1);
+        assert!(snapshot.rate_per_sec > 0.0);
+    }
+
+    #[test]
+    fn test_per_agent_counts_bounded() {
+        let stats = IngestStats::new();
+        stats.record("agent-0");
+        for i in 1..=MAX_TRACKED_AGENTS {
+            stats.record(&format!("agent-{}", i));
+        }
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total, MAX_TRACKED_AGENTS as u64 + 1);
+        assert_eq!(snapshot.per_agent.len(), MAX_TRACKED_AGENTS);
+        assert_eq!(snapshot.evicted_agents, 1);
+        assert_eq!(
+            snapshot.per_agent[&format!("agent-{}", MAX_TRACKED_AGENTS)],
+            1
+        );
+    }
+
+    #[test]
+    fn test_old_buckets_evicted() {
+        let mut buckets = VecDeque::from([(0, 5), (30, 2), (61, 1)]);
+        IngestStats::evict(&mut buckets, 61);
+        assert_eq!(buckets, VecDeque::from([(30, 2), (61, 1)]));
+    }
+}
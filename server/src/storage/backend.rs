@@ -0,0 +1,324 @@
+//! 持久化后端抽象
+//!
+//! [`Storage`](super::Storage) 的落盘、查询与清理都经由 [`PersistBackend`] 完成，默认实现为
+//! redb（[`PersistStorage`](super::persist::PersistStorage)），测试中另有内存实现（memory.rs），
+//! 存储集成测试对两者各运行一遍。
+//!
+//! 后端只需实现按 Agent 读写、分批删除等基本操作；分批清理、保留策略与小时汇总等组合逻辑
+//! 由 trait 的默认方法提供，各后端行为一致
+
+use super::persist::CompactReport;
+use super::retention::StripPlan;
+use super::rollup::{hour_start, rollup_hours, HourlyRollup, HOUR_MS};
+use super::{AgentEvent, HostnameChange};
+use anyhow::Result;
+use common::proto::MetricsRequest;
+use tokio::sync::mpsc;
+
+/// 一次删完时每批最多删除的 key 数，避免单次事务过大
+#[cfg(test)]
+const DELETE_BATCH_SIZE: usize = 10000;
+
+/// 生成小时汇总时每次读取的原始数据时间跨度（小时）
+const ROLLUP_CHUNK_HOURS: i64 = 24;
+
+/// 持久化后端
+#[tonic::async_trait]
+pub trait PersistBackend: Send + Sync {
+    /// 批量写入指标数据
+    ///
+    /// 带采集序号（`sequence` 非 0）的样本按 (agent, timestamp, sequence) 去重：重连重放或
+    /// 整批重试时已落盘的相同样本直接跳过。同一 key 下内容不同的样本另存一条，重放时同样跳过
+    async fn flush_batch(&self, metrics: &[MetricsRequest]) -> Result<()>;
+
+    /// 获取指定 Agent 的最新指标
+    async fn get_latest_metrics(&self, agent_id: &str) -> Result<Option<MetricsRequest>>;
+
+    /// 获取指定 Agent 最新 limit 条指标（按时间戳升序）
+    async fn query_latest_by_agent(
+        &self,
+        agent_id: &str,
+        limit: usize,
+    ) -> Result<Vec<MetricsRequest>>;
+
+    /// 获取指定 Agent 在 `[start_ts, end_ts]` 内的指标（按时间戳升序）
+    async fn query_range_by_agent(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> Result<Vec<MetricsRequest>>;
+
+    /// 按时间戳升序流式读取指定 Agent 在 `[start_ts, end_ts]` 内的指标
+    ///
+    /// 记录在后台逐条读出并通过有界通道发送，内存占用与范围大小无关；
+    /// 接收端被丢弃后游标在下一次发送时停止。读取出错时发送一条错误后结束
+    fn stream_range_by_agent(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> mpsc::Receiver<Result<MetricsRequest>>;
+
+    /// 获取所有 Agent 中最新的 limit 条指标（按时间戳降序）
+    async fn query_recent_across_agents(&self, limit: usize) -> Result<Vec<MetricsRequest>>;
+
+    /// 获取指定 Agent 的主机名变更历史
+    async fn get_hostname_history(&self, agent_id: &str) -> Result<Vec<HostnameChange>>;
+
+    /// 追加一条时间线事件，每个 Agent 超出 `MAX_AGENT_EVENTS` 时丢弃最旧的
+    async fn append_event(&self, agent_id: &str, event: &AgentEvent) -> Result<()>;
+
+    /// 获取指定 Agent 时间在 `[start_ts, end_ts]` 内的时间线事件（按时间升序）
+    async fn query_events(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> Result<Vec<AgentEvent>>;
+
+    /// 获取所有仍有记录的 agent_id 列表
+    async fn get_all_agent_ids(&self) -> Result<Vec<String>>;
+
+    /// 统计指定 agent 的记录数
+    async fn count_agent_records(&self, agent_id: &str) -> Result<usize>;
+
+    /// 删除指定 agent 最旧的至多 `limit` 条记录（`before_ts` 为 Some 时只删早于该时间的），
+    /// 返回删除数量
+    async fn delete_agent_chunk(
+        &self,
+        agent_id: &str,
+        before_ts: Option<i64>,
+        limit: usize,
+    ) -> Result<usize>;
+
+    /// 改写指定 Agent 时间戳在 `[from_ts, before_ts)` 内最旧的一批记录：按 `plan` 去掉已超出
+    /// 保留期的字段组，有变化的记录以原 key 写回
+    ///
+    /// 每批至少 `limit` 条，与最后一条时间戳相同的记录并入同一批，保证下一批可以按时间戳续接。
+    /// 返回 (本批改写数, 下一批的起点)，范围内没有更多记录时起点为 None
+    async fn strip_agent_chunk(
+        &self,
+        agent_id: &str,
+        from_ts: i64,
+        before_ts: i64,
+        limit: usize,
+        plan: &StripPlan,
+    ) -> Result<(usize, Option<i64>)>;
+
+    /// 指定 Agent 不早于 `from_ts` 的第一条记录的时间戳
+    async fn next_timestamp_from(&self, agent_id: &str, from_ts: i64) -> Result<Option<i64>>;
+
+    /// 指定 Agent 最后一条小时汇总的小时起点
+    async fn last_hourly_rollup(&self, agent_id: &str) -> Result<Option<i64>>;
+
+    /// 写入指定 Agent 的若干小时汇总，已存在的小时被覆盖
+    async fn write_hourly_rollups(
+        &self,
+        agent_id: &str,
+        rollups: Vec<(i64, HourlyRollup)>,
+    ) -> Result<()>;
+
+    /// 获取指定 Agent 小时起点在 `[start_ts, end_ts]` 内的小时汇总（按时间升序）
+    async fn query_hourly_range(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> Result<Vec<(i64, HourlyRollup)>>;
+
+    /// 清理操作累计读取的 key 数（用于观察清理开销），不统计的后端返回 0
+    fn keys_scanned(&self) -> u64 {
+        0
+    }
+
+    /// 压缩存储文件，把删除记录后的空闲空间归还给操作系统；不需要压缩的后端返回 None
+    async fn compact(&self) -> Result<Option<CompactReport>> {
+        Ok(None)
+    }
+
+    /// 为指定 Agent 补齐 `until` 之前已结束小时的汇总，返回新写入的小时数
+    ///
+    /// 从已有汇总的最后一小时之后继续，按 [`ROLLUP_CHUNK_HOURS`] 分段读取原始数据，没有数据的
+    /// 时间段直接跳过。已汇总的小时不会重写；每段之前调用 `should_continue`，返回 false 时停止，
+    /// 下次调用从中断处继续
+    async fn rollup_agent_hours(
+        &self,
+        agent_id: &str,
+        until: i64,
+        should_continue: &mut (dyn FnMut() -> bool + Send),
+    ) -> Result<usize> {
+        let end = hour_start(until);
+        let mut cursor = match self.last_hourly_rollup(agent_id).await? {
+            Some(hour) => hour + HOUR_MS,
+            None => 0,
+        };
+        let mut written = 0;
+
+        while cursor < end && should_continue() {
+            let Some(next) = self.next_timestamp_from(agent_id, cursor).await? else {
+                break;
+            };
+            if next >= end {
+                break;
+            }
+            let chunk_start = hour_start(next);
+            let chunk_end = (chunk_start + ROLLUP_CHUNK_HOURS * HOUR_MS).min(end);
+            let samples = self
+                .query_range_by_agent(agent_id, chunk_start, chunk_end - 1)
+                .await?;
+            let rollups = rollup_hours(&samples);
+            written += rollups.len();
+            self.write_hourly_rollups(agent_id, rollups).await?;
+            cursor = chunk_end;
+            tokio::task::yield_now().await;
+        }
+
+        Ok(written)
+    }
+
+    /// 分批去掉指定 agent 不早于 `from_ts` 的记录中已超出保留期的字段组，返回改写数量
+    ///
+    /// 只扫描 `plan` 最晚截止时间之前的记录；分批与中断语义同 [`Self::trim_agent_records`]，
+    /// 改写不改变记录的 key，中断后再次调用只会跳过已改写的记录
+    async fn strip_agent_fields(
+        &self,
+        agent_id: &str,
+        from_ts: i64,
+        plan: &StripPlan,
+        chunk_size: usize,
+        should_continue: &mut (dyn FnMut() -> bool + Send),
+    ) -> Result<usize> {
+        let Some(before_ts) = plan.newest_cutoff() else {
+            return Ok(0);
+        };
+        let chunk_size = chunk_size.max(1);
+        let mut cursor = Some(from_ts);
+        let mut total_stripped = 0;
+
+        while let Some(from_ts) = cursor {
+            if !should_continue() {
+                break;
+            }
+            let (stripped, next) = self
+                .strip_agent_chunk(agent_id, from_ts, before_ts, chunk_size, plan)
+                .await?;
+            total_stripped += stripped;
+            cursor = next;
+            tokio::task::yield_now().await;
+        }
+
+        Ok(total_stripped)
+    }
+
+    /// 删除指定 agent 超过保留数量的旧记录，返回删除数量
+    ///
+    /// 一次删完全部超出部分，清理任务使用可中断的 [`Self::trim_agent_records`]
+    #[cfg(test)]
+    async fn delete_old_records(&self, agent_id: &str, keep_count: usize) -> Result<usize> {
+        let deleted = self
+            .trim_agent_records(agent_id, keep_count, DELETE_BATCH_SIZE, &mut || true)
+            .await?;
+        if deleted > 0 {
+            tracing::info!(
+                "Agent {} deleted {} old records, keeping {} records",
+                agent_id,
+                deleted,
+                keep_count
+            );
+        }
+        Ok(deleted)
+    }
+
+    /// 分批删除指定 agent 超过保留数量的最旧记录，返回删除数量
+    ///
+    /// 每批最多 `chunk_size` 条，各由一次 [`Self::delete_agent_chunk`] 完成，批间让出执行权，
+    /// 避免长时间占用后端的写入。每批之前调用 `should_continue`，返回 false
+    /// 时停止：已删除的批次不回滚，剩余记录始终是最新的若干条，再次调用即从中断处继续
+    async fn trim_agent_records(
+        &self,
+        agent_id: &str,
+        keep_count: usize,
+        chunk_size: usize,
+        should_continue: &mut (dyn FnMut() -> bool + Send),
+    ) -> Result<usize> {
+        let mut excess = self
+            .count_agent_records(agent_id)
+            .await?
+            .saturating_sub(keep_count);
+        let chunk_size = chunk_size.max(1);
+        let mut total_deleted = 0;
+
+        while excess > 0 && should_continue() {
+            let deleted = self
+                .delete_agent_chunk(agent_id, None, excess.min(chunk_size))
+                .await?;
+            if deleted == 0 {
+                break;
+            }
+            total_deleted += deleted;
+            excess -= deleted;
+            tokio::task::yield_now().await;
+        }
+
+        Ok(total_deleted)
+    }
+
+    /// 删除指定时间之前的所有记录，返回删除数量（`exempt` 中的 agent 不受影响）
+    ///
+    /// 一次删完全部过期记录，清理任务使用可中断的 [`Self::delete_agent_records_before`]
+    #[cfg(test)]
+    async fn delete_before_timestamp(
+        &self,
+        before_ts: i64,
+        exempt: &std::collections::HashSet<String>,
+    ) -> Result<usize> {
+        let mut total_deleted = 0;
+        for agent_id in self.get_all_agent_ids().await? {
+            if !exempt.contains(&agent_id) {
+                total_deleted += self
+                    .delete_agent_records_before(
+                        &agent_id,
+                        before_ts,
+                        DELETE_BATCH_SIZE,
+                        &mut || true,
+                    )
+                    .await?;
+            }
+        }
+
+        if total_deleted > 0 {
+            tracing::info!("删除了 {} 条早于 {} 的记录", total_deleted, before_ts);
+        } else {
+            tracing::debug!("没有早于 {} 的记录需要删除", before_ts);
+        }
+        Ok(total_deleted)
+    }
+
+    /// 分批删除指定 agent 早于 `before_ts` 的记录，返回删除数量
+    ///
+    /// 分批与中断语义同 [`Self::trim_agent_records`]
+    async fn delete_agent_records_before(
+        &self,
+        agent_id: &str,
+        before_ts: i64,
+        chunk_size: usize,
+        should_continue: &mut (dyn FnMut() -> bool + Send),
+    ) -> Result<usize> {
+        let chunk_size = chunk_size.max(1);
+        let mut total_deleted = 0;
+
+        while should_continue() {
+            let deleted = self
+                .delete_agent_chunk(agent_id, Some(before_ts), chunk_size)
+                .await?;
+            total_deleted += deleted;
+            if deleted < chunk_size {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+
+        Ok(total_deleted)
+    }
+}
@@ -2,6 +2,7 @@
 //!
 //! 使用 HashMap + VecDeque 实现每个 Agent 的固定大小缓存
 
+use super::{record_hostname, AgentEvent, HostnameChange, MAX_AGENT_EVENTS};
 use common::proto::MetricsRequest;
 use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
@@ -14,6 +15,12 @@ pub struct Cache {
     max_size: usize,
     /// agent_id -> 数据队列
     data: Arc<RwLock<HashMap<String, VecDeque<MetricsRequest>>>>,
+    /// agent_id -> 本进程内观察到的主机名变更
+    hostnames: Arc<RwLock<HashMap<String, Vec<HostnameChange>>>>,
+    /// agent_id -> 超出 max_size 被淘汰的累计条数
+    evictions: Arc<RwLock<HashMap<String, u64>>>,
+    /// agent_id -> 时间线事件（仅内存模式使用），按写入顺序
+    events: Arc<RwLock<HashMap<String, VecDeque<AgentEvent>>>>,
 }
 
 impl Cache {
@@ -22,23 +29,84 @@ impl Cache {
         Self {
             max_size,
             data: Arc::new(RwLock::new(HashMap::new())),
+            hostnames: Arc::new(RwLock::new(HashMap::new())),
+            evictions: Arc::new(RwLock::new(HashMap::new())),
+            events: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
     /// 更新缓存
     pub async fn update(&self, metrics: MetricsRequest) {
         let agent_id = metrics.agent_id.clone();
+
+        // 主机名仅在变化时记录
+        {
+            let mut hostnames = self.hostnames.write().await;
+            let history = hostnames.entry(agent_id.clone()).or_default();
+            record_hostname(history, &metrics.hostname, metrics.timestamp);
+        }
+
         let mut data = self.data.write().await;
 
-        let entry = data.entry(agent_id).or_insert_with(VecDeque::new);
+        let entry = data.entry(agent_id.clone()).or_insert_with(VecDeque::new);
         entry.push_back(metrics);
 
         // 超过最大条数时，移除最旧的数据
+        let mut evicted = 0;
         while entry.len() > self.max_size {
             entry.pop_front();
+            evicted += 1;
+        }
+        drop(data);
+
+        if evicted > 0 {
+            *self.evictions.write().await.entry(agent_id).or_default() += evicted;
         }
     }
 
+    /// 按时间顺序插入一条早于当前最新样本的历史数据，不改变最新样本
+    ///
+    /// 该 Agent 没有更新的样本时不缓存并返回 false，避免旧样本成为最新样本
+    pub async fn insert_history(&self, metrics: MetricsRequest) -> bool {
+        let agent_id = metrics.agent_id.clone();
+        let mut data = self.data.write().await;
+        let Some(entry) = data.get_mut(&agent_id) else {
+            return false;
+        };
+        let position = entry.partition_point(|m| m.timestamp <= metrics.timestamp);
+        if position == entry.len() {
+            return false;
+        }
+        entry.insert(position, metrics);
+
+        let mut evicted = 0;
+        while entry.len() > self.max_size {
+            entry.pop_front();
+            evicted += 1;
+        }
+        drop(data);
+
+        if evicted > 0 {
+            *self.evictions.write().await.entry(agent_id).or_default() += evicted;
+        }
+        true
+    }
+
+    /// 各 Agent 因超出缓存上限被淘汰的累计条数（未发生淘汰的 Agent 不在其中）
+    pub async fn evictions(&self) -> HashMap<String, u64> {
+        self.evictions.read().await.clone()
+    }
+
+    /// 指定 Agent 因超出缓存上限被淘汰的累计条数
+    pub async fn evicted(&self, agent_id: &str) -> u64 {
+        self.evictions
+            .read()
+            .await
+            .get(agent_id)
+            .copied()
+            .unwrap_or(0)
+    }
+
     /// 获取所有 Agent ID
     pub async fn get_all_agents(&self) -> Vec<String> {
         let data = self.data.read().await;
@@ -62,6 +130,74 @@ impl Cache {
             Vec::new()
         }
     }
+
+    /// 对指定 Agent 最近 limit 条数据逐条取值（按时间升序），不克隆样本
+    pub async fn map_recent<T>(
+        &self,
+        agent_id: &str,
+        limit: usize,
+        f: impl FnMut(&MetricsRequest) -> Option<T>,
+    ) -> Vec<T> {
+        let data = self.data.read().await;
+        match data.get(agent_id) {
+            Some(entry) => entry
+                .range(entry.len().saturating_sub(limit)..)
+                .filter_map(f)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// 获取所有 Agent 中最新的 limit 条数据（按时间戳降序）
+    pub async fn get_recent_across_agents(&self, limit: usize) -> Vec<MetricsRequest> {
+        let data = self.data.read().await;
+        let mut recent: Vec<MetricsRequest> = data
+            .values()
+            .flat_map(|entry| entry.iter().rev().take(limit).cloned())
+            .collect();
+        sort_newest_first(&mut recent);
+        recent.truncate(limit);
+        recent
+    }
+
+    /// 获取指定 Agent 的主机名变更历史
+    pub async fn get_hostname_history(&self, agent_id: &str) -> Vec<HostnameChange> {
+        let hostnames = self.hostnames.read().await;
+        hostnames.get(agent_id).cloned().unwrap_or_default()
+    }
+
+    /// 追加一条时间线事件，超出 `MAX_AGENT_EVENTS` 时丢弃最旧的
+    pub async fn record_event(&self, agent_id: &str, event: AgentEvent) {
+        let mut events = self.events.write().await;
+        let entry = events.entry(agent_id.to_string()).or_default();
+        entry.push_back(event);
+        while entry.len() > MAX_AGENT_EVENTS {
+            entry.pop_front();
+        }
+    }
+
+    /// 获取指定 Agent 时间在 `[start_ts, end_ts]` 内的时间线事件（按时间升序）
+    pub async fn get_events(&self, agent_id: &str, start_ts: i64, end_ts: i64) -> Vec<AgentEvent> {
+        let events = self.events.read().await;
+        let mut matched: Vec<AgentEvent> = events
+            .get(agent_id)
+            .into_iter()
+            .flatten()
+            .filter(|event| (start_ts..=end_ts).contains(&event.timestamp))
+            .cloned()
+            .collect();
+        matched.sort_by_key(|event| event.timestamp);
+        matched
+    }
+}
+
+/// 按时间戳降序排列，同一时间戳按 agent_id 排序以保证结果稳定
+pub(crate) fn sort_newest_first(metrics: &mut [MetricsRequest]) {
+    metrics.sort_by(|a, b| {
+        b.timestamp
+            .cmp(&a.timestamp)
+            .then_with(|| a.agent_id.cmp(&b.agent_id))
+    });
 }
 
 #[cfg(test)]
@@ -75,6 +211,8 @@ mod tests {
             agent_id: agent_id.to_string(),
             timestamp,
             hostname: "test-host".to_string(),
+            sequence: 0,
+            labels: Default::default(),
             system: Some(SystemMetrics {
                 cpu: Some(CpuMetrics {
                     usage_percent: 50.0,
@@ -83,6 +221,8 @@ mod tests {
                     load_avg_1: 1.0,
                     load_avg_5: 0.8,
                     load_avg_15: 0.5,
+                    per_core_frequency: vec![],
+                    per_core_temperature: vec![],
                 }),
                 memory: Some(MemoryMetrics {
                     total: 16_000_000_000,
@@ -104,6 +244,16 @@ mod tests {
                 system_info: None,
                 agent_metrics: None,
                 tcp_ping: vec![],
+                collector_status: vec![],
+                pressure: None,
+                gpu: vec![],
+                zombie_count: 0,
+                file_descriptors: None,
+                top_processes: vec![],
+                entropy: None,
+                systemd: None,
+                numa_nodes: vec![],
+                containers: None,
             }),
         }
     }
@@ -391,4 +541,54 @@ mod tests {
         let latest = cache.get_latest("agent-1").await.unwrap();
         assert_eq!(latest.timestamp, 2000);
     }
+
+    #[tokio::test]
+    async fn test_cache_hostname_history_records_changes_only() {
+        let cache = Cache::new(10);
+
+        let mut m = create_test_metrics("agent-1", 1000);
+        cache.update(m.clone()).await;
+        m.timestamp = 2000;
+        cache.update(m.clone()).await;
+        m.timestamp = 3000;
+        m.hostname = "renamed-host".to_string();
+        cache.update(m.clone()).await;
+        m.timestamp = 4000;
+        cache.update(m).await;
+
+        let history = cache.get_hostname_history("agent-1").await;
+        assert_eq!(
+            history,
+            vec![
+                HostnameChange {
+                    hostname: "test-host".to_string(),
+                    since: 1000,
+                },
+                HostnameChange {
+                    hostname: "renamed-host".to_string(),
+                    since: 3000,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_eviction_counter() {
+        let cache = Cache::new(3);
+
+        for i in 0..3 {
+            cache.update(create_test_metrics("agent-1", i)).await;
+        }
+        assert!(cache.evictions().await.is_empty());
+
+        // 超出上限 2 条
+        for i in 3..5 {
+            cache.update(create_test_metrics("agent-1", i)).await;
+        }
+        cache.update(create_test_metrics("agent-2", 0)).await;
+
+        let evictions = cache.evictions().await;
+        assert_eq!(evictions.get("agent-1"), Some(&2));
+        assert_eq!(evictions.get("agent-2"), None);
+    }
 }
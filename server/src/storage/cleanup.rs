@@ -3,26 +3,45 @@
 //! 定期清理过期的指标数据：
 //! - 每个 Agent 保留最近 max_records_per_agent 条
 //! - 删除超过 retention_days 天的旧数据
+//! - 按 field_retention 去掉剩余样本中已超出保留期的低重要度字段（见 [`super::retention`]）
+//!
+//! cleanup_exempt_agents 中的 Agent 不参与以上三项清理
+//!
+//! 清理前先为所有 Agent 补齐已结束小时的长期小时汇总（见 [`super::rollup`]），汇总不受清理影响
+//!
+//! 删除按 Agent、按批进行，每批一个写事务，批间检查停止信号；中途停止时已删除的批次保留，
+//! 下一轮清理从剩余的超出部分继续
 
-use crate::storage::persist::PersistStorage;
+use crate::storage::backend::PersistBackend;
 use crate::storage::StorageConfig;
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, PoisonError};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tracing::{error, info, warn};
 
+/// 每批（每个写事务）最多删除的记录数
+///
+/// 批越小，单次占用 blocking 线程与写锁的时间越短，停止信号的响应也越及时
+pub const CLEANUP_CHUNK_SIZE: usize = 1000;
+
 /// 清理任务
 pub struct CleanupTask {
     config: StorageConfig,
     /// 持久化存储引用
-    storage: Arc<PersistStorage>,
+    storage: Arc<dyn PersistBackend>,
     /// 运行状态标志，用于优雅停止
     running: Arc<AtomicBool>,
+    /// 每批删除的记录数
+    chunk_size: usize,
+    /// 各 Agent 已完成字段改写的时间点：早于它的记录已去掉全部字段组，下一轮从这里开始扫描。
+    /// 只在内存中保留，Server 重启后第一轮清理重新扫描一遍
+    stripped_until: Mutex<HashMap<String, i64>>,
 }
 
 impl CleanupTask {
     /// 创建清理任务
-    pub fn new(mut config: StorageConfig, storage: Arc<PersistStorage>) -> Self {
+    pub fn new(mut config: StorageConfig, storage: Arc<dyn PersistBackend>) -> Self {
         if config.cleanup_interval_hours == 0 {
             warn!("cleanup_interval_hours is 0, using 1 hour as fallback");
             config.cleanup_interval_hours = 1;
@@ -36,6 +55,8 @@ impl CleanupTask {
             config,
             storage,
             running: Arc::new(AtomicBool::new(true)),
+            chunk_size: CLEANUP_CHUNK_SIZE,
+            stripped_until: Mutex::new(HashMap::new()),
         }
     }
 
@@ -48,13 +69,15 @@ impl CleanupTask {
     pub async fn run(&self) {
         let interval = Duration::from_secs(self.config.cleanup_interval_hours * 3600);
         let mut ticker = tokio::time::interval(interval);
-        // 创建一个独立的检查间隔，用于及时响应停止信号
-        let mut check_interval = tokio::time::interval(Duration::from_secs(10));
+        // 创建一个独立的检查间隔，用于及时响应停止信号：关闭预算内还要留出落盘时间
+        let mut check_interval = tokio::time::interval(Duration::from_millis(100));
 
         info!(
             interval_hours = self.config.cleanup_interval_hours,
             max_records_per_agent = self.config.max_records_per_agent,
             retention_days = self.config.retention_days,
+            field_retention = %self.config.field_retention,
+            exempt_agents = self.config.cleanup_exempt_agents.len(),
             "Cleanup task started"
         );
 
@@ -82,6 +105,11 @@ impl CleanupTask {
         info!("Cleanup task stopped");
     }
 
+    /// 是否仍在运行（未收到停止信号）
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
     /// 执行一次清理
     async fn execute_cleanup(&self) {
         info!("Starting data cleanup");
@@ -100,20 +128,61 @@ impl CleanupTask {
             return;
         }
 
+        let keys_scanned_before = self.storage.keys_scanned();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+
+        // 0. 删除前先生成小时汇总，被清理的原始数据仍保留在长期序列中
+        let mut hours_rolled_up = 0usize;
+        for agent_id in &agent_ids {
+            match self
+                .storage
+                .rollup_agent_hours(agent_id, now, &mut || self.is_running())
+                .await
+            {
+                Ok(written) => hours_rolled_up += written,
+                Err(e) => {
+                    error!(
+                        agent_id = %agent_id,
+                        error = %e,
+                        "Failed to roll up hourly series for agent"
+                    );
+                }
+            }
+
+            if !self.is_running() {
+                warn!(
+                    hours_rolled_up = hours_rolled_up,
+                    "Received stop signal during cleanup, exiting early"
+                );
+                return;
+            }
+        }
+
         let mut total_deleted_by_count = 0usize;
         let mut agents_cleaned = 0usize;
 
+        let exempt = &self.config.cleanup_exempt_agents;
+        let mut agents_exempt = 0usize;
+
         // 1. 对每个 agent 执行数量限制清理
         for agent_id in &agent_ids {
-            // 检查停止信号，避免长时间清理过程中无法响应
-            if !self.running.load(Ordering::SeqCst) {
-                warn!("Received stop signal during cleanup, exiting early");
-                return;
+            if exempt.contains(agent_id) {
+                agents_exempt += 1;
+                continue;
             }
 
+            // 每批之前检查停止信号，单个 Agent 积压很多时也能及时退出
             match self
                 .storage
-                .delete_old_records(agent_id, self.config.max_records_per_agent)
+                .trim_agent_records(
+                    agent_id,
+                    self.config.max_records_per_agent,
+                    self.chunk_size,
+                    &mut || self.is_running(),
+                )
                 .await
             {
                 Ok(deleted) => {
@@ -130,34 +199,260 @@ impl CleanupTask {
                     );
                 }
             }
+
+            if !self.is_running() {
+                warn!(
+                    deleted_by_count = total_deleted_by_count,
+                    "Received stop signal during cleanup, exiting early"
+                );
+                return;
+            }
         }
 
         // 2. 执行时间限制清理（仅当 retention_days > 0 时）
         let total_deleted_by_time = if self.config.retention_days > 0 {
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_millis() as i64;
             let retention_ms = self.config.retention_days.saturating_mul(86_400_000) as i64;
             let cutoff_ts = now.saturating_sub(retention_ms);
-            match self.storage.delete_before_timestamp(cutoff_ts).await {
-                Ok(deleted) => deleted,
-                Err(e) => {
-                    error!("Failed to delete expired records: {}", e);
-                    0
+            let mut deleted_by_time = 0usize;
+            for agent_id in agent_ids.iter().filter(|id| !exempt.contains(*id)) {
+                let result = self
+                    .storage
+                    .delete_agent_records_before(agent_id, cutoff_ts, self.chunk_size, &mut || {
+                        self.is_running()
+                    })
+                    .await;
+                match result {
+                    Ok(deleted) => deleted_by_time += deleted,
+                    Err(e) => {
+                        error!(
+                            agent_id = %agent_id,
+                            error = %e,
+                            "Failed to delete expired records for agent"
+                        );
+                    }
+                }
+                if !self.is_running() {
+                    warn!(
+                        deleted_by_count = total_deleted_by_count,
+                        deleted_by_time = deleted_by_time,
+                        "Received stop signal during cleanup, exiting early"
+                    );
+                    return;
                 }
             }
+            deleted_by_time
         } else {
             0
         };
 
+        // 3. 按字段分级保留改写剩余的旧样本（仅当配置了字段组时）
+        let plan = self.config.field_retention.plan(now);
+        let mut total_stripped = 0usize;
+        if let Some(oldest_cutoff) = plan.oldest_cutoff() {
+            for agent_id in agent_ids.iter().filter(|id| !exempt.contains(*id)) {
+                let from_ts = self
+                    .stripped_until
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get(agent_id)
+                    .copied()
+                    .unwrap_or(0);
+                let result = self
+                    .storage
+                    .strip_agent_fields(agent_id, from_ts, &plan, self.chunk_size, &mut || {
+                        self.is_running()
+                    })
+                    .await;
+                let succeeded = match result {
+                    Ok(stripped) => {
+                        total_stripped += stripped;
+                        true
+                    }
+                    Err(e) => {
+                        error!(
+                            agent_id = %agent_id,
+                            error = %e,
+                            "Failed to strip expired fields for agent"
+                        );
+                        false
+                    }
+                };
+                if !self.is_running() {
+                    warn!(
+                        deleted_by_count = total_deleted_by_count,
+                        deleted_by_time = total_deleted_by_time,
+                        fields_stripped = total_stripped,
+                        "Received stop signal during cleanup, exiting early"
+                    );
+                    return;
+                }
+                // 出错时保留原位置，下一轮重新扫描
+                if succeeded {
+                    self.stripped_until
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .insert(agent_id.clone(), oldest_cutoff.max(from_ts));
+                }
+            }
+        }
+
         info!(
             agents_total = agent_ids.len(),
             agents_cleaned = agents_cleaned,
+            agents_exempt = agents_exempt,
+            hours_rolled_up = hours_rolled_up,
             deleted_by_count = total_deleted_by_count,
             deleted_by_time = total_deleted_by_time,
+            fields_stripped = total_stripped,
+            keys_scanned = self.storage.keys_scanned() - keys_scanned_before,
             retention_days = self.config.retention_days,
             "Data cleanup completed"
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::persist::PersistStorage;
+    use common::proto::{CpuMetrics, MetricsRequest, SystemMetrics};
+
+    fn metrics(agent_id: &str, timestamp: i64) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: agent_id.to_string(),
+            timestamp,
+            ..Default::default()
+        }
+    }
+
+    async fn count(storage: &PersistStorage, agent_id: &str) -> usize {
+        storage
+            .query_latest_by_agent(agent_id, usize::MAX)
+            .await
+            .unwrap()
+            .len()
+    }
+
+    /// 豁免的 Agent 不受数量与时间清理影响，其他 Agent 照常清理
+    #[tokio::test]
+    async fn test_exempt_agent_is_not_pruned() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let storage = Arc::new(PersistStorage::new(db_path.to_str().unwrap()).unwrap());
+
+        // 时间戳远早于任何保留期
+        let batch: Vec<_> = ["exempt", "normal"]
+            .iter()
+            .flat_map(|id| (1..=10).map(move |ts| metrics(id, ts * 1000)))
+            .collect();
+        storage.flush_batch(&batch).await.unwrap();
+
+        let mut config = StorageConfig {
+            max_records_per_agent: 3,
+            retention_days: 0,
+            cleanup_exempt_agents: ["exempt".to_string()].into(),
+            ..Default::default()
+        };
+
+        // 数量限制
+        CleanupTask::new(config.clone(), storage.clone())
+            .execute_cleanup()
+            .await;
+        assert_eq!(count(&storage, "exempt").await, 10);
+        assert_eq!(count(&storage, "normal").await, 3);
+
+        // 时间限制
+        config.retention_days = 1;
+        CleanupTask::new(config, storage.clone())
+            .execute_cleanup()
+            .await;
+        assert_eq!(count(&storage, "exempt").await, 10);
+        assert_eq!(count(&storage, "normal").await, 0);
+    }
+
+    /// 收到停止信号后在当前批次结束时退出，不再继续删除
+    #[tokio::test]
+    async fn test_stop_signal_interrupts_cleanup() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let storage = Arc::new(PersistStorage::new(db_path.to_str().unwrap()).unwrap());
+
+        let batch: Vec<_> = (1..=50).map(|ts| metrics("agent-1", ts)).collect();
+        storage.flush_batch(&batch).await.unwrap();
+
+        let config = StorageConfig {
+            max_records_per_agent: 5,
+            ..Default::default()
+        };
+        let mut task = CleanupTask::new(config, storage.clone());
+        task.chunk_size = 10;
+
+        task.running_flag().store(false, Ordering::SeqCst);
+        task.execute_cleanup().await;
+        assert_eq!(count(&storage, "agent-1").await, 50);
+
+        // 恢复运行后下一轮清理完成剩余部分
+        task.running_flag().store(true, Ordering::SeqCst);
+        task.execute_cleanup().await;
+        assert_eq!(count(&storage, "agent-1").await, 5);
+    }
+
+    /// 超出字段保留期的旧样本保留 CPU 使用率，去掉 per_core；近期样本不受影响
+    #[tokio::test]
+    async fn test_field_retention_strips_per_core_from_old_samples() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let storage = Arc::new(PersistStorage::new(db_path.to_str().unwrap()).unwrap());
+
+        let with_cpu = |timestamp| MetricsRequest {
+            system: Some(SystemMetrics {
+                cpu: Some(CpuMetrics {
+                    usage_percent: 42.0,
+                    per_core: vec![40.0, 44.0],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..metrics("agent-1", timestamp)
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        // 同一时间戳的多条记录跨越分批边界
+        let mut batch: Vec<_> = [1, 2, 2, 2, 3, 4, 4, 5]
+            .into_iter()
+            .map(|ts| with_cpu(ts * 1000))
+            .collect();
+        batch.push(with_cpu(now));
+        storage.flush_batch(&batch).await.unwrap();
+
+        let config = StorageConfig {
+            retention_days: 0,
+            field_retention: "per_core=1".parse().unwrap(),
+            ..Default::default()
+        };
+        let mut task = CleanupTask::new(config, storage.clone());
+        task.chunk_size = 2;
+        task.execute_cleanup().await;
+
+        let history = storage
+            .query_latest_by_agent("agent-1", usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 9);
+        let (recent, old) = history.split_last().unwrap();
+        for sample in old {
+            let cpu = sample.system.as_ref().unwrap().cpu.as_ref().unwrap();
+            assert_eq!(cpu.usage_percent, 42.0);
+            assert!(cpu.per_core.is_empty(), "timestamp {}", sample.timestamp);
+        }
+        let cpu = recent.system.as_ref().unwrap().cpu.as_ref().unwrap();
+        assert_eq!(cpu.per_core, vec![40.0, 44.0]);
+
+        // 下一轮从上次完成的位置继续，只剩数量清理统计记录数时扫描的 key
+        let scanned = storage.keys_scanned();
+        task.execute_cleanup().await;
+        assert_eq!(storage.keys_scanned() - scanned, 9);
+    }
+}
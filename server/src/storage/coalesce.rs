@@ -0,0 +1,156 @@
+//! 落盘前的写入合并
+//!
+//! 上报频率远高于查询粒度时，同一批次内同一 Agent 在很短时间窗口内、数值几乎不变的
+//! 样本只保留窗口内最后一条，减少落盘行数。与按序号去重不同，这里是按时间窗口的有损缩减
+
+use common::proto::{MetricsRequest, SystemMetrics};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// 写入合并配置
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoalesceConfig {
+    /// 合并窗口：从窗口内第一条样本起算，为零时不合并
+    pub window: Duration,
+    /// 视为“几乎相同”的最大差值（百分点），比较 CPU、内存与各挂载点的使用率
+    pub max_delta: f64,
+}
+
+impl CoalesceConfig {
+    /// 是否启用合并
+    pub fn is_enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+}
+
+/// 返回批次中需要落盘的样本下标（升序）
+///
+/// 按 Agent 分组、按时间排序后划分窗口：窗口从第一条样本开始，之后的样本与窗口首条样本
+/// 时间差小于 `window` 且数值在 `max_delta` 内时并入该窗口，否则开启新窗口。每个窗口只保留
+/// 最后一条样本
+pub fn coalesce_batch(batch: &[MetricsRequest], config: CoalesceConfig) -> Vec<usize> {
+    if !config.is_enabled() {
+        return (0..batch.len()).collect();
+    }
+    let window_ms = config.window.as_millis().min(i64::MAX as u128) as i64;
+
+    let mut by_agent: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (index, metrics) in batch.iter().enumerate() {
+        by_agent.entry(&metrics.agent_id).or_default().push(index);
+    }
+
+    let mut kept = Vec::with_capacity(batch.len());
+    for mut indices in by_agent.into_values() {
+        indices.sort_by_key(|&index| batch[index].timestamp);
+
+        let mut anchor = indices[0];
+        let mut last = anchor;
+        for &index in &indices[1..] {
+            let (first, sample) = (&batch[anchor], &batch[index]);
+            if sample.timestamp - first.timestamp < window_ms
+                && near_identical(first, sample, config.max_delta)
+            {
+                last = index;
+            } else {
+                kept.push(last);
+                anchor = index;
+                last = index;
+            }
+        }
+        kept.push(last);
+    }
+
+    kept.sort_unstable();
+    kept
+}
+
+/// 两条样本的主机名一致，且 CPU、内存与各挂载点使用率的差值都不超过 `max_delta`
+fn near_identical(a: &MetricsRequest, b: &MetricsRequest, max_delta: f64) -> bool {
+    if a.hostname != b.hostname {
+        return false;
+    }
+    match (&a.system, &b.system) {
+        (Some(a), Some(b)) => usage_within(a, b, max_delta),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn usage_within(a: &SystemMetrics, b: &SystemMetrics, max_delta: f64) -> bool {
+    let close = |x: f64, y: f64| (x - y).abs() <= max_delta;
+
+    let cpu = match (&a.cpu, &b.cpu) {
+        (Some(x), Some(y)) => close(x.usage_percent, y.usage_percent),
+        (None, None) => true,
+        _ => false,
+    };
+    let memory = match (&a.memory, &b.memory) {
+        (Some(x), Some(y)) => close(x.usage_percent, y.usage_percent),
+        (None, None) => true,
+        _ => false,
+    };
+    let disks = a.disks.len() == b.disks.len()
+        && a.disks.iter().zip(&b.disks).all(|(x, y)| {
+            x.mount_point == y.mount_point && close(x.usage_percent, y.usage_percent)
+        });
+
+    cpu && memory && disks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::CpuMetrics;
+
+    fn sample(agent_id: &str, timestamp: i64, cpu: f64) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: agent_id.to_string(),
+            timestamp,
+            system: Some(SystemMetrics {
+                cpu: Some(CpuMetrics {
+                    usage_percent: cpu,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    const CONFIG: CoalesceConfig = CoalesceConfig {
+        window: Duration::from_millis(100),
+        max_delta: 1.0,
+    };
+
+    #[test]
+    fn test_coalesce_keeps_last_in_window() {
+        let batch = vec![
+            sample("agent-1", 0, 10.0),
+            sample("agent-1", 40, 10.5),
+            sample("agent-1", 80, 10.2),
+            // 超出窗口，开启新窗口
+            sample("agent-1", 120, 10.0),
+            sample("agent-1", 150, 10.1),
+            // 数值变化过大，单独成窗口
+            sample("agent-1", 160, 50.0),
+        ];
+        assert_eq!(coalesce_batch(&batch, CONFIG), vec![2, 4, 5]);
+    }
+
+    #[test]
+    fn test_coalesce_per_agent_and_disabled() {
+        let batch = vec![
+            sample("agent-1", 0, 10.0),
+            sample("agent-2", 10, 10.0),
+            sample("agent-1", 20, 10.0),
+            sample("agent-2", 30, 10.0),
+        ];
+        assert_eq!(coalesce_batch(&batch, CONFIG), vec![2, 3]);
+
+        let disabled = CoalesceConfig {
+            window: Duration::ZERO,
+            ..CONFIG
+        };
+        assert_eq!(coalesce_batch(&batch, disabled), vec![0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,394 @@
+//! 指标记录编解码
+//!
+//! 每条记录以 `RECORD_MARKER` + 1 字节格式版本开头，按版本路由解码，
+//! 以便在不清空数据库的前提下演进 schema：
+//! - v0：早期无版本前缀的 bincode 记录，按冻结的旧结构（`legacy` 模块）解码后转换
+//! - v1：Protobuf 编码，新增字段不影响已有数据的读取
+
+use anyhow::Result;
+use common::proto::MetricsRequest;
+use prost::Message;
+
+/// 带版本记录的前缀标记
+///
+/// v0 记录以 agent_id 长度（u64 小端）开头，第 2 字节起不会是该序列
+const RECORD_MARKER: [u8; 3] = [0xff, b'I', b'P'];
+
+/// 数据格式版本（版本号以 ASCII 数字存储）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatVersion {
+    /// 无版本前缀的 bincode
+    V0,
+    /// Protobuf
+    V1,
+}
+
+impl FormatVersion {
+    /// 新记录使用的格式版本
+    pub const CURRENT: Self = Self::V1;
+
+    fn tag(self) -> Option<u8> {
+        match self {
+            Self::V0 => None,
+            Self::V1 => Some(b'1'),
+        }
+    }
+
+    /// 识别记录的格式版本，返回版本与去掉前缀后的负载
+    fn detect(bytes: &[u8]) -> Result<(Self, &[u8])> {
+        let Some(rest) = bytes.strip_prefix(&RECORD_MARKER) else {
+            return Ok((Self::V0, bytes));
+        };
+        match rest.split_first() {
+            Some((b'1', payload)) => Ok((Self::V1, payload)),
+            Some((tag, _)) => anyhow::bail!("unsupported record format version: {:#04x}", tag),
+            None => anyhow::bail!("truncated record header"),
+        }
+    }
+}
+
+/// 以当前格式版本编码一条指标记录
+pub fn encode_metrics(metrics: &MetricsRequest) -> Vec<u8> {
+    let tag = FormatVersion::CURRENT
+        .tag()
+        .expect("current format version is tagged");
+    let mut bytes = Vec::with_capacity(RECORD_MARKER.len() + 1 + metrics.encoded_len());
+    bytes.extend_from_slice(&RECORD_MARKER);
+    bytes.push(tag);
+    // 写入 Vec 不会因容量不足失败
+    metrics
+        .encode(&mut bytes)
+        .expect("encoding into Vec cannot fail");
+    bytes
+}
+
+/// 解码一条指标记录，按格式版本路由
+pub fn decode_metrics(bytes: &[u8]) -> Result<MetricsRequest> {
+    match FormatVersion::detect(bytes)? {
+        (FormatVersion::V0, payload) => {
+            let legacy: legacy::MetricsRequest = bincode::deserialize(payload)?;
+            Ok(legacy.into())
+        }
+        (FormatVersion::V1, payload) => Ok(MetricsRequest::decode(payload)?),
+    }
+}
+
+/// 旧 bincode 记录的结构定义（与最初的 proto 字段一一对应，不得修改）
+mod legacy {
+    use common::proto;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct MetricsRequest {
+        agent_id: String,
+        timestamp: i64,
+        system: Option<SystemMetrics>,
+        hostname: String,
+    }
+
+    #[derive(Deserialize)]
+    struct SystemMetrics {
+        cpu: Option<CpuMetrics>,
+        memory: Option<MemoryMetrics>,
+        disks: Vec<DiskMetrics>,
+        network: Option<NetworkMetrics>,
+        system_info: Option<SystemInfo>,
+        agent_metrics: Option<AgentMetrics>,
+        tcp_ping: Vec<TcpPingMetrics>,
+    }
+
+    #[derive(Deserialize)]
+    struct CpuMetrics {
+        usage_percent: f64,
+        core_count: i32,
+        per_core: Vec<f64>,
+        load_avg_1: f64,
+        load_avg_5: f64,
+        load_avg_15: f64,
+    }
+
+    #[derive(Deserialize)]
+    struct MemoryMetrics {
+        total: u64,
+        used: u64,
+        available: u64,
+        usage_percent: f64,
+        swap_total: u64,
+        swap_used: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct DiskMetrics {
+        mount_point: String,
+        device: String,
+        total: u64,
+        used: u64,
+        available: u64,
+        usage_percent: f64,
+        read_bytes: u64,
+        write_bytes: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct NetworkMetrics {
+        bytes_sent: u64,
+        bytes_recv: u64,
+        packets_sent: u64,
+        packets_recv: u64,
+        errors_in: u64,
+        errors_out: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct SystemInfo {
+        os_name: String,
+        os_version: String,
+        kernel_version: String,
+        arch: String,
+        uptime: u64,
+        cpu_model: String,
+        cpu_frequency: f64,
+        hostname: String,
+    }
+
+    #[derive(Deserialize)]
+    struct AgentMetrics {
+        cpu_usage: f64,
+        memory_usage: u64,
+        collection_time_ms: u64,
+        uptime_seconds: u64,
+        metrics_sent: u64,
+        errors_count: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct TcpPingMetrics {
+        carrier: String,
+        endpoint: String,
+        latency_ms: u64,
+        success: bool,
+        error: String,
+    }
+
+    impl From<MetricsRequest> for proto::MetricsRequest {
+        fn from(m: MetricsRequest) -> Self {
+            Self {
+                agent_id: m.agent_id,
+                timestamp: m.timestamp,
+                system: m.system.map(Into::into),
+                hostname: m.hostname,
+                sequence: 0,
+                labels: Default::default(),
+            }
+        }
+    }
+
+    impl From<SystemMetrics> for proto::SystemMetrics {
+        fn from(s: SystemMetrics) -> Self {
+            Self {
+                cpu: s.cpu.map(|c| proto::CpuMetrics {
+                    usage_percent: c.usage_percent,
+                    core_count: c.core_count,
+                    per_core: c.per_core,
+                    load_avg_1: c.load_avg_1,
+                    load_avg_5: c.load_avg_5,
+                    load_avg_15: c.load_avg_15,
+                    ..Default::default()
+                }),
+                memory: s.memory.map(|m| proto::MemoryMetrics {
+                    total: m.total,
+                    used: m.used,
+                    available: m.available,
+                    usage_percent: m.usage_percent,
+                    swap_total: m.swap_total,
+                    swap_used: m.swap_used,
+                }),
+                disks: s
+                    .disks
+                    .into_iter()
+                    .map(|d| proto::DiskMetrics {
+                        mount_point: d.mount_point,
+                        device: d.device,
+                        total: d.total,
+                        used: d.used,
+                        available: d.available,
+                        usage_percent: d.usage_percent,
+                        read_bytes: d.read_bytes,
+                        write_bytes: d.write_bytes,
+                    })
+                    .collect(),
+                network: s.network.map(|n| proto::NetworkMetrics {
+                    bytes_sent: n.bytes_sent,
+                    bytes_recv: n.bytes_recv,
+                    packets_sent: n.packets_sent,
+                    packets_recv: n.packets_recv,
+                    errors_in: n.errors_in,
+                    errors_out: n.errors_out,
+                }),
+                system_info: s.system_info.map(|i| proto::SystemInfo {
+                    os_name: i.os_name,
+                    os_version: i.os_version,
+                    kernel_version: i.kernel_version,
+                    arch: i.arch,
+                    uptime: i.uptime,
+                    cpu_model: i.cpu_model,
+                    cpu_frequency: i.cpu_frequency,
+                    hostname: i.hostname,
+                    ..Default::default()
+                }),
+                agent_metrics: s.agent_metrics.map(|a| proto::AgentMetrics {
+                    cpu_usage: a.cpu_usage,
+                    memory_usage: a.memory_usage,
+                    collection_time_ms: a.collection_time_ms,
+                    uptime_seconds: a.uptime_seconds,
+                    metrics_sent: a.metrics_sent,
+                    errors_count: a.errors_count,
+                    ..Default::default()
+                }),
+                tcp_ping: s
+                    .tcp_ping
+                    .into_iter()
+                    .map(|t| proto::TcpPingMetrics {
+                        carrier: t.carrier,
+                        endpoint: t.endpoint,
+                        latency_ms: t.latency_ms,
+                        success: t.success,
+                        error: t.error,
+                    })
+                    .collect(),
+                ..Default::default()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::*;
+
+    fn sample() -> MetricsRequest {
+        MetricsRequest {
+            agent_id: "agent-1".to_string(),
+            timestamp: 1000,
+            hostname: "test-host".to_string(),
+            sequence: 0,
+            labels: Default::default(),
+            system: Some(SystemMetrics {
+                cpu: Some(CpuMetrics {
+                    usage_percent: 50.0,
+                    core_count: 4,
+                    per_core: vec![25.0, 50.0, 75.0, 100.0],
+                    ..Default::default()
+                }),
+                disks: vec![DiskMetrics {
+                    mount_point: "/".to_string(),
+                    total: 100,
+                    used: 40,
+                    ..Default::default()
+                }],
+                collector_status: vec![CollectorStatus {
+                    subsystem: "disks".to_string(),
+                    state: CollectorState::Failed as i32,
+                    message: "boom".to_string(),
+                }],
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let metrics = sample();
+        let bytes = encode_metrics(&metrics);
+        assert_eq!(
+            FormatVersion::detect(&bytes).unwrap().0,
+            FormatVersion::CURRENT
+        );
+        assert_eq!(decode_metrics(&bytes).unwrap(), metrics);
+    }
+
+    #[test]
+    fn test_unknown_version_rejected() {
+        let mut bytes = encode_metrics(&sample());
+        bytes[RECORD_MARKER.len()] = b'9';
+        let err = decode_metrics(&bytes).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("unsupported record format version"));
+
+        assert!(decode_metrics(&RECORD_MARKER).is_err());
+    }
+
+    /// 按最初 schema 序列化的 bincode 记录仍可读取
+    #[test]
+    fn test_decode_legacy_bincode() {
+        let bytes = test_support::v0_bytes("agent-1", 1000);
+        assert_eq!(FormatVersion::detect(&bytes).unwrap().0, FormatVersion::V0);
+
+        let decoded = decode_metrics(&bytes).unwrap();
+        assert_eq!(decoded.agent_id, "agent-1");
+        assert_eq!(decoded.hostname, "test-host");
+        let system = decoded.system.unwrap();
+        assert_eq!(system.cpu.unwrap().core_count, 4);
+        assert_eq!(system.disks[0].device, "/dev/sda1");
+        assert!(system.collector_status.is_empty());
+    }
+}
+
+/// 测试辅助：构造 v0（无版本前缀 bincode）记录
+#[cfg(test)]
+pub(crate) mod test_support {
+    use serde::Serialize;
+
+    // 旧结构按字段顺序序列化，bincode 中与同序元组等价
+    type OldDisk = (String, String, u64, u64, u64, f64, u64, u64);
+    type OldSystemInfo = (String, String, String, String, u64, String, f64, String);
+
+    #[derive(Serialize)]
+    struct OldRequest {
+        agent_id: String,
+        timestamp: i64,
+        system: Option<OldSystem>,
+        hostname: String,
+    }
+
+    #[derive(Serialize)]
+    struct OldSystem {
+        cpu: Option<(f64, i32, Vec<f64>, f64, f64, f64)>,
+        memory: Option<(u64, u64, u64, f64, u64, u64)>,
+        disks: Vec<OldDisk>,
+        network: Option<(u64, u64, u64, u64, u64, u64)>,
+        system_info: Option<OldSystemInfo>,
+        agent_metrics: Option<(f64, u64, u64, u64, u64, u64)>,
+        tcp_ping: Vec<(String, String, u64, bool, String)>,
+    }
+
+    /// 按最初 schema 序列化一条记录（4 核 CPU、一块 /dev/sda1 磁盘）
+    pub fn v0_bytes(agent_id: &str, timestamp: i64) -> Vec<u8> {
+        let old = OldRequest {
+            agent_id: agent_id.to_string(),
+            timestamp,
+            system: Some(OldSystem {
+                cpu: Some((50.0, 4, vec![25.0, 50.0, 75.0, 100.0], 1.0, 0.8, 0.5)),
+                memory: None,
+                disks: vec![(
+                    "/".to_string(),
+                    "/dev/sda1".to_string(),
+                    100,
+                    40,
+                    60,
+                    40.0,
+                    0,
+                    0,
+                )],
+                network: None,
+                system_info: None,
+                agent_metrics: None,
+                tcp_ping: vec![],
+            }),
+            hostname: "test-host".to_string(),
+        };
+        bincode::serialize(&old).unwrap()
+    }
+}
@@ -1,17 +1,109 @@
 //! Storage 集成测试
 //!
-//! 测试完整的写入和查询流程
+//! 测试完整的写入和查询流程，每个测试分别对 redb 与内存持久化后端运行一遍
 
+use super::memory::MemoryBackend;
 use super::*;
 use common::proto::*;
+use std::collections::HashMap;
+use std::sync::OnceLock;
 use std::time::Duration;
 
+/// 测试使用的持久化后端
+#[derive(Debug, Clone, Copy)]
+enum Backend {
+    Redb,
+    Memory,
+}
+
+impl Backend {
+    /// 按配置创建 Storage
+    ///
+    /// 内存后端按 `db_path` 复用同一实例，模拟重启后重新打开同一个数据库
+    fn open(self, config: StorageConfig) -> Storage {
+        match (self, config.db_path.clone()) {
+            (Backend::Memory, Some(db_path)) => {
+                Storage::with_backend(config, self.persist(&db_path))
+            }
+            _ => Storage::with_config(config),
+        }
+    }
+
+    /// 直接打开 `db_path` 对应的持久化后端
+    fn persist(self, db_path: &str) -> Arc<dyn PersistBackend> {
+        static MEMORY_BACKENDS: OnceLock<std::sync::Mutex<HashMap<String, Arc<MemoryBackend>>>> =
+            OnceLock::new();
+
+        match self {
+            Backend::Redb => Arc::new(PersistStorage::new(db_path).unwrap()),
+            Backend::Memory => MEMORY_BACKENDS
+                .get_or_init(Default::default)
+                .lock()
+                .unwrap()
+                .entry(db_path.to_string())
+                .or_default()
+                .clone(),
+        }
+    }
+}
+
+/// 为每个测试函数分别生成 redb 与内存后端的测试用例
+macro_rules! backend_tests {
+    ($($name:ident),* $(,)?) => {
+        mod redb {
+            $(
+                #[tokio::test]
+                async fn $name() {
+                    super::$name(super::Backend::Redb).await;
+                }
+            )*
+        }
+
+        mod memory {
+            $(
+                #[tokio::test]
+                async fn $name() {
+                    super::$name(super::Backend::Memory).await;
+                }
+            )*
+        }
+    };
+}
+
+backend_tests!(
+    test_storage_write_and_read,
+    test_storage_multiple_agents,
+    test_storage_history,
+    test_storage_history_fallback_to_persistence_when_cache_insufficient,
+    test_storage_history_persist_authoritative_ordering,
+    test_storage_batch_write,
+    test_storage_timeout_flush,
+    test_storage_cache_limit,
+    test_storage_multiple_agents_cache_isolation,
+    test_storage_shutdown,
+    test_storage_channel_full,
+    test_storage_save_after_shutdown_does_not_block,
+    test_storage_empty_query,
+    test_storage_persistence_across_restarts,
+    test_storage_high_frequency_writes,
+    test_storage_cleanup_disabled,
+    test_storage_cleanup_shutdown,
+    test_storage_shutdown_flushes_all_queued,
+    test_storage_hostname_history_across_restarts,
+    test_storage_recent_across_agents,
+    test_storage_coalesce_dense_burst,
+    test_storage_stream_range_folds_incrementally,
+    test_storage_strip_fields_keeps_core_series,
+);
+
 /// 创建完整的测试指标数据
 fn create_test_metrics(agent_id: &str, timestamp: i64) -> MetricsRequest {
     MetricsRequest {
         agent_id: agent_id.to_string(),
         timestamp,
         hostname: "test-host".to_string(),
+        sequence: 0,
+        labels: Default::default(),
         system: Some(SystemMetrics {
             cpu: Some(CpuMetrics {
                 usage_percent: 50.0,
@@ -20,6 +112,8 @@ fn create_test_metrics(agent_id: &str, timestamp: i64) -> MetricsRequest {
                 load_avg_1: 1.0,
                 load_avg_5: 0.8,
                 load_avg_15: 0.5,
+                per_core_frequency: vec![],
+                per_core_temperature: vec![],
             }),
             memory: Some(MemoryMetrics {
                 total: 16_000_000_000,
@@ -41,12 +135,21 @@ fn create_test_metrics(agent_id: &str, timestamp: i64) -> MetricsRequest {
             system_info: None,
             agent_metrics: None,
             tcp_ping: vec![],
+            collector_status: vec![],
+            pressure: None,
+            gpu: vec![],
+            zombie_count: 0,
+            file_descriptors: None,
+            top_processes: vec![],
+            entropy: None,
+            systemd: None,
+            numa_nodes: vec![],
+            containers: None,
         }),
     }
 }
 
-#[tokio::test]
-async fn test_storage_write_and_read() {
+async fn test_storage_write_and_read(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -64,7 +167,7 @@ async fn test_storage_write_and_read() {
         ..Default::default()
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     // 写入数据
     let metrics = create_test_metrics("agent-1", 1000);
@@ -84,8 +187,7 @@ async fn test_storage_write_and_read() {
     assert!(agents.contains(&"agent-1".to_string()));
 }
 
-#[tokio::test]
-async fn test_storage_multiple_agents() {
+async fn test_storage_multiple_agents(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -103,7 +205,7 @@ async fn test_storage_multiple_agents() {
         ..Default::default()
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     // 写入多个 agent 的数据
     for i in 1..=5 {
@@ -127,8 +229,7 @@ async fn test_storage_multiple_agents() {
     }
 }
 
-#[tokio::test]
-async fn test_storage_history() {
+async fn test_storage_history(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -146,7 +247,7 @@ async fn test_storage_history() {
         ..Default::default()
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     // 写入多条历史数据
     for i in 1..=10 {
@@ -166,8 +267,7 @@ async fn test_storage_history() {
     assert_eq!(history[4].timestamp, 10000);
 }
 
-#[tokio::test]
-async fn test_storage_history_fallback_to_persistence_when_cache_insufficient() {
+async fn test_storage_history_fallback_to_persistence_when_cache_insufficient(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -185,7 +285,7 @@ async fn test_storage_history_fallback_to_persistence_when_cache_insufficient()
         ..Default::default()
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     for i in 1..=20 {
         let metrics = create_test_metrics("agent-1", i * 1000);
@@ -201,8 +301,80 @@ async fn test_storage_history_fallback_to_persistence_when_cache_insufficient()
     assert_eq!(history[19].timestamp, 20000);
 }
 
-#[tokio::test]
-async fn test_storage_batch_write() {
+async fn test_storage_history_persist_authoritative_ordering(backend: Backend) {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir
+        .path()
+        .join("test.db")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    // 较早的 10 条样本已落盘
+    let batch: Vec<_> = (1..=10)
+        .map(|i| create_test_metrics("agent-1", i * 1000))
+        .collect();
+    backend.persist(&db_path).flush_batch(&batch).await.unwrap();
+
+    // 批量大小与超时都远未达到，新写入的样本只在缓存与写入队列中
+    let storage = backend.open(StorageConfig {
+        db_path: Some(db_path),
+        cache_size_per_agent: 5,
+        batch_size: 100,
+        batch_timeout: Duration::from_secs(10),
+        channel_capacity: 100,
+        ..Default::default()
+    });
+    for i in 11..=13 {
+        storage
+            .save_metrics(&create_test_metrics("agent-1", i * 1000))
+            .await;
+    }
+    // 迟到的回填样本进入缓存历史
+    storage
+        .save_backfill(&create_test_metrics("agent-1", 4500))
+        .await;
+
+    let timestamps =
+        |history: &[MetricsRequest]| -> Vec<i64> { history.iter().map(|m| m.timestamp).collect() };
+
+    // cache-preferred 只看缓存，回填样本挤掉了已落盘的 10000
+    let cached = storage
+        .get_agent_history_with("agent-1", 4, HistoryConsistency::CachePreferred)
+        .await
+        .unwrap();
+    assert_eq!(timestamps(&cached), vec![4500, 11000, 12000, 13000]);
+
+    // persist-authoritative 立即落盘后只读持久化层，不必等待批量超时
+    let authoritative = tokio::time::timeout(
+        Duration::from_secs(2),
+        storage.get_agent_history_with("agent-1", 4, HistoryConsistency::PersistAuthoritative),
+    )
+    .await
+    .expect("pending metrics should be flushed on demand")
+    .unwrap();
+    assert_eq!(timestamps(&authoritative), vec![10000, 11000, 12000, 13000]);
+
+    // 完整窗口严格按时间戳排序，重复查询结果一致
+    let full = storage
+        .get_agent_history_with("agent-1", 100, HistoryConsistency::PersistAuthoritative)
+        .await
+        .unwrap();
+    let mut expected: Vec<i64> = (1..=13).map(|i| i * 1000).collect();
+    expected.insert(4, 4500);
+    assert_eq!(timestamps(&full), expected);
+    assert_eq!(
+        storage
+            .get_agent_history_with("agent-1", 100, HistoryConsistency::PersistAuthoritative)
+            .await
+            .unwrap(),
+        full
+    );
+
+    storage.shutdown().await.unwrap();
+}
+
+async fn test_storage_batch_write(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -221,7 +393,7 @@ async fn test_storage_batch_write() {
         ..Default::default()
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     // 写入 3 条数据，正好触发批量写入
     for i in 1..=3 {
@@ -237,8 +409,7 @@ async fn test_storage_batch_write() {
     assert_eq!(history.len(), 3);
 }
 
-#[tokio::test]
-async fn test_storage_timeout_flush() {
+async fn test_storage_timeout_flush(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -257,7 +428,7 @@ async fn test_storage_timeout_flush() {
         ..Default::default()
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     // 只写入 1 条数据，不足以触发批量写入
     let metrics = create_test_metrics("agent-1", 1000);
@@ -271,8 +442,7 @@ async fn test_storage_timeout_flush() {
     assert!(latest.is_some());
 }
 
-#[tokio::test]
-async fn test_storage_cache_limit() {
+async fn test_storage_cache_limit(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -291,7 +461,7 @@ async fn test_storage_cache_limit() {
         ..Default::default()
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     // 写入超过缓存大小的数据
     for i in 1..=10 {
@@ -309,8 +479,7 @@ async fn test_storage_cache_limit() {
     assert_eq!(history[9].timestamp, 10000);
 }
 
-#[tokio::test]
-async fn test_storage_multiple_agents_cache_isolation() {
+async fn test_storage_multiple_agents_cache_isolation(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -328,7 +497,7 @@ async fn test_storage_multiple_agents_cache_isolation() {
         ..Default::default()
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     // agent-1 写入 5 条
     for i in 1..=5 {
@@ -354,8 +523,7 @@ async fn test_storage_multiple_agents_cache_isolation() {
     assert_eq!(history2.len(), 2);
 }
 
-#[tokio::test]
-async fn test_storage_shutdown() {
+async fn test_storage_shutdown(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -373,7 +541,7 @@ async fn test_storage_shutdown() {
         ..Default::default()
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     // 写入数据
     for i in 1..=5 {
@@ -388,8 +556,7 @@ async fn test_storage_shutdown() {
     tokio::time::sleep(Duration::from_millis(100)).await;
 }
 
-#[tokio::test]
-async fn test_storage_channel_full() {
+async fn test_storage_channel_full(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -407,7 +574,7 @@ async fn test_storage_channel_full() {
         ..Default::default()
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     // 快速写入超过通道容量的数据
     for i in 0..10 {
@@ -420,8 +587,7 @@ async fn test_storage_channel_full() {
     assert!(latest.is_some());
 }
 
-#[tokio::test]
-async fn test_storage_save_after_shutdown_does_not_block() {
+async fn test_storage_save_after_shutdown_does_not_block(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -439,20 +605,20 @@ async fn test_storage_save_after_shutdown_does_not_block() {
         ..Default::default()
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     storage.shutdown().await.unwrap();
 
     let metrics = create_test_metrics("agent-1", 1000);
     let result = tokio::time::timeout(Duration::from_secs(2), storage.save_metrics(&metrics)).await;
 
-    assert!(result.is_ok(), "save_metrics 不应卡住");
+    assert_eq!(result, Ok(false), "save_metrics 不应卡住，且应报告未保存");
+    // 未保存的样本不进缓存，Agent 重试时不会留下重复条目
     let latest = storage.get_agent_latest("agent-1").await;
-    assert!(latest.is_some(), "即使持久化队列关闭，缓存也应更新");
+    assert!(latest.is_none(), "持久化队列关闭时不应写入缓存");
 }
 
-#[tokio::test]
-async fn test_storage_empty_query() {
+async fn test_storage_empty_query(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -461,7 +627,7 @@ async fn test_storage_empty_query() {
         .unwrap()
         .to_string();
 
-    let storage = Storage::with_config(StorageConfig {
+    let storage = backend.open(StorageConfig {
         db_path: Some(db_path),
         ..Default::default()
     });
@@ -475,8 +641,7 @@ async fn test_storage_empty_query() {
     assert!(storage.get_all_agents().await.is_empty());
 }
 
-#[tokio::test]
-async fn test_storage_persistence_across_restarts() {
+async fn test_storage_persistence_across_restarts(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -496,7 +661,7 @@ async fn test_storage_persistence_across_restarts() {
             ..Default::default()
         };
 
-        let storage = Storage::with_config(config);
+        let storage = backend.open(config);
         for i in 1..=5 {
             let metrics = create_test_metrics("agent-1", i * 1000);
             storage.save_metrics(&metrics).await;
@@ -516,7 +681,7 @@ async fn test_storage_persistence_across_restarts() {
             ..Default::default()
         };
 
-        let storage = Storage::with_config(config);
+        let storage = backend.open(config);
 
         // 数据应该持久化在数据库中（缓存为空，但数据在磁盘上）
         // 注意：当前实现中，缓存从磁盘重新加载的功能还未实现
@@ -533,8 +698,7 @@ async fn test_storage_persistence_across_restarts() {
     }
 }
 
-#[tokio::test]
-async fn test_storage_high_frequency_writes() {
+async fn test_storage_high_frequency_writes(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -552,7 +716,7 @@ async fn test_storage_high_frequency_writes() {
         ..Default::default()
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     // 高频写入 200 条数据
     for i in 0..200 {
@@ -568,8 +732,7 @@ async fn test_storage_high_frequency_writes() {
     assert_eq!(history.len(), 200);
 }
 
-#[tokio::test]
-async fn test_storage_cleanup_disabled() {
+async fn test_storage_cleanup_disabled(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -591,7 +754,7 @@ async fn test_storage_cleanup_disabled() {
         ..Default::default()
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     // 写入超过限制的数据
     for i in 1..=10 {
@@ -608,8 +771,7 @@ async fn test_storage_cleanup_disabled() {
     storage.shutdown().await.unwrap();
 }
 
-#[tokio::test]
-async fn test_storage_cleanup_shutdown() {
+async fn test_storage_cleanup_shutdown(backend: Backend) {
     let temp_dir = tempfile::tempdir().unwrap();
     let db_path = temp_dir
         .path()
@@ -624,14 +786,24 @@ async fn test_storage_cleanup_shutdown() {
         cache_size_per_agent: 100,
         batch_size: 10,
         batch_timeout: Duration::from_millis(100),
+        batch_timeout_jitter: Duration::ZERO,
         channel_capacity: 100,
         max_records_per_agent: 10000,
         retention_days: 30,
         cleanup_interval_hours: 1, // 1 小时间隔
         enable_cleanup: true,      // 启用清理
+        cleanup_exempt_agents: HashSet::new(),
+        strict_permissions: false,
+        require_persistence: false,
+        db_shards: 1,
+        db_cache_bytes: None,
+        coalesce_window: Duration::ZERO,
+        coalesce_max_delta: DEFAULT_COALESCE_MAX_DELTA,
+        field_retention: FieldRetention::default(),
+        live_only: false,
     };
 
-    let storage = Storage::with_config(config);
+    let storage = backend.open(config);
 
     // 写入一些数据
     for i in 1..=5 {
@@ -648,3 +820,295 @@ async fn test_storage_cleanup_shutdown() {
     // 等待关闭完成
     tokio::time::sleep(Duration::from_millis(200)).await;
 }
+
+async fn test_storage_shutdown_flushes_all_queued(backend: Backend) {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir
+        .path()
+        .join("test.db")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    // batch 很大、超时很长：关闭前不会自然落盘
+    let config = StorageConfig {
+        db_path: Some(db_path.clone()),
+        cache_size_per_agent: 10,
+        batch_size: 1000,
+        batch_timeout: Duration::from_secs(60),
+        channel_capacity: 100,
+        enable_cleanup: false,
+        ..Default::default()
+    };
+
+    let storage = backend.open(config);
+    for i in 1..=30 {
+        let metrics = create_test_metrics("agent-1", i * 1000);
+        storage.save_metrics(&metrics).await;
+    }
+
+    let report = storage.shutdown().await.unwrap();
+    assert_eq!(report.flushed, 30);
+    assert_eq!(report.dropped, 0);
+
+    // 再次关闭不应重复计数
+    let report = storage.shutdown().await.unwrap();
+    assert_eq!(report, ShutdownReport::default());
+    drop(storage);
+
+    // 重新打开数据库，确认每条样本都已落盘
+    let persist = backend.persist(&db_path);
+    let persisted = persist
+        .query_latest_by_agent("agent-1", usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(persisted.len(), 30);
+    assert_eq!(persisted[0].timestamp, 1000);
+    assert_eq!(persisted[29].timestamp, 30000);
+}
+
+async fn test_storage_hostname_history_across_restarts(backend: Backend) {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir
+        .path()
+        .join("test.db")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let config = StorageConfig {
+        db_path: Some(db_path),
+        batch_size: 100,
+        batch_timeout: Duration::from_secs(60),
+        ..Default::default()
+    };
+
+    {
+        let storage = backend.open(config.clone());
+        for ts in [1000, 2000, 3000] {
+            storage
+                .save_metrics(&create_test_metrics("agent-1", ts))
+                .await;
+        }
+        for ts in [4000, 5000] {
+            let mut m = create_test_metrics("agent-1", ts);
+            m.hostname = "renamed-host".to_string();
+            storage.save_metrics(&m).await;
+        }
+
+        // 尚未落盘时也能从内存查到
+        assert_eq!(storage.get_hostname_history("agent-1").await.len(), 2);
+        storage.shutdown().await.unwrap();
+    }
+
+    let storage = backend.open(config);
+    let history = storage.get_hostname_history("agent-1").await;
+    assert_eq!(
+        history,
+        vec![
+            HostnameChange {
+                hostname: "test-host".to_string(),
+                since: 1000,
+            },
+            HostnameChange {
+                hostname: "renamed-host".to_string(),
+                since: 4000,
+            },
+        ]
+    );
+    assert!(storage.get_hostname_history("agent-2").await.is_empty());
+    storage.shutdown().await.unwrap();
+}
+
+async fn test_storage_recent_across_agents(backend: Backend) {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir
+        .path()
+        .join("test.db")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let config = StorageConfig {
+        db_path: Some(db_path),
+        batch_size: 100,
+        batch_timeout: Duration::from_secs(60),
+        ..Default::default()
+    };
+
+    {
+        let storage = backend.open(config.clone());
+        for (agent_id, ts) in [("agent-a", 1000), ("agent-b", 2000), ("agent-c", 3000)] {
+            storage
+                .save_metrics(&create_test_metrics(agent_id, ts))
+                .await;
+        }
+        storage.shutdown().await.unwrap();
+    }
+
+    // 已落盘的数据与仅在缓存中的数据合并，且缓存中重复的样本不会重复返回
+    let storage = backend.open(config);
+    for (agent_id, ts) in [("agent-a", 4000), ("agent-b", 5000), ("agent-a", 6000)] {
+        storage
+            .save_metrics(&create_test_metrics(agent_id, ts))
+            .await;
+    }
+
+    let recent = storage.get_recent_across_agents(4).await;
+    let order: Vec<_> = recent
+        .iter()
+        .map(|m| (m.agent_id.as_str(), m.timestamp))
+        .collect();
+    assert_eq!(
+        order,
+        vec![
+            ("agent-a", 6000),
+            ("agent-b", 5000),
+            ("agent-a", 4000),
+            ("agent-c", 3000),
+        ]
+    );
+
+    // 总数不足 limit 时返回全部
+    assert_eq!(storage.get_recent_across_agents(100).await.len(), 6);
+    assert!(storage.get_recent_across_agents(0).await.is_empty());
+    storage.shutdown().await.unwrap();
+}
+
+async fn test_storage_coalesce_dense_burst(backend: Backend) {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir
+        .path()
+        .join("test.db")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    // 每 100ms 一条、数值不变的密集样本，按 1s 窗口合并
+    {
+        let config = StorageConfig {
+            db_path: Some(db_path.clone()),
+            batch_size: 100,
+            batch_timeout: Duration::from_secs(10),
+            channel_capacity: 100,
+            coalesce_window: Duration::from_secs(1),
+            ..Default::default()
+        };
+
+        let storage = backend.open(config);
+        for i in 0..25 {
+            storage
+                .save_metrics(&create_test_metrics("agent-1", i * 100))
+                .await;
+        }
+        storage.shutdown().await.unwrap();
+    }
+
+    // 重启后从磁盘读取：每个窗口只保留最后一条，突发的最后一条样本必然保留
+    let storage = backend.open(StorageConfig {
+        db_path: Some(db_path),
+        ..Default::default()
+    });
+    let rows = storage.get_agent_range("agent-1", 0, i64::MAX).await;
+    let timestamps: Vec<_> = rows.iter().map(|m| m.timestamp).collect();
+    assert_eq!(timestamps, vec![900, 1900, 2400]);
+    storage.shutdown().await.unwrap();
+}
+
+async fn test_storage_stream_range_folds_incrementally(backend: Backend) {
+    use crate::analytics::{Aggregation, BucketFolder};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir
+        .path()
+        .join("test.db")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    // 约 5.5 小时的 1Hz 数据直接写入持久化层
+    const SAMPLES: i64 = 20_000;
+    let batch: Vec<_> = (0..SAMPLES)
+        .map(|i| create_test_metrics("agent-1", i * 1000))
+        .collect();
+    backend.persist(&db_path).flush_batch(&batch).await.unwrap();
+
+    let storage = backend.open(StorageConfig {
+        db_path: Some(db_path),
+        ..Default::default()
+    });
+    let (from, to) = (5_000_000, 14_999_000);
+    let mut samples = storage.stream_agent_range("agent-1", from, to);
+
+    // 消费方不读取时，已交付给接收端的记录数受通道容量限制，不会把整个范围读入内存
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(samples.len() <= 1, "{}", samples.len());
+
+    let mut folder = BucketFolder::new(from, 60_000, Aggregation::Max);
+    let mut records = 0;
+    let mut last_ts = i64::MIN;
+    while let Some(metrics) = samples.recv().await {
+        assert!(metrics.timestamp > last_ts);
+        assert!((from..=to).contains(&metrics.timestamp));
+        last_ts = metrics.timestamp;
+        folder.push(
+            metrics.timestamp,
+            metrics.system.unwrap().cpu.unwrap().usage_percent,
+        );
+        records += 1;
+    }
+    assert_eq!(records, 10_000);
+    // 一万条记录折叠为每分钟一个桶状态
+    assert_eq!(folder.bucket_count(), 167);
+    assert!(folder.finish().iter().all(|(_, value)| *value == 50.0));
+
+    assert_eq!(
+        storage.stream_agent_range("agent-1", to, from).recv().await,
+        None
+    );
+    storage.shutdown().await.unwrap();
+}
+
+async fn test_storage_strip_fields_keeps_core_series(backend: Backend) {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let db_path = temp_dir
+        .path()
+        .join("test.db")
+        .to_str()
+        .unwrap()
+        .to_string();
+    let persist = backend.persist(&db_path);
+
+    // 同一时间戳的多条记录跨越分批边界
+    let batch: Vec<_> = [1, 1, 1, 2, 3, 3, 4]
+        .into_iter()
+        .map(|ts| create_test_metrics("agent-1", ts * 1000))
+        .collect();
+    persist.flush_batch(&batch).await.unwrap();
+
+    let retention: FieldRetention = "per_core=1".parse().unwrap();
+    let plan = retention.plan(3000 + 3_600_000);
+    let stripped = persist
+        .strip_agent_fields("agent-1", 0, &plan, 2, &mut || true)
+        .await
+        .unwrap();
+    assert_eq!(stripped, 4);
+
+    let history = persist
+        .query_latest_by_agent("agent-1", usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 7);
+    for metrics in &history {
+        let cpu = metrics.system.as_ref().unwrap().cpu.as_ref().unwrap();
+        assert_eq!(cpu.usage_percent, 50.0);
+        assert_eq!(cpu.per_core.is_empty(), metrics.timestamp < 3000);
+    }
+
+    // 已改写的记录再次改写时没有变化
+    let stripped = persist
+        .strip_agent_fields("agent-1", 0, &plan, 2, &mut || true)
+        .await
+        .unwrap();
+    assert_eq!(stripped, 0);
+}
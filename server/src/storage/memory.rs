@@ -0,0 +1,392 @@
+//! 内存持久化后端
+//!
+//! 把全部数据保存在进程内存中，进程退出即丢失。用于在 redb 之外验证 [`PersistBackend`]
+//! 的约定：存储集成测试对两种后端各运行一遍
+
+use super::backend::PersistBackend;
+use super::cache::sort_newest_first;
+use super::retention::StripPlan;
+use super::rollup::HourlyRollup;
+use super::{record_hostname, AgentEvent, HostnameChange, MAX_AGENT_EVENTS};
+use anyhow::Result;
+use common::proto::MetricsRequest;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// 流式导出时最多缓冲的记录数
+const EXPORT_CHANNEL_CAPACITY: usize = 256;
+
+/// 单个 Agent 内的记录键: (timestamp, sequence, nonce)
+///
+/// 带采集序号的样本以 (timestamp, sequence) 为前缀，首次写入时 nonce 为 0，同一序号下
+/// 内容不同的样本使用递增的 nonce；普通样本 sequence 为 0，同样使用递增的 nonce 避免覆盖
+type RowKey = (i64, u64, u64);
+
+#[derive(Default)]
+struct Inner {
+    /// 各 Agent 的记录，按时间戳升序；没有记录的 Agent 不保留条目
+    metrics: HashMap<String, BTreeMap<RowKey, MetricsRequest>>,
+    /// 各 Agent 的主机名变更历史
+    hostnames: HashMap<String, Vec<HostnameChange>>,
+    /// 各 Agent 的小时汇总，按小时起点升序
+    hourly: HashMap<String, BTreeMap<i64, HourlyRollup>>,
+    /// 各 Agent 的时间线事件，按时间升序（时间相同时按写入顺序）
+    events: HashMap<String, Vec<AgentEvent>>,
+    /// 下一个 nonce
+    next_nonce: u64,
+}
+
+/// 内存持久化后端
+#[derive(Default)]
+pub struct MemoryBackend {
+    inner: Mutex<Inner>,
+    /// 每次批量写入前的等待，模拟缓慢或卡住的磁盘
+    flush_delay: Duration,
+}
+
+impl MemoryBackend {
+    /// 创建空的内存后端
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 每次批量写入前等待 `delay`
+    pub fn with_flush_delay(mut self, delay: Duration) -> Self {
+        self.flush_delay = delay;
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// 指定 Agent 按时间戳升序的全部记录
+    fn agent_rows(&self, agent_id: &str) -> Vec<MetricsRequest> {
+        self.lock()
+            .metrics
+            .get(agent_id)
+            .map(|rows| rows.values().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[tonic::async_trait]
+impl PersistBackend for MemoryBackend {
+    async fn flush_batch(&self, metrics: &[MetricsRequest]) -> Result<()> {
+        if !self.flush_delay.is_zero() {
+            tokio::time::sleep(self.flush_delay).await;
+        }
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        for m in metrics {
+            let rows = inner.metrics.entry(m.agent_id.clone()).or_default();
+            let key = if m.sequence == 0 {
+                inner.next_nonce += 1;
+                (m.timestamp, 0, inner.next_nonce)
+            } else {
+                let mut same_sequence = rows
+                    .range((m.timestamp, m.sequence, 0)..=(m.timestamp, m.sequence, u64::MAX))
+                    .peekable();
+                if same_sequence.peek().is_none() {
+                    (m.timestamp, m.sequence, 0)
+                } else if same_sequence.any(|(_, existing)| existing == m) {
+                    continue;
+                } else {
+                    inner.next_nonce += 1;
+                    (m.timestamp, m.sequence, inner.next_nonce)
+                }
+            };
+            rows.insert(key, m.clone());
+
+            let history = inner.hostnames.entry(m.agent_id.clone()).or_default();
+            record_hostname(history, &m.hostname, m.timestamp);
+        }
+        Ok(())
+    }
+
+    async fn get_latest_metrics(&self, agent_id: &str) -> Result<Option<MetricsRequest>> {
+        Ok(self
+            .lock()
+            .metrics
+            .get(agent_id)
+            .and_then(|rows| rows.values().next_back().cloned()))
+    }
+
+    async fn query_latest_by_agent(
+        &self,
+        agent_id: &str,
+        limit: usize,
+    ) -> Result<Vec<MetricsRequest>> {
+        let mut rows = self.agent_rows(agent_id);
+        rows.drain(..rows.len().saturating_sub(limit));
+        Ok(rows)
+    }
+
+    async fn query_range_by_agent(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> Result<Vec<MetricsRequest>> {
+        if end_ts < start_ts {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .lock()
+            .metrics
+            .get(agent_id)
+            .map(|rows| {
+                rows.range((start_ts, 0, 0)..=(end_ts, u64::MAX, u64::MAX))
+                    .map(|(_, m)| m.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn stream_range_by_agent(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> mpsc::Receiver<Result<MetricsRequest>> {
+        let (tx, rx) = mpsc::channel(EXPORT_CHANNEL_CAPACITY);
+        let mut rows = self.agent_rows(agent_id);
+        rows.retain(|m| m.timestamp >= start_ts && m.timestamp <= end_ts);
+        tokio::spawn(async move {
+            for metrics in rows {
+                if tx.send(Ok(metrics)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        rx
+    }
+
+    async fn query_recent_across_agents(&self, limit: usize) -> Result<Vec<MetricsRequest>> {
+        let inner = self.lock();
+        // 每个 Agent 最多贡献 limit 条
+        let mut results: Vec<MetricsRequest> = inner
+            .metrics
+            .values()
+            .flat_map(|rows| rows.values().rev().take(limit).cloned())
+            .collect();
+        sort_newest_first(&mut results);
+        results.truncate(limit);
+        Ok(results)
+    }
+
+    async fn get_hostname_history(&self, agent_id: &str) -> Result<Vec<HostnameChange>> {
+        Ok(self
+            .lock()
+            .hostnames
+            .get(agent_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn append_event(&self, agent_id: &str, event: &AgentEvent) -> Result<()> {
+        let mut inner = self.lock();
+        let events = inner.events.entry(agent_id.to_string()).or_default();
+        let index = events.partition_point(|e| e.timestamp <= event.timestamp);
+        events.insert(index, event.clone());
+        if events.len() > MAX_AGENT_EVENTS {
+            let excess = events.len() - MAX_AGENT_EVENTS;
+            events.drain(..excess);
+        }
+        Ok(())
+    }
+
+    async fn query_events(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> Result<Vec<AgentEvent>> {
+        Ok(self
+            .lock()
+            .events
+            .get(agent_id)
+            .into_iter()
+            .flatten()
+            .filter(|event| (start_ts..=end_ts).contains(&event.timestamp))
+            .cloned()
+            .collect())
+    }
+
+    async fn get_all_agent_ids(&self) -> Result<Vec<String>> {
+        let mut agent_ids: Vec<String> = self.lock().metrics.keys().cloned().collect();
+        agent_ids.sort();
+        Ok(agent_ids)
+    }
+
+    async fn count_agent_records(&self, agent_id: &str) -> Result<usize> {
+        Ok(self.lock().metrics.get(agent_id).map_or(0, BTreeMap::len))
+    }
+
+    async fn delete_agent_chunk(
+        &self,
+        agent_id: &str,
+        before_ts: Option<i64>,
+        limit: usize,
+    ) -> Result<usize> {
+        let mut inner = self.lock();
+        let Some(rows) = inner.metrics.get_mut(agent_id) else {
+            return Ok(0);
+        };
+
+        let keys: Vec<RowKey> = rows
+            .keys()
+            .take_while(|(ts, _, _)| before_ts.is_none_or(|before| *ts < before))
+            .take(limit)
+            .copied()
+            .collect();
+        for key in &keys {
+            rows.remove(key);
+        }
+        if rows.is_empty() {
+            inner.metrics.remove(agent_id);
+        }
+        Ok(keys.len())
+    }
+
+    async fn strip_agent_chunk(
+        &self,
+        agent_id: &str,
+        from_ts: i64,
+        before_ts: i64,
+        limit: usize,
+        plan: &StripPlan,
+    ) -> Result<(usize, Option<i64>)> {
+        let mut inner = self.lock();
+        let Some(rows) = inner.metrics.get_mut(agent_id) else {
+            return Ok((0, None));
+        };
+
+        let mut stripped = 0;
+        let mut last_ts = None;
+        let range = rows.range_mut((from_ts, 0, 0)..(before_ts, 0, 0));
+        for (taken, ((ts, _, _), metrics)) in range.enumerate() {
+            // 凑满一批后，只继续改写与最后一条时间戳相同的记录
+            if taken >= limit && last_ts != Some(*ts) {
+                return Ok((stripped, Some(*ts)));
+            }
+            if plan.apply(metrics) {
+                stripped += 1;
+            }
+            last_ts = Some(*ts);
+        }
+        Ok((stripped, None))
+    }
+
+    async fn next_timestamp_from(&self, agent_id: &str, from_ts: i64) -> Result<Option<i64>> {
+        Ok(self.lock().metrics.get(agent_id).and_then(|rows| {
+            rows.range((from_ts, 0, 0)..)
+                .next()
+                .map(|((ts, _, _), _)| *ts)
+        }))
+    }
+
+    async fn last_hourly_rollup(&self, agent_id: &str) -> Result<Option<i64>> {
+        Ok(self
+            .lock()
+            .hourly
+            .get(agent_id)
+            .and_then(|hours| hours.keys().next_back().copied()))
+    }
+
+    async fn write_hourly_rollups(
+        &self,
+        agent_id: &str,
+        rollups: Vec<(i64, HourlyRollup)>,
+    ) -> Result<()> {
+        if rollups.is_empty() {
+            return Ok(());
+        }
+        self.lock()
+            .hourly
+            .entry(agent_id.to_string())
+            .or_default()
+            .extend(rollups);
+        Ok(())
+    }
+
+    async fn query_hourly_range(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> Result<Vec<(i64, HourlyRollup)>> {
+        if end_ts < start_ts {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .lock()
+            .hourly
+            .get(agent_id)
+            .map(|hours| {
+                hours
+                    .range(start_ts..=end_ts)
+                    .map(|(hour, rollup)| (*hour, *rollup))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: i64, sequence: u64, hostname: &str) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: "agent-1".to_string(),
+            timestamp,
+            sequence,
+            hostname: hostname.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend_dedup_and_trim() {
+        let backend = MemoryBackend::new();
+        let batch = vec![
+            sample(1000, 1, "host-a"),
+            sample(2000, 0, "host-a"),
+            sample(2000, 0, "host-a"),
+            sample(3000, 2, "host-b"),
+        ];
+        backend.flush_batch(&batch).await.unwrap();
+        // 重放带序号的样本不会重复保存，内容不同时另存一条，再次重放也不重复
+        for _ in 0..2 {
+            backend
+                .flush_batch(&[sample(1000, 1, "host-a"), sample(3000, 2, "host-c")])
+                .await
+                .unwrap();
+        }
+        assert_eq!(backend.count_agent_records("agent-1").await.unwrap(), 5);
+        let history = backend.get_hostname_history("agent-1").await.unwrap();
+        assert_eq!(history.len(), 3);
+
+        let deleted = backend
+            .trim_agent_records("agent-1", 2, 2, &mut || true)
+            .await
+            .unwrap();
+        assert_eq!(deleted, 3);
+        let remaining = backend.query_latest_by_agent("agent-1", 10).await.unwrap();
+        assert!(remaining.iter().all(|m| m.timestamp == 3000));
+
+        backend
+            .delete_before_timestamp(i64::MAX, &Default::default())
+            .await
+            .unwrap();
+        assert!(backend.get_all_agent_ids().await.unwrap().is_empty());
+        assert!(backend
+            .get_latest_metrics("agent-1")
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
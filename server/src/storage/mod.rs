@@ -2,12 +2,19 @@
 //!
 //! 架构:
 //! - Cache (cache.rs): 内存缓存层，每个 Agent 保留最新 100 条数据
-//! - Persist (persist.rs): redb 持久化层，长期存储
+//! - Backend (backend.rs): 持久化后端抽象，默认为 redb 持久化层 (persist.rs)，测试另有内存实现 (memory.rs)
 //! - 本模块 (mod.rs): 异步批量写入队列，整合缓存和持久化
 
+pub mod backend;
 pub mod cache;
 pub mod cleanup;
+mod coalesce;
+mod codec;
+#[cfg(test)]
+pub mod memory;
 pub mod persist;
+pub mod retention;
+pub mod rollup;
 
 #[cfg(test)]
 mod integration_tests;
@@ -15,24 +22,156 @@ mod integration_tests;
 mod performance_tests;
 
 use anyhow::Result;
+pub use backend::PersistBackend;
+pub use coalesce::CoalesceConfig;
 use common::proto::MetricsRequest;
 use persist::PersistStorage;
-use std::collections::HashSet;
+pub use persist::{CompactReport, DEFAULT_DB_CACHE_BYTES};
+pub use retention::FieldRetention;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc;
-use tokio::sync::RwLock;
-use tracing::{debug, error, info, warn};
+use tokio::sync::{Mutex, Notify, RwLock};
+use tracing::{debug, error, info, instrument, warn, Span};
 
 /// 批量写入配置
 pub const BATCH_SIZE: usize = 50;
 pub const BATCH_TIMEOUT: Duration = Duration::from_secs(5);
 pub const CHANNEL_CAPACITY: usize = 1000;
+/// 每个 Agent 在内存中缓存的默认条数
+pub const DEFAULT_CACHE_SIZE_PER_AGENT: usize = 100;
+/// 写入队列占用超过该比例时视为积压
+pub const BACKLOG_THRESHOLD: f64 = 0.8;
+/// 关闭时等待批量写入任务排空队列的最长时间
+pub const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+/// 写入合并时视为“几乎相同”的默认最大差值（百分点）
+pub const DEFAULT_COALESCE_MAX_DELTA: f64 = 1.0;
+/// 以 `persist-authoritative` 查询历史时等待已入队样本落盘的最长时间
+pub const HISTORY_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 每个 Agent 保留的主机名变更记录上限
+pub const MAX_HOSTNAME_HISTORY: usize = 32;
+
+/// 主机名变更记录
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostnameChange {
+    pub hostname: String,
+    /// 首次出现该主机名的样本时间戳（毫秒）
+    pub since: i64,
+}
+
+/// 若主机名与最近一条记录不同则追加，返回是否发生变更
+///
+/// 超出 `MAX_HOSTNAME_HISTORY` 时丢弃最旧的记录
+pub(crate) fn record_hostname(
+    history: &mut Vec<HostnameChange>,
+    hostname: &str,
+    since: i64,
+) -> bool {
+    if history.last().is_some_and(|last| last.hostname == hostname) {
+        return false;
+    }
+    history.push(HostnameChange {
+        hostname: hostname.to_string(),
+        since,
+    });
+    if history.len() > MAX_HOSTNAME_HISTORY {
+        let excess = history.len() - MAX_HOSTNAME_HISTORY;
+        history.drain(..excess);
+    }
+    true
+}
+
+/// 每个 Agent 保留的时间线事件上限，超出后丢弃最旧的
+pub const MAX_AGENT_EVENTS: usize = 1000;
+
+/// Agent 时间线事件类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentEventKind {
+    /// 主机重启（boot_id 变化，或没有 boot_id 时运行时长回落）
+    Reboot,
+    /// 建立流式连接
+    Online,
+    /// 流式连接断开
+    Offline,
+    /// 主机名变更（由主机名变更历史生成，不单独存储）
+    HostnameChange,
+    /// 告警触发
+    AlertFired,
+    /// 告警恢复
+    AlertResolved,
+}
+
+/// Agent 时间线事件
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgentEvent {
+    #[serde(rename = "type")]
+    pub kind: AgentEventKind,
+    /// 事件发生时间（毫秒）
+    pub timestamp: i64,
+    /// 事件说明
+    pub details: String,
+}
+
+/// 历史查询的一致性模式
+///
+/// 持久化模式下，最近的样本可能只在缓存与写入队列中，尚未落盘：
+/// - `cache-preferred`：缓存够用时直接返回缓存，否则与持久化结果按时间戳合并。速度快，
+///   但并发写入时窗口可能不一致，例如迟到的回填样本挤掉更新的已落盘样本，或同一样本
+///   在落盘前后各被读到一次
+/// - `persist-authoritative`：先让已入队的样本立即落盘并等待完成，再只读取持久化层。
+///   结果与持久化数据完全一致，按时间戳升序（时间戳相同时按写入顺序），适合对账等工具
+///
+/// 仅内存模式下缓存是唯一数据源，两种模式结果相同
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HistoryConsistency {
+    /// 优先使用缓存
+    #[default]
+    CachePreferred,
+    /// 以持久化数据为准
+    PersistAuthoritative,
+}
+
+impl FromStr for HistoryConsistency {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "cache-preferred" => Ok(Self::CachePreferred),
+            "persist-authoritative" => Ok(Self::PersistAuthoritative),
+            other => Err(format!(
+                "未知的历史查询一致性模式: {}（可选 cache-preferred、persist-authoritative）",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HistoryConsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CachePreferred => f.write_str("cache-preferred"),
+            Self::PersistAuthoritative => f.write_str("persist-authoritative"),
+        }
+    }
+}
 
 /// 写入请求
 #[derive(Debug)]
 struct WriteRequest {
     metrics: MetricsRequest,
+    /// 入队时所在的 span（携带上游请求的 trace_id），落盘事件记录在该 span 下
+    span: Span,
 }
 
 /// Storage 配置
@@ -46,6 +185,9 @@ pub struct StorageConfig {
     pub batch_size: usize,
     /// 批量写入超时
     pub batch_timeout: Duration,
+    /// 批量写入超时的随机抖动上限：每次超时在 `[batch_timeout, batch_timeout + jitter]` 内随机取值，
+    /// 避免多个写入任务同时刷盘。为零时不抖动
+    pub batch_timeout_jitter: Duration,
     /// 写入通道容量
     pub channel_capacity: usize,
     /// 每个 Agent 保留的最大记录数
@@ -56,25 +198,67 @@ pub struct StorageConfig {
     pub cleanup_interval_hours: u64,
     /// 是否启用清理任务
     pub enable_cleanup: bool,
+    /// 不参与自动清理（数量与时间限制均不生效）的 agent_id，按完整 ID 精确匹配
+    pub cleanup_exempt_agents: HashSet<String>,
+    /// 是否拒绝打开对其他用户可访问的已有数据库文件（仅 Unix 生效，默认只告警）
+    pub strict_permissions: bool,
+    /// 持久化初始化失败时是否报错，而不是退化为仅内存模式（需配合 `try_with_config`）
+    pub require_persistence: bool,
+    /// 数据库分片数：大于 1 时按 agent_id 哈希拆分到 `<文件名>.shard-<i>.<扩展名>` 多个文件，
+    /// 各分片并行写入。已有数据库不能更改分片数
+    pub db_shards: usize,
+    /// redb 缓存大小（字节，读缓存与写缓冲合计，多个分片平分），None 时使用 redb 默认的 1 GiB。
+    /// 内存紧张的节点可调小，代价是查询更多地读盘；查询集中在大时间范围的节点可调大
+    pub db_cache_bytes: Option<usize>,
+    /// 落盘前的写入合并窗口：批次内同一 Agent 在该窗口内数值几乎不变的样本只保留最后一条。
+    /// 为零时不合并
+    pub coalesce_window: Duration,
+    /// 写入合并时视为“几乎相同”的最大差值（CPU、内存、磁盘使用率的百分点）
+    pub coalesce_max_delta: f64,
+    /// 按字段分级保留：超出保留期的样本由清理任务去掉对应字段组，其余字段照常保留。
+    /// 为空时不改写（豁免的 Agent 同样不受影响）
+    pub field_retention: FieldRetention,
+    /// 仅实时模式：只保留每个 Agent 最近 `cache_size_per_agent` 条样本供实时查看，不打开数据库、
+    /// 不启动写入队列与清理任务（忽略 `db_path`），超出缓存的历史查询标记为已截断
+    pub live_only: bool,
 }
 
 impl Default for StorageConfig {
     fn default() -> Self {
         Self {
             db_path: None, // 默认仅内存模式
-            cache_size_per_agent: 100,
+            cache_size_per_agent: DEFAULT_CACHE_SIZE_PER_AGENT,
             batch_size: BATCH_SIZE,
             batch_timeout: BATCH_TIMEOUT,
+            batch_timeout_jitter: Duration::ZERO,
             channel_capacity: CHANNEL_CAPACITY,
             // 保留约 7 天数据（1秒1次上报：7 × 86400 = 604,800 条）
             max_records_per_agent: 604_800,
             retention_days: 0,         // 禁用时间清理，仅按数量限制
             cleanup_interval_hours: 6, // 每 6 小时清理一次
             enable_cleanup: true,
+            cleanup_exempt_agents: HashSet::new(),
+            strict_permissions: false,
+            require_persistence: false,
+            db_shards: 1,
+            db_cache_bytes: None,
+            coalesce_window: Duration::ZERO,
+            coalesce_max_delta: DEFAULT_COALESCE_MAX_DELTA,
+            field_retention: FieldRetention::default(),
+            live_only: false,
         }
     }
 }
 
+/// Storage 关闭报告
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// 关闭过程中排空并落盘的样本数
+    pub flushed: u64,
+    /// 已入队但未能落盘的样本数
+    pub dropped: u64,
+}
+
 /// Storage - 异步批量写入存储
 ///
 /// 数据流:
@@ -88,14 +272,26 @@ pub struct Storage {
     cache: Arc<cache::Cache>,
     /// 写入通道 sender（仅持久化模式），包装在 Arc<RwLock> 中以支持 shutdown 时关闭
     write_tx: Option<Arc<RwLock<Option<mpsc::Sender<WriteRequest>>>>>,
-    /// 后台任务句柄
-    writer_handle: Option<Arc<tokio::task::JoinHandle<()>>>,
+    /// 后台任务句柄（shutdown 时取出并等待其退出）
+    writer_handle: Option<Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>>,
+    /// 已成功入队的样本数
+    enqueued: Arc<AtomicU64>,
+    /// 已成功落盘的样本数
+    persisted: Arc<AtomicU64>,
+    /// 通知批量写入任务立即落盘已入队的样本
+    flush_now: Arc<Notify>,
     /// 运行状态
     running: Arc<RwLock<bool>>,
     /// 是否启用持久化
     persist_enabled: bool,
+    /// 配置中是否要求持久化（用于区分“仅内存模式”和“持久化初始化失败”）
+    persist_requested: bool,
+    /// 是否为仅实时模式（见 `StorageConfig::live_only`）
+    live_only: bool,
+    /// 是否已进入关闭流程
+    shutting_down: Arc<AtomicBool>,
     /// 持久化存储引用（用于清理任务）
-    persist: Option<Arc<PersistStorage>>,
+    persist: Option<Arc<dyn PersistBackend>>,
     /// 清理任务句柄
     cleanup_handle: Option<Arc<tokio::task::JoinHandle<()>>>,
     /// 清理任务停止标志
@@ -109,99 +305,193 @@ impl Storage {
     }
 
     /// 使用自定义配置创建 Storage
+    ///
+    /// 持久化初始化失败时总是退化为仅内存模式并记录错误（即使设置了 `require_persistence`），
+    /// 需要把该错误作为启动失败处理时使用 [`Storage::try_with_config`]
     pub fn with_config(config: StorageConfig) -> Self {
+        let persist = Self::open_persist(&config).unwrap_or_else(|e| {
+            error!(error = %format!("{:#}", e), "Required persistence unavailable, fallback to memory-only mode");
+            None
+        });
+        Self::start(config, persist)
+    }
+
+    /// 使用自定义配置创建 Storage，`require_persistence` 时持久化初始化失败返回错误
+    pub fn try_with_config(config: StorageConfig) -> Result<Self> {
+        let persist = Self::open_persist(&config)?;
+        Ok(Self::start(config, persist))
+    }
+
+    /// 按配置打开持久化后端（默认 redb）
+    ///
+    /// 未配置或仅实时模式时返回 None；初始化失败时仅在 `require_persistence` 下返回错误，
+    /// 否则记录错误并返回 None
+    fn open_persist(config: &StorageConfig) -> Result<Option<Arc<dyn PersistBackend>>> {
+        let persist: Option<Arc<dyn PersistBackend>> = match &config.db_path {
+            Some(db_path) if config.live_only => {
+                info!(db_path = %db_path, "Live-only mode, ignoring db_path");
+                None
+            }
+            Some(db_path) => match PersistStorage::open_with_cache(
+                db_path,
+                config.db_shards,
+                config.strict_permissions,
+                config.db_cache_bytes,
+            ) {
+                Ok(persist) => {
+                    info!(db_path = %db_path, shards = config.db_shards, "Opened redb persistence");
+                    Some(Arc::new(persist))
+                }
+                Err(e) if config.require_persistence => {
+                    return Err(e.context(format!(
+                        "Failed to initialize required persistence (db_path={})",
+                        db_path
+                    )));
+                }
+                Err(e) => {
+                    error!(
+                        db_path = %db_path,
+                        error = %e,
+                        "Failed to initialize persistence, fallback to memory-only mode"
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+        Ok(persist)
+    }
+
+    /// 使用指定的持久化后端创建 Storage，配置中的 `db_path` 与 `db_shards` 被忽略
+    #[cfg(test)]
+    pub fn with_backend(config: StorageConfig, backend: Arc<dyn PersistBackend>) -> Self {
+        Self::start(config, Some(backend))
+    }
+
+    /// 创建 Storage，有持久化后端时启动后台批量写入与清理任务
+    fn start(config: StorageConfig, persist: Option<Arc<dyn PersistBackend>>) -> Self {
+        // 仅实时模式不使用持久化后端
+        let persist = persist.filter(|_| !config.live_only);
         let cache = Arc::new(cache::Cache::new(config.cache_size_per_agent));
         let running = Arc::new(RwLock::new(true));
+        let enqueued = Arc::new(AtomicU64::new(0));
+        let persisted = Arc::new(AtomicU64::new(0));
+        let flush_now = Arc::new(Notify::new());
+        let persist_requested =
+            !config.live_only && (config.db_path.is_some() || persist.is_some());
+
+        let (write_tx, writer_handle, cleanup_handle, cleanup_running) = match &persist {
+            Some(persist) => {
+                let (tx, rx) = mpsc::channel(config.channel_capacity);
+
+                // 启动后台批量写入任务
+                let running_clone = running.clone();
+                let persist_clone = persist.clone();
+                let persisted_clone = persisted.clone();
+                let flush_now_clone = flush_now.clone();
+                let coalesce = CoalesceConfig {
+                    window: config.coalesce_window,
+                    max_delta: config.coalesce_max_delta,
+                };
+                let batch_config = config.clone();
+                let handle = tokio::spawn(async move {
+                    Self::batch_writer_task(
+                        rx,
+                        persist_clone,
+                        batch_config.batch_size,
+                        batch_config.batch_timeout,
+                        batch_config.batch_timeout_jitter,
+                        coalesce,
+                        running_clone,
+                        persisted_clone,
+                        flush_now_clone,
+                    )
+                    .await;
+                });
+
+                // 启动清理任务（如果启用）
+                let (cleanup_handle, cleanup_running) = if config.enable_cleanup {
+                    let cleanup_task = cleanup::CleanupTask::new(config.clone(), persist.clone());
+                    let cleanup_running = cleanup_task.running_flag();
+                    let handle = tokio::spawn(async move {
+                        cleanup_task.run().await;
+                    });
+                    (Some(Arc::new(handle)), Some(cleanup_running))
+                } else {
+                    (None, None)
+                };
 
-        // 根据配置决定是否启用持久化
-        let (write_tx, writer_handle, persist_enabled, persist, cleanup_handle, cleanup_running) =
-            if let Some(db_path) = &config.db_path {
-                match PersistStorage::new(db_path) {
-                    Ok(persist) => {
-                        let persist = Arc::new(persist);
-                        let (tx, rx) = mpsc::channel(config.channel_capacity);
-
-                        // 启动后台批量写入任务
-                        let running_clone = running.clone();
-                        let persist_clone = persist.clone();
-                        let handle = tokio::spawn(async move {
-                            Self::batch_writer_task(
-                                rx,
-                                persist_clone,
-                                config.batch_size,
-                                config.batch_timeout,
-                                running_clone,
-                            )
-                            .await;
-                        });
-
-                        // 启动清理任务（如果启用）
-                        let (cleanup_handle, cleanup_running) = if config.enable_cleanup {
-                            let cleanup_task =
-                                cleanup::CleanupTask::new(config.clone(), persist.clone());
-                            let cleanup_running = cleanup_task.running_flag();
-                            let handle = tokio::spawn(async move {
-                                cleanup_task.run().await;
-                            });
-                            (Some(Arc::new(handle)), Some(cleanup_running))
-                        } else {
-                            (None, None)
-                        };
-
-                        info!(
-                            db_path = %db_path,
-                            cache_size = config.cache_size_per_agent,
-                            batch_size = config.batch_size,
-                            enable_cleanup = config.enable_cleanup,
-                            "Storage initialized with persistence"
-                        );
-
-                        (
-                            Some(Arc::new(RwLock::new(Some(tx)))),
-                            Some(Arc::new(handle)),
-                            true,
-                            Some(persist),
-                            cleanup_handle,
-                            cleanup_running,
-                        )
-                    }
-                    Err(e) => {
-                        error!(
-                            db_path = %db_path,
-                            error = %e,
-                            "Failed to initialize persistence, fallback to memory-only mode"
-                        );
-                        (None, None, false, None, None, None)
-                    }
-                }
-            } else {
                 info!(
                     cache_size = config.cache_size_per_agent,
-                    "Storage initialized in memory-only mode"
+                    batch_size = config.batch_size,
+                    enable_cleanup = config.enable_cleanup,
+                    "Storage initialized with persistence"
                 );
 
-                (None, None, false, None, None, None)
-            };
-
-        if !persist_enabled {
-            info!("Persistence is disabled");
-        }
+                (
+                    Some(Arc::new(RwLock::new(Some(tx)))),
+                    Some(Arc::new(Mutex::new(Some(handle)))),
+                    cleanup_handle,
+                    cleanup_running,
+                )
+            }
+            None => {
+                if config.live_only {
+                    info!(
+                        cache_size = config.cache_size_per_agent,
+                        "Storage initialized in live-only mode"
+                    );
+                } else if persist_requested {
+                    info!("Persistence is disabled");
+                } else {
+                    info!(
+                        cache_size = config.cache_size_per_agent,
+                        "Storage initialized in memory-only mode"
+                    );
+                }
+                (None, None, None, None)
+            }
+        };
 
         Self {
             cache,
             write_tx,
             writer_handle,
+            enqueued,
+            persisted,
+            flush_now,
             running,
-            persist_enabled,
+            persist_enabled: persist.is_some(),
+            persist_requested,
+            live_only: config.live_only,
+            shutting_down: Arc::new(AtomicBool::new(false)),
             persist,
             cleanup_handle,
             cleanup_running,
         }
     }
 
-    /// 是否已启用持久化
-    pub fn is_persist_enabled(&self) -> bool {
-        self.persist_enabled
+    /// 是否可以对外提供服务
+    ///
+    /// 以下情况视为未就绪：
+    /// - 配置了持久化但初始化失败
+    /// - 批量写入任务已退出
+    /// - 正在关闭
+    pub async fn is_ready(&self) -> bool {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return false;
+        }
+        if self.persist_requested && !self.persist_enabled {
+            return false;
+        }
+        match &self.writer_handle {
+            Some(handle_lock) => handle_lock
+                .lock()
+                .await
+                .as_ref()
+                .is_some_and(|handle| !handle.is_finished()),
+            None => true,
+        }
     }
 
     async fn enqueue_metrics(&self, metrics: &MetricsRequest) -> Result<()> {
@@ -220,24 +510,44 @@ impl Storage {
 
         tx.send(WriteRequest {
             metrics: metrics.clone(),
+            span: Span::current(),
         })
         .await
         .map_err(|e| anyhow::anyhow!("failed to send metrics to queue: {}", e))?;
+        self.enqueued.fetch_add(1, Ordering::Relaxed);
 
         Ok(())
     }
 
-    /// 保存指标数据（仅保证写入缓存，持久化为异步排队）
-    pub async fn save_metrics(&self, metrics: &MetricsRequest) {
-        self.cache.update(metrics.clone()).await;
+    /// 写入队列是否积压（仅持久化模式，占用超过 `BACKLOG_THRESHOLD`）
+    pub async fn is_backlogged(&self) -> bool {
+        let Some(tx_lock) = &self.write_tx else {
+            return false;
+        };
+        match tx_lock.read().await.as_ref() {
+            Some(tx) => {
+                let used = tx.max_capacity() - tx.capacity();
+                used as f64 >= tx.max_capacity() as f64 * BACKLOG_THRESHOLD
+            }
+            None => false,
+        }
+    }
 
+    /// 保存指标数据（持久化为异步排队，入队后写入缓存）
+    ///
+    /// 持久化模式下入队失败时返回 false，且不写入缓存：调用方据此让 Agent 原样重试，
+    /// 重试不会在缓存中留下重复样本
+    #[instrument(name = "save_metrics", skip_all, fields(timestamp = metrics.timestamp))]
+    pub async fn save_metrics(&self, metrics: &MetricsRequest) -> bool {
         if let Err(e) = self.enqueue_metrics(metrics).await {
             error!(
                 agent_id = %metrics.agent_id,
                 error = %e,
                 "Failed to enqueue metrics for persistence"
             );
+            return false;
         }
+        self.cache.update(metrics.clone()).await;
 
         debug!(
             agent_id = %metrics.agent_id,
@@ -246,6 +556,31 @@ impl Storage {
             "Metrics saved to cache{}",
             if self.persist_enabled { " and queued for persistence" } else { "" }
         );
+        true
+    }
+
+    /// 保存一条过期的回填样本：照常排队持久化，但不会成为该 Agent 的最新样本
+    ///
+    /// 缓存中已有更新的样本时按时间顺序插入缓存历史，否则只落盘（仅内存模式下即丢弃）。
+    /// 持久化模式下入队失败时返回 false，且不写入缓存
+    pub async fn save_backfill(&self, metrics: &MetricsRequest) -> bool {
+        if let Err(e) = self.enqueue_metrics(metrics).await {
+            error!(
+                agent_id = %metrics.agent_id,
+                error = %e,
+                "Failed to enqueue backfill metrics for persistence"
+            );
+            return false;
+        }
+        let cached = self.cache.insert_history(metrics.clone()).await;
+
+        debug!(
+            agent_id = %metrics.agent_id,
+            timestamp = metrics.timestamp,
+            cached = cached,
+            "Backfill metrics saved as history only"
+        );
+        true
     }
 
     /// 获取所有 Agent ID
@@ -291,7 +626,17 @@ impl Storage {
         }
     }
 
-    /// 获取指定 Agent 的历史指标
+    /// 从内存缓存中对指定 Agent 最近 `limit` 条样本逐条取值（按时间升序），不读取持久化数据
+    pub async fn map_cached_recent<T>(
+        &self,
+        agent_id: &str,
+        limit: usize,
+        f: impl FnMut(&MetricsRequest) -> Option<T>,
+    ) -> Vec<T> {
+        self.cache.map_recent(agent_id, limit, f).await
+    }
+
+    /// 获取指定 Agent 最近 `limit` 条历史指标（按时间戳升序），按 `cache-preferred` 合并缓存
     pub async fn get_agent_history(&self, agent_id: &str, limit: usize) -> Vec<MetricsRequest> {
         if limit == 0 {
             return Vec::new();
@@ -336,11 +681,297 @@ impl Storage {
         }
     }
 
+    /// 仅实时模式下，历史查询结果是否因缓存上限而不完整
+    ///
+    /// 返回的 `returned` 条少于请求的 `limit` 条，且该 Agent 更早的样本已被缓存淘汰时为 true。
+    /// 其他模式下总是 false
+    pub async fn is_history_truncated(
+        &self,
+        agent_id: &str,
+        limit: usize,
+        returned: usize,
+    ) -> bool {
+        returned < limit && self.has_evicted_history(agent_id).await
+    }
+
+    /// 仅实时模式下，该 Agent 是否有更早的样本已被缓存淘汰（之后的查询结果都不完整）
+    pub async fn has_evicted_history(&self, agent_id: &str) -> bool {
+        self.live_only && self.cache.evicted(agent_id).await > 0
+    }
+
+    /// 按指定一致性模式获取指定 Agent 最近 `limit` 条历史指标（按时间戳升序）
+    ///
+    /// `persist-authoritative` 下已入队的样本未能在 `HISTORY_FLUSH_TIMEOUT` 内落盘，
+    /// 或读取持久化层失败时返回错误，不退回缓存结果
+    pub async fn get_agent_history_with(
+        &self,
+        agent_id: &str,
+        limit: usize,
+        consistency: HistoryConsistency,
+    ) -> Result<Vec<MetricsRequest>> {
+        let persist = match (consistency, &self.persist) {
+            (HistoryConsistency::PersistAuthoritative, Some(persist)) if limit > 0 => persist,
+            _ => return Ok(self.get_agent_history(agent_id, limit).await),
+        };
+
+        if !self.flush_pending(HISTORY_FLUSH_TIMEOUT).await {
+            return Err(anyhow::anyhow!(
+                "queued metrics were not persisted within {:?}",
+                HISTORY_FLUSH_TIMEOUT
+            ));
+        }
+        persist.query_latest_by_agent(agent_id, limit).await
+    }
+
+    /// 让批量写入任务立即落盘此前已入队的样本，并等待完成
+    ///
+    /// 返回此前入队的样本是否已全部落盘（超过 `timeout` 时为 false）；仅内存模式直接返回 true
+    pub async fn flush_pending(&self, timeout: Duration) -> bool {
+        if !self.persist_enabled {
+            return true;
+        }
+        // 写入队列先进先出，落盘数追上此刻的入队数即说明此前的样本都已落盘
+        let target = self.enqueued.load(Ordering::SeqCst);
+        if self.persisted.load(Ordering::SeqCst) >= target {
+            return true;
+        }
+
+        self.flush_now.notify_one();
+        tokio::time::timeout(timeout, async {
+            while self.persisted.load(Ordering::SeqCst) < target {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    /// 获取指定 Agent 在 `[start_ts, end_ts]` 内的历史指标（按时间戳升序）
+    ///
+    /// 持久化模式下合并尚未落盘的缓存数据
+    pub async fn get_agent_range(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> Vec<MetricsRequest> {
+        let mut cached = self.cache.get_history(agent_id, usize::MAX).await;
+        cached.retain(|m| m.timestamp >= start_ts && m.timestamp <= end_ts);
+        let Some(persist) = &self.persist else {
+            return cached;
+        };
+
+        match persist
+            .query_range_by_agent(agent_id, start_ts, end_ts)
+            .await
+        {
+            Ok(mut persisted) => {
+                persisted.extend(cached);
+                persisted.sort_by_key(|m| m.timestamp);
+                persisted.dedup_by(|a, b| a.timestamp == b.timestamp && a == b);
+                persisted
+            }
+            Err(e) => {
+                error!(agent_id = %agent_id, error = %e, "Failed to load range from persistence");
+                cached
+            }
+        }
+    }
+
+    /// 获取指定 Agent 小时起点在 `[start_ts, end_ts]` 内的长期小时汇总（按时间升序）
+    ///
+    /// 小时汇总由清理任务生成并只存在于持久化层，仅内存模式下为空
+    pub async fn get_hourly_range(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> Vec<(i64, rollup::HourlyRollup)> {
+        let Some(persist) = &self.persist else {
+            return Vec::new();
+        };
+
+        match persist.query_hourly_range(agent_id, start_ts, end_ts).await {
+            Ok(rollups) => rollups,
+            Err(e) => {
+                error!(agent_id = %agent_id, error = %e, "Failed to load hourly rollups from persistence");
+                Vec::new()
+            }
+        }
+    }
+
+    /// 按时间戳升序流式读取指定 Agent 的全部历史指标，见 [`Storage::stream_agent_range`]
+    pub fn stream_agent_history(&self, agent_id: &str) -> mpsc::Receiver<MetricsRequest> {
+        self.stream_agent_range(agent_id, i64::MIN, i64::MAX)
+    }
+
+    /// 按时间戳升序流式读取指定 Agent 在 `[start_ts, end_ts]` 内的历史指标
+    ///
+    /// 先输出持久化记录，再补上缓存中尚未落盘的更新样本；接收端被丢弃后读取随即停止。
+    /// 同时在途的记录数有上限，消费方逐条折叠时内存占用与范围大小无关
+    pub fn stream_agent_range(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> mpsc::Receiver<MetricsRequest> {
+        let (tx, rx) = mpsc::channel(1);
+        let cache = self.cache.clone();
+        let persisted = self
+            .persist
+            .as_ref()
+            .map(|p| p.stream_range_by_agent(agent_id, start_ts, end_ts));
+        let agent_id = agent_id.to_string();
+
+        tokio::spawn(async move {
+            let mut last_ts = i64::MIN;
+            if let Some(mut persisted) = persisted {
+                while let Some(item) = persisted.recv().await {
+                    match item {
+                        Ok(metrics) => {
+                            last_ts = metrics.timestamp;
+                            if tx.send(metrics).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) => {
+                            error!(agent_id = %agent_id, error = %e, "Failed to stream history from persistence");
+                            return;
+                        }
+                    }
+                }
+            }
+
+            for metrics in cache.get_history(&agent_id, usize::MAX).await {
+                let in_range = metrics.timestamp >= start_ts && metrics.timestamp <= end_ts;
+                if in_range && metrics.timestamp > last_ts && tx.send(metrics).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        rx
+    }
+
+    /// 获取所有 Agent 中最新的 limit 条指标（按时间戳降序）
+    ///
+    /// 持久化模式下合并尚未落盘的缓存数据；总样本数不足 limit 时返回全部
+    pub async fn get_recent_across_agents(&self, limit: usize) -> Vec<MetricsRequest> {
+        if limit == 0 {
+            return Vec::new();
+        }
+
+        let cache_recent = self.cache.get_recent_across_agents(limit).await;
+        let Some(persist) = &self.persist else {
+            return cache_recent;
+        };
+
+        match persist.query_recent_across_agents(limit).await {
+            Ok(mut persisted) => {
+                persisted.extend(cache_recent);
+                cache::sort_newest_first(&mut persisted);
+                persisted.dedup();
+                persisted.truncate(limit);
+                persisted
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to load recent metrics from persistence");
+                cache_recent
+            }
+        }
+    }
+
+    /// 压缩持久化数据库文件，仅内存模式返回 None
+    pub async fn compact(&self) -> Result<Option<CompactReport>> {
+        match &self.persist {
+            Some(persist) => persist.compact().await,
+            None => Ok(None),
+        }
+    }
+
+    /// 各 Agent 内存缓存因超出 `cache_size_per_agent` 被淘汰的累计条数
+    pub async fn cache_evictions(&self) -> BTreeMap<String, u64> {
+        self.cache.evictions().await.into_iter().collect()
+    }
+
+    /// 获取指定 Agent 的主机名变更历史（按时间升序）
+    ///
+    /// 持久化记录在前，再补上内存中尚未落盘的变更
+    pub async fn get_hostname_history(&self, agent_id: &str) -> Vec<HostnameChange> {
+        let recent = self.cache.get_hostname_history(agent_id).await;
+
+        let Some(persist) = &self.persist else {
+            return recent;
+        };
+
+        let mut history = match persist.get_hostname_history(agent_id).await {
+            Ok(history) => history,
+            Err(e) => {
+                error!(agent_id = %agent_id, error = %e, "Failed to load hostname history from persistence");
+                return recent;
+            }
+        };
+
+        let last_since = history.last().map(|c| c.since).unwrap_or(i64::MIN);
+        for change in recent.into_iter().filter(|c| c.since > last_since) {
+            record_hostname(&mut history, &change.hostname, change.since);
+        }
+        history
+    }
+
+    /// 追加一条 Agent 时间线事件
+    ///
+    /// 事件很少，持久化模式下直接落盘，不经批量写入队列；仅内存模式下保存在缓存中
+    pub async fn record_event(&self, agent_id: &str, event: AgentEvent) {
+        match &self.persist {
+            Some(persist) => {
+                if let Err(e) = persist.append_event(agent_id, &event).await {
+                    error!(agent_id = %agent_id, error = %e, "Failed to persist agent event");
+                }
+            }
+            None => self.cache.record_event(agent_id, event).await,
+        }
+    }
+
+    /// 获取指定 Agent 时间在 `[start_ts, end_ts]` 内的时间线事件（按时间升序）
+    ///
+    /// 主机名变更由主机名变更历史生成，首次出现的主机名不计为变更
+    pub async fn get_events(&self, agent_id: &str, start_ts: i64, end_ts: i64) -> Vec<AgentEvent> {
+        let mut events = match &self.persist {
+            Some(persist) => match persist.query_events(agent_id, start_ts, end_ts).await {
+                Ok(events) => events,
+                Err(e) => {
+                    error!(agent_id = %agent_id, error = %e, "Failed to load agent events from persistence");
+                    Vec::new()
+                }
+            },
+            None => self.cache.get_events(agent_id, start_ts, end_ts).await,
+        };
+
+        let hostnames = self.get_hostname_history(agent_id).await;
+        events.extend(
+            hostnames
+                .windows(2)
+                .filter(|pair| (start_ts..=end_ts).contains(&pair[1].since))
+                .map(|pair| AgentEvent {
+                    kind: AgentEventKind::HostnameChange,
+                    timestamp: pair[1].since,
+                    details: format!("{} → {}", pair[0].hostname, pair[1].hostname),
+                }),
+        );
+        events.sort_by_key(|event| event.timestamp);
+        events
+    }
+
     /// 优雅关闭
     ///
-    /// 等待队列中的数据全部写入完成
-    pub async fn shutdown(&self) -> Result<()> {
+    /// 先关闭写入通道拒绝新数据，再等待批量写入任务把队列中剩余数据全部落盘后返回。
+    /// 等待时间受 `SHUTDOWN_FLUSH_TIMEOUT` 限制，超时未落盘的样本计入报告中的 `dropped`
+    pub async fn shutdown(&self) -> Result<ShutdownReport> {
         info!("Storage shutdown initiated");
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        let mut report = ShutdownReport::default();
 
         // 如果启用了持久化，关闭写入通道并等待任务完成
         if self.persist_enabled {
@@ -380,46 +1011,61 @@ impl Storage {
                 info!("Write channel closed");
             }
 
+            // 此后不会再有新数据入队，入队总数已固定
+            let persisted_before = self.persisted.load(Ordering::SeqCst);
+
             // 标记为不再运行（让批量写入任务作为备用退出机制）
             *self.running.write().await = false;
 
-            // 等待批量写入任务完成（最多 10 秒）
-            if let Some(writer_handle) = &self.writer_handle {
-                let writer_abort = writer_handle.abort_handle();
-                match tokio::time::timeout(Duration::from_secs(10), async {
-                    // 轮询检查任务是否完成
-                    loop {
-                        if writer_handle.is_finished() {
-                            break;
-                        }
-                        tokio::time::sleep(Duration::from_millis(100)).await;
-                    }
-                })
-                .await
-                {
-                    Ok(_) => info!("Batch writer task stopped successfully"),
+            // 等待批量写入任务排空队列并退出
+            let handle = match &self.writer_handle {
+                Some(handle_lock) => handle_lock.lock().await.take(),
+                None => None,
+            };
+            if let Some(mut handle) = handle {
+                match tokio::time::timeout(SHUTDOWN_FLUSH_TIMEOUT, &mut handle).await {
+                    Ok(Ok(())) => info!("Batch writer task stopped successfully"),
+                    Ok(Err(e)) => error!("Batch writer task failed: {}", e),
                     Err(_) => {
                         warn!("Batch writer task timeout, aborting...");
-                        writer_abort.abort();
+                        handle.abort();
                     }
                 }
             }
+
+            let enqueued = self.enqueued.load(Ordering::SeqCst);
+            let persisted = self.persisted.load(Ordering::SeqCst);
+            report.flushed = persisted.saturating_sub(persisted_before);
+            report.dropped = enqueued.saturating_sub(persisted);
         }
 
-        info!("Storage shutdown complete");
-        Ok(())
+        info!(
+            flushed = report.flushed,
+            dropped = report.dropped,
+            "Storage shutdown complete"
+        );
+        Ok(report)
     }
 
     /// 后台批量写入任务
+    #[allow(clippy::too_many_arguments)]
     async fn batch_writer_task(
         mut rx: mpsc::Receiver<WriteRequest>,
-        persist: Arc<PersistStorage>,
+        persist: Arc<dyn PersistBackend>,
         batch_size: usize,
         timeout: Duration,
+        jitter: Duration,
+        coalesce: CoalesceConfig,
         running: Arc<RwLock<bool>>,
+        persisted: Arc<AtomicU64>,
+        flush_now: Arc<Notify>,
     ) {
         let mut buffer = Vec::with_capacity(batch_size);
-        let mut interval = tokio::time::interval(timeout);
+        // 与 buffer 一一对应的入队 span
+        let mut spans = Vec::with_capacity(batch_size);
+        // 每次超时触发后重新随机下一次的间隔，保证任意数据最迟在 timeout + jitter 内落盘
+        let flush_timer = tokio::time::sleep(jittered_timeout(timeout, jitter));
+        tokio::pin!(flush_timer);
 
         info!("Batch writer task started");
 
@@ -430,10 +1076,11 @@ impl Storage {
                     match result {
                         Some(req) => {
                             buffer.push(req.metrics);
+                            spans.push(req.span);
 
                             // 达到批量大小，立即写入
                             if buffer.len() >= batch_size {
-                                Self::flush_buffer(&persist, &mut buffer, "batch size reached").await;
+                                Self::flush_buffer(&persist, &mut buffer, &mut spans, &persisted, coalesce, "batch size reached").await;
                             }
                         }
                         None => {
@@ -443,10 +1090,24 @@ impl Storage {
                         }
                     }
                 }
+                // 查询要求立即落盘：把通道中已入队的样本一并取出写入
+                _ = flush_now.notified() => {
+                    while let Ok(req) = rx.try_recv() {
+                        buffer.push(req.metrics);
+                        spans.push(req.span);
+                    }
+                    if !buffer.is_empty() {
+                        Self::flush_buffer(&persist, &mut buffer, &mut spans, &persisted, coalesce, "flush requested").await;
+                    }
+                }
                 // 超时触发
-                _ = interval.tick() => {
+                _ = &mut flush_timer => {
+                    flush_timer
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + jittered_timeout(timeout, jitter));
+
                     if !buffer.is_empty() {
-                        Self::flush_buffer(&persist, &mut buffer, "timeout").await;
+                        Self::flush_buffer(&persist, &mut buffer, &mut spans, &persisted, coalesce, "timeout").await;
                     }
 
                     // 检查是否应该继续运行（备用退出机制）
@@ -458,30 +1119,72 @@ impl Storage {
             }
         }
 
+        // 备用退出路径下通道里可能仍有数据：此时发送端已关闭，把剩余数据全部取出
+        rx.close();
+        while let Some(req) = rx.recv().await {
+            buffer.push(req.metrics);
+            spans.push(req.span);
+        }
+
         // 刷新剩余数据
         if !buffer.is_empty() {
             info!(
                 "Flushing remaining {} metrics before shutdown",
                 buffer.len()
             );
-            let _ = Self::flush_buffer(&persist, &mut buffer, "shutdown").await;
+            if !Self::flush_buffer(
+                &persist,
+                &mut buffer,
+                &mut spans,
+                &persisted,
+                coalesce,
+                "shutdown",
+            )
+            .await
+            {
+                error!("Dropping {} metrics that failed to flush", buffer.len());
+            }
         }
 
         info!("Batch writer task stopped");
     }
 
+    #[instrument(name = "flush", skip_all, fields(reason = reason, count = buffer.len()))]
     async fn flush_buffer(
-        persist: &Arc<PersistStorage>,
+        persist: &Arc<dyn PersistBackend>,
         buffer: &mut Vec<MetricsRequest>,
+        spans: &mut Vec<Span>,
+        persisted: &AtomicU64,
+        coalesce: CoalesceConfig,
         reason: &str,
     ) -> bool {
         if buffer.is_empty() {
             return true;
         }
 
-        match persist.flush_batch(buffer).await {
+        // 合并后的样本只落盘保留的行，但整批都视为已处理
+        let rows: Cow<[MetricsRequest]> = if coalesce.is_enabled() {
+            let kept = coalesce::coalesce_batch(buffer, coalesce);
+            Cow::Owned(kept.into_iter().map(|i| buffer[i].clone()).collect())
+        } else {
+            Cow::Borrowed(buffer.as_slice())
+        };
+
+        match persist.flush_batch(&rows).await {
             Ok(_) => {
-                debug!("Flushed {} metrics ({})", buffer.len(), reason);
+                debug!(
+                    "Flushed {} metrics as {} rows ({})",
+                    buffer.len(),
+                    rows.len(),
+                    reason
+                );
+                // 在各样本入队时的 span 下记录落盘，日志中可按 trace_id 找到
+                for (metrics, span) in buffer.iter().zip(spans.drain(..)) {
+                    span.in_scope(
+                        || debug!(agent_id = %metrics.agent_id, "Metrics flushed to persistence"),
+                    );
+                }
+                persisted.fetch_add(buffer.len() as u64, Ordering::SeqCst);
                 buffer.clear();
                 true
             }
@@ -493,6 +1196,17 @@ impl Storage {
     }
 }
 
+/// 在 `[timeout, timeout + jitter]` 内随机取一个超时
+fn jittered_timeout(timeout: Duration, jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return timeout;
+    }
+    // 每个 RandomState 使用随机种子，足以打散刷盘时刻，无需引入随机数依赖
+    let random = RandomState::new().hash_one(0u8);
+    let jitter_nanos = jitter.as_nanos().min(u64::MAX as u128) as u64;
+    timeout + Duration::from_nanos(random % jitter_nanos.saturating_add(1))
+}
+
 impl Default for Storage {
     fn default() -> Self {
         Self::new()
@@ -519,4 +1233,110 @@ mod tests {
         };
         assert_eq!(config.db_path, Some("test.db".to_string()));
     }
+
+    #[tokio::test]
+    async fn test_require_persistence_fails_on_unopenable_db() {
+        // 以目录作为数据库路径，redb 无法打开
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig {
+            db_path: Some(temp_dir.path().to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        // 默认仍退化为仅内存模式
+        let storage = Storage::try_with_config(config.clone()).unwrap();
+        assert!(!storage.persist_enabled);
+
+        let result = Storage::try_with_config(StorageConfig {
+            require_persistence: true,
+            ..config.clone()
+        });
+        assert!(result.is_err());
+
+        // with_config 不 panic，记录错误后退化为仅内存模式，且不报告就绪
+        let storage = Storage::with_config(StorageConfig {
+            require_persistence: true,
+            ..config
+        });
+        assert!(!storage.persist_enabled);
+        assert!(!storage.is_ready().await);
+    }
+
+    #[test]
+    fn test_history_consistency_parse() {
+        for mode in [
+            HistoryConsistency::CachePreferred,
+            HistoryConsistency::PersistAuthoritative,
+        ] {
+            assert_eq!(mode.to_string().parse::<HistoryConsistency>(), Ok(mode));
+        }
+        assert!("strict".parse::<HistoryConsistency>().is_err());
+    }
+
+    #[test]
+    fn test_jittered_timeout_bounds() {
+        let timeout = Duration::from_millis(100);
+        assert_eq!(jittered_timeout(timeout, Duration::ZERO), timeout);
+
+        let jitter = Duration::from_millis(50);
+        for _ in 0..1000 {
+            let value = jittered_timeout(timeout, jitter);
+            assert!(value >= timeout && value <= timeout + jitter, "{:?}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_jittered_flush_within_bound() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let persist = Arc::new(PersistStorage::open(db_path.to_str().unwrap(), false).unwrap());
+        let persisted = Arc::new(AtomicU64::new(0));
+        let timeout = Duration::from_millis(50);
+        let jitter = Duration::from_millis(50);
+
+        let (tx, rx) = mpsc::channel(16);
+        let writer = tokio::spawn(Storage::batch_writer_task(
+            rx,
+            persist,
+            100,
+            timeout,
+            jitter,
+            CoalesceConfig {
+                window: Duration::ZERO,
+                max_delta: DEFAULT_COALESCE_MAX_DELTA,
+            },
+            Arc::new(RwLock::new(true)),
+            persisted.clone(),
+            Arc::new(Notify::new()),
+        ));
+
+        // 批量大小远未达到，每条数据只能靠超时落盘
+        for round in 1..=5u64 {
+            let sent = std::time::Instant::now();
+            tx.send(WriteRequest {
+                span: Span::none(),
+                metrics: MetricsRequest {
+                    agent_id: "agent-1".to_string(),
+                    timestamp: round as i64,
+                    ..Default::default()
+                },
+            })
+            .await
+            .unwrap();
+            while persisted.load(Ordering::SeqCst) < round {
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+            // 留出调度与写盘的余量
+            let elapsed = sent.elapsed();
+            assert!(
+                elapsed <= timeout + jitter + Duration::from_millis(100),
+                "round {} flushed after {:?}",
+                round,
+                elapsed
+            );
+        }
+
+        drop(tx);
+        writer.await.unwrap();
+    }
 }
@@ -11,6 +11,8 @@ fn create_test_metrics(agent_id: &str, timestamp: i64) -> MetricsRequest {
         agent_id: agent_id.to_string(),
         timestamp,
         hostname: "test-host".to_string(),
+        sequence: 0,
+        labels: Default::default(),
         system: Some(SystemMetrics {
             cpu: Some(CpuMetrics {
                 usage_percent: 50.0,
@@ -19,6 +21,8 @@ fn create_test_metrics(agent_id: &str, timestamp: i64) -> MetricsRequest {
                 load_avg_1: 1.0,
                 load_avg_5: 0.8,
                 load_avg_15: 0.5,
+                per_core_frequency: vec![],
+                per_core_temperature: vec![],
             }),
             memory: Some(MemoryMetrics {
                 total: 16_000_000_000,
@@ -40,6 +44,16 @@ fn create_test_metrics(agent_id: &str, timestamp: i64) -> MetricsRequest {
             system_info: None,
             agent_metrics: None,
             tcp_ping: vec![],
+            collector_status: vec![],
+            pressure: None,
+            gpu: vec![],
+            zombie_count: 0,
+            file_descriptors: None,
+            top_processes: vec![],
+            entropy: None,
+            systemd: None,
+            numa_nodes: vec![],
+            containers: None,
         }),
     }
 }
@@ -1,19 +1,31 @@
 //! redb 持久化层
 //!
-//! 使用 redb 数据库进行长期存储
-
+//! 使用 redb 数据库进行长期存储，是 [`PersistBackend`] 的默认实现
+
+use super::backend::PersistBackend;
+use super::cache::sort_newest_first;
+use super::codec::{decode_metrics, encode_metrics};
+use super::retention::StripPlan;
+use super::rollup::HourlyRollup;
+use super::{record_hostname, AgentEvent, HostnameChange, MAX_AGENT_EVENTS};
 use anyhow::Result;
 use common::proto::MetricsRequest;
 use redb::{Database, ReadableTable, TableDefinition};
-use std::path::Path;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tracing::{debug, info};
+use serde::Serialize;
+use std::collections::{BinaryHeap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, TryLockError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// redb 默认的缓存大小（1 GiB，读缓存与写缓冲合计）
+pub const DEFAULT_DB_CACHE_BYTES: usize = 1024 * 1024 * 1024;
 
 /// 表定义: metrics
 /// Key: "agent_id\0timestamp" (字符串，使用 \0 分隔)
-/// Value: 序列化后的 MetricsRequest
+/// Value: 编码后的 MetricsRequest（见 codec.rs）
 const METRICS_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("metrics");
 
 /// 表定义: agent_latest
@@ -21,14 +33,132 @@ const METRICS_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("metric
 /// Value: 最新时间戳 (i64 序列化)
 const AGENT_LATEST_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("agent_latest");
 
+/// 表定义: hostname_history
+/// Key: agent_id
+/// Value: 序列化后的 Vec<HostnameChange>（按时间升序）
+const HOSTNAME_HISTORY_TABLE: TableDefinition<&str, &[u8]> =
+    TableDefinition::new("hostname_history");
+
+/// 表定义: hourly_rollup
+/// Key: "agent_id\0hour_start"（小时起点固定 20 位，用于排序）
+/// Value: 序列化后的 HourlyRollup；不受保留策略清理
+const HOURLY_ROLLUP_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("hourly_rollup");
+
+/// 表定义: agent_events
+/// Key: "agent_id\0timestamp"（时间戳固定 20 位，用于排序）
+/// Value: 序列化后的 Vec<AgentEvent>（同一毫秒内的事件按写入顺序）；不受保留策略清理，
+/// 每个 Agent 最多保留 `MAX_AGENT_EVENTS` 个时间戳
+const AGENT_EVENTS_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("agent_events");
+
+/// 表定义: meta
+/// Key: 标记名
+/// Value: 标记值
+const META_TABLE: TableDefinition<&str, u64> = TableDefinition::new("meta");
+
+/// meta 标记：旧格式 key 已迁移为新格式
+const LEGACY_KEYS_MIGRATED: &str = "legacy_keys_migrated";
+
+/// meta 标记：创建该文件时的分片数，分片数变化后 agent 的路由会改变，因此拒绝打开
+const SHARD_COUNT: &str = "shard_count";
+
+/// 新建数据目录的权限：仅属主可访问
+#[cfg(unix)]
+const DB_DIR_MODE: u32 = 0o700;
+
+/// 新建数据库文件的权限：仅属主可读写
+#[cfg(unix)]
+const DB_FILE_MODE: u32 = 0o600;
+
+/// 流式导出时游标与消费者之间最多缓冲的记录数
+const EXPORT_CHANNEL_CAPACITY: usize = 256;
+
+/// 压缩等待独占数据库的最长时间，超时后放弃本次压缩
+const COMPACT_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 读扫描每读取这么多条记录检查一次取消标记
+const SCAN_CHECK_INTERVAL: usize = 1024;
+
 /// 进程内递增序号，用于避免同毫秒 key 冲突
 static KEY_SEQUENCE: AtomicU64 = AtomicU64::new(0);
 
+/// 阻塞读扫描的取消标记
+///
+/// 在等待扫描结果的 async 函数中持有，该 future 被丢弃（如 HTTP 请求超时）时置位；阻塞线程中的
+/// 扫描每读取 [`SCAN_CHECK_INTERVAL`] 条记录检查一次，置位后提前返回并释放读事务
+struct ScanCancel(Arc<AtomicBool>);
+
+impl ScanCancel {
+    fn new() -> Self {
+        Self(Arc::new(AtomicBool::new(false)))
+    }
+
+    fn flag(&self) -> Arc<AtomicBool> {
+        self.0.clone()
+    }
+}
+
+impl Drop for ScanCancel {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// 已读取 `scanned` 条记录时检查取消标记，按批检查以免每条记录都读原子变量
+fn check_cancelled(cancelled: &AtomicBool, scanned: usize) -> Result<()> {
+    if scanned.is_multiple_of(SCAN_CHECK_INTERVAL) && cancelled.load(Ordering::Relaxed) {
+        anyhow::bail!("scan cancelled after {} records", scanned);
+    }
+    Ok(())
+}
+
+/// 样本内容的稳定摘要（FNV-1a 64），跨进程一致，用于生成确定的去重 key
+///
+/// labels 按键排序后参与计算，与 HashMap 的迭代顺序无关
+fn content_digest(metrics: &MetricsRequest) -> u64 {
+    let mut unlabeled = metrics.clone();
+    let labels: std::collections::BTreeMap<_, _> =
+        std::mem::take(&mut unlabeled.labels).into_iter().collect();
+    let mut bytes = encode_metrics(&unlabeled);
+    for (key, value) in labels {
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.push(0);
+    }
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// 数据库压缩结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CompactReport {
+    /// 压缩前文件大小（字节）
+    pub before_bytes: u64,
+    /// 压缩后文件大小（字节）
+    pub after_bytes: u64,
+    /// 耗时（毫秒，含等待独占的时间）
+    pub elapsed_ms: u64,
+}
+
+/// 单个 redb 数据库文件
+struct Shard {
+    /// redb 数据库：普通事务持有读锁，压缩时持有写锁独占
+    db: Arc<RwLock<Database>>,
+    /// 数据库文件路径
+    path: PathBuf,
+}
+
 /// 持久化存储
+///
+/// 默认使用单个数据库文件；分片模式下按 agent_id 的哈希把每个 Agent 固定路由到
+/// N 个文件之一，各文件的写事务互不阻塞
 #[derive(Clone)]
 pub struct PersistStorage {
-    /// redb 数据库
-    db: Arc<Database>,
+    /// 数据库分片，单文件模式下只有一个
+    shards: Arc<[Shard]>,
+    /// 清理操作累计读取的 key 数（用于观察清理开销）
+    keys_scanned: Arc<AtomicU64>,
 }
 
 impl PersistStorage {
@@ -37,29 +167,196 @@ impl PersistStorage {
     /// # Errors
     ///
     /// 如果数据库创建/打开失败，返回错误
+    #[cfg(test)]
     pub fn new(db_path: &str) -> Result<Self> {
+        Self::open(db_path, false)
+    }
+
+    /// 创建新的持久化存储，可选择拒绝打开权限过宽的已有数据库文件
+    ///
+    /// Unix 下新建的目录为 0700、数据库文件为 0600；已有数据库文件对其他用户
+    /// 可读/写时默认只记录告警，`strict_permissions` 为 true 时拒绝打开
+    ///
+    /// # Errors
+    ///
+    /// 如果数据库创建/打开失败，或权限检查未通过，返回错误
+    #[cfg(test)]
+    pub fn open(db_path: &str, strict_permissions: bool) -> Result<Self> {
+        Self::open_sharded(db_path, 1, strict_permissions)
+    }
+
+    /// 以分片模式创建持久化存储：`shards` 个数据库文件，按 agent_id 路由
+    ///
+    /// `shards` 为 1 时即单文件模式，直接使用 `db_path`；大于 1 时使用同目录下的
+    /// `<文件名>.shard-<i>.<扩展名>`。分片数在创建后不可更改
+    ///
+    /// # Errors
+    ///
+    /// 除 [`PersistStorage::open`] 的错误外，已有数据以不同的分片数创建时返回错误
+    #[cfg(test)]
+    pub fn open_sharded(db_path: &str, shards: usize, strict_permissions: bool) -> Result<Self> {
+        Self::open_with_cache(db_path, shards, strict_permissions, None)
+    }
+
+    /// 以指定的 redb 缓存大小创建持久化存储，`cache_bytes` 为 None 时使用 redb 默认值
+    /// （[`DEFAULT_DB_CACHE_BYTES`]）
+    ///
+    /// 缓存总量在各分片间平分；redb 把其中 90% 用作读缓存、10% 用作写缓冲。缓存越大，
+    /// 重复查询同一时间范围与大批量写入越少落到磁盘，代价是常驻内存随之增长（按需增长到上限）
+    ///
+    /// # Errors
+    ///
+    /// 数据库创建/打开失败、`strict_permissions` 下权限检查未通过，或已有数据以不同的分片数创建时返回错误
+    pub fn open_with_cache(
+        db_path: &str,
+        shards: usize,
+        strict_permissions: bool,
+        cache_bytes: Option<usize>,
+    ) -> Result<Self> {
         let path = Path::new(db_path);
+        let shards = shards.max(1);
+
+        // 切换模式会让已有数据不可见，直接拒绝
+        if shards == 1 && shard_path(path, 0).exists() {
+            anyhow::bail!(
+                "found sharded database files next to {}; open it with the original shard count",
+                db_path
+            );
+        }
+        if shards > 1 && path.exists() {
+            anyhow::bail!(
+                "database {} was created in single-file mode and cannot be opened with {} shards",
+                db_path,
+                shards
+            );
+        }
 
+        let paths: Vec<PathBuf> = if shards == 1 {
+            vec![path.to_path_buf()]
+        } else {
+            (0..shards).map(|i| shard_path(path, i)).collect()
+        };
+        let mut builder = redb::Builder::new();
+        if let Some(cache_bytes) = cache_bytes {
+            builder.set_cache_size(cache_bytes / shards);
+        }
+        let shards = paths
+            .iter()
+            .map(|path| Self::open_shard(&builder, path, shards, strict_permissions))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            shards: shards.into(),
+            keys_scanned: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// 创建或打开单个数据库文件
+    fn open_shard(
+        builder: &redb::Builder,
+        path: &Path,
+        shard_count: usize,
+        strict_permissions: bool,
+    ) -> Result<Shard> {
         // 如果父目录不存在，创建它
         if let Some(parent) = path.parent() {
             if !parent.exists() {
-                std::fs::create_dir_all(parent)?;
+                create_private_dir(parent)?;
             }
         }
 
         // 尝试创建或打开数据库
         let db = if path.exists() {
-            info!("Opening existing redb database at {}", db_path);
-            Database::open(path)?
+            check_permissions(path, strict_permissions)?;
+            info!("Opening existing redb database at {}", path.display());
+            builder.open(path)?
         } else {
-            info!("Creating new redb database at {}", db_path);
-            Database::create(path)?
+            info!("Creating new redb database at {}", path.display());
+            create_private_file(path)?;
+            builder.create(path)?
         };
 
         // 初始化表结构
         Self::init_tables(&db)?;
+        Self::migrate_legacy_keys(&db)?;
+        Self::check_shard_count(&db, path, shard_count)?;
+
+        Ok(Shard {
+            db: Arc::new(RwLock::new(db)),
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// agent_id 所在分片的下标
+    fn shard_index(&self, agent_id: &str) -> usize {
+        shard_index(agent_id, self.shards.len())
+    }
+
+    /// agent_id 所在的分片
+    fn shard(&self, agent_id: &str) -> &Shard {
+        &self.shards[self.shard_index(agent_id)]
+    }
+
+    /// 压缩数据库文件，把删除记录后的空闲页归还给操作系统
+    ///
+    /// 压缩需要独占数据库：这里轮询写锁而不是阻塞等待，等待期间普通读写不受影响；
+    /// 长时间拿不到锁（如有慢速导出）时放弃并返回错误。压缩期间新的读写会等待其完成
+    pub async fn compact(&self) -> Result<CompactReport> {
+        let start = Instant::now();
+        let mut report = CompactReport {
+            before_bytes: 0,
+            after_bytes: 0,
+            elapsed_ms: 0,
+        };
+        // 逐个分片压缩，同一时刻只独占一个文件
+        for shard in self.shards.iter() {
+            let shard_report = Self::compact_shard(shard.db.clone(), shard.path.clone()).await?;
+            report.before_bytes += shard_report.before_bytes;
+            report.after_bytes += shard_report.after_bytes;
+        }
+        report.elapsed_ms = start.elapsed().as_millis() as u64;
+
+        info!(
+            before_bytes = report.before_bytes,
+            after_bytes = report.after_bytes,
+            elapsed_ms = report.elapsed_ms,
+            "Database compacted"
+        );
+        Ok(report)
+    }
 
-        Ok(Self { db: Arc::new(db) })
+    /// 压缩单个数据库文件
+    async fn compact_shard(db: Arc<RwLock<Database>>, path: PathBuf) -> Result<CompactReport> {
+        tokio::task::spawn_blocking(move || {
+            let start = Instant::now();
+            let before_bytes = std::fs::metadata(&path)?.len();
+
+            let mut db = loop {
+                match db.try_write() {
+                    Ok(guard) => break guard,
+                    Err(TryLockError::Poisoned(poisoned)) => break poisoned.into_inner(),
+                    Err(TryLockError::WouldBlock) if start.elapsed() < COMPACT_LOCK_TIMEOUT => {
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                    Err(TryLockError::WouldBlock) => {
+                        anyhow::bail!(
+                            "database busy for {:?}, compaction skipped",
+                            COMPACT_LOCK_TIMEOUT
+                        )
+                    }
+                }
+            };
+            db.compact()?;
+            drop(db);
+
+            Ok(CompactReport {
+                before_bytes,
+                after_bytes: std::fs::metadata(&path)?.len(),
+                elapsed_ms: start.elapsed().as_millis() as u64,
+            })
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
     }
 
     /// 初始化数据库表
@@ -70,11 +367,96 @@ impl PersistStorage {
             let _ = write_txn.open_table(METRICS_TABLE)?;
             // 打开或创建 agent_latest 表
             let _ = write_txn.open_table(AGENT_LATEST_TABLE)?;
+            // 打开或创建 hostname_history 表
+            let _ = write_txn.open_table(HOSTNAME_HISTORY_TABLE)?;
+            // 打开或创建 hourly_rollup 表
+            let _ = write_txn.open_table(HOURLY_ROLLUP_TABLE)?;
+            // 打开或创建 agent_events 表
+            let _ = write_txn.open_table(AGENT_EVENTS_TABLE)?;
+            // 打开或创建 meta 表
+            let _ = write_txn.open_table(META_TABLE)?;
         }
         write_txn.commit()?;
         Ok(())
     }
 
+    /// 将旧格式 key（agent_id:timestamp）一次性迁移为新格式
+    ///
+    /// 迁移完成后在 meta 表记录标记，此后查询与清理只需按 agent 前缀做范围扫描
+    fn migrate_legacy_keys(db: &Database) -> Result<()> {
+        {
+            let read_txn = db.begin_read()?;
+            let meta = read_txn.open_table(META_TABLE)?;
+            if meta.get(LEGACY_KEYS_MIGRATED)?.is_some() {
+                return Ok(());
+            }
+        }
+
+        let write_txn = db.begin_write()?;
+        let migrated = {
+            let mut table = write_txn.open_table(METRICS_TABLE)?;
+
+            let mut legacy = Vec::new();
+            for item in table.iter()? {
+                let (key, value) = item?;
+                let key_str = key.value();
+                if key_str.contains('\0') {
+                    continue;
+                }
+                if let Some((agent_id, ts)) = Self::parse_key(key_str) {
+                    legacy.push((
+                        key_str.to_string(),
+                        Self::make_key(agent_id, ts),
+                        value.value().to_vec(),
+                    ));
+                }
+            }
+
+            for (old_key, new_key, value) in &legacy {
+                table.insert(new_key.as_str(), value.as_slice())?;
+                table.remove(old_key.as_str())?;
+            }
+
+            let mut meta = write_txn.open_table(META_TABLE)?;
+            meta.insert(LEGACY_KEYS_MIGRATED, 1)?;
+            legacy.len()
+        };
+        write_txn.commit()?;
+
+        if migrated > 0 {
+            info!("Migrated {} legacy metrics keys", migrated);
+        }
+        Ok(())
+    }
+
+    /// 校验文件创建时记录的分片数，首次打开时写入
+    fn check_shard_count(db: &Database, path: &Path, shard_count: usize) -> Result<()> {
+        let recorded = {
+            let read_txn = db.begin_read()?;
+            let meta = read_txn.open_table(META_TABLE)?;
+            let recorded = meta.get(SHARD_COUNT)?.map(|v| v.value());
+            recorded
+        };
+
+        match recorded {
+            Some(recorded) if recorded == shard_count as u64 => Ok(()),
+            Some(recorded) => anyhow::bail!(
+                "database {} was created with {} shards but is opened with {}",
+                path.display(),
+                recorded,
+                shard_count
+            ),
+            None => {
+                let write_txn = db.begin_write()?;
+                write_txn
+                    .open_table(META_TABLE)?
+                    .insert(SHARD_COUNT, shard_count as u64)?;
+                write_txn.commit()?;
+                Ok(())
+            }
+        }
+    }
+
     /// 生成复合键: "agent_id\0timestamp\0nonce"
     /// timestamp 固定 20 位用于排序；nonce 避免同毫秒覆盖
     fn make_key(agent_id: &str, timestamp: i64) -> String {
@@ -89,6 +471,27 @@ impl PersistStorage {
         )
     }
 
+    /// 带采集序号样本的复合键: "agent_id\0timestamp\0s<sequence>"
+    ///
+    /// 同一样本重放时落在同一个 key 上，key 本身即充当 (agent, sequence) 索引；
+    /// 序号带时间戳限定，Agent 重启后序号从 1 重新计数也不会与旧样本冲突
+    fn make_sequence_key(agent_id: &str, timestamp: i64, sequence: u64) -> String {
+        format!("{}\0{:020}\0s{:020}", agent_id, timestamp, sequence)
+    }
+
+    /// 比较 key 下已存的样本与 `metrics` 是否相同，key 不存在时返回 None
+    ///
+    /// 按解码后的内容比较：labels 为 HashMap，相同样本在不同进程中的编码字节顺序可能不同
+    fn stored_sample_matches(
+        table: &redb::Table<&str, &[u8]>,
+        key: &str,
+        metrics: &MetricsRequest,
+    ) -> Result<Option<bool>> {
+        Ok(table
+            .get(key)?
+            .map(|stored| decode_metrics(stored.value()).is_ok_and(|stored| stored == *metrics)))
+    }
+
     /// 解析复合键，返回 (agent_id, timestamp)
     fn parse_key(key: &str) -> Option<(&str, i64)> {
         // 新格式: agent_id\0timestamp\0nonce
@@ -117,68 +520,229 @@ impl PersistStorage {
         (start, end)
     }
 
-    /// 批量写入指标数据
-    pub async fn flush_batch(&self, metrics: &[MetricsRequest]) -> Result<()> {
-        if metrics.is_empty() {
-            return Ok(());
-        }
+    /// 在单个数据库文件中以一个写事务写入一批指标
+    fn write_batch(db: &Database, metrics: &[MetricsRequest]) -> Result<()> {
+        let write_txn = db.begin_write()?;
 
-        let db = self.db.clone();
-        let metrics = metrics.to_vec();
+        {
+            let mut metrics_table = write_txn.open_table(METRICS_TABLE)?;
+            let mut latest_table = write_txn.open_table(AGENT_LATEST_TABLE)?;
+            let mut hostname_table = write_txn.open_table(HOSTNAME_HISTORY_TABLE)?;
 
-        // 在 blocking task 中执行，因为 redb 操作是同步的
-        tokio::task::spawn_blocking(move || {
-            let write_txn = db.begin_write()?;
+            // 本批次涉及的主机名历史，批末仅回写发生变化的 Agent
+            let mut hostnames: HashMap<String, (Vec<HostnameChange>, bool)> = HashMap::new();
 
-            {
-                let mut metrics_table = write_txn.open_table(METRICS_TABLE)?;
-                let mut latest_table = write_txn.open_table(AGENT_LATEST_TABLE)?;
-
-                for m in &metrics {
-                    // 序列化 MetricsRequest
-                    let bytes = bincode::serialize(&m)?;
-
-                    // 写入 metrics 表
-                    let key = Self::make_key(&m.agent_id, m.timestamp);
-                    metrics_table.insert(key.as_str(), bytes.as_slice())?;
-
-                    // 更新 agent_latest 表（只在时间戳更新时写入）
-                    let should_update = match latest_table.get(m.agent_id.as_str())? {
-                        Some(existing) => {
-                            let arr = existing.value();
-                            if arr.len() == 8 {
-                                let existing_ts = i64::from_be_bytes([
-                                    arr[0], arr[1], arr[2], arr[3], arr[4], arr[5], arr[6], arr[7],
-                                ]);
-                                m.timestamp > existing_ts
-                            } else {
-                                true
+            for m in metrics {
+                // 序列化 MetricsRequest
+                let bytes = encode_metrics(m);
+
+                // 写入 metrics 表；带序号的样本使用确定的 key，已存在相同内容时跳过
+                let key = if m.sequence == 0 {
+                    Self::make_key(&m.agent_id, m.timestamp)
+                } else {
+                    let key = Self::make_sequence_key(&m.agent_id, m.timestamp, m.sequence);
+                    // 同一 key 下已有内容不同的样本时按内容摘要另存，重放时同样落在确定的 key 上
+                    let key = match Self::stored_sample_matches(&metrics_table, &key, m)? {
+                        None => Some(key),
+                        Some(true) => None,
+                        Some(false) => {
+                            let variant = format!("{}\0{:016x}", key, content_digest(m));
+                            match Self::stored_sample_matches(&metrics_table, &variant, m)? {
+                                None => Some(variant),
+                                Some(true) => None,
+                                // 摘要碰撞，退回随机 nonce
+                                Some(false) => Some(Self::make_key(&m.agent_id, m.timestamp)),
                             }
                         }
-                        None => true,
                     };
-
-                    if should_update {
-                        let timestamp_bytes = m.timestamp.to_be_bytes();
-                        latest_table.insert(m.agent_id.as_str(), timestamp_bytes.as_slice())?;
+                    let Some(key) = key else {
+                        debug!(
+                            "跳过重复样本 {} (timestamp={}, sequence={})",
+                            m.agent_id, m.timestamp, m.sequence
+                        );
+                        continue;
+                    };
+                    key
+                };
+                metrics_table.insert(key.as_str(), bytes.as_slice())?;
+
+                // 更新 agent_latest 表（只在时间戳更新时写入）
+                let should_update = match latest_table.get(m.agent_id.as_str())? {
+                    Some(existing) => {
+                        let arr = existing.value();
+                        if arr.len() == 8 {
+                            let existing_ts = i64::from_be_bytes([
+                                arr[0], arr[1], arr[2], arr[3], arr[4], arr[5], arr[6], arr[7],
+                            ]);
+                            m.timestamp > existing_ts
+                        } else {
+                            true
+                        }
                     }
+                    None => true,
+                };
+
+                if should_update {
+                    let timestamp_bytes = m.timestamp.to_be_bytes();
+                    latest_table.insert(m.agent_id.as_str(), timestamp_bytes.as_slice())?;
+                }
+
+                // 主机名仅在变化时追加
+                if !hostnames.contains_key(&m.agent_id) {
+                    let history = match hostname_table.get(m.agent_id.as_str())? {
+                        Some(bytes) => bincode::deserialize(bytes.value())?,
+                        None => Vec::new(),
+                    };
+                    hostnames.insert(m.agent_id.clone(), (history, false));
+                }
+                if let Some((history, changed)) = hostnames.get_mut(&m.agent_id) {
+                    *changed |= record_hostname(history, &m.hostname, m.timestamp);
                 }
             }
 
-            write_txn.commit()?;
-            debug!("Flushed {} metrics to redb", metrics.len());
-            Ok::<(), anyhow::Error>(())
-        })
-        .await
-        .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
+            for (agent_id, (history, changed)) in hostnames {
+                if changed {
+                    let bytes = bincode::serialize(&history)?;
+                    hostname_table.insert(agent_id.as_str(), bytes.as_slice())?;
+                }
+            }
+        }
+
+        write_txn.commit()?;
+        debug!("Flushed {} metrics to redb", metrics.len());
+        Ok(())
+    }
+
+    /// 读取 [start_key, end_key) 区间内的全部记录，取消标记置位后提前返回错误
+    fn read_range(
+        db: &Database,
+        start_key: &str,
+        end_key: &str,
+        cancelled: &AtomicBool,
+    ) -> Result<Vec<MetricsRequest>> {
+        let read_txn = db.begin_read()?;
+        let table = read_txn.open_table(METRICS_TABLE)?;
+
+        let mut results = Vec::new();
+        for (scanned, item) in table.range(start_key..end_key)?.enumerate() {
+            check_cancelled(cancelled, scanned)?;
+            let (_, value) = item?;
+            results.push(decode_metrics(value.value())?);
+        }
+        Ok(results)
+    }
+
+    /// 单个数据库文件中最新的 limit 条指标（按时间戳降序）
+    ///
+    /// 按 agent_latest 索引从最新的 Agent 开始，对各 Agent 的 key 倒序做多路归并；
+    /// 最新时间戳早于已选出样本的 Agent 不会被读取
+    fn recent_in_shard(db: &Database, limit: usize) -> Result<Vec<MetricsRequest>> {
+        let read_txn = db.begin_read()?;
+
+        // 各 Agent 按最新时间戳降序排列
+        let mut agents: Vec<(i64, String)> = {
+            let latest_table = read_txn.open_table(AGENT_LATEST_TABLE)?;
+            let mut agents = Vec::new();
+            for item in latest_table.iter()? {
+                let (key, value) = item?;
+                let ts = <[u8; 8]>::try_from(value.value())
+                    .map(i64::from_be_bytes)
+                    .unwrap_or(i64::MAX);
+                agents.push((ts, key.value().to_string()));
+            }
+            agents
+        };
+        agents.sort_by_key(|(ts, _)| std::cmp::Reverse(*ts));
+
+        let table = read_txn.open_table(METRICS_TABLE)?;
+        let mut ranges = Vec::new();
+        // (时间戳, 编码后的记录, ranges 下标)
+        let mut heap: BinaryHeap<(i64, Vec<u8>, usize)> = BinaryHeap::new();
+        let mut pending = agents.into_iter().peekable();
+        let mut results = Vec::with_capacity(limit);
+
+        while results.len() < limit {
+            // 激活最新时间戳不早于当前堆顶的 Agent
+            while let Some((latest, _)) = pending.peek() {
+                if heap.peek().is_some_and(|(ts, _, _)| ts > latest) {
+                    break;
+                }
+                let (_, agent_id) = pending.next().expect("peeked");
+                let (start_prefix, end_prefix) = Self::make_key_range(&agent_id);
+                let mut range = table.range(start_prefix.as_str()..end_prefix.as_str())?;
+                if let Some(entry) = Self::next_back_entry(&mut range)? {
+                    heap.push((entry.0, entry.1, ranges.len()));
+                }
+                ranges.push(range);
+            }
+
+            let Some((_, bytes, idx)) = heap.pop() else {
+                break;
+            };
+            results.push(decode_metrics(&bytes)?);
+            if let Some(entry) = Self::next_back_entry(&mut ranges[idx])? {
+                heap.push((entry.0, entry.1, idx));
+            }
+        }
+
+        Ok(results)
     }
 
-    /// 获取指定 Agent 的最新指标
-    pub async fn get_latest_metrics(&self, agent_id: &str) -> Result<Option<MetricsRequest>> {
-        let db = self.db.clone();
+    /// 从 range 末尾取下一条可解析的记录，返回 (时间戳, 编码后的记录)
+    fn next_back_entry(range: &mut redb::Range<'_, &str, &[u8]>) -> Result<Option<(i64, Vec<u8>)>> {
+        for item in range.rev() {
+            let (key, value) = item?;
+            if let Some((_, ts)) = Self::parse_key(key.value()) {
+                return Ok(Some((ts, value.value().to_vec())));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[tonic::async_trait]
+impl PersistBackend for PersistStorage {
+    fn keys_scanned(&self) -> u64 {
+        self.keys_scanned.load(Ordering::Relaxed)
+    }
+
+    async fn compact(&self) -> Result<Option<CompactReport>> {
+        PersistStorage::compact(self).await.map(Some)
+    }
+
+    /// 分片模式下按分片分组，各分片在独立的 blocking task 中并行写入；部分分片失败时
+    /// 已提交的分片不回滚，整批重试时其中带序号的样本会被跳过
+    async fn flush_batch(&self, metrics: &[MetricsRequest]) -> Result<()> {
+        if metrics.is_empty() {
+            return Ok(());
+        }
+
+        let mut groups: Vec<Vec<MetricsRequest>> = vec![Vec::new(); self.shards.len()];
+        for m in metrics {
+            groups[self.shard_index(&m.agent_id)].push(m.clone());
+        }
+
+        // 在 blocking task 中执行，因为 redb 操作是同步的
+        let writes = groups
+            .into_iter()
+            .zip(self.shards.iter())
+            .filter(|(group, _)| !group.is_empty())
+            .map(|(group, shard)| {
+                let db = shard.db.clone();
+                tokio::task::spawn_blocking(move || Self::write_batch(&lock_db(&db), &group))
+            });
+        for result in futures::future::join_all(writes).await {
+            result.map_err(|e| anyhow::anyhow!("Join error: {}", e))??;
+        }
+        Ok(())
+    }
+
+    async fn get_latest_metrics(&self, agent_id: &str) -> Result<Option<MetricsRequest>> {
+        let db = self.shard(agent_id).db.clone();
         let agent_id = agent_id.to_string();
 
         tokio::task::spawn_blocking(move || {
+            let db = lock_db(&db);
             let read_txn = db.begin_read()?;
             let table = read_txn.open_table(METRICS_TABLE)?;
             let (start_prefix, end_prefix) = Self::make_key_range(&agent_id);
@@ -191,25 +755,7 @@ impl PersistStorage {
                 let key_str = key.value();
                 if let Some((id, ts)) = Self::parse_key(key_str) {
                     if id == agent_id {
-                        let metrics: MetricsRequest = bincode::deserialize(value.value())?;
-                        if latest.as_ref().map(|m| m.timestamp).unwrap_or(i64::MIN) <= ts {
-                            latest = Some(metrics);
-                        }
-                    }
-                }
-            }
-
-            // 兼容旧格式 key（agent_id:timestamp）
-            let all_iter = table.iter()?;
-            for item in all_iter {
-                let (key, value) = item?;
-                let key_str = key.value();
-                if key_str.contains('\0') {
-                    continue;
-                }
-                if let Some((id, ts)) = Self::parse_key(key_str) {
-                    if id == agent_id {
-                        let metrics: MetricsRequest = bincode::deserialize(value.value())?;
+                        let metrics = decode_metrics(value.value())?;
                         if latest.as_ref().map(|m| m.timestamp).unwrap_or(i64::MIN) <= ts {
                             latest = Some(metrics);
                         }
@@ -223,8 +769,7 @@ impl PersistStorage {
         .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
     }
 
-    /// 获取指定 Agent 最新 limit 条指标
-    pub async fn query_latest_by_agent(
+    async fn query_latest_by_agent(
         &self,
         agent_id: &str,
         limit: usize,
@@ -233,38 +778,26 @@ impl PersistStorage {
             return Ok(Vec::new());
         }
 
-        let db = self.db.clone();
+        let db = self.shard(agent_id).db.clone();
         let agent_id = agent_id.to_string();
+        let cancel = ScanCancel::new();
+        let cancelled = cancel.flag();
 
         tokio::task::spawn_blocking(move || {
+            let db = lock_db(&db);
             let read_txn = db.begin_read()?;
             let table = read_txn.open_table(METRICS_TABLE)?;
             let (start_prefix, end_prefix) = Self::make_key_range(&agent_id);
 
             let mut results = Vec::new();
             let iter = table.range(start_prefix.as_str()..end_prefix.as_str())?;
-            for item in iter {
+            for (scanned, item) in iter.enumerate() {
+                check_cancelled(&cancelled, scanned)?;
                 let (key, value) = item?;
                 let key_str = key.value();
                 if let Some((id, _)) = Self::parse_key(key_str) {
                     if id == agent_id {
-                        let metrics: MetricsRequest = bincode::deserialize(value.value())?;
-                        results.push(metrics);
-                    }
-                }
-            }
-
-            // 兼容旧格式 key（agent_id:timestamp）
-            let all_iter = table.iter()?;
-            for item in all_iter {
-                let (key, value) = item?;
-                let key_str = key.value();
-                if key_str.contains('\0') {
-                    continue;
-                }
-                if let Some((id, _)) = Self::parse_key(key_str) {
-                    if id == agent_id {
-                        let metrics: MetricsRequest = bincode::deserialize(value.value())?;
+                        let metrics = decode_metrics(value.value())?;
                         results.push(metrics);
                     }
                 }
@@ -281,255 +814,573 @@ impl PersistStorage {
         .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
     }
 
-    /// 获取所有 agent_id 列表
-    pub async fn get_all_agent_ids(&self) -> Result<Vec<String>> {
-        let db = self.db.clone();
+    /// 只扫描该时间范围对应的 key 区间
+    async fn query_range_by_agent(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> Result<Vec<MetricsRequest>> {
+        if end_ts < start_ts || end_ts < 0 {
+            return Ok(Vec::new());
+        }
+
+        let db = self.shard(agent_id).db.clone();
+        let start_key = format!("{}\0{:020}", agent_id, start_ts.max(0));
+        let end_key = format!("{}\0{:020}\u{10ffff}", agent_id, end_ts);
+
+        let cancel = ScanCancel::new();
+        let cancelled = cancel.flag();
 
         tokio::task::spawn_blocking(move || {
+            Self::read_range(&lock_db(&db), &start_key, &end_key, &cancelled)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
+    }
+
+    async fn next_timestamp_from(&self, agent_id: &str, from_ts: i64) -> Result<Option<i64>> {
+        let db = self.shard(agent_id).db.clone();
+        let start_key = format!("{}\0{:020}", agent_id, from_ts.max(0));
+        let (_, end_key) = Self::make_key_range(agent_id);
+
+        tokio::task::spawn_blocking(move || {
+            let db = lock_db(&db);
             let read_txn = db.begin_read()?;
-            let table = read_txn.open_table(AGENT_LATEST_TABLE)?;
+            let table = read_txn.open_table(METRICS_TABLE)?;
 
-            let mut agent_ids = Vec::new();
-            let iter = table.iter()?;
-            for item in iter {
-                let (key, _) = item?;
-                agent_ids.push(key.value().to_string());
+            let first = table.range(start_key.as_str()..end_key.as_str())?.next();
+            match first {
+                Some(item) => {
+                    let (key, _) = item?;
+                    Ok(Self::parse_key(key.value()).map(|(_, ts)| ts))
+                }
+                None => Ok::<Option<i64>, anyhow::Error>(None),
             }
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
+    }
 
-            debug!("获取到 {} 个 agent_id", agent_ids.len());
-            Ok::<Vec<String>, anyhow::Error>(agent_ids)
+    async fn last_hourly_rollup(&self, agent_id: &str) -> Result<Option<i64>> {
+        let db = self.shard(agent_id).db.clone();
+        let (start_key, end_key) = Self::make_key_range(agent_id);
+
+        tokio::task::spawn_blocking(move || {
+            let db = lock_db(&db);
+            let read_txn = db.begin_read()?;
+            let table = read_txn.open_table(HOURLY_ROLLUP_TABLE)?;
+
+            let last = table
+                .range(start_key.as_str()..end_key.as_str())?
+                .next_back();
+            match last {
+                Some(item) => {
+                    let (key, _) = item?;
+                    Ok(Self::parse_key(key.value()).map(|(_, hour)| hour))
+                }
+                None => Ok::<Option<i64>, anyhow::Error>(None),
+            }
         })
         .await
         .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
     }
 
-    /// 删除指定 agent 超过保留数量的旧记录，返回删除数量
-    ///
-    /// 为避免内存占用过大，分批处理删除操作
-    pub async fn delete_old_records(&self, agent_id: &str, keep_count: usize) -> Result<usize> {
-        let db = self.db.clone();
+    async fn write_hourly_rollups(
+        &self,
+        agent_id: &str,
+        rollups: Vec<(i64, HourlyRollup)>,
+    ) -> Result<()> {
+        if rollups.is_empty() {
+            return Ok(());
+        }
+        let db = self.shard(agent_id).db.clone();
         let agent_id = agent_id.to_string();
 
         tokio::task::spawn_blocking(move || {
-            // 先读取该 agent 的所有 key
-            let mut records: Vec<(i64, String)> = {
-                let read_txn = db.begin_read()?;
-                let table = read_txn.open_table(METRICS_TABLE)?;
+            let db = lock_db(&db);
+            let write_txn = db.begin_write()?;
+            {
+                let mut table = write_txn.open_table(HOURLY_ROLLUP_TABLE)?;
+                for (hour, rollup) in &rollups {
+                    let key = format!("{}\0{:020}", agent_id, hour);
+                    table.insert(key.as_str(), bincode::serialize(rollup)?.as_slice())?;
+                }
+            }
+            write_txn.commit()?;
+            Ok::<(), anyhow::Error>(())
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
+    }
 
-                let (start_prefix, end_prefix) = Self::make_key_range(&agent_id);
+    async fn query_hourly_range(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> Result<Vec<(i64, HourlyRollup)>> {
+        if end_ts < start_ts || end_ts < 0 {
+            return Ok(Vec::new());
+        }
 
-                let iter = table.range(start_prefix.as_str()..end_prefix.as_str())?;
-                let mut keys = Vec::new();
-                for item in iter {
-                    let (key, _) = item?;
-                    let key_str = key.value().to_string();
-                    if let Some((id, ts)) = Self::parse_key(&key_str) {
-                        if id == agent_id {
-                            keys.push((ts, key_str));
-                        }
+        let db = self.shard(agent_id).db.clone();
+        let start_key = format!("{}\0{:020}", agent_id, start_ts.max(0));
+        let end_key = format!("{}\0{:020}\u{10ffff}", agent_id, end_ts);
+
+        let cancel = ScanCancel::new();
+        let cancelled = cancel.flag();
+
+        tokio::task::spawn_blocking(move || {
+            let db = lock_db(&db);
+            let read_txn = db.begin_read()?;
+            let table = read_txn.open_table(HOURLY_ROLLUP_TABLE)?;
+
+            let mut results = Vec::new();
+            let range = table.range(start_key.as_str()..end_key.as_str())?;
+            for (scanned, item) in range.enumerate() {
+                check_cancelled(&cancelled, scanned)?;
+                let (key, value) = item?;
+                if let Some((_, hour)) = Self::parse_key(key.value()) {
+                    results.push((hour, bincode::deserialize(value.value())?));
+                }
+            }
+            Ok::<Vec<(i64, HourlyRollup)>, anyhow::Error>(results)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
+    }
+
+    fn stream_range_by_agent(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> mpsc::Receiver<Result<MetricsRequest>> {
+        let (tx, rx) = mpsc::channel(EXPORT_CHANNEL_CAPACITY);
+        if end_ts < start_ts {
+            return rx;
+        }
+        let db = self.shard(agent_id).db.clone();
+        let agent_id = agent_id.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let db = lock_db(&db);
+            let read = || -> Result<()> {
+                let read_txn = db.begin_read()?;
+                let table = read_txn.open_table(METRICS_TABLE)?;
+                // 负时间戳的 key 排在最前，起点不大于 0 时从前缀开始扫描，再按解码后的时间戳过滤
+                let (mut start_key, _) = Self::make_key_range(&agent_id);
+                if start_ts > 0 {
+                    start_key = format!("{}\0{:020}", agent_id, start_ts);
+                }
+                let end_key = format!("{}\0{:020}\u{10ffff}", agent_id, end_ts.max(0));
+
+                let range = table.range(start_key.as_str()..end_key.as_str())?;
+                for (scanned, item) in range.enumerate() {
+                    // 被过滤的记录不经过 blocking_send，按批检查消费者是否已离开
+                    if scanned.is_multiple_of(SCAN_CHECK_INTERVAL) && tx.is_closed() {
+                        debug!("Export of agent {} cancelled by consumer", agent_id);
+                        return Ok(());
+                    }
+                    let (_, value) = item?;
+                    let metrics = decode_metrics(value.value())?;
+                    if metrics.timestamp < start_ts || metrics.timestamp > end_ts {
+                        continue;
+                    }
+                    if tx.blocking_send(Ok(metrics)).is_err() {
+                        debug!("Export of agent {} cancelled by consumer", agent_id);
+                        return Ok(());
                     }
                 }
-                keys
+                Ok(())
             };
 
-            // 兼容旧格式 key（agent_id:timestamp）
+            if let Err(e) = read() {
+                let _ = tx.blocking_send(Err(e));
+            }
+        });
+
+        rx
+    }
+
+    /// 各分片并行查询后合并，见 [`PersistStorage::recent_in_shard`]
+    async fn query_recent_across_agents(&self, limit: usize) -> Result<Vec<MetricsRequest>> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+
+        let queries = self.shards.iter().map(|shard| {
+            let db = shard.db.clone();
+            tokio::task::spawn_blocking(move || Self::recent_in_shard(&lock_db(&db), limit))
+        });
+        let mut results = Vec::new();
+        for result in futures::future::join_all(queries).await {
+            results.extend(result.map_err(|e| anyhow::anyhow!("Join error: {}", e))??);
+        }
+
+        if self.shards.len() > 1 {
+            sort_newest_first(&mut results);
+            results.truncate(limit);
+        }
+        Ok(results)
+    }
+
+    async fn get_hostname_history(&self, agent_id: &str) -> Result<Vec<HostnameChange>> {
+        let db = self.shard(agent_id).db.clone();
+        let agent_id = agent_id.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            let db = lock_db(&db);
+            let read_txn = db.begin_read()?;
+            let table = read_txn.open_table(HOSTNAME_HISTORY_TABLE)?;
+
+            let history = match table.get(agent_id.as_str())? {
+                Some(bytes) => bincode::deserialize(bytes.value())?,
+                None => Vec::new(),
+            };
+            Ok::<Vec<HostnameChange>, anyhow::Error>(history)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
+    }
+
+    async fn append_event(&self, agent_id: &str, event: &AgentEvent) -> Result<()> {
+        let db = self.shard(agent_id).db.clone();
+        let key = format!("{}\0{:020}", agent_id, event.timestamp.max(0));
+        let prefix_start = format!("{}\0", agent_id);
+        let prefix_end = format!("{}\0\u{10ffff}", agent_id);
+        let event = event.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let db = lock_db(&db);
+            let write_txn = db.begin_write()?;
             {
+                let mut table = write_txn.open_table(AGENT_EVENTS_TABLE)?;
+                let mut events: Vec<AgentEvent> = match table.get(key.as_str())? {
+                    Some(bytes) => bincode::deserialize(bytes.value())?,
+                    None => Vec::new(),
+                };
+                events.push(event);
+                table.insert(key.as_str(), bincode::serialize(&events)?.as_slice())?;
+
+                // 超出上限时删除最旧的时间戳
+                let count = table
+                    .range(prefix_start.as_str()..prefix_end.as_str())?
+                    .count();
+                if count > MAX_AGENT_EVENTS {
+                    let oldest: Vec<String> = table
+                        .range(prefix_start.as_str()..prefix_end.as_str())?
+                        .take(count - MAX_AGENT_EVENTS)
+                        .map(|item| item.map(|(key, _)| key.value().to_string()))
+                        .collect::<Result<_, _>>()?;
+                    for key in oldest {
+                        table.remove(key.as_str())?;
+                    }
+                }
+            }
+            write_txn.commit()?;
+            Ok::<(), anyhow::Error>(())
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
+    }
+
+    async fn query_events(
+        &self,
+        agent_id: &str,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> Result<Vec<AgentEvent>> {
+        if end_ts < start_ts || end_ts < 0 {
+            return Ok(Vec::new());
+        }
+
+        let db = self.shard(agent_id).db.clone();
+        let start_key = format!("{}\0{:020}", agent_id, start_ts.max(0));
+        let end_key = format!("{}\0{:020}\u{10ffff}", agent_id, end_ts);
+
+        let cancel = ScanCancel::new();
+        let cancelled = cancel.flag();
+
+        tokio::task::spawn_blocking(move || {
+            let db = lock_db(&db);
+            let read_txn = db.begin_read()?;
+            let table = read_txn.open_table(AGENT_EVENTS_TABLE)?;
+
+            let mut results = Vec::new();
+            let range = table.range(start_key.as_str()..end_key.as_str())?;
+            for (scanned, item) in range.enumerate() {
+                check_cancelled(&cancelled, scanned)?;
+                let (_, value) = item?;
+                let events: Vec<AgentEvent> = bincode::deserialize(value.value())?;
+                results.extend(events);
+            }
+            Ok::<Vec<AgentEvent>, anyhow::Error>(results)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
+    }
+
+    async fn get_all_agent_ids(&self) -> Result<Vec<String>> {
+        let dbs: Vec<_> = self.shards.iter().map(|shard| shard.db.clone()).collect();
+
+        tokio::task::spawn_blocking(move || {
+            let mut agent_ids = Vec::new();
+            for db in &dbs {
+                let db = lock_db(db);
                 let read_txn = db.begin_read()?;
-                let table = read_txn.open_table(METRICS_TABLE)?;
+                let table = read_txn.open_table(AGENT_LATEST_TABLE)?;
+
                 let iter = table.iter()?;
                 for item in iter {
-                    let (key, _) = item?;
-                    let key_str = key.value().to_string();
-                    if key_str.contains('\0') {
-                        continue;
-                    }
-                    if let Some((id, ts)) = Self::parse_key(&key_str) {
-                        if id == agent_id {
-                            records.push((ts, key_str));
-                        }
-                    }
+                    let (key, _) = item?;
+                    agent_ids.push(key.value().to_string());
                 }
             }
 
-            records.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+            debug!("获取到 {} 个 agent_id", agent_ids.len());
+            Ok::<Vec<String>, anyhow::Error>(agent_ids)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
+    }
+
+    /// 只遍历 key，不解码记录
+    async fn count_agent_records(&self, agent_id: &str) -> Result<usize> {
+        let db = self.shard(agent_id).db.clone();
+        let keys_scanned = self.keys_scanned.clone();
+        let agent_id = agent_id.to_string();
+        let cancel = ScanCancel::new();
+        let cancelled = cancel.flag();
+
+        tokio::task::spawn_blocking(move || {
+            let db = lock_db(&db);
+            let read_txn = db.begin_read()?;
+            let table = read_txn.open_table(METRICS_TABLE)?;
+            let (start_prefix, end_prefix) = Self::make_key_range(&agent_id);
 
-            let total = records.len();
-            if total <= keep_count {
-                debug!(
-                    "Agent {} has {} records, keeping {} records, no deletion needed",
-                    agent_id, total, keep_count
-                );
-                return Ok(0);
+            let mut count = 0;
+            for item in table.range(start_prefix.as_str()..end_prefix.as_str())? {
+                check_cancelled(&cancelled, count)?;
+                item?;
+                count += 1;
             }
+            keys_scanned.fetch_add(count as u64, Ordering::Relaxed);
+            Ok::<usize, anyhow::Error>(count)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
+    }
 
-            let delete_count = total - keep_count;
-            let keys_to_delete: Vec<String> = records[..delete_count]
-                .iter()
-                .map(|(_, k)| k.clone())
-                .collect();
-            let latest_after = if delete_count < records.len() {
-                Some(records[records.len() - 1].0)
-            } else {
-                None
-            };
+    /// 在一个写事务中完成。删除的总是最旧的记录，因此 agent_latest 只需在该 agent 没有剩余
+    /// 记录时移除
+    async fn delete_agent_chunk(
+        &self,
+        agent_id: &str,
+        before_ts: Option<i64>,
+        limit: usize,
+    ) -> Result<usize> {
+        let db = self.shard(agent_id).db.clone();
+        let keys_scanned = self.keys_scanned.clone();
+        let agent_id = agent_id.to_string();
 
-            // 分批删除，每批最多 10000 条，避免单次事务过大
-            const BATCH_SIZE: usize = 10000;
-            let mut total_deleted = 0;
+        tokio::task::spawn_blocking(move || {
+            let db = lock_db(&db);
+            let (start_prefix, end_prefix) = Self::make_key_range(&agent_id);
+            // 时间戳 < before_ts 的 key 都小于该边界
+            let cutoff = match before_ts {
+                Some(ts) => format!("{}\0{:020}", agent_id, ts),
+                None => end_prefix.clone(),
+            };
 
-            for chunk in keys_to_delete.chunks(BATCH_SIZE) {
-                let write_txn = db.begin_write()?;
-                {
-                    let mut table = write_txn.open_table(METRICS_TABLE)?;
-                    for key in chunk {
-                        table.remove(key.as_str())?;
-                    }
+            let write_txn = db.begin_write()?;
+            let deleted = {
+                let mut table = write_txn.open_table(METRICS_TABLE)?;
+                let keys: Vec<String> = table
+                    .range(start_prefix.as_str()..cutoff.as_str())?
+                    .take(limit)
+                    .map(|item| item.map(|(key, _)| key.value().to_string()))
+                    .collect::<std::result::Result<_, _>>()?;
+                keys_scanned.fetch_add(keys.len() as u64, Ordering::Relaxed);
+                for key in &keys {
+                    table.remove(key.as_str())?;
                 }
-                write_txn.commit()?;
-                total_deleted += chunk.len();
-            }
 
-            // 同步更新 agent_latest 索引，避免陈旧数据
-            let write_txn = db.begin_write()?;
-            {
-                let mut latest_table = write_txn.open_table(AGENT_LATEST_TABLE)?;
-                if let Some(ts) = latest_after {
-                    let ts_bytes = ts.to_be_bytes();
-                    latest_table.insert(agent_id.as_str(), ts_bytes.as_slice())?;
-                } else {
+                let empty = table
+                    .range(start_prefix.as_str()..end_prefix.as_str())?
+                    .next()
+                    .is_none();
+                if empty {
+                    let mut latest_table = write_txn.open_table(AGENT_LATEST_TABLE)?;
                     latest_table.remove(agent_id.as_str())?;
                 }
-            }
+                keys.len()
+            };
             write_txn.commit()?;
 
-            info!(
-                "Agent {} deleted {} old records, keeping {} records",
-                agent_id, total_deleted, keep_count
-            );
-            Ok::<usize, anyhow::Error>(total_deleted)
+            Ok::<usize, anyhow::Error>(deleted)
         })
         .await
         .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
     }
 
-    /// 删除指定时间之前的所有记录，返回删除数量
-    ///
-    /// 为避免内存占用过大和提升性能，按 agent 分批处理
-    pub async fn delete_before_timestamp(&self, before_ts: i64) -> Result<usize> {
-        let db = self.db.clone();
+    /// 在一个写事务中完成。只改写记录内容，key 与 agent_latest 不变
+    async fn strip_agent_chunk(
+        &self,
+        agent_id: &str,
+        from_ts: i64,
+        before_ts: i64,
+        limit: usize,
+        plan: &StripPlan,
+    ) -> Result<(usize, Option<i64>)> {
+        let db = self.shard(agent_id).db.clone();
+        let keys_scanned = self.keys_scanned.clone();
+        let agent_id = agent_id.to_string();
+        let plan = plan.clone();
 
         tokio::task::spawn_blocking(move || {
-            // 先获取所有 agent_id
-            let agent_ids: Vec<String> = {
-                let read_txn = db.begin_read()?;
-                let table = read_txn.open_table(AGENT_LATEST_TABLE)?;
-                let mut ids = Vec::new();
-                let iter = table.iter()?;
-                for item in iter {
-                    let (key, _) = item?;
-                    ids.push(key.value().to_string());
-                }
-                ids
-            };
-
-            let mut total_deleted = 0;
-
-            // 对每个 agent 分别处理，避免一次性遍历整个表
-            for agent_id in agent_ids {
-                // 收集该 agent 需要删除的 key
-                let (keys_to_delete, latest_remaining_ts): (Vec<String>, Option<i64>) = {
-                    let read_txn = db.begin_read()?;
-                    let table = read_txn.open_table(METRICS_TABLE)?;
-
-                    let (start_prefix, end_prefix) = Self::make_key_range(&agent_id);
-                    let mut keys = Vec::new();
-                    let mut latest_ts: Option<i64> = None;
-                    let iter = table.range(start_prefix.as_str()..end_prefix.as_str())?;
-
-                    for item in iter {
-                        let (key, _) = item?;
-                        let key_str = key.value();
-                        if let Some((_, ts)) = Self::parse_key(key_str) {
-                            if ts < before_ts {
-                                keys.push(key_str.to_string());
-                            } else if latest_ts.map(|v| ts > v).unwrap_or(true) {
-                                latest_ts = Some(ts);
-                            }
-                        }
-                    }
-                    (keys, latest_ts)
-                };
-
-                // 兼容旧格式 key（agent_id:timestamp）
-                let mut keys_to_delete = keys_to_delete;
-                let mut latest_remaining_ts = latest_remaining_ts;
-                {
-                    let read_txn = db.begin_read()?;
-                    let table = read_txn.open_table(METRICS_TABLE)?;
-                    let iter = table.iter()?;
-                    for item in iter {
-                        let (key, _) = item?;
-                        let key_str = key.value();
-                        if key_str.contains('\0') {
-                            continue;
-                        }
-                        if let Some((id, ts)) = Self::parse_key(key_str) {
-                            if id == agent_id {
-                                if ts < before_ts {
-                                    keys_to_delete.push(key_str.to_string());
-                                } else if latest_remaining_ts.map(|v| ts > v).unwrap_or(true) {
-                                    latest_remaining_ts = Some(ts);
-                                }
-                            }
-                        }
-                    }
-                }
+            let db = lock_db(&db);
+            let start = format!("{}\0{:020}", agent_id, from_ts);
+            let end = format!("{}\0{:020}", agent_id, before_ts);
 
-                // 分批删除，每批最多 10000 条
-                const BATCH_SIZE: usize = 10000;
-                for chunk in keys_to_delete.chunks(BATCH_SIZE) {
-                    let write_txn = db.begin_write()?;
-                    {
-                        let mut table = write_txn.open_table(METRICS_TABLE)?;
-                        for key in chunk {
-                            table.remove(key.as_str())?;
-                        }
+            let write_txn = db.begin_write()?;
+            let result = {
+                let mut table = write_txn.open_table(METRICS_TABLE)?;
+                let mut rows: Vec<(String, MetricsRequest)> = Vec::new();
+                let mut last_ts = None;
+                let mut next_from = None;
+                for item in table.range(start.as_str()..end.as_str())? {
+                    let (key, value) = item?;
+                    let key = key.value().to_string();
+                    let timestamp = Self::parse_key(&key).map_or(from_ts, |(_, ts)| ts);
+                    // 凑满一批后，只继续收下与最后一条时间戳相同的记录
+                    if rows.len() >= limit && last_ts != Some(timestamp) {
+                        next_from = Some(timestamp);
+                        break;
                     }
-                    write_txn.commit()?;
-                    total_deleted += chunk.len();
+                    rows.push((key, decode_metrics(value.value())?));
+                    last_ts = Some(timestamp);
                 }
+                keys_scanned.fetch_add(rows.len() as u64, Ordering::Relaxed);
 
-                // 同步更新 agent_latest 索引
-                let write_txn = db.begin_write()?;
-                {
-                    let mut latest_table = write_txn.open_table(AGENT_LATEST_TABLE)?;
-                    if let Some(ts) = latest_remaining_ts {
-                        let ts_bytes = ts.to_be_bytes();
-                        latest_table.insert(agent_id.as_str(), ts_bytes.as_slice())?;
-                    } else {
-                        latest_table.remove(agent_id.as_str())?;
+                let mut stripped = 0;
+                for (key, mut metrics) in rows {
+                    if plan.apply(&mut metrics) {
+                        table.insert(key.as_str(), encode_metrics(&metrics).as_slice())?;
+                        stripped += 1;
                     }
                 }
-                write_txn.commit()?;
-            }
-
-            if total_deleted > 0 {
-                info!("删除了 {} 条早于 {} 的记录", total_deleted, before_ts);
-            } else {
-                debug!("没有早于 {} 的记录需要删除", before_ts);
-            }
+                (stripped, next_from)
+            };
+            write_txn.commit()?;
 
-            Ok::<usize, anyhow::Error>(total_deleted)
+            Ok::<(usize, Option<i64>), anyhow::Error>(result)
         })
         .await
         .map_err(|e| anyhow::anyhow!("Join error: {}", e))?
     }
 }
 
+/// 分片文件路径：`<文件名>.shard-<i>.<扩展名>`（无扩展名时省略）
+fn shard_path(path: &Path, index: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.shard-{}.{}", stem, index, ext.to_string_lossy()),
+        None => format!("{}.shard-{}", stem, index),
+    };
+    path.with_file_name(name)
+}
+
+/// agent_id 路由到的分片下标
+///
+/// 使用 FNV-1a 而不是标准库的随机种子哈希，保证重启后同一 Agent 仍落在同一文件
+fn shard_index(agent_id: &str, shards: usize) -> usize {
+    let hash = agent_id
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+    (hash % shards as u64) as usize
+}
+
+/// 获取数据库读锁：普通读写事务之间共享，只与压缩互斥
+fn lock_db(db: &RwLock<Database>) -> RwLockReadGuard<'_, Database> {
+    db.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// 以仅属主可访问的权限递归创建目录
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(DB_DIR_MODE)
+        .create(dir)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    Ok(())
+}
+
+/// 预先以仅属主可读写的权限创建空文件，redb 随后在其中初始化数据库
+#[cfg(unix)]
+fn create_private_file(path: &Path) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(DB_FILE_MODE)
+        .open(path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_private_file(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// 检查已有数据库文件是否对其他用户可访问：默认只告警，`strict` 时返回错误
+#[cfg(unix)]
+fn check_permissions(path: &Path, strict: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)?.permissions().mode() & 0o777;
+    if mode & 0o007 == 0 {
+        return Ok(());
+    }
+
+    if strict {
+        anyhow::bail!(
+            "database file {} is accessible by other users (mode {:o}); \
+             run `chmod 600` on it or disable strict permission checks",
+            path.display(),
+            mode
+        )
+    }
+    warn!(
+        "Database file {} is accessible by other users (mode {:o}); consider `chmod 600`",
+        path.display(),
+        mode
+    );
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path, _strict: bool) -> Result<()> {
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use common::proto::*;
+    use std::collections::HashSet;
 
     trait PersistStorageTestExt {
         async fn query_by_agent(
@@ -548,9 +1399,7 @@ mod tests {
             start_ts: i64,
             end_ts: i64,
         ) -> Result<Vec<MetricsRequest>> {
-            let mut results = self.query_latest_by_agent(agent_id, usize::MAX).await?;
-            results.retain(|m| m.timestamp >= start_ts && m.timestamp <= end_ts);
-            Ok(results)
+            self.query_range_by_agent(agent_id, start_ts, end_ts).await
         }
 
         async fn get_agent_latest_timestamp(&self, agent_id: &str) -> Result<Option<i64>> {
@@ -567,6 +1416,8 @@ mod tests {
             agent_id: agent_id.to_string(),
             timestamp,
             hostname: "test-host".to_string(),
+            sequence: 0,
+            labels: Default::default(),
             system: Some(SystemMetrics {
                 cpu: Some(CpuMetrics {
                     usage_percent: 50.0,
@@ -575,6 +1426,8 @@ mod tests {
                     load_avg_1: 1.0,
                     load_avg_5: 0.8,
                     load_avg_15: 0.5,
+                    per_core_frequency: vec![],
+                    per_core_temperature: vec![],
                 }),
                 memory: Some(MemoryMetrics {
                     total: 16_000_000_000,
@@ -611,6 +1464,8 @@ mod tests {
                     cpu_model: "Test CPU".to_string(),
                     cpu_frequency: 3000.0,
                     hostname: "test-host".to_string(),
+                    machine_id: String::new(),
+                    boot_id: String::new(),
                 }),
                 agent_metrics: Some(AgentMetrics {
                     cpu_usage: 5.0,
@@ -619,8 +1474,21 @@ mod tests {
                     uptime_seconds: 3600,
                     metrics_sent: 1000,
                     errors_count: 0,
+                    last_error: String::new(),
+                    reconnect_count: 0,
+                    collection_time_avg_ms: 100.0,
                 }),
                 tcp_ping: vec![],
+                collector_status: vec![],
+                pressure: None,
+                gpu: vec![],
+                zombie_count: 0,
+                file_descriptors: None,
+                top_processes: vec![],
+                entropy: None,
+                systemd: None,
+                numa_nodes: vec![],
+                containers: None,
             }),
         }
     }
@@ -847,6 +1715,37 @@ mod tests {
         assert_eq!(result.len(), 1000);
     }
 
+    #[tokio::test]
+    async fn test_range_scan_stops_when_cancelled() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir
+            .path()
+            .join("test.db")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let storage = PersistStorage::new(&db_path).unwrap();
+        let metrics: Vec<_> = (0..3 * SCAN_CHECK_INTERVAL as i64)
+            .map(|i| create_test_metrics("agent-1", i))
+            .collect();
+        storage.flush_batch(&metrics).await.unwrap();
+
+        let (start_key, end_key) = PersistStorage::make_key_range("agent-1");
+        let db = storage.shard("agent-1").db.clone();
+        let cancel = ScanCancel::new();
+        let cancelled = cancel.flag();
+        let all = PersistStorage::read_range(&lock_db(&db), &start_key, &end_key, &cancelled);
+        assert_eq!(all.unwrap().len(), metrics.len());
+
+        // 等待结果的一方离开后，扫描在下一批记录处停止
+        drop(cancel);
+        assert!(cancelled.load(Ordering::Relaxed));
+        let err = PersistStorage::read_range(&lock_db(&db), &start_key, &end_key, &cancelled)
+            .unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{}", err);
+    }
+
     #[tokio::test]
     async fn test_persist_concurrent_writes() {
         let temp_dir = tempfile::tempdir().unwrap();
@@ -1091,7 +1990,10 @@ mod tests {
         storage.flush_batch(&metrics).await.unwrap();
 
         // 删除时间戳 < 2000 的记录（agent-1:1000, agent-2:1500）
-        let deleted = storage.delete_before_timestamp(2000).await.unwrap();
+        let deleted = storage
+            .delete_before_timestamp(2000, &HashSet::new())
+            .await
+            .unwrap();
         assert_eq!(deleted, 2);
 
         // 验证 agent-1 剩余记录
@@ -1106,6 +2008,44 @@ mod tests {
         assert_eq!(r2[0].timestamp, 2500);
     }
 
+    #[tokio::test]
+    async fn test_compact_shrinks_file_after_delete() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let storage = PersistStorage::new(db_path.to_str().unwrap()).unwrap();
+
+        for batch in 0..20 {
+            let metrics: Vec<_> = (0..500)
+                .map(|i| create_test_metrics("agent-1", batch * 500 + i))
+                .collect();
+            storage.flush_batch(&metrics).await.unwrap();
+        }
+        let deleted = storage
+            .delete_before_timestamp(i64::MAX, &HashSet::new())
+            .await
+            .unwrap();
+        assert_eq!(deleted, 10_000);
+
+        let report = storage.compact().await.unwrap();
+        assert!(
+            report.after_bytes < report.before_bytes,
+            "compaction should shrink the file: {:?}",
+            report
+        );
+        assert_eq!(
+            report.after_bytes,
+            std::fs::metadata(&db_path).unwrap().len()
+        );
+
+        // 压缩后仍可正常读写
+        storage
+            .flush_batch(&[create_test_metrics("agent-1", 20_000)])
+            .await
+            .unwrap();
+        let latest = storage.get_latest_metrics("agent-1").await.unwrap();
+        assert_eq!(latest.unwrap().timestamp, 20_000);
+    }
+
     #[tokio::test]
     async fn test_delete_before_timestamp_no_match() {
         let temp_dir = tempfile::tempdir().unwrap();
@@ -1122,10 +2062,474 @@ mod tests {
         storage.flush_batch(&metrics).await.unwrap();
 
         // 所有记录都在 1000 之后，不应删除
-        let deleted = storage.delete_before_timestamp(1000).await.unwrap();
+        let deleted = storage
+            .delete_before_timestamp(1000, &HashSet::new())
+            .await
+            .unwrap();
         assert_eq!(deleted, 0);
 
         let remaining = storage.query_by_agent("agent-1", 0, 99999).await.unwrap();
         assert_eq!(remaining.len(), 1);
     }
+
+    #[tokio::test]
+    async fn test_delete_before_timestamp_seeks_cutoff_range() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let storage = PersistStorage::new(db_path.to_str().unwrap()).unwrap();
+
+        const RECORDS: i64 = 20_000;
+        let metrics: Vec<_> = (0..RECORDS)
+            .map(|i| create_test_metrics("agent-1", i * 1000))
+            .chain((0..10).map(|i| create_test_metrics("agent-2", i * 1000)))
+            .collect();
+        storage.flush_batch(&metrics).await.unwrap();
+
+        // 只删除最旧的 1000 条：读取的 key 数应只与删除量相关，而非数据总量
+        let deleted = storage
+            .delete_before_timestamp(1000 * 1000, &HashSet::new())
+            .await
+            .unwrap();
+        assert_eq!(deleted, 1000 + 10);
+        assert!(
+            storage.keys_scanned() <= 1010 + 2,
+            "scanned {} keys",
+            storage.keys_scanned()
+        );
+
+        // 跨多个删除批次
+        let deleted = storage
+            .delete_before_timestamp(15_000 * 1000, &HashSet::new())
+            .await
+            .unwrap();
+        assert_eq!(deleted, 14_000);
+
+        let remaining = storage
+            .query_latest_by_agent("agent-1", usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(remaining.len(), 5_000);
+        assert_eq!(remaining[0].timestamp, 15_000 * 1000);
+        assert_eq!(
+            storage.get_agent_latest_timestamp("agent-1").await.unwrap(),
+            Some((RECORDS - 1) * 1000)
+        );
+
+        // agent-2 已全部删除，索引同步移除
+        assert_eq!(
+            storage.get_agent_latest_timestamp("agent-2").await.unwrap(),
+            None
+        );
+        assert_eq!(storage.get_all_agent_ids().await.unwrap(), vec!["agent-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_legacy_keys_migrated_on_open() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+
+        // 直接写入旧格式 key
+        {
+            create_private_file(&db_path).unwrap();
+            let db = Database::create(&db_path).unwrap();
+            let write_txn = db.begin_write().unwrap();
+            {
+                let mut table = write_txn.open_table(METRICS_TABLE).unwrap();
+                for ts in [1000, 2000] {
+                    let bytes = encode_metrics(&create_test_metrics("agent-1", ts));
+                    table
+                        .insert(format!("agent-1:{}", ts).as_str(), bytes.as_slice())
+                        .unwrap();
+                }
+                let mut latest = write_txn.open_table(AGENT_LATEST_TABLE).unwrap();
+                latest
+                    .insert("agent-1", 2000i64.to_be_bytes().as_slice())
+                    .unwrap();
+            }
+            write_txn.commit().unwrap();
+        }
+
+        let storage = PersistStorage::new(db_path.to_str().unwrap()).unwrap();
+        let history = storage.query_latest_by_agent("agent-1", 10).await.unwrap();
+        assert_eq!(
+            history.iter().map(|m| m.timestamp).collect::<Vec<_>>(),
+            vec![1000, 2000]
+        );
+
+        // 迁移后旧格式 key 不再存在
+        let read_txn = lock_db(&storage.shards[0].db).begin_read().unwrap();
+        let table = read_txn.open_table(METRICS_TABLE).unwrap();
+        for item in table.iter().unwrap() {
+            let (key, _) = item.unwrap();
+            assert!(key.value().contains('\0'));
+        }
+        drop(table);
+        drop(read_txn);
+
+        assert_eq!(
+            storage
+                .delete_before_timestamp(1500, &HashSet::new())
+                .await
+                .unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_v0_and_v1_rows() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let storage = PersistStorage::new(db_path.to_str().unwrap()).unwrap();
+
+        // v0：无版本前缀的旧 bincode 行
+        {
+            let write_txn = lock_db(&storage.shards[0].db).begin_write().unwrap();
+            {
+                let mut table = write_txn.open_table(METRICS_TABLE).unwrap();
+                let key = PersistStorage::make_key("agent-1", 1000);
+                let bytes = super::super::codec::test_support::v0_bytes("agent-1", 1000);
+                table.insert(key.as_str(), bytes.as_slice()).unwrap();
+            }
+            write_txn.commit().unwrap();
+        }
+        // v1：当前格式
+        storage
+            .flush_batch(&[create_test_metrics("agent-1", 2000)])
+            .await
+            .unwrap();
+
+        let history = storage.query_latest_by_agent("agent-1", 10).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].timestamp, 1000);
+        assert_eq!(
+            history[0].system.as_ref().unwrap().disks[0].device,
+            "/dev/sda1"
+        );
+        assert_eq!(history[1], create_test_metrics("agent-1", 2000));
+    }
+
+    #[tokio::test]
+    async fn test_query_recent_across_agents() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let storage = PersistStorage::new(db_path.to_str().unwrap()).unwrap();
+
+        let batch: Vec<_> = [
+            ("agent-a", [1000, 4000, 7000].as_slice()),
+            ("agent-b", &[2000, 5000, 8000]),
+            ("agent-c", &[3000, 6000]),
+            ("agent-d", &[100]),
+        ]
+        .iter()
+        .flat_map(|(id, timestamps)| timestamps.iter().map(|ts| create_test_metrics(id, *ts)))
+        .collect();
+        storage.flush_batch(&batch).await.unwrap();
+
+        let recent = storage.query_recent_across_agents(5).await.unwrap();
+        let order: Vec<_> = recent
+            .iter()
+            .map(|m| (m.agent_id.as_str(), m.timestamp))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("agent-b", 8000),
+                ("agent-a", 7000),
+                ("agent-c", 6000),
+                ("agent-b", 5000),
+                ("agent-a", 4000),
+            ]
+        );
+
+        // 总数不足 limit 时返回全部，仍按时间戳降序
+        let all = storage.query_recent_across_agents(100).await.unwrap();
+        assert_eq!(all.len(), 9);
+        assert!(all.windows(2).all(|w| w[0].timestamp > w[1].timestamp));
+        assert_eq!(all.last().unwrap().agent_id, "agent-d");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_created_db_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let data_dir = temp_dir.path().join("data");
+        let db_path = data_dir.join("test.db");
+        PersistStorage::new(db_path.to_str().unwrap()).unwrap();
+
+        let mode = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&data_dir), 0o700);
+        assert_eq!(mode(&db_path), 0o600);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_world_readable_db_refused_only_when_strict() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let db_path = db_path.to_str().unwrap();
+        drop(PersistStorage::new(db_path).unwrap());
+        std::fs::set_permissions(db_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        // 默认只告警
+        drop(PersistStorage::new(db_path).unwrap());
+        let err = PersistStorage::open(db_path, true).err().unwrap();
+        assert!(err.to_string().contains("accessible by other users"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_range_by_agent_after_early_drop() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let storage = PersistStorage::new(db_path.to_str().unwrap()).unwrap();
+
+        let batch: Vec<_> = (0..(EXPORT_CHANNEL_CAPACITY as i64 * 4))
+            .map(|ts| create_test_metrics("agent-1", ts))
+            .collect();
+        storage.flush_batch(&batch).await.unwrap();
+
+        let mut rx = storage.stream_range_by_agent("agent-1", i64::MIN, i64::MAX);
+        assert_eq!(rx.recv().await.unwrap().unwrap().timestamp, 0);
+        drop(rx);
+
+        // 提前丢弃接收端后游标退出，不影响之后的完整导出
+        let mut rx = storage.stream_range_by_agent("agent-1", i64::MIN, i64::MAX);
+        let mut count = 0;
+        while let Some(item) = rx.recv().await {
+            item.unwrap();
+            count += 1;
+        }
+        assert_eq!(count, batch.len());
+    }
+
+    #[tokio::test]
+    async fn test_custom_cache_size_round_trips() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("metrics.redb");
+        let db_path = db_path.to_str().unwrap();
+
+        // 远小于默认值的缓存只影响命中率，不影响读写结果
+        let storage = PersistStorage::open_with_cache(db_path, 2, false, Some(256 * 1024)).unwrap();
+        let batch: Vec<_> = (0..500)
+            .map(|ts| create_test_metrics(&format!("agent-{}", ts % 4), ts))
+            .collect();
+        storage.flush_batch(&batch).await.unwrap();
+        drop(storage);
+
+        // 缓存大小不写入文件，重新打开时可以更换
+        let reopened = PersistStorage::open_sharded(db_path, 2, false).unwrap();
+        let history = reopened
+            .query_latest_by_agent("agent-1", 1000)
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 125);
+        assert_eq!(history.last().unwrap().timestamp, 497);
+    }
+
+    #[tokio::test]
+    async fn test_sharded_storage_routes_by_agent() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("metrics.redb");
+        let db_path = db_path.to_str().unwrap();
+        let storage = PersistStorage::open_sharded(db_path, 4, false).unwrap();
+        assert!(temp_dir.path().join("metrics.shard-3.redb").exists());
+        assert!(!temp_dir.path().join("metrics.redb").exists());
+
+        let agents: Vec<String> = (0..16).map(|i| format!("agent-{}", i)).collect();
+        let batch: Vec<_> = agents
+            .iter()
+            .enumerate()
+            .flat_map(|(i, agent)| {
+                (0..3).map(move |ts| create_test_metrics(agent, i as i64 * 10 + ts))
+            })
+            .collect();
+        storage.flush_batch(&batch).await.unwrap();
+
+        let mut ids = storage.get_all_agent_ids().await.unwrap();
+        ids.sort();
+        let mut expected = agents.clone();
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        for (i, agent) in agents.iter().enumerate() {
+            let latest = storage.get_latest_metrics(agent).await.unwrap().unwrap();
+            assert_eq!(latest.agent_id, *agent);
+            assert_eq!(latest.timestamp, i as i64 * 10 + 2);
+            let history = storage.query_latest_by_agent(agent, 10).await.unwrap();
+            assert_eq!(history.len(), 3);
+            assert!(history.iter().all(|m| m.agent_id == *agent));
+        }
+
+        // 跨分片合并后仍按时间倒序截断
+        let recent = storage.query_recent_across_agents(5).await.unwrap();
+        let timestamps: Vec<_> = recent.iter().map(|m| m.timestamp).collect();
+        assert_eq!(timestamps, vec![152, 151, 150, 142, 141]);
+
+        let deleted = storage
+            .delete_before_timestamp(150, &HashSet::new())
+            .await
+            .unwrap();
+        assert_eq!(deleted, 45);
+        drop(storage);
+
+        // 分片数在创建后固定
+        assert!(PersistStorage::open_sharded(db_path, 2, false).is_err());
+        assert!(PersistStorage::open(db_path, false).is_err());
+        let reopened = PersistStorage::open_sharded(db_path, 4, false).unwrap();
+        assert_eq!(
+            reopened.get_all_agent_ids().await.unwrap(),
+            vec!["agent-15"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replayed_samples_persisted_once() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let storage = PersistStorage::new(db_path.to_str().unwrap()).unwrap();
+
+        let batch: Vec<_> = (1..=5)
+            .map(|seq| MetricsRequest {
+                sequence: seq,
+                labels: Default::default(),
+                ..create_test_metrics("agent-1", 1000 + seq as i64)
+            })
+            .collect();
+        storage.flush_batch(&batch).await.unwrap();
+        // 重连后整批重放，另有一条在同一批次内重复
+        let mut replay = batch.clone();
+        replay.push(batch[0].clone());
+        storage.flush_batch(&replay).await.unwrap();
+
+        let rows = storage
+            .query_by_agent("agent-1", 0, i64::MAX)
+            .await
+            .unwrap();
+        let sequences: Vec<_> = rows.iter().map(|m| m.sequence).collect();
+        assert_eq!(sequences, vec![1, 2, 3, 4, 5]);
+
+        // 同一毫秒的不同样本仍分别保存：序号不同、同序号但内容不同、旧版 Agent 不带序号
+        let mut same_ms = vec![
+            MetricsRequest {
+                sequence: 6,
+                labels: Default::default(),
+                ..create_test_metrics("agent-1", 2000)
+            },
+            MetricsRequest {
+                sequence: 7,
+                labels: Default::default(),
+                ..create_test_metrics("agent-1", 2000)
+            },
+            MetricsRequest {
+                sequence: 7,
+                labels: Default::default(),
+                hostname: "renamed-host".to_string(),
+                ..create_test_metrics("agent-1", 2000)
+            },
+        ];
+        same_ms.extend([
+            create_test_metrics("agent-1", 2000),
+            create_test_metrics("agent-1", 2000),
+        ]);
+        storage.flush_batch(&same_ms).await.unwrap();
+
+        let rows = storage.query_by_agent("agent-1", 2000, 2000).await.unwrap();
+        assert_eq!(rows.len(), 5);
+
+        // 再次重放带序号的样本（含内容不同的同序号样本）不新增行
+        storage.flush_batch(&same_ms[..3]).await.unwrap();
+        storage.flush_batch(&same_ms[..3]).await.unwrap();
+        let rows = storage.query_by_agent("agent-1", 2000, 2000).await.unwrap();
+        assert_eq!(rows.len(), 5);
+
+        // labels 以不同顺序构造的相同样本视为同一条
+        let labeled = |keys: &[&str]| MetricsRequest {
+            sequence: 8,
+            labels: keys
+                .iter()
+                .map(|k| (k.to_string(), format!("v-{}", k)))
+                .collect(),
+            ..create_test_metrics("agent-1", 3000)
+        };
+        let keys = ["env", "region", "rack", "team", "zone", "tier"];
+        let mut reversed = keys;
+        reversed.reverse();
+        storage.flush_batch(&[labeled(&keys)]).await.unwrap();
+        storage.flush_batch(&[labeled(&reversed)]).await.unwrap();
+        let rows = storage.query_by_agent("agent-1", 3000, 3000).await.unwrap();
+        assert_eq!(rows.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_trim_interrupted_mid_agent_resumes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db_path = temp_dir.path().join("test.db");
+        let storage = PersistStorage::new(db_path.to_str().unwrap()).unwrap();
+
+        let batch: Vec<_> = (1..=100)
+            .map(|ts| create_test_metrics("agent-1", ts))
+            .collect();
+        storage.flush_batch(&batch).await.unwrap();
+
+        // 第 4 批之前收到停止信号
+        let mut chunks = 0;
+        let deleted = storage
+            .trim_agent_records("agent-1", 10, 7, &mut || {
+                chunks += 1;
+                chunks <= 3
+            })
+            .await
+            .unwrap();
+        assert_eq!(deleted, 21);
+
+        // 中断后剩余的仍是连续的最新记录，最新样本与 agent 索引不受影响
+        let remaining = storage
+            .query_by_agent("agent-1", 0, i64::MAX)
+            .await
+            .unwrap();
+        let timestamps: Vec<_> = remaining.iter().map(|m| m.timestamp).collect();
+        assert_eq!(timestamps, (22..=100).collect::<Vec<_>>());
+        let latest = storage
+            .get_latest_metrics("agent-1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.timestamp, 100);
+        assert_eq!(storage.get_all_agent_ids().await.unwrap(), vec!["agent-1"]);
+
+        // 再次调用从中断处继续
+        let deleted = storage
+            .trim_agent_records("agent-1", 10, 7, &mut || true)
+            .await
+            .unwrap();
+        assert_eq!(deleted, 69);
+        let remaining = storage
+            .query_by_agent("agent-1", 0, i64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(remaining.first().unwrap().timestamp, 91);
+        assert_eq!(remaining.len(), 10);
+
+        // 按时间分批删除同样可中断
+        let mut chunks = 0;
+        let deleted = storage
+            .delete_agent_records_before("agent-1", 96, 2, &mut || {
+                chunks += 1;
+                chunks <= 1
+            })
+            .await
+            .unwrap();
+        assert_eq!(deleted, 2);
+        assert_eq!(
+            storage
+                .delete_agent_records_before("agent-1", i64::MAX, 2, &mut || true)
+                .await
+                .unwrap(),
+            8
+        );
+        assert!(storage.get_all_agent_ids().await.unwrap().is_empty());
+    }
 }
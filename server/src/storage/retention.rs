@@ -0,0 +1,237 @@
+//! 按字段分级保留
+//!
+//! CPU、内存、磁盘等核心序列需要长期保留，而每核使用率、Top 进程等体积较大的数组通常只在
+//! 近期排查时用到。[`FieldRetention`] 为这些低重要度的字段组分别指定保留时长，清理任务把
+//! 超出保留期的样本原位改写为去掉这些字段后的版本：记录的 key 不变，其余字段原样保留，
+//! 改写后的行与 Agent 未上报这些字段时落盘的行相同，照常可读
+
+use common::proto::{MetricsRequest, SystemMetrics};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// 可单独设置保留时长的字段组
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FieldGroup {
+    /// `system.cpu.per_core`，以及与其对齐的 `per_core_frequency`、`per_core_temperature`
+    PerCore,
+    /// `system.top_processes`
+    Processes,
+    /// `system.tcp_ping`
+    TcpPing,
+    /// `system.collector_status`
+    CollectorStatus,
+}
+
+impl FieldGroup {
+    const ALL: [Self; 4] = [
+        Self::PerCore,
+        Self::Processes,
+        Self::TcpPing,
+        Self::CollectorStatus,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::PerCore => "per_core",
+            Self::Processes => "processes",
+            Self::TcpPing => "tcp_ping",
+            Self::CollectorStatus => "collector_status",
+        }
+    }
+
+    /// 去掉该字段组，返回样本是否有变化
+    fn strip(self, system: &mut SystemMetrics) -> bool {
+        fn clear<T>(values: &mut Vec<T>) -> bool {
+            let changed = !values.is_empty();
+            *values = Vec::new();
+            changed
+        }
+
+        match self {
+            Self::PerCore => system.cpu.as_mut().is_some_and(|cpu| {
+                // 不能短路：三个数组都要清空
+                clear(&mut cpu.per_core)
+                    | clear(&mut cpu.per_core_frequency)
+                    | clear(&mut cpu.per_core_temperature)
+            }),
+            Self::Processes => clear(&mut system.top_processes),
+            Self::TcpPing => clear(&mut system.tcp_ping),
+            Self::CollectorStatus => clear(&mut system.collector_status),
+        }
+    }
+}
+
+impl FromStr for FieldGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|group| group.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "未知的字段组: {}（可选 per_core、processes、tcp_ping、collector_status）",
+                    s
+                )
+            })
+    }
+}
+
+impl fmt::Display for FieldGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// 各字段组的保留时长，未列出的字段组随样本一起保留
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FieldRetention {
+    groups: BTreeMap<FieldGroup, Duration>,
+}
+
+impl FieldRetention {
+    /// 以 `now`（毫秒）为准计算各字段组的截止时间
+    pub fn plan(&self, now: i64) -> StripPlan {
+        StripPlan {
+            cutoffs: self
+                .groups
+                .iter()
+                .map(|(group, retention)| {
+                    (*group, now.saturating_sub(retention.as_millis() as i64))
+                })
+                .collect(),
+        }
+    }
+}
+
+impl FromStr for FieldRetention {
+    type Err = String;
+
+    /// 解析 `per_core=24,processes=6`，保留时长以小时为单位
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut groups = BTreeMap::new();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (name, hours) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("无效的字段保留时长 {}，应为 字段组=小时数", entry))?;
+            let group: FieldGroup = name.trim().parse()?;
+            let hours: u64 = hours
+                .trim()
+                .parse()
+                .ok()
+                .filter(|hours| *hours > 0)
+                .ok_or_else(|| format!("无效的字段保留时长 {}，小时数应为正整数", entry))?;
+            groups.insert(group, Duration::from_secs(hours * 3600));
+        }
+        Ok(Self { groups })
+    }
+}
+
+impl fmt::Display for FieldRetention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (group, retention)) in self.groups.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", group, retention.as_secs() / 3600)?;
+        }
+        Ok(())
+    }
+}
+
+/// 一次清理中各字段组的截止时间：时间戳早于截止时间的样本去掉该字段组
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StripPlan {
+    cutoffs: Vec<(FieldGroup, i64)>,
+}
+
+impl StripPlan {
+    /// 最早的截止时间：早于它的样本已去掉全部字段组，没有字段组时为 None
+    pub fn oldest_cutoff(&self) -> Option<i64> {
+        self.cutoffs.iter().map(|(_, cutoff)| *cutoff).min()
+    }
+
+    /// 最晚的截止时间：不早于它的样本不需要改写，没有字段组时为 None
+    pub fn newest_cutoff(&self) -> Option<i64> {
+        self.cutoffs.iter().map(|(_, cutoff)| *cutoff).max()
+    }
+
+    /// 去掉样本中已超出保留期的字段组，返回样本是否有变化
+    pub fn apply(&self, metrics: &mut MetricsRequest) -> bool {
+        let Some(system) = metrics.system.as_mut() else {
+            return false;
+        };
+        let mut changed = false;
+        for (group, cutoff) in &self.cutoffs {
+            if metrics.timestamp < *cutoff {
+                changed |= group.strip(system);
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::{CpuMetrics, ProcessMetrics};
+
+    #[test]
+    fn test_parse_field_retention() {
+        let retention: FieldRetention = "processes=6, per_core=24".parse().unwrap();
+        assert_eq!(retention.to_string(), "per_core=24,processes=6");
+        assert_eq!(retention.to_string().parse(), Ok(retention));
+        assert_eq!("".parse::<FieldRetention>(), Ok(FieldRetention::default()));
+
+        assert!("per_core".parse::<FieldRetention>().is_err());
+        assert!("per_core=0".parse::<FieldRetention>().is_err());
+        assert!("disks=24".parse::<FieldRetention>().is_err());
+    }
+
+    #[test]
+    fn test_plan_strips_only_expired_groups() {
+        let retention: FieldRetention = "per_core=1,processes=2".parse().unwrap();
+        let now = 10 * 3_600_000;
+        let plan = retention.plan(now);
+        assert_eq!(plan.oldest_cutoff(), Some(8 * 3_600_000));
+        assert_eq!(plan.newest_cutoff(), Some(9 * 3_600_000));
+
+        let sample = |timestamp| MetricsRequest {
+            timestamp,
+            system: Some(SystemMetrics {
+                cpu: Some(CpuMetrics {
+                    usage_percent: 40.0,
+                    per_core: vec![30.0, 50.0],
+                    ..Default::default()
+                }),
+                top_processes: vec![ProcessMetrics::default()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        // 只超出 per_core 的保留期
+        let mut metrics = sample(now - 90 * 60_000);
+        assert!(plan.apply(&mut metrics));
+        let system = metrics.system.as_ref().unwrap();
+        assert_eq!(system.cpu.as_ref().unwrap().usage_percent, 40.0);
+        assert!(system.cpu.as_ref().unwrap().per_core.is_empty());
+        assert_eq!(system.top_processes.len(), 1);
+
+        // 两组都超出保留期，再次应用时没有变化
+        let mut metrics = sample(now - 3 * 3_600_000);
+        assert!(plan.apply(&mut metrics));
+        assert!(metrics.system.as_ref().unwrap().top_processes.is_empty());
+        assert!(!plan.apply(&mut metrics));
+
+        let mut metrics = sample(now);
+        assert!(!plan.apply(&mut metrics));
+        assert_eq!(metrics, sample(now));
+    }
+}
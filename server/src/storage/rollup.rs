@@ -0,0 +1,120 @@
+//! 长期小时级汇总
+//!
+//! 原始样本按保留策略清理后，仍为每个 Agent 保留一条按小时汇总的 CPU/内存/磁盘序列用于
+//! 容量趋势分析。汇总由清理任务在删除前写入，不受保留策略影响；为控制体积只保留少数字段的
+//! 小时均值
+
+use crate::analytics::MetricField;
+use common::proto::MetricsRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// 一小时的毫秒数
+pub const HOUR_MS: i64 = 3_600_000;
+
+/// 小时汇总保留的指标
+pub const HOURLY_FIELDS: [MetricField; 3] =
+    [MetricField::Cpu, MetricField::Memory, MetricField::Disk];
+
+/// 单个 Agent 一小时内的汇总
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct HourlyRollup {
+    /// 参与汇总的样本数
+    pub samples: u32,
+    /// CPU 使用率均值（%）
+    pub cpu: Option<f32>,
+    /// 内存使用率均值（%）
+    pub memory: Option<f32>,
+    /// 各挂载点中最高磁盘使用率的均值（%）
+    pub disk: Option<f32>,
+}
+
+impl HourlyRollup {
+    /// 取该小时指定指标的均值，未保留的指标或该小时缺少数据时为 None
+    pub fn value(&self, field: MetricField) -> Option<f64> {
+        match field {
+            MetricField::Cpu => self.cpu,
+            MetricField::Memory => self.memory,
+            MetricField::Disk => self.disk,
+            _ => None,
+        }
+        .map(f64::from)
+    }
+}
+
+/// 时间戳所在小时的起点（毫秒）
+pub fn hour_start(timestamp: i64) -> i64 {
+    timestamp.div_euclid(HOUR_MS) * HOUR_MS
+}
+
+/// 把样本按所在小时汇总，返回 (小时起点, 汇总)，按时间升序
+pub fn rollup_hours(samples: &[MetricsRequest]) -> Vec<(i64, HourlyRollup)> {
+    let mut hours: BTreeMap<i64, Vec<&MetricsRequest>> = BTreeMap::new();
+    for metrics in samples {
+        hours
+            .entry(hour_start(metrics.timestamp))
+            .or_default()
+            .push(metrics);
+    }
+
+    hours
+        .into_iter()
+        .map(|(hour, samples)| {
+            let mean = |field: MetricField| {
+                let values: Vec<f64> = samples.iter().filter_map(|m| field.value(m)).collect();
+                (!values.is_empty())
+                    .then(|| (values.iter().sum::<f64>() / values.len() as f64) as f32)
+            };
+            let rollup = HourlyRollup {
+                samples: samples.len() as u32,
+                cpu: mean(MetricField::Cpu),
+                memory: mean(MetricField::Memory),
+                disk: mean(MetricField::Disk),
+            };
+            (hour, rollup)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::{CpuMetrics, SystemMetrics};
+
+    fn sample(timestamp: i64, cpu: f64) -> MetricsRequest {
+        MetricsRequest {
+            agent_id: "agent-1".to_string(),
+            timestamp,
+            system: Some(SystemMetrics {
+                cpu: Some(CpuMetrics {
+                    usage_percent: cpu,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rollup_hours_means_per_hour() {
+        let samples = vec![
+            sample(HOUR_MS, 10.0),
+            sample(HOUR_MS + 1000, 30.0),
+            sample(3 * HOUR_MS + 5, 50.0),
+        ];
+        let rollups = rollup_hours(&samples);
+        assert_eq!(rollups.len(), 2);
+
+        let (hour, first) = rollups[0];
+        assert_eq!(hour, HOUR_MS);
+        assert_eq!(first.samples, 2);
+        assert_eq!(first.value(MetricField::Cpu), Some(20.0));
+        // 样本中没有的指标不保留
+        assert_eq!(first.value(MetricField::Memory), None);
+        assert_eq!(first.value(MetricField::Load1), None);
+
+        assert_eq!(rollups[1].0, 3 * HOUR_MS);
+        assert_eq!(hour_start(-1), -HOUR_MS);
+    }
+}
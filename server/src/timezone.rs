@@ -0,0 +1,647 @@
+//! 查询分桶使用的时区
+//!
+//! 支持固定偏移（`+08:00`、`-0530`、`UTC`）与 IANA 时区名（`Asia/Shanghai`）。时区名从系统
+//! zoneinfo 数据库（`TZDIR`，缺省 `/usr/share/zoneinfo`）读取 TZif 文件：文件内的转换表覆盖
+//! 历史规则，最后一次转换之后按文件末尾的 POSIX TZ 规则推算夏令时。
+//!
+//! 解析成功的时区名在进程内缓存；请求处理中使用 [`TimeZone::resolve`]，首次读取 zoneinfo
+//! 在阻塞线程池中完成，不占用异步运行时的工作线程
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+const HOUR_MS: i64 = 3_600_000;
+const DAY_MS: i64 = 24 * HOUR_MS;
+
+/// 已加载的 IANA 时区规则，只缓存解析成功的时区名，条目数不超过 zoneinfo 中的时区数
+static ZONE_CACHE: Mutex<BTreeMap<String, Arc<ZoneRules>>> = Mutex::new(BTreeMap::new());
+
+/// 时区：把 UTC 时间戳换算为本地时钟
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZone {
+    name: String,
+    rules: Rules,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Rules {
+    /// 固定偏移（毫秒，本地 = UTC + 偏移）
+    Fixed(i64),
+    Zone(Arc<ZoneRules>),
+}
+
+/// TZif 文件解析出的规则
+#[derive(Debug, PartialEq, Eq)]
+struct ZoneRules {
+    /// 第一次转换之前的偏移（毫秒）
+    initial: i64,
+    /// (转换时刻 UTC 毫秒, 转换后的偏移毫秒)，按时间升序
+    transitions: Vec<(i64, i64)>,
+    /// 最后一次转换之后的规则，没有时沿用最后一次转换的偏移
+    tail: Option<PosixRule>,
+}
+
+/// 不读取文件即可得到的解析结果
+enum Spec {
+    Ready(TimeZone),
+    /// 尚未加载的 IANA 时区名（已校验格式）
+    Named(String),
+}
+
+impl TimeZone {
+    /// 解析时区，用于异步上下文：未缓存的时区名在阻塞线程池中读取 zoneinfo
+    pub async fn resolve(s: &str) -> Result<Self, String> {
+        match parse_spec(s)? {
+            Spec::Ready(zone) => Ok(zone),
+            Spec::Named(name) => tokio::task::spawn_blocking(move || load_named(&name))
+                .await
+                .map_err(|e| format!("加载时区失败: {}", e))?,
+        }
+    }
+
+    /// UTC
+    pub fn utc() -> Self {
+        Self {
+            name: "UTC".to_string(),
+            rules: Rules::Fixed(0),
+        }
+    }
+
+    /// `utc_ms` 时刻的偏移（毫秒，本地 = UTC + 偏移）
+    pub fn offset_ms(&self, utc_ms: i64) -> i64 {
+        match &self.rules {
+            Rules::Fixed(offset) => *offset,
+            Rules::Zone(zone) => zone.offset_ms(utc_ms),
+        }
+    }
+
+    /// `ts` 所在桶的起点（UTC 毫秒）
+    ///
+    /// 桶边界按本地时钟对齐到本地 1970-01-01 零点起的整数倍桶宽，小时桶与天桶因此落在本地
+    /// 整点与零点。夏令时切换当天的天桶仍是从本地零点到下一个本地零点（23 或 25 小时）；
+    /// 回拨时重复的本地小时并入同一个小时桶，跳过的本地时刻不产生桶，本地零点恰好被跳过时
+    /// 以切换时刻为起点
+    pub fn bucket_start(&self, ts: i64, bucket_ms: i64) -> i64 {
+        let local = ts + self.offset_ms(ts);
+        self.local_to_utc(local.div_euclid(bucket_ms) * bucket_ms)
+    }
+
+    /// 把本地时钟换算回 UTC：有两个对应时刻时取较早者，落在跳过的区间内时取切换时刻
+    fn local_to_utc(&self, local: i64) -> i64 {
+        let before = self.offset_ms(local - DAY_MS);
+        let after = self.offset_ms(local + DAY_MS);
+        let (early, late) = (local - before.max(after), local - before.min(after));
+        for utc in [early, late] {
+            if utc + self.offset_ms(utc) == local {
+                return utc;
+            }
+        }
+
+        // 跳过的区间：切换前的偏移在 early 之前生效，二分找到切换时刻
+        let (mut lo, mut hi) = (early, late);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.offset_ms(mid) == before {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+}
+
+/// 同步解析时区，未缓存的时区名直接读取 zoneinfo；异步上下文中请使用 [`TimeZone::resolve`]
+impl FromStr for TimeZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_spec(s)? {
+            Spec::Ready(zone) => Ok(zone),
+            Spec::Named(name) => load_named(&name),
+        }
+    }
+}
+
+fn unknown_zone(s: &str) -> String {
+    format!(
+        "未知的时区: {}（可用 IANA 时区名如 Asia/Shanghai，或固定偏移如 +08:00）",
+        s
+    )
+}
+
+/// 解析 UTC、固定偏移与已缓存的时区名，其余合法的时区名留待加载
+fn parse_spec(s: &str) -> Result<Spec, String> {
+    let s = s.trim();
+    if s.eq_ignore_ascii_case("UTC") || s == "Z" {
+        return Ok(Spec::Ready(TimeZone::utc()));
+    }
+    if let Some(offset) = parse_fixed_offset(s) {
+        return Ok(Spec::Ready(TimeZone {
+            name: s.to_string(),
+            rules: Rules::Fixed(offset),
+        }));
+    }
+    // 只接受时区名，避免读取 zoneinfo 目录以外的文件
+    let valid_name = !s.is_empty()
+        && !s.starts_with('/')
+        && s.split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"/_+-".contains(&b));
+    if !valid_name {
+        return Err(unknown_zone(s));
+    }
+    let cached = ZONE_CACHE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(s)
+        .cloned();
+    Ok(match cached {
+        Some(zone) => Spec::Ready(TimeZone {
+            name: s.to_string(),
+            rules: Rules::Zone(zone),
+        }),
+        None => Spec::Named(s.to_string()),
+    })
+}
+
+/// 从 zoneinfo 读取并解析时区文件（阻塞），成功后写入缓存
+fn load_named(name: &str) -> Result<TimeZone, String> {
+    let dir = std::env::var_os("TZDIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/usr/share/zoneinfo"));
+    let data = std::fs::read(dir.join(name)).map_err(|_| unknown_zone(name))?;
+    let zone = Arc::new(parse_tzif(&data).ok_or_else(|| format!("无法解析时区文件: {}", name))?);
+    ZONE_CACHE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(name.to_string(), zone.clone());
+    Ok(TimeZone {
+        name: name.to_string(),
+        rules: Rules::Zone(zone),
+    })
+}
+
+impl fmt::Display for TimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// 解析 `+08:00`、`+0800`、`+08`，返回偏移毫秒
+fn parse_fixed_offset(s: &str) -> Option<i64> {
+    let sign = match s.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let digits: String = s[1..].chars().filter(|c| *c != ':').collect();
+    if !digits.bytes().all(|b| b.is_ascii_digit()) || !matches!(digits.len(), 2 | 4) {
+        return None;
+    }
+    let hours: i64 = digits[..2].parse().ok()?;
+    let minutes: i64 = digits.get(2..).map_or(Some(0), |m| m.parse().ok())?;
+    if hours > 14 || minutes > 59 {
+        return None;
+    }
+    Some(sign * (hours * HOUR_MS + minutes * 60_000))
+}
+
+impl ZoneRules {
+    fn offset_ms(&self, utc_ms: i64) -> i64 {
+        let index = self
+            .transitions
+            .partition_point(|(time, _)| *time <= utc_ms);
+        match &self.tail {
+            Some(tail) if index == self.transitions.len() => tail.offset_ms(utc_ms),
+            _ if index == 0 => self.initial,
+            _ => self.transitions[index - 1].1,
+        }
+    }
+}
+
+/// 解析 TZif 文件（RFC 8536），v2 及以上版本使用 64 位数据块与末尾的 POSIX TZ 规则
+fn parse_tzif(data: &[u8]) -> Option<ZoneRules> {
+    struct Header {
+        version: u8,
+        counts: [usize; 6],
+    }
+
+    fn header(data: &[u8]) -> Option<Header> {
+        if data.get(..4)? != b"TZif" {
+            return None;
+        }
+        let mut counts = [0; 6];
+        for (i, count) in counts.iter_mut().enumerate() {
+            let start = 20 + i * 4;
+            *count = u32::from_be_bytes(data.get(start..start + 4)?.try_into().ok()?) as usize;
+        }
+        Some(Header {
+            version: *data.get(4)?,
+            counts,
+        })
+    }
+
+    /// 数据块长度；time_size 为转换时刻的字节数（v1 为 4，v2+ 为 8）
+    fn block_len(header: &Header, time_size: usize) -> usize {
+        let [isutcnt, isstdcnt, leapcnt, timecnt, typecnt, charcnt] = header.counts;
+        timecnt * time_size
+            + timecnt
+            + typecnt * 6
+            + charcnt
+            + leapcnt * (time_size + 4)
+            + isstdcnt
+            + isutcnt
+    }
+
+    let first = header(data)?;
+    let (header, body, time_size) = if first.version >= b'2' {
+        let rest = data.get(44 + block_len(&first, 4)..)?;
+        (header(rest)?, rest.get(44..)?, 8)
+    } else {
+        (first, data.get(44..)?, 4)
+    };
+    let [_, _, _, timecnt, typecnt, _] = header.counts;
+    if typecnt == 0 {
+        return None;
+    }
+
+    let times = body.get(..timecnt * time_size)?;
+    let indices = body.get(timecnt * time_size..timecnt * (time_size + 1))?;
+    let types = body.get(timecnt * (time_size + 1)..timecnt * (time_size + 1) + typecnt * 6)?;
+    let offset_of = |index: usize| -> Option<i64> {
+        let entry = types.get(index * 6..index * 6 + 4)?;
+        Some(i32::from_be_bytes(entry.try_into().ok()?) as i64 * 1000)
+    };
+
+    let mut transitions = Vec::with_capacity(timecnt);
+    for (time, index) in times.chunks_exact(time_size).zip(indices) {
+        let time = if time_size == 8 {
+            i64::from_be_bytes(time.try_into().ok()?)
+        } else {
+            i32::from_be_bytes(time.try_into().ok()?) as i64
+        };
+        transitions.push((time.saturating_mul(1000), offset_of(*index as usize)?));
+    }
+
+    let tail = if time_size == 8 {
+        let footer = body.get(block_len(&header, 8)..)?;
+        std::str::from_utf8(footer)
+            .ok()
+            .and_then(|footer| footer.trim_matches('\n').lines().next())
+            .and_then(PosixRule::parse)
+    } else {
+        None
+    };
+
+    Some(ZoneRules {
+        initial: offset_of(0)?,
+        transitions,
+        tail,
+    })
+}
+
+/// POSIX TZ 规则（如 `CET-1CEST,M3.5.0,M10.5.0/3`），偏移均已换算为本地 = UTC + 偏移
+#[derive(Debug, PartialEq, Eq)]
+struct PosixRule {
+    std_offset: i64,
+    dst: Option<DstRule>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct DstRule {
+    offset: i64,
+    /// (开始日期, 当地标准时间的切换时刻毫秒)
+    start: (RuleDate, i64),
+    /// (结束日期, 当地夏令时的切换时刻毫秒)
+    end: (RuleDate, i64),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RuleDate {
+    /// `Jn`：1–365，不计 2 月 29 日
+    Julian(i64),
+    /// `n`：0–365，计 2 月 29 日
+    DayOfYear(i64),
+    /// `Mm.w.d`：m 月第 w 个星期 d（w=5 为最后一个，d=0 为星期日）
+    Month { month: i64, week: i64, weekday: i64 },
+}
+
+impl PosixRule {
+    fn parse(s: &str) -> Option<Self> {
+        let mut rest = s;
+        skip_name(&mut rest)?;
+        // POSIX 偏移与 UTC 偏移符号相反
+        let std_offset = -parse_posix_time(&mut rest)?;
+        if rest.is_empty() {
+            return Some(Self {
+                std_offset,
+                dst: None,
+            });
+        }
+
+        skip_name(&mut rest)?;
+        let offset = if rest.is_empty() || rest.starts_with(',') {
+            std_offset + HOUR_MS
+        } else {
+            -parse_posix_time(&mut rest)?
+        };
+        // 缺省规则沿用美国的 M3.2.0,M11.1.0
+        let rules = rest.strip_prefix(',').unwrap_or("M3.2.0,M11.1.0");
+        let (start, end) = rules.split_once(',')?;
+        Some(Self {
+            std_offset,
+            dst: Some(DstRule {
+                offset,
+                start: parse_transition(start)?,
+                end: parse_transition(end)?,
+            }),
+        })
+    }
+
+    fn offset_ms(&self, utc_ms: i64) -> i64 {
+        let Some(dst) = &self.dst else {
+            return self.std_offset;
+        };
+        let year = civil_year((utc_ms + self.std_offset).div_euclid(DAY_MS));
+        let start = dst.start.0.day(year) * DAY_MS + dst.start.1 - self.std_offset;
+        let end = dst.end.0.day(year) * DAY_MS + dst.end.1 - dst.offset;
+        let in_dst = if start < end {
+            (start..end).contains(&utc_ms)
+        } else {
+            // 南半球：夏令时跨年
+            utc_ms < end || utc_ms >= start
+        };
+        if in_dst {
+            dst.offset
+        } else {
+            self.std_offset
+        }
+    }
+}
+
+impl RuleDate {
+    /// `year` 年中该日期距 1970-01-01 的天数
+    fn day(&self, year: i64) -> i64 {
+        let jan1 = days_from_civil(year, 1, 1);
+        match *self {
+            Self::Julian(n) => {
+                let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+                jan1 + n - 1 + i64::from(leap && n >= 60)
+            }
+            Self::DayOfYear(n) => jan1 + n,
+            Self::Month {
+                month,
+                week,
+                weekday,
+            } => {
+                let first = days_from_civil(year, month, 1);
+                let next = if month == 12 {
+                    days_from_civil(year + 1, 1, 1)
+                } else {
+                    days_from_civil(year, month + 1, 1)
+                };
+                // 1970-01-01 是星期四
+                let first_weekday = (first + 4).rem_euclid(7);
+                let mut day = first + (weekday - first_weekday).rem_euclid(7) + (week - 1) * 7;
+                while day >= next {
+                    day -= 7;
+                }
+                day
+            }
+        }
+    }
+}
+
+/// 跳过时区缩写（字母，或 `<...>` 括起的任意字符）
+fn skip_name(rest: &mut &str) -> Option<()> {
+    let len = if let Some(quoted) = rest.strip_prefix('<') {
+        quoted.find('>')? + 2
+    } else {
+        rest.find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len())
+    };
+    if len < 3 {
+        return None;
+    }
+    *rest = &rest[len..];
+    Some(())
+}
+
+/// 解析 `[+-]hh[:mm[:ss]]`，返回毫秒
+fn parse_posix_time(rest: &mut &str) -> Option<i64> {
+    let end = rest
+        .find(|c: char| !(c.is_ascii_digit() || c == ':' || c == '+' || c == '-'))
+        .unwrap_or(rest.len());
+    let (time, remaining) = rest.split_at(end);
+    *rest = remaining;
+    let (sign, time) = match time.strip_prefix('-') {
+        Some(time) => (-1, time),
+        None => (1, time.strip_prefix('+').unwrap_or(time)),
+    };
+    let mut millis = 0;
+    let mut unit = HOUR_MS;
+    for part in time.split(':') {
+        millis += part.parse::<i64>().ok()? * unit;
+        unit /= 60;
+    }
+    Some(sign * millis)
+}
+
+/// 解析 `date[/time]`，时刻缺省为 02:00:00
+fn parse_transition(s: &str) -> Option<(RuleDate, i64)> {
+    let (date, time) = match s.split_once('/') {
+        Some((date, mut time)) => (date, parse_posix_time(&mut time)?),
+        None => (s, 2 * HOUR_MS),
+    };
+    let date = if let Some(spec) = date.strip_prefix('M') {
+        let mut parts = spec.splitn(3, '.').map(|part| part.parse::<i64>().ok());
+        let (month, week, weekday) = (parts.next()??, parts.next()??, parts.next()??);
+        if !(1..=12).contains(&month) || !(1..=5).contains(&week) || !(0..=6).contains(&weekday) {
+            return None;
+        }
+        RuleDate::Month {
+            month,
+            week,
+            weekday,
+        }
+    } else if let Some(n) = date.strip_prefix('J') {
+        RuleDate::Julian(n.parse().ok().filter(|n| (1..=365).contains(n))?)
+    } else {
+        RuleDate::DayOfYear(date.parse().ok().filter(|n| (0..=365).contains(n))?)
+    };
+    Some((date, time))
+}
+
+/// 公历日期距 1970-01-01 的天数
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// 距 1970-01-01 的天数所在的公历年
+fn civil_year(days: i64) -> i64 {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    // 3 月起算，month_index >= 10 为次年 1、2 月
+    year_of_era + era * 400 + i64::from(month_index >= 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 本地日期时间对应的 UTC 毫秒
+    fn utc(year: i64, month: i64, day: i64, hour: i64) -> i64 {
+        days_from_civil(year, month, day) * DAY_MS + hour * HOUR_MS
+    }
+
+    #[test]
+    fn test_parse_time_zone() {
+        assert_eq!("UTC".parse::<TimeZone>().unwrap().offset_ms(0), 0);
+        assert_eq!(
+            "+08:00".parse::<TimeZone>().unwrap().offset_ms(0),
+            8 * HOUR_MS
+        );
+        assert_eq!(
+            "-0530".parse::<TimeZone>().unwrap().offset_ms(0),
+            -(5 * HOUR_MS + 30 * 60_000)
+        );
+        assert_eq!("+08:00".parse::<TimeZone>().unwrap().to_string(), "+08:00");
+
+        for invalid in [
+            "+25:00",
+            "+8",
+            "../etc/passwd",
+            "/etc/localtime",
+            "Mars/Olympus",
+        ] {
+            assert!(invalid.parse::<TimeZone>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_civil_year() {
+        for year in [1969, 1970, 2000, 2023, 2024, 2100] {
+            assert_eq!(civil_year(days_from_civil(year, 1, 1)), year);
+            assert_eq!(civil_year(days_from_civil(year, 12, 31)), year);
+            assert_eq!(civil_year(days_from_civil(year, 2, 28)), year);
+        }
+    }
+
+    #[test]
+    fn test_daily_buckets_align_to_fixed_offset_midnight() {
+        let tz: TimeZone = "+08:00".parse().unwrap();
+        // 2024-03-10 15:59 UTC 为本地 23:59，16:00 UTC 已是次日本地零点
+        let midnight = utc(2024, 3, 10, 16);
+        assert_eq!(
+            tz.bucket_start(midnight - 60_000, DAY_MS),
+            midnight - DAY_MS
+        );
+        assert_eq!(tz.bucket_start(midnight, DAY_MS), midnight);
+        assert_eq!(tz.bucket_start(midnight + 23 * HOUR_MS, DAY_MS), midnight);
+        // 半小时偏移的小时桶落在本地整点
+        let tz: TimeZone = "+05:30".parse().unwrap();
+        assert_eq!(
+            tz.bucket_start(utc(2024, 3, 10, 12) + 10 * 60_000, HOUR_MS),
+            utc(2024, 3, 10, 11) + 30 * 60_000
+        );
+    }
+
+    #[test]
+    fn test_posix_rule_daylight_saving() {
+        let rule = PosixRule::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        // 2024 年欧洲中部夏令时：3 月 31 日 01:00 UTC 开始，10 月 27 日 01:00 UTC 结束
+        assert_eq!(rule.offset_ms(utc(2024, 3, 31, 1) - 1), HOUR_MS);
+        assert_eq!(rule.offset_ms(utc(2024, 3, 31, 1)), 2 * HOUR_MS);
+        assert_eq!(rule.offset_ms(utc(2024, 10, 27, 1) - 1), 2 * HOUR_MS);
+        assert_eq!(rule.offset_ms(utc(2024, 10, 27, 1)), HOUR_MS);
+
+        let southern = PosixRule::parse("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap();
+        assert_eq!(southern.offset_ms(utc(2024, 1, 15, 0)), 11 * HOUR_MS);
+        assert_eq!(southern.offset_ms(utc(2024, 7, 15, 0)), 10 * HOUR_MS);
+
+        let fixed = PosixRule::parse("<+08>-8").unwrap();
+        assert_eq!(fixed.offset_ms(0), 8 * HOUR_MS);
+    }
+
+    #[test]
+    fn test_daily_buckets_across_daylight_saving() {
+        // 没有转换表时全部按 POSIX 规则推算
+        let zone = TimeZone {
+            name: "Europe/Berlin".to_string(),
+            rules: Rules::Zone(Arc::new(ZoneRules {
+                initial: HOUR_MS,
+                transitions: vec![],
+                tail: PosixRule::parse("CET-1CEST,M3.5.0,M10.5.0/3"),
+            })),
+        };
+
+        // 3 月 31 日本地零点为 UTC 前一日 23:00，当天只有 23 小时
+        let day_start = utc(2024, 3, 30, 23);
+        let next_day = utc(2024, 3, 31, 22);
+        assert_eq!(zone.bucket_start(utc(2024, 3, 31, 12), DAY_MS), day_start);
+        assert_eq!(zone.bucket_start(next_day - 1, DAY_MS), day_start);
+        assert_eq!(zone.bucket_start(next_day, DAY_MS), next_day);
+
+        // 10 月 27 日当天有 25 小时
+        let day_start = utc(2024, 10, 26, 22);
+        assert_eq!(zone.bucket_start(utc(2024, 10, 27, 22), DAY_MS), day_start);
+        assert_eq!(
+            zone.bucket_start(utc(2024, 10, 27, 23), DAY_MS),
+            utc(2024, 10, 27, 23)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_caches_named_zones() {
+        assert_eq!(
+            TimeZone::resolve("+08:00").await.unwrap().offset_ms(0),
+            8 * HOUR_MS
+        );
+        assert!(TimeZone::resolve("../etc/passwd").await.is_err());
+        assert!(TimeZone::resolve("Mars/Olympus").await.is_err());
+        assert!(!ZONE_CACHE.lock().unwrap().contains_key("Mars/Olympus"));
+
+        // 系统没有 zoneinfo 数据库时跳过
+        let Ok(first) = TimeZone::resolve("Europe/Berlin").await else {
+            return;
+        };
+        // 再次解析直接取缓存中的同一份规则
+        let second = TimeZone::resolve("Europe/Berlin").await.unwrap();
+        match (&first.rules, &second.rules) {
+            (Rules::Zone(a), Rules::Zone(b)) => assert!(Arc::ptr_eq(a, b)),
+            _ => panic!("expected named zone rules"),
+        }
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_named_zone_from_system_database() {
+        // 系统没有 zoneinfo 数据库时跳过
+        let Ok(zone) = "America/New_York".parse::<TimeZone>() else {
+            return;
+        };
+        assert_eq!(zone.offset_ms(utc(2024, 1, 15, 12)), -5 * HOUR_MS);
+        assert_eq!(zone.offset_ms(utc(2024, 7, 15, 12)), -4 * HOUR_MS);
+        // 超出转换表范围时由 POSIX 规则推算
+        assert_eq!(zone.offset_ms(utc(2080, 7, 15, 12)), -4 * HOUR_MS);
+        assert_eq!(
+            zone.bucket_start(utc(2024, 7, 15, 12), DAY_MS),
+            utc(2024, 7, 15, 4)
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! 请求关联 ID（trace_id）
+//!
+//! 每个 gRPC 请求/流式连接与 HTTP 请求都带一个 trace_id，记录在 tracing span 上；
+//! 存储层的 span 与落盘事件挂在发起请求的 span 之下，按 trace_id 即可在日志中串起
+//! 一个 Agent 从接收、缓存、入队到落盘的完整路径。
+
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{info_span, Instrument};
+
+/// 传递 trace_id 的请求头 / gRPC metadata 键
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// 调用方提供的 trace_id 最大长度，超出时重新生成
+const MAX_TRACE_ID_LEN: usize = 128;
+
+/// 生成新的 trace_id（16 位十六进制）
+pub fn new_trace_id() -> String {
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+    // 与批量写入抖动一样借用 RandomState 的随机种子，无需引入随机数依赖
+    let random = RandomState::new().hash_one(SEQ.fetch_add(1, Ordering::Relaxed));
+    format!("{:016x}", random)
+}
+
+/// 优先沿用调用方提供的 trace_id，缺失或含不安全字符时生成新的
+///
+/// 只接受字母数字与 `-_.:`，避免把任意内容写进日志
+pub fn trace_id_or_new(provided: Option<&str>) -> String {
+    match provided {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_TRACE_ID_LEN
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':')) =>
+        {
+            id.to_string()
+        }
+        _ => new_trace_id(),
+    }
+}
+
+/// HTTP 中间件：为请求建立带 trace_id 的 span，并在响应头 `X-Request-Id` 中返回该 ID
+pub async fn http_request_id(request: Request, next: Next) -> Response {
+    let trace_id = trace_id_or_new(
+        request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok()),
+    );
+    let span = info_span!(
+        "http_request",
+        trace_id = %trace_id,
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+
+    let mut response = next.run(request).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(&trace_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_id_or_new() {
+        assert_eq!(trace_id_or_new(Some("req-42.a_b:c")), "req-42.a_b:c");
+
+        for provided in [None, Some(""), Some("bad id\n"), Some(&*"x".repeat(200))] {
+            let id = trace_id_or_new(provided);
+            assert_eq!(id.len(), 16, "{:?} -> {}", provided, id);
+            assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        }
+        assert_ne!(new_trace_id(), new_trace_id());
+    }
+}
@@ -1,5 +1,9 @@
+use agent::{AgentConfig, CollectorsConfig, SpoolConfig, TlsConfig};
 use anyhow::Result;
 use clap::Parser;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Parser)]
@@ -7,29 +11,237 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 #[command(version = env!("CARGO_PKG_VERSION"))]
 #[command(about = "Iris Agent - 服务器监控探针", long_about = None)]
 struct Cli {
-    /// Server 地址
-    #[arg(short, long, default_value = "http://127.0.0.1:50051")]
-    server: String,
+    /// TOML 配置文件路径，命令行参数优先于配置文件中的同名项
+    #[arg(short, long)]
+    config: Option<PathBuf>,
 
-    /// 上报间隔（秒）
-    #[arg(short, long, default_value = "1")]
-    interval: u64,
+    /// Server 地址（http://host:port 或 unix:/path/to/iris.sock），可重复或以逗号分隔指定多个 [默认: http://127.0.0.1:50051]
+    #[arg(short, long, value_delimiter = ',')]
+    server: Vec<String>,
+
+    /// 多个 Server 时的上报方式：failover（出错后切换到下一个）或 broadcast（同时发往所有） [默认: failover]
+    #[arg(long)]
+    mode: Option<agent::ReportMode>,
+
+    /// 主机名解析方式：system（原样）、short（短名）或 fqdn（完全限定域名）；IRIS_HOSTNAME 始终优先 [默认: system]
+    #[arg(long)]
+    hostname_mode: Option<agent::HostnameMode>,
+
+    /// 样本时间戳来源：wall（系统时钟）或 monotonic（启动时锚定系统时钟后单调递增，不受 NTP 回拨影响） [默认: wall]
+    #[arg(long)]
+    timestamp_source: Option<agent::TimestampSource>,
+
+    /// 上报间隔（秒） [默认: 1]
+    #[arg(short, long)]
+    interval: Option<u64>,
+
+    /// 出站代理（http://[user:pass@]host:port 或 socks5://[user:pass@]host:port），gRPC 连接经 HTTP CONNECT 或 SOCKS5 隧道到达 Server [默认: 读取 HTTPS_PROXY/ALL_PROXY]
+    #[arg(long, value_name = "URL")]
+    proxy: Option<agent::Proxy>,
+
+    /// 部署标签 key=value，可重复；同名键覆盖配置文件中的值
+    #[arg(long = "label", value_name = "KEY=VALUE", value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+
+    /// 不采集探针自身进程的 CPU/内存（省去每次的进程刷新）
+    #[arg(long)]
+    no_self_metrics: bool,
+
+    /// 上报 CPU 使用率最高的 N 个进程（含 I/O 字节数与线程数），0 表示不采集 [默认: 0]
+    #[arg(long, value_name = "N")]
+    top_processes: Option<usize>,
+
+    /// 上报全部挂载点（默认同一文件系统的绑定挂载、overlay 等只上报一次）
+    #[arg(long)]
+    all_mounts: bool,
+
+    /// 上报 systemd 失败单元（每次采集执行一次 systemctl，主机未运行 systemd 时自动跳过）
+    #[arg(long)]
+    systemd: bool,
+
+    /// 上报 Docker 容器数与运行中容器的 CPU/内存合计（经 /var/run/docker.sock 查询，主机没有 Docker 时自动跳过）
+    #[arg(long)]
+    docker: bool,
+
+    /// 上报各 NUMA 节点的内存总量与空闲量（读取 /sys/devices/system/node，非 NUMA 主机只有一项）
+    #[arg(long)]
+    numa: bool,
+
+    /// 离线缓冲目录：所有 Server 都连不上时样本写入该目录，连接恢复后先补发 [默认: 不缓冲]
+    #[arg(long, value_name = "DIR")]
+    spool_dir: Option<PathBuf>,
+
+    /// 离线缓冲上限（MiB），超出后丢弃最旧的样本 [默认: 64]
+    #[arg(long, value_name = "MB")]
+    spool_max_mb: Option<u64>,
+
+    /// 离线缓冲不压缩（默认以 gzip 压缩存储，省磁盘但多占 CPU）
+    #[arg(long)]
+    no_spool_compress: bool,
+
+    /// 补发离线缓冲时把早于 SECS 秒的样本按时间桶合并为一条（瞬时值取平均），限制重连后的补发量 [默认: 逐条补发]
+    #[arg(long, value_name = "SECS")]
+    spool_aggregate_after: Option<u64>,
+
+    /// 聚合的时间桶宽度（秒） [默认: 60]
+    #[arg(long, value_name = "SECS")]
+    spool_aggregate_bucket: Option<u64>,
+
+    /// 校验 Server 证书的 CA 证书（PEM）。当前构建不支持 TLS，设置任一 --tls-* 参数时启动失败
+    #[arg(long, value_name = "FILE")]
+    tls_ca_cert: Option<PathBuf>,
+
+    /// 双向 TLS 的客户端证书（PEM），需与 --tls-key 同时设置
+    #[arg(long, value_name = "FILE")]
+    tls_cert: Option<PathBuf>,
+
+    /// 双向 TLS 的客户端私钥（PEM），需与 --tls-cert 同时设置
+    #[arg(long, value_name = "FILE")]
+    tls_key: Option<PathBuf>,
+
+    /// 校验证书时使用的域名 [默认: Server 地址中的主机名]
+    #[arg(long, value_name = "DOMAIN")]
+    tls_domain: Option<String>,
+
+    /// 端到端自检：向每个 Server 单次上报一条样本并经 HTTP API 查回，逐步报告解析、连接、准入、接收与查询结果后退出
+    #[arg(long, conflicts_with_all = ["print", "replay", "once"])]
+    self_test: bool,
+
+    /// 自检查询所用的 HTTP API 地址（http://host:port） [默认: Server 地址的端口 + 1]
+    #[arg(long, value_name = "URL", requires = "self_test")]
+    api: Option<String>,
+
+    /// 只采集一次：配合 --print 时打印后退出，否则单次上报后退出
+    #[arg(long)]
+    once: bool,
+
+    /// 将样本以 JSON 打印到标准输出，不连接 Server（日志输出到标准错误）
+    #[arg(long)]
+    print: bool,
+
+    /// 不采集本机，按原始间隔回放录制的样本文件（NDJSON，如 Server 导出的 history.ndjson）
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+
+    /// 回放速度倍数，如 10 表示以 10 倍速回放 [默认: 1]
+    #[arg(long, value_name = "N", value_parser = parse_speed, requires = "replay")]
+    replay_speed: Option<f64>,
+
+    /// 回放完后从头循环
+    #[arg(long, requires = "replay")]
+    replay_loop: bool,
+
+    /// 回放时把样本时间戳改写为发送时刻
+    #[arg(long, requires = "replay")]
+    replay_now: bool,
+}
+
+impl Cli {
+    /// 命令行中显式指定的项
+    fn overrides(&self) -> AgentConfig {
+        AgentConfig {
+            servers: (!self.server.is_empty()).then(|| self.server.clone()),
+            mode: self.mode,
+            hostname_mode: self.hostname_mode,
+            timestamp_source: self.timestamp_source,
+            interval: self.interval,
+            proxy: self.proxy.clone(),
+            labels: self.labels.iter().cloned().collect(),
+            collectors: CollectorsConfig {
+                self_metrics: self.no_self_metrics.then_some(false),
+                top_processes: self.top_processes,
+                all_mounts: self.all_mounts.then_some(true),
+                systemd: self.systemd.then_some(true),
+                docker: self.docker.then_some(true),
+                numa: self.numa.then_some(true),
+            },
+            spool: SpoolConfig {
+                dir: self.spool_dir.clone(),
+                max_mb: self.spool_max_mb,
+                compress: self.no_spool_compress.then_some(false),
+                aggregate_after_secs: self.spool_aggregate_after,
+                aggregate_bucket_secs: self.spool_aggregate_bucket,
+            },
+            tls: TlsConfig {
+                ca_cert: self.tls_ca_cert.clone(),
+                cert: self.tls_cert.clone(),
+                key: self.tls_key.clone(),
+                domain: self.tls_domain.clone(),
+            },
+        }
+    }
+}
+
+/// 解析 `key=value` 形式的标签
+fn parse_label(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("标签格式应为 key=value: {}", s)),
+    }
+}
+
+/// 解析回放速度，必须为正数
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed > 0.0 => Ok(speed),
+        _ => Err(format!("回放速度应为正数: {}", s)),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // 初始化日志
+    let cli = Cli::parse();
+
+    // 初始化日志（打印模式下标准输出只留给 JSON）
+    let writer = if cli.print || cli.self_test {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "iris=info".into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(writer))
         .init();
 
-    let cli = Cli::parse();
-    let agent = agent::Agent::new(cli.server, cli.interval);
-    agent.run().await?;
+    let file_config = match &cli.config {
+        Some(path) => AgentConfig::load(path)?,
+        None => AgentConfig::default(),
+    };
+    let config = cli.overrides().or(file_config);
+    let interval = config.interval();
+    let agent = config.build()?;
+
+    if cli.self_test {
+        let reports = agent.self_test(cli.api.as_deref()).await;
+        for report in &reports {
+            println!("{}", report);
+        }
+        if !reports.iter().all(agent::SelfTestReport::passed) {
+            anyhow::bail!("自检未通过");
+        }
+    } else if let Some(path) = &cli.replay {
+        let replay = agent::Replay::load(path)?
+            .with_speed(cli.replay_speed.unwrap_or(1.0))
+            .with_loop(cli.replay_loop)
+            .with_rewrite_timestamps(cli.replay_now);
+        agent.replay(replay).await?;
+    } else if cli.print {
+        let mut stdout = std::io::stdout();
+        loop {
+            agent.print_sample(&mut stdout)?;
+            if cli.once {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+        }
+    } else if cli.once {
+        agent.report_once().await?;
+    } else {
+        agent.run().await?;
+    }
 
     Ok(())
 }
@@ -7,9 +7,113 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 #[command(version = env!("CARGO_PKG_VERSION"))]
 #[command(about = "Iris Server - 监控数据中心服务器", long_about = None)]
 struct Cli {
-    /// gRPC 监听地址
+    /// gRPC 监听地址（ip:port，支持 IPv6 如 [::]:50051，或 unix:/path/to/iris.sock）
     #[arg(short, long, default_value = "0.0.0.0:50051")]
     addr: String,
+
+    /// HTTP API 监听地址（默认 gRPC 端口 + 1；gRPC 使用 Unix socket 时必填）
+    #[arg(long)]
+    http_addr: Option<std::net::SocketAddr>,
+
+    /// 单次历史查询允许的最大条数
+    #[arg(long, default_value_t = server::DEFAULT_MAX_HISTORY_LIMIT)]
+    max_history_limit: usize,
+
+    /// 每个 SSE / WebSocket 订阅者的事件缓冲条数，填满后该订阅者改为接收 resync 快照
+    #[arg(long, default_value_t = server::DEFAULT_SSE_CLIENT_BUFFER)]
+    sse_client_buffer: usize,
+
+    /// 每个 Agent 在内存中缓存的样本数，超出后淘汰最旧的样本（淘汰数见 /api/admin/ingest-stats）
+    #[arg(long, default_value_t = server::DEFAULT_CACHE_SIZE_PER_AGENT)]
+    cache_size_per_agent: usize,
+
+    /// 不参与自动清理的 agent_id（按完整 ID 精确匹配），可重复或以逗号分隔指定多个
+    #[arg(long, value_delimiter = ',')]
+    cleanup_exempt: Vec<String>,
+
+    /// 管理接口（POST /api/admin/agents）的访问令牌，请求须带 Authorization: Bearer <令牌>；
+    /// 未指定时该接口关闭
+    #[arg(long, value_name = "TOKEN")]
+    admin_token: Option<String>,
+
+    /// 开启 Agent 准入名单：只接受 --allow-agent 指定或经 POST /api/admin/agents 登记的 agent_id，
+    /// 其余上报以 PERMISSION_DENIED 拒绝（默认接受任何 Agent）
+    #[arg(long)]
+    allowlist: bool,
+
+    /// 准入名单中的 agent_id，可重复或以逗号分隔指定多个（隐含 --allowlist）
+    #[arg(long, value_name = "AGENT_ID", value_delimiter = ',')]
+    allow_agent: Vec<String>,
+
+    /// 磁盘剩余空间告警规则 [<挂载点>=]<阈值>，阈值为可用字节数（如 10GiB、500MB）、可用百分比（如 5%）
+    /// 或按增长趋势预计写满的时长（如 full:4h），
+    /// 未指定挂载点时作用于所有挂载点；可重复或以逗号分隔指定多个，触发与恢复记录在 Agent 事件时间线中
+    #[arg(long, value_name = "RULE", value_delimiter = ',')]
+    alert_disk_free: Vec<server::DiskFreeRule>,
+
+    /// 已有数据库文件对其他用户可访问时拒绝启动（默认只记录告警）
+    #[arg(long)]
+    strict_db_permissions: bool,
+
+    /// 要求持久化：数据目录不存在时拒绝以仅内存模式启动（默认退化为仅内存，便于开发）
+    #[arg(long)]
+    require_persistence: bool,
+
+    /// 数据库分片数：大于 1 时按 agent_id 拆分到多个 redb 文件并行写入（已有数据库不能更改）
+    #[arg(long, default_value_t = 1)]
+    db_shards: usize,
+
+    /// redb 缓存大小（MiB，读缓存与写缓冲合计，多个分片平分）：内存紧张时调小，查询大时间范围较多时调大
+    #[arg(long, value_name = "MB", default_value_t = server::DEFAULT_DB_CACHE_BYTES >> 20)]
+    db_cache_mb: usize,
+
+    /// 同时活跃的流式连接数上限，超出时拒绝新连接（活跃数见 /api/admin/ingest-stats）
+    #[arg(long, default_value_t = server::DEFAULT_MAX_CONCURRENT_STREAMS)]
+    max_concurrent_streams: usize,
+
+    /// 落盘前的写入合并窗口（毫秒）：同一批次内同一 Agent 在窗口内数值几乎不变的样本只保留最后一条，0 表示不合并
+    #[arg(long, default_value_t = 0)]
+    coalesce_window_ms: u64,
+
+    /// 写入合并时视为“几乎相同”的最大差值（CPU、内存、磁盘使用率的百分点）
+    #[arg(long, default_value_t = server::DEFAULT_COALESCE_MAX_DELTA)]
+    coalesce_max_delta: f64,
+
+    /// 样本最大时效（秒）：时间戳早于接收时刻超过该时长的样本只作为历史保存，不更新最新样本、不推送实时流，0 表示不检查
+    #[arg(long, default_value_t = 0)]
+    max_sample_age_secs: u64,
+
+    /// 样本含 NaN/Inf 时的处理方式：zero 替换为 0 后照常保存，drop 丢弃整条样本
+    #[arg(long, default_value_t = server::NonFinitePolicy::Zero)]
+    non_finite: server::NonFinitePolicy,
+
+    /// 健康评分各维度的权重，如 cpu=0.4,memory=0.3,disk=0.2,load=0.1（未列出的维度保留默认值，0 表示不参与评分）
+    #[arg(long, default_value_t = server::HealthWeights::default())]
+    health_weights: server::HealthWeights,
+
+    /// 优雅关闭的总预算（秒）：排空连接与数据落盘须在此时长内完成，超时后放弃等待直接退出
+    #[arg(long, default_value_t = server::DEFAULT_SHUTDOWN_TIMEOUT.as_secs())]
+    shutdown_timeout_secs: u64,
+
+    /// 关闭实时推送：不再广播样本，SSE / WebSocket 接口返回 404（适用于没有看板、只做接收与存储的节点）
+    #[arg(long)]
+    no_broadcast: bool,
+
+    /// 历史查询默认的一致性模式：cache-preferred 优先读缓存，persist-authoritative 先等待已入队样本落盘再只读持久化层（单次查询可用 consistency 参数覆盖）
+    #[arg(long, default_value_t = server::HistoryConsistency::CachePreferred)]
+    history_consistency: server::HistoryConsistency,
+
+    /// 按字段分级保留（小时），如 per_core=24,processes=6：清理时去掉超出保留期的样本中的这些字段，CPU、内存等核心指标照常保留（可选 per_core、processes、tcp_ping、collector_status）
+    #[arg(long)]
+    field_retention_hours: Option<server::FieldRetention>,
+
+    /// 仅实时模式：不持久化、不清理，每个 Agent 只在内存中保留最近 --cache-size-per-agent 条样本，超出的历史查询标记 truncated（适用于内存受限的小节点）
+    #[arg(long, conflicts_with = "require_persistence")]
+    live_only: bool,
+
+    /// 单个 HTTP 请求的处理时限（秒），超出时取消查询并返回 504，0 表示不限制（SSE / WebSocket 不受限制）
+    #[arg(long, default_value_t = server::DEFAULT_REQUEST_TIMEOUT.as_secs())]
+    request_timeout_secs: u64,
 }
 
 #[tokio::main]
@@ -24,7 +128,39 @@ async fn main() -> Result<()> {
         .init();
 
     let cli = Cli::parse();
-    server::ProbeServer::run(cli.addr).await?;
+    let config = server::ServerConfig {
+        max_history_limit: cli.max_history_limit,
+        sse_client_buffer: cli.sse_client_buffer,
+        cache_size_per_agent: cli.cache_size_per_agent,
+        http_addr: cli.http_addr,
+        cleanup_exempt_agents: cli.cleanup_exempt.into_iter().collect(),
+        strict_permissions: cli.strict_db_permissions,
+        require_persistence: cli.require_persistence,
+        db_shards: cli.db_shards,
+        db_cache_bytes: Some(cli.db_cache_mb << 20),
+        max_concurrent_streams: cli.max_concurrent_streams,
+        coalesce_window: std::time::Duration::from_millis(cli.coalesce_window_ms),
+        coalesce_max_delta: cli.coalesce_max_delta,
+        max_sample_age: std::time::Duration::from_secs(cli.max_sample_age_secs),
+        non_finite: cli.non_finite,
+        health_weights: cli.health_weights,
+        shutdown_timeout: std::time::Duration::from_secs(cli.shutdown_timeout_secs),
+        broadcast_enabled: !cli.no_broadcast,
+        history_consistency: cli.history_consistency,
+        field_retention: cli.field_retention_hours.unwrap_or_default(),
+        live_only: cli.live_only,
+        request_timeout: std::time::Duration::from_secs(cli.request_timeout_secs),
+        allowed_agents: (cli.allowlist || !cli.allow_agent.is_empty())
+            .then(|| cli.allow_agent.into_iter().collect()),
+        admin_token: cli.admin_token.filter(|token| !token.trim().is_empty()),
+        disk_free_alerts: cli.alert_disk_free,
+        ..Default::default()
+    };
+    if let Err(e) = server::ProbeServer::run_with_config(cli.addr, config).await {
+        // 关闭超时时写入可能仍卡在阻塞线程中，直接退出而不是等待运行时回收后台任务
+        tracing::error!("{:#}", e);
+        std::process::exit(1);
+    }
 
     Ok(())
 }